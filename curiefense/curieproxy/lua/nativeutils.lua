@@ -1,7 +1,5 @@
 local nativeutils = {}
 -- helpers for native rust libraries
-local accesslog   = require "lua.accesslog"
-local log_request = accesslog.envoy_log_request
 local cjson       = require "cjson"
 
 function nativeutils.trim(s)
@@ -60,6 +58,46 @@ function nativeutils.map_fn (T, fn)
     return ret
 end
 
+-- removes a cookie from a cookie header value
+local function remove_cookie(cookies, name)
+    local kept = {}
+    for _, c in ipairs(nativeutils.split(cookies or "", ";")) do
+        local cname = nativeutils.trim(nativeutils.split(c, "=", 1)[1] or "")
+        if cname ~= name and nativeutils.trim(c) ~= "" then
+            table.insert(kept, nativeutils.trim(c))
+        end
+    end
+    return table.concat(kept, "; ")
+end
+
+-- applies the mutations of a sanitize action, through the proxy specific accessors
+-- ops is a table with get_header, set_header, remove_header and set_query functions
+local function apply_mutations(mutations, ops)
+    if not mutations or mutations == cjson.null then return end
+    for _, m in ipairs(mutations) do
+        if m.op == "remove_header" then
+            ops.remove_header(m.name)
+        elseif m.op == "truncate_header" then
+            local value = ops.get_header(m.name)
+            if value then ops.set_header(m.name, string.sub(value, 1, m.length)) end
+        elseif m.op == "remove_cookie" then
+            local cookies = remove_cookie(ops.get_header("cookie"), m.name)
+            if cookies == "" then
+                ops.remove_header("cookie")
+            else
+                ops.set_header("cookie", cookies)
+            end
+        elseif m.op == "set_query" then
+            ops.set_query(m.query)
+        end
+    end
+end
+
+-- the request is passed, once sanitized, with the headers injected toward the upstream
+local function is_passthrough(action_params)
+    return action_params.atype == "alter_headers" or action_params.atype == "sanitize"
+end
+
 function nativeutils.nginx_custom_response(request_map, action_params)
     if not action_params then action_params = {} end
     local block_mode = action_params.block_mode
@@ -67,6 +105,25 @@ function nativeutils.nginx_custom_response(request_map, action_params)
 
     local handle = request_map.handle
 
+    if is_passthrough(action_params) then
+        apply_mutations(action_params.mutations, {
+            get_header = function(k)
+                local v = handle.req.get_headers()[k]
+                if type(v) == "table" then return v[1] end
+                return v
+            end,
+            set_header = function(k, v) handle.req.set_header(k, v) end,
+            remove_header = function(k) handle.req.clear_header(k) end,
+            set_query = function(q) handle.req.set_uri_args(q) end,
+        })
+        if action_params["headers"] and action_params["headers"] ~= cjson.null then
+            for k, v in pairs(action_params["headers"]) do
+                handle.req.set_header(k, v)
+            end
+        end
+        return
+    end
+
     if action_params["headers"] and action_params["headers"] ~= cjson.null then
         for k, v in pairs(action_params["headers"]) do
             handle.header[k] = v
@@ -111,6 +168,26 @@ function nativeutils.envoy_custom_response(request_map, action_params)
     local block_mode = action_params.block_mode
     -- if not block_mode then block_mode = true end
 
+    if is_passthrough(action_params) then
+        local headers = request_map.handle:headers()
+        apply_mutations(action_params.mutations, {
+            get_header = function(k) return headers:get(k) end,
+            set_header = function(k, v) headers:replace(k, v) end,
+            remove_header = function(k) headers:remove(k) end,
+            set_query = function(q)
+                local path = nativeutils.split(headers:get(":path") or "/", "?", 1)[1]
+                if q ~= "" then path = path .. "?" .. q end
+                headers:replace(":path", path)
+            end,
+        })
+        if action_params["headers"] and action_params["headers"] ~= cjson.null then
+            for k, v in pairs(action_params["headers"]) do
+                headers:replace(k, v)
+            end
+        end
+        return
+    end
+
     local response = {
         [ "status" ] = "503",
         [ "headers"] = { [":status"] = "503" },
@@ -128,12 +205,7 @@ function nativeutils.envoy_custom_response(request_map, action_params)
 
     response["headers"][":status"] = response["status"]
 
-    request_map.attrs.blocked = true
-    request_map.attrs.block_reason = response["reason"]
-
-
     if block_mode then
-        log_request(request_map)
         request_map.handle:respond( response["headers"], response["content"])
     end
 
@@ -158,4 +230,17 @@ function nativeutils.log_envoy_messages(handle, logs)
     end
 end
 
+-- exposes the verdict to the downstream envoy filters, see the metadata module
+function nativeutils.envoy_set_metadata(handle, metadata)
+    if not metadata then
+        return
+    end
+    local dynamic_metadata = handle:streamInfo():dynamicMetadata()
+    for k, v in pairs(metadata) do
+        if v ~= cjson.null then
+            dynamic_metadata:set("com.curiefense", k, v)
+        end
+    end
+end
+
 return nativeutils
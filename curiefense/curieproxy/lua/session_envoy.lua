@@ -1,7 +1,9 @@
 local session_rust_envoy = {}
 local cjson       = require "cjson"
 local curiefense  = require "curiefense"
-local grasshopper = require "grasshopper"
+-- without the grasshopper component, the native challenge is used (see the challenge settings)
+local has_grasshopper, grasshopper = pcall(require, "grasshopper")
+if not has_grasshopper then grasshopper = nil end
 local accesslog   = require "lua.accesslog"
 local utils       = require "lua.nativeutils"
 local sfmt = string.format
@@ -38,46 +40,97 @@ function session_rust_envoy.inspect(handle)
 
     local headers = {}
     local meta = {}
+    local header_order = {}
     for k, v in pairs(handle:headers()) do
         if utils.startswith(k, ":") then
             meta[k:sub(2):lower()] = v
         else
             headers[k] = v
+            table.insert(header_order, k)
+        end
+    end
+    meta.header_order = table.concat(header_order, ",")
+    meta.http_version = handle:streamInfo():protocol()
+    local local_port = string.match(handle:streamInfo():downstreamLocalAddress() or "", ":(%d+)$")
+    if local_port then
+        meta.port = local_port
+    end
+    -- PROXY protocol information, forwarded by the listener
+    for k, v in pairs(handle:metadata()) do
+        if utils.startswith(k, "proxy_protocol_") then
+            meta[k] = v
         end
     end
 
+    -- the route can select another configuration, or another security policy
+    meta.config_path = handle:metadata():get("config_path")
+    meta.securitypolicy = handle:metadata():get("securitypolicy")
+
+    -- mTLS client certificate, when the downstream connection presented one
+    local ssl = handle:streamInfo():downstreamSslConnection()
+    if ssl and ssl:peerCertificatePresented() then
+        meta.tls_client_subject = ssl:subjectPeerCertificate()
+        local sans = {}
+        for _, san in ipairs(ssl:uriSanPeerCertificate()) do
+            table.insert(sans, san)
+        end
+        for _, san in ipairs(ssl:dnsSansPeerCertificate()) do
+            table.insert(sans, san)
+        end
+        meta.tls_client_san = table.concat(sans, ",")
+        meta.tls_client_fingerprint = ssl:sha256PeerCertificateDigest()
+        meta.tls_client_verified = tostring(ssl:peerCertificateValidated())
+    end
+
     local hbody = handle:body()
     local body_content = nil
     if hbody then
         body_content = hbody:getBytes(0, hbody:length())
     end
+    -- the trailers, once the body was received, are inspected with the headers
+    local trailers = handle:trailers()
+    if trailers then
+        for k, v in pairs(trailers) do
+            meta["trailer_" .. k:lower()] = v
+        end
+    end
 
     -- the meta table contains the following elements:
     --   * path : the full request uri
     --   * method : the HTTP verb
     --   * authority : optionally, the HTTP2 authority field
-    local response, err = curiefense.inspect_request(
+    --   * scheme, port, http_version : optionally, the request scheme, destination port and protocol version
+    --   * proxy_protocol_* : optionally, the PROXY protocol connection information
+    --   * header_order : optionally, the comma separated header names, in the order they were received
+    --   * tls_client_* : optionally, the mTLS client certificate details
+    --   * trailer_* : optionally, the trailers of the request
+    --   * chunk_extensions : optionally, the concatenated extensions of the body chunks (";name=value...")
+    local decision, err = curiefense.inspect(
         meta, headers, body_content, ip_str, grasshopper
     )
 
     if err then
-        handle:logErr(sfmt("curiefense.inspect_request_map error %s", err))
+        handle:logErr(sfmt("curiefense.inspect error %s", err))
     end
 
-    local request_map = nil
-    if response then
-        local response_table = cjson.decode(response)
-        handle:logDebug("decision " .. response)
-        utils.log_envoy_messages(handle, response_table["logs"])
-        request_map = response_table["request_map"]
+    if decision then
+        handle:logDebug("decision " .. decision:action())
+        utils.log_envoy_messages(handle, decision:logs())
+        utils.envoy_set_metadata(handle, decision:metadata())
+        -- the generated request id is forwarded upstream, so that its logs can be joined to the inspection
+        local request_id = decision:request_id()
+        if request_id and not handle:headers():get("x-request-id") then
+            handle:headers():add("x-request-id", request_id)
+        end
+        -- logged before responding, as blocking responses end the filter
+        log_request(handle, decision:access_log())
+        local request_map = decision:request_map()
         request_map.handle = handle
-        if response_table["action"] == "custom_response" then
-            custom_response(request_map, response_table["response"])
+        if decision:action() == "custom_response" then
+            custom_response(request_map, decision:response())
         end
     end
 
-    log_request(request_map)
-
 end
 
 return session_rust_envoy
\ No newline at end of file
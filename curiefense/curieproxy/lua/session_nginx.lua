@@ -1,58 +1,86 @@
 local session_rust_nginx = {}
 local cjson       = require "cjson"
 local curiefense  = require "curiefense"
-local grasshopper = require "grasshopper"
+-- without the grasshopper component, the native challenge is used (see the challenge settings)
+local has_grasshopper, grasshopper = pcall(require, "grasshopper")
+if not has_grasshopper then grasshopper = nil end
 local utils       = require "lua.nativeutils"
 local sfmt = string.format
 local custom_response = utils.nginx_custom_response
 
-function session_rust_nginx.inspect(handle)
-    local ip_str = handle.var.remote_addr
-
-    local headers = {}
-
-    local rheaders, err = handle.req.get_headers()
-    if err == "truncated" then
-        handle.log(handle.ERR, "truncated headers: " .. err)
+-- maximum size of the body that is read from nginx temporary files
+local MAX_BODY_CAPTURE = 1024 * 1024
+
+-- runs an asynchronous curiefense function in a coroutine, that yields while it waits for I/O (redis):
+-- nginx handles other requests until the coroutine is resumed
+local function run_async(handle, f, ...)
+    local co = coroutine.create(f)
+    local res = { coroutine.resume(co, ...) }
+    while coroutine.status(co) ~= "dead" do
+        handle.sleep(0)
+        res = { coroutine.resume(co) }
     end
-
-    for k, v in pairs(rheaders) do
-        headers[k] = v
+    if not res[1] then
+        return nil, res[2]
     end
+    return res[2], res[3]
+end
 
-    handle.log(handle.INFO, cjson.encode(headers))
-
+function session_rust_nginx.inspect(handle)
+    local body_truncated = false
     handle.req.read_body()
     local body_content = handle.req.get_body_data()
+    if body_content == nil then
+        -- large bodies are buffered to a file, only inspect their beginning
+        local body_file = handle.req.get_body_file()
+        if body_file then
+            local f = io.open(body_file, "rb")
+            if f then
+                body_content = f:read(MAX_BODY_CAPTURE)
+                if f:read(0) ~= nil then
+                    body_truncated = true
+                end
+                f:close()
+            end
+        end
+    end
     if body_content ~= nil then
         handle.ctx.body_len = body_content:len()
     else
         handle.ctx.body_len = 0
     end
-    local meta = { path=handle.var.request_uri, method=handle.req.get_method(), authority=nil }
-
-    -- the meta table contains the following elements:
-    --   * path : the full request uri
-    --   * method : the HTTP verb
-    --   * authority : optionally, the HTTP2 authority field
-    local response
-    response, err = curiefense.inspect_request(
-        meta, headers, body_content, ip_str, grasshopper
+
+    -- the method, headers, and metadata (path, scheme, port, protocol, header order, PROXY protocol information) are
+    -- read from the ngx API by curiefense
+    local decision, err = run_async(
+        handle, curiefense.inspect_nginx,
+        handle, body_content, body_truncated, grasshopper
     )
 
     if err then
-        handle.log(handle.ERR, sfmt("curiefense.inspect_request_map error %s", err))
+        handle.log(handle.ERR, sfmt("curiefense.inspect error %s", err))
     end
 
-    if response then
-        local response_table = cjson.decode(response)
-        handle.ctx.response = response_table
-        handle.log(handle.DEBUG, "decision: " .. response)
-        utils.log_nginx_messages(handle, response_table["logs"])
-        local request_map = response_table["request_map"]
+    if decision then
+        local request_map = decision:request_map()
+        handle.ctx.response = {
+            request_map = request_map,
+            action = decision:action(),
+            response = decision:response(),
+            metadata = decision:metadata(),
+            access_log = decision:access_log(),
+            logs = decision:logs(),
+        }
+        handle.log(handle.DEBUG, "decision: " .. decision:action())
+        utils.log_nginx_messages(handle, handle.ctx.response["logs"])
+        -- the generated request id is forwarded upstream, so that its logs can be joined to the inspection
+        local request_id = decision:request_id()
+        if request_id and not handle.req.get_headers()["x-request-id"] then
+            handle.req.set_header("x-request-id", request_id)
+        end
         request_map.handle = handle
-        if response_table["action"] == "custom_response" then
-            custom_response(request_map, response_table["response"])
+        if decision:action() == "custom_response" then
+            custom_response(request_map, decision:response())
         end
     end
 end
@@ -75,7 +103,8 @@ end
 function session_rust_nginx.log(handle)
     local response = handle.ctx.response
     handle.ctx.response = nil
-    local request_map = response.request_map
+    -- request, decision and timings, as built by curiefense
+    local access_log = response.access_log
 
     local body_len = handle.ctx.body_len
     local req_len = handle.var.request_length
@@ -83,7 +112,7 @@ function session_rust_nginx.log(handle)
     local raw_status = handle.var.status
     local status = tonumber(raw_status) or raw_status
     local req = {
-        tags=request_map["tags"],
+        tags=access_log.tags,
         path=handle.var.uri,
         host=handle.var.host,
         -- TODO: authority
@@ -97,18 +126,15 @@ function session_rust_nginx.log(handle)
           nxgrequestlength=handle.var.request_length
         },
         scheme=handle.var.scheme,
-        metadata={},
+        metadata=access_log.metadata,
         port=0,
+        blocked=access_log.blocked,
+        block_reason=access_log.block_reason,
+        triggers=access_log.triggers,
+        timings=access_log.timings,
+        timestamp=access_log.timestamp,
     }
 
-    if response.response ~= cjson.null then
-        req.block_reason=response.response.reason
-        req.blocked=response.response.block_mode
-    else
-        req.block_reason=nil
-        req.blocked=false
-    end
-
     local raw_server_port = handle.var.server_port
     local raw_remote_port = handle.var.remote_port
     local server_port = tonumber(raw_server_port) or raw_server_port
@@ -151,10 +177,10 @@ function session_rust_nginx.log(handle)
 
     req.request = {
         originalpath="",
-        geo=request_map["geo"],
-        arguments=request_map["args"],
-        headers=request_map["headers"],
-        cookies=request_map["cookies"],
+        geo=access_log.geo,
+        arguments=access_log.arguments,
+        headers=access_log.headers,
+        cookies=access_log.cookies,
         -- TODO: are we currently including the length of the first line of the HTTP request?
         headersbytes=req_len - body_len,
         bodybytes=body_len
@@ -198,13 +224,7 @@ function session_rust_nginx.log(handle)
         lastdownstreambyte=nil
     }
 
-    -- building the formatted timestamp (should it be added by logstash?)
-    local tm = handle.utctime() -- format "yyyy-mm-dd hh:mm:ss"
-    local fracpart = tostring(handle.now()%1):sub(2,10)
-    local timestamp = tm:gsub(' ', 'T') .. fracpart .. 'Z'
-    req.timestamp = timestamp
-
-    req.request.attributes=request_map.attrs
+    req.request.attributes=access_log.attributes
     handle.var.request_map = cjson.encode(req)
 end
 
@@ -1,10 +1,12 @@
 use core::ffi::c_void;
+use curiefense::config::{reload_config, with_config};
 use curiefense::grasshopper::{DummyGrasshopper, Grasshopper};
-use curiefense::inspect_generic_request_map_async;
 use curiefense::interface::{Decision, Tags};
 use curiefense::logs::{LogLevel, Logs};
 use curiefense::simple_executor::{new_executor_and_spawner, Executor, Progress, TaskCB};
 use curiefense::utils::{RawRequest, RequestInfo, RequestMeta};
+use curiefense::{inspect_generic_request_map, inspect_generic_request_map_async};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_uchar};
@@ -19,6 +21,8 @@ pub enum CFProgress {
 
 pub struct CHashmap {
     inner: HashMap<String, String>,
+    /// original bytes of the values that are not valid UTF-8
+    raw: HashMap<String, Vec<u8>>,
 }
 
 /// # Safety
@@ -26,7 +30,10 @@ pub struct CHashmap {
 /// New C hashmap
 #[no_mangle]
 pub unsafe extern "C" fn cf_hashmap_new() -> *mut CHashmap {
-    Box::into_raw(Box::new(CHashmap { inner: HashMap::new() }))
+    Box::into_raw(Box::new(CHashmap {
+        inner: HashMap::new(),
+        raw: HashMap::new(),
+    }))
 }
 
 /// # Safety
@@ -35,8 +42,12 @@ pub unsafe extern "C" fn cf_hashmap_new() -> *mut CHashmap {
 #[no_mangle]
 pub unsafe extern "C" fn cf_hashmap_insert(hm: *mut CHashmap, key: *const c_char, value: *const c_char) {
     let s_key = CStr::from_ptr(key).to_string_lossy().to_string();
-    let s_value = CStr::from_ptr(value).to_string_lossy().to_string();
+    let c_value = CStr::from_ptr(value);
+    let s_value = c_value.to_string_lossy().to_string();
     if let Some(r) = hm.as_mut() {
+        if c_value.to_str().is_err() {
+            r.raw.insert(s_key.clone(), c_value.to_bytes().to_vec());
+        }
         r.inner.insert(s_key, s_value);
     }
 }
@@ -216,6 +227,130 @@ pub unsafe extern "C" fn curiefense_str_free(ptr: *mut c_char) {
     drop(CString::from_raw(ptr));
 }
 
+fn log_level(loglevel: u8) -> Option<LogLevel> {
+    match loglevel {
+        0 => Some(LogLevel::Debug),
+        1 => Some(LogLevel::Info),
+        2 => Some(LogLevel::Warning),
+        3 => Some(LogLevel::Error),
+        _ => None,
+    }
+}
+
+fn has_errors(logs: &Logs) -> bool {
+    logs.logs.iter().any(|l| l.level == LogLevel::Error)
+}
+
+/// an inspection engine, for the synchronous API
+pub struct CFEngine {
+    configpath: String,
+    loglevel: LogLevel,
+}
+
+/// the request description of the synchronous API
+#[derive(Deserialize)]
+struct CFRequest {
+    ip: String,
+    /// same keys as the meta table of the Lua API (method, path, authority, ...)
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+}
+
+fn json_error(rr: &str) -> *mut c_char {
+    let out = serde_json::json!({ "error": rr }).to_string();
+    CString::new(out)
+        .map(|cs| cs.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// # Safety
+///
+/// Creates a synchronous inspection engine, loading the configuration. Returns a null pointer when the log level is
+/// invalid, or when errors happened while loading the configuration. Must be freed with cf_free.
+#[no_mangle]
+pub unsafe extern "C" fn cf_init(raw_configpath: *const c_char, loglevel: u8) -> *mut CFEngine {
+    let loglevel = match log_level(loglevel) {
+        None => return std::ptr::null_mut(),
+        Some(l) => l,
+    };
+    let configpath = CStr::from_ptr(raw_configpath).to_string_lossy().to_string();
+    let mut logs = Logs::new(loglevel);
+    if with_config(&configpath, &mut logs, |_, _| {}).is_none() || has_errors(&logs) {
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(CFEngine { configpath, loglevel }))
+}
+
+/// # Safety
+///
+/// Reloads the configuration, even if it has not been modified. Note that the configuration is otherwise reloaded
+/// automatically, shortly after the modification time of the configuration directory changes. Returns false when errors
+/// happened.
+#[no_mangle]
+pub unsafe extern "C" fn cf_reload(ptr: *const CFEngine) -> bool {
+    match ptr.as_ref() {
+        None => false,
+        Some(engine) => reload_config(&engine.configpath, &mut Logs::new(engine.loglevel)),
+    }
+}
+
+/// # Safety
+///
+/// Inspects a request, described by a JSON object with the `ip`, `meta` and `headers` keys, along with its body.
+/// Returns the decision, JSON encoded in the same format as the Lua API, or an object with an `error` key. The
+/// returned string must be freed with curiefense_str_free.
+#[no_mangle]
+pub unsafe extern "C" fn cf_inspect(
+    ptr: *const CFEngine,
+    request_json: *const c_char,
+    mbody: *const c_uchar,
+    mbody_len: usize,
+) -> *mut c_char {
+    let engine = match ptr.as_ref() {
+        None => return json_error("Null pointer"),
+        Some(e) => e,
+    };
+    let request: CFRequest = match serde_json::from_slice(CStr::from_ptr(request_json).to_bytes()) {
+        Err(rr) => return json_error(&format!("Invalid request: {}", rr)),
+        Ok(r) => r,
+    };
+    let mut meta = match RequestMeta::from_map(request.meta) {
+        Err(rr) => return json_error(rr),
+        Ok(m) => m,
+    };
+    // the configuration is the one of the engine, it can't be selected by the request
+    meta.config_path = None;
+    let mbody = if mbody_len == 0 || mbody.is_null() {
+        None
+    } else {
+        Some(std::slice::from_raw_parts(mbody, mbody_len))
+    };
+    let raw_request = RawRequest {
+        ipstr: meta.client_ip(request.ip),
+        headers: request.headers,
+        header_bytes: HashMap::new(),
+        meta,
+        mbody,
+    };
+    let mut logs = Logs::new(engine.loglevel);
+    let (decision, tags, reqinfo) =
+        inspect_generic_request_map(&engine.configpath, None::<DummyGrasshopper>, raw_request, &mut logs);
+    CString::new(decision.to_json(reqinfo, tags, logs))
+        .map(|cs| cs.into_raw())
+        .unwrap_or_else(|_| json_error("Irrepresentable decision"))
+}
+
+/// # Safety
+///
+/// Frees an engine returned by cf_init.
+#[no_mangle]
+pub unsafe extern "C" fn cf_free(ptr: *mut CFEngine) {
+    if ptr.is_null() {
+        return;
+    }
+    drop(Box::from_raw(ptr));
+}
+
 /// Simple wrapper to return the reqinfo data
 pub async fn inspect_wrapper<GH: Grasshopper>(
     logs: Logs,
@@ -248,12 +383,9 @@ pub unsafe extern "C" fn curiefense_async_init(
     cb: extern "C" fn(u64),
     data: u64,
 ) -> *mut CFExec {
-    let lloglevel = match loglevel {
-        0 => LogLevel::Debug,
-        1 => LogLevel::Info,
-        2 => LogLevel::Warning,
-        3 => LogLevel::Error,
-        _ => return std::ptr::null_mut(),
+    let lloglevel = match log_level(loglevel) {
+        None => return std::ptr::null_mut(),
+        Some(l) => l,
     };
     // convert the strings and loglevel
     let configpath = CStr::from_ptr(raw_configpath).to_string_lossy().to_string();
@@ -267,9 +399,12 @@ pub unsafe extern "C" fn curiefense_async_init(
             Ok(x) => x,
         },
     };
-    let headers = match raw_headers.as_mut() {
+    let (headers, header_bytes) = match raw_headers.as_mut() {
         None => return std::ptr::null_mut(),
-        Some(rf) => Box::from_raw(rf).as_ref().inner.clone(),
+        Some(rf) => {
+            let hm = Box::from_raw(rf);
+            (hm.inner.clone(), hm.raw.clone())
+        }
     };
 
     // retrieve the body
@@ -282,14 +417,16 @@ pub unsafe extern "C" fn curiefense_async_init(
     // create the requestinfo structure
     let logs = Logs::new(lloglevel);
     let raw_request = RawRequest {
-        ipstr: ip,
+        ipstr: meta.client_ip(ip),
         headers,
+        header_bytes,
         meta,
         mbody,
     };
     let (executor, spawner) = new_executor_and_spawner::<TaskCB<CFDecision>>();
+    // no external grasshopper: the native challenge is used when it is configured
     spawner.spawn_cb(
-        inspect_wrapper(logs, configpath, raw_request, Some(DummyGrasshopper {})),
+        inspect_wrapper(logs, configpath, raw_request, None::<DummyGrasshopper>),
         cb,
         data,
     );
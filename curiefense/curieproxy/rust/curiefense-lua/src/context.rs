@@ -0,0 +1,391 @@
+use crate::lua::Luagrasshopper;
+use curiefense::acl::check_acl;
+use curiefense::analyze::{acl_phase, analyze, combine_phases, conclude, content_filter_decision, observe, AclOutcome};
+use curiefense::blockpage::apply_template;
+use curiefense::body::body_too_large;
+use curiefense::captcha::captcha_verified;
+use curiefense::challenge_verified;
+use curiefense::config::hostmap::SecurityPolicy;
+use curiefense::config::{config_snapshot, request_config_path, ConfigSnapshot};
+use curiefense::contentfilter::{content_filter_check, masking};
+use curiefense::dnsbl;
+use curiefense::explain::explain_enabled;
+use curiefense::grasshopper::{Challenger, DummyGrasshopper, Grasshopper};
+use curiefense::interface::{Action, Decision, SimpleDecision, Tags};
+use curiefense::limit::{limit_check, report_response};
+use curiefense::logs::Logs;
+use curiefense::reason::stamp;
+use curiefense::securitypolicy::match_securitypolicy;
+use curiefense::tagging::tag_request;
+use curiefense::utils::{map_request, GeoIp, RawRequest, RequestInfo};
+use mlua::prelude::*;
+use serde::Deserialize;
+
+pub struct RequestContext {
+    snapshot: ConfigSnapshot,
+    secpolname: String,
+    securitypolicy: SecurityPolicy,
+    rinfo: RequestInfo,
+    tags: Tags,
+    globalfilter_dec: SimpleDecision,
+    is_human: bool,
+    /// set when the body exceeds the maximum size of the content filter profile
+    body_too_large: Option<Action>,
+    logs: Logs,
+    /// the outcomes of the checks that were run, for `final_decision`
+    limit_decision: Option<Decision>,
+    acl_outcome: Option<AclOutcome>,
+    content_filter_decision: Option<Decision>,
+}
+
+impl RequestContext {
+    pub fn new<GH: Grasshopper>(
+        configpath: &str,
+        raw: &RawRequest,
+        mgh: Option<GH>,
+        logs: Logs,
+    ) -> Result<Self, String> {
+        let mut ctx = Self::untagged(configpath, raw, mgh, logs)?;
+        ctx.tag();
+        Ok(ctx)
+    }
+
+    /// the context, before the request is tagged by `tag`
+    pub fn untagged<GH: Grasshopper>(
+        configpath: &str,
+        raw: &RawRequest,
+        mgh: Option<GH>,
+        mut logs: Logs,
+    ) -> Result<Self, String> {
+        // the integration can select another configuration for this request
+        let configpath = request_config_path(configpath, &raw.meta, &mut logs);
+        let snapshot = match config_snapshot(configpath, &mut logs) {
+            Some(s) => s,
+            None => return Err("could not find a matching security policy".to_string()),
+        };
+        let cfg = &snapshot.config;
+        let (secpolname, secpol) =
+            match match_securitypolicy(&raw.get_host(), &raw.meta.canonical_path(), &raw.meta, cfg, &mut logs) {
+                Some(x) => x,
+                None => return Err("could not find a matching security policy".to_string()),
+            };
+        if explain_enabled(secpol, &raw.headers) {
+            logs.explain = Some(Vec::new());
+        }
+        let profile = &secpol.content_filter_profile;
+        let body_too_large = raw
+            .mbody
+            .filter(|b| b.len() > profile.max_body_size)
+            .map(|b| body_too_large(profile.max_body_size, b.len()));
+        let rinfo = map_request(
+            &mut logs,
+            &profile.decoding,
+            &profile.content_type,
+            if body_too_large.is_some() {
+                0
+            } else {
+                profile.max_body_depth
+            },
+            &secpol.session,
+            &profile.parse_budget,
+            profile.nested_args,
+            raw,
+        );
+        let is_human = match (&mgh, &cfg.native_challenge) {
+            (Some(gh), _) => challenge_verified(gh, &rinfo, &mut logs),
+            (None, Some(n)) => challenge_verified(n, &rinfo, &mut logs),
+            (None, None) => false,
+        } || captcha_verified(secpol, &rinfo);
+        let securitypolicy = secpol.clone();
+        Ok(RequestContext {
+            snapshot,
+            secpolname,
+            securitypolicy,
+            rinfo,
+            tags: Tags::default(),
+            globalfilter_dec: SimpleDecision::Pass,
+            is_human,
+            body_too_large,
+            logs,
+            limit_decision: None,
+            acl_outcome: None,
+            content_filter_decision: None,
+        })
+    }
+
+    /// runs the global filters, and adds the security policy tags, replacing the current tags
+    pub fn tag(&mut self) {
+        let (mut tags, globalfilter_dec) = tag_request(
+            &mut self.logs,
+            self.is_human,
+            &self.snapshot.config.globalfilters,
+            &self.rinfo,
+        );
+        let secpol = &self.securitypolicy;
+        let profile = &secpol.content_filter_profile;
+        tags.insert("all");
+        async_std::task::block_on(dnsbl::tag(&mut self.logs, &mut tags, self.rinfo.rinfo.geoip.ip));
+        tags.insert_qualified("securitypolicy", &self.secpolname);
+        tags.insert_qualified("securitypolicy-entry", &secpol.name);
+        tags.insert_qualified("aclid", &secpol.acl_profile.id);
+        tags.insert_qualified("aclname", &secpol.acl_profile.name);
+        tags.insert_qualified("contentfilterid", &profile.id);
+        tags.insert_qualified("contentfiltername", &profile.name);
+        self.tags = tags;
+        self.globalfilter_dec = globalfilter_dec;
+    }
+
+    /// replaces the tags
+    pub fn set_tags(&mut self, tags: &[String]) {
+        self.tags = Tags::from_slice(tags);
+    }
+
+    /// selects the security policy of the named hostmap, the request must then be tagged again
+    ///
+    /// The request map is not rebuilt, so that it still follows the decoding settings of the original policy.
+    pub fn override_urlmap(&mut self, name: &str) -> Result<(), String> {
+        let config = self.snapshot.config.clone();
+        if !config
+            .securitypolicies
+            .iter()
+            .map(|m| &m.inner)
+            .chain(config.default.as_ref())
+            .any(|h| h.name == name)
+        {
+            return Err(format!("unknown security policy {}", name));
+        }
+        let mut meta = self.rinfo.rinfo.meta.clone();
+        meta.securitypolicy = Some(name.to_string());
+        let (secpolname, secpol) = match_securitypolicy(
+            &self.rinfo.rinfo.host,
+            &self.rinfo.rinfo.qinfo.canonical_path,
+            &meta,
+            &config,
+            &mut self.logs,
+        )
+        .ok_or_else(|| format!("security policy {} has no matching entry", name))?;
+        self.secpolname = secpolname;
+        self.securitypolicy = secpol.clone();
+        Ok(())
+    }
+
+    /// overrides geolocation fields, the request must then be tagged again
+    pub fn set_geo(&mut self, geo: GeoOverride) {
+        geo.apply(&mut self.rinfo.rinfo.geoip);
+    }
+
+    fn challenger<GH: Grasshopper>(&self, mgh: Option<GH>) -> Option<Challenger<GH>> {
+        match mgh {
+            Some(gh) => Some(Challenger::External(gh)),
+            None => self.snapshot.config.native_challenge.clone().map(Challenger::Native),
+        }
+    }
+
+    fn to_decision(&self, decision: SimpleDecision) -> Decision {
+        match decision {
+            SimpleDecision::Pass => Decision::Pass,
+            SimpleDecision::Action(action, reason) => action.to_decision(
+                self.is_human,
+                &self.snapshot.config.native_challenge,
+                self.securitypolicy.captcha.as_deref(),
+                &self.rinfo,
+                reason,
+            ),
+        }
+    }
+
+    pub fn limits(&mut self) -> Decision {
+        let decision = async_std::task::block_on(limit_check(
+            &mut self.logs,
+            &self.securitypolicy.name,
+            &self.rinfo,
+            &self.securitypolicy.limits,
+            &mut self.tags,
+        ));
+        let decision = self.to_decision(decision);
+        self.limit_decision = Some(decision.clone());
+        decision
+    }
+
+    /// counts the status of the upstream response, for the limits on the responses
+    pub fn report_response(&mut self, status: u32) -> usize {
+        async_std::task::block_on(report_response(
+            &mut self.logs,
+            &self.securitypolicy.name,
+            &self.rinfo,
+            &self.securitypolicy.limits,
+            &self.tags,
+            status,
+        ))
+    }
+
+    pub fn waf(&mut self) -> Decision {
+        let decision = match &self.body_too_large {
+            Some(action) => Decision::Action(action.clone()),
+            None => {
+                let profile = &self.securitypolicy.content_filter_profile;
+                let result = content_filter_check(
+                    &mut self.logs,
+                    &mut self.tags,
+                    &self.rinfo,
+                    profile,
+                    self.snapshot.hsdb.get(&profile.id),
+                );
+                content_filter_decision(result, &self.securitypolicy)
+            }
+        };
+        self.content_filter_decision = Some(decision.clone());
+        decision
+    }
+
+    /// the decisions of the checks that were run, combined as in the complete inspection
+    pub fn final_decision(&self) -> serde_json::Value {
+        let mut logs = self.logs.clone();
+        let mut tags = self.tags.clone();
+        let secpol = &self.securitypolicy;
+        let profile = &secpol.content_filter_profile;
+        let rinfo = masking(&profile.masking_seed, self.rinfo.clone(), profile);
+        let decision = combine_phases(
+            secpol,
+            &rinfo,
+            &tags,
+            self.to_decision(self.globalfilter_dec.clone()),
+            self.limit_decision.clone(),
+            self.acl_outcome.clone(),
+            self.content_filter_decision.clone(),
+        );
+        let decision = async_std::task::block_on(conclude(
+            &mut logs,
+            &self.secpolname,
+            secpol,
+            &rinfo,
+            decision,
+            &mut tags,
+        ));
+        decision.to_value(rinfo, tags, logs)
+    }
+
+    /// the complete inspection, on a copy of the context, returning the result of `inspect_request`
+    pub fn decision<GH: Grasshopper>(&self, mgh: Option<GH>) -> serde_json::Value {
+        let mut logs = self.logs.clone();
+        let secpol = &self.securitypolicy;
+        if let Some(action) = &self.body_too_large {
+            let mut tags = self.tags.clone();
+            let mut decision = apply_template(&mut logs, Decision::Action(action.clone()), &self.rinfo, secpol);
+            if secpol.observe {
+                decision = observe(&mut logs, decision, &mut tags);
+            }
+            let decision = stamp(&logs, decision, &self.rinfo);
+            return decision.to_value(self.rinfo.clone(), tags, logs);
+        }
+        let (decision, tags, rinfo) = async_std::task::block_on(analyze(
+            &mut logs,
+            &self.snapshot.hsdb,
+            self.challenger(mgh),
+            self.tags.clone(),
+            &self.secpolname,
+            secpol,
+            self.rinfo.clone(),
+            self.is_human,
+            self.globalfilter_dec.clone(),
+            &self.snapshot.config.flows,
+        ));
+        decision.to_value(rinfo, tags, logs)
+    }
+
+    pub fn sorted_tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.tags.iter().map(|t| t.to_string()).collect();
+        tags.sort();
+        tags
+    }
+
+    /// the ACL result, the outcome of the ACL phase being recorded for `final_decision`
+    pub fn acl(&mut self) -> Result<serde_json::Value, serde_json::Error> {
+        let is_human = self.is_human || self.securitypolicy.challenge.bypassed(&self.rinfo.rinfo.qinfo.qpath);
+        let challenger = self.challenger::<DummyGrasshopper>(None);
+        self.acl_outcome = Some(acl_phase(
+            &mut self.logs,
+            &self.tags,
+            &self.securitypolicy,
+            &self.rinfo,
+            is_human,
+            challenger.as_ref(),
+        ));
+        serde_json::to_value(check_acl(&self.tags, &self.securitypolicy.acl_profile))
+    }
+
+    pub fn request_map(&self) -> serde_json::Value {
+        let profile = &self.securitypolicy.content_filter_profile;
+        masking(&profile.masking_seed, self.rinfo.clone(), profile).into_json(self.tags.clone())
+    }
+}
+
+/// geolocation fields, the missing ones being left unchanged
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoOverride {
+    pub country_iso: Option<String>,
+    pub country_name: Option<String>,
+    pub continent_code: Option<String>,
+    pub continent_name: Option<String>,
+    pub city_name: Option<String>,
+    pub region: Option<String>,
+    pub subregion: Option<String>,
+    pub in_eu: Option<bool>,
+    pub asn: Option<u32>,
+    pub company: Option<String>,
+    pub anonymous: Option<bool>,
+    pub vpn: Option<bool>,
+    pub hosting: Option<bool>,
+    pub public_proxy: Option<bool>,
+    pub tor: Option<bool>,
+}
+
+impl GeoOverride {
+    fn apply(self, geoip: &mut GeoIp) {
+        fn set<T>(target: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *target = value;
+            }
+        }
+        set(&mut geoip.country_iso, self.country_iso);
+        set(&mut geoip.country_name, self.country_name);
+        set(&mut geoip.continent_code, self.continent_code);
+        set(&mut geoip.continent_name, self.continent_name);
+        set(&mut geoip.city_name, self.city_name);
+        set(&mut geoip.region, self.region);
+        set(&mut geoip.subregion, self.subregion);
+        set(&mut geoip.in_eu, self.in_eu);
+        set(&mut geoip.asn, self.asn);
+        set(&mut geoip.company, self.company);
+        set(&mut geoip.anonymous.is_anonymous, self.anonymous);
+        set(&mut geoip.anonymous.is_anonymous_vpn, self.vpn);
+        set(&mut geoip.anonymous.is_hosting_provider, self.hosting);
+        set(&mut geoip.anonymous.is_public_proxy, self.public_proxy);
+        set(&mut geoip.anonymous.is_tor_exit_node, self.tor);
+    }
+}
+
+/// the decision of a single phase, without the request map
+pub fn phase_value(decision: Decision) -> serde_json::Value {
+    decision.to_value_raw(serde_json::Value::Null, Logs::default())
+}
+
+impl LuaUserData for RequestContext {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("tags", |_, this, ()| Ok(this.sorted_tags()));
+        methods.add_method("request_map", |_, this, ()| Ok(this.request_map().to_string()));
+        methods.add_method_mut("acl", |_, this, ()| {
+            this.acl().map(|a| a.to_string()).map_err(LuaError::external)
+        });
+        methods.add_method_mut("limits", |_, this, ()| Ok(phase_value(this.limits()).to_string()));
+        methods.add_method_mut("waf", |_, this, ()| Ok(phase_value(this.waf()).to_string()));
+        methods.add_method("final_decision", |_, this, ()| Ok(this.final_decision().to_string()));
+        methods.add_method("decision", |_, this, grasshopper: Option<LuaTable>| {
+            Ok(this.decision(grasshopper.map(Luagrasshopper)).to_string())
+        });
+        methods.add_method_mut("report_response", |_, this, status: u32| {
+            Ok(this.report_response(status))
+        });
+    }
+}
@@ -0,0 +1,131 @@
+use curiefense::accesslog::AccessLog;
+use curiefense::decisioncache::hint;
+use curiefense::interface::{Action, Decision, Tags};
+use curiefense::logs::Logs;
+use curiefense::metadata::DynamicMetadata;
+use curiefense::requestmap::{Geo, RequestMap};
+use curiefense::utils::{InspectionResult, RequestInfo};
+use mlua::prelude::*;
+use mlua::LuaSerdeExt;
+
+pub struct LuaDecision {
+    decision: Decision,
+    tags: Tags,
+    rinfo: Option<RequestInfo>,
+    logs: Logs,
+}
+
+impl LuaDecision {
+    pub fn pass() -> Self {
+        LuaDecision {
+            decision: Decision::Pass,
+            tags: Tags::default(),
+            rinfo: None,
+            logs: Logs::default(),
+        }
+    }
+
+    fn action(&self) -> Option<&Action> {
+        match &self.decision {
+            Decision::Pass => None,
+            Decision::Action(a) => Some(a),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match &self.rinfo {
+            None => self.decision.to_json_raw(serde_json::Value::Null, self.logs.clone()),
+            Some(rinfo) => self
+                .decision
+                .to_json(rinfo.clone(), self.tags.clone(), self.logs.clone()),
+        }
+    }
+}
+
+impl From<InspectionResult> for LuaDecision {
+    fn from(ir: InspectionResult) -> Self {
+        let mut tags = ir.tags.unwrap_or_default();
+        if let Decision::Action(a) = &ir.decision {
+            for t in a.extra_tags.iter().flatten() {
+                tags.insert(t);
+            }
+        }
+        LuaDecision {
+            decision: ir.decision,
+            tags,
+            rinfo: ir.rinfo,
+            logs: ir.logs,
+        }
+    }
+}
+
+impl LuaUserData for LuaDecision {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("action", |_, this, ()| {
+            Ok(match this.decision {
+                Decision::Pass => "pass",
+                Decision::Action(_) => "custom_response",
+            })
+        });
+        methods.add_method("is_block", |_, this, ()| {
+            Ok(this
+                .action()
+                .map(|a| a.block_mode && a.atype.is_blocking())
+                .unwrap_or(false))
+        });
+        methods.add_method("status", |_, this, ()| Ok(this.action().map(|a| a.status)));
+        methods.add_method("headers", |_, this, ()| {
+            Ok(this.action().and_then(|a| a.headers.clone()))
+        });
+        methods.add_method("body", |_, this, ()| Ok(this.action().map(|a| a.content.clone())));
+        methods.add_method("response", |lua, this, ()| match this.action() {
+            None => Ok(LuaValue::Nil),
+            Some(a) => lua.to_value(a),
+        });
+        methods.add_method("reason", |lua, this, ()| match this.action() {
+            None => Ok(LuaValue::Nil),
+            Some(a) => lua.to_value(&a.reason),
+        });
+        methods.add_method("tags", |_, this, ()| {
+            let mut tags: Vec<String> = this.tags.iter().map(|t| t.to_string()).collect();
+            tags.sort();
+            Ok(tags)
+        });
+        methods.add_method("metadata", |lua, this, ()| {
+            let metadata = DynamicMetadata::new(&this.decision, &this.tags);
+            match &this.rinfo {
+                None => lua.to_value(&metadata),
+                Some(rinfo) => lua.to_value(
+                    &metadata
+                        .with_request_id(&rinfo.request_id)
+                        .with_geo(&Geo::new(&rinfo.rinfo.geoip))
+                        .with_set_cookie(rinfo.csrf.as_ref().and_then(|c| c.set_cookie.clone())),
+                ),
+            }
+        });
+        methods.add_method("logs", |lua, this, ()| lua.to_value(&this.logs.logs));
+        methods.add_method("request_map", |lua, this, ()| match &this.rinfo {
+            None => Ok(LuaValue::Nil),
+            Some(rinfo) => lua.to_value(&rinfo.clone().into_json(this.tags.clone())),
+        });
+        methods.add_method("access_log", |lua, this, ()| match &this.rinfo {
+            None => Ok(LuaValue::Nil),
+            Some(rinfo) => {
+                let request_map = RequestMap::new(rinfo.clone(), this.tags.clone());
+                lua.to_value(&AccessLog::new(&this.decision, &request_map, &this.logs))
+            }
+        });
+        methods.add_method("request_id", |_, this, ()| {
+            Ok(this.rinfo.as_ref().map(|r| r.request_id.clone()))
+        });
+        methods.add_method("explain", |lua, this, ()| lua.to_value(&this.logs.explain));
+        methods.add_method("inspection", |lua, this, ()| lua.to_value(&this.logs.inspection));
+        methods.add_method("cache", |lua, this, ()| {
+            match this.rinfo.as_ref().and_then(|r| hint(&this.decision, r)) {
+                None => Ok(LuaValue::Nil),
+                Some(h) => lua.to_value(&h),
+            }
+        });
+        methods.add_method("to_json", |_, this, ()| Ok(this.to_json()));
+    }
+}
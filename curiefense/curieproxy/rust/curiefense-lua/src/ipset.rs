@@ -0,0 +1,66 @@
+use crate::shared::Shared;
+use curiefense::ipset::{parse_network, IpSet};
+use lazy_static::lazy_static;
+use mlua::prelude::*;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    pub static ref IP_SETS: Shared<RwLock<IpSet<String>>> = Shared::new();
+}
+
+/// a set of IP networks with string values, for the Lua code, shared with the other Lua states once registered
+pub struct LuaIpSet(pub Arc<RwLock<IpSet<String>>>);
+
+impl LuaIpSet {
+    pub fn new(set: IpSet<String>) -> Self {
+        LuaIpSet(Arc::new(RwLock::new(set)))
+    }
+}
+
+fn poisoned<E: ToString>(rr: E) -> LuaError {
+    LuaError::RuntimeError(rr.to_string())
+}
+
+impl LuaUserData for LuaIpSet {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        // returns true, or nil and an error message
+        methods.add_method("insert", |_, this, (net, value): (String, Option<String>)| {
+            Ok(match parse_network(&net) {
+                Ok(n) => {
+                    this.0.write().map_err(poisoned)?.insert(n, value.unwrap_or_default());
+                    (Some(true), None)
+                }
+                Err(rr) => (None, Some(rr)),
+            })
+        });
+        // returns the number of loaded networks, or nil and an error message
+        methods.add_method("load", |_, this, path: String| {
+            let mut set = this.0.write().map_err(poisoned)?;
+            Ok(match set.extend_from_file(&path, |v| Ok(v.to_string())) {
+                Ok(n) => (Some(n), None),
+                Err(rr) => (None, Some(rr)),
+            })
+        });
+        // returns the value and the longest matching network, or nil
+        methods.add_method("lookup", |_, this, ip: String| {
+            let set = this.0.read().map_err(poisoned)?;
+            Ok(match ip.parse::<IpAddr>().ok().and_then(|a| set.lookup(a)) {
+                Some((net, value)) => (Some(value.clone()), Some(net.to_string())),
+                None => (None, None),
+            })
+        });
+        methods.add_method("contains", |_, this, ip: String| {
+            let set = this.0.read().map_err(poisoned)?;
+            Ok(ip.parse::<IpAddr>().map(|a| set.contains(a)).unwrap_or(false))
+        });
+        methods.add_method("len", |_, this, ()| Ok(this.0.read().map_err(poisoned)?.len()));
+        methods.add_method("serialize", |_, this, ()| {
+            serde_json::to_string(&*this.0.read().map_err(poisoned)?).map_err(LuaError::external)
+        });
+        methods.add_method("register", |_, this, name: String| {
+            IP_SETS.register(name, this.0.clone());
+            Ok(true)
+        });
+    }
+}
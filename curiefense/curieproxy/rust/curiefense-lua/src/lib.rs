@@ -1,17 +1,48 @@
+mod context;
+mod decision;
+mod ipset;
 mod lua;
+mod nginx;
+mod regexes;
+mod sessions;
+mod shared;
+mod sigset;
 
+use crate::context::{phase_value, GeoOverride, RequestContext};
+use crate::decision::LuaDecision;
+use crate::ipset::{LuaIpSet, IP_SETS};
 use crate::lua::Luagrasshopper;
+use crate::nginx::NginxRequest;
+use crate::regexes::REGEXES;
+use crate::sessions::{Encoding, Session, Step};
+use crate::sigset::{LuaSigSet, SIG_SETS};
 
 use curiefense::grasshopper::Grasshopper;
 use curiefense::utils::RequestMeta;
 use mlua::prelude::*;
+use mlua::LuaSerdeExt;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use curiefense::content_filter_check_generic_request_map;
-use curiefense::inspect_generic_request_map;
+use curiefense::diagnostics;
+use curiefense::dnsbl::DNSBL;
+use curiefense::helpers::{
+    aggregate_cidrs, cidr_contains, cidr_overlaps, ip_to_num, modhash, range_to_cidrs, HashAlgorithm,
+};
+use curiefense::hits::HITS;
 use curiefense::interface::Decision;
-use curiefense::logs::Logs;
-use curiefense::utils::{InspectionResult, RawRequest};
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::metrics::{record_inspection, METRICS};
+use curiefense::requestmap::Geo;
+use curiefense::shipper::ship;
+use curiefense::sigset::SigSet;
+use curiefense::utils::decoders::{urldecode_component, urlencode, UrlComponent};
+use curiefense::utils::{decode_header_bytes, find_geoip, InspectionRequest, InspectionResult, RawRequest};
+use curiefense::{
+    config_info, inspect_batch, inspect_generic_request_map, inspect_generic_request_map_async, preload,
+    request_cache_key,
+};
 
 // ******************************************
 // Content Filter ONLY CHECKS
@@ -20,8 +51,8 @@ use curiefense::utils::{InspectionResult, RawRequest};
 /// Lua interface to the inspection function
 ///
 /// args are
-/// * meta (contains keys "method", "path", and optionally "authority")
-/// * headers
+/// * meta (contains keys "method", "path", and optionally "authority" and "body_truncated")
+/// * headers (values are not required to be valid UTF-8)
 /// * (opt) body
 /// * ip addr
 /// * (opt) grasshopper
@@ -30,14 +61,15 @@ use curiefense::utils::{InspectionResult, RawRequest};
 fn lua_inspect_content_filter(
     _lua: &Lua,
     args: (
-        HashMap<String, String>, // meta
-        HashMap<String, String>, // headers
-        Option<LuaString>,       // maybe body
-        String,                  // ip
-        String,                  // content_filter_id
+        HashMap<String, String>,    // meta
+        HashMap<String, LuaString>, // headers
+        Option<LuaString>,          // maybe body
+        String,                     // ip
+        String,                     // content_filter_id
     ),
 ) -> LuaResult<(String, Option<String>)> {
-    let (meta, headers, lua_body, str_ip, content_filter_id) = args;
+    let (meta, lua_headers, lua_body, str_ip, content_filter_id) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
 
     let res = inspect_content_filter(
         "/cf-config/current/config",
@@ -61,7 +93,7 @@ fn lua_inspect_content_filter(
 fn inspect_content_filter(
     configpath: &str,
     meta: HashMap<String, String>,
-    headers: HashMap<String, String>,
+    (headers, header_bytes): (HashMap<String, String>, HashMap<String, Vec<u8>>),
     mbody: Option<&[u8]>,
     ip: String,
     content_filter_id: String,
@@ -71,9 +103,10 @@ fn inspect_content_filter(
     let rmeta: RequestMeta = RequestMeta::from_map(meta)?;
 
     let raw = RawRequest {
-        ipstr: ip,
+        ipstr: rmeta.client_ip(ip),
         meta: rmeta,
         headers,
+        header_bytes,
         mbody,
     };
 
@@ -96,8 +129,8 @@ fn inspect_content_filter(
 /// Lua interface to the inspection function
 ///
 /// args are
-/// * meta (contains keys "method", "path", and optionally "authority")
-/// * headers
+/// * meta (contains keys "method", "path", and optionally "authority" and "body_truncated")
+/// * headers (values are not required to be valid UTF-8)
 /// * (opt) body
 /// * ip addr
 /// * (opt) grasshopper
@@ -106,14 +139,15 @@ fn inspect_content_filter(
 fn lua_inspect_request(
     _lua: &Lua,
     args: (
-        HashMap<String, String>, // meta
-        HashMap<String, String>, // headers
-        Option<LuaString>,       // maybe body
-        String,                  // ip
-        Option<LuaTable>,        // grasshopper
+        HashMap<String, String>,    // meta
+        HashMap<String, LuaString>, // headers
+        Option<LuaString>,          // maybe body
+        String,                     // ip
+        Option<LuaTable>,           // grasshopper
     ),
 ) -> LuaResult<(String, Option<String>)> {
-    let (meta, headers, lua_body, str_ip, lua_grasshopper) = args;
+    let (meta, lua_headers, lua_body, str_ip, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
     let grasshopper = lua_grasshopper.map(Luagrasshopper);
     let res = inspect_request(
         "/cf-config/current/config",
@@ -159,8 +193,8 @@ impl Grasshopper for DummyGrasshopper {
 /// allows settings the Grasshopper result!
 ///
 /// args are
-/// * meta (contains keys "method", "path", and optionally "authority")
-/// * headers
+/// * meta (contains keys "method", "path", and optionally "authority" and "body_truncated")
+/// * headers (values are not required to be valid UTF-8)
 /// * (opt) body
 /// * ip addr
 /// * (opt) grasshopper
@@ -169,14 +203,15 @@ impl Grasshopper for DummyGrasshopper {
 fn lua_test_inspect_request(
     _lua: &Lua,
     args: (
-        HashMap<String, String>, // meta
-        HashMap<String, String>, // headers
-        Option<LuaString>,       // maybe body
-        String,                  // ip
-        bool,                    // humanity
+        HashMap<String, String>,    // meta
+        HashMap<String, LuaString>, // headers
+        Option<LuaString>,          // maybe body
+        String,                     // ip
+        bool,                       // humanity
     ),
 ) -> LuaResult<(String, Option<String>)> {
-    let (meta, headers, lua_body, str_ip, humanity) = args;
+    let (meta, lua_headers, lua_body, str_ip, humanity) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
     let grasshopper = Some(DummyGrasshopper { humanity });
 
     let res = inspect_request(
@@ -197,26 +232,315 @@ fn lua_test_inspect_request(
     })
 }
 
+/// Lua interface to the asynchronous inspection function
+///
+/// It takes the same arguments as `inspect_request`, and must be called from a coroutine: while waiting for I/O, it
+/// yields, and the coroutine must be resumed until it returns.
+#[allow(clippy::type_complexity)]
+async fn lua_inspect_request_async<'lua>(
+    _lua: &'lua Lua,
+    args: (
+        HashMap<String, String>,          // meta
+        HashMap<String, LuaString<'lua>>, // headers
+        Option<LuaString<'lua>>,          // maybe body
+        String,                           // ip
+        Option<LuaTable<'lua>>,           // grasshopper
+    ),
+) -> LuaResult<(String, Option<String>)> {
+    let (meta, lua_headers, lua_body, str_ip, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = inspect_request_async(
+        "/cf-config/current/config",
+        meta,
+        headers,
+        lua_body.as_ref().map(|b| b.as_bytes()),
+        str_ip,
+        grasshopper,
+    )
+    .await;
+
+    Ok(match res {
+        Err(rr) => (
+            Decision::Pass.to_json_raw(serde_json::Value::Null, Logs::default()),
+            Some(rr),
+        ),
+        Ok(ir) => ir.into_json(),
+    })
+}
+
+/// Lua interface to the inspection function, returning a decision userdata (see the `decision` module)
+///
+/// It takes the same arguments as `inspect_request`.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::unnecessary_wraps)]
+fn lua_inspect(
+    _lua: &Lua,
+    args: (
+        HashMap<String, String>,    // meta
+        HashMap<String, LuaString>, // headers
+        Option<LuaString>,          // maybe body
+        String,                     // ip
+        Option<LuaTable>,           // grasshopper
+    ),
+) -> LuaResult<(LuaDecision, Option<String>)> {
+    let (meta, lua_headers, lua_body, str_ip, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = inspect_request(
+        "/cf-config/current/config",
+        meta,
+        headers,
+        lua_body.as_ref().map(|b| b.as_bytes()),
+        str_ip,
+        grasshopper,
+    );
+
+    Ok(match res {
+        Err(rr) => (LuaDecision::pass(), Some(rr)),
+        Ok(ir) => {
+            let err = ir.err.clone();
+            (ir.into(), err)
+        }
+    })
+}
+
+/// Lua interface to the asynchronous inspection function, returning a decision userdata
+///
+/// It takes the same arguments as `inspect_request`, and must be called from a coroutine, as `inspect_request_async`.
+#[allow(clippy::type_complexity)]
+async fn lua_inspect_async<'lua>(
+    _lua: &'lua Lua,
+    args: (
+        HashMap<String, String>,          // meta
+        HashMap<String, LuaString<'lua>>, // headers
+        Option<LuaString<'lua>>,          // maybe body
+        String,                           // ip
+        Option<LuaTable<'lua>>,           // grasshopper
+    ),
+) -> LuaResult<(LuaDecision, Option<String>)> {
+    let (meta, lua_headers, lua_body, str_ip, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = inspect_request_async(
+        "/cf-config/current/config",
+        meta,
+        headers,
+        lua_body.as_ref().map(|b| b.as_bytes()),
+        str_ip,
+        grasshopper,
+    )
+    .await;
+
+    Ok(match res {
+        Err(rr) => (LuaDecision::pass(), Some(rr)),
+        Ok(ir) => {
+            let err = ir.err.clone();
+            (ir.into(), err)
+        }
+    })
+}
+
+/// OpenResty interface to the asynchronous inspection function, returning a decision userdata
+///
+/// The request is read with the `ngx` API (see the `nginx` module), and the function must be called from a coroutine,
+/// as `inspect_request_async`.
+///
+/// args are
+/// * the `ngx` table
+/// * (opt) body
+/// * (opt) body_truncated, when only the beginning of the body is inspected
+/// * (opt) grasshopper
+#[allow(clippy::type_complexity)]
+async fn lua_inspect_nginx<'lua>(
+    _lua: &'lua Lua,
+    args: (
+        LuaTable<'lua>,          // ngx
+        Option<LuaString<'lua>>, // maybe body
+        Option<bool>,            // body truncated
+        Option<LuaTable<'lua>>,  // grasshopper
+    ),
+) -> LuaResult<(LuaDecision, Option<String>)> {
+    let (ngx, lua_body, body_truncated, lua_grasshopper) = args;
+    let request = NginxRequest::new(&ngx, body_truncated.unwrap_or(false))?;
+    let headers = decode_header_bytes(request.headers.iter().map(|(k, v)| (k.clone(), v.as_slice())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = inspect_request_async(
+        "/cf-config/current/config",
+        request.meta,
+        headers,
+        lua_body.as_ref().map(|b| b.as_bytes()),
+        request.ip,
+        grasshopper,
+    )
+    .await;
+
+    Ok(match res {
+        Err(rr) => (LuaDecision::pass(), Some(rr)),
+        Ok(ir) => {
+            let err = ir.err.clone();
+            (ir.into(), err)
+        }
+    })
+}
+
+/// Lua interface to the decision cache keys
+///
+/// It takes the same arguments as `inspect_request`, but for the grasshopper, and returns the key of the decision of
+/// the request, or `nil` when it can't be cached. The decisions of `inspect` and `inspect_async` whose `cache()`
+/// method returns this key can be reused for the requests with the same key, for the returned `ttl`.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::unnecessary_wraps)]
+fn lua_cache_key(
+    _lua: &Lua,
+    args: (
+        HashMap<String, String>,    // meta
+        HashMap<String, LuaString>, // headers
+        Option<LuaString>,          // maybe body
+        String,                     // ip
+    ),
+) -> LuaResult<(Option<String>, Option<String>)> {
+    let (meta, lua_headers, lua_body, str_ip) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let raw = match raw_request(meta, headers, lua_body.as_ref().map(|b| b.as_bytes()), str_ip) {
+        Ok(r) => r,
+        Err(rr) => return Ok((None, Some(rr))),
+    };
+    let mut logs = Logs::default();
+    Ok((request_cache_key("/cf-config/current/config", &raw, &mut logs), None))
+}
+
+fn raw_request(
+    meta: HashMap<String, String>,
+    (headers, header_bytes): (HashMap<String, String>, HashMap<String, Vec<u8>>),
+    mbody: Option<&[u8]>,
+    ip: String,
+) -> Result<RawRequest<'_>, String> {
+    let rmeta: RequestMeta = RequestMeta::from_map(meta)?;
+    Ok(RawRequest {
+        ipstr: rmeta.client_ip(ip),
+        meta: rmeta,
+        headers,
+        header_bytes,
+        mbody,
+    })
+}
+
+/// Lua interface to the batch inspection function
+///
+/// args are
+/// * a list of JSON encoded requests, with the `ip`, `meta`, `headers` and optionally `body` keys
+/// * (opt) grasshopper
+///
+/// It returns the list of the JSON encoded decisions, in the order of the requests and in the format of
+/// `inspect_request`, and the list of errors. The requests that can't be decoded are passed.
+#[allow(clippy::unnecessary_wraps)]
+fn lua_inspect_batch(
+    _lua: &Lua,
+    args: (
+        Vec<LuaString>,   // requests
+        Option<LuaTable>, // grasshopper
+    ),
+) -> LuaResult<(Vec<String>, Vec<String>)> {
+    let (lua_requests, lua_grasshopper) = args;
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    Ok(inspect_batch_json(
+        "/cf-config/current/config",
+        lua_requests.iter().map(|r| r.as_bytes()),
+        grasshopper,
+    ))
+}
+
+/// Rust-native batch inspection function, for JSON encoded requests
+fn inspect_batch_json<'t, GH: Grasshopper, I: Iterator<Item = &'t [u8]>>(
+    configpath: &str,
+    requests: I,
+    grasshopper: Option<GH>,
+) -> (Vec<String>, Vec<String>) {
+    let decoded: Vec<Result<InspectionRequest, String>> = requests
+        .map(|r| serde_json::from_slice(r).map_err(|rr| rr.to_string()))
+        .collect();
+    let mut errors = Vec::new();
+    let mut raws = Vec::new();
+    // whether each request is inspected
+    let mut valid = Vec::new();
+    for (idx, request) in decoded.iter().enumerate() {
+        match request
+            .as_ref()
+            .map_err(|rr| rr.clone())
+            .and_then(|r| r.to_raw().map_err(|rr| rr.to_string()))
+        {
+            Ok(raw) => {
+                raws.push(raw);
+                valid.push(true);
+            }
+            Err(rr) => {
+                errors.push(format!("request {}: {}", idx + 1, rr));
+                valid.push(false);
+            }
+        }
+    }
+
+    // the errors that happened while loading the configuration
+    let mut logs = Logs::default();
+    let mut results = inspect_batch(configpath, grasshopper, &raws, &mut logs).into_iter();
+    errors.extend(
+        logs.logs
+            .into_iter()
+            .filter(|l| l.level == LogLevel::Error)
+            .map(|l| l.message),
+    );
+
+    let decisions = valid
+        .into_iter()
+        .map(|ok| match if ok { results.next() } else { None } {
+            Some((decision, tags, rinfo, rlogs)) => decision.to_json(rinfo, tags, rlogs),
+            None => Decision::Pass.to_json_raw(serde_json::Value::Null, Logs::default()),
+        })
+        .collect();
+    (decisions, errors)
+}
+
 /// Rust-native inspection top level function
 fn inspect_request<GH: Grasshopper>(
     configpath: &str,
     meta: HashMap<String, String>,
-    headers: HashMap<String, String>,
+    headers: (HashMap<String, String>, HashMap<String, Vec<u8>>),
     mbody: Option<&[u8]>,
     ip: String,
     grasshopper: Option<GH>,
 ) -> Result<InspectionResult, String> {
     let mut logs = Logs::default();
     logs.debug("Inspection init");
-    let rmeta: RequestMeta = RequestMeta::from_map(meta)?;
-
-    let raw = RawRequest {
-        ipstr: ip,
-        meta: rmeta,
-        headers,
-        mbody,
-    };
+    let raw = raw_request(meta, headers, mbody, ip)?;
     let (dec, tags, masked_rinfo) = inspect_generic_request_map(configpath, grasshopper, raw, &mut logs);
+    record_inspection(&dec, &masked_rinfo.request_id, &logs);
+    ship(&dec, &tags, &masked_rinfo, &logs);
+
+    Ok(InspectionResult {
+        decision: dec,
+        tags: Some(tags),
+        logs,
+        err: None,
+        rinfo: Some(masked_rinfo),
+    })
+}
+
+/// Rust-native asynchronous inspection function, that does not block while waiting for redis
+async fn inspect_request_async<GH: Grasshopper>(
+    configpath: &str,
+    meta: HashMap<String, String>,
+    headers: (HashMap<String, String>, HashMap<String, Vec<u8>>),
+    mbody: Option<&[u8]>,
+    ip: String,
+    grasshopper: Option<GH>,
+) -> Result<InspectionResult, String> {
+    let mut logs = Logs::default();
+    logs.debug("Inspection init");
+    let raw = raw_request(meta, headers, mbody, ip)?;
+    let (dec, tags, masked_rinfo) = inspect_generic_request_map_async(configpath, grasshopper, raw, &mut logs).await;
+    record_inspection(&dec, &masked_rinfo.request_id, &logs);
+    ship(&dec, &tags, &masked_rinfo, &logs);
 
     Ok(InspectionResult {
         decision: dec,
@@ -227,12 +551,502 @@ fn inspect_request<GH: Grasshopper>(
     })
 }
 
+// ******************************************
+// PHASE BY PHASE API
+// ******************************************
+
+/// Lua interface to the request contexts
+///
+/// args are
+/// * headers (values are not required to be valid UTF-8)
+/// * meta (contains keys "method", "path", and optionally "authority" and "body_truncated")
+/// * ip addr
+/// * (opt) body
+/// * (opt) grasshopper
+#[allow(clippy::type_complexity)]
+#[allow(clippy::unnecessary_wraps)]
+fn lua_new_context(
+    _lua: &Lua,
+    args: (
+        HashMap<String, LuaString>, // headers
+        HashMap<String, String>,    // meta
+        String,                     // ip
+        Option<LuaString>,          // maybe body
+        Option<LuaTable>,           // grasshopper
+    ),
+) -> LuaResult<(Option<RequestContext>, Option<String>)> {
+    let (lua_headers, meta, str_ip, lua_body, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = raw_request(meta, headers, lua_body.as_ref().map(|b| b.as_bytes()), str_ip)
+        .and_then(|raw| RequestContext::new("/cf-config/current/config", &raw, grasshopper, Logs::default()));
+    Ok(match res {
+        Ok(ctx) => (Some(ctx), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// creates a session, with the arguments of `new_context`, without tagging the request
+///
+/// returns the session id, or an error message
+#[allow(clippy::type_complexity)]
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_init(
+    _lua: &Lua,
+    args: (
+        HashMap<String, LuaString>, // headers
+        HashMap<String, String>,    // meta
+        String,                     // ip
+        Option<LuaString>,          // maybe body
+        Option<LuaTable>,           // grasshopper
+    ),
+) -> LuaResult<(Option<u64>, Option<String>)> {
+    let (lua_headers, meta, str_ip, lua_body, lua_grasshopper) = args;
+    let headers = decode_header_bytes(lua_headers.iter().map(|(k, v)| (k.clone(), v.as_bytes())));
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    let res = raw_request(meta, headers, lua_body.as_ref().map(|b| b.as_bytes()), str_ip)
+        .and_then(|raw| RequestContext::untagged("/cf-config/current/config", &raw, grasshopper, Logs::default()))
+        .and_then(sessions::insert);
+    Ok(match res {
+        Ok(id) => (Some(id), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// the result of a step of a session, or an error message
+fn session_result<R, F: FnOnce(&mut Session) -> Result<R, String>>(
+    id: u64,
+    step: Step,
+    f: F,
+) -> (Option<R>, Option<String>) {
+    match sessions::with_session(id, step, f).and_then(|r| r) {
+        Ok(r) => (Some(r), None),
+        Err(rr) => (None, Some(rr)),
+    }
+}
+
+/// the document computed by a step of a session, in the requested encoding, or an error message
+fn session_encoded<'lua, F: FnOnce(&mut Session) -> Result<serde_json::Value, String>>(
+    lua: &'lua Lua,
+    id: u64,
+    step: Step,
+    encoding: Option<String>,
+    f: F,
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let encoded = Encoding::from_name(encoding.as_deref()).and_then(|enc| {
+        sessions::with_session(id, step, f)
+            .and_then(|r| r)
+            .and_then(|value| enc.encode(&value))
+    });
+    match encoded {
+        Ok(bytes) => Ok((Some(lua.create_string(&bytes)?), None)),
+        Err(rr) => Ok((None, Some(rr))),
+    }
+}
+
+/// tags the request of a session, returning the sorted tags
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_tag_request(_lua: &Lua, id: u64) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    Ok(session_result(id, Step::TagRequest, |s| {
+        s.ctx.tag();
+        Ok(s.ctx.sorted_tags())
+    }))
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_tags(_lua: &Lua, id: u64) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    Ok(session_result(id, Step::Tags, |s| Ok(s.ctx.sorted_tags())))
+}
+
+fn lua_session_serialize_request_map<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, encoding) = args;
+    session_encoded(lua, id, Step::SerializeRequestMap, encoding, |s| {
+        Ok(s.ctx.request_map())
+    })
+}
+
+fn lua_session_acl_check<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, encoding) = args;
+    session_encoded(lua, id, Step::AclCheck, encoding, |s| {
+        s.ctx.acl().map_err(|rr| rr.to_string())
+    })
+}
+
+fn lua_session_limit_check<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, encoding) = args;
+    session_encoded(lua, id, Step::LimitCheck, encoding, |s| Ok(phase_value(s.ctx.limits())))
+}
+
+fn lua_session_waf_check<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, encoding) = args;
+    session_encoded(lua, id, Step::WafCheck, encoding, |s| Ok(phase_value(s.ctx.waf())))
+}
+
+/// the complete inspection of a session, as returned by `inspect_request`
+fn lua_session_decision<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<LuaTable>, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, lua_grasshopper, encoding) = args;
+    let grasshopper = lua_grasshopper.map(Luagrasshopper);
+    session_encoded(lua, id, Step::Decision, encoding, |s| Ok(s.ctx.decision(grasshopper)))
+}
+
+/// combines the outcomes of the checks of a session that were run, as `inspect_request` would
+fn lua_session_final_decision<'lua>(
+    lua: &'lua Lua,
+    args: (u64, Option<String>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<String>)> {
+    let (id, encoding) = args;
+    session_encoded(lua, id, Step::FinalDecision, encoding, |s| Ok(s.ctx.final_decision()))
+}
+
+/// replaces the tags of a session
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_set_tags(_lua: &Lua, args: (u64, Vec<String>)) -> LuaResult<(Option<bool>, Option<String>)> {
+    let (id, tags) = args;
+    Ok(session_result(id, Step::SetTags, |s| {
+        s.ctx.set_tags(&tags);
+        Ok(true)
+    }))
+}
+
+/// tags the request again, when it was already tagged
+fn retag(session: &mut Session) {
+    if session.phases.tagged() {
+        session.ctx.tag();
+    }
+}
+
+/// selects the security policy of a session by hostmap name
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_override_urlmap(_lua: &Lua, args: (u64, String)) -> LuaResult<(Option<bool>, Option<String>)> {
+    let (id, name) = args;
+    Ok(session_result(id, Step::OverrideUrlmap, |s| {
+        s.ctx.override_urlmap(&name)?;
+        retag(s);
+        Ok(true)
+    }))
+}
+
+/// overrides the geolocation fields of a session, from a table such as `{country_iso = "FR", asn = 1234}`
+fn lua_session_set_geo(lua: &Lua, args: (u64, LuaValue)) -> LuaResult<(Option<bool>, Option<String>)> {
+    let (id, lua_geo) = args;
+    let geo: GeoOverride = match lua.from_value(lua_geo) {
+        Ok(g) => g,
+        Err(rr) => return Ok((None, Some(rr.to_string()))),
+    };
+    Ok(session_result(id, Step::SetGeo, |s| {
+        s.ctx.set_geo(geo);
+        retag(s);
+        Ok(true)
+    }))
+}
+
+/// counts the status of the upstream response of a session, for the limits on the responses
+///
+/// returns the number of counters that were hit, or an error message
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_report_response(_lua: &Lua, args: (u64, u32)) -> LuaResult<(Option<usize>, Option<String>)> {
+    let (id, status) = args;
+    Ok(session_result(id, Step::ReportResponse, |s| {
+        Ok(s.ctx.report_response(status))
+    }))
+}
+
+/// releases a session, returning whether it was still live
+#[allow(clippy::unnecessary_wraps)]
+fn lua_session_clean(_lua: &Lua, id: u64) -> LuaResult<bool> {
+    Ok(sessions::remove(id))
+}
+
+// ******************************************
+// PROCESS LOGS
+// ******************************************
+
+/// sets the levels of the process logs, with directives such as `warn,curiefense::redis=debug`
+///
+/// returns an error message, or `nil`
+#[allow(clippy::unnecessary_wraps)]
+fn lua_set_log_levels(_lua: &Lua, directives: String) -> LuaResult<Option<String>> {
+    Ok(diagnostics::set_levels(&directives).err())
+}
+
+/// loads the configuration, compiles the content filter databases and opens the GeoIP databases, so that the first
+/// inspection does not pay for it
+///
+/// returns a JSON report, whose `warnings` include the configuration loading errors, or an error message
+#[allow(clippy::unnecessary_wraps)]
+fn lua_preload(_lua: &Lua, configpath: Option<String>) -> LuaResult<(Option<String>, Option<String>)> {
+    let mut logs = Logs::new(LogLevel::Warning);
+    let configpath = configpath.unwrap_or_else(|| "/cf-config/current/config".to_string());
+    Ok(match preload(&configpath, &mut logs) {
+        Ok(mut report) => {
+            report.warnings.extend(logs.logs.into_iter().map(|l| l.message));
+            match serde_json::to_string(&report) {
+                Ok(json) => (Some(json), None),
+                Err(rr) => (None, Some(rr.to_string())),
+            }
+        }
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// the configuration revision and the content filter matcher of the process, as JSON
+fn lua_config_info(_lua: &Lua, configpath: Option<String>) -> LuaResult<String> {
+    let mut logs = Logs::new(LogLevel::Warning);
+    let configpath = configpath.unwrap_or_else(|| "/cf-config/current/config".to_string());
+    serde_json::to_string(&config_info(&configpath, &mut logs)).map_err(LuaError::external)
+}
+
+/// the most recent process logs, oldest first
+#[allow(clippy::unnecessary_wraps)]
+fn lua_recent_logs(_lua: &Lua, _: ()) -> LuaResult<Vec<String>> {
+    Ok(diagnostics::recent_events())
+}
+
+/// the inspection metrics of the process, in the Prometheus text format
+#[allow(clippy::unnecessary_wraps)]
+fn lua_metrics_dump(_lua: &Lua, _: ()) -> LuaResult<String> {
+    Ok(METRICS.render())
+}
+
+/// the hit counters of the tags, ACL columns and signatures, as JSON
+#[allow(clippy::unnecessary_wraps)]
+fn lua_hits_dump(_lua: &Lua, _: ()) -> LuaResult<String> {
+    Ok(HITS.to_json())
+}
+
+/// creates a set of IP networks, empty or from the output of its `serialize` method
+///
+/// returns the set, or an error message
+#[allow(clippy::unnecessary_wraps)]
+fn lua_new_ip_set(_lua: &Lua, serialized: Option<String>) -> LuaResult<(Option<LuaIpSet>, Option<String>)> {
+    Ok(match serialized.map(|s| serde_json::from_str(&s)).transpose() {
+        Ok(set) => (Some(LuaIpSet::new(set.unwrap_or_default())), None),
+        Err(rr) => (None, Some(rr.to_string())),
+    })
+}
+
+/// compiles a set of signatures, from a table of patterns and an optional table of options
+///
+/// returns the set, or an error message
+fn lua_new_sig_set(_lua: &Lua, args: (LuaTable, Option<LuaTable>)) -> LuaResult<(Option<LuaSigSet>, Option<String>)> {
+    let (patterns, options) = args;
+    Ok(
+        match SigSet::new(sigset::patterns(patterns)?, sigset::options(options)?) {
+            Ok(set) => (Some(LuaSigSet(Arc::new(set))), None),
+            Err(rr) => (None, Some(rr)),
+        },
+    )
+}
+
+/// the numeric value of an IP: an integer for IPv4, a string of 32 hexadecimal digits for IPv6
+#[allow(clippy::unnecessary_wraps)]
+fn lua_iptonum<'lua>(lua: &'lua Lua, ip: String) -> LuaResult<(Option<LuaValue<'lua>>, Option<String>)> {
+    Ok(match ip.parse::<std::net::IpAddr>() {
+        Ok(addr @ std::net::IpAddr::V4(_)) => (Some(LuaValue::Integer(ip_to_num(addr) as i64)), None),
+        Ok(addr) => (
+            Some(LuaValue::String(
+                lua.create_string(format!("{:032x}", ip_to_num(addr)).as_bytes())?,
+            )),
+            None,
+        ),
+        Err(rr) => (None, Some(format!("invalid ip {}: {}", ip, rr))),
+    })
+}
+
+/// hashes a byte string into `0..modulo`, with an optional algorithm (`md5`, `sha256` or `fnv1a`)
+#[allow(clippy::unnecessary_wraps)]
+fn lua_modhash(_lua: &Lua, args: (LuaString, u64, Option<String>)) -> LuaResult<(Option<u64>, Option<String>)> {
+    let (data, modulo, algorithm) = args;
+    let res = HashAlgorithm::from_name(algorithm.as_deref()).and_then(|a| modhash(data.as_bytes(), modulo, a));
+    Ok(match res {
+        Ok(h) => (Some(h), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// converts the results of the CIDR helpers
+fn cidr_result<T>(res: Result<T, String>) -> (Option<T>, Option<String>) {
+    match res {
+        Ok(r) => (Some(r), None),
+        Err(rr) => (None, Some(rr)),
+    }
+}
+
+/// the second network (or address) is included in the first one
+#[allow(clippy::unnecessary_wraps)]
+fn lua_cidr_contains(_lua: &Lua, args: (String, String)) -> LuaResult<(Option<bool>, Option<String>)> {
+    Ok(cidr_result(cidr_contains(&args.0, &args.1)))
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn lua_cidr_overlaps(_lua: &Lua, args: (String, String)) -> LuaResult<(Option<bool>, Option<String>)> {
+    Ok(cidr_result(cidr_overlaps(&args.0, &args.1)))
+}
+
+/// the networks covering a range of addresses
+#[allow(clippy::unnecessary_wraps)]
+fn lua_range_to_cidrs(_lua: &Lua, args: (String, String)) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    let res = range_to_cidrs(&args.0, &args.1).map(|nets| nets.iter().map(|n| n.to_string()).collect());
+    Ok(cidr_result(res))
+}
+
+/// merges a list of networks
+#[allow(clippy::unnecessary_wraps)]
+fn lua_aggregate_cidrs(_lua: &Lua, networks: Vec<String>) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    let res = aggregate_cidrs(&networks).map(|nets| nets.iter().map(|n| n.to_string()).collect());
+    Ok(cidr_result(res))
+}
+
+/// compiles a regex under a name, with optional flags (`i`, `m`, `s`, `x` or `U`)
+///
+/// returns true, or nil and an error message
+#[allow(clippy::unnecessary_wraps)]
+fn lua_compile_regex(_lua: &Lua, args: (String, String, Option<String>)) -> LuaResult<(Option<bool>, Option<String>)> {
+    let (name, pattern, flags) = args;
+    let res = match REGEXES.lock() {
+        Ok(mut cache) => cache.insert(&name, &pattern, flags.as_deref().unwrap_or_default()),
+        Err(rr) => Err(rr.to_string()),
+    };
+    Ok(match res {
+        Ok(()) => (Some(true), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// matches a value against a regex compiled with `compile_regex`
+///
+/// returns whether it matched, or nil and an error message
+#[allow(clippy::unnecessary_wraps)]
+fn lua_regex_match(_lua: &Lua, args: (String, LuaString)) -> LuaResult<(Option<bool>, Option<String>)> {
+    let (name, value) = args;
+    // the lock is not held while matching
+    let regex = match REGEXES.lock() {
+        Ok(mut cache) => cache.get(&name),
+        Err(rr) => Err(rr.to_string()),
+    };
+    Ok(match regex {
+        Ok(re) => (Some(re.is_match(&String::from_utf8_lossy(value.as_bytes()))), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// the names of the configured DNSBL zones that list an IP
+#[allow(clippy::unnecessary_wraps)]
+fn lua_dnsbl_lookup(_lua: &Lua, ip: String) -> LuaResult<(Option<Vec<String>>, Option<String>)> {
+    let addr = match ip.parse::<std::net::IpAddr>() {
+        Ok(a) => a,
+        Err(rr) => return Ok((None, Some(format!("invalid ip {}: {}", ip, rr)))),
+    };
+    let dnsbl = match DNSBL.as_ref() {
+        Some(d) => d,
+        None => return Ok((Some(Vec::new()), None)),
+    };
+    let mut logs = Logs::default();
+    let listed = async_std::task::block_on(dnsbl.listed(&mut logs, addr));
+    Ok((Some(listed.into_iter().map(|n| n.to_string()).collect()), None))
+}
+
+/// percent encodes a byte string for an URL component (`component`, the default, `path`, `query` or `form`)
+#[allow(clippy::unnecessary_wraps)]
+fn lua_encodeurl(_lua: &Lua, args: (LuaString, Option<String>)) -> LuaResult<(Option<String>, Option<String>)> {
+    let (input, component) = args;
+    Ok(match UrlComponent::from_name(component.as_deref()) {
+        Ok(c) => (Some(urlencode(input.as_bytes(), c)), None),
+        Err(rr) => (None, Some(rr)),
+    })
+}
+
+/// decodes an URL component, strictly or not
+///
+/// returns the decoded value, whether it still looks encoded, and an error message
+#[allow(clippy::type_complexity)]
+fn lua_decodeurl<'lua>(
+    lua: &'lua Lua,
+    args: (LuaString, Option<String>, Option<bool>),
+) -> LuaResult<(Option<LuaString<'lua>>, Option<bool>, Option<String>)> {
+    let (input, component, strict) = args;
+    let res = UrlComponent::from_name(component.as_deref())
+        .and_then(|c| urldecode_component(input.as_bytes(), c, strict.unwrap_or(false)));
+    Ok(match res {
+        Ok(decoded) => (
+            Some(lua.create_string(&decoded.value)?),
+            Some(decoded.double_encoded),
+            None,
+        ),
+        Err(rr) => (None, None, Some(rr)),
+    })
+}
+
+/// the IP set registered under this name, or nil
+#[allow(clippy::unnecessary_wraps)]
+fn lua_get_ip_set(_lua: &Lua, name: String) -> LuaResult<Option<LuaIpSet>> {
+    Ok(IP_SETS.get(&name).map(LuaIpSet))
+}
+
+/// the signature set registered under this name, or nil
+#[allow(clippy::unnecessary_wraps)]
+fn lua_get_sig_set(_lua: &Lua, name: String) -> LuaResult<Option<LuaSigSet>> {
+    Ok(SIG_SETS.get(&name).map(LuaSigSet))
+}
+
+/// the geolocation of an IP, as JSON
+#[allow(clippy::unnecessary_wraps)]
+fn lua_geoip_lookup(_lua: &Lua, ip: String) -> LuaResult<(Option<String>, Option<String>)> {
+    if let Err(rr) = ip.parse::<std::net::IpAddr>() {
+        return Ok((None, Some(format!("invalid ip {}: {}", ip, rr))));
+    }
+    let mut logs = Logs::default();
+    let geo = Geo::new(&find_geoip(&mut logs, ip));
+    Ok(match serde_json::to_string(&geo) {
+        Ok(s) => (Some(s), None),
+        Err(rr) => (None, Some(rr.to_string())),
+    })
+}
+
+/// records the response status of a login attempt, returns false when the request was not one
+#[allow(clippy::unnecessary_wraps)]
+fn lua_login_result(_lua: &Lua, args: (String, u32)) -> LuaResult<bool> {
+    let (request_id, status) = args;
+    Ok(curiefense::login::record_result(&request_id, status))
+}
+
 #[mlua::lua_module]
 fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
+    // fails when the process already has a subscriber, which then receives the events
+    let _ = diagnostics::init(diagnostics::DEFAULT_DIRECTIVES);
+
     let exports = lua.create_table()?;
 
     // end-to-end inspection
     exports.set("inspect_request", lua.create_function(lua_inspect_request)?)?;
+    // end-to-end inspection, yielding while waiting for I/O
+    exports.set(
+        "inspect_request_async",
+        lua.create_async_function(lua_inspect_request_async)?,
+    )?;
+    // end-to-end inspection, returning a decision userdata
+    exports.set("inspect", lua.create_function(lua_inspect)?)?;
+    exports.set("inspect_async", lua.create_async_function(lua_inspect_async)?)?;
+    // end-to-end inspection, for OpenResty
+    exports.set("inspect_nginx", lua.create_async_function(lua_inspect_nginx)?)?;
+    // end-to-end inspection of a list of JSON encoded requests
+    exports.set("inspect_batch", lua.create_function(lua_inspect_batch)?)?;
+    exports.set("cache_key", lua.create_function(lua_cache_key)?)?;
     // end-to-end inspection (test)
     exports.set("test_inspect_request", lua.create_function(lua_test_inspect_request)?)?;
     // content filter inspection
@@ -241,6 +1055,61 @@ fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
         lua.create_function(lua_inspect_content_filter)?,
     )?;
 
+    // phase by phase inspection
+    exports.set("new_context", lua.create_function(lua_new_context)?)?;
+    // phase by phase inspection, by session id
+    exports.set("session_init", lua.create_function(lua_session_init)?)?;
+    exports.set("session_tag_request", lua.create_function(lua_session_tag_request)?)?;
+    exports.set("session_tags", lua.create_function(lua_session_tags)?)?;
+    exports.set(
+        "session_serialize_request_map",
+        lua.create_function(lua_session_serialize_request_map)?,
+    )?;
+    exports.set("session_acl_check", lua.create_function(lua_session_acl_check)?)?;
+    exports.set("session_limit_check", lua.create_function(lua_session_limit_check)?)?;
+    exports.set("session_waf_check", lua.create_function(lua_session_waf_check)?)?;
+    exports.set("session_decision", lua.create_function(lua_session_decision)?)?;
+    exports.set(
+        "session_final_decision",
+        lua.create_function(lua_session_final_decision)?,
+    )?;
+    exports.set("session_set_tags", lua.create_function(lua_session_set_tags)?)?;
+    exports.set(
+        "session_override_urlmap",
+        lua.create_function(lua_session_override_urlmap)?,
+    )?;
+    exports.set("session_set_geo", lua.create_function(lua_session_set_geo)?)?;
+    exports.set(
+        "session_report_response",
+        lua.create_function(lua_session_report_response)?,
+    )?;
+    exports.set("session_clean", lua.create_function(lua_session_clean)?)?;
+    // process logs
+    // worker initialization
+    exports.set("preload", lua.create_function(lua_preload)?)?;
+    exports.set("config_info", lua.create_function(lua_config_info)?)?;
+    exports.set("set_log_levels", lua.create_function(lua_set_log_levels)?)?;
+    exports.set("recent_logs", lua.create_function(lua_recent_logs)?)?;
+    exports.set("metrics_dump", lua.create_function(lua_metrics_dump)?)?;
+    exports.set("hits_dump", lua.create_function(lua_hits_dump)?)?;
+    exports.set("geoip_lookup", lua.create_function(lua_geoip_lookup)?)?;
+    exports.set("login_result", lua.create_function(lua_login_result)?)?;
+    exports.set("new_ip_set", lua.create_function(lua_new_ip_set)?)?;
+    exports.set("new_sig_set", lua.create_function(lua_new_sig_set)?)?;
+    exports.set("get_ip_set", lua.create_function(lua_get_ip_set)?)?;
+    exports.set("get_sig_set", lua.create_function(lua_get_sig_set)?)?;
+    exports.set("iptonum", lua.create_function(lua_iptonum)?)?;
+    exports.set("modhash", lua.create_function(lua_modhash)?)?;
+    exports.set("cidr_contains", lua.create_function(lua_cidr_contains)?)?;
+    exports.set("cidr_overlaps", lua.create_function(lua_cidr_overlaps)?)?;
+    exports.set("range_to_cidrs", lua.create_function(lua_range_to_cidrs)?)?;
+    exports.set("aggregate_cidrs", lua.create_function(lua_aggregate_cidrs)?)?;
+    exports.set("dnsbl_lookup", lua.create_function(lua_dnsbl_lookup)?)?;
+    exports.set("compile_regex", lua.create_function(lua_compile_regex)?)?;
+    exports.set("regex_match", lua.create_function(lua_regex_match)?)?;
+    exports.set("encodeurl", lua.create_function(lua_encodeurl)?)?;
+    exports.set("decodeurl", lua.create_function(lua_decodeurl)?)?;
+
     Ok(exports)
 }
 
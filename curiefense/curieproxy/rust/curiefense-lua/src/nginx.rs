@@ -0,0 +1,108 @@
+use mlua::prelude::*;
+use std::collections::HashMap;
+
+/// meta keys, and the nginx variables they are read from
+const NGINX_VARS: &[(&str, &str)] = &[
+    ("path", "request_uri"),
+    ("scheme", "scheme"),
+    ("port", "server_port"),
+    ("http_version", "server_protocol"),
+    // set when the listener accepts the PROXY protocol
+    ("proxy_protocol_src_ip", "proxy_protocol_addr"),
+    ("proxy_protocol_src_port", "proxy_protocol_port"),
+    ("proxy_protocol_dst_ip", "proxy_protocol_server_addr"),
+    ("proxy_protocol_dst_port", "proxy_protocol_server_port"),
+    ("proxy_protocol_trusted", "curiefense_proxy_protocol_trusted"),
+];
+
+pub struct NginxRequest {
+    pub meta: HashMap<String, String>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub ip: String,
+}
+
+/// the value of a header, that is a list of values when the header is repeated
+fn header_value(value: LuaValue) -> Option<Vec<u8>> {
+    match value {
+        LuaValue::String(s) => Some(s.as_bytes().to_vec()),
+        LuaValue::Table(t) => {
+            let values: Vec<Vec<u8>> = t
+                .sequence_values::<LuaString>()
+                .filter_map(|v| v.ok())
+                .map(|v| v.as_bytes().to_vec())
+                .collect();
+            Some(values.join(&b", "[..]))
+        }
+        _ => None,
+    }
+}
+
+/// the comma separated header names, in the order they were received, from the raw header block
+fn header_order(raw_header: &str) -> String {
+    raw_header
+        .lines()
+        .filter_map(|l| l.split_once(':').map(|(name, _)| name.trim()))
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl NginxRequest {
+    pub fn new(ngx: &LuaTable, body_truncated: bool) -> LuaResult<Self> {
+        let var: LuaTable = ngx.get("var")?;
+        let req: LuaTable = ngx.get("req")?;
+
+        let mut meta = HashMap::new();
+        for (key, name) in NGINX_VARS {
+            // unset variables, such as the PROXY protocol ones without the PROXY protocol, are empty
+            match var.get::<_, Option<String>>(*name)? {
+                Some(value) if !value.is_empty() => {
+                    meta.insert(key.to_string(), value);
+                }
+                _ => (),
+            }
+        }
+        let method: String = req.get::<_, LuaFunction>("get_method")?.call(())?;
+        meta.insert("method".to_string(), method);
+        // raw_header raises an error for the HTTP/2 and HTTP/3 requests, whose header order is then left unset
+        let http_version: Option<f64> = req.get::<_, LuaFunction>("http_version")?.call(())?;
+        let raw_header: Option<String> = match http_version {
+            Some(v) if v < 2.0 => req.get::<_, LuaFunction>("raw_header")?.call(true)?,
+            _ => None,
+        };
+        if let Some(raw) = raw_header {
+            meta.insert("header_order".to_string(), header_order(&raw));
+        }
+        if body_truncated {
+            meta.insert("body_truncated".to_string(), "true".to_string());
+        }
+
+        // 0 disables the default limit of 100 headers
+        let (lua_headers, _): (LuaTable, Option<String>) = req.get::<_, LuaFunction>("get_headers")?.call(0)?;
+        let headers = lua_headers
+            .pairs::<String, LuaValue>()
+            .filter_map(|kv| kv.ok())
+            .filter_map(|(k, v)| header_value(v).map(|v| (k.to_lowercase(), v)))
+            .collect();
+
+        Ok(NginxRequest {
+            meta,
+            headers,
+            ip: var.get::<_, Option<String>>("remote_addr")?.unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_header_order() {
+        assert_eq!(
+            header_order("Host: example.com\r\nUser-Agent: curl/7.1\r\nAccept: */*\r\n\r\n"),
+            "Host,User-Agent,Accept"
+        );
+        assert_eq!(header_order(""), "");
+    }
+}
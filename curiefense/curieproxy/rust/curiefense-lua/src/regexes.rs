@@ -0,0 +1,129 @@
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// the maximum size of a compiled regex, so that a pathological pattern fails to compile instead of eating the memory
+const SIZE_LIMIT: usize = 1 << 20;
+
+lazy_static! {
+    pub static ref REGEXES: Mutex<Cache> = Mutex::new(Cache::new(
+        std::env::var("CURIEFENSE_REGEX_CACHE_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000)
+    ));
+}
+
+struct Compiled {
+    pattern: String,
+    flags: String,
+    regex: Arc<Regex>,
+    last_used: u64,
+}
+
+/// the compiled regexes, by name, the least recently used being evicted when full
+pub struct Cache {
+    capacity: usize,
+    tick: u64,
+    regexes: HashMap<String, Compiled>,
+}
+
+/// compiles a pattern, with flags such as `i` for case insensitivity, or `s` so that `.` matches new lines
+pub fn compile(pattern: &str, flags: &str) -> Result<Regex, String> {
+    let mut builder = RegexBuilder::new(pattern);
+    builder.size_limit(SIZE_LIMIT).dfa_size_limit(SIZE_LIMIT);
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            'U' => builder.swap_greed(true),
+            other => return Err(format!("unknown regex flag {}, expected i, m, s, x or U", other)),
+        };
+    }
+    builder.build().map_err(|rr| rr.to_string())
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity: capacity.max(1),
+            tick: 0,
+            regexes: HashMap::new(),
+        }
+    }
+
+    /// compiles the pattern under this name, unless it is already compiled with the same flags
+    pub fn insert(&mut self, name: &str, pattern: &str, flags: &str) -> Result<(), String> {
+        self.tick += 1;
+        if let Some(c) = self.regexes.get_mut(name) {
+            if c.pattern == pattern && c.flags == flags {
+                c.last_used = self.tick;
+                return Ok(());
+            }
+        }
+        let regex = Arc::new(compile(pattern, flags)?);
+        if !self.regexes.contains_key(name) && self.regexes.len() >= self.capacity {
+            if let Some(lru) = self
+                .regexes
+                .iter()
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(n, _)| n.clone())
+            {
+                self.regexes.remove(&lru);
+            }
+        }
+        self.regexes.insert(
+            name.to_string(),
+            Compiled {
+                pattern: pattern.to_string(),
+                flags: flags.to_string(),
+                regex,
+                last_used: self.tick,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<Arc<Regex>, String> {
+        self.tick += 1;
+        match self.regexes.get_mut(name) {
+            Some(c) => {
+                c.last_used = self.tick;
+                Ok(c.regex.clone())
+            }
+            None => Err(format!("unknown regex {}, it was not compiled or was evicted", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut cache = Cache::new(2);
+        cache.insert("a", "^a+$", "i").unwrap();
+        cache.insert("b", "^b", "").unwrap();
+        assert!(cache.get("a").unwrap().is_match("AAA"));
+        cache.insert("c", "c", "").unwrap();
+        // b was the least recently used
+        assert!(cache.get("b").is_err());
+        assert!(cache.get("a").is_ok());
+        assert!(cache.get("c").is_ok());
+
+        // recompiled when the pattern changes
+        cache.insert("a", "^b+$", "").unwrap();
+        assert!(!cache.get("a").unwrap().is_match("aaa"));
+
+        assert_eq!(
+            cache.insert("d", "x", "q").err(),
+            Some("unknown regex flag q, expected i, m, s, x or U".to_string())
+        );
+        assert!(cache.insert("d", "(", "").is_err());
+        assert!(cache.insert("d", "\\w{1000}\\w{1000}\\w{1000}", "").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use crate::context::RequestContext;
+use curiefense::utils::env_or;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref SESSIONS: Mutex<Registry<Session>> = Mutex::new(Registry::new(
+        env_or("CURIEFENSE_MAX_SESSIONS", 10000usize).max(1),
+        Duration::from_secs(env_or("CURIEFENSE_SESSION_TTL_SECS", 60).max(1)),
+    ));
+}
+
+/// the session functions, whose order is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    TagRequest,
+    Tags,
+    SerializeRequestMap,
+    AclCheck,
+    LimitCheck,
+    WafCheck,
+    Decision,
+    FinalDecision,
+    SetTags,
+    OverrideUrlmap,
+    SetGeo,
+    ReportResponse,
+}
+
+impl Step {
+    pub fn name(self) -> &'static str {
+        match self {
+            Step::TagRequest => "session_tag_request",
+            Step::Tags => "session_tags",
+            Step::SerializeRequestMap => "session_serialize_request_map",
+            Step::AclCheck => "session_acl_check",
+            Step::LimitCheck => "session_limit_check",
+            Step::WafCheck => "session_waf_check",
+            Step::Decision => "session_decision",
+            Step::FinalDecision => "session_final_decision",
+            Step::SetTags => "session_set_tags",
+            Step::OverrideUrlmap => "session_override_urlmap",
+            Step::SetGeo => "session_set_geo",
+            Step::ReportResponse => "session_report_response",
+        }
+    }
+
+    /// the steps that run once, the response being reported once too
+    fn is_check(self) -> bool {
+        matches!(
+            self,
+            Step::AclCheck | Step::LimitCheck | Step::WafCheck | Step::ReportResponse
+        )
+    }
+}
+
+/// the phases that already ran
+#[derive(Debug, Default)]
+pub struct Phases {
+    tagged: bool,
+    checks: Vec<Step>,
+}
+
+impl Phases {
+    /// records a step, unless it is out of order
+    pub fn enter(&mut self, step: Step) -> Result<(), String> {
+        match step {
+            Step::TagRequest if self.tagged => Err(format!("{} was already called", step.name())),
+            Step::TagRequest => {
+                self.tagged = true;
+                Ok(())
+            }
+            Step::OverrideUrlmap | Step::SetGeo => match self.checks.first() {
+                Some(check) => Err(format!("{} called after {}", step.name(), check.name())),
+                None => Ok(()),
+            },
+            _ if !self.tagged => Err(format!("{} called before {}", step.name(), Step::TagRequest.name())),
+            _ if step.is_check() && self.checks.contains(&step) => Err(format!("{} was already called", step.name())),
+            _ if step.is_check() => {
+                self.checks.push(step);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn tagged(&self) -> bool {
+        self.tagged
+    }
+}
+
+pub struct Session {
+    pub ctx: RequestContext,
+    pub phases: Phases,
+}
+
+/// why a session is not in the registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gone {
+    Unknown,
+    Cleaned,
+    Expired,
+    Evicted,
+}
+
+impl Gone {
+    fn describe(self, id: u64) -> String {
+        match self {
+            Gone::Unknown => format!("unknown session {}", id),
+            Gone::Cleaned => format!("session {} was cleaned", id),
+            Gone::Expired => format!("session {} expired", id),
+            Gone::Evicted => format!("session {} was evicted, too many sessions are live", id),
+        }
+    }
+}
+
+struct Entry<T> {
+    value: Arc<Mutex<T>>,
+    last_used: Instant,
+}
+
+/// a map of values, bounded in size and in idle time
+pub struct Registry<T> {
+    max_sessions: usize,
+    ttl: Duration,
+    next_id: u64,
+    /// the expired sessions are not removed more often than once per second
+    next_purge: Instant,
+    sessions: HashMap<u64, Entry<T>>,
+    /// the most recently removed sessions, as many as the live sessions at most
+    removed: VecDeque<(u64, Gone)>,
+}
+
+impl<T> Registry<T> {
+    pub fn new(max_sessions: usize, ttl: Duration) -> Self {
+        Registry {
+            max_sessions: max_sessions.max(1),
+            ttl,
+            next_id: 1,
+            next_purge: Instant::now(),
+            sessions: HashMap::new(),
+            removed: VecDeque::new(),
+        }
+    }
+
+    fn removal(&mut self, id: u64, reason: Gone) {
+        if self.removed.len() >= self.max_sessions {
+            self.removed.pop_front();
+        }
+        self.removed.push_back((id, reason));
+    }
+
+    pub fn insert(&mut self, value: T, now: Instant) -> u64 {
+        if now >= self.next_purge {
+            let ttl = self.ttl;
+            let expired: Vec<u64> = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| now.duration_since(s.last_used) >= ttl)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in expired {
+                self.sessions.remove(&id);
+                self.removal(id, Gone::Expired);
+            }
+            self.next_purge = now + Duration::from_secs(1);
+        }
+        while self.sessions.len() >= self.max_sessions {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(id, s)| (s.last_used, **id))
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(id) => {
+                    self.sessions.remove(&id);
+                    self.removal(id, Gone::Evicted);
+                }
+                None => break,
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(
+            id,
+            Entry {
+                value: Arc::new(Mutex::new(value)),
+                last_used: now,
+            },
+        );
+        id
+    }
+
+    /// the value of a live session, marking it as used
+    pub fn get(&mut self, id: u64, now: Instant) -> Result<Arc<Mutex<T>>, Gone> {
+        let ttl = self.ttl;
+        match self.sessions.get_mut(&id) {
+            Some(s) if now.duration_since(s.last_used) < ttl => {
+                s.last_used = now;
+                Ok(s.value.clone())
+            }
+            Some(_) => {
+                self.sessions.remove(&id);
+                self.removal(id, Gone::Expired);
+                Err(Gone::Expired)
+            }
+            None => Err(self
+                .removed
+                .iter()
+                .rev()
+                .find(|(rid, _)| *rid == id)
+                .map(|(_, reason)| *reason)
+                .unwrap_or(Gone::Unknown)),
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) -> bool {
+        let found = self.sessions.remove(&id).is_some();
+        if found {
+            self.removal(id, Gone::Cleaned);
+        }
+        found
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+/// the encoding of the documents returned by the session functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// JSON by default
+    pub fn from_name(name: Option<&str>) -> Result<Self, String> {
+        match name {
+            None | Some("json") => Ok(Encoding::Json),
+            Some("msgpack") => Ok(Encoding::MessagePack),
+            Some(other) => Err(format!("unknown encoding {}", other)),
+        }
+    }
+
+    pub fn encode(self, value: &serde_json::Value) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|rr| rr.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec(value).map_err(|rr| rr.to_string()),
+        }
+    }
+}
+
+pub fn insert(ctx: RequestContext) -> Result<u64, String> {
+    let mut sessions = SESSIONS.lock().map_err(|rr| rr.to_string())?;
+    Ok(sessions.insert(
+        Session {
+            ctx,
+            phases: Phases::default(),
+        },
+        Instant::now(),
+    ))
+}
+
+/// runs a step of a session, when it is in order, the registry being unlocked while it runs
+pub fn with_session<R, F: FnOnce(&mut Session) -> R>(id: u64, step: Step, f: F) -> Result<R, String> {
+    let session = SESSIONS
+        .lock()
+        .map_err(|rr| rr.to_string())?
+        .get(id, Instant::now())
+        .map_err(|gone| gone.describe(id))?;
+    let mut session = session.lock().map_err(|rr| rr.to_string())?;
+    session.phases.enter(step)?;
+    Ok(f(&mut session))
+}
+
+pub fn remove(id: u64) -> bool {
+    SESSIONS.lock().map(|mut s| s.remove(id)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let start = Instant::now();
+        let mut registry = Registry::new(2, Duration::from_secs(60));
+        let a = registry.insert("a", start);
+        let b = registry.insert("b", start + Duration::from_millis(1));
+        assert!(registry.get(a, start + Duration::from_millis(2)).is_ok());
+        // b is the least recently used
+        let c = registry.insert("c", start + Duration::from_millis(3));
+        assert_eq!(registry.len(), 2);
+        assert_eq!(
+            registry.get(b, start + Duration::from_millis(4)).err(),
+            Some(Gone::Evicted)
+        );
+        assert_eq!(
+            *registry
+                .get(c, start + Duration::from_millis(4))
+                .unwrap()
+                .lock()
+                .unwrap(),
+            "c"
+        );
+        assert!(registry.remove(a));
+        assert!(!registry.remove(a));
+        assert_eq!(
+            registry.get(a, start + Duration::from_millis(5)).err(),
+            Some(Gone::Cleaned)
+        );
+        assert_eq!(registry.get(42, start).err(), Some(Gone::Unknown));
+    }
+
+    #[test]
+    fn encodings() {
+        let value = serde_json::json!({"action": "pass", "tags": ["all", "human"], "status": 403, "response": null});
+        assert_eq!(Encoding::from_name(None), Ok(Encoding::Json));
+        assert!(Encoding::from_name(Some("xml")).is_err());
+        let json = Encoding::Json.encode(&value).unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&json).unwrap(), value);
+        let msgpack = Encoding::from_name(Some("msgpack")).unwrap().encode(&value).unwrap();
+        assert!(msgpack.len() < json.len());
+        assert_eq!(rmp_serde::from_slice::<serde_json::Value>(&msgpack).unwrap(), value);
+    }
+
+    #[test]
+    fn expiry() {
+        let start = Instant::now();
+        let mut registry = Registry::new(10, Duration::from_secs(60));
+        let a = registry.insert("a", start);
+        let b = registry.insert("b", start);
+        assert!(registry.get(a, start + Duration::from_secs(30)).is_ok());
+        // abandoned sessions expire
+        assert_eq!(
+            registry.get(b, start + Duration::from_secs(61)).err(),
+            Some(Gone::Expired)
+        );
+        assert!(registry.get(a, start + Duration::from_secs(61)).is_ok());
+        // and are purged when inserting
+        registry.insert("c", start + Duration::from_secs(200));
+        assert_eq!(registry.len(), 1);
+        assert_eq!(
+            registry.get(a, start + Duration::from_secs(200)).err(),
+            Some(Gone::Expired)
+        );
+    }
+
+    #[test]
+    fn phase_order() {
+        let mut phases = Phases::default();
+        assert_eq!(
+            phases.enter(Step::AclCheck),
+            Err("session_acl_check called before session_tag_request".to_string())
+        );
+        assert!(phases.enter(Step::SetGeo).is_ok());
+        assert!(phases.enter(Step::TagRequest).is_ok());
+        assert!(phases.enter(Step::TagRequest).is_err());
+        assert!(phases.enter(Step::OverrideUrlmap).is_ok());
+        assert!(phases.enter(Step::LimitCheck).is_ok());
+        assert_eq!(
+            phases.enter(Step::LimitCheck),
+            Err("session_limit_check was already called".to_string())
+        );
+        assert_eq!(
+            phases.enter(Step::SetGeo),
+            Err("session_set_geo called after session_limit_check".to_string())
+        );
+        assert!(phases.enter(Step::AclCheck).is_ok());
+        assert!(phases.enter(Step::SetTags).is_ok());
+        assert!(phases.enter(Step::Decision).is_ok());
+        assert!(phases.enter(Step::Decision).is_ok());
+        assert!(phases.enter(Step::ReportResponse).is_ok());
+        assert_eq!(
+            phases.enter(Step::ReportResponse),
+            Err("session_report_response was already called".to_string())
+        );
+    }
+}
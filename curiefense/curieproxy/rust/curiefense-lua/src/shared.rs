@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// objects registered under a name, so that all the Lua states of the process can use them without building them
+/// again
+pub struct Shared<T>(RwLock<HashMap<String, Arc<T>>>);
+
+impl<T> Shared<T> {
+    pub fn new() -> Self {
+        Shared(RwLock::new(HashMap::new()))
+    }
+
+    /// registers the object, replacing the one that had the same name
+    pub fn register(&self, name: String, value: Arc<T>) {
+        if let Ok(mut objects) = self.0.write() {
+            objects.insert(name, value);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<T>> {
+        self.0.read().ok().and_then(|objects| objects.get(name).cloned())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::shared::Shared;
+use curiefense::sigset::{SigSet, SigSetOptions};
+use lazy_static::lazy_static;
+use mlua::prelude::*;
+use std::sync::Arc;
+
+lazy_static! {
+    pub static ref SIG_SETS: Shared<SigSet> = Shared::new();
+}
+
+/// a set of signatures, for the Lua code, shared with the other Lua states once registered
+pub struct LuaSigSet(pub Arc<SigSet>);
+
+/// reads the patterns, from a list (their ids being their positions) or from a table of ids to patterns
+pub fn patterns(table: LuaTable) -> LuaResult<Vec<(String, Vec<u8>)>> {
+    let mut out = Vec::new();
+    for pair in table.pairs::<LuaValue, LuaString>() {
+        let (k, pattern) = pair?;
+        let id = match k {
+            LuaValue::String(s) => s.to_str()?.to_string(),
+            LuaValue::Integer(i) => i.to_string(),
+            other => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: other.type_name(),
+                    to: "pattern id",
+                    message: None,
+                })
+            }
+        };
+        out.push((id, pattern.as_bytes().to_vec()));
+    }
+    // the iteration order of the tables is not specified
+    out.sort();
+    Ok(out)
+}
+
+pub fn options(table: Option<LuaTable>) -> LuaResult<SigSetOptions> {
+    Ok(match table {
+        None => SigSetOptions::default(),
+        Some(t) => SigSetOptions {
+            case_insensitive: t.get::<_, Option<bool>>("case_insensitive")?.unwrap_or(false),
+            whole_word: t.get::<_, Option<bool>>("whole_word")?.unwrap_or(false),
+        },
+    })
+}
+
+impl LuaUserData for LuaSigSet {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        // returns a list of tables with the `id`, `start` and `end` of the matches, the offsets being those of
+        // `string.find`
+        methods.add_method("matches", |lua, this, input: LuaString| {
+            let out = lua.create_table()?;
+            for (idx, m) in this.0.matches(input.as_bytes()).into_iter().enumerate() {
+                let entry = lua.create_table()?;
+                entry.set("id", m.id)?;
+                entry.set("start", m.start + 1)?;
+                entry.set("end", m.end)?;
+                out.set(idx + 1, entry)?;
+            }
+            Ok(out)
+        });
+        methods.add_method("is_match", |_, this, input: LuaString| {
+            Ok(this.0.is_match(input.as_bytes()))
+        });
+        methods.add_method("len", |_, this, ()| Ok(this.0.len()));
+        methods.add_method("register", |_, this, name: String| {
+            SIG_SETS.register(name, this.0.clone());
+            Ok(true)
+        });
+    }
+}
@@ -0,0 +1,232 @@
+use curiefense::acl::check_acl;
+use curiefense::config::hostmap::SecurityPolicy;
+use curiefense::config::with_config;
+use curiefense::grasshopper::DummyGrasshopper;
+use curiefense::interface::{Decision, SimpleDecision, Tags};
+use curiefense::limit::limit_check;
+use curiefense::logs::Logs;
+use curiefense::securitypolicy::match_securitypolicy;
+use curiefense::tagging::tag_request;
+use curiefense::utils::{map_request, RawRequest, RequestInfo, RequestMeta};
+use curiefense::{content_filter_check_generic_request_map, inspect_generic_request_map};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+const CONFIG_PATH: &str = "/cf-config/current/config";
+
+fn raw_request(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    mbody: Option<&[u8]>,
+) -> PyResult<RawRequest<'_>> {
+    let meta = RequestMeta::from_map(meta).map_err(PyValueError::new_err)?;
+    Ok(RawRequest {
+        ipstr: meta.client_ip(ip),
+        headers,
+        header_bytes: HashMap::new(),
+        meta,
+        mbody,
+    })
+}
+
+/// a request, mapped according to its security policy, with the tags that are set before the checks
+struct Mapped {
+    securitypolicy: SecurityPolicy,
+    rinfo: RequestInfo,
+    tags: Tags,
+    globalfilter_dec: SimpleDecision,
+}
+
+fn mapped(configpath: &str, raw: &RawRequest, human: bool, logs: &mut Logs) -> PyResult<Mapped> {
+    with_config(configpath, logs, |slogs, cfg| {
+        let (name, secpol) = match_securitypolicy(&raw.get_host(), &raw.meta.canonical_path(), &raw.meta, cfg, slogs)?;
+        let profile = &secpol.content_filter_profile;
+        let rinfo = map_request(
+            slogs,
+            &profile.decoding,
+            &profile.content_type,
+            profile.max_body_depth,
+            &secpol.session,
+            &profile.parse_budget,
+            profile.nested_args,
+            raw,
+        );
+        let (mut tags, globalfilter_dec) = tag_request(slogs, human, &cfg.globalfilters, &rinfo);
+        tags.insert("all");
+        tags.insert_qualified("securitypolicy", &name);
+        tags.insert_qualified("securitypolicy-entry", &secpol.name);
+        tags.insert_qualified("aclid", &secpol.acl_profile.id);
+        tags.insert_qualified("aclname", &secpol.acl_profile.name);
+        tags.insert_qualified("contentfilterid", &profile.id);
+        tags.insert_qualified("contentfiltername", &profile.name);
+        Some(Mapped {
+            securitypolicy: secpol.clone(),
+            rinfo,
+            tags,
+            globalfilter_dec,
+        })
+    })
+    .flatten()
+    .ok_or_else(|| PyValueError::new_err("no security policy matches the request"))
+}
+
+/// the request map, as logged by the proxies
+#[pyfunction]
+#[pyo3(name = "map_request", signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH, human = false))]
+fn py_map_request(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+    human: bool,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let m = mapped(configpath, &raw, human, &mut Logs::default())?;
+    Ok(m.rinfo.into_json(m.tags).to_string())
+}
+
+/// the tags of the request, sorted, including the global filter tags
+#[pyfunction]
+#[pyo3(name = "tag_request", signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH, human = false))]
+fn py_tag_request(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+    human: bool,
+) -> PyResult<Vec<String>> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let m = mapped(configpath, &raw, human, &mut Logs::default())?;
+    let mut tags: Vec<String> = m.tags.iter().map(|t| t.to_string()).collect();
+    tags.sort();
+    Ok(tags)
+}
+
+/// the result of the ACL profile of the security policy
+#[pyfunction]
+#[pyo3(name = "check_acl", signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH, human = false))]
+fn py_check_acl(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+    human: bool,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let m = mapped(configpath, &raw, human, &mut Logs::default())?;
+    serde_json::to_string(&check_acl(&m.tags, &m.securitypolicy.acl_profile))
+        .map_err(|rr| PyValueError::new_err(rr.to_string()))
+}
+
+/// the decision of the limits of the security policy, which requires access to redis
+#[pyfunction]
+#[pyo3(name = "limit_check", signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH, human = false))]
+fn py_limit_check(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+    human: bool,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let mut logs = Logs::default();
+    let m = mapped(configpath, &raw, human, &mut logs)?;
+    let mut tags = m.tags;
+    let secpol = &m.securitypolicy;
+    let decision = match async_std::task::block_on(limit_check(
+        &mut logs,
+        &secpol.name,
+        &m.rinfo,
+        &secpol.limits,
+        &mut tags,
+    )) {
+        SimpleDecision::Pass => Decision::Pass,
+        SimpleDecision::Action(action, reason) => action.to_decision(
+            human,
+            &None::<DummyGrasshopper>,
+            secpol.captcha.as_deref(),
+            &m.rinfo,
+            reason,
+        ),
+    };
+    Ok(decision.to_json(m.rinfo, tags, logs))
+}
+
+/// the decision of the global filters, that is taken along with the tagging
+#[pyfunction]
+#[pyo3(name = "globalfilter_check", signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH, human = false))]
+fn py_globalfilter_check(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+    human: bool,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let mut logs = Logs::default();
+    let m = mapped(configpath, &raw, human, &mut logs)?;
+    let decision = match m.globalfilter_dec {
+        SimpleDecision::Pass => Decision::Pass,
+        SimpleDecision::Action(action, reason) => action.to_decision(
+            human,
+            &None::<DummyGrasshopper>,
+            m.securitypolicy.captcha.as_deref(),
+            &m.rinfo,
+            reason,
+        ),
+    };
+    Ok(decision.to_json(m.rinfo, m.tags, logs))
+}
+
+/// the decision of a content filter (WAF) profile, regardless of the security policy
+#[pyfunction]
+#[pyo3(signature = (meta, headers, ip, content_filter_id, body = None, configpath = CONFIG_PATH))]
+fn inspect_content_filter(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    content_filter_id: &str,
+    body: Option<&[u8]>,
+    configpath: &str,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let mut logs = Logs::default();
+    let (decision, rinfo, tags) =
+        content_filter_check_generic_request_map(configpath, &raw, content_filter_id, &mut logs);
+    Ok(decision.to_json(rinfo, tags, logs))
+}
+
+/// the full inspection, as run by the proxies (without the grasshopper component)
+#[pyfunction]
+#[pyo3(signature = (meta, headers, ip, body = None, configpath = CONFIG_PATH))]
+fn inspect_request(
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: String,
+    body: Option<&[u8]>,
+    configpath: &str,
+) -> PyResult<String> {
+    let raw = raw_request(meta, headers, ip, body)?;
+    let mut logs = Logs::default();
+    let (decision, tags, rinfo) = inspect_generic_request_map(configpath, None::<DummyGrasshopper>, raw, &mut logs);
+    Ok(decision.to_json(rinfo, tags, logs))
+}
+
+#[pymodule]
+fn pycuriefense(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(py_map_request, m)?)?;
+    m.add_function(wrap_pyfunction!(py_tag_request, m)?)?;
+    m.add_function(wrap_pyfunction!(py_check_acl, m)?)?;
+    m.add_function(wrap_pyfunction!(py_limit_check, m)?)?;
+    m.add_function(wrap_pyfunction!(py_globalfilter_check, m)?)?;
+    m.add_function(wrap_pyfunction!(inspect_content_filter, m)?)?;
+    m.add_function(wrap_pyfunction!(inspect_request, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use curiefense::config::install_config;
+use curiefense::grasshopper::DummyGrasshopper;
+use curiefense::inspect_generic_request_map;
+use curiefense::interface::{Decision, RequestChanges};
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::metadata::{DynamicMetadata, METADATA_NAMESPACE};
+use curiefense::requestmap::Geo;
+use curiefense::utils::{RawRequest, RequestMeta};
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, ContextType};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// the name under which the configuration of the plugin is installed, see `install_config`
+const CONFIG_NAME: &str = "proxy-wasm";
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(proxy_wasm::types::LogLevel::Info);
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(CuriefenseRoot {
+            loglevel: LogLevel::Warning,
+        })
+    });
+}}
+
+/// the plugin configuration, the documents being the contents of the configuration files, by file name (see
+/// `CONFIG_FILES`)
+#[derive(Debug, Deserialize)]
+struct PluginConfiguration {
+    #[serde(default)]
+    loglevel: Option<String>,
+    documents: HashMap<String, serde_json::Value>,
+}
+
+struct CuriefenseRoot {
+    loglevel: LogLevel,
+}
+
+impl Context for CuriefenseRoot {}
+
+impl RootContext for CuriefenseRoot {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let configuration = match self.get_plugin_configuration() {
+            Some(c) => c,
+            None => {
+                log::error!("curiefense: missing plugin configuration");
+                return false;
+            }
+        };
+        let parsed: PluginConfiguration = match serde_json::from_slice(&configuration) {
+            Ok(p) => p,
+            Err(rr) => {
+                log::error!("curiefense: invalid plugin configuration: {}", rr);
+                return false;
+            }
+        };
+        if let Some(level) = parsed.loglevel {
+            match level.parse() {
+                Ok(l) => self.loglevel = l,
+                Err(rr) => log::warn!("curiefense: {}", rr),
+            }
+        }
+        let documents: HashMap<String, Vec<u8>> = parsed
+            .documents
+            .iter()
+            .filter_map(|(name, document)| serde_json::to_vec(document).ok().map(|d| (name.clone(), d)))
+            .collect();
+        let mut logs = Logs::new(LogLevel::Warning);
+        let installed = install_config(CONFIG_NAME, &mut logs, &documents);
+        forward_logs(&logs);
+        installed
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(CuriefenseFilter {
+            loglevel: self.loglevel,
+            meta: HashMap::new(),
+            headers: HashMap::new(),
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+/// the request, held until its body is complete
+struct CuriefenseFilter {
+    loglevel: LogLevel,
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+}
+
+impl Context for CuriefenseFilter {}
+
+impl HttpContext for CuriefenseFilter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        for (key, value) in self.get_http_request_headers() {
+            let key = key.to_lowercase();
+            match key.strip_prefix(':') {
+                Some(pseudo) => {
+                    self.meta.insert(pseudo.to_string(), value);
+                }
+                None => {
+                    // repeated headers are combined, as by the other proxies
+                    self.headers
+                        .entry(key)
+                        .and_modify(|v| {
+                            v.push_str(", ");
+                            v.push_str(&value)
+                        })
+                        .or_insert(value);
+                }
+            }
+        }
+        if end_of_stream {
+            self.inspect(None)
+        } else {
+            // the headers are held until the body is buffered
+            Action::Pause
+        }
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if !end_of_stream {
+            return Action::Pause;
+        }
+        let body = self.get_http_request_body(0, body_size);
+        self.inspect(body.as_deref())
+    }
+}
+
+impl CuriefenseFilter {
+    /// the client address, from the connection, without its port
+    fn client_ip(&self) -> String {
+        let address = self
+            .get_property(vec!["source", "address"])
+            .map(|a| String::from_utf8_lossy(&a).into_owned())
+            .unwrap_or_default();
+        match address.parse::<std::net::SocketAddr>() {
+            Ok(socket) => socket.ip().to_string(),
+            Err(_) => address,
+        }
+    }
+
+    /// inspects the request, then sends the block response, or applies the changes and resumes the request
+    fn inspect(&mut self, body: Option<&[u8]>) -> Action {
+        let meta = match RequestMeta::from_map(self.meta.clone()) {
+            Ok(m) => m,
+            Err(rr) => {
+                log::warn!("curiefense: invalid request: {}", rr);
+                return Action::Continue;
+            }
+        };
+        let raw = RawRequest {
+            ipstr: meta.client_ip(self.client_ip()),
+            headers: self.headers.clone(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: body.filter(|b| !b.is_empty()),
+        };
+        let mut logs = Logs::new(self.loglevel);
+        let (decision, tags, rinfo) =
+            inspect_generic_request_map(CONFIG_NAME, None::<DummyGrasshopper>, raw, &mut logs);
+        forward_logs(&logs);
+        let metadata = DynamicMetadata::new(&decision, &tags)
+            .with_request_id(&rinfo.request_id)
+            .with_geo(&Geo::new(&rinfo.rinfo.geoip))
+            .with_set_cookie(rinfo.csrf.as_ref().and_then(|c| c.set_cookie.clone()));
+        if let Ok(encoded) = serde_json::to_vec(&metadata) {
+            self.set_property(vec![METADATA_NAMESPACE], Some(&encoded));
+        }
+
+        let action = match &decision {
+            Decision::Pass => None,
+            Decision::Action(a) => Some(a),
+        };
+        if let Some(a) = action.filter(|a| a.block_mode && a.atype.is_blocking()) {
+            let headers: Vec<(String, String)> = a
+                .headers
+                .iter()
+                .flatten()
+                .map(|(k, v)| (k.to_lowercase(), v.clone()))
+                .collect();
+            self.send_http_response(
+                a.status,
+                headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+                Some(a.content.as_bytes()),
+            );
+            return Action::Pause;
+        }
+
+        let changes = RequestChanges::new(action, &self.headers);
+        for name in &changes.remove_headers {
+            self.set_http_request_header(name, None);
+        }
+        for (name, value) in &changes.set_headers {
+            self.set_http_request_header(name, Some(value));
+        }
+        if let (Some(query), Some(path)) = (&changes.query, self.meta.get("path")) {
+            let path = path.split('?').next().unwrap_or(path);
+            let rewritten = if query.is_empty() {
+                path.to_string()
+            } else {
+                format!("{}?{}", path, query)
+            };
+            self.set_http_request_header(":path", Some(&rewritten));
+        }
+        Action::Continue
+    }
+}
+
+/// forwards the warnings and errors to the proxy logs
+fn forward_logs(logs: &Logs) {
+    for log in &logs.logs {
+        match log.level {
+            LogLevel::Error => log::error!("curiefense: {}", log.message),
+            LogLevel::Warning => log::warn!("curiefense: {}", log.message),
+            _ => log::debug!("curiefense: {}", log.message),
+        }
+    }
+}
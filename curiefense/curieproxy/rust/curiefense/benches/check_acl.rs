@@ -31,6 +31,7 @@ fn gen_profile(sz: usize) -> AclProfile {
         deny_bot: tags_vec(sz).into_iter().collect(),
         passthrough: tags_vec(sz).into_iter().collect(),
         force_deny: tags_vec(sz).into_iter().collect(),
+        redirect: None,
     }
 }
 
@@ -0,0 +1,93 @@
+use curiefense::acl::check_acl;
+use curiefense::config::hostmap::SecurityPolicy;
+use curiefense::config::ConfigSnapshot;
+use curiefense::contentfilter::content_filter_check;
+use curiefense::corpus::{load_config, load_requests, map_with_policy};
+use curiefense::interface::Tags;
+use curiefense::limit::limit_keys;
+use curiefense::logs::Logs;
+use curiefense::tagging::tag_request;
+use curiefense::utils::{InspectionRequest, RawRequest, RequestInfo};
+
+use criterion::*;
+
+const CONFIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../luatests/config");
+const CORPORA: &[&str] = &["api", "form", "attacks"];
+
+fn corpus(name: &str) -> Vec<InspectionRequest> {
+    load_requests(format!("{}/benches/corpus/{}.json", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+/// the requests of the corpus, mapped and tagged
+fn mapped<'a>(snapshot: &'a ConfigSnapshot, raws: &[RawRequest]) -> Vec<(&'a SecurityPolicy, RequestInfo, Tags)> {
+    let mut logs = Logs::default();
+    raws.iter()
+        .map(|raw| {
+            let (secpolicy, reqinfo) = map_with_policy(&mut logs, &snapshot.config, raw).unwrap();
+            let (tags, _) = tag_request(&mut logs, false, &snapshot.config.globalfilters, &reqinfo);
+            (secpolicy, reqinfo, tags)
+        })
+        .collect()
+}
+
+fn phases(c: &mut Criterion) {
+    let snapshot = load_config(CONFIG).unwrap();
+    for name in CORPORA {
+        let requests = corpus(name);
+        let raws: Vec<RawRequest> = requests.iter().map(|r| r.to_raw().unwrap()).collect();
+        let mapped = mapped(&snapshot, &raws);
+
+        let mut group = c.benchmark_group(*name);
+        group.throughput(Throughput::Elements(raws.len() as u64));
+        group.bench_function("map_request", |b| {
+            b.iter(|| {
+                let mut logs = Logs::default();
+                for raw in &raws {
+                    black_box(map_with_policy(&mut logs, &snapshot.config, raw));
+                }
+            })
+        });
+        group.bench_function("tagging", |b| {
+            b.iter(|| {
+                let mut logs = Logs::default();
+                for (_, reqinfo, _) in &mapped {
+                    black_box(tag_request(&mut logs, false, &snapshot.config.globalfilters, reqinfo));
+                }
+            })
+        });
+        group.bench_function("limits", |b| {
+            b.iter(|| {
+                for (secpolicy, reqinfo, tags) in &mapped {
+                    black_box(limit_keys(&secpolicy.name, reqinfo, &secpolicy.limits, tags));
+                }
+            })
+        });
+        group.bench_function("acl", |b| {
+            b.iter(|| {
+                for (secpolicy, _, tags) in &mapped {
+                    black_box(check_acl(tags, &secpolicy.acl_profile));
+                }
+            })
+        });
+        group.bench_function("content_filter", |b| {
+            b.iter(|| {
+                let mut logs = Logs::default();
+                for (secpolicy, reqinfo, tags) in &mapped {
+                    let mut tags = tags.clone();
+                    let profile = &secpolicy.content_filter_profile;
+                    let _ = black_box(content_filter_check(
+                        &mut logs,
+                        &mut tags,
+                        reqinfo,
+                        profile,
+                        snapshot.hsdb.get(&profile.id),
+                    ));
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, phases);
+criterion_main!(benches);
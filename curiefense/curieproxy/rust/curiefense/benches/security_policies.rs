@@ -1,13 +1,14 @@
 use curiefense::config::contentfilter::ContentFilterProfile;
 use curiefense::config::hostmap::*;
 use curiefense::config::raw::AclProfile;
-use curiefense::config::utils::Matching;
+use curiefense::config::utils::{Matching, MatchingSet};
 use curiefense::config::Config;
 use curiefense::logs::Logs;
 use curiefense::securitypolicy::match_securitypolicy;
+use curiefense::utils::RequestMeta;
 
 use criterion::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 fn gen_bogus_config(sz: usize) -> Config {
     let mut def = Config::empty();
@@ -19,6 +20,8 @@ fn gen_bogus_config(sz: usize) -> Config {
                     id: format!("abcd{}", i),
                     name: format!("Dummy hostmap {}", i),
                     entries: Vec::new(),
+                    paths: None,
+                    normalization: None,
                     default: None,
                 },
             )
@@ -35,6 +38,7 @@ fn gen_bogus_config(sz: usize) -> Config {
         deny_bot: HashSet::new(),
         passthrough: HashSet::new(),
         force_deny: HashSet::new(),
+        redirect: None,
     };
 
     let dummy_entries: Vec<Matching<SecurityPolicy>> = (0..sz)
@@ -48,6 +52,35 @@ fn gen_bogus_config(sz: usize) -> Config {
                     content_filter_active: false,
                     content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
                     limits: Vec::new(),
+                    session: Vec::new(),
+                    request_line: RequestLineConditions::default(),
+                    template: None,
+                    templates: Default::default(),
+                    observe: false,
+                    run_all_phases: false,
+                    captcha: None,
+                    risk: None,
+                    campaigns: None,
+                    harvesting: None,
+                    jwt: None,
+                    request_signature: None,
+                    openapi: None,
+                    honeypot: None,
+                    mode: None,
+                    quota: None,
+                    normalization: None,
+                    experiment: None,
+                    login: None,
+                    csrf: None,
+                    cookie_signing: None,
+                    ssrf: None,
+                    allowed_methods: None,
+                    challenge: ChallengePolicy::default(),
+                    ban_on_decision: HashMap::new(),
+                    block_responses: HashMap::new(),
+                    json_errors: false,
+                    explain: false,
+                    explain_secret: None,
                 },
             )
             .unwrap()
@@ -57,6 +90,8 @@ fn gen_bogus_config(sz: usize) -> Config {
     def.default = Some(HostMap {
         id: "__default__".into(),
         name: "__default__".into(),
+        paths: MatchingSet::new(&dummy_entries).ok(),
+        normalization: None,
         entries: dummy_entries,
         default: Some(SecurityPolicy {
             name: "selected".into(),
@@ -65,6 +100,35 @@ fn gen_bogus_config(sz: usize) -> Config {
             content_filter_active: false,
             content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
             limits: Vec::new(),
+            session: Vec::new(),
+            request_line: RequestLineConditions::default(),
+            template: None,
+            templates: Default::default(),
+            observe: false,
+            run_all_phases: false,
+            captcha: None,
+            risk: None,
+            campaigns: None,
+            harvesting: None,
+            jwt: None,
+            request_signature: None,
+            openapi: None,
+            honeypot: None,
+            mode: None,
+            quota: None,
+            normalization: None,
+            experiment: None,
+            login: None,
+            csrf: None,
+            cookie_signing: None,
+            ssrf: None,
+            allowed_methods: None,
+            challenge: ChallengePolicy::default(),
+            ban_on_decision: HashMap::new(),
+            block_responses: HashMap::new(),
+            json_errors: false,
+            explain: false,
+            explain_secret: None,
         }),
     });
 
@@ -76,10 +140,18 @@ fn forms_string_map(c: &mut Criterion) {
     for sz in [10, 100, 500, 1000].iter() {
         group.bench_with_input(BenchmarkId::from_parameter(sz), sz, |b, &size| {
             let cfg = gen_bogus_config(size);
+            let meta = RequestMeta::from_map(
+                [("method", "GET"), ("path", "/non/matching/path")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+            .unwrap();
             b.iter(|| {
                 let mut logs = Logs::default();
                 let (_, umap) =
-                    match_securitypolicy("my.host.name", "/non/matching/path", black_box(&cfg), &mut logs).unwrap();
+                    match_securitypolicy("my.host.name", "/non/matching/path", &meta, black_box(&cfg), &mut logs)
+                        .unwrap();
                 assert_eq!(umap.name, "selected");
             })
         });
@@ -0,0 +1,233 @@
+use crate::config::contentfilter::SectionIdx;
+use crate::interface::Decision;
+use crate::logs::{Logs, PhaseTiming};
+use crate::metadata::DynamicMetadata;
+use crate::reason::Reason;
+use crate::requestmap::{Attrs, Geo, RequestMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ACCESSLOG_SCHEMA_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AccessLog {
+    pub schema_version: u32,
+    /// start of the inspection, formatted as `2022-05-01T12:34:56.789012Z`
+    pub timestamp: String,
+    pub request_id: Option<String>,
+    /// the request was not forwarded upstream
+    pub blocked: bool,
+    /// the reason of the action, including the actions in monitor mode
+    pub block_reason: Option<Reason>,
+    pub metadata: DynamicMetadata,
+    /// the request tags, sorted
+    pub tags: Vec<String>,
+    pub geo: Geo,
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub arguments: HashMap<String, String>,
+    pub attributes: Attrs,
+    /// what matched, grouped by initiator (`acl`, `content_filter`, `limit`, ...)
+    pub triggers: BTreeMap<String, Vec<Trigger>>,
+    /// time at which each inspection phase ended
+    pub timings: Vec<PhaseTiming>,
+    pub elapsed_micros: u64,
+    /// number of identical events this record stands for, see the `aggregation` module
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Trigger {
+    pub name: Option<String>,
+    pub rule_ids: Vec<String>,
+    pub section: Option<SectionIdx>,
+    pub entry: Option<String>,
+    pub value: Option<String>,
+    pub message: Option<String>,
+}
+
+impl Trigger {
+    fn new(reason: &Reason) -> Self {
+        Trigger {
+            name: reason.name.clone(),
+            rule_ids: reason.rule_ids.clone(),
+            section: reason.section,
+            entry: reason.entry.clone(),
+            value: reason.value.clone(),
+            message: reason.message.clone(),
+        }
+    }
+}
+
+/// formats a time as an RFC 3339 UTC timestamp, with a microsecond precision
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from the number of days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// parses a timestamp formatted by `format_timestamp`, or with a lower precision
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut hms = hms.splitn(3, ':').map(|p| p.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (hms.next()??, hms.next()??, hms.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let micros = if fraction.is_empty() {
+        0
+    } else if fraction.len() <= 6 && fraction.bytes().all(|c| c.is_ascii_digit()) {
+        fraction.parse::<u64>().ok()? * 10u64.pow(6 - fraction.len() as u32)
+    } else {
+        return None;
+    };
+    // number of days since the epoch from the civil date (Howard Hinnant's algorithm)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros))
+}
+
+impl AccessLog {
+    /// the request map must have been built with the tags of the decision
+    pub fn new(decision: &Decision, request_map: &RequestMap, logs: &Logs) -> Self {
+        let metadata = DynamicMetadata::new(decision, &request_map.tags)
+            .with_request_id(&request_map.attrs.request_id)
+            .with_geo(&request_map.geo);
+        let action = match decision {
+            Decision::Pass => None,
+            Decision::Action(a) => Some(a),
+        };
+        let mut triggers: BTreeMap<String, Vec<Trigger>> = BTreeMap::new();
+        if let Some(a) = action {
+            for reason in std::iter::once(&a.reason).chain(a.reason.matches.iter()) {
+                triggers
+                    .entry(reason.initiator.as_str().to_string())
+                    .or_default()
+                    .push(Trigger::new(reason));
+            }
+        }
+        let elapsed = logs.start.elapsed();
+        let start = SystemTime::now().checked_sub(elapsed).unwrap_or(UNIX_EPOCH);
+        AccessLog {
+            schema_version: ACCESSLOG_SCHEMA_VERSION,
+            timestamp: format_timestamp(start),
+            request_id: metadata.request_id.clone(),
+            blocked: action.map(|a| a.block_mode && a.atype.is_blocking()).unwrap_or(false),
+            block_reason: action.map(|a| a.reason.clone()),
+            tags: metadata.tags.clone(),
+            metadata,
+            geo: request_map.geo.clone(),
+            headers: request_map.headers.clone(),
+            cookies: request_map.cookies.clone(),
+            arguments: request_map.args.clone(),
+            attributes: request_map.attrs.clone(),
+            triggers,
+            timings: logs.phases.clone(),
+            elapsed_micros: elapsed.as_micros() as u64,
+            count: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::ParseBudget;
+    use crate::interface::{Action, Tags};
+    use crate::reason::Initiator;
+    use crate::utils::{map_request, RawRequest, RequestMeta};
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_micros(1_709_251_199_123_456)),
+            "2024-02-29T23:59:59.123456Z"
+        );
+        for micros in [0, 1_709_251_199_123_456, 951_782_400_000_001] {
+            let time = UNIX_EPOCH + Duration::from_micros(micros);
+            assert_eq!(parse_timestamp(&format_timestamp(time)), Some(time));
+        }
+        assert_eq!(
+            parse_timestamp("1970-01-02T00:00:01Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(86401))
+        );
+        assert_eq!(parse_timestamp("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_timestamp("2024-02-29 23:59:59"), None);
+    }
+
+    #[test]
+    fn blocked_request() {
+        let meta = RequestMeta::from_map(
+            [("method", "GET"), ("path", "/foo?a=1"), ("authority", "myhost")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap();
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: HashMap::new(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: None,
+        };
+        let mut logs = Logs::default();
+        logs.phase("acl");
+        let rinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        let mut tags = Tags::default();
+        tags.insert("t1");
+
+        let mut reason = Reason::new(Initiator::Acl);
+        reason.name = Some("deny-bots".to_string());
+        let mut other = Reason::new(Initiator::ContentFilter);
+        other.rule_ids = vec!["100".to_string()];
+        reason.matches.push(other);
+        let decision = Decision::Action(Action {
+            reason,
+            ..Action::default()
+        });
+
+        let log = AccessLog::new(&decision, &RequestMap::new(rinfo, tags), &logs);
+        assert!(log.blocked);
+        assert_eq!(log.tags, vec!["t1".to_string()]);
+        assert_eq!(log.arguments.get("a").map(|s| s.as_str()), Some("1"));
+        assert_eq!(log.attributes.path, "/foo");
+        assert_eq!(log.triggers["acl"][0].name.as_deref(), Some("deny-bots"));
+        assert_eq!(log.triggers["content_filter"][0].rule_ids, vec!["100".to_string()]);
+        assert_eq!(log.timings.len(), 1);
+        assert_eq!(log.metadata.action, "block");
+    }
+}
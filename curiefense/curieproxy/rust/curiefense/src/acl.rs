@@ -1,5 +1,6 @@
 use crate::config::raw::AclProfile;
 use crate::interface::Tags;
+use crate::logs::Logs;
 
 use serde::Serialize;
 use std::collections::HashSet;
@@ -24,9 +25,43 @@ pub struct BotHuman {
     pub human: Option<AclDecision>,
 }
 
+/// the sorted tags that match each column of the profile
+pub fn column_matches<'a>(tags: &'a Tags, acl: &'a AclProfile) -> Vec<(&'static str, Vec<&'a String>)> {
+    [
+        ("force_deny", &acl.force_deny),
+        ("passthrough", &acl.passthrough),
+        ("allow_bot", &acl.allow_bot),
+        ("deny_bot", &acl.deny_bot),
+        ("allow", &acl.allow),
+        ("deny", &acl.deny),
+    ]
+    .iter()
+    .map(|(column, checks)| {
+        let mut matching: Vec<&String> = checks.iter().filter(|t| tags.contains(t)).collect();
+        matching.sort();
+        (*column, matching)
+    })
+    .collect()
+}
+
+/// records the tags that matched each column of the profile in the explain trace
+pub fn explain_acl(logs: &mut Logs, tags: &Tags, acl: &AclProfile) {
+    if logs.explain.is_none() {
+        return;
+    }
+    for (column, matching) in column_matches(tags, acl) {
+        logs.explain(
+            "acl",
+            column,
+            !matching.is_empty(),
+            || serde_json::json!({ "profile": acl.id, "tags": matching }),
+        );
+    }
+}
+
 pub fn check_acl(tags: &Tags, acl: &AclProfile) -> AclResult {
     let subcheck = |checks: &HashSet<String>, allowed: bool| {
-        let tags: Vec<String> = checks.intersection(tags.as_hash_ref()).cloned().collect();
+        let tags: Vec<String> = checks.iter().filter(|t| tags.contains(t)).cloned().collect();
         if tags.is_empty() {
             None
         } else {
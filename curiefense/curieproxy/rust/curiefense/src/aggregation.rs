@@ -0,0 +1,169 @@
+use crate::accesslog::AccessLog;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AggregationKey {
+    ip: String,
+    action: String,
+    initiator: String,
+    name: Option<String>,
+    rule_ids: Vec<String>,
+}
+
+impl AggregationKey {
+    /// only the actions are aggregated, the passed requests are sampled
+    pub fn new(record: &AccessLog) -> Option<Self> {
+        let reason = record.block_reason.as_ref()?;
+        Some(AggregationKey {
+            ip: record.attributes.ip.clone(),
+            action: record.metadata.action.clone(),
+            initiator: reason.initiator.as_str().to_string(),
+            name: reason.name.clone(),
+            rule_ids: record.metadata.rule_ids.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Aggregate {
+    /// the first duplicate, that stands for all of them
+    duplicate: Option<AccessLog>,
+    duplicates: u64,
+    expires: Instant,
+}
+
+impl Aggregate {
+    fn summary(self) -> Option<AccessLog> {
+        let duplicates = self.duplicates;
+        self.duplicate.map(|mut record| {
+            record.count = duplicates;
+            record
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Aggregator {
+    window: Duration,
+    events: HashMap<AggregationKey, Aggregate>,
+}
+
+impl Aggregator {
+    pub fn new(window: Duration) -> Self {
+        Aggregator {
+            window,
+            events: HashMap::new(),
+        }
+    }
+
+    /// adds an event, returning the records to ship now
+    pub fn add(&mut self, key: AggregationKey, record: AccessLog, now: Instant) -> Vec<AccessLog> {
+        let mut out = Vec::new();
+        match self.events.get_mut(&key) {
+            Some(aggregate) if aggregate.expires > now => {
+                aggregate.duplicates += 1;
+                if aggregate.duplicate.is_none() {
+                    aggregate.duplicate = Some(record);
+                }
+                return out;
+            }
+            _ => (),
+        }
+        let previous = self.events.insert(
+            key,
+            Aggregate {
+                duplicate: None,
+                duplicates: 0,
+                expires: now + self.window,
+            },
+        );
+        out.extend(previous.and_then(Aggregate::summary));
+        out.push(record);
+        out
+    }
+
+    /// removes the windows that ended, returning the records that summarize their duplicates
+    pub fn expired(&mut self, now: Instant) -> Vec<AccessLog> {
+        let keys: Vec<AggregationKey> = self
+            .events
+            .iter()
+            .filter(|(_, a)| a.expires <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.into_iter()
+            .filter_map(|k| self.events.remove(&k))
+            .filter_map(Aggregate::summary)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::ParseBudget;
+    use crate::interface::{Action, Decision, Tags};
+    use crate::logs::Logs;
+    use crate::reason::{Initiator, Reason};
+    use crate::requestmap::RequestMap;
+    use crate::utils::{map_request, RawRequest, RequestMeta};
+
+    fn record(ip: &str, initiator: Initiator) -> AccessLog {
+        let meta = RequestMeta::from_map(
+            [("method", "GET"), ("path", "/")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap();
+        let raw = RawRequest {
+            ipstr: ip.to_string(),
+            headers: HashMap::new(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: None,
+        };
+        let mut logs = Logs::default();
+        let rinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        let decision = Decision::Action(Action {
+            reason: Reason::new(initiator),
+            ..Action::default()
+        });
+        AccessLog::new(&decision, &RequestMap::new(rinfo, Tags::default()), &logs)
+    }
+
+    #[test]
+    fn collapses_duplicates() {
+        let mut aggregator = Aggregator::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let mut add = |ip: &str, initiator: Initiator, at: Duration| {
+            let r = record(ip, initiator);
+            aggregator.add(AggregationKey::new(&r).unwrap(), r, now + at)
+        };
+        assert_eq!(add("1.2.3.4", Initiator::Acl, Duration::ZERO).len(), 1);
+        assert!(add("1.2.3.4", Initiator::Acl, Duration::from_secs(1)).is_empty());
+        assert!(add("1.2.3.4", Initiator::Acl, Duration::from_secs(2)).is_empty());
+        // another IP, or another initiator, is another event
+        assert_eq!(add("1.2.3.5", Initiator::Acl, Duration::from_secs(2)).len(), 1);
+        assert_eq!(add("1.2.3.4", Initiator::Limit, Duration::from_secs(2)).len(), 1);
+
+        // the next window starts with the summary of the previous one
+        let shipped = add("1.2.3.4", Initiator::Acl, Duration::from_secs(11));
+        assert_eq!(shipped.iter().map(|r| r.count).collect::<Vec<_>>(), vec![2, 1]);
+
+        assert!(aggregator.expired(now + Duration::from_secs(15)).is_empty());
+        let r = record("1.2.3.4", Initiator::Acl);
+        aggregator.add(AggregationKey::new(&r).unwrap(), r, now + Duration::from_secs(16));
+        let summaries = aggregator.expired(now + Duration::from_secs(30));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].count, 1);
+        assert!(aggregator.events.is_empty());
+    }
+
+    #[test]
+    fn passed_requests() {
+        let mut r = record("1.2.3.4", Initiator::Acl);
+        r.block_reason = None;
+        assert_eq!(AggregationKey::new(&r), None);
+    }
+}
@@ -1,19 +1,42 @@
 use serde_json::json;
 use std::collections::HashMap;
 
-use crate::acl::{check_acl, AclDecision, AclResult, BotHuman};
+use crate::acl::{check_acl, explain_acl, AclDecision, AclResult, BotHuman};
+use crate::blocklist::{record_ban, record_risk};
+use crate::blockpage::apply_template;
+use crate::config::contentfilter::ContentFilterRules;
 use crate::config::flow::{FlowElement, SequenceKey};
-use crate::config::hostmap::SecurityPolicy;
-use crate::config::HSDB;
-use crate::contentfilter::{content_filter_check, masking};
+use crate::config::hostmap::{ChallengePolicy, SecurityPolicy};
+use crate::config::raw::{AclRedirect, HumanAclFailure};
+use crate::contentfilter::{content_filter_check, mask_logs, mask_reason, masking, ContentFilterBlock};
 use crate::flow::flow_check;
-use crate::grasshopper::{challenge_phase01, challenge_phase02, Grasshopper};
-use crate::interface::{Action, ActionType, Decision, SimpleDecision, Tags};
+use crate::grasshopper::{challenge_phase01, challenge_phase02, limit_challenges, Grasshopper};
+use crate::hits::HITS;
+use crate::interface::{
+    render_header_value, Action, ActionType, Decision, SimpleAction, SimpleActionT, SimpleDecision, Tags,
+};
 use crate::limit::limit_check;
 use crate::logs::Logs;
+use crate::notify::{notify, Event};
+use crate::reason::{stamp, Initiator, Reason};
+use crate::redis::{is_decision_banned, register_decision_ban};
 use crate::utils::{BodyDecodingResult, RequestInfo};
 
-fn acl_block(blocking: bool, code: i32, tags: &[String]) -> Decision {
+fn acl_block(blocking: bool, code: i32, tags: &[String], redirect: Option<(&AclRedirect, &RequestInfo)>) -> Decision {
+    if let Some((r, rinfo)) = redirect {
+        return Decision::Action(Action {
+            block_mode: blocking,
+            atype: if blocking {
+                ActionType::Redirect
+            } else {
+                ActionType::Monitor
+            },
+            reason: Reason::new(Initiator::Acl)
+                .with_tags(tags)
+                .with_details(json!({ "action": code })),
+            ..Action::redirect(&r.location, r.status, Some(rinfo))
+        });
+    }
     Decision::Action(Action {
         atype: if blocking {
             ActionType::Block
@@ -24,15 +47,23 @@ fn acl_block(blocking: bool, code: i32, tags: &[String]) -> Decision {
         ban: false,
         status: 403,
         headers: None,
-        reason: json!({"action": code, "initiator": "acl", "reason": tags }),
+        reason: Reason::new(Initiator::Acl)
+            .with_tags(tags)
+            .with_details(json!({ "action": code })),
         content: "access denied".to_string(),
         extra_tags: None,
+        template: None,
+        mutations: Vec::new(),
     })
 }
 
+/// runs all the checks, rendering the response template of the blocking actions
+///
+/// `hsdb` holds the content filter rules, by content filter profile id
 #[allow(clippy::too_many_arguments)]
 pub async fn analyze<GH: Grasshopper>(
     logs: &mut Logs,
+    hsdb: &HashMap<String, ContentFilterRules>,
     mgh: Option<GH>,
     itags: Tags,
     secpolname: &str,
@@ -42,110 +73,287 @@ pub async fn analyze<GH: Grasshopper>(
     globalfilter_dec: SimpleDecision,
     flows: &HashMap<SequenceKey, Vec<FlowElement>>,
 ) -> (Decision, Tags, RequestInfo) {
-    let mut tags = itags;
-    let masking_seed = &securitypolicy.content_filter_profile.masking_seed;
+    let mut injected = None;
+    let (decision, mut tags, rinfo) = analyze_checks(
+        logs,
+        hsdb,
+        mgh,
+        itags,
+        secpolname,
+        securitypolicy,
+        reqinfo,
+        is_human,
+        globalfilter_dec,
+        flows,
+        &mut injected,
+    )
+    .await;
+    let decision = inject(securitypolicy, decision, injected, &tags, &rinfo);
+    let decision = conclude(logs, secpolname, securitypolicy, &rinfo, decision, &mut tags).await;
+    (decision, tags, rinfo)
+}
 
-    logs.debug("request tagged");
-    tags.insert_qualified("securitypolicy", secpolname);
-    tags.insert_qualified("securitypolicy-entry", &securitypolicy.name);
-    tags.insert_qualified("aclid", &securitypolicy.acl_profile.id);
-    tags.insert_qualified("aclname", &securitypolicy.acl_profile.name);
-    tags.insert_qualified("contentfilterid", &securitypolicy.content_filter_profile.id);
-    tags.insert_qualified("contentfiltername", &securitypolicy.content_filter_profile.name);
+/// masks the reason of the decision, and applies the stashed header alterations to a passed or sanitized request
+fn inject(
+    securitypolicy: &SecurityPolicy,
+    mut decision: Decision,
+    injected: Option<Action>,
+    tags: &Tags,
+    rinfo: &RequestInfo,
+) -> Decision {
+    // the request map is masked by the checks, the values of the reasons are masked the same way
+    let profile = &securitypolicy.content_filter_profile;
+    if let Decision::Action(action) = &mut decision {
+        mask_reason(&profile.masking_seed, &mut action.reason, profile);
+    }
+    match (decision, injected) {
+        // the request is passed, with the headers injected toward the upstream
+        (Decision::Pass, Some(mut action)) => {
+            if let Some(hdrs) = action.headers.as_mut() {
+                for v in hdrs.values_mut() {
+                    *v = render_header_value(v, tags, rinfo);
+                }
+            }
+            Decision::Action(action)
+        }
+        (Decision::Action(mut action), Some(injection)) if action.atype == ActionType::Sanitize => {
+            action.headers = injection.headers.map(|hdrs| {
+                hdrs.into_iter()
+                    .map(|(k, v)| {
+                        let rendered = render_header_value(&v, tags, rinfo);
+                        (k, rendered)
+                    })
+                    .collect()
+            });
+            Decision::Action(action)
+        }
+        (d, _) => d,
+    }
+}
 
-    if !securitypolicy.content_filter_profile.content_type.is_empty()
-        && reqinfo.rinfo.qinfo.body_decoding != BodyDecodingResult::ProperlyDecoded
-    {
-        let error: &str = if let BodyDecodingResult::DecodingFailed(rr) = &reqinfo.rinfo.qinfo.body_decoding {
-            rr
-        } else {
-            "Expected a body, but there were none"
-        };
-        // we expect the body to be properly decoded
-        let action = Action {
-            reason: json!({
-                "initiator": "body_decoding",
-                "error": error
-            }),
-            status: 403,
-            ..Action::default()
-        };
-        return (
-            Decision::Action(action),
-            tags,
-            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-        );
+/// the last steps of an inspection, once the checks decided: challenge limits, block page, risk score, observe mode,
+/// bans
+pub async fn conclude(
+    logs: &mut Logs,
+    secpolname: &str,
+    securitypolicy: &SecurityPolicy,
+    rinfo: &RequestInfo,
+    decision: Decision,
+    tags: &mut Tags,
+) -> Decision {
+    let profile = &securitypolicy.content_filter_profile;
+    let decision = limit_challenges(logs, secpolname, &securitypolicy.challenge, rinfo, decision).await;
+    let decision = apply_template(logs, decision, rinfo, securitypolicy);
+    // the decisions raise the risk score even when they are not enforced
+    if let Some(risk) = &securitypolicy.risk {
+        risk.record(logs, rinfo, &decision).await;
     }
+    let decision = if securitypolicy.observe {
+        observe(logs, decision, tags)
+    } else {
+        decision
+    };
+    let decision = ban_on_decision(logs, securitypolicy, rinfo, decision).await;
+    let decision = stamp(logs, decision, rinfo);
+    mask_logs(&profile.masking_seed, logs, profile);
+    HITS.record_tags(tags);
+    decision
+}
 
-    if let Some(dec) = mgh
-        .as_ref()
-        .and_then(|gh| challenge_phase02(gh, &reqinfo.rinfo.qinfo.uri, &reqinfo.headers))
-    {
-        return (
-            dec,
-            tags,
-            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-        );
+/// registers a ban of the session, when the initiator of the blocking decision is configured to do so
+async fn ban_on_decision(
+    logs: &mut Logs,
+    securitypolicy: &SecurityPolicy,
+    rinfo: &RequestInfo,
+    decision: Decision,
+) -> Decision {
+    let mut action = match decision {
+        Decision::Action(a) if a.block_mode && a.atype.is_blocking() => a,
+        d => return d,
+    };
+    // the honeypot decoys always ban, for their own duration unless the entry overrides it
+    let duration = securitypolicy
+        .ban_on_decision
+        .get(&action.reason.initiator)
+        .copied()
+        .or_else(|| match action.reason.initiator {
+            Initiator::Honeypot => securitypolicy.honeypot.as_ref().map(|h| h.ban_duration),
+            _ => None,
+        });
+    if let Some(duration) = duration {
+        register_decision_ban(logs, &rinfo.session, duration).await;
+        record_ban(rinfo.rinfo.geoip.ip, duration);
+        notify(Event::Ban {
+            scope: "session",
+            name: action.reason.initiator.as_str().to_string(),
+            key: rinfo.session.clone(),
+            duration,
+        });
+        action.ban = true;
     }
-    logs.debug("challenge phase2 ignored");
+    Decision::Action(action)
+}
 
-    if let SimpleDecision::Action(action, reason) = globalfilter_dec {
-        logs.debug(|| format!("Global filter decision {:?}", reason));
-        let decision = action.to_decision(is_human, &mgh, &reqinfo.headers, reason);
-        if decision.is_final() {
-            return (
-                decision,
-                tags,
-                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-            );
+/// observe only mode: decisions that would block or alter the request are replaced with a monitoring action
+///
+/// the original action is kept in the reason, and the request is tagged with `observe:would-block` and the
+/// initiator of the original action
+pub fn observe(logs: &mut Logs, decision: Decision, tags: &mut Tags) -> Decision {
+    let action = match decision {
+        Decision::Action(a) if a.block_mode || a.atype == ActionType::Sanitize => a,
+        d => return d,
+    };
+    let initiator = action.reason.initiator;
+    logs.info(|| {
+        format!(
+            "observe mode, the {:?} action from {} is not enforced",
+            action.atype, initiator
+        )
+    });
+    tags.insert("observe:would-block");
+    tags.insert_qualified("observe-initiator", initiator.as_str());
+    let original = serde_json::to_value(&action).unwrap_or(serde_json::Value::Null);
+    Decision::Action(Action {
+        atype: ActionType::Monitor,
+        block_mode: false,
+        ban: false,
+        status: action.status,
+        reason: Reason::new(Initiator::Observe).with_details(original),
+        extra_tags: action.extra_tags,
+        ..Action::default()
+    })
+}
+
+/// stores header alteration actions, so that they are applied if the request is passed
+fn stash_injection(injected: &mut Option<Action>, decision: &Decision) {
+    if let Decision::Action(a) = decision {
+        if a.atype == ActionType::AlterHeaders {
+            match injected {
+                None => *injected = Some(a.clone()),
+                Some(prev) => {
+                    if let (Some(ph), Some(nh)) = (prev.headers.as_mut(), a.headers.as_ref()) {
+                        ph.extend(nh.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    }
+                }
+            }
         }
     }
+}
 
-    match flow_check(logs, flows, &reqinfo, &mut tags).await {
-        Err(rr) => logs.error(|| rr.to_string()),
-        Ok(SimpleDecision::Pass) => {}
-        Ok(SimpleDecision::Action(a, reason)) => {
-            let decision = a.to_decision(is_human, &mgh, &reqinfo.headers, reason);
-            if decision.is_final() {
-                return (
-                    decision,
-                    tags,
-                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-                );
+/// decisions taken by the inspection phases
+///
+/// by default, the inspection stops at the first final decision. When all the phases are run, all the decisions are
+/// collected, and the first final one is returned with the others listed in its reason.
+struct Matches {
+    run_all: bool,
+    actions: Vec<Action>,
+}
+
+impl Matches {
+    fn new(securitypolicy: &SecurityPolicy) -> Self {
+        Matches {
+            run_all: securitypolicy.run_all_phases,
+            actions: Vec::new(),
+        }
+    }
+
+    /// records a decision, returning it when the inspection must stop
+    fn record(&mut self, decision: Decision) -> Option<Decision> {
+        match decision {
+            Decision::Pass => None,
+            Decision::Action(a) if self.run_all => {
+                if a.atype != ActionType::AlterHeaders {
+                    self.actions.push(a);
+                }
+                None
             }
+            d if d.is_final() => Some(d),
+            _ => None,
         }
     }
-    logs.debug("flow checks done");
 
-    // limit checks
-    let limit_check = limit_check(logs, &securitypolicy.name, &reqinfo, &securitypolicy.limits, &mut tags);
-    if let SimpleDecision::Action(action, reason) = limit_check.await {
-        let decision = action.to_decision(is_human, &mgh, &reqinfo.headers, reason);
-        if decision.is_final() {
-            return (
-                decision,
-                tags,
-                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-            );
+    /// the decision to return, given the one of the last phase that was run
+    fn finish(self, decision: Decision) -> Decision {
+        let mut actions = self.actions;
+        if let Decision::Action(a) = decision {
+            actions.push(a);
+        }
+        if actions.is_empty() {
+            return Decision::Pass;
         }
+        let primary = actions.iter().position(|a| a.atype.is_final()).unwrap_or(0);
+        let mut action = actions.remove(primary);
+        action.reason.matches = actions.into_iter().map(|a| a.reason).collect();
+        Decision::Action(action)
     }
-    logs.debug(|| format!("limit checks done ({} limits)", securitypolicy.limits.len()));
+}
+
+/// when configured, the clients that are not verified humans are challenged instead of being blocked, when they only
+/// fail the human ACL rules
+fn challenge_human_failures(acl_result: AclResult, policy: &ChallengePolicy, is_human: bool) -> AclResult {
+    match acl_result {
+        AclResult::Match(BotHuman {
+            bot,
+            human: Some(human),
+        }) if !human.allowed
+            && !is_human
+            && policy.human_acl_failure == HumanAclFailure::Challenge
+            && bot.as_ref().map(|b| b.allowed).unwrap_or(true) =>
+        {
+            AclResult::Match(BotHuman {
+                bot: Some(human),
+                human: None,
+            })
+        }
+        r => r,
+    }
+}
 
-    let acl_result = check_acl(&tags, &securitypolicy.acl_profile);
+/// the result of the ACL phase
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum AclOutcome {
+    /// a passthrough rule matched, the following checks are skipped
+    Passthrough,
+    /// the client is challenged
+    Challenge(Decision),
+    /// the block code and the matching tags, the block is enforced when the ACL is active, and monitored otherwise
+    Block(i32, Vec<String>),
+    Pass,
+}
+
+/// runs the ACL phase, the clients that are not verified humans being challenged when possible
+pub fn acl_phase<GH: Grasshopper>(
+    logs: &mut Logs,
+    tags: &Tags,
+    securitypolicy: &SecurityPolicy,
+    reqinfo: &RequestInfo,
+    is_human: bool,
+    mgh: Option<&GH>,
+) -> AclOutcome {
+    // nothing can match an empty profile
+    if securitypolicy.acl_profile.is_empty() {
+        logs.debug("empty ACL profile");
+        logs.phase("acl");
+        return AclOutcome::Pass;
+    }
+    explain_acl(logs, tags, &securitypolicy.acl_profile);
+    HITS.record_acl(tags, &securitypolicy.acl_profile);
+    let acl_result = challenge_human_failures(
+        check_acl(tags, &securitypolicy.acl_profile),
+        &securitypolicy.challenge,
+        is_human,
+    );
     logs.debug(|| format!("ACL result: {:?}", acl_result));
-    // store the check_acl result here
-    let blockcode: Option<(i32, Vec<String>)> = match acl_result {
+    logs.phase("acl");
+    match acl_result {
         AclResult::Passthrough(dec) => {
             if dec.allowed {
                 logs.debug("ACL passthrough detected");
-                return (
-                    Decision::Pass,
-                    tags,
-                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-                );
+                AclOutcome::Passthrough
             } else {
                 logs.debug("ACL force block detected");
-                Some((0, dec.tags))
+                AclOutcome::Block(0, dec.tags)
             }
         }
         // bot blocked, human blocked
@@ -161,7 +369,7 @@ pub async fn analyze<GH: Grasshopper>(
             }),
         }) => {
             logs.debug("ACL human block detected");
-            Some((5, if is_human { human_tags } else { bot_tags }))
+            AclOutcome::Block(5, if is_human { human_tags } else { bot_tags })
         }
         // human blocked, always block, even if it is a bot
         AclResult::Match(BotHuman {
@@ -172,7 +380,7 @@ pub async fn analyze<GH: Grasshopper>(
             }),
         }) => {
             logs.debug("ACL human block detected");
-            Some((5, dtags))
+            AclOutcome::Block(5, dtags)
         }
         // robot blocked, should be challenged
         AclResult::Match(BotHuman {
@@ -183,16 +391,15 @@ pub async fn analyze<GH: Grasshopper>(
             human: _,
         }) => {
             if is_human {
-                None
+                AclOutcome::Pass
+            } else if securitypolicy.acl_active && securitypolicy.acl_profile.redirect.is_some() {
+                logs.debug("ACL challenge detected: redirected");
+                AclOutcome::Block(3, dtags)
             } else {
-                match (reqinfo.headers.get("user-agent"), &mgh) {
+                match (reqinfo.headers.get("user-agent"), mgh) {
                     (Some(ua), Some(gh)) => {
                         logs.debug("ACL challenge detected: challenged");
-                        return (
-                            challenge_phase01(gh, ua, dtags),
-                            tags,
-                            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
-                        );
+                        AclOutcome::Challenge(challenge_phase01(gh, ua, dtags))
                     }
                     (gua, ggh) => {
                         logs.debug(|| {
@@ -202,59 +409,699 @@ pub async fn analyze<GH: Grasshopper>(
                                 ggh.is_some()
                             )
                         });
-                        Some((3, dtags))
+                        AclOutcome::Block(3, dtags)
                     }
                 }
             }
         }
+        _ => AclOutcome::Pass,
+    }
+}
+
+/// the decision of the content filter phase, the actions being monitored when the content filter is not active
+pub fn content_filter_decision(result: Result<(), ContentFilterBlock>, securitypolicy: &SecurityPolicy) -> Decision {
+    match result {
+        Ok(()) => Decision::Pass,
+        Err(wb) => {
+            let mut action = wb.to_action();
+            action.block_mode &= securitypolicy.content_filter_active;
+            if !securitypolicy.content_filter_active && action.atype == ActionType::Sanitize {
+                action.atype = ActionType::Monitor;
+                action.mutations.clear();
+            }
+            Decision::Action(action)
+        }
+    }
+}
+
+/// records the ACL block when the ACL is active, returning the decision when the inspection must stop
+fn enforce_acl(
+    matches: &mut Matches,
+    securitypolicy: &SecurityPolicy,
+    reqinfo: &RequestInfo,
+    blockcode: &Option<(i32, Vec<String>)>,
+) -> Option<Decision> {
+    match blockcode {
+        Some((cde, tgs)) if securitypolicy.acl_active => {
+            let redirect = securitypolicy.acl_profile.redirect.as_ref().map(|r| (r, reqinfo));
+            matches.record(acl_block(true, *cde, tgs, redirect))
+        }
+        _ => None,
+    }
+}
+
+/// the decision of the checks, from the content filter decision and the ACL block, monitored when it was not enforced
+fn finish_checks(
+    mut matches: Matches,
+    securitypolicy: &SecurityPolicy,
+    reqinfo: &RequestInfo,
+    blockcode: Option<(i32, Vec<String>)>,
+    content_filter: Decision,
+) -> Decision {
+    // if the acl was not enforced, its decision is kept for logging purposes
+    // (when all the phases are run and the acl is active, it was already recorded)
+    let acl_monitor = match blockcode {
+        Some((cde, tgs)) if !(matches.run_all && securitypolicy.acl_active) => {
+            let redirect = securitypolicy.acl_profile.redirect.as_ref().map(|r| (r, reqinfo));
+            Some(acl_block(false, cde, &tgs, redirect))
+        }
         _ => None,
     };
-    logs.debug(|| format!("ACL checks done {:?}", blockcode));
+    let decision = match content_filter {
+        // if content filter was ok, but we had an acl decision, return the monitored acl decision
+        Decision::Pass => acl_monitor.unwrap_or(Decision::Pass),
+        decision => {
+            if let Some(monitored) = acl_monitor.filter(|_| matches.run_all) {
+                matches.record(monitored);
+            }
+            decision
+        }
+    };
+    matches.finish(decision)
+}
 
-    // if the acl is active, and we had a block result, immediately block
-    if securitypolicy.acl_active {
-        if let Some((cde, tgs)) = blockcode {
+/// combines the decisions of the phases that were run one by one, with the precedence of `analyze`: the global
+/// filters, the limits, the ACL, and the content filter, the phases that were not run being skipped
+pub fn combine_phases(
+    securitypolicy: &SecurityPolicy,
+    reqinfo: &RequestInfo,
+    tags: &Tags,
+    globalfilter: Decision,
+    limit: Option<Decision>,
+    acl: Option<AclOutcome>,
+    content_filter: Option<Decision>,
+) -> Decision {
+    let mut matches = Matches::new(securitypolicy);
+    let mut injected = None;
+    let done = |decision| inject(securitypolicy, decision, None, tags, reqinfo);
+    for decision in std::iter::once(globalfilter).chain(limit) {
+        stash_injection(&mut injected, &decision);
+        if let Some(decision) = matches.record(decision) {
+            return done(decision);
+        }
+    }
+    let blockcode = match acl.unwrap_or(AclOutcome::Pass) {
+        AclOutcome::Passthrough => return done(matches.finish(Decision::Pass)),
+        AclOutcome::Challenge(decision) => match matches.record(decision) {
+            Some(decision) => return done(decision),
+            None => None,
+        },
+        AclOutcome::Block(code, tgs) => Some((code, tgs)),
+        AclOutcome::Pass => None,
+    };
+    if let Some(decision) = enforce_acl(&mut matches, securitypolicy, reqinfo, &blockcode) {
+        return done(decision);
+    }
+    let decision = finish_checks(
+        matches,
+        securitypolicy,
+        reqinfo,
+        blockcode,
+        content_filter.unwrap_or(Decision::Pass),
+    );
+    inject(securitypolicy, decision, injected, tags, reqinfo)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn analyze_checks<GH: Grasshopper>(
+    logs: &mut Logs,
+    hsdb: &HashMap<String, ContentFilterRules>,
+    mgh: Option<GH>,
+    itags: Tags,
+    secpolname: &str,
+    securitypolicy: &SecurityPolicy,
+    reqinfo: RequestInfo,
+    is_human: bool,
+    globalfilter_dec: SimpleDecision,
+    flows: &HashMap<SequenceKey, Vec<FlowElement>>,
+    injected: &mut Option<Action>,
+) -> (Decision, Tags, RequestInfo) {
+    let mut tags = itags;
+    let masking_seed = &securitypolicy.content_filter_profile.masking_seed;
+    // the paths that are exempt from challenges are handled as if the client was a verified human
+    let is_human = is_human || securitypolicy.challenge.bypassed(&reqinfo.rinfo.qinfo.qpath);
+    let mut matches = Matches::new(securitypolicy);
+
+    logs.debug("request tagged");
+    tags.insert_qualified("securitypolicy", secpolname);
+    tags.insert_qualified("securitypolicy-entry", &securitypolicy.name);
+    tags.insert_qualified("aclid", &securitypolicy.acl_profile.id);
+    tags.insert_qualified("aclname", &securitypolicy.acl_profile.name);
+    tags.insert_qualified("contentfilterid", &securitypolicy.content_filter_profile.id);
+    tags.insert_qualified("contentfiltername", &securitypolicy.content_filter_profile.name);
+
+    if let Some(risk) = &securitypolicy.risk {
+        let score = risk.score(logs, &reqinfo).await;
+        risk.tag(score, &mut tags);
+        record_risk(reqinfo.rinfo.geoip.ip, score);
+    }
+    if let Some(campaigns) = &securitypolicy.campaigns {
+        campaigns.tag(&reqinfo, &mut tags);
+    }
+    if let Some(harvesting) = &securitypolicy.harvesting {
+        harvesting.tag(&reqinfo, &mut tags);
+    }
+
+    // the incident modes apply before any other check, the bypass disabling them all
+    if let Some(modes) = &securitypolicy.mode {
+        let mode = modes.current(logs).await;
+        if let Some(decision) = modes.check(mode, &mut tags) {
             return (
-                acl_block(true, cde, &tgs),
+                decision,
                 tags,
                 masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
             );
         }
     }
 
-    // otherwise, run content_filter_check
-    let content_filter_result = match HSDB.read() {
-        Ok(rd) => content_filter_check(
-            logs,
-            &mut tags,
-            &reqinfo,
-            &securitypolicy.content_filter_profile,
-            rd.get(&securitypolicy.content_filter_profile.id),
-        ),
-        Err(rr) => {
-            logs.error(|| format!("Could not get lock on HSDB: {}", rr));
-            Ok(())
+    // sessions banned by a previous decision are rejected before running the checks
+    if (!securitypolicy.ban_on_decision.is_empty() || securitypolicy.honeypot.is_some())
+        && is_decision_banned(logs, &reqinfo.session).await
+    {
+        tags.insert("banned");
+        let action = Action {
+            status: 403,
+            ban: true,
+            reason: Reason::new(Initiator::Ban).with_message("banned by a previous decision"),
+            ..Action::default()
+        };
+        return (
+            Decision::Action(action),
+            tags,
+            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+        );
+    }
+
+    if let Some(action) = securitypolicy
+        .honeypot
+        .as_ref()
+        .and_then(|h| h.check(&reqinfo, &mut tags))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
         }
-    };
-    logs.debug("Content Filter checks done");
+    }
 
-    (
-        match content_filter_result {
-            Ok(()) => {
-                // if content filter was ok, but we had an acl decision, return the monitored acl decision for logged purposes
-                if let Some((cde, tgs)) = blockcode {
-                    acl_block(false, cde, &tgs)
-                } else {
-                    Decision::Pass
-                }
+    if let Some(action) = securitypolicy
+        .allowed_methods
+        .as_ref()
+        .and_then(|m| m.check(&reqinfo, &mut tags))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(leaks) = &securitypolicy.content_filter_profile.leak_detection {
+        leaks.tag(logs, &reqinfo, &mut tags);
+    }
+
+    if !securitypolicy.content_filter_profile.content_type.is_empty() {
+        let merror: Option<&str> = match &reqinfo.rinfo.qinfo.body_decoding {
+            BodyDecodingResult::ProperlyDecoded => None,
+            // a truncated body can't be fully decoded, but it was present
+            BodyDecodingResult::Truncated(rr) => {
+                logs.info(|| format!("Truncated body could not be decoded: {}", rr));
+                None
             }
-            Err(wb) => {
-                let mut action = wb.to_action();
-                action.block_mode &= securitypolicy.content_filter_active;
-                Decision::Action(action)
+            BodyDecodingResult::DecodingFailed(rr) => Some(rr),
+            BodyDecodingResult::NoBody => Some("Expected a body, but there were none"),
+        };
+        if let Some(error) = merror {
+            // we expect the body to be properly decoded
+            let action = Action {
+                reason: Reason::new(Initiator::BodyDecoding).with_message(error),
+                status: 403,
+                ..Action::default()
+            };
+            if let Some(decision) = matches.record(Decision::Action(action)) {
+                return (
+                    decision,
+                    tags,
+                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+                );
             }
+        }
+    }
+
+    if let Some(action) = securitypolicy
+        .request_signature
+        .as_ref()
+        .and_then(|s| s.action(reqinfo.signature.as_ref()))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(action) = securitypolicy
+        .openapi
+        .as_ref()
+        .and_then(|o| o.action(reqinfo.openapi.as_ref()))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(action) = securitypolicy
+        .csrf
+        .as_ref()
+        .and_then(|c| c.action(reqinfo.csrf.as_ref()))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(action) = securitypolicy
+        .cookie_signing
+        .as_ref()
+        .and_then(|s| s.action(reqinfo.cookies_integrity.as_ref()))
+    {
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(ssrf) = &securitypolicy.ssrf {
+        if let Some(action) = ssrf.check(logs, &reqinfo, &mut tags).await {
+            if let Some(decision) = matches.record(Decision::Action(action)) {
+                return (
+                    decision,
+                    tags,
+                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+                );
+            }
+        }
+    }
+
+    if let Some(login) = &securitypolicy.login {
+        let entry = format!("{}/{}", secpolname, securitypolicy.name);
+        if let Some(decision) = login
+            .check(
+                logs,
+                &entry,
+                &reqinfo,
+                &mut tags,
+                is_human,
+                &mgh,
+                securitypolicy.captcha.as_deref(),
+            )
+            .and_then(|d| matches.record(d))
+        {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(threshold) = securitypolicy.challenge.bot_score_threshold {
+        if !is_human && reqinfo.bot.score >= threshold {
+            let challenge = SimpleAction {
+                atype: SimpleActionT::Challenge,
+                status: 403,
+                reason: "bot score".to_string(),
+                template: None,
+            };
+            let reason = Reason::new(Initiator::BotScore).with_message(format!(
+                "bot score {} ({})",
+                reqinfo.bot.score,
+                reqinfo.bot.signals.join(", ")
+            ));
+            let decision = challenge.to_decision(is_human, &mgh, securitypolicy.captcha.as_deref(), &reqinfo, reason);
+            if let Some(decision) = matches.record(decision) {
+                return (
+                    decision,
+                    tags,
+                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+                );
+            }
+        }
+    }
+
+    if reqinfo.parse_overflow() && securitypolicy.content_filter_profile.parse_budget.block_on_overflow {
+        let action = Action {
+            reason: Reason::new(Initiator::ParseBudget).with_message("the request exceeds the parsing budget"),
+            status: 403,
+            ..Action::default()
+        };
+        if let Some(decision) = matches.record(Decision::Action(action)) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    if let Some(captcha) = &securitypolicy.captcha {
+        if let Some(dec) = captcha
+            .phase02(logs, &reqinfo, securitypolicy.challenge.cookie_ttl)
+            .await
+        {
+            return (
+                dec,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+    if let Some(dec) = mgh.as_ref().and_then(|gh| {
+        challenge_phase02(
+            gh,
+            &reqinfo.rinfo.qinfo.uri,
+            &reqinfo.headers,
+            securitypolicy.challenge.cookie_ttl,
+        )
+    }) {
+        return (
+            dec,
+            tags,
+            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+        );
+    }
+    logs.debug("challenge phase2 ignored");
+    logs.phase("challenge");
+
+    if let SimpleDecision::Action(action, reason) = globalfilter_dec {
+        logs.debug(|| format!("Global filter decision {:?}", reason));
+        let decision = action.to_decision(is_human, &mgh, securitypolicy.captcha.as_deref(), &reqinfo, reason);
+        stash_injection(injected, &decision);
+        if let Some(decision) = matches.record(decision) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+
+    match flow_check(logs, flows, &reqinfo, &mut tags).await {
+        Err(rr) => logs.error(|| rr.to_string()),
+        Ok(SimpleDecision::Pass) => {}
+        Ok(SimpleDecision::Action(a, reason)) => {
+            let decision = a.to_decision(is_human, &mgh, securitypolicy.captcha.as_deref(), &reqinfo, reason);
+            stash_injection(injected, &decision);
+            if let Some(decision) = matches.record(decision) {
+                return (
+                    decision,
+                    tags,
+                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+                );
+            }
+        }
+    }
+    logs.debug("flow checks done");
+    logs.phase("flow");
+
+    // limit checks
+    let limit_check = limit_check(logs, &securitypolicy.name, &reqinfo, &securitypolicy.limits, &mut tags);
+    if let SimpleDecision::Action(action, reason) = limit_check.await {
+        let decision = action.to_decision(is_human, &mgh, securitypolicy.captcha.as_deref(), &reqinfo, reason);
+        stash_injection(injected, &decision);
+        if let Some(decision) = matches.record(decision) {
+            return (
+                decision,
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            );
+        }
+    }
+    logs.debug(|| format!("limit checks done ({} limits)", securitypolicy.limits.len()));
+    logs.phase("limit");
+
+    let blockcode = match acl_phase(logs, &tags, securitypolicy, &reqinfo, is_human, mgh.as_ref()) {
+        AclOutcome::Passthrough => {
+            return (
+                matches.finish(Decision::Pass),
+                tags,
+                masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+            )
+        }
+        AclOutcome::Challenge(decision) => match matches.record(decision) {
+            Some(decision) => {
+                return (
+                    decision,
+                    tags,
+                    masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+                )
+            }
+            None => None,
         },
+        AclOutcome::Block(code, tgs) => Some((code, tgs)),
+        AclOutcome::Pass => None,
+    };
+    logs.debug(|| format!("ACL checks done {:?}", blockcode));
+
+    // if the acl is active, and we had a block result, immediately block
+    if let Some(decision) = enforce_acl(&mut matches, securitypolicy, &reqinfo, &blockcode) {
+        return (
+            decision,
+            tags,
+            masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
+        );
+    }
+
+    // otherwise, run content_filter_check
+    let content_filter_result = content_filter_check(
+        logs,
+        &mut tags,
+        &reqinfo,
+        &securitypolicy.content_filter_profile,
+        hsdb.get(&securitypolicy.content_filter_profile.id),
+    );
+    logs.debug("Content Filter checks done");
+    logs.phase("content_filter");
+
+    let content_filter = content_filter_decision(content_filter_result, securitypolicy);
+    if let Some(campaigns) = &securitypolicy.campaigns {
+        campaigns.observe(logs, &reqinfo, &content_filter, &mut tags);
+    }
+    (
+        finish_checks(matches, securitypolicy, &reqinfo, blockcode, content_filter),
         tags,
         masking(masking_seed, reqinfo, &securitypolicy.content_filter_profile),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn observe_block() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        let decision = acl_block(true, 5, &["deny".to_string()], None);
+        let observed = match observe(&mut logs, decision, &mut tags) {
+            Decision::Action(a) => a,
+            Decision::Pass => panic!("observed decision should be an action"),
+        };
+        assert_eq!(observed.atype, ActionType::Monitor);
+        assert!(!observed.block_mode);
+        assert_eq!(observed.reason.initiator, Initiator::Observe);
+        assert_eq!(observed.reason.details["atype"], json!("block"));
+        assert_eq!(observed.reason.details["reason"]["initiator"], json!("acl"));
+        assert!(tags.contains("observe:would-block"));
+        assert!(tags.contains("observe-initiator:acl"));
+
+        let monitored = acl_block(false, 5, &["deny".to_string()], None);
+        let mut tags = Tags::default();
+        assert!(matches!(
+            observe(&mut logs, monitored, &mut tags),
+            Decision::Action(Action {
+                atype: ActionType::Monitor,
+                ..
+            })
+        ));
+        assert!(!tags.contains("observe:would-block"));
+    }
+
+    #[test]
+    fn human_acl_failures() {
+        let deny = |allowed| AclDecision {
+            allowed,
+            tags: vec!["t".to_string()],
+        };
+        let human_only = || {
+            AclResult::Match(BotHuman {
+                bot: None,
+                human: Some(deny(false)),
+            })
+        };
+        let is_challenged = |r: &AclResult| {
+            matches!(
+                r,
+                AclResult::Match(BotHuman {
+                    bot: Some(AclDecision { allowed: false, .. }),
+                    human: None
+                })
+            )
+        };
+        let mut policy = ChallengePolicy::default();
+        assert!(!is_challenged(&challenge_human_failures(human_only(), &policy, false)));
+        policy.human_acl_failure = HumanAclFailure::Challenge;
+        assert!(is_challenged(&challenge_human_failures(human_only(), &policy, false)));
+        // verified humans are blocked
+        assert!(!is_challenged(&challenge_human_failures(human_only(), &policy, true)));
+        // so are the clients that fail the bot rules too
+        let both = AclResult::Match(BotHuman {
+            bot: Some(deny(false)),
+            human: Some(deny(false)),
+        });
+        assert!(matches!(
+            challenge_human_failures(both, &policy, false),
+            AclResult::Match(BotHuman { human: Some(_), .. })
+        ));
+    }
+
+    #[test]
+    fn challenge_bypass() {
+        let policy = ChallengePolicy::resolve(
+            &mut Logs::default(),
+            &crate::config::raw::RawChallengePolicy {
+                bypass_paths: vec!["^/static/".to_string(), "^/health$".to_string(), "(".to_string()],
+                ..Default::default()
+            },
+        );
+        assert_eq!(policy.bypass_paths.len(), 2);
+        assert_eq!(policy.attempts_window, 3600);
+        assert!(policy.bypassed("/static/app.js"));
+        assert!(policy.bypassed("/health"));
+        assert!(!policy.bypassed("/healthz"));
+        assert!(!policy.bypassed("/login"));
+    }
+
+    #[test]
+    fn run_all_phases() {
+        let limit = || {
+            Decision::Action(Action {
+                reason: Reason::new(Initiator::Limit),
+                ..Action::default()
+            })
+        };
+        let monitor = acl_block(false, 5, &[], None);
+
+        let mut matches = Matches {
+            run_all: false,
+            actions: Vec::new(),
+        };
+        assert!(matches.record(monitor.clone()).is_none());
+        assert!(matches.record(limit()).is_some());
+
+        let mut matches = Matches {
+            run_all: true,
+            actions: Vec::new(),
+        };
+        assert!(matches.record(monitor).is_none());
+        assert!(matches.record(limit()).is_none());
+        let action = match matches.finish(acl_block(true, 5, &[], None)) {
+            Decision::Action(a) => a,
+            Decision::Pass => panic!("aggregated decision should be an action"),
+        };
+        assert_eq!(action.reason.initiator, Initiator::Limit);
+        assert_eq!(
+            action.reason.matches.iter().map(|r| r.initiator).collect::<Vec<_>>(),
+            vec![Initiator::Acl, Initiator::Acl]
+        );
+    }
+
+    #[test]
+    fn combined_phases() {
+        use crate::test_utils::{PolicyBuilder, RequestBuilder};
+
+        let rinfo = RequestBuilder::get("/").request_info();
+        let mut secpol = PolicyBuilder::new("default").build();
+        let action = |initiator| {
+            Decision::Action(Action {
+                reason: Reason::new(initiator),
+                ..Action::default()
+            })
+        };
+        let tags = Tags::default();
+        let initiator = |decision: Decision| match decision {
+            Decision::Action(a) => Some((a.reason.initiator, a.atype)),
+            Decision::Pass => None,
+        };
+        let combine = |secpol: &SecurityPolicy, limit, acl, content_filter| {
+            initiator(combine_phases(
+                secpol,
+                &rinfo,
+                &tags,
+                Decision::Pass,
+                limit,
+                acl,
+                content_filter,
+            ))
+        };
+
+        // the limits come first
+        assert_eq!(
+            combine(
+                &secpol,
+                Some(action(Initiator::Limit)),
+                Some(AclOutcome::Block(5, Vec::new())),
+                Some(action(Initiator::ContentFilter))
+            ),
+            Some((Initiator::Limit, ActionType::Block))
+        );
+        // then the active ACL
+        assert_eq!(
+            combine(
+                &secpol,
+                Some(Decision::Pass),
+                Some(AclOutcome::Block(5, Vec::new())),
+                Some(action(Initiator::ContentFilter))
+            ),
+            Some((Initiator::Acl, ActionType::Block))
+        );
+        // a passthrough skips the content filter
+        assert_eq!(
+            combine(
+                &secpol,
+                None,
+                Some(AclOutcome::Passthrough),
+                Some(action(Initiator::ContentFilter))
+            ),
+            None
+        );
+        // the phases that were not run are skipped
+        assert_eq!(
+            combine(&secpol, None, None, Some(action(Initiator::ContentFilter))),
+            Some((Initiator::ContentFilter, ActionType::Block))
+        );
+        // an inactive ACL is only monitored
+        secpol.acl_active = false;
+        assert_eq!(
+            combine(&secpol, None, Some(AclOutcome::Block(5, Vec::new())), None),
+            Some((Initiator::Acl, ActionType::Monitor))
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::accesslog::format_timestamp;
+use crate::crypto::to_hex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+/// the digests of the configuration entries, by file and id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Revision {
+    entries: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct FileDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+impl Revision {
+    /// records the entries of a configuration file, those without an `id` being identified by their position
+    pub fn add(&mut self, file: &str, values: &[serde_json::Value]) {
+        let entries = self.entries.entry(file.to_string()).or_default();
+        for (idx, value) in values.iter().enumerate() {
+            let id = value
+                .get("id")
+                .and_then(|i| i.as_str())
+                .map(|i| i.to_string())
+                .unwrap_or_else(|| format!("#{}", idx));
+            entries.insert(id, to_hex(&Sha256::digest(value.to_string().as_bytes())));
+        }
+    }
+
+    /// the digest of all the entries, in hexadecimal
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (file, entries) in &self.entries {
+            for (id, digest) in entries {
+                hasher.update(format!("{}\0{}\0{}\n", file, id, digest).as_bytes());
+            }
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    /// the files whose entries changed since `previous`
+    pub fn diff(&self, previous: &Revision) -> BTreeMap<String, FileDiff> {
+        let empty = BTreeMap::new();
+        let mut out = BTreeMap::new();
+        let files: BTreeSet<&String> = self.entries.keys().chain(previous.entries.keys()).collect();
+        for file in files {
+            let current = self.entries.get(file).unwrap_or(&empty);
+            let old = previous.entries.get(file).unwrap_or(&empty);
+            let diff = FileDiff {
+                added: current.keys().filter(|id| !old.contains_key(*id)).count(),
+                removed: old.keys().filter(|id| !current.contains_key(*id)).count(),
+                modified: current
+                    .iter()
+                    .filter(|(id, digest)| old.get(*id).map(|d| d != *digest).unwrap_or(false))
+                    .count(),
+            };
+            if diff != FileDiff::default() {
+                out.insert(file.clone(), diff);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConfigAudit {
+    pub timestamp: String,
+    /// the configuration path
+    pub source: String,
+    /// `modified` or `forced`
+    pub trigger: &'static str,
+    pub revision: String,
+    /// unknown for the first load of a path
+    pub previous_revision: Option<String>,
+    /// no errors were logged while loading the configuration
+    pub success: bool,
+    pub errors: usize,
+    pub warnings: usize,
+    pub duration_micros: u64,
+    pub diff: BTreeMap<String, FileDiff>,
+}
+
+impl ConfigAudit {
+    pub fn new(
+        source: &str,
+        trigger: &'static str,
+        revision: &Revision,
+        previous: Option<&Revision>,
+        errors: usize,
+        warnings: usize,
+        duration_micros: u64,
+    ) -> Self {
+        ConfigAudit {
+            timestamp: format_timestamp(SystemTime::now()),
+            source: source.to_string(),
+            trigger,
+            revision: revision.hash(),
+            previous_revision: previous.map(Revision::hash),
+            success: errors == 0,
+            errors,
+            warnings,
+            duration_micros,
+            diff: revision.diff(previous.unwrap_or(&Revision::default())),
+        }
+    }
+
+    /// logs the event
+    pub fn emit(&self) {
+        let diff = serde_json::to_string(&self.diff).unwrap_or_default();
+        macro_rules! event {
+            ($level:ident) => {
+                tracing::$level!(
+                    target: "curiefense::audit",
+                    timestamp = %self.timestamp,
+                    source = %self.source,
+                    trigger = self.trigger,
+                    revision = %self.revision,
+                    previous_revision = ?self.previous_revision,
+                    success = self.success,
+                    errors = self.errors,
+                    warnings = self.warnings,
+                    duration_micros = self.duration_micros,
+                    diff = %diff,
+                    "configuration activated"
+                )
+            };
+        }
+        if self.success {
+            event!(info)
+        } else {
+            event!(warn)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn revisions() {
+        let mut first = Revision::default();
+        first.add(
+            "limits.json",
+            &[json!({"id": "a", "limit": 1}), json!({"id": "b", "limit": 2})],
+        );
+        first.add("settings.json", &[json!({"x": 1})]);
+        let mut same = Revision::default();
+        same.add("settings.json", &[json!({"x": 1})]);
+        same.add(
+            "limits.json",
+            &[json!({"limit": 2, "id": "b"}), json!({"id": "a", "limit": 1})],
+        );
+        assert_eq!(first.hash(), same.hash());
+        assert!(same.diff(&first).is_empty());
+
+        let mut second = Revision::default();
+        second.add(
+            "limits.json",
+            &[json!({"id": "a", "limit": 10}), json!({"id": "c", "limit": 2})],
+        );
+        assert_ne!(first.hash(), second.hash());
+        let diff = second.diff(&first);
+        assert_eq!(
+            diff["limits.json"],
+            FileDiff {
+                added: 1,
+                removed: 1,
+                modified: 1
+            }
+        );
+        assert_eq!(
+            diff["settings.json"],
+            FileDiff {
+                added: 0,
+                removed: 1,
+                modified: 0
+            }
+        );
+
+        let audit = ConfigAudit::new("/config", "forced", &second, None, 0, 2, 100);
+        assert!(audit.success);
+        assert_eq!(audit.previous_revision, None);
+        assert_eq!(audit.diff["limits.json"].added, 2);
+    }
+}
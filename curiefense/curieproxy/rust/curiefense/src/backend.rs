@@ -0,0 +1,103 @@
+use crate::utils::env_or;
+use async_std::channel::{bounded, Receiver, Sender};
+use futures::executor::LocalPool;
+use futures::task::SpawnExt;
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+type Call = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+lazy_static! {
+    static ref CALLS: Option<Sender<Call>> = start();
+    /// the default deadline of the calls
+    pub static ref DEADLINE: Duration = Duration::from_millis(env_or("CURIEFENSE_BACKEND_TIMEOUT_MS", 100));
+}
+
+fn start() -> Option<Sender<Call>> {
+    let (sender, receiver) = bounded(env_or("CURIEFENSE_BACKEND_QUEUE", 1024).max(1));
+    let mut started = false;
+    for i in 0..env_or("CURIEFENSE_BACKEND_THREADS", 2).max(1) {
+        let receiver = receiver.clone();
+        match std::thread::Builder::new()
+            .name(format!("curiefense-backend-{}", i))
+            .spawn(move || run(receiver))
+        {
+            Ok(_) => started = true,
+            Err(rr) => tracing::error!(target: "curiefense::backend", "could not start a backend thread: {}", rr),
+        }
+    }
+    if started {
+        Some(sender)
+    } else {
+        None
+    }
+}
+
+/// runs the calls concurrently, until the queue is closed
+fn run(calls: Receiver<Call>) {
+    let mut pool = LocalPool::new();
+    let spawner = pool.spawner();
+    pool.run_until(async move {
+        while let Ok(call) = calls.recv().await {
+            if let Err(rr) = spawner.spawn(call) {
+                tracing::error!(target: "curiefense::backend", "could not run a backend call: {}", rr);
+            }
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendError {
+    /// the queue is full, or the backend threads could not be started
+    Unavailable,
+    /// the result was not available before the deadline
+    Timeout,
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Unavailable => write!(f, "the backend threads are saturated"),
+            BackendError::Timeout => write!(f, "the backend call timed out"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// runs the call on the backend threads, and waits for its result until the deadline
+pub async fn call<T, F>(deadline: Duration, future: F) -> Result<T, BackendError>
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    let calls = CALLS.as_ref().ok_or(BackendError::Unavailable)?;
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let call: Call = Box::pin(async move {
+        // the caller may have given up
+        let _ = sender.send(future.await);
+    });
+    calls.try_send(call).map_err(|_| BackendError::Unavailable)?;
+    match async_std::future::timeout(deadline, receiver).await {
+        Ok(Ok(result)) => Ok(result),
+        // the call panicked
+        Ok(Err(_)) => Err(BackendError::Unavailable),
+        Err(_) => Err(BackendError::Timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadlines() {
+        async_std::task::block_on(async {
+            assert_eq!(call(Duration::from_secs(5), async { 42 }).await, Ok(42));
+            let slow = async_std::task::sleep(Duration::from_secs(5));
+            assert_eq!(call(Duration::from_millis(10), slow).await, Err(BackendError::Timeout));
+        });
+    }
+}
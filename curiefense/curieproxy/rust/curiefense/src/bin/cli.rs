@@ -0,0 +1,68 @@
+use curiefense::cli::{parse_curl, parse_har, parse_raw, DEFAULT_IP};
+use curiefense::grasshopper::DummyGrasshopper;
+use curiefense::inspect_generic_request_map;
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::utils::InspectionRequest;
+use std::env;
+
+const USAGE: &str = "usage: curie-cli [--ip address] [--level level] <configuration path> (curl <curl arguments> | raw <file> | har <file> [entry])";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+fn read(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|rr| fail(&format!("{}: {}", path, rr)))
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut ip = DEFAULT_IP.to_string();
+    let mut level = LogLevel::Debug;
+    loop {
+        match args.first().map(|s| s.as_str()) {
+            Some("--ip") if args.len() > 1 => {
+                ip = args[1].clone();
+                args.drain(..2);
+            }
+            Some("--level") if args.len() > 1 => {
+                level = args[1].parse().unwrap_or_else(|rr: String| fail(&rr));
+                args.drain(..2);
+            }
+            Some("-h") | Some("--help") | None => fail(USAGE),
+            _ => break,
+        }
+    }
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let configpath = args[0].clone();
+    let request: Result<InspectionRequest, String> = match (args[1].as_str(), &args[2..]) {
+        ("curl", rest) => parse_curl(&ip, rest),
+        ("raw", [path]) => parse_raw(&ip, &read(path)),
+        ("har", [path]) => parse_har(&ip, &read(path), 0),
+        ("har", [path, entry]) => match entry.parse() {
+            Ok(entry) => parse_har(&ip, &read(path), entry),
+            Err(rr) => Err(format!("invalid entry index {}: {}", entry, rr)),
+        },
+        _ => Err(USAGE.to_string()),
+    };
+    let request = request.unwrap_or_else(|rr| fail(&rr));
+
+    if env::var("CURIEFENSE_LIMIT_STORE").is_err() {
+        env::set_var("CURIEFENSE_LIMIT_STORE", "local");
+    }
+    let raw = request.to_raw().unwrap_or_else(|rr| fail(rr));
+    let mut logs = Logs::new(level);
+    logs.explain = Some(Vec::new());
+    let (decision, tags, rinfo) = inspect_generic_request_map(&configpath, None::<DummyGrasshopper>, raw, &mut logs);
+    let blocking = decision.is_blocking();
+    let output = decision.to_value(rinfo, tags, logs);
+    match serde_json::to_string_pretty(&output) {
+        Ok(s) => println!("{}", s),
+        Err(rr) => fail(&rr.to_string()),
+    }
+    // like curl --fail, blocked requests exit with an error
+    std::process::exit(if blocking { 1 } else { 0 });
+}
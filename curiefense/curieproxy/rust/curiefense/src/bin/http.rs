@@ -0,0 +1,36 @@
+use curiefense::diagnostics;
+use curiefense::httpserver::{serve, InspectionService};
+use curiefense::logs::LogLevel;
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg = |i: usize, def: &str| args.get(i).cloned().unwrap_or_else(|| def.to_string());
+    let listen = arg(1, "0.0.0.0:8080");
+    let configpath = arg(2, "/cf-config/current/config");
+    let loglevel: LogLevel = match arg(3, "info").parse() {
+        Ok(l) => l,
+        Err(rr) => {
+            eprintln!("{}", rr);
+            std::process::exit(1);
+        }
+    };
+    let directives = env::var("CURIEFENSE_LOG").unwrap_or_else(|_| "info".to_string());
+    if let Err(rr) = diagnostics::init(&directives) {
+        eprintln!("{}", rr);
+        std::process::exit(1);
+    }
+    let addr = match listen.parse() {
+        Ok(a) => a,
+        Err(rr) => {
+            eprintln!("invalid listen address {}: {}", listen, rr);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("serving HTTP inspections on {}, configuration {}", addr, configpath);
+    if let Err(rr) = serve(addr, InspectionService::new(configpath, loglevel)).await {
+        tracing::error!("server error: {}", rr);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,25 @@
+use curiefense::lint::lint;
+use std::env;
+
+const USAGE: &str = "usage: curieconf-lint [--strict] <configuration path>";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (strict, configpath) = match args.as_slice() {
+        [flag, path] if flag == "--strict" => (true, path),
+        [path] if !path.starts_with('-') => (false, path),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let report = lint(configpath);
+    match serde_json::to_string_pretty(&report) {
+        Ok(s) => println!("{}", s),
+        Err(rr) => {
+            eprintln!("{}", rr);
+            std::process::exit(2);
+        }
+    }
+    std::process::exit(if report.failed(strict) { 1 } else { 0 });
+}
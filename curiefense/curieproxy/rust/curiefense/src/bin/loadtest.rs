@@ -0,0 +1,63 @@
+use curiefense::config::config_snapshot;
+use curiefense::loadtest::{load_corpus, run, LoadOptions};
+use curiefense::logs::{LogLevel, Logs};
+use std::env;
+
+const USAGE: &str = "usage: curiefense-loadtest [--rate rps] [--requests count] [--workers count] <configuration path> <corpus file>";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("invalid {}: {}", name, value)))
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut options = LoadOptions::default();
+    loop {
+        match args.first().map(|s| s.as_str()) {
+            Some("--rate") if args.len() > 1 => options.rate = number("rate", &args[1]),
+            Some("--requests") if args.len() > 1 => options.requests = number("request count", &args[1]),
+            Some("--workers") if args.len() > 1 => options.workers = number("worker count", &args[1]),
+            Some("-h") | Some("--help") | None => fail(USAGE),
+            _ => break,
+        }
+        args.drain(..2);
+    }
+    let (configpath, corpuspath) = match args.as_slice() {
+        [configpath, corpuspath] => (configpath, corpuspath),
+        _ => fail(USAGE),
+    };
+    if !(options.rate >= 0.0 && options.rate.is_finite()) {
+        fail(&format!("invalid rate: {}", options.rate));
+    }
+
+    let content = std::fs::read_to_string(corpuspath).unwrap_or_else(|rr| fail(&format!("{}: {}", corpuspath, rr)));
+    let corpus = load_corpus(&content).unwrap_or_else(|rr| fail(&format!("{}: {}", corpuspath, rr)));
+    if env::var("CURIEFENSE_LIMIT_STORE").is_err() {
+        env::set_var("CURIEFENSE_LIMIT_STORE", "local");
+    }
+    let mut logs = Logs::new(LogLevel::Error);
+    let snapshot = match config_snapshot(configpath, &mut logs) {
+        Some(s) if s.config.default.is_some() || !s.config.securitypolicies.is_empty() => s,
+        _ => fail(&format!(
+            "could not load the configuration from {}: {:?}",
+            configpath,
+            logs.to_stringvec()
+        )),
+    };
+
+    let report = run(&snapshot, &corpus, &options);
+    match serde_json::to_string_pretty(&report) {
+        Ok(s) => println!("{}", s),
+        Err(rr) => fail(&rr.to_string()),
+    }
+    if report.requests == 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,41 @@
+use curiefense::replay::replay;
+use std::env;
+use std::fs::File;
+use std::io::{stdin, BufReader};
+
+const USAGE: &str = "usage: curiefense-replay [--seed number] <candidate configuration path> [access log file]";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut seed = 0;
+    if args.first().map(|a| a.as_str()) == Some("--seed") && args.len() > 1 {
+        seed = args[1]
+            .parse()
+            .unwrap_or_else(|_| fail(&format!("invalid seed: {}", args[1])));
+        args.drain(..2);
+    }
+    let configpath = match args.first() {
+        Some(p) if !p.starts_with('-') => p.clone(),
+        _ => fail(USAGE),
+    };
+    if env::var("CURIEFENSE_LIMIT_STORE").is_err() {
+        env::set_var("CURIEFENSE_LIMIT_STORE", "local");
+    }
+    let report = match args.get(1) {
+        None => replay(&configpath, stdin().lock(), seed),
+        Some(path) => match File::open(path) {
+            Ok(f) => replay(&configpath, BufReader::new(f), seed),
+            Err(rr) => fail(&format!("{}: {}", path, rr)),
+        },
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(s) => println!("{}", s),
+        Err(rr) => fail(&rr.to_string()),
+    }
+    std::process::exit(if report.changed.is_empty() { 0 } else { 1 });
+}
@@ -0,0 +1,380 @@
+use crate::clock;
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+lazy_static! {
+    static ref BLOCKLIST: Option<Arc<Blocklist>> = start();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    Plain,
+    Ipset,
+    Envoy,
+}
+
+impl std::str::FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(ListFormat::Plain),
+            "ipset" => Ok(ListFormat::Ipset),
+            "envoy" => Ok(ListFormat::Envoy),
+            _ => Err(format!(
+                "unknown blocklist format {}, expected plain, ipset or envoy",
+                s
+            )),
+        }
+    }
+}
+
+/// why the address is listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Ban,
+    Risk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub ip: IpAddr,
+    pub until: SystemTime,
+    pub source: Source,
+}
+
+#[derive(Debug)]
+pub struct Blocklist {
+    max: usize,
+    /// 0 when the high risk clients are not listed
+    risk_score: f64,
+    risk_ttl: Duration,
+    interval: Duration,
+    entries: Mutex<HashMap<IpAddr, (SystemTime, Source)>>,
+    /// the addresses that were not listed because the list was full
+    dropped: AtomicU64,
+    /// the last export, and when it was computed
+    snapshot: Mutex<Option<(SystemTime, Arc<Vec<Entry>>)>>,
+}
+
+impl Blocklist {
+    pub fn new(max: usize, risk_score: f64, risk_ttl: Duration, interval: Duration) -> Self {
+        Blocklist {
+            max,
+            risk_score,
+            risk_ttl,
+            interval,
+            entries: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// lists the address until `now + duration`, an existing entry being only extended
+    pub fn record(&self, ip: IpAddr, duration: Duration, source: Source, now: SystemTime) {
+        let until = now + duration;
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        if !entries.contains_key(&ip) && entries.len() >= self.max {
+            entries.retain(|_, (u, _)| *u > now);
+            if entries.len() >= self.max {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        let entry = entries.entry(ip).or_insert((until, source));
+        if entry.0 < until {
+            *entry = (until, source);
+        }
+    }
+
+    /// lists the client when its score reaches the threshold
+    pub fn record_score(&self, ip: IpAddr, score: f64, now: SystemTime) {
+        if self.risk_score > 0.0 && score >= self.risk_score {
+            self.record(ip, self.risk_ttl, Source::Risk, now);
+        }
+    }
+
+    /// the listed addresses, sorted, the expired ones being removed
+    pub fn entries(&self, now: SystemTime) -> Vec<Entry> {
+        let mut out: Vec<Entry> = match self.entries.lock() {
+            Ok(mut entries) => {
+                entries.retain(|_, (until, _)| *until > now);
+                entries
+                    .iter()
+                    .map(|(ip, (until, source))| Entry {
+                        ip: *ip,
+                        until: *until,
+                        source: *source,
+                    })
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        out.sort_by_key(|e| e.ip);
+        out
+    }
+
+    /// the listed addresses, computed at most once per interval
+    pub fn snapshot(&self, now: SystemTime) -> Arc<Vec<Entry>> {
+        let mut snapshot = match self.snapshot.lock() {
+            Ok(s) => s,
+            Err(_) => return Arc::new(self.entries(now)),
+        };
+        match snapshot.as_ref() {
+            Some((at, entries)) if now.duration_since(*at).map(|d| d < self.interval).unwrap_or(true) => {
+                entries.clone()
+            }
+            _ => {
+                let entries = Arc::new(self.entries(now));
+                *snapshot = Some((now, entries.clone()));
+                entries
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn start() -> Option<Arc<Blocklist>> {
+    let spec = std::env::var("CURIEFENSE_BLOCKLIST_EXPORT")
+        .ok()
+        .filter(|s| !s.is_empty())?;
+    let blocklist = Arc::new(Blocklist::new(
+        env_or("CURIEFENSE_BLOCKLIST_MAX", 10000),
+        env_or("CURIEFENSE_BLOCKLIST_RISK_SCORE", 0.0),
+        Duration::from_secs(env_or("CURIEFENSE_BLOCKLIST_RISK_TTL_SECS", 600)),
+        Duration::from_secs(env_or("CURIEFENSE_BLOCKLIST_INTERVAL_SECS", 30).max(1)),
+    ));
+    if spec == "http" {
+        return Some(blocklist);
+    }
+    let path = match spec.strip_prefix("file:") {
+        Some(p) if !p.is_empty() => PathBuf::from(p),
+        _ => {
+            tracing::error!(target: "curiefense::blocklist", "invalid blocklist export {}, expected file:<path> or http", spec);
+            return None;
+        }
+    };
+    let format = match std::env::var("CURIEFENSE_BLOCKLIST_FORMAT") {
+        Ok(f) if !f.is_empty() => match f.parse() {
+            Ok(f) => f,
+            Err(rr) => {
+                tracing::error!(target: "curiefense::blocklist", "{}", rr);
+                return None;
+            }
+        },
+        _ => ListFormat::Plain,
+    };
+    let writer = blocklist.clone();
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-blocklist".to_string())
+        .spawn(move || run(&writer, &path, format))
+    {
+        tracing::error!(target: "curiefense::blocklist", "could not start the blocklist export: {}", rr);
+        return None;
+    }
+    Some(blocklist)
+}
+
+fn run(blocklist: &Blocklist, path: &Path, format: ListFormat) {
+    loop {
+        let now = clock::now();
+        let content = render(&blocklist.snapshot(now), format, now);
+        if let Err(rr) = write(path, &content) {
+            tracing::warn!(target: "curiefense::blocklist", "could not export the blocklist: {}", rr);
+        }
+        std::thread::sleep(blocklist.interval);
+    }
+}
+
+/// writes the file next to the exported one, and renames it, so that the readers never see a partial list
+fn write(path: &Path, content: &str) -> Result<(), String> {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    std::fs::write(&tmp, content).map_err(|rr| format!("could not write {}: {}", tmp.display(), rr))?;
+    std::fs::rename(&tmp, path).map_err(|rr| format!("could not rename {}: {}", tmp.display(), rr))
+}
+
+/// the seconds left, at least one
+fn remaining(entry: &Entry, now: SystemTime) -> u64 {
+    entry.until.duration_since(now).map(|d| d.as_secs()).unwrap_or(0).max(1)
+}
+
+pub fn render(entries: &[Entry], format: ListFormat, now: SystemTime) -> String {
+    match format {
+        ListFormat::Plain => {
+            let mut out = format!("# curiefense blocklist, {} addresses\n", entries.len());
+            for entry in entries {
+                out.push_str(&format!("{}\n", entry.ip));
+            }
+            out
+        }
+        ListFormat::Ipset => {
+            let set = std::env::var("CURIEFENSE_BLOCKLIST_IPSET")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "curiefense-blocklist".to_string());
+            let mut out = format!(
+                "create {} hash:ip family inet timeout 0 -exist\ncreate {}6 hash:ip family inet6 timeout 0 -exist\n",
+                set, set
+            );
+            for entry in entries {
+                let family = if entry.ip.is_ipv4() { "" } else { "6" };
+                out.push_str(&format!(
+                    "add {}{} {} timeout {} -exist\n",
+                    set,
+                    family,
+                    entry.ip,
+                    remaining(entry, now)
+                ));
+            }
+            out
+        }
+        ListFormat::Envoy => {
+            let principals: Vec<serde_json::Value> = entries
+                .iter()
+                .map(|entry| {
+                    serde_json::json!({ "direct_remote_ip": {
+                        "address_prefix": entry.ip.to_string(),
+                        "prefix_len": if entry.ip.is_ipv4() { 32 } else { 128 },
+                    }})
+                })
+                .collect();
+            // a policy needs principals, the empty list denies nothing
+            let policies = if principals.is_empty() {
+                serde_json::json!({})
+            } else {
+                serde_json::json!({ "curiefense-blocklist": {
+                    "permissions": [{ "any": true }],
+                    "principals": principals,
+                }})
+            };
+            serde_json::json!({ "action": "DENY", "policies": policies }).to_string()
+        }
+    }
+}
+
+/// lists the client of a new ban, for `duration` seconds
+pub fn record_ban(ip: Option<IpAddr>, duration: u64) {
+    if let (Some(blocklist), Some(ip)) = (BLOCKLIST.as_ref(), ip) {
+        blocklist.record(ip, Duration::from_secs(duration), Source::Ban, clock::now());
+    }
+}
+
+/// lists the client when its risk score is high enough
+pub fn record_risk(ip: Option<IpAddr>, score: f64) {
+    if let (Some(blocklist), Some(ip)) = (BLOCKLIST.as_ref(), ip) {
+        blocklist.record_score(ip, score, clock::now());
+    }
+}
+
+/// the list, in this format, when it is exported
+pub fn export(format: ListFormat) -> Option<String> {
+    BLOCKLIST.as_ref().map(|blocklist| {
+        let now = clock::now();
+        render(&blocklist.snapshot(now), format, now)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn entries() {
+        let blocklist = Blocklist::new(2, 50.0, Duration::from_secs(600), Duration::from_secs(30));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        blocklist.record(ip("10.0.0.2"), Duration::from_secs(60), Source::Ban, now);
+        blocklist.record_score(ip("10.0.0.1"), 49.0, now);
+        blocklist.record_score(ip("10.0.0.1"), 50.0, now);
+        // an existing entry is extended, never shortened
+        blocklist.record(ip("10.0.0.1"), Duration::from_secs(60), Source::Ban, now);
+        let listed = blocklist.entries(now);
+        assert_eq!(
+            listed,
+            vec![
+                Entry {
+                    ip: ip("10.0.0.1"),
+                    until: now + Duration::from_secs(600),
+                    source: Source::Risk
+                },
+                Entry {
+                    ip: ip("10.0.0.2"),
+                    until: now + Duration::from_secs(60),
+                    source: Source::Ban
+                },
+            ]
+        );
+
+        // the list is full, until an entry expires
+        blocklist.record(ip("10.0.0.3"), Duration::from_secs(60), Source::Ban, now);
+        assert_eq!(blocklist.dropped(), 1);
+        let later = now + Duration::from_secs(61);
+        blocklist.record(ip("10.0.0.3"), Duration::from_secs(60), Source::Ban, later);
+        let listed: Vec<IpAddr> = blocklist.entries(later).iter().map(|e| e.ip).collect();
+        assert_eq!(listed, vec![ip("10.0.0.1"), ip("10.0.0.3")]);
+
+        // the snapshot is refreshed once per interval
+        let snapshot = blocklist.snapshot(later);
+        blocklist.record(ip("10.0.0.1"), Duration::from_secs(6000), Source::Ban, later);
+        assert!(Arc::ptr_eq(
+            &snapshot,
+            &blocklist.snapshot(later + Duration::from_secs(29))
+        ));
+        assert!(!Arc::ptr_eq(
+            &snapshot,
+            &blocklist.snapshot(later + Duration::from_secs(30))
+        ));
+    }
+
+    #[test]
+    fn formats() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let entries = vec![
+            Entry {
+                ip: ip("10.0.0.1"),
+                until: now + Duration::from_secs(300),
+                source: Source::Ban,
+            },
+            Entry {
+                ip: ip("2001:db8::1"),
+                until: now,
+                source: Source::Risk,
+            },
+        ];
+        assert_eq!(
+            render(&entries, ListFormat::Plain, now),
+            "# curiefense blocklist, 2 addresses\n10.0.0.1\n2001:db8::1\n"
+        );
+        let ipset = render(&entries, ListFormat::Ipset, now);
+        assert!(ipset.contains("add curiefense-blocklist 10.0.0.1 timeout 300 -exist\n"));
+        assert!(ipset.contains("add curiefense-blocklist6 2001:db8::1 timeout 1 -exist\n"));
+        let envoy: serde_json::Value = serde_json::from_str(&render(&entries, ListFormat::Envoy, now)).unwrap();
+        assert_eq!(envoy["action"], "DENY");
+        assert_eq!(
+            envoy["policies"]["curiefense-blocklist"]["principals"][1],
+            serde_json::json!({"direct_remote_ip": {"address_prefix": "2001:db8::1", "prefix_len": 128}})
+        );
+        assert_eq!(
+            render(&[], ListFormat::Envoy, now),
+            r#"{"action":"DENY","policies":{}}"#
+        );
+        assert_eq!("ipset".parse(), Ok(ListFormat::Ipset));
+        assert!("iptables".parse::<ListFormat>().is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::raw::{BlockResponse, ResponseTemplate, TemplateFormat};
+use crate::interface::{Action, ActionType, Decision};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+use std::collections::HashMap;
+
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// escapes a value so that it can be used inside a JSON string
+fn escape_json(s: &str) -> String {
+    let quoted = serde_json::Value::String(s.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+pub fn render(template: &ResponseTemplate, action: &Action, rinfo: &RequestInfo) -> String {
+    let escape = match template.format {
+        TemplateFormat::Html => escape_html,
+        TemplateFormat::Json => escape_json,
+    };
+    let reason = action.reason.initiator.as_str();
+    [
+        ("{{request_id}}", rinfo.request_id.as_str()),
+        ("{{reason}}", reason),
+        ("{{ip}}", &rinfo.rinfo.geoip.ipstr),
+        ("{{support_contact}}", &template.support_contact),
+    ]
+    .iter()
+    .fold(template.content.clone(), |acc, (placeholder, value)| {
+        if acc.contains(placeholder) {
+            acc.replace(placeholder, &escape(value))
+        } else {
+            acc
+        }
+    })
+}
+
+fn set_content_type(action: &mut Action, content_type: &str) {
+    action
+        .headers
+        .get_or_insert_with(HashMap::new)
+        .insert("Content-Type".to_string(), content_type.to_string());
+}
+
+pub fn apply_block_response(action: &mut Action, response: &BlockResponse) {
+    if let Some(status) = response.status {
+        action.status = status;
+    }
+    if let Some(content) = &response.content {
+        action.content = content.clone();
+    }
+    if let Some(content_type) = &response.content_type {
+        set_content_type(action, content_type);
+    }
+}
+
+/// the JSON body of blocking actions, for APIs
+fn json_error(action: &Action, rinfo: &RequestInfo) -> String {
+    serde_json::json!({
+        "error": action.content,
+        "status": action.status,
+        "reason": action.reason.initiator,
+        "request_id": rinfo.request_id,
+    })
+    .to_string()
+}
+
+/// renders the content of blocking actions, when a template is available
+///
+/// challenges and redirections are left untouched
+pub fn apply_template(logs: &mut Logs, decision: Decision, rinfo: &RequestInfo, secpol: &SecurityPolicy) -> Decision {
+    let mut action = match decision {
+        Decision::Action(a) if a.atype == ActionType::Block && a.status >= 400 => a,
+        d => return d,
+    };
+    let response = secpol
+        .block_responses
+        .get(action.reason.initiator.as_str())
+        .or_else(|| secpol.block_responses.get("default"));
+    if let Some(response) = response {
+        apply_block_response(&mut action, response);
+    }
+    let tid = match action.template.as_ref().or(secpol.template.as_ref()) {
+        None => {
+            if secpol.json_errors {
+                action.content = json_error(&action, rinfo);
+                if response.and_then(|r| r.content_type.as_ref()).is_none() {
+                    set_content_type(&mut action, "application/json");
+                }
+            }
+            return Decision::Action(action);
+        }
+        Some(t) => t,
+    };
+    let template = match secpol.templates.get(tid) {
+        None => {
+            logs.warning(|| format!("Unknown response template {}", tid));
+            return Decision::Action(action);
+        }
+        Some(t) => t,
+    };
+    action.content = render(template, &action, rinfo);
+    let content_type = match response.and_then(|r| r.content_type.as_deref()) {
+        Some(ct) => ct,
+        None => match template.format {
+            TemplateFormat::Html => "text/html; charset=utf-8",
+            TemplateFormat::Json => "application/json",
+        },
+    };
+    set_content_type(&mut action, content_type);
+    Decision::Action(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reason::{Initiator, Reason};
+    use crate::test_utils::RequestBuilder;
+    use serde_json::json;
+
+    fn rinfo() -> RequestInfo {
+        RequestBuilder::get("/").header("x-request-id", "<abc>").request_info()
+    }
+
+    fn template(format: TemplateFormat, content: &str) -> ResponseTemplate {
+        ResponseTemplate {
+            id: "t".to_string(),
+            name: "t".to_string(),
+            format,
+            content: content.to_string(),
+            support_contact: "support@\"example\".com".to_string(),
+        }
+    }
+
+    #[test]
+    fn render_html() {
+        let action = Action {
+            reason: Reason::new(Initiator::Acl),
+            ..Action::default()
+        };
+        let tpl = template(
+            TemplateFormat::Html,
+            "<p>{{request_id}} {{reason}} {{ip}} {{support_contact}} {{unknown}}</p>",
+        );
+        assert_eq!(
+            render(&tpl, &action, &rinfo()),
+            "<p>&lt;abc&gt; acl 1.2.3.4 support@&quot;example&quot;.com {{unknown}}</p>"
+        );
+    }
+
+    #[test]
+    fn block_response() {
+        let mut action = Action {
+            reason: Reason::new(Initiator::Limit),
+            ..Action::default()
+        };
+        apply_block_response(
+            &mut action,
+            &BlockResponse {
+                status: Some(429),
+                content_type: Some("text/plain".to_string()),
+                content: Some("slow down".to_string()),
+            },
+        );
+        assert_eq!(action.status, 429);
+        assert_eq!(action.content, "slow down");
+        assert_eq!(
+            action
+                .headers
+                .as_ref()
+                .and_then(|h| h.get("Content-Type"))
+                .map(|s| s.as_str()),
+            Some("text/plain")
+        );
+        let body: serde_json::Value = serde_json::from_str(&json_error(&action, &rinfo())).unwrap();
+        assert_eq!(
+            body,
+            json!({"error": "slow down", "status": 429, "reason": "limit", "request_id": "<abc>"})
+        );
+    }
+
+    #[test]
+    fn render_json() {
+        let tpl = template(
+            TemplateFormat::Json,
+            r#"{"id": "{{request_id}}", "contact": "{{support_contact}}"}"#,
+        );
+        let rendered: serde_json::Value = serde_json::from_str(&render(&tpl, &Action::default(), &rinfo())).unwrap();
+        assert_eq!(rendered, json!({"id": "<abc>", "contact": "support@\"example\".com"}));
+    }
+}
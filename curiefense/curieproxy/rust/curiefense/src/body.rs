@@ -18,6 +18,7 @@ use crate::config::raw::ContentType;
 use crate::config::utils::DataSource;
 use crate::interface::{Action, ActionType};
 use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
 use crate::requestfields::RequestField;
 use crate::utils::decoders::parse_urlencoded_params_bytes;
 
@@ -269,6 +270,7 @@ fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8])
             let mut content = Vec::new();
             let _ = entry.data.read_to_end(&mut content);
             let name = entry.headers.name.to_string();
+            let name = args.param_key(name);
             let scontent = String::from_utf8_lossy(&content);
             args.add(name, DataSource::FromBody, scontent.to_string());
         })
@@ -350,13 +352,14 @@ pub fn body_too_deep(expected: usize, actual: usize) -> Action {
         ban: false,
         status: 403,
         headers: None,
-        reason: json!({
-            "initiator": "body_max_depth",
+        reason: Reason::new(Initiator::BodyMaxDepth).with_details(json!({
             "expected": expected,
             "actual": actual
-        }),
+        })),
         content: "Access denied".to_string(),
         extra_tags: None,
+        template: None,
+        mutations: Vec::new(),
     }
 }
 
@@ -367,13 +370,14 @@ pub fn body_too_large(expected: usize, actual: usize) -> Action {
         ban: false,
         status: 403,
         headers: None,
-        reason: json!({
-            "initiator": "body_max_size",
+        reason: Reason::new(Initiator::BodyMaxSize).with_details(json!({
             "expected": expected,
             "actual": actual
-        }),
+        })),
         content: "Access denied".to_string(),
         extra_tags: None,
+        template: None,
+        mutations: Vec::new(),
     }
 }
 
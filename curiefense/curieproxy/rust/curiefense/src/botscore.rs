@@ -0,0 +1,207 @@
+use crate::requestfields::RequestField;
+use crate::utils::RequestMeta;
+
+/// the user agent fragments of the HTTP libraries and of the browser automation frameworks, lowercased
+const AUTOMATION_AGENTS: &[&str] = &[
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "httpx",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "apache-httpclient",
+    "libwww-perl",
+    "node-fetch",
+    "axios/",
+    "scrapy",
+    "headlesschrome",
+    "phantomjs",
+    "selenium",
+    "puppeteer",
+    "playwright",
+];
+
+/// the score of the request, and the names of the signals that raised it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BotScore {
+    pub score: u8,
+    pub signals: Vec<&'static str>,
+}
+
+impl BotScore {
+    fn add(&mut self, signal: &'static str, weight: u8) {
+        self.score = self.score.saturating_add(weight).min(100);
+        self.signals.push(signal);
+    }
+
+    /// the coarse level of the score, for the `bot-score` tag
+    pub fn level(&self) -> &'static str {
+        match self.score {
+            70..=100 => "high",
+            30..=69 => "medium",
+            _ => "low",
+        }
+    }
+}
+
+/// the browser family claimed by a user agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Browser {
+    Chromium,
+    Firefox,
+    Safari,
+}
+
+fn claimed_browser(ua: &str) -> Option<Browser> {
+    if !ua.starts_with("mozilla/") {
+        return None;
+    }
+    if ua.contains("firefox/") {
+        Some(Browser::Firefox)
+    } else if ua.contains("chrome/") || ua.contains("chromium/") || ua.contains("edg/") {
+        Some(Browser::Chromium)
+    } else if ua.contains("safari/") {
+        Some(Browser::Safari)
+    } else {
+        None
+    }
+}
+
+/// the platform of a `sec-ch-ua-platform` hint is consistent with the user agent
+fn platform_matches(platform: &str, ua: &str) -> bool {
+    match platform.trim_matches('"').to_lowercase().as_str() {
+        "windows" => ua.contains("windows"),
+        "macos" => ua.contains("macintosh") || ua.contains("mac os"),
+        "linux" => ua.contains("linux") && !ua.contains("android"),
+        "android" => ua.contains("android"),
+        "ios" => ua.contains("iphone") || ua.contains("ipad"),
+        "chrome os" | "chromeos" => ua.contains("cros"),
+        // unknown platforms are not held against the client
+        _ => true,
+    }
+}
+
+/// computes the heuristic bot score of a request
+pub fn bot_score(meta: &RequestMeta, headers: &RequestField) -> BotScore {
+    let mut score = BotScore::default();
+    let ua = match headers.get_str("user-agent").map(|u| u.trim().to_lowercase()) {
+        Some(ua) if !ua.is_empty() => ua,
+        _ => {
+            score.add("missing-user-agent", 60);
+            return score;
+        }
+    };
+    if AUTOMATION_AGENTS.iter().any(|a| ua.contains(a)) {
+        score.add("automation-framework", 80);
+    }
+    if headers.get("x-selenium").is_some() || headers.get("x-devtools-emulate-network-conditions-client-id").is_some() {
+        score.add("automation-header", 80);
+    }
+    let browser = match claimed_browser(&ua) {
+        Some(b) => b,
+        None => return score,
+    };
+
+    // the headers that every browser sends along its navigations and subresource requests
+    if headers.get("accept").is_none() {
+        score.add("missing-accept", 20);
+    }
+    if headers.get("accept-language").is_none() {
+        score.add("missing-accept-language", 20);
+    }
+    if headers.get("accept-encoding").is_none() {
+        score.add("missing-accept-encoding", 20);
+    }
+    if meta.http_version.as_deref() == Some("1.0") {
+        score.add("http-version-mismatch", 30);
+    }
+    let hints = headers.get("sec-ch-ua").is_some();
+    if hints && browser != Browser::Chromium {
+        score.add("client-hints-mismatch", 30);
+    } else if let Some(platform) = headers.get_str("sec-ch-ua-platform") {
+        if !platform_matches(platform, &ua) {
+            score.add("client-hints-mismatch", 30);
+        }
+    }
+    // the browsers send the host header first on HTTP/1, and the cookies after the user agent
+    if let Some(order) = &meta.header_order {
+        let position = |name: &str| order.iter().position(|h| h == name);
+        let http1 = matches!(meta.http_version.as_deref(), Some("1.0") | Some("1.1"));
+        let host_not_first = http1 && position("host").map(|p| p > 0).unwrap_or(false);
+        let cookie_first = matches!((position("cookie"), position("user-agent")), (Some(c), Some(u)) if c < u);
+        if host_not_first || cookie_first {
+            score.add("header-order", 25);
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    const CHROME: &str =
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+
+    fn browser(ua: &str) -> RequestBuilder {
+        RequestBuilder::get("/")
+            .header("user-agent", ua)
+            .header("accept", "text/html")
+            .header("accept-language", "en-US")
+            .header("accept-encoding", "gzip, br")
+    }
+
+    fn score(rq: RequestBuilder) -> BotScore {
+        let reqinfo = rq.request_info();
+        bot_score(&reqinfo.rinfo.meta, &reqinfo.headers)
+    }
+
+    #[test]
+    fn browsers() {
+        assert_eq!(score(browser(CHROME)), BotScore::default());
+        let chrome = browser(CHROME)
+            .header("sec-ch-ua", "\"Chromium\";v=\"120\"")
+            .header("sec-ch-ua-platform", "\"Windows\"")
+            .meta("http_version", "HTTP/2")
+            .meta("header_order", "user-agent,accept,cookie");
+        assert_eq!(score(chrome).score, 0);
+        assert_eq!(score(browser(FIREFOX)).level(), "low");
+    }
+
+    #[test]
+    fn inconsistencies() {
+        assert_eq!(score(RequestBuilder::get("/")).signals, vec!["missing-user-agent"]);
+        let curl = score(RequestBuilder::get("/").header("user-agent", "curl/8.4.0"));
+        assert_eq!((curl.score, curl.level()), (80, "high"));
+
+        let bare = score(RequestBuilder::get("/").header("user-agent", CHROME));
+        assert_eq!(
+            bare.signals,
+            vec!["missing-accept", "missing-accept-language", "missing-accept-encoding"]
+        );
+        assert_eq!(bare.level(), "medium");
+
+        let firefox = score(
+            browser(FIREFOX)
+                .header("sec-ch-ua", "\"Chromium\";v=\"120\"")
+                .meta("http_version", "1.0"),
+        );
+        assert_eq!(firefox.signals, vec!["http-version-mismatch", "client-hints-mismatch"]);
+        let platform = score(browser(CHROME).header("sec-ch-ua-platform", "\"macOS\""));
+        assert_eq!(platform.signals, vec!["client-hints-mismatch"]);
+
+        let order = score(
+            browser(CHROME)
+                .meta("http_version", "1.1")
+                .meta("header_order", "user-agent,host,accept"),
+        );
+        assert_eq!(order.signals, vec!["header-order"]);
+        let headless = score(browser(&CHROME.replace("Chrome/", "HeadlessChrome/")));
+        assert_eq!(headless.signals, vec!["automation-framework"]);
+    }
+}
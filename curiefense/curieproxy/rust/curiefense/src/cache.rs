@@ -0,0 +1,155 @@
+use crate::utils::GeoIp;
+use lazy_static::lazy_static;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const MAX_CACHED: usize = 100_000;
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TTL: Duration = Duration::from_secs(
+        std::env::var("CURIEFENSE_CACHE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60)
+    );
+    /// the GeoIP information, without the Tor exit list flags
+    pub static ref GEOIP: Cache<IpAddr, GeoIp> = Cache::new(*TTL, MAX_CACHED);
+    /// the claims of the JWT payloads, by encoded payload
+    pub static ref JWT: Cache<String, Option<Arc<serde_json::Value>>> = Cache::new(*TTL, MAX_CACHED);
+}
+
+/// invalidates the entries of all the caches
+pub fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+struct Entry<V> {
+    at: Instant,
+    generation: u64,
+    value: V,
+}
+
+pub struct Cache<K, V> {
+    ttl: Duration,
+    /// the entries are invalidated by `invalidate`
+    generational: bool,
+    capacity: usize,
+    entries: RwLock<HashMap<K, Entry<V>>>,
+}
+
+impl<K: Hash + Eq, V: Clone> Cache<K, V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Cache {
+            ttl,
+            generational: true,
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// a cache whose entries only expire after their time to live
+    pub fn ttl_only(ttl: Duration, capacity: usize) -> Self {
+        Cache {
+            generational: false,
+            ..Self::new(ttl, capacity)
+        }
+    }
+
+    fn fresh(&self, entry: &Entry<V>, now: Instant, generation: u64) -> bool {
+        (!self.generational || entry.generation == generation) && now.saturating_duration_since(entry.at) < self.ttl
+    }
+
+    pub fn get<Q>(&self, key: &Q, now: Instant) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let generation = generation();
+        let entries = self.entries.read().ok()?;
+        entries
+            .get(key)
+            .filter(|e| self.fresh(e, now, generation))
+            .map(|e| e.value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V, now: Instant) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let generation = generation();
+        if let Ok(mut entries) = self.entries.write() {
+            if entries.len() >= self.capacity && !entries.contains_key(&key) {
+                entries.retain(|_, e| self.fresh(e, now, generation));
+                if entries.len() >= self.capacity {
+                    entries.clear();
+                }
+            }
+            entries.insert(
+                key,
+                Entry {
+                    at: now,
+                    generation,
+                    value,
+                },
+            );
+        }
+    }
+
+    /// the cached value, or the computed one, that is then cached
+    pub fn get_or_insert_with(&self, key: K, now: Instant, compute: impl FnOnce() -> V) -> V {
+        if let Some(value) = self.get(&key, now) {
+            return value;
+        }
+        let value = compute();
+        self.insert(key, value.clone(), now);
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().map(|e| e.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_and_capacity() {
+        let cache: Cache<String, u32> = Cache::ttl_only(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        cache.insert("a".to_string(), 1, now);
+        assert_eq!(cache.get("a", now + Duration::from_secs(9)), Some(1));
+        assert_eq!(cache.get("a", now + Duration::from_secs(10)), None);
+        let later = now + Duration::from_secs(5);
+        assert_eq!(cache.get_or_insert_with("b".to_string(), later, || 2), 2);
+        assert_eq!(cache.get_or_insert_with("b".to_string(), later, || 3), 2);
+
+        // the stale entry of a is purged first
+        cache.insert("c".to_string(), 3, now + Duration::from_secs(10));
+        assert_eq!(cache.len(), 2);
+        // then the whole cache
+        cache.insert("d".to_string(), 4, now + Duration::from_secs(10));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get("d", now + Duration::from_secs(10)), Some(4));
+
+        let disabled: Cache<String, u32> = Cache::new(Duration::from_secs(0), 2);
+        disabled.insert("a".to_string(), 1, now);
+        assert!(disabled.is_empty());
+    }
+}
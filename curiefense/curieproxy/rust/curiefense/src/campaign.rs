@@ -0,0 +1,243 @@
+use crate::config::raw::RawCampaignSettings;
+use crate::counters;
+use crate::interface::{Decision, Tags};
+use crate::logs::Logs;
+use crate::notify::{notify, Event};
+use crate::reason::Initiator;
+use crate::utils::RequestInfo;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref CAMPAIGNS: Mutex<Correlator> = Mutex::new(Correlator::default());
+}
+
+/// signature, path and country
+type CampaignKey = (String, String, String);
+
+#[derive(Debug, Clone)]
+pub struct CampaignSettings {
+    /// in milliseconds
+    window: u64,
+    min_clients: usize,
+    max_aggregates: usize,
+}
+
+struct Aggregate {
+    started: u64,
+    clients: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Campaign {
+    pub hash: String,
+    pub rule: String,
+    pub path: String,
+    pub country: String,
+    pub clients: usize,
+    expires: u64,
+}
+
+#[derive(Default)]
+pub struct Correlator {
+    aggregates: HashMap<CampaignKey, Aggregate>,
+    campaigns: HashMap<CampaignKey, Campaign>,
+}
+
+fn campaign_hash(key: &CampaignKey) -> String {
+    let digest = md5::compute(format!("{}\n{}\n{}", key.0, key.1, key.2));
+    format!("{:x}", digest)[..12].to_string()
+}
+
+impl Correlator {
+    /// records a match of the client, returning the campaign when the match starts it
+    pub fn record(
+        &mut self,
+        settings: &CampaignSettings,
+        key: CampaignKey,
+        client: &str,
+        now: u64,
+    ) -> Option<Campaign> {
+        if let Some(campaign) = self.campaigns.get_mut(&key) {
+            if campaign.expires > now {
+                campaign.expires = now + settings.window;
+                return None;
+            }
+        }
+        if !self.aggregates.contains_key(&key) && self.aggregates.len() >= settings.max_aggregates {
+            self.purge(settings, now);
+            if self.aggregates.len() >= settings.max_aggregates {
+                return None;
+            }
+        }
+        let aggregate = self.aggregates.entry(key.clone()).or_insert_with(|| Aggregate {
+            started: now,
+            clients: HashSet::new(),
+        });
+        if now.saturating_sub(aggregate.started) >= settings.window {
+            aggregate.started = now;
+            aggregate.clients.clear();
+        }
+        aggregate.clients.insert(client.to_string());
+        if aggregate.clients.len() < settings.min_clients {
+            return None;
+        }
+        let clients = aggregate.clients.len();
+        self.aggregates.remove(&key);
+        self.campaigns.retain(|_, c| c.expires > now);
+        let campaign = Campaign {
+            hash: campaign_hash(&key),
+            rule: key.0.clone(),
+            path: key.1.clone(),
+            country: key.2.clone(),
+            clients,
+            expires: now + settings.window,
+        };
+        self.campaigns.insert(key, campaign.clone());
+        Some(campaign)
+    }
+
+    /// the hashes of the campaigns targeting the path from the country
+    pub fn active(&self, path: &str, country: &str, now: u64) -> Vec<String> {
+        self.campaigns
+            .values()
+            .filter(|c| c.expires > now && c.path == path && c.country == country)
+            .map(|c| c.hash.clone())
+            .collect()
+    }
+
+    fn purge(&mut self, settings: &CampaignSettings, now: u64) {
+        self.aggregates
+            .retain(|_, a| now.saturating_sub(a.started) < settings.window);
+        self.campaigns.retain(|_, c| c.expires > now);
+    }
+}
+
+fn country(reqinfo: &RequestInfo) -> String {
+    reqinfo.rinfo.geoip.country_iso.clone().unwrap_or_default()
+}
+
+fn correlator() -> std::sync::MutexGuard<'static, Correlator> {
+    match CAMPAIGNS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl CampaignSettings {
+    pub fn resolve(raw: &RawCampaignSettings) -> Self {
+        CampaignSettings {
+            window: raw.window.max(1) * 1000,
+            min_clients: raw.min_clients.max(2),
+            max_aggregates: raw.max_aggregates,
+        }
+    }
+
+    /// tags the requests targeted by an active campaign
+    pub fn tag(&self, reqinfo: &RequestInfo, tags: &mut Tags) {
+        let hashes = correlator().active(&reqinfo.rinfo.qinfo.canonical_path, &country(reqinfo), counters::now());
+        if !hashes.is_empty() {
+            tags.insert("campaign");
+        }
+        for hash in hashes {
+            tags.insert_qualified("campaign", &hash);
+        }
+    }
+
+    /// correlates the content filter matches of the decision, the decisions that are not enforced included
+    pub fn observe(&self, logs: &mut Logs, reqinfo: &RequestInfo, decision: &Decision, tags: &mut Tags) {
+        let reason = match decision {
+            Decision::Action(a) if a.reason.initiator == Initiator::ContentFilter => &a.reason,
+            _ => return,
+        };
+        let now = counters::now();
+        let path = &reqinfo.rinfo.qinfo.canonical_path;
+        let country = country(reqinfo);
+        let started: Vec<Campaign> = {
+            let mut correlator = correlator();
+            reason
+                .rule_ids
+                .iter()
+                .filter_map(|rule| {
+                    let key = (rule.clone(), path.clone(), country.clone());
+                    correlator.record(self, key, &reqinfo.rinfo.geoip.ipstr, now)
+                })
+                .collect()
+        };
+        for campaign in started {
+            logs.warning(|| {
+                format!(
+                    "campaign {}: {} clients triggered rule {} on {} from {:?}",
+                    campaign.hash, campaign.clients, campaign.rule, campaign.path, campaign.country
+                )
+            });
+            tags.insert("campaign");
+            tags.insert_qualified("campaign", &campaign.hash);
+            notify(Event::Campaign {
+                hash: campaign.hash,
+                rule: campaign.rule,
+                path: campaign.path,
+                country: campaign.country,
+                clients: campaign.clients,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> CampaignSettings {
+        let raw: RawCampaignSettings = serde_json::from_str(r#"{"window": 60, "min_clients": 3}"#).unwrap();
+        CampaignSettings::resolve(&raw)
+    }
+
+    fn key(rule: &str, path: &str) -> CampaignKey {
+        (rule.to_string(), path.to_string(), "FR".to_string())
+    }
+
+    #[test]
+    fn correlation() {
+        let settings = settings();
+        let mut correlator = Correlator::default();
+        // the same client does not start a campaign
+        for _ in 0..5 {
+            assert!(correlator
+                .record(&settings, key("100", "/login"), "1.1.1.1", 0)
+                .is_none());
+        }
+        assert!(correlator
+            .record(&settings, key("100", "/login"), "2.2.2.2", 1000)
+            .is_none());
+        // another rule, or another path, is another aggregate
+        assert!(correlator
+            .record(&settings, key("101", "/login"), "3.3.3.3", 1000)
+            .is_none());
+        assert!(correlator
+            .record(&settings, key("100", "/search"), "3.3.3.3", 1000)
+            .is_none());
+        let campaign = correlator
+            .record(&settings, key("100", "/login"), "3.3.3.3", 2000)
+            .unwrap();
+        assert_eq!(campaign.clients, 3);
+        assert_eq!(campaign.hash, campaign_hash(&key("100", "/login")));
+        assert_eq!(campaign.hash.len(), 12);
+        assert_eq!(correlator.active("/login", "FR", 3000), vec![campaign.hash.clone()]);
+        assert!(correlator.active("/login", "DE", 3000).is_empty());
+        // started once, and extended by the following matches
+        assert!(correlator
+            .record(&settings, key("100", "/login"), "4.4.4.4", 50_000)
+            .is_none());
+        assert_eq!(correlator.active("/login", "FR", 100_000).len(), 1);
+        assert!(correlator.active("/login", "FR", 110_000).is_empty());
+
+        // the clients of an aggregate are forgotten once its window ended
+        assert!(correlator.record(&settings, key("102", "/"), "1.1.1.1", 0).is_none());
+        assert!(correlator.record(&settings, key("102", "/"), "2.2.2.2", 0).is_none());
+        assert!(correlator
+            .record(&settings, key("102", "/"), "3.3.3.3", 60_000)
+            .is_none());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::blockpage::escape_html;
+use crate::challenge::{check_signed_cookie, now, signed_cookie};
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::raw::{CaptchaProvider, CaptchaSettings};
+use crate::interface::{Action, ActionType, Decision};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::utils::RequestInfo;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const CAPTCHA_PATH: &str = "/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/captcha";
+pub const CAPTCHA_COOKIE: &str = "cf_captcha";
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "captcha"), allow(dead_code))]
+pub struct Captcha {
+    provider: CaptchaProvider,
+    site_key: String,
+    secret_key: String,
+    verify_url: String,
+    ttl: u64,
+    timeout: Duration,
+}
+
+impl CaptchaProvider {
+    fn script(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api.js",
+        }
+    }
+
+    fn widget_class(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "h-captcha",
+            CaptchaProvider::ReCaptcha => "g-recaptcha",
+        }
+    }
+
+    /// name of the form field containing the token
+    fn response_field(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "h-captcha-response",
+            CaptchaProvider::ReCaptcha => "g-recaptcha-response",
+        }
+    }
+
+    fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://hcaptcha.com/siteverify",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+/// only local paths are accepted as redirection targets, to avoid open redirects
+fn return_path(target: Option<&str>) -> &str {
+    match target {
+        Some(p) if p.starts_with('/') && !p.starts_with("//") && !p.contains(|c: char| c == '\\' || c.is_control()) => {
+            p
+        }
+        _ => "/",
+    }
+}
+
+/// reads the verification response of the provider, that has the same format for all of them
+#[cfg_attr(not(feature = "captcha"), allow(dead_code))]
+fn parse_verification(body: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("success").and_then(|s| s.as_bool()))
+        .unwrap_or(false)
+}
+
+fn tag(name: &str) -> Option<std::collections::HashSet<String>> {
+    Some(std::iter::once(name.to_string()).collect())
+}
+
+impl Captcha {
+    pub fn new(settings: &CaptchaSettings) -> Self {
+        Captcha {
+            provider: settings.provider,
+            site_key: settings.site_key.clone(),
+            secret_key: settings.secret_key.clone(),
+            verify_url: settings
+                .verify_url
+                .clone()
+                .unwrap_or_else(|| settings.provider.verify_url().to_string()),
+            ttl: settings.ttl,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        }
+    }
+
+    /// the captcha page, that returns to the current uri once solved
+    pub fn page(&self, rinfo: &RequestInfo, tags: Vec<String>) -> Decision {
+        self.page_returning_to(&rinfo.rinfo.qinfo.uri, Reason::new(Initiator::Captcha).with_tags(&tags))
+    }
+
+    fn page_returning_to(&self, target: &str, reason: Reason) -> Decision {
+        let content = format!(
+            "<html><head><meta charset=\"utf-8\"><script src=\"{}\" async defer></script></head><body>\
+             <form method=\"POST\" action=\"{}\"><input type=\"hidden\" name=\"return\" value=\"{}\">\
+             <div class=\"{}\" data-sitekey=\"{}\"></div><input type=\"submit\" value=\"Continue\"></form>\
+             </body></html>",
+            self.provider.script(),
+            CAPTCHA_PATH,
+            escape_html(return_path(Some(target))),
+            self.provider.widget_class(),
+            escape_html(&self.site_key)
+        );
+        let headers: HashMap<String, String> = [
+            ("Content-Type", "text/html; charset=utf-8"),
+            ("Cache-Control", "no-cache, private, no-transform, no-store"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        Decision::Action(Action {
+            atype: ActionType::Block,
+            block_mode: true,
+            reason,
+            headers: Some(headers),
+            status: 247,
+            content,
+            extra_tags: tag("captcha_phase01"),
+            ..Action::default()
+        })
+    }
+
+    pub fn verified(&self, rinfo: &RequestInfo) -> bool {
+        match (rinfo.cookies.get(CAPTCHA_COOKIE), rinfo.headers.get("user-agent")) {
+            (Some(cookie), Some(ua)) => check_signed_cookie(self.secret_key.as_bytes(), cookie, ua, now()),
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "captcha")]
+    async fn verify_token(&self, token: String, ip: String) -> Result<bool, String> {
+        let url = self.verify_url.clone();
+        let secret = self.secret_key.clone();
+        let timeout = self.timeout;
+        async_std::task::spawn_blocking(move || {
+            let body = attohttpc::post(&url)
+                .timeout(timeout)
+                .form(&[("secret", &secret), ("response", &token), ("remoteip", &ip)])
+                .and_then(|rq| rq.send())
+                .and_then(|rsp| rsp.text())
+                .map_err(|rr| rr.to_string())?;
+            Ok(parse_verification(&body))
+        })
+        .await
+    }
+
+    #[cfg(not(feature = "captcha"))]
+    async fn verify_token(&self, _token: String, _ip: String) -> Result<bool, String> {
+        Err("the captcha tokens are not verified in this build".to_string())
+    }
+
+    /// handles the submission of the captcha form, `cookie_ttl` overriding the configured cookie validity
+    pub async fn phase02(&self, logs: &mut Logs, rinfo: &RequestInfo, cookie_ttl: Option<u64>) -> Option<Decision> {
+        if rinfo.rinfo.qinfo.qpath != CAPTCHA_PATH || rinfo.rinfo.meta.method != "POST" {
+            return None;
+        }
+        let target = return_path(rinfo.rinfo.qinfo.args.get_str("return"));
+        let ua = rinfo.headers.get_str("user-agent").unwrap_or_default();
+        let valid = match rinfo.rinfo.qinfo.args.get(self.provider.response_field()) {
+            None => false,
+            Some(token) => match self
+                .verify_token(token.to_string(), rinfo.rinfo.geoip.ipstr.clone())
+                .await
+            {
+                Ok(v) => v,
+                Err(rr) => {
+                    logs.error(|| format!("Could not verify the captcha token: {}", rr));
+                    false
+                }
+            },
+        };
+        if !valid {
+            logs.debug("captcha verification failed");
+            return Some(self.page_returning_to(
+                target,
+                Reason::new(Initiator::Captcha).with_message("captcha verification failed"),
+            ));
+        }
+        Some(self.solved(target, ua, now(), cookie_ttl.unwrap_or(self.ttl)))
+    }
+
+    fn solved(&self, target: &str, ua: &str, now: u64, ttl: u64) -> Decision {
+        let mut action = Action::redirect(target, 303, None);
+        action.reason = Reason::new(Initiator::Captcha).with_message("captcha solved");
+        action.extra_tags = tag("captcha_phase02");
+        action.headers.get_or_insert_with(HashMap::new).insert(
+            "Set-Cookie".to_string(),
+            format!(
+                "{}={}; Path=/; HttpOnly; Max-Age={}",
+                CAPTCHA_COOKIE,
+                signed_cookie(self.secret_key.as_bytes(), ua, now + ttl),
+                ttl
+            ),
+        );
+        Decision::Action(action)
+    }
+}
+
+/// is the request carrying a valid captcha cookie
+pub fn captcha_verified(secpol: &SecurityPolicy, rinfo: &RequestInfo) -> bool {
+    secpol.captcha.as_ref().map(|c| c.verified(rinfo)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captcha() -> Captcha {
+        Captcha::new(&CaptchaSettings {
+            provider: CaptchaProvider::HCaptcha,
+            site_key: "site\"key".to_string(),
+            secret_key: "secret".to_string(),
+            verify_url: None,
+            ttl: 100,
+            timeout_ms: 1000,
+        })
+    }
+
+    #[test]
+    fn return_paths() {
+        assert_eq!(return_path(Some("/a/b?c=d")), "/a/b?c=d");
+        assert_eq!(return_path(Some("//evil.com/")), "/");
+        assert_eq!(return_path(Some("/\\evil.com/")), "/");
+        assert_eq!(return_path(Some("https://evil.com/")), "/");
+        assert_eq!(return_path(Some("/a\r\nSet-Cookie: x")), "/");
+        assert_eq!(return_path(None), "/");
+    }
+
+    #[test]
+    fn verification_response() {
+        assert!(parse_verification(
+            r#"{"success": true, "challenge_ts": "2022-01-01T00:00:00Z"}"#
+        ));
+        assert!(!parse_verification(
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#
+        ));
+        assert!(!parse_verification("<html>"));
+    }
+
+    #[test]
+    fn page_and_cookie() {
+        let chall = captcha();
+        let content = match chall.page_returning_to("/p?a=<b>", Reason::new(Initiator::Captcha)) {
+            Decision::Action(a) => a.content,
+            Decision::Pass => panic!("the captcha page should block"),
+        };
+        assert!(content.contains("value=\"/p?a=&lt;b&gt;\""));
+        assert!(content.contains("class=\"h-captcha\" data-sitekey=\"site&quot;key\""));
+
+        let action = match chall.solved("/p", "ua", 1000, 100) {
+            Decision::Action(a) => a,
+            Decision::Pass => panic!("the solved captcha should redirect"),
+        };
+        assert_eq!(action.status, 303);
+        let headers = action.headers.unwrap();
+        assert_eq!(headers.get("Location").map(|s| s.as_str()), Some("/p"));
+        let cookie = headers.get("Set-Cookie").unwrap();
+        let value = cookie
+            .strip_prefix("cf_captcha=")
+            .and_then(|c| c.split(';').next())
+            .unwrap();
+        assert!(check_signed_cookie(b"secret", value, "ua", 1100));
+        assert!(!check_signed_cookie(b"secret", value, "other", 1100));
+        assert!(!check_signed_cookie(b"secret", value, "ua", 1101));
+    }
+}
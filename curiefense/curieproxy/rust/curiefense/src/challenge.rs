@@ -0,0 +1,198 @@
+use crate::clock;
+use crate::config::raw::ChallengeSettings;
+use crate::crypto::{sign_hex, to_hex, verify_hex};
+use crate::grasshopper::Grasshopper;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone)]
+pub struct NativeChallenge {
+    secret: Vec<u8>,
+    ttl: u64,
+    difficulty: usize,
+}
+
+pub(crate) fn now() -> u64 {
+    clock::unix_secs()
+}
+
+/// a cookie proving that a challenge was passed, formatted as `expiry.signature`
+pub(crate) fn signed_cookie(secret: &[u8], ua: &str, expiry: u64) -> String {
+    let expiry = expiry.to_string();
+    let signature = sign_hex(secret, ["cookie", ua, &expiry].join("|").as_bytes());
+    format!("{}.{}", expiry, signature)
+}
+
+pub(crate) fn check_signed_cookie(secret: &[u8], cookie: &str, ua: &str, now: u64) -> bool {
+    match cookie.split_once('.') {
+        None => false,
+        Some((expiry, signature)) => match expiry.parse::<u64>() {
+            Ok(e) => e >= now && verify_hex(secret, ["cookie", ua, expiry].join("|").as_bytes(), signature),
+            Err(_) => false,
+        },
+    }
+}
+
+impl NativeChallenge {
+    pub fn new(settings: &ChallengeSettings) -> Self {
+        NativeChallenge {
+            secret: settings.secret.as_bytes().to_vec(),
+            ttl: settings.ttl,
+            difficulty: settings.difficulty,
+        }
+    }
+
+    fn sign(&self, parts: &[&str]) -> String {
+        sign_hex(&self.secret, parts.join("|").as_bytes())
+    }
+
+    /// seed format: `expiry.nonce.signature`
+    fn seed_at(&self, ua: &str, now: u64) -> String {
+        let expiry = (now + self.ttl).to_string();
+        let nonce = format!("{:016x}", clock::random::<u64>());
+        let signature = self.sign(&["seed", ua, &expiry, &nonce]);
+        format!("{}.{}.{}", expiry, nonce, signature)
+    }
+
+    /// workproof format: `seed.counter`, returns the cookie, formatted as `expiry.signature`
+    fn verify_at(&self, workproof: &str, ua: &str, now: u64) -> Option<String> {
+        let (seed, _) = workproof.rsplit_once('.')?;
+        let mut parts = seed.split('.');
+        let (expiry, nonce, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some()
+            || !verify_hex(
+                &self.secret,
+                ["seed", ua, expiry, nonce].join("|").as_bytes(),
+                signature,
+            )
+            || expiry.parse::<u64>().ok()? < now
+        {
+            return None;
+        }
+        let hash = to_hex(&Sha256::digest(workproof.as_bytes()));
+        if !hash.bytes().take(self.difficulty).all(|c| c == b'0') {
+            return None;
+        }
+        Some(signed_cookie(&self.secret, ua, now + self.ttl))
+    }
+
+    fn check_cookie_at(&self, rbzid: &str, ua: &str, now: u64) -> bool {
+        check_signed_cookie(&self.secret, rbzid, ua, now)
+    }
+}
+
+impl Grasshopper for NativeChallenge {
+    fn js_app(&self) -> Option<String> {
+        Some(
+            r#"function winsocks(){
+var seed=window.rbzns.seed,difficulty=DIFFICULTY,prefix=new Array(difficulty+1).join("0");
+function send(counter){
+var x=new XMLHttpRequest();
+x.open("GET","/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/"+Date.now(),true);
+x.setRequestHeader("x-zebra-proof",seed+"."+counter);
+x.onload=function(){window.location.reload()};
+x.send();
+}
+function hex(buf){return Array.prototype.map.call(new Uint8Array(buf),function(b){return ("0"+b.toString(16)).slice(-2)}).join("")}
+if(difficulty===0){send(0);return}
+var enc=new TextEncoder();
+(function step(counter){
+crypto.subtle.digest("SHA-256",enc.encode(seed+"."+counter)).then(function(h){
+if(hex(h).slice(0,difficulty)===prefix){send(counter)}else{step(counter+1)}
+});
+})(0);
+}"#
+            .replace("DIFFICULTY", &self.difficulty.to_string()),
+        )
+    }
+
+    fn js_bio(&self) -> Option<String> {
+        None
+    }
+
+    fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool> {
+        Some(self.check_cookie_at(rbzid, seed, now()))
+    }
+
+    fn gen_new_seed(&self, seed: &str) -> Option<String> {
+        Some(self.seed_at(seed, now()))
+    }
+
+    fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String> {
+        self.verify_at(workproof, seed, now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(difficulty: usize) -> NativeChallenge {
+        NativeChallenge::new(&ChallengeSettings {
+            secret: "secret".to_string(),
+            ttl: 100,
+            difficulty,
+        })
+    }
+
+    fn solve(seed: &str, difficulty: usize) -> String {
+        (0..)
+            .map(|counter| format!("{}.{}", seed, counter))
+            .find(|proof| {
+                to_hex(&Sha256::digest(proof.as_bytes()))
+                    .bytes()
+                    .take(difficulty)
+                    .all(|c| c == b'0')
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn full_challenge() {
+        let chall = challenge(2);
+        let seed = chall.seed_at("ua", 1000);
+        let proof = solve(&seed, 2);
+        let cookie = chall.verify_at(&proof, "ua", 1050).unwrap();
+        assert!(!cookie.contains('-') && !cookie.contains('='));
+        assert!(chall.check_cookie_at(&cookie, "ua", 1100));
+        // expired
+        assert!(!chall.check_cookie_at(&cookie, "ua", 1151));
+        // other user agent
+        assert!(!chall.check_cookie_at(&cookie, "other", 1100));
+        // other secret
+        let mut other = challenge(2);
+        other.secret = b"other".to_vec();
+        assert!(!other.check_cookie_at(&cookie, "ua", 1100));
+        assert!(!chall.check_cookie_at("1200.abcd", "ua", 1100));
+    }
+
+    #[test]
+    fn invalid_workproofs() {
+        let chall = challenge(2);
+        let seed = chall.seed_at("ua", 1000);
+        let proof = solve(&seed, 2);
+        // expired seed
+        assert_eq!(chall.verify_at(&proof, "ua", 1101), None);
+        // seed issued for another user agent
+        assert_eq!(chall.verify_at(&proof, "other", 1050), None);
+        // insufficient work
+        let lazy = (0..)
+            .map(|counter| format!("{}.{}", seed, counter))
+            .find(|proof| !to_hex(&Sha256::digest(proof.as_bytes())).starts_with('0'))
+            .unwrap();
+        assert_eq!(chall.verify_at(&lazy, "ua", 1050), None);
+        assert_eq!(chall.verify_at("1100.0.0.0", "ua", 1050), None);
+        assert_eq!(chall.verify_at("garbage", "ua", 1050), None);
+        // without difficulty, any counter is accepted
+        assert!(challenge(0).verify_at(&format!("{}.0", seed), "ua", 1050).is_some());
+    }
+
+    #[test]
+    fn deterministic_seeds() {
+        let chall = challenge(2);
+        let start = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let seed = || clock::deterministic(start, 7, || chall.gen_new_seed("ua").unwrap());
+        assert_eq!(seed(), seed());
+        assert!(seed().starts_with("1100."));
+        assert_ne!(seed(), chall.gen_new_seed("ua").unwrap());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::utils::InspectionRequest;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const DEFAULT_IP: &str = "1.2.3.4";
+
+/// splits an URL into the scheme, authority and path with the query string
+fn split_url(url: &str) -> Result<(Option<String>, Option<String>, String), String> {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_lowercase()), rest),
+        None if url.starts_with('/') => (None, url),
+        // curl defaults to http
+        None => (Some("http".to_string()), url),
+    };
+    if rest.starts_with('/') {
+        return Ok((scheme, None, rest.to_string()));
+    }
+    let (authority, path) = match rest.find(&['/', '?'][..]) {
+        Some(idx) if rest[idx..].starts_with('?') => (&rest[..idx], format!("/{}", &rest[idx..])),
+        Some(idx) => (&rest[..idx], rest[idx..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    if authority.is_empty() {
+        return Err(format!("invalid URL {}", url));
+    }
+    // the fragment is not sent
+    let path = path.split('#').next().unwrap_or("/").to_string();
+    Ok((scheme, Some(authority.to_string()), path))
+}
+
+fn request(
+    ip: &str,
+    method: &str,
+    url: &str,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+) -> Result<InspectionRequest, String> {
+    let (scheme, authority, path) = split_url(url)?;
+    let mut meta: HashMap<String, String> = HashMap::new();
+    meta.insert("method".to_string(), method.to_uppercase());
+    meta.insert("path".to_string(), path);
+    if let Some(scheme) = scheme {
+        meta.insert("scheme".to_string(), scheme);
+    }
+    let mut hmap: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers {
+        let name = name.trim().to_lowercase();
+        // the pseudo headers of HTTP/2 entries
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if pseudo == "authority" {
+                meta.insert("authority".to_string(), value);
+            }
+            continue;
+        }
+        let value = value.trim().to_string();
+        match hmap.get_mut(&name) {
+            Some(v) if name == "cookie" => *v = format!("{}; {}", v, value),
+            Some(v) => *v = format!("{}, {}", v, value),
+            None => {
+                hmap.insert(name, value);
+            }
+        }
+    }
+    if let Some(authority) = authority.or_else(|| hmap.get("host").cloned()) {
+        meta.entry("authority".to_string()).or_insert(authority);
+    }
+    Ok(InspectionRequest {
+        ip: ip.to_string(),
+        meta,
+        headers: hmap,
+        body,
+    })
+}
+
+fn split_header(line: &str) -> Result<(String, String), String> {
+    // the pseudo headers start with a colon
+    let offset = if line.starts_with(':') { 1 } else { 0 };
+    match line[offset..].split_once(':') {
+        Some((name, value)) => Ok((line[..offset].to_string() + name, value.to_string())),
+        None => Err(format!("invalid header {}", line)),
+    }
+}
+
+/// parses the arguments of a curl command line, without the `curl` command itself
+///
+/// only the options that change the request are supported: `-X`/`--request`, `-H`/`--header`, `-d`/`--data`/
+/// `--data-raw`/`--data-binary` (with `@file`), `-A`/`--user-agent`, `-b`/`--cookie`, `-e`/`--referer`, and `-I`/
+/// `--head`, the other options being ignored
+pub fn parse_curl(ip: &str, args: &[String]) -> Result<InspectionRequest, String> {
+    let mut method = None;
+    let mut url = None;
+    let mut headers = Vec::new();
+    let mut body: Option<String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "-X" | "--request" => method = Some(value()?.clone()),
+            "-H" | "--header" => headers.push(split_header(value()?)?),
+            "-A" | "--user-agent" => headers.push(("user-agent".to_string(), value()?.clone())),
+            "-b" | "--cookie" => headers.push(("cookie".to_string(), value()?.clone())),
+            "-e" | "--referer" => headers.push(("referer".to_string(), value()?.clone())),
+            "-I" | "--head" => method = Some("HEAD".to_string()),
+            "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-ascii" => {
+                let data = value()?;
+                let data = match data.strip_prefix('@') {
+                    Some(path) if arg != "--data-raw" => {
+                        std::fs::read_to_string(path).map_err(|rr| format!("{}: {}", path, rr))?
+                    }
+                    _ => data.clone(),
+                };
+                // curl joins the data arguments with &
+                body = Some(match body {
+                    Some(b) => format!("{}&{}", b, data),
+                    None => data,
+                });
+            }
+            "--url" => url = Some(value()?.clone()),
+            a if a.starts_with('-') => {}
+            a => url = Some(a.to_string()),
+        }
+    }
+    if body.is_some() && !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("content-type")) {
+        headers.push((
+            "content-type".to_string(),
+            "application/x-www-form-urlencoded".to_string(),
+        ));
+    }
+    let method = method.unwrap_or_else(|| if body.is_some() { "POST" } else { "GET" }.to_string());
+    let url = url.ok_or("missing URL")?;
+    request(ip, &method, &url, headers, body)
+}
+
+/// parses a raw HTTP/1 request: the request line, the headers, and the body after an empty line
+pub fn parse_raw(ip: &str, raw: &str) -> Result<InspectionRequest, String> {
+    let (head, body) = match raw.split_once("\r\n\r\n").or_else(|| raw.split_once("\n\n")) {
+        Some((head, body)) => (head, Some(body.to_string()).filter(|b| !b.is_empty())),
+        None => (raw.trim_end(), None),
+    };
+    let mut lines = head.lines().map(|l| l.trim_end_matches('\r'));
+    let request_line = lines.next().ok_or("empty request")?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(format!("invalid request line {}", request_line)),
+    };
+    let headers = lines
+        .filter(|l| !l.is_empty())
+        .map(split_header)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut request = request(ip, method, target, headers, body)?;
+    if let Some(version) = parts.next() {
+        request.meta.insert("http_version".to_string(), version.to_string());
+    }
+    Ok(request)
+}
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+struct HarEntry {
+    request: HarRequest,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    http_version: Option<String>,
+    #[serde(default)]
+    headers: Vec<HarHeader>,
+    #[serde(default)]
+    post_data: Option<HarPostData>,
+}
+
+#[derive(Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarPostData {
+    #[serde(default)]
+    mime_type: Option<String>,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+/// parses the request of an entry of a HAR file
+pub fn parse_har(ip: &str, har: &str, entry: usize) -> Result<InspectionRequest, String> {
+    let har: Har = serde_json::from_str(har).map_err(|rr| format!("invalid HAR file: {}", rr))?;
+    let count = har.log.entries.len();
+    let rq = har
+        .log
+        .entries
+        .into_iter()
+        .nth(entry)
+        .ok_or_else(|| format!("entry {} not found, the file has {} entries", entry, count))?
+        .request;
+    let mut headers: Vec<(String, String)> = rq.headers.into_iter().map(|h| (h.name, h.value)).collect();
+    let (mime, body) = match rq.post_data {
+        Some(pd) => (pd.mime_type, pd.text),
+        None => (None, None),
+    };
+    if let Some(mime) = mime.filter(|m| !m.is_empty()) {
+        if !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("content-type")) {
+            headers.push(("content-type".to_string(), mime));
+        }
+    }
+    let mut request = request(ip, &rq.method, &rq.url, headers, body)?;
+    if let Some(version) = rq.http_version {
+        request.meta.insert("http_version".to_string(), version);
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn curl_arguments() {
+        let rq = parse_curl(
+            DEFAULT_IP,
+            &args(&[
+                "-H",
+                "X-Test: a",
+                "-H",
+                "x-test: b",
+                "-b",
+                "c=d",
+                "-d",
+                "e=f",
+                "--compressed",
+                "https://example.com:8443?q=1#top",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(rq.meta["method"], "POST");
+        assert_eq!(rq.meta["path"], "/?q=1");
+        assert_eq!(rq.meta["authority"], "example.com:8443");
+        assert_eq!(rq.meta["scheme"], "https");
+        assert_eq!(rq.headers["x-test"], "a, b");
+        assert_eq!(rq.headers["cookie"], "c=d");
+        assert_eq!(rq.headers["content-type"], "application/x-www-form-urlencoded");
+        assert_eq!(rq.body.as_deref(), Some("e=f"));
+
+        let raw = rq.to_raw().unwrap();
+        assert_eq!(raw.get_host(), "example.com:8443");
+        assert!(parse_curl(DEFAULT_IP, &args(&["-X", "GET"])).is_err());
+    }
+
+    #[test]
+    fn raw_request() {
+        let rq = parse_raw(
+            DEFAULT_IP,
+            "PUT /a/b?c=d HTTP/1.1\r\nHost: example.com\r\nContent-Type: application/json\r\n\r\n{\"e\": 1}",
+        )
+        .unwrap();
+        assert_eq!(rq.meta["method"], "PUT");
+        assert_eq!(rq.meta["path"], "/a/b?c=d");
+        assert_eq!(rq.meta["authority"], "example.com");
+        assert_eq!(rq.meta["http_version"], "HTTP/1.1");
+        assert_eq!(rq.headers["content-type"], "application/json");
+        assert_eq!(rq.body.as_deref(), Some("{\"e\": 1}"));
+        assert!(parse_raw(DEFAULT_IP, "GET\n").is_err());
+    }
+
+    #[test]
+    fn har_entry() {
+        let har = serde_json::json!({
+            "log": { "entries": [
+                { "request": { "method": "GET", "url": "https://example.com/", "headers": [] } },
+                { "request": {
+                    "method": "POST",
+                    "url": "https://example.com/login",
+                    "httpVersion": "HTTP/2",
+                    "headers": [{ "name": ":authority", "value": "example.com" }, { "name": "Accept", "value": "*/*" }],
+                    "postData": { "mimeType": "application/json", "text": "{}" }
+                } }
+            ] }
+        })
+        .to_string();
+        let rq = parse_har(DEFAULT_IP, &har, 1).unwrap();
+        assert_eq!(rq.meta["method"], "POST");
+        assert_eq!(rq.meta["path"], "/login");
+        assert_eq!(rq.meta["authority"], "example.com");
+        assert_eq!(rq.headers.len(), 2);
+        assert_eq!(rq.headers["content-type"], "application/json");
+        assert_eq!(rq.body.as_deref(), Some("{}"));
+        assert!(parse_har(DEFAULT_IP, &har, 2).is_err());
+    }
+}
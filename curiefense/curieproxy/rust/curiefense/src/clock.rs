@@ -0,0 +1,118 @@
+use lazy_static::lazy_static;
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+struct Deterministic {
+    start: SystemTime,
+    now: SystemTime,
+    rng: StdRng,
+}
+
+thread_local! {
+    static DETERMINISTIC: RefCell<Option<Deterministic>> = const { RefCell::new(None) };
+}
+
+/// runs `f` with a deterministic clock, starting at `start`, and a random generator seeded with `seed`
+///
+/// the previous source of the thread is restored afterwards
+pub fn deterministic<R, F: FnOnce() -> R>(start: SystemTime, seed: u64, f: F) -> R {
+    let source = Deterministic {
+        start,
+        now: start,
+        rng: StdRng::seed_from_u64(seed),
+    };
+    let previous = DETERMINISTIC.with(|d| d.borrow_mut().replace(source));
+    // restores the previous source even when `f` panics
+    struct Restore(Option<Deterministic>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            DETERMINISTIC.with(|d| *d.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(previous);
+    f()
+}
+
+/// sets the time of the deterministic clock, does nothing with the system clock
+///
+/// the time can go backwards, for the replays of records that are not ordered
+pub fn set_time(time: SystemTime) {
+    DETERMINISTIC.with(|d| {
+        if let Some(d) = d.borrow_mut().as_mut() {
+            d.now = time;
+        }
+    })
+}
+
+/// advances the deterministic clock, does nothing with the system clock
+pub fn advance(duration: Duration) {
+    DETERMINISTIC.with(|d| {
+        if let Some(d) = d.borrow_mut().as_mut() {
+            d.now += duration;
+        }
+    })
+}
+
+pub fn now() -> SystemTime {
+    DETERMINISTIC
+        .with(|d| d.borrow().as_ref().map(|d| d.now))
+        .unwrap_or_else(SystemTime::now)
+}
+
+/// seconds since the epoch
+pub fn unix_secs() -> u64 {
+    now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// milliseconds of a monotonic clock, since the process started, or since the deterministic clock was installed
+pub fn monotonic_millis() -> u64 {
+    DETERMINISTIC
+        .with(|d| {
+            d.borrow()
+                .as_ref()
+                .map(|d| d.now.duration_since(d.start).unwrap_or_default().as_millis() as u64)
+        })
+        .unwrap_or_else(|| START.elapsed().as_millis() as u64)
+}
+
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    DETERMINISTIC
+        .with(|d| d.borrow_mut().as_mut().map(|d| Standard.sample(&mut d.rng)))
+        .unwrap_or_else(rand::random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_source() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let run = || {
+            deterministic(start, 42, || {
+                assert_eq!(unix_secs(), 1_000_000);
+                advance(Duration::from_millis(1500));
+                assert_eq!(monotonic_millis(), 1500);
+                set_time(start + Duration::from_secs(10));
+                assert_eq!(unix_secs(), 1_000_010);
+                (random::<u64>(), random::<f64>())
+            })
+        };
+        let first = run();
+        assert_eq!(first, run());
+        // the system source is restored
+        assert!(unix_secs() > 1_000_010);
+        assert_ne!(random::<u64>(), first.0);
+    }
+}
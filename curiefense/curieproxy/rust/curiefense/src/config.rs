@@ -6,53 +6,396 @@ pub mod limit;
 pub mod raw;
 pub mod utils;
 
+use arc_swap::ArcSwap;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use std::path::Path;
-use std::path::PathBuf;
-use std::sync::RwLock;
-use std::time::SystemTime;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "file-config")]
+use std::time::Duration;
+use std::time::{Instant, SystemTime};
 
-use crate::config::limit::Limit;
-use crate::logs::Logs;
+use crate::audit::{ConfigAudit, Revision};
+use crate::cache;
+use crate::campaign::CampaignSettings;
+use crate::captcha::Captcha;
+use crate::challenge::NativeChallenge;
+use crate::config::limit::{resolve_selector_map, Limit};
+use crate::cookiesigning::CookieSigning;
+use crate::csrf::CsrfProtection;
+use crate::experiment::Experiment;
+#[cfg(feature = "file-config")]
+use crate::geoupdate;
+use crate::harvesting::HarvestingDetection;
+use crate::hits::HITS;
+use crate::honeypot::Honeypot;
+use crate::interface::{Tags, REDIRECT_STATUSES};
+use crate::jwt::JwtPolicy;
+use crate::login::LoginProtection;
+use crate::logs::{LogLevel, Logs};
+use crate::methods::MethodPolicy;
+use crate::metrics::record_config_reload;
+use crate::modes::ModeSettings;
+use crate::normalization::Normalization;
+use crate::notify::{notify, Event};
+use crate::openapi::OpenApi;
+use crate::quota::Quota;
+use crate::reason::Initiator;
+use crate::requestsignature::RequestSignature;
+use crate::risk::RiskScoring;
+use crate::ssrf::SsrfDetection;
+use crate::symbols;
+use crate::utils::{env_or, normalize_http_version, RequestMeta};
 use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
 use flow::{flow_resolve, FlowElement, SequenceKey};
 use globalfilter::GlobalFilterSection;
-use hostmap::{HostMap, SecurityPolicy};
-use raw::{AclProfile, RawFlowEntry, RawGlobalFilterSection, RawHostMap, RawLimit, RawSecurityPolicy};
-use utils::Matching;
+use hostmap::{ChallengePolicy, HostMap, RequestLineConditions, SecurityPolicy};
+use raw::{
+    AclProfile, BlockResponse, GlobalSettings, RawFlowEntry, RawGeoipUpdates, RawGlobalFilterSection, RawHostMap,
+    RawLimit, RawSecurityPolicy, ResponseTemplate,
+};
+use utils::{Matching, MatchingSet};
 
 lazy_static! {
-    pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::empty());
-    pub static ref HSDB: RwLock<HashMap<String, ContentFilterRules>> = RwLock::new(HashMap::new());
+    /// the loaded configurations, and their content filter rules, by path
+    ///
+    /// the whole map is published at once by the reloads, so that the inspections read it without taking any lock,
+    /// and are never blocked while a configuration, and its signatures, are being rebuilt
+    static ref SNAPSHOTS: ArcSwap<HashMap<String, ConfigSnapshot>> = ArcSwap::from_pointee(HashMap::new());
+    /// serializes the reloads, it is never taken by the inspections
+    static ref RELOADING: Mutex<()> = Mutex::new(());
+    /// the directories of the configurations the integrations can select per request, see `request_config_path`
+    static ref CONFIG_ROOTS: Vec<PathBuf> = config_roots(&env_or("CURIEFENSE_CONFIG_ROOTS", "/cf-config".to_string()));
 }
 
+#[cfg(feature = "file-config")]
+lazy_static! {
+    /// how often the loaded paths are checked for modifications, `0` meaning on every inspection
+    static ref RELOAD_INTERVAL: Duration = Duration::from_millis(env_or("CURIEFENSE_CONFIG_RELOAD_MS", 1000));
+    /// the missing paths, and when they were looked for, so that they are not looked for again before `RELOAD_INTERVAL`
+    static ref FAILED: ArcSwap<HashMap<String, Instant>> = ArcSwap::from_pointee(HashMap::new());
+}
+
+/// the JSON files of a configuration, in its `json` directory
+pub const CONFIG_FILES: [&str; 10] = [
+    "securitypolicy.json",
+    "globalfilter-lists.json",
+    "limits.json",
+    "acl-profiles.json",
+    "contentfilter-profiles.json",
+    "contentfilter-rules.json",
+    "contentfilter-groups.json",
+    "flow-control.json",
+    "response-templates.json",
+    "settings.json",
+];
+
+/// a configuration and its content filter rules, as loaded at some point
+///
+/// the phases of a request that hold a snapshot are all evaluated against the same revision, even when the
+/// configuration is reloaded in the meantime
+#[derive(Clone)]
+pub struct ConfigSnapshot {
+    pub config: Arc<Config>,
+    /// the content filter rules, by content filter profile id
+    pub hsdb: Arc<HashMap<String, ContentFilterRules>>,
+}
+
+impl ConfigSnapshot {
+    /// the snapshot of the paths that could not be loaded
+    pub fn empty() -> Self {
+        ConfigSnapshot {
+            config: Arc::new(Config::empty()),
+            hsdb: Arc::default(),
+        }
+    }
+}
+
+/// the ACL profiles by id, without their redirections whose status is not a redirection status
+fn resolve_acls(logs: &mut Logs, rawacls: Vec<AclProfile>) -> HashMap<String, AclProfile> {
+    rawacls
+        .into_iter()
+        .map(|mut acl| {
+            if let Some(status) = acl.redirect.as_ref().map(|r| r.status) {
+                if !REDIRECT_STATUSES.contains(&status) {
+                    logs.error(|| format!("ACL profile {}: invalid redirect status: {}", acl.id, status));
+                    acl.redirect = None;
+                }
+            }
+            (acl.id.clone(), acl)
+        })
+        .collect()
+}
+
+/// the comma separated list of directories of `CURIEFENSE_CONFIG_ROOTS`
+fn config_roots(list: &str) -> Vec<PathBuf> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// whether `path` is in one of the `roots`, the paths going up the directories being rejected
+fn in_roots(path: &str, roots: &[PathBuf]) -> bool {
+    let path = Path::new(path);
+    !path.components().any(|c| c == Component::ParentDir) && roots.iter().any(|r| path.starts_with(r))
+}
+
+/// the path of the configuration of the request, `default` being the one of the integration
+///
+/// the path selected for the request (see `RequestMeta::config_path`) is only used when it is in one of the
+/// `CURIEFENSE_CONFIG_ROOTS` directories (`/cf-config` by default), so that any directory can't be loaded
+pub fn request_config_path<'a>(default: &'a str, meta: &'a RequestMeta, logs: &mut Logs) -> &'a str {
+    match meta.config_path.as_deref() {
+        Some(path) if path != default => {
+            if in_roots(path, &CONFIG_ROOTS) {
+                path
+            } else {
+                logs.warning(|| format!("the configuration path {} is not in CURIEFENSE_CONFIG_ROOTS", path));
+                default
+            }
+        }
+        _ => default,
+    }
+}
+
+/// the configuration stored at `basepath`
+///
+/// the first call for a path loads it, it is then reloaded in the background when it is modified (see
+/// `CURIEFENSE_CONFIG_RELOAD_MS`). The configurations of the different paths are kept side by side, so that
+/// alternating between them does not reload them.
+#[cfg(feature = "file-config")]
+pub fn config_snapshot(basepath: &str, logs: &mut Logs) -> Option<ConfigSnapshot> {
+    if !RELOAD_INTERVAL.is_zero() {
+        if let Some(snapshot) = SNAPSHOTS.load().get(basepath) {
+            return Some(snapshot.clone());
+        }
+        if FAILED
+            .load()
+            .get(basepath)
+            .is_some_and(|t| t.elapsed() < *RELOAD_INTERVAL)
+        {
+            logs.error(|| format!("could not load the configuration of {}", basepath));
+            return Some(ConfigSnapshot::empty());
+        }
+    }
+    reload_if_modified(basepath, logs)
+}
+
+/// the configuration installed under `basepath` (see `install_config`), as there are no configuration files to load
+#[cfg(not(feature = "file-config"))]
+pub fn config_snapshot(basepath: &str, logs: &mut Logs) -> Option<ConfigSnapshot> {
+    let snapshot = SNAPSHOTS.load().get(basepath).cloned();
+    if snapshot.is_none() {
+        logs.error(|| format!("no configuration was installed for {}", basepath));
+    }
+    snapshot
+}
+
+/// runs `f` with the configuration stored at `basepath` (see `config_snapshot`)
 pub fn with_config<R, F>(basepath: &str, logs: &mut Logs, f: F) -> Option<R>
 where
     F: FnOnce(&mut Logs, &Config) -> R,
 {
-    let (newconfig, newhsdb) = match CONFIG.read() {
-        Ok(cfg) => match cfg.reload(logs, basepath) {
-            None => return Some(f(logs, &cfg)),
-            Some(cfginfo) => cfginfo,
-        },
-        Err(rr) =>
-        // read failed :(
-        {
+    let snapshot = config_snapshot(basepath, logs)?;
+    Some(f(logs, &snapshot.config))
+}
+
+#[cfg(feature = "file-config")]
+fn reload_if_modified(basepath: &str, logs: &mut Logs) -> Option<ConfigSnapshot> {
+    let started = Instant::now();
+    let first_log = logs.logs.len();
+    let _reloading = match RELOADING.lock() {
+        Ok(guard) => guard,
+        Err(rr) => {
             logs.error(|| rr.to_string());
             return None;
         }
     };
-    let r = f(logs, &newconfig);
-    match CONFIG.write() {
-        Ok(mut w) => *w = newconfig,
-        Err(rr) => logs.error(|| rr.to_string()),
+    // loaded again, as another reload could have completed while waiting for the lock
+    let current = SNAPSHOTS.load().get(basepath).cloned();
+    // a missing path is neither stored nor watched, it is loaded again after the reload interval
+    if current.is_none() {
+        if let Err(rr) = std::fs::metadata(basepath) {
+            logs.error(|| format!("Could not get last modified time for {}: {}", basepath, rr));
+            notify_failure(logs, basepath, first_log);
+            let mut failed = HashMap::clone(&FAILED.load());
+            failed.retain(|_, t| t.elapsed() < *RELOAD_INTERVAL);
+            failed.insert(basepath.to_string(), Instant::now());
+            FAILED.store(Arc::new(failed));
+            return Some(ConfigSnapshot::empty());
+        }
+    }
+    let reloaded = match &current {
+        Some(snapshot) => snapshot.config.reload(logs, basepath),
+        None => Config::empty().reload(logs, basepath),
+    };
+    let (newconfig, newhsdb) = match reloaded {
+        Some(cfginfo) => cfginfo,
+        None => {
+            notify_failure(logs, basepath, first_log);
+            return Some(current.unwrap_or_else(ConfigSnapshot::empty));
+        }
+    };
+    let snapshot = ConfigSnapshot {
+        config: Arc::new(newconfig),
+        hsdb: Arc::new(newhsdb),
+    };
+    store_config(logs, basepath, "modified", (started, first_log), snapshot.clone(), true);
+    Some(snapshot)
+}
+
+/// checks the path for modifications every `RELOAD_INTERVAL`, the logs of the reloads being forwarded to `tracing`
+#[cfg(feature = "file-config")]
+fn watch(basepath: String) {
+    loop {
+        std::thread::sleep(*RELOAD_INTERVAL);
+        // an unreadable path keeps its current configuration, instead of being reloaded on every check
+        let modified = match std::fs::metadata(&basepath).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        let current = SNAPSHOTS.load().get(&basepath).map(|s| s.config.last_mod);
+        if current == Some(modified) {
+            continue;
+        }
+        let mut logs = Logs::new(LogLevel::Warning);
+        reload_if_modified(&basepath, &mut logs);
+        for log in logs.logs {
+            match log.level {
+                LogLevel::Error => tracing::error!(target: "curiefense::config", "{}: {}", basepath, log.message),
+                _ => tracing::warn!(target: "curiefense::config", "{}: {}", basepath, log.message),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "file-config")]
+fn start_watcher(logs: &mut Logs, basepath: &str) {
+    if RELOAD_INTERVAL.is_zero() {
+        return;
+    }
+    let path = basepath.to_string();
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-config".to_string())
+        .spawn(move || watch(path))
+    {
+        logs.error(|| format!("could not start the configuration watcher: {}", rr));
+    }
+}
+
+/// without the configuration files, there is nothing to watch
+#[cfg(not(feature = "file-config"))]
+fn start_watcher(_logs: &mut Logs, _basepath: &str) {}
+
+/// activates a configuration, `load` being the time at which its loading started, and the number of logs at that time
+///
+/// it must be called while `RELOADING` is held, `watched` starting the watcher of the path the first time it is stored
+fn store_config(
+    logs: &mut Logs,
+    basepath: &str,
+    trigger: &'static str,
+    load: (Instant, usize),
+    snapshot: ConfigSnapshot,
+    watched: bool,
+) {
+    record_config_reload();
+    cache::invalidate();
+    let config = snapshot.config.clone();
+    #[cfg(feature = "file-config")]
+    geoupdate::configure(config.geoip_updates.as_ref());
+    HITS.reset(
+        config
+            .globalfilters
+            .iter()
+            .flat_map(|gf| gf.tags.iter().map(|t| t.to_string())),
+        snapshot
+            .hsdb
+            .values()
+            .flat_map(|rules| rules.ids.iter().map(|r| r.id.clone())),
+    );
+    let mut snapshots = HashMap::clone(&SNAPSHOTS.load());
+    let previous = snapshots.insert(basepath.to_string(), snapshot).map(|p| p.config);
+    SNAPSHOTS.store(Arc::new(snapshots));
+    if watched && previous.is_none() {
+        start_watcher(logs, basepath);
+    }
+    let (started, first_log) = load;
+    let count = |level: LogLevel| logs.logs.iter().skip(first_log).filter(|l| l.level == level).count();
+    ConfigAudit::new(
+        basepath,
+        trigger,
+        &config.revision,
+        previous.as_ref().map(|p| &p.revision),
+        count(LogLevel::Error),
+        count(LogLevel::Warning),
+        started.elapsed().as_micros() as u64,
+    )
+    .emit();
+    notify_failure(logs, basepath, first_log);
+}
+
+/// notifies the errors of a reload, the logs before `first_log` being those of the previous operations
+fn notify_failure(logs: &Logs, basepath: &str, first_log: usize) {
+    let mut errors = logs.logs.iter().skip(first_log).filter(|l| l.level == LogLevel::Error);
+    if let Some(first) = errors.next() {
+        notify(Event::ConfigReloadFailed {
+            path: basepath.to_string(),
+            errors: errors.count() + 1,
+            message: first.message.clone(),
+        });
+    }
+}
+
+/// loads the configuration, even if it has not been modified, returning false when errors were logged
+#[cfg(feature = "file-config")]
+pub fn reload_config(basepath: &str, logs: &mut Logs) -> bool {
+    let started = Instant::now();
+    let first_log = logs.logs.len();
+    let _reloading = match RELOADING.lock() {
+        Ok(guard) => guard,
+        Err(rr) => {
+            logs.error(|| rr.to_string());
+            return false;
+        }
+    };
+    let (newconfig, newhsdb) = match Config::empty().reload(logs, basepath) {
+        None => {
+            notify_failure(logs, basepath, first_log);
+            return false;
+        }
+        Some(cfginfo) => cfginfo,
+    };
+    let snapshot = ConfigSnapshot {
+        config: Arc::new(newconfig),
+        hsdb: Arc::new(newhsdb),
+    };
+    store_config(logs, basepath, "forced", (started, first_log), snapshot, true);
+    !logs.logs.iter().any(|l| l.level == LogLevel::Error)
+}
+
+/// installs the configuration resolved from the contents of its files (see `CONFIG_FILES`) under `basepath`, for the
+/// integrations that receive it instead of reading it, returning false when errors were logged
+///
+/// the installed configuration is not watched, it is replaced by installing another one
+pub fn install_config(basepath: &str, logs: &mut Logs, documents: &HashMap<String, Vec<u8>>) -> bool {
+    let started = Instant::now();
+    let first_log = logs.logs.len();
+    let _reloading = match RELOADING.lock() {
+        Ok(guard) => guard,
+        Err(rr) => {
+            logs.error(|| rr.to_string());
+            return false;
+        }
     };
-    match HSDB.write() {
-        Ok(mut dbw) => *dbw = newhsdb,
-        Err(rr) => logs.error(|| rr.to_string()),
+    let (newconfig, newhsdb) = Config::from_documents(logs, SystemTime::now(), Path::new(basepath), documents);
+    let snapshot = ConfigSnapshot {
+        config: Arc::new(newconfig),
+        hsdb: Arc::new(newhsdb),
     };
-    Some(r)
+    store_config(logs, basepath, "installed", (started, first_log), snapshot, false);
+    !logs.logs.iter().skip(first_log).any(|l| l.level == LogLevel::Error)
 }
 
 pub fn with_config_default_path<R, F>(logs: &mut Logs, f: F) -> Option<R>
@@ -71,6 +414,70 @@ pub struct Config {
     pub container_name: Option<String>,
     pub flows: HashMap<SequenceKey, Vec<FlowElement>>,
     pub content_filter_profiles: HashMap<String, ContentFilterProfile>,
+    pub native_challenge: Option<NativeChallenge>,
+    /// the digests of the loaded entries (see the `audit` module)
+    pub revision: Revision,
+    /// the digest of the revision, empty when the configuration was not loaded from files
+    pub revision_hash: String,
+    /// see the `geoupdate` module
+    pub geoip_updates: Option<RawGeoipUpdates>,
+}
+
+/// interns the tags the configuration refers to, so that the matching request tags share them (see `symbols`)
+fn intern_tags(
+    limits: &[RawLimit],
+    globalfilters: &[RawGlobalFilterSection],
+    acls: &[AclProfile],
+    content_filter_profiles: &HashMap<String, ContentFilterProfile>,
+    flows: &[RawFlowEntry],
+) {
+    let gftags: Vec<Tags> = globalfilters.iter().map(|gf| Tags::from_slice(&gf.tags)).collect();
+    let acltags = acls.iter().flat_map(|a| {
+        a.allow
+            .iter()
+            .chain(&a.allow_bot)
+            .chain(&a.deny)
+            .chain(&a.deny_bot)
+            .chain(&a.passthrough)
+            .chain(&a.force_deny)
+    });
+    let cftags = content_filter_profiles
+        .values()
+        .flat_map(|p| p.active.iter().chain(&p.ignore).chain(&p.report));
+    let limittags = limits.iter().flat_map(|l| l.include.iter().chain(&l.exclude));
+    let flowtags = flows.iter().flat_map(|f| f.include.iter().chain(&f.exclude));
+    symbols::intern(
+        gftags.iter().flat_map(|t| t.iter()).chain(
+            acltags
+                .chain(cftags)
+                .chain(limittags)
+                .chain(flowtags)
+                .map(|s| s.as_str()),
+        ),
+    );
+}
+
+/// drops the block responses with an unknown initiator, and the statuses that are not errors
+fn resolve_block_responses(
+    logs: &mut Logs,
+    entry: &str,
+    raw: HashMap<String, BlockResponse>,
+) -> HashMap<String, BlockResponse> {
+    raw.into_iter()
+        .filter_map(|(initiator, mut response)| {
+            if initiator != "default"
+                && serde_json::from_value::<Initiator>(serde_json::Value::String(initiator.clone())).is_err()
+            {
+                logs.warning(|| format!("Unknown initiator {} in the block responses of {}", initiator, entry));
+                return None;
+            }
+            if let Some(status) = response.status.filter(|s| !(400..600).contains(s)) {
+                logs.warning(|| format!("Invalid block status {} in entry {}", status, entry));
+                response.status = None;
+            }
+            Some((initiator, response))
+        })
+        .collect()
 }
 
 fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
@@ -88,9 +495,33 @@ impl Config {
         limits: &HashMap<String, Limit>,
         acls: &HashMap<String, AclProfile>,
         contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
+        templates: &Arc<HashMap<String, ResponseTemplate>>,
+        settings: &GlobalSettings,
+        jwt: &Option<Arc<JwtPolicy>>,
+        openapi: &Option<Arc<OpenApi>>,
+        honeypot: &Option<Arc<Honeypot>>,
+        mode: &Option<Arc<ModeSettings>>,
+        quota: &Option<Arc<Quota>>,
+        normalization: &Option<Arc<Normalization>>,
     ) -> (Vec<Matching<SecurityPolicy>>, Option<SecurityPolicy>) {
         let mut default: Option<SecurityPolicy> = None;
         let mut entries: Vec<Matching<SecurityPolicy>> = Vec::new();
+        // without the verification of the tokens, the captchas fall back to the challenge
+        let captcha = settings
+            .captcha
+            .as_ref()
+            .filter(|_| cfg!(feature = "captcha"))
+            .map(|c| Arc::new(Captcha::new(c)));
+        let explain_secret = settings.explain_secret.clone().map(Arc::new);
+        let risk = settings.risk.as_ref().map(|r| Arc::new(RiskScoring::resolve(r)));
+        let campaigns = settings
+            .campaigns
+            .as_ref()
+            .map(|c| Arc::new(CampaignSettings::resolve(c)));
+        let harvesting = settings
+            .harvesting
+            .as_ref()
+            .map(|h| Arc::new(HarvestingDetection::resolve(h)));
 
         for rawmap in rawmaps {
             let acl_profile: AclProfile = match acls.get(&rawmap.acl_profile) {
@@ -115,6 +546,24 @@ impl Config {
                     Err(rr) => logs.error(format!("When resolving limits in rawmap {}, {}", rawmap.name, rr).as_str()),
                 }
             }
+            let mut session = Vec::new();
+            for sel in rawmap.session {
+                match resolve_selector_map(sel) {
+                    Ok(s) => session.push(s),
+                    Err(rr) => logs.error(format!("When resolving session in rawmap {}, {}", rawmap.name, rr).as_str()),
+                }
+            }
+            if let Some(tid) = &rawmap.template {
+                if !templates.contains_key(tid) {
+                    logs.warning(format!("Unknown response template {} in entry {}", tid, rawmap.name).as_str());
+                }
+            }
+            let request_line = RequestLineConditions {
+                methods: rawmap.methods.iter().map(|m| m.to_uppercase()).collect(),
+                schemes: rawmap.schemes.iter().map(|s| s.to_lowercase()).collect(),
+                ports: rawmap.ports,
+                http_versions: rawmap.http_versions.iter().map(|v| normalize_http_version(v)).collect(),
+            };
             let mapname = rawmap.name.clone();
             let securitypolicy = SecurityPolicy {
                 acl_active: rawmap.acl_active,
@@ -123,6 +572,55 @@ impl Config {
                 content_filter_profile,
                 limits: olimits,
                 name: rawmap.name,
+                session,
+                request_line,
+                template: rawmap.template,
+                templates: templates.clone(),
+                observe: settings.observe || rawmap.observe,
+                run_all_phases: settings.run_all_phases || rawmap.run_all_phases,
+                captcha: captcha.clone(),
+                risk: risk.clone(),
+                campaigns: campaigns.clone(),
+                harvesting: harvesting.clone(),
+                jwt: jwt.clone(),
+                request_signature: rawmap
+                    .request_signature
+                    .as_ref()
+                    .map(|s| Arc::new(RequestSignature::resolve(s))),
+                openapi: openapi.clone(),
+                honeypot: honeypot.clone(),
+                mode: mode.clone(),
+                quota: quota.clone(),
+                normalization: normalization.clone(),
+                login: rawmap.login.as_ref().map(|l| Arc::new(LoginProtection::resolve(l))),
+                csrf: rawmap.csrf.as_ref().map(|c| Arc::new(CsrfProtection::resolve(c))),
+                cookie_signing: rawmap
+                    .cookie_signing
+                    .as_ref()
+                    .map(|c| Arc::new(CookieSigning::resolve(c))),
+                ssrf: rawmap.ssrf.as_ref().map(|s| Arc::new(SsrfDetection::resolve(s))),
+                allowed_methods: rawmap
+                    .allowed_methods
+                    .as_ref()
+                    .map(|m| Arc::new(MethodPolicy::resolve(m))),
+                challenge: ChallengePolicy::resolve(logs, &rawmap.challenge),
+                ban_on_decision: rawmap.ban_on_decision,
+                block_responses: resolve_block_responses(logs, &mapname, rawmap.block_responses),
+                json_errors: rawmap.json_errors,
+                explain: rawmap.explain,
+                explain_secret: explain_secret.clone(),
+                experiment: None,
+            };
+            let securitypolicy = match &rawmap.experiment {
+                None => securitypolicy,
+                Some(rawexp) => {
+                    let experiment =
+                        Experiment::resolve(logs, &securitypolicy, rawexp, limits, acls, contentfilterprofiles);
+                    SecurityPolicy {
+                        experiment: Some(Arc::new(experiment)),
+                        ..securitypolicy
+                    }
+                }
             };
             if rawmap.match_ == "__default__" || (rawmap.match_ == "/" && securitypolicy.name == "default") {
                 if default.is_some() {
@@ -152,17 +650,59 @@ impl Config {
         content_filter_profiles: HashMap<String, ContentFilterProfile>,
         container_name: Option<String>,
         rawflows: Vec<RawFlowEntry>,
+        rawtemplates: Vec<ResponseTemplate>,
+        rawsettings: Vec<GlobalSettings>,
     ) -> Config {
         let mut default: Option<HostMap> = None;
         let mut securitypolicies: Vec<Matching<HostMap>> = Vec::new();
 
+        intern_tags(
+            &rawlimits,
+            &rawglobalfilters,
+            &rawacls,
+            &content_filter_profiles,
+            &rawflows,
+        );
+
         let limits = Limit::resolve(logs, rawlimits);
-        let acls = rawacls.into_iter().map(|a| (a.id.clone(), a)).collect();
+        let acls = resolve_acls(logs, rawacls);
+        let templates = Arc::new(rawtemplates.into_iter().map(|t| (t.id.clone(), t)).collect());
+        if rawsettings.len() > 1 {
+            logs.warning("Multiple entries in the settings, only the first one is used");
+        }
+        let settings = rawsettings.into_iter().next().unwrap_or_default();
 
         // build the entries while looking for the default entry
         for rawmap in rawmaps {
-            let (entries, default_entry) =
-                Config::resolve_security_policies(logs, rawmap.map, &limits, &acls, &content_filter_profiles);
+            let jwt = rawmap.jwt.as_ref().map(|j| Arc::new(JwtPolicy::resolve(logs, j)));
+            let openapi = rawmap
+                .openapi
+                .as_ref()
+                .and_then(|o| OpenApi::resolve(logs, o))
+                .map(Arc::new);
+            let honeypot = rawmap.honeypot.as_ref().map(|h| Arc::new(Honeypot::resolve(logs, h)));
+            let mode = Some(Arc::new(ModeSettings::resolve(&rawmap)));
+            let quota = rawmap.quota.as_ref().map(|q| Arc::new(Quota::resolve(q)));
+            let normalization = rawmap
+                .normalization
+                .as_ref()
+                .and_then(Normalization::resolve)
+                .map(Arc::new);
+            let (entries, default_entry) = Config::resolve_security_policies(
+                logs,
+                rawmap.map,
+                &limits,
+                &acls,
+                &content_filter_profiles,
+                &templates,
+                &settings,
+                &jwt,
+                &openapi,
+                &honeypot,
+                &mode,
+                &quota,
+                &normalization,
+            );
             if default_entry.is_none() {
                 logs.warning(
                     format!(
@@ -173,11 +713,20 @@ impl Config {
                 );
             }
             let mapname = rawmap.name.clone();
+            let paths = match MatchingSet::new(&entries) {
+                Ok(set) => Some(set),
+                Err(rr) => {
+                    logs.warning(|| format!("The entries of {} are matched one by one: {}", mapname, rr));
+                    None
+                }
+            };
             let hostmap = HostMap {
                 id: rawmap.id,
                 name: rawmap.name,
                 entries,
+                paths,
                 default: default_entry,
+                normalization,
             };
             if rawmap.match_ == "__default__" {
                 if default.is_some() {
@@ -214,21 +763,27 @@ impl Config {
             container_name,
             flows,
             content_filter_profiles,
+            native_challenge: settings.challenge.as_ref().map(NativeChallenge::new),
+            revision: Revision::default(),
+            revision_hash: String::new(),
+            geoip_updates: settings.geoip_updates.clone(),
         }
     }
 
-    fn load_config_file<A: serde::de::DeserializeOwned>(logs: &mut Logs, base: &Path, fname: &str) -> Vec<A> {
-        let mut path = base.to_path_buf();
-        path.push(fname);
-        let fullpath = path.to_str().unwrap_or(fname).to_string();
-        let file = match std::fs::File::open(path) {
-            Ok(f) => f,
-            Err(rr) => {
-                logs.error(|| format!("when loading {}: {}", fullpath, rr));
-                return Vec::new();
-            }
+    fn parse_config_file<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        revision: &mut Revision,
+        base: &Path,
+        documents: &HashMap<String, Vec<u8>>,
+        fname: &str,
+    ) -> Vec<A> {
+        // the missing files are reported when they are read
+        let content = match documents.get(fname) {
+            Some(c) => c,
+            None => return Vec::new(),
         };
-        let values: Vec<serde_json::Value> = match serde_json::from_reader(std::io::BufReader::new(file)) {
+        let fullpath = base.join(fname).to_string_lossy().to_string();
+        let values: Vec<serde_json::Value> = match serde_json::from_slice(content) {
             Ok(vs) => vs,
             Err(rr) => {
                 // if it is not a json array, abort early and do not resolve anything
@@ -236,40 +791,47 @@ impl Config {
                 return Vec::new();
             }
         };
+        revision.add(fname, &values);
         let mut out = Vec::new();
-        for value in values {
+        for (idx, value) in values.into_iter().enumerate() {
+            // the entries are identified by their id, or their position when they have none
+            let entry = match value.get("id").and_then(|i| i.as_str()) {
+                Some(id) => id.to_string(),
+                None => format!("#{}", idx),
+            };
             // for each entry, try to resolve it as a raw configuration value, failing otherwise
             match serde_json::from_value(value) {
-                Err(rr) => logs.error(|| format!("when resolving entry from {}: {}", fullpath, rr)),
+                Err(rr) => logs.error(|| format!("when resolving entry {} from {}: {}", entry, fullpath, rr)),
                 Ok(v) => out.push(v),
             }
         }
         out
     }
 
-    pub fn reload(&self, logs: &mut Logs, basepath: &str) -> Option<(Config, HashMap<String, ContentFilterRules>)> {
-        let last_mod = std::fs::metadata(basepath)
-            .and_then(|x| x.modified())
-            .unwrap_or_else(|rr| {
-                logs.error(|| format!("Could not get last modified time for {}: {}", basepath, rr));
-                SystemTime::now()
-            });
-        if self.last_mod == last_mod {
-            return None;
-        }
-
-        logs.debug("Loading new configuration - CFGLOAD");
-        let mut bjson = PathBuf::from(basepath);
-        bjson.push("json");
+    /// resolves a configuration from the contents of its files (see `CONFIG_FILES`), the missing files being empty
+    ///
+    /// `base` is the directory of the files, that is only used in the error messages
+    pub fn from_documents(
+        logs: &mut Logs,
+        last_mod: SystemTime,
+        base: &Path,
+        documents: &HashMap<String, Vec<u8>>,
+    ) -> (Config, HashMap<String, ContentFilterRules>) {
+        let mut revision = Revision::default();
 
-        let securitypolicy = Config::load_config_file(logs, &bjson, "securitypolicy.json");
-        let globalfilters = Config::load_config_file(logs, &bjson, "globalfilter-lists.json");
-        let limits = Config::load_config_file(logs, &bjson, "limits.json");
-        let acls = Config::load_config_file(logs, &bjson, "acl-profiles.json");
-        let rawcontentfilterprofiles = Config::load_config_file(logs, &bjson, "contentfilter-profiles.json");
-        let contentfilterrules = Config::load_config_file(logs, &bjson, "contentfilter-rules.json");
-        let contentfiltergroups = Config::load_config_file(logs, &bjson, "contentfilter-groups.json");
-        let flows = Config::load_config_file(logs, &bjson, "flow-control.json");
+        let securitypolicy = Config::parse_config_file(logs, &mut revision, base, documents, "securitypolicy.json");
+        let globalfilters = Config::parse_config_file(logs, &mut revision, base, documents, "globalfilter-lists.json");
+        let limits = Config::parse_config_file(logs, &mut revision, base, documents, "limits.json");
+        let acls = Config::parse_config_file(logs, &mut revision, base, documents, "acl-profiles.json");
+        let rawcontentfilterprofiles =
+            Config::parse_config_file(logs, &mut revision, base, documents, "contentfilter-profiles.json");
+        let contentfilterrules =
+            Config::parse_config_file(logs, &mut revision, base, documents, "contentfilter-rules.json");
+        let contentfiltergroups =
+            Config::parse_config_file(logs, &mut revision, base, documents, "contentfilter-groups.json");
+        let flows = Config::parse_config_file(logs, &mut revision, base, documents, "flow-control.json");
+        let templates = Config::parse_config_file(logs, &mut revision, base, documents, "response-templates.json");
+        let settings = Config::parse_config_file(logs, &mut revision, base, documents, "settings.json");
 
         let container_name = std::fs::read_to_string("/etc/hostname")
             .ok()
@@ -279,7 +841,7 @@ impl Config {
 
         let hsdb = resolve_rules(logs, &content_filter_profiles, contentfilterrules, contentfiltergroups);
 
-        let config = Config::resolve(
+        let mut config = Config::resolve(
             logs,
             last_mod,
             securitypolicy,
@@ -289,8 +851,40 @@ impl Config {
             content_filter_profiles,
             container_name,
             flows,
+            templates,
+            settings,
         );
-        Some((config, hsdb))
+        config.revision_hash = revision.hash();
+        config.revision = revision;
+        (config, hsdb)
+    }
+
+    #[cfg(feature = "file-config")]
+    pub fn reload(&self, logs: &mut Logs, basepath: &str) -> Option<(Config, HashMap<String, ContentFilterRules>)> {
+        let last_mod = std::fs::metadata(basepath)
+            .and_then(|x| x.modified())
+            .unwrap_or_else(|rr| {
+                logs.error(|| format!("Could not get last modified time for {}: {}", basepath, rr));
+                SystemTime::now()
+            });
+        if self.last_mod == last_mod {
+            return None;
+        }
+
+        logs.debug("Loading new configuration - CFGLOAD");
+        let mut bjson = PathBuf::from(basepath);
+        bjson.push("json");
+        let mut documents = HashMap::new();
+        for fname in CONFIG_FILES.iter() {
+            let path = bjson.join(fname);
+            match std::fs::read(&path) {
+                Ok(content) => {
+                    documents.insert(fname.to_string(), content);
+                }
+                Err(rr) => logs.error(|| format!("when loading {}: {}", path.to_string_lossy(), rr)),
+            }
+        }
+        Some(Config::from_documents(logs, last_mod, &bjson, &documents))
     }
 
     pub fn empty() -> Config {
@@ -302,6 +896,10 @@ impl Config {
             container_name: None,
             flows: HashMap::new(),
             content_filter_profiles: HashMap::new(),
+            native_challenge: None,
+            revision: Revision::default(),
+            revision_hash: String::new(),
+            geoip_updates: None,
         }
     }
 }
@@ -312,3 +910,59 @@ pub fn init_config() -> (bool, Vec<String>) {
     let is_ok = logs.logs.is_empty();
     (is_ok, logs.to_stringvec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_config_roots() {
+        let roots = config_roots("/cf-config, /srv/curiefense,");
+        assert_eq!(roots, [PathBuf::from("/cf-config"), PathBuf::from("/srv/curiefense")]);
+        assert!(in_roots("/cf-config/staging/config", &roots));
+        assert!(in_roots("/srv/curiefense/config", &roots));
+        assert!(!in_roots("/cf-config/../etc", &roots));
+        assert!(!in_roots("/cf-configs/config", &roots));
+        assert!(!in_roots("cf-config/config", &roots));
+        assert!(!in_roots("/tmp/config", &config_roots("")));
+    }
+
+    #[cfg(feature = "file-config")]
+    #[test]
+    fn failed_path() {
+        let mut logs = Logs::default();
+        assert!(config_snapshot("/nonexistent/failed", &mut logs).is_some());
+        assert!(!logs.logs.is_empty());
+        // not loaded again before the reload interval
+        let mut logs = Logs::default();
+        assert!(config_snapshot("/nonexistent/failed", &mut logs).is_some());
+        assert_eq!(logs.to_stringvec().len(), 1);
+        assert!(logs.logs[0]
+            .message
+            .contains("could not load the configuration of /nonexistent/failed"));
+    }
+
+    #[test]
+    fn acl_redirect_status() {
+        let acl = |status: u32| -> AclProfile {
+            serde_json::from_value(serde_json::json!({
+                "id": format!("acl{}", status),
+                "name": "acl",
+                "allow": [],
+                "allow_bot": [],
+                "deny": ["all"],
+                "deny_bot": [],
+                "passthrough": [],
+                "force_deny": [],
+                "redirect": {"location": "/denied", "status": status}
+            }))
+            .unwrap()
+        };
+        let mut logs = Logs::default();
+        let acls = resolve_acls(&mut logs, vec![acl(307), acl(200)]);
+        assert_eq!(acls["acl307"].redirect.as_ref().map(|r| r.status), Some(307));
+        assert!(acls["acl200"].redirect.is_none());
+        assert_eq!(logs.logs.len(), 1);
+        assert!(logs.logs[0].message.contains("invalid redirect status: 200"));
+    }
+}
@@ -1,16 +1,23 @@
 use crate::config::raw::{
-    ContentFilterGroup, ContentFilterRule, ContentType, RawContentFilterEntryMatch, RawContentFilterProfile,
-    RawContentFilterProperties,
+    ContentFilterGroup, ContentFilterRule, ContentType, ParseBudget, RawContentFilterEntryMatch,
+    RawContentFilterProfile, RawContentFilterProperties,
 };
 use crate::config::utils::Matching;
 use crate::interface::Tags;
+use crate::leaks::LeakDetection;
 use crate::logs::Logs;
+#[cfg(feature = "hyperscan")]
+use crate::matcher::{backend, MatcherBackend};
 
-use hyperscan::prelude::{pattern, Builder, CompileFlags, Pattern, Patterns, VectoredDatabase};
-use hyperscan::Vectored;
+#[cfg(feature = "hyperscan")]
+use hyperscan::prelude::{Builder, CompileFlags, Pattern, Patterns, Scratch, VectoredDatabase};
+#[cfg(feature = "hyperscan")]
+use hyperscan::{CpuFeatures, Matching as HsMatching, Platform, Tune, Vectored};
 use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use std::collections::{HashMap, HashSet};
+#[cfg(feature = "hyperscan")]
 use std::iter::FromIterator;
 
 #[derive(Debug, Clone)]
@@ -35,6 +42,30 @@ pub struct ContentFilterProfile {
     pub content_type: Vec<ContentType>,
     pub max_body_size: usize,
     pub max_body_depth: usize,
+    pub parse_budget: ParseBudget,
+    pub nested_args: bool,
+    pub sanitize: bool,
+    /// the parts of the values that are masked, whatever their names
+    pub mask_patterns: Vec<Regex>,
+    pub leak_detection: Option<LeakDetection>,
+}
+
+/// predefined masking patterns, that can be used instead of regular expressions
+const MASK_PATTERNS: &[(&str, &str)] = &[
+    ("credit_card", r"\b(?:\d[ -]?){12,18}\d\b"),
+    (
+        "email",
+        r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+    ),
+];
+
+fn mask_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    let pattern = MASK_PATTERNS
+        .iter()
+        .find(|(name, _)| *name == pattern)
+        .map(|(_, p)| *p)
+        .unwrap_or(pattern);
+    Regex::new(pattern)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -85,6 +116,11 @@ impl ContentFilterProfile {
             content_type: Vec::new(),
             max_body_size: usize::MAX,
             max_body_depth: usize::MAX,
+            parse_budget: ParseBudget::default(),
+            nested_args: false,
+            sanitize: false,
+            mask_patterns: Vec::new(),
+            leak_detection: None,
         }
     }
 }
@@ -105,7 +141,7 @@ pub struct ContentFilterEntryMatch {
     pub exclusions: HashSet<String>,
 }
 
-#[derive(Debug, Clone, Eq, Serialize, PartialEq, Copy)]
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, PartialEq, Copy, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum SectionIdx {
     Headers,
@@ -148,19 +184,186 @@ where
     }
 }
 
+/// the signatures of a content filter profile
+///
+/// they are matched in two stages. The first one scans all the values of the request at once, with hyperscan in
+/// prefilter mode, that also accepts the constructs it can not match exactly (such as backreferences or lookarounds),
+/// and reports the candidate signatures of each value. The candidates are then confirmed against their value with the
+/// exact expression, so that only the values that might match are evaluated with it. The signatures that the exact
+/// matcher does not support are matched by hyperscan alone, without the prefilter approximation.
+///
+/// When the `hyperscan` feature is disabled, for the targets where it is not available, or when the host can not run
+/// it (see the `matcher` module), the first stage uses the `regex` crate, and is exact for the signatures it supports.
 pub struct ContentFilterRules {
-    pub db: VectoredDatabase,
+    pub db: SignatureDb,
+    /// the expressions that confirm the candidates, by signature index, `None` when the first stage is exact
+    pub confirm: Vec<Option<fancy_regex::Regex>>,
+    pub ids: Vec<ContentFilterRule>,
+}
+
+/// the first stage of the signatures, compiled for the selected matcher
+pub enum SignatureDb {
+    #[cfg(feature = "hyperscan")]
+    Hyperscan(VectoredDatabase),
+    Regex(regex::bytes::RegexSet),
+}
+
+/// the pure-Rust matcher of the signatures, that is the first stage when hyperscan is not used, and the reference of
+/// the hyperscan matcher in the differential tests otherwise (see `differential_scan`)
+///
+/// the signatures that the regex crate does not support (such as backreferences) are candidates for all the values,
+/// and are only evaluated with their confirming expression. They are dropped when it does not support them either.
+pub struct RegexRules {
+    pub db: regex::bytes::RegexSet,
+    pub confirm: Vec<Option<fancy_regex::Regex>>,
     pub ids: Vec<ContentFilterRule>,
 }
 
+/// the matching signatures, as pairs of value and signature indices, that are few for most requests
+pub type SignatureMatches = SmallVec<[(usize, usize); 8]>;
+
+/// matches values against the signatures, keeping the matcher state between the scans
+pub struct RuleScanner<'a> {
+    rules: &'a ContentFilterRules,
+    /// only allocated for the hyperscan databases
+    #[cfg(feature = "hyperscan")]
+    scratch: Option<Scratch>,
+}
+
 impl ContentFilterRules {
     pub fn empty() -> Self {
-        let pattern: Pattern = pattern! { "^TEST$" };
         ContentFilterRules {
-            db: pattern.build().unwrap(),
+            db: SignatureDb::Regex(regex::bytes::RegexSet::empty()),
+            confirm: Vec::new(),
             ids: Vec::new(),
         }
     }
+
+    pub fn scanner(&self) -> anyhow::Result<RuleScanner<'_>> {
+        Ok(RuleScanner {
+            rules: self,
+            #[cfg(feature = "hyperscan")]
+            scratch: match &self.db {
+                SignatureDb::Hyperscan(db) => Some(db.alloc_scratch()?),
+                SignatureDb::Regex(_) => None,
+            },
+        })
+    }
+}
+
+impl<'a> RuleScanner<'a> {
+    /// the candidates, as pairs of value and signature indices, from a single scan of the values separated by new lines
+    #[cfg(feature = "hyperscan")]
+    fn hyperscan_candidates(&self, db: &VectoredDatabase, values: &[&str]) -> anyhow::Result<SignatureMatches> {
+        let scratch = self
+            .scratch
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no scratch space allocated"))?;
+        let mut joined: Vec<u8> = Vec::new();
+        let mut starts = Vec::with_capacity(values.len());
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                joined.push(b'\n');
+            }
+            starts.push(joined.len());
+            joined.extend_from_slice(value.as_bytes());
+        }
+        let mut out = SignatureMatches::new();
+        db.scan([&joined], scratch, |id, _, to, _| {
+            // the value that holds the last byte of the match, a separator belonging to the value it follows
+            let last = (to as usize).saturating_sub(1);
+            let value = starts.partition_point(|start| *start <= last).saturating_sub(1);
+            out.push((value, id as usize));
+            HsMatching::Continue
+        })?;
+        out.sort_unstable();
+        out.dedup();
+        Ok(out)
+    }
+
+    /// the candidates, as pairs of value and signature indices
+    fn candidates(&self, values: &[&str]) -> anyhow::Result<SignatureMatches> {
+        match &self.rules.db {
+            #[cfg(feature = "hyperscan")]
+            SignatureDb::Hyperscan(db) => self.hyperscan_candidates(db, values),
+            SignatureDb::Regex(db) => Ok(regex_candidates(db, values)),
+        }
+    }
+
+    /// the matching signatures, as pairs of value and signature (in `ids`) indices, ordered by value
+    pub fn scan(&self, values: &[&str]) -> anyhow::Result<SignatureMatches> {
+        let mut candidates = self.candidates(values)?;
+        candidates.retain(|(value, id)| confirmed(&self.rules.confirm, *id, values[*value]));
+        Ok(candidates)
+    }
+
+    pub fn is_match(&self, data: &[u8]) -> anyhow::Result<bool> {
+        Ok(!self.matches(data)?.is_empty())
+    }
+
+    /// indices, in `ids`, of the matching signatures
+    pub fn matches(&self, data: &[u8]) -> anyhow::Result<Vec<usize>> {
+        let value = String::from_utf8_lossy(data);
+        Ok(self.scan(&[&value])?.into_iter().map(|(_, id)| id).collect())
+    }
+}
+
+/// the candidates of the regex set, as pairs of value and signature indices
+fn regex_candidates(db: &regex::bytes::RegexSet, values: &[&str]) -> SignatureMatches {
+    // most requests do not match anything, so all the values are checked at once first
+    if !db.is_match(values.join("\n").as_bytes()) {
+        return SignatureMatches::new();
+    }
+    values
+        .iter()
+        .enumerate()
+        .flat_map(|(i, value)| db.matches(value.as_bytes()).into_iter().map(move |id| (i, id)))
+        .collect()
+}
+
+fn confirmed(confirm: &[Option<fancy_regex::Regex>], id: usize, value: &str) -> bool {
+    match confirm.get(id) {
+        // a value that exceeds the backtracking limit is considered as matching
+        Some(Some(re)) => re.is_match(value).unwrap_or(true),
+        _ => true,
+    }
+}
+
+impl RegexRules {
+    pub fn build(logs: &mut Logs, ids: Vec<ContentFilterRule>) -> anyhow::Result<Self> {
+        let mut operands = Vec::new();
+        let mut confirm = Vec::new();
+        let mut kept = Vec::new();
+        for r in ids {
+            match rule_set(std::iter::once(&r.operand)) {
+                Ok(_) => {
+                    operands.push(r.operand.clone());
+                    confirm.push(None);
+                }
+                Err(rr) => match confirming_regex(&r) {
+                    // the empty pattern matches every value
+                    Ok(re) => {
+                        operands.push(String::new());
+                        confirm.push(Some(re));
+                    }
+                    Err(_) => {
+                        logs.error(|| format!("content filter rule {} is not supported: {}", r.id, rr));
+                        continue;
+                    }
+                },
+            }
+            kept.push(r);
+        }
+        let db = rule_set(&operands)?;
+        Ok(RegexRules { db, confirm, ids: kept })
+    }
+
+    /// the matching signatures, as pairs of value and signature (in `ids`) indices, ordered by value
+    pub fn scan(&self, values: &[&str]) -> SignatureMatches {
+        let mut candidates = regex_candidates(&self.db, values);
+        candidates.retain(|(value, id)| confirmed(&self.confirm, *id, values[*value]));
+        candidates
+    }
 }
 
 fn mk_entry_match(em: RawContentFilterEntryMatch) -> anyhow::Result<(String, ContentFilterEntryMatch)> {
@@ -241,6 +444,15 @@ fn convert_entry(entry: RawContentFilterProfile) -> anyhow::Result<(String, Cont
             content_type: entry.content_type,
             max_body_size: entry.max_body_size.unwrap_or(usize::MAX),
             max_body_depth: entry.max_body_depth.unwrap_or(usize::MAX),
+            parse_budget: entry.parse_budget,
+            nested_args: entry.nested_args,
+            sanitize: entry.sanitize,
+            mask_patterns: entry
+                .mask_patterns
+                .iter()
+                .map(|p| mask_pattern(p))
+                .collect::<Result<_, _>>()?,
+            leak_detection: entry.leak_detection.as_ref().map(LeakDetection::resolve),
         },
     ))
 }
@@ -261,13 +473,173 @@ impl ContentFilterProfile {
     }
 }
 
-fn convert_rule(entry: &ContentFilterRule) -> anyhow::Result<Pattern> {
+/// the backtracking limit of the confirming expressions
+const CONFIRM_BACKTRACK_LIMIT: usize = 1_000_000;
+
+/// the exact expression of a signature, with the same flags as the hyperscan patterns
+fn confirming_regex(entry: &ContentFilterRule) -> Result<fancy_regex::Regex, fancy_regex::Error> {
+    fancy_regex::RegexBuilder::new(&format!("(?ims){}", entry.operand))
+        .backtrack_limit(CONFIRM_BACKTRACK_LIMIT)
+        .build()
+}
+
+/// checks that the signature can be compiled on its own, like `build_rules` does
+#[cfg(feature = "hyperscan")]
+pub fn check_rule(entry: &ContentFilterRule) -> Result<(), String> {
+    let platform = match hyperscan_platform(backend()) {
+        Some(platform) => platform,
+        None => return check_regex_rule(entry),
+    };
+    let prefilter = confirming_regex(entry).is_ok();
+    convert_rule(entry, prefilter)
+        .and_then(|p| Patterns::from_iter(std::iter::once(p)).for_platform::<Vectored>(Some(&platform)))
+        .map(|_| ())
+        .map_err(|rr| rr.to_string())
+}
+
+/// checks that the signature can be compiled on its own, like `build_rules` does
+#[cfg(not(feature = "hyperscan"))]
+pub fn check_rule(entry: &ContentFilterRule) -> Result<(), String> {
+    check_regex_rule(entry)
+}
+
+fn check_regex_rule(entry: &ContentFilterRule) -> Result<(), String> {
+    match rule_set(std::iter::once(&entry.operand)) {
+        Ok(_) => Ok(()),
+        Err(rr) => confirming_regex(entry).map(|_| ()).map_err(|_| rr.to_string()),
+    }
+}
+
+/// the target of the hyperscan databases, `None` when the regex crate is used instead
+#[cfg(feature = "hyperscan")]
+fn hyperscan_platform(backend: MatcherBackend) -> Option<Platform> {
+    match backend {
+        MatcherBackend::HyperscanAvx2 => Some(Platform::new(Tune::Generic, CpuFeatures::AVX2)),
+        MatcherBackend::HyperscanSse => Some(Platform::new(Tune::Generic, CpuFeatures::empty())),
+        MatcherBackend::Regex => None,
+    }
+}
+
+/// the pattern of the first stage, a prefilter when the signature can be confirmed
+#[cfg(feature = "hyperscan")]
+fn convert_rule(entry: &ContentFilterRule, prefilter: bool) -> anyhow::Result<Pattern> {
+    let flags = CompileFlags::MULTILINE | CompileFlags::DOTALL | CompileFlags::CASELESS;
     Pattern::with_flags(
         &entry.operand,
-        CompileFlags::MULTILINE | CompileFlags::DOTALL | CompileFlags::CASELESS,
+        if prefilter {
+            flags | CompileFlags::PREFILTER
+        } else {
+            flags
+        },
     )
 }
 
+#[cfg(feature = "hyperscan")]
+pub(crate) fn build_rules(logs: &mut Logs, ids: Vec<ContentFilterRule>) -> anyhow::Result<ContentFilterRules> {
+    let platform = match hyperscan_platform(backend()) {
+        Some(platform) => platform,
+        None => return build_regex_rules(logs, ids),
+    };
+    let confirm: Vec<Option<fancy_regex::Regex>> = ids
+        .iter()
+        .map(|r| match confirming_regex(r) {
+            Ok(re) => Some(re),
+            Err(rr) => {
+                logs.debug(|| format!("content filter rule {} is matched by hyperscan alone: {}", r.id, rr));
+                None
+            }
+        })
+        .collect();
+    let patterns: anyhow::Result<Vec<Pattern>> = ids
+        .iter()
+        .zip(&confirm)
+        .map(|(r, c)| convert_rule(r, c.is_some()))
+        .collect();
+    patterns
+        .and_then(|ptrns| Patterns::from_iter(ptrns).for_platform::<Vectored>(Some(&platform)))
+        .map(|db| ContentFilterRules {
+            db: SignatureDb::Hyperscan(db),
+            confirm,
+            ids,
+        })
+}
+
+/// same flags as the hyperscan patterns
+fn rule_set<S: AsRef<str>, I: IntoIterator<Item = S>>(patterns: I) -> Result<regex::bytes::RegexSet, regex::Error> {
+    regex::bytes::RegexSetBuilder::new(patterns)
+        .case_insensitive(true)
+        .multi_line(true)
+        .dot_matches_new_line(true)
+        .size_limit(1 << 28)
+        .build()
+}
+
+#[cfg(not(feature = "hyperscan"))]
+pub(crate) fn build_rules(logs: &mut Logs, ids: Vec<ContentFilterRule>) -> anyhow::Result<ContentFilterRules> {
+    build_regex_rules(logs, ids)
+}
+
+/// the unsupported signatures are dropped, instead of the profile (see `RegexRules`)
+fn build_regex_rules(logs: &mut Logs, ids: Vec<ContentFilterRule>) -> anyhow::Result<ContentFilterRules> {
+    let RegexRules { db, confirm, ids } = RegexRules::build(logs, ids)?;
+    Ok(ContentFilterRules {
+        db: SignatureDb::Regex(db),
+        confirm,
+        ids,
+    })
+}
+
+/// a value on which the hyperscan and pure-Rust matchers disagree, with the ids of the signatures that only one of
+/// them matched
+#[cfg(feature = "hyperscan")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub value: String,
+    pub hyperscan_only: Vec<String>,
+    pub regex_only: Vec<String>,
+}
+
+/// scans the values with both matchers, built from the same signatures, and returns the values they disagree on
+///
+/// the signatures that the pure-Rust matcher does not support are left out of the comparison. The matchers are only
+/// expected to agree on ASCII data: hyperscan matches bytes, while the classes and the case folding of the regex crate
+/// apply to Unicode characters.
+#[cfg(feature = "hyperscan")]
+pub fn differential_scan(
+    rules: &ContentFilterRules,
+    reference: &RegexRules,
+    values: &[&str],
+) -> anyhow::Result<Vec<Divergence>> {
+    let compared: HashSet<&str> = reference.ids.iter().map(|r| r.id.as_str()).collect();
+    let by_value = |matches: &[(usize, usize)], ids: &[ContentFilterRule]| {
+        let mut out: Vec<HashSet<String>> = vec![HashSet::new(); values.len()];
+        for (value, id) in matches {
+            let sig = &ids[*id].id;
+            if compared.contains(sig.as_str()) {
+                out[*value].insert(sig.clone());
+            }
+        }
+        out
+    };
+    let hyperscan = by_value(&rules.scanner()?.scan(values)?, &rules.ids);
+    let regex = by_value(&reference.scan(values), &reference.ids);
+    let sorted = |ids: &mut dyn Iterator<Item = &String>| {
+        let mut ids: Vec<String> = ids.cloned().collect();
+        ids.sort();
+        ids
+    };
+    Ok(values
+        .iter()
+        .zip(hyperscan.iter().zip(regex.iter()))
+        .filter(|(_, (h, r))| h != r)
+        .map(|(value, (h, r))| Divergence {
+            value: value.to_string(),
+            hyperscan_only: sorted(&mut h.difference(r)),
+            regex_only: sorted(&mut r.difference(h)),
+        })
+        .collect())
+}
+
 pub fn rule_tags(sig: &ContentFilterRule) -> (Tags, Tags) {
     let mut new_specific_tags = Tags::default();
     new_specific_tags.insert_qualified("cf-rule-id", &sig.id);
@@ -332,21 +704,20 @@ pub fn resolve_rules(
         false
     };
 
-    let build_from_profile = |prof: &ContentFilterProfile| -> anyhow::Result<ContentFilterRules> {
-        let ids: Vec<ContentFilterRule> = all_rules.iter().filter(|r| rule_kept(r, prof)).cloned().collect();
-        if ids.is_empty() {
-            return Err(anyhow::anyhow!("no rules were selected, empty profile"));
-        }
-        let patterns: anyhow::Result<Vec<Pattern>> = ids.iter().map(convert_rule).collect();
-        patterns
-            .and_then(|ptrns| Patterns::from_iter(ptrns).build::<Vectored>())
-            .map(|db| ContentFilterRules { db, ids })
-    };
-
     let mut out: HashMap<String, ContentFilterRules> = HashMap::new();
 
     for v in profiles.values() {
-        match build_from_profile(v) {
+        let ids: Vec<ContentFilterRule> = all_rules.iter().filter(|r| rule_kept(r, v)).cloned().collect();
+        if ids.is_empty() {
+            // the profiles without active or report tags are skipped by the content filter phase
+            if v.active.is_empty() && v.report.is_empty() {
+                logs.debug(|| format!("Profile {} selects no rules", v.id));
+            } else {
+                logs.warning(|| format!("The tags of profile {} select no rules", v.id));
+            }
+            continue;
+        }
+        match build_rules(logs, ids) {
             Ok(p) => {
                 logs.debug(|| format!("Loaded profile {} with {} rules", v.id, p.ids.len()));
                 out.insert(v.id.to_string(), p);
@@ -357,3 +728,142 @@ pub fn resolve_rules(
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: &str, operand: &str) -> ContentFilterRule {
+        ContentFilterRule {
+            id: id.to_string(),
+            operand: operand.to_string(),
+            risk: 1,
+            category: "c".to_string(),
+            subcategory: "s".to_string(),
+            tags: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn signature_scanner() {
+        let rules = build_rules(&mut Logs::default(), vec![rule("1", "foo"), rule("2", "ba+r")]).unwrap();
+        let scanner = rules.scanner().unwrap();
+        assert!(scanner.is_match(b"x\nBAAR").unwrap());
+        assert!(!scanner.is_match(b"br").unwrap());
+        assert_eq!(scanner.matches(b"bar foo bar").unwrap(), vec![0, 1]);
+        assert_eq!(scanner.matches(b"baz").unwrap(), Vec::<usize>::new());
+        // the matches are attributed to the values they end in
+        assert_eq!(
+            scanner
+                .scan(&["xfoo", "", "nothing", "bar\nfoo", "fo", "o"])
+                .unwrap()
+                .to_vec(),
+            vec![(0, 0), (3, 0), (3, 1)]
+        );
+        assert!(scanner.scan(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn confirming_regexes() {
+        let backref = rule("1", "(a)\\1");
+        let re = confirming_regex(&backref).unwrap();
+        assert!(re.is_match("xaa").unwrap());
+        assert!(!re.is_match("ab").unwrap());
+        assert!(confirming_regex(&rule("2", "(")).is_err());
+    }
+
+    #[test]
+    fn regex_rules() {
+        let rules = RegexRules::build(
+            &mut Logs::default(),
+            vec![rule("1", "foo"), rule("2", "(a)\\1"), rule("3", "(")],
+        )
+        .unwrap();
+        assert_eq!(rules.ids.len(), 2);
+        assert_eq!(rules.scan(&["FOO", "xaa", "ab"]).to_vec(), vec![(0, 0), (1, 1)]);
+    }
+
+    /// the signatures of the differential tests: the ones of `CURIEFENSE_DIFFERENTIAL_RULES`, or of the shipped
+    /// configuration
+    #[cfg(feature = "hyperscan")]
+    fn differential_signatures() -> Vec<ContentFilterRule> {
+        let path = std::env::var("CURIEFENSE_DIFFERENTIAL_RULES").unwrap_or_else(|_| {
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../../cf-config/json/contentfilter-rules.json"
+            )
+            .to_string()
+        });
+        let content = std::fs::read_to_string(&path).unwrap();
+        let rules: Vec<ContentFilterRule> = serde_json::from_str(&content).unwrap();
+        // the signatures that can not be compiled on their own are reported by the linter
+        rules.into_iter().filter(|r| check_rule(r).is_ok()).collect()
+    }
+
+    #[cfg(feature = "hyperscan")]
+    lazy_static::lazy_static! {
+        static ref MATCHERS: (ContentFilterRules, RegexRules) = {
+            let signatures = differential_signatures();
+            (
+                build_rules(&mut Logs::default(), signatures.clone()).unwrap(),
+                RegexRules::build(&mut Logs::default(), signatures).unwrap(),
+            )
+        };
+    }
+
+    /// values made of ASCII characters and of fragments of attack payloads, so that some signatures match
+    #[cfg(feature = "hyperscan")]
+    fn request_values() -> impl proptest::strategy::Strategy<Value = Vec<String>> {
+        use proptest::prelude::*;
+        const FRAGMENTS: [&str; 16] = [
+            "select",
+            " union ",
+            "' or 1=1",
+            "--",
+            "/*",
+            "*/",
+            "<script>",
+            "alert(",
+            "javascript:",
+            "../",
+            "etc/passwd",
+            "${jndi:",
+            "\n",
+            "%00",
+            "onerror=",
+            ";ls",
+        ];
+        let piece = prop_oneof![
+            proptest::sample::select(&FRAGMENTS[..]).prop_map(String::from),
+            "[ -~\n\t]{0,6}"
+        ];
+        let value = proptest::collection::vec(piece, 0..8).prop_map(|pieces| pieces.concat());
+        proptest::collection::vec(value, 1..5)
+    }
+
+    #[cfg(feature = "hyperscan")]
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(512))]
+
+        #[test]
+        fn differential_matchers(values in request_values()) {
+            let (rules, reference) = &*MATCHERS;
+            let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+            let divergences = differential_scan(rules, reference, &values).unwrap();
+            proptest::prop_assert!(divergences.is_empty(), "{:?}", divergences);
+        }
+    }
+
+    #[test]
+    fn predefined_mask_patterns() {
+        let cc = mask_pattern("credit_card").unwrap();
+        assert!(cc.is_match("card=4111 1111 1111 1111"));
+        assert!(cc.is_match("4111-1111-1111-1111"));
+        assert!(!cc.is_match("id=12345"));
+        let email = mask_pattern("email").unwrap();
+        assert!(email.is_match("to=john.doe+tag@mail.example.com"));
+        assert!(!email.is_match("user@localhost"));
+        assert!(mask_pattern("secret=\\w+").unwrap().is_match("secret=abc"));
+        assert!(mask_pattern("(").is_err());
+    }
+}
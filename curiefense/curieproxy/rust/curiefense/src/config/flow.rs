@@ -56,7 +56,10 @@ pub struct FlowElement {
 
 impl FlowEntry {
     fn convert(rawentry: RawFlowEntry) -> anyhow::Result<FlowEntry> {
-        let mkey: anyhow::Result<Vec<RequestSelector>> = rawentry.key.into_iter().map(resolve_selector_map).collect();
+        let mkey: anyhow::Result<Vec<RequestSelector>> = match rawentry.key {
+            None => Ok(vec![RequestSelector::Session]),
+            Some(k) => k.into_iter().map(resolve_selector_map).collect(),
+        };
         let msequence: anyhow::Result<Vec<FlowStep>> = rawentry.sequence.into_iter().map(FlowStep::convert).collect();
         let sequence = msequence?;
 
@@ -109,7 +112,7 @@ pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> HashMap<S
             continue;
         }
         match FlowEntry::convert(rawentry) {
-            Err(rr) => logs.warning(|| rr.to_string()),
+            Err(rr) => logs.warning(|| format!("{:#}", rr)),
             Ok(entry) => {
                 let nsteps = entry.sequence.len();
                 for (stepid, step) in entry.sequence.into_iter().enumerate() {
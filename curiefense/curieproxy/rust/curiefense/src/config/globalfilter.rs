@@ -13,6 +13,8 @@ use crate::logs::Logs;
 
 #[derive(Debug, Clone)]
 pub struct GlobalFilterSection {
+    pub id: String,
+    pub name: String,
     pub tags: Tags,
     pub relation: Relation,
     pub sections: Vec<GlobalFilterSSection>,
@@ -65,9 +67,14 @@ pub enum GlobalFilterEntryE {
     Region(SingleEntry),
     SubRegion(SingleEntry),
     Method(SingleEntry),
+    Scheme(SingleEntry),
+    Port(SingleEntry),
+    HttpVersion(SingleEntry),
     Asn(u32),
     Company(SingleEntry),
     Authority(SingleEntry),
+    Ja3(SingleEntry),
+    Ja4(SingleEntry),
 }
 
 /// tries to aggregate ip ranges
@@ -138,7 +145,7 @@ pub fn optimize_ipranges(rel: Relation, unoptimized: Vec<GlobalFilterEntry>) ->
         let mut i = elems.into_iter();
         match i.next() {
             None => {
-                println!("invariant violated, elems is empty! Please report this.");
+                tracing::error!("invariant violated, elems is empty! Please report this.");
                 IpRange::default()
             }
             Some(first) => i
@@ -294,9 +301,14 @@ impl GlobalFilterSection {
                 GlobalFilterEntryType::Region => single_re(logs, GlobalFilterEntryE::Region, val),
                 GlobalFilterEntryType::SubRegion => single_re(logs, GlobalFilterEntryE::SubRegion, val),
                 GlobalFilterEntryType::Method => single_re(logs, GlobalFilterEntryE::Method, val),
+                GlobalFilterEntryType::Scheme => single_re(logs, GlobalFilterEntryE::Scheme, val),
+                GlobalFilterEntryType::Port => single_re(logs, GlobalFilterEntryE::Port, val),
+                GlobalFilterEntryType::HttpVersion => single_re(logs, GlobalFilterEntryE::HttpVersion, val),
                 GlobalFilterEntryType::Asn => single(|rawasn| Ok(GlobalFilterEntryE::Asn(rawasn.parse()?)), val),
                 GlobalFilterEntryType::Company => single_re(logs, GlobalFilterEntryE::Company, val),
                 GlobalFilterEntryType::Authority => single_re(logs, GlobalFilterEntryE::Authority, val),
+                GlobalFilterEntryType::Ja3 => single_re(logs, GlobalFilterEntryE::Ja3, val),
+                GlobalFilterEntryType::Ja4 => single_re(logs, GlobalFilterEntryE::Ja4, val),
             }
         }
         fn convert_subsection(logs: &mut Logs, ss: RawGlobalFilterSSection) -> anyhow::Result<GlobalFilterSSection> {
@@ -333,6 +345,8 @@ impl GlobalFilterSection {
                 None => None,
             };
             Ok(GlobalFilterSection {
+                id: s.id.clone(),
+                name: s.name.clone(),
                 tags: Tags::from_slice(&s.tags),
                 relation: s.rule.relation,
                 sections: subsections,
@@ -344,7 +358,7 @@ impl GlobalFilterSection {
 
         for rgf in rawglobalfilters.into_iter().filter(|s| s.active) {
             match convert_section(logs, rgf) {
-                Err(rr) => logs.error(|| rr.to_string()),
+                Err(rr) => logs.error(|| format!("{:#}", rr)),
                 Ok(gfilter) => out.push(gfilter),
             }
         }
@@ -1,7 +1,30 @@
+use crate::campaign::CampaignSettings;
+use crate::captcha::Captcha;
 use crate::config::contentfilter::ContentFilterProfile;
 use crate::config::limit::Limit;
-use crate::config::raw::AclProfile;
-use crate::config::utils::Matching;
+use crate::config::raw::{AclProfile, BlockResponse, HumanAclFailure, RawChallengePolicy, ResponseTemplate};
+use crate::config::utils::{Matching, MatchingSet, RequestSelector};
+use crate::cookiesigning::CookieSigning;
+use crate::csrf::CsrfProtection;
+use crate::experiment::Experiment;
+use crate::harvesting::HarvestingDetection;
+use crate::honeypot::Honeypot;
+use crate::jwt::JwtPolicy;
+use crate::login::LoginProtection;
+use crate::logs::Logs;
+use crate::methods::MethodPolicy;
+use crate::modes::ModeSettings;
+use crate::normalization::Normalization;
+use crate::openapi::OpenApi;
+use crate::quota::Quota;
+use crate::reason::Initiator;
+use crate::requestsignature::RequestSignature;
+use crate::risk::RiskScoring;
+use crate::ssrf::SsrfDetection;
+use crate::utils::RequestMeta;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 /// the default entry is statically encoded so that it is certain it exists
 #[derive(Debug, Clone)]
@@ -9,7 +32,11 @@ pub struct HostMap {
     pub id: String,
     pub name: String,
     pub entries: Vec<Matching<SecurityPolicy>>,
+    /// the path patterns of the entries, when they could be compiled together
+    pub paths: Option<MatchingSet>,
     pub default: Option<SecurityPolicy>,
+    /// the parsing of the upstream, when it is not the generic one
+    pub normalization: Option<Arc<Normalization>>,
 }
 
 /// a map entry, with links to the acl and content filter profiles
@@ -21,4 +48,121 @@ pub struct SecurityPolicy {
     pub content_filter_active: bool,
     pub content_filter_profile: ContentFilterProfile,
     pub limits: Vec<Limit>,
+    /// selectors for the session identifier, the client IP is used when none are present
+    pub session: Vec<RequestSelector>,
+    pub request_line: RequestLineConditions,
+    /// default response template for blocking actions
+    pub template: Option<String>,
+    /// all the response templates, shared between the security policies
+    pub templates: Arc<HashMap<String, ResponseTemplate>>,
+    /// observe only mode, set globally or for this entry
+    pub observe: bool,
+    /// run all the inspection phases, set globally or for this entry
+    pub run_all_phases: bool,
+    /// the captcha configuration, shared between the security policies
+    pub captcha: Option<Arc<Captcha>>,
+    /// the risk scoring settings, shared between the security policies
+    pub risk: Option<Arc<RiskScoring>>,
+    /// the attack campaigns correlation settings, shared between the security policies
+    pub campaigns: Option<Arc<CampaignSettings>>,
+    /// the detection of the content harvesting, shared between the security policies
+    pub harvesting: Option<Arc<HarvestingDetection>>,
+    /// the JWT validation settings, shared between the security policies of the host map
+    pub jwt: Option<Arc<JwtPolicy>>,
+    pub request_signature: Option<Arc<RequestSignature>>,
+    /// the API specification, shared between the security policies of the host map
+    pub openapi: Option<Arc<OpenApi>>,
+    /// the decoys, shared between the security policies of the host map
+    pub honeypot: Option<Arc<Honeypot>>,
+    /// the incident mode, shared between the security policies of the host map
+    pub mode: Option<Arc<ModeSettings>>,
+    /// the resource caps, shared between the security policies of the host map
+    pub quota: Option<Arc<Quota>>,
+    /// the parsing of the upstream, shared between the security policies of the host map
+    pub normalization: Option<Arc<Normalization>>,
+    /// the variant of the entry applied to a share of the clients
+    pub experiment: Option<Arc<Experiment>>,
+    /// the login protection of the entry, shared by its attempts
+    pub login: Option<Arc<LoginProtection>>,
+    pub csrf: Option<Arc<CsrfProtection>>,
+    pub cookie_signing: Option<Arc<CookieSigning>>,
+    pub ssrf: Option<Arc<SsrfDetection>>,
+    pub allowed_methods: Option<Arc<MethodPolicy>>,
+    pub challenge: ChallengePolicy,
+    /// ban duration of the sessions, by initiator of the blocking decisions
+    pub ban_on_decision: HashMap<Initiator, u64>,
+    /// block responses, by initiator name, or `default`
+    pub block_responses: HashMap<String, BlockResponse>,
+    pub json_errors: bool,
+    /// record the explain trace of all the requests
+    pub explain: bool,
+    /// the key of the explain headers, shared between the security policies
+    pub explain_secret: Option<Arc<String>>,
+}
+
+/// challenge behavior, see `RawChallengePolicy`
+#[derive(Debug, Clone)]
+pub struct ChallengePolicy {
+    pub cookie_ttl: Option<u64>,
+    pub bypass_paths: Vec<Regex>,
+    pub max_attempts: Option<u64>,
+    pub attempts_window: u64,
+    pub human_acl_failure: HumanAclFailure,
+    pub bot_score_threshold: Option<u8>,
+}
+
+impl std::default::Default for ChallengePolicy {
+    fn default() -> Self {
+        ChallengePolicy::resolve(&mut Logs::default(), &RawChallengePolicy::default())
+    }
+}
+
+impl ChallengePolicy {
+    pub fn resolve(logs: &mut Logs, raw: &RawChallengePolicy) -> Self {
+        let bypass_paths = raw
+            .bypass_paths
+            .iter()
+            .filter_map(|p| match Regex::new(p) {
+                Ok(re) => Some(re),
+                Err(rr) => {
+                    logs.error(|| format!("Invalid challenge bypass path {}: {}", p, rr));
+                    None
+                }
+            })
+            .collect();
+        ChallengePolicy {
+            cookie_ttl: raw.cookie_ttl,
+            bypass_paths,
+            max_attempts: raw.max_attempts,
+            attempts_window: raw.attempts_window.unwrap_or(3600),
+            human_acl_failure: raw.human_acl_failure,
+            bot_score_threshold: raw.bot_score_threshold,
+        }
+    }
+
+    /// the path is exempt from challenges
+    pub fn bypassed(&self, path: &str) -> bool {
+        self.bypass_paths.iter().any(|re| re.is_match(path))
+    }
+}
+
+/// restrictions on the request method, scheme, port and protocol version, an empty list matching everything
+#[derive(Debug, Clone, Default)]
+pub struct RequestLineConditions {
+    pub methods: Vec<String>,
+    pub schemes: Vec<String>,
+    pub ports: Vec<u16>,
+    pub http_versions: Vec<String>,
+}
+
+impl RequestLineConditions {
+    pub fn matches(&self, meta: &RequestMeta) -> bool {
+        fn check<A: PartialEq>(allowed: &[A], actual: Option<&A>) -> bool {
+            allowed.is_empty() || actual.map(|a| allowed.contains(a)).unwrap_or(false)
+        }
+        check(&self.methods, Some(&meta.method))
+            && check(&self.schemes, meta.scheme.as_ref())
+            && check(&self.ports, meta.port.as_ref())
+            && check(&self.http_versions, meta.http_version.as_ref())
+    }
 }
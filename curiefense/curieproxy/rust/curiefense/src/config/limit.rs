@@ -20,6 +20,8 @@ pub struct Limit {
     pub include: HashSet<String>,
     pub pairwith: Option<RequestSelector>,
     pub key: Vec<RequestSelector>,
+    /// the statuses of the counted responses, the requests being counted when empty
+    pub statuses: Vec<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -50,7 +52,10 @@ pub fn resolve_selectors(rawsel: RawLimitSelector) -> anyhow::Result<Vec<Request
 
 impl Limit {
     fn convert(rawlimit: RawLimit) -> anyhow::Result<(String, Limit)> {
-        let mkey: anyhow::Result<Vec<RequestSelector>> = rawlimit.key.into_iter().map(resolve_selector_map).collect();
+        let mkey: anyhow::Result<Vec<RequestSelector>> = match rawlimit.key {
+            None => Ok(vec![RequestSelector::Session]),
+            Some(k) => k.into_iter().map(resolve_selector_map).collect(),
+        };
         let key = mkey.with_context(|| "when converting the key entry")?;
         let pairwith = resolve_selector_map(rawlimit.pairwith).ok();
         let mut thresholds: Vec<LimitThreshold> = Vec::new();
@@ -75,6 +80,7 @@ impl Limit {
                 thresholds,
                 pairwith,
                 key,
+                statuses: rawlimit.statuses,
             },
         ))
     }
@@ -120,4 +126,24 @@ mod tests {
         let expected: Vec<String> = ["l2", "l3", "l4", "l1"].iter().map(|x| x.to_string()).collect();
         assert_eq!(names, expected);
     }
+
+    #[test]
+    fn limit_without_key() {
+        let convert = |key: Option<serde_json::Value>| {
+            let mut raw = serde_json::json!({
+                "id": "l",
+                "name": "l",
+                "timeframe": "60",
+                "pairwith": {}
+            });
+            if let Some(k) = key {
+                raw["key"] = k;
+            }
+            Limit::convert(serde_json::from_value(raw).unwrap()).unwrap().1
+        };
+        // counted per session by default
+        assert!(matches!(convert(None).key.as_slice(), [RequestSelector::Session]));
+        // a single counter for all the requests
+        assert!(convert(Some(serde_json::json!([]))).key.is_empty());
+    }
 }
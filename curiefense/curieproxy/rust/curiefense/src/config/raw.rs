@@ -1,7 +1,10 @@
+use crate::reason::Initiator;
+use crate::requestfields::ParseArena;
 use serde::de::{self, Deserializer, SeqAccess, Visitor};
 /// this module contains types that map to the the JSON configuration format of curiefense configuration files
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 /// a mapping of the configuration file for security policy entries
 /// it is called "securitypolicy" in the lua code
@@ -12,6 +15,79 @@ pub struct RawHostMap {
     pub id: String,
     pub name: String,
     pub map: Vec<RawSecurityPolicy>,
+    /// validation of the JWTs of the requests, see the `jwt` module
+    #[serde(default)]
+    pub jwt: Option<RawJwtSettings>,
+    /// the API specification the requests must conform to, see the `openapi` module
+    #[serde(default)]
+    pub openapi: Option<RawOpenApiSettings>,
+    /// decoy paths and arguments, see the `honeypot` module
+    #[serde(default)]
+    pub honeypot: Option<RawHoneypot>,
+    /// the incident mode of the host map, see the `modes` module
+    #[serde(default)]
+    pub mode: HostMapMode,
+    /// the response of the maintenance mode
+    #[serde(default)]
+    pub maintenance_response: Option<BlockResponse>,
+    /// the tags of the requests that pass in lockdown mode
+    #[serde(default)]
+    pub lockdown_allow: Vec<String>,
+    /// the resources the inspection of the requests of the host map can use, see the `quota` module
+    #[serde(default)]
+    pub quota: Option<RawQuota>,
+    /// the parameter parsing quirks of the upstream, see the `normalization` module
+    #[serde(default)]
+    pub normalization: Option<RawNormalization>,
+}
+
+/// the request normalization of a host map, see the `normalization` module
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawNormalization {
+    #[serde(default)]
+    pub preset: NormalizationPreset,
+    /// `;` also separates the query parameters, overriding the preset
+    #[serde(default)]
+    pub semicolon_separator: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationPreset {
+    #[default]
+    Generic,
+    Php,
+    Tomcat,
+    Iis,
+    Node,
+}
+
+/// resource caps of a host map, see the `quota` module
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawQuota {
+    /// in seconds
+    #[serde(default = "default_quota_window")]
+    pub window: u64,
+    /// CPU time of the inspections, in milliseconds per window
+    #[serde(default)]
+    pub max_cpu_ms: Option<u64>,
+    /// inspected data, in kilobytes per window
+    #[serde(default)]
+    pub max_memory_kb: Option<u64>,
+}
+
+fn default_quota_window() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostMapMode {
+    #[default]
+    Normal,
+    Bypass,
+    Maintenance,
+    Lockdown,
 }
 
 /// a mapping of the configuration file for security policies
@@ -26,6 +102,123 @@ pub struct RawSecurityPolicy {
     pub acl_active: bool,
     pub content_filter_active: bool,
     pub limit_ids: Vec<String>,
+    /// selectors used to identify the session, the first one that is present is used
+    #[serde(default)]
+    pub session: Vec<HashMap<String, String>>,
+    /// when not empty, the entry only matches requests with one of these methods
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub schemes: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub http_versions: Vec<String>,
+    /// id of the response template used for blocking actions that do not specify one
+    #[serde(default)]
+    pub template: Option<String>,
+    /// observe only mode, see `GlobalSettings`
+    #[serde(default)]
+    pub observe: bool,
+    /// run all the inspection phases, see `GlobalSettings`
+    #[serde(default)]
+    pub run_all_phases: bool,
+    #[serde(default)]
+    pub challenge: RawChallengePolicy,
+    /// ban duration, in seconds, of the sessions that get a blocking decision from these initiators
+    #[serde(default)]
+    pub ban_on_decision: HashMap<Initiator, u64>,
+    /// block responses, by initiator name, or `default`
+    #[serde(default)]
+    pub block_responses: HashMap<String, BlockResponse>,
+    /// the blocking responses that are not rendered by a template have a JSON body
+    #[serde(default)]
+    pub json_errors: bool,
+    /// records the explain trace of all the requests, see the `explain` module
+    #[serde(default)]
+    pub explain: bool,
+    /// verification of the HMAC signatures of the requests, see the `requestsignature` module
+    #[serde(default)]
+    pub request_signature: Option<RawRequestSignature>,
+    /// marks the entry as a login endpoint, see the `login` module
+    #[serde(default)]
+    pub login: Option<RawLoginProtection>,
+    /// double submit CSRF tokens, see the `csrf` module
+    #[serde(default)]
+    pub csrf: Option<RawCsrfProtection>,
+    /// signatures of the upstream cookies, see the `cookiesigning` module
+    #[serde(default)]
+    pub cookie_signing: Option<RawCookieSigning>,
+    /// detection of the arguments targeting internal addresses, see the `ssrf` module
+    #[serde(default)]
+    pub ssrf: Option<RawSsrfDetection>,
+    /// the methods allowed on the entry, and their overrides, see the `methods` module
+    #[serde(default)]
+    pub allowed_methods: Option<RawMethodPolicy>,
+    /// the alternate profiles applied to a share of the clients, see the `experiment` module
+    #[serde(default)]
+    pub experiment: Option<RawExperiment>,
+}
+
+/// an experiment of a security policy entry, see the `experiment` module
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawExperiment {
+    pub name: String,
+    /// the share of the clients that get the variant, in percent
+    pub percent: f64,
+    /// how the clients are identified, to always select the same variant for a client
+    #[serde(default)]
+    pub identity: RiskIdentity,
+    /// the profiles of the variant, the ones of the entry being used when they are not set
+    #[serde(default)]
+    pub acl_profile: Option<String>,
+    #[serde(default)]
+    pub content_filter_profile: Option<String>,
+    #[serde(default)]
+    pub limit_ids: Option<Vec<String>>,
+}
+
+/// overrides of the response of the blocking actions
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct BlockResponse {
+    pub status: Option<u32>,
+    pub content_type: Option<String>,
+    pub content: Option<String>,
+}
+
+/// challenge behavior of a security policy entry
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawChallengePolicy {
+    /// validity of the challenge cookies, in seconds
+    #[serde(default)]
+    pub cookie_ttl: Option<u64>,
+    /// regular expressions matching the paths that are never challenged
+    #[serde(default)]
+    pub bypass_paths: Vec<String>,
+    /// number of challenges served to a session, within `attempts_window` seconds, before it is blocked
+    #[serde(default)]
+    pub max_attempts: Option<u64>,
+    #[serde(default)]
+    pub attempts_window: Option<u64>,
+    #[serde(default)]
+    pub human_acl_failure: HumanAclFailure,
+    /// the clients that are not verified humans are challenged from this heuristic bot score, see `botscore`
+    #[serde(default)]
+    pub bot_score_threshold: Option<u8>,
+}
+
+/// what happens to the clients that are not verified humans, when they fail an ACL rule that only denies humans
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HumanAclFailure {
+    Block,
+    Challenge,
+}
+
+impl std::default::Default for HumanAclFailure {
+    fn default() -> Self {
+        HumanAclFailure::Block
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
@@ -66,9 +259,15 @@ pub enum GlobalFilterEntryType {
     Region,
     SubRegion,
     Method,
+    Scheme,
+    Port,
+    #[serde(rename = "http_version")]
+    HttpVersion,
     Ip,
     Company,
     Authority,
+    Ja3,
+    Ja4,
 }
 
 /// a special datatype for deserializing tuples with 2 elements, and optional extra elements
@@ -121,8 +320,8 @@ pub struct RawLimit {
     pub id: String,
     pub name: String,
     pub timeframe: String,
-    #[serde(default)]
-    pub key: Vec<HashMap<String, String>>,
+    /// defaults to the session identifier when absent, an empty list counting all the requests together
+    pub key: Option<Vec<HashMap<String, String>>>,
     #[serde(default)]
     pub thresholds: Vec<RawLimitThreshold>,
     #[serde(default)]
@@ -130,6 +329,10 @@ pub struct RawLimit {
     #[serde(default)]
     pub exclude: Vec<String>,
     pub pairwith: HashMap<String, String>,
+    /// when not empty, the limit counts the responses with these statuses, as reported by the integrations, instead
+    /// of the requests
+    #[serde(default)]
+    pub statuses: Vec<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -165,6 +368,7 @@ pub enum RawActionType {
     Ban,
     Response,
     Challenge,
+    Captcha,
     Redirect,
     Monitor,
     RequestHeader,
@@ -192,6 +396,9 @@ pub struct RawActionParams {
     pub content: Option<String>,
     pub location: Option<String>,
     pub duration: Option<String>,
+    /// id of the response template used for the content
+    #[serde(default)]
+    pub template: Option<String>,
 }
 
 impl std::default::Default for RawActionParams {
@@ -205,10 +412,637 @@ impl std::default::Default for RawActionParams {
             content: None,
             location: None,
             duration: None,
+            template: None,
+        }
+    }
+}
+
+/// settings that apply to the whole configuration, stored as the single entry of `settings.json`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct GlobalSettings {
+    /// observe only mode: the decisions that would alter the request are logged and tagged, but not enforced
+    #[serde(default)]
+    pub observe: bool,
+    /// the inspection phases all run to completion, even after one of them decided to block, and the decision lists
+    /// all the matches
+    #[serde(default)]
+    pub run_all_phases: bool,
+    /// enables the native challenge, used when the grasshopper component is not available
+    #[serde(default)]
+    pub challenge: Option<ChallengeSettings>,
+    /// enables the `captcha` action
+    #[serde(default)]
+    pub captcha: Option<CaptchaSettings>,
+    /// the key used to sign the explain headers, which are ignored when it is not set
+    #[serde(default)]
+    pub explain_secret: Option<String>,
+    /// enables the risk score of the clients, see the `risk` module
+    #[serde(default)]
+    pub risk: Option<RawRiskSettings>,
+    /// enables the correlation of the attacks of many clients, see the `campaign` module
+    #[serde(default)]
+    pub campaigns: Option<RawCampaignSettings>,
+    /// enables the downloads of the MaxMind databases, see the `geoupdate` module
+    #[serde(default)]
+    pub geoip_updates: Option<RawGeoipUpdates>,
+    /// enables the detection of the range abuse and of the enumerations, see the `harvesting` module
+    #[serde(default)]
+    pub harvesting: Option<RawHarvestingSettings>,
+}
+
+/// settings of the downloads of the MaxMind databases, see the `geoupdate` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawGeoipUpdates {
+    pub license_key: String,
+    /// the edition ids of the databases, that are also the names of their files
+    #[serde(default = "default_geoip_editions")]
+    pub editions: Vec<String>,
+    /// the age of a database file that triggers its download, in seconds
+    #[serde(default = "default_geoip_interval")]
+    pub interval: u64,
+    #[serde(default = "default_geoip_url")]
+    pub url: String,
+}
+
+fn default_geoip_editions() -> Vec<String> {
+    vec![
+        "GeoLite2-ASN".to_string(),
+        "GeoLite2-Country".to_string(),
+        "GeoLite2-City".to_string(),
+    ]
+}
+
+fn default_geoip_interval() -> u64 {
+    86400
+}
+
+fn default_geoip_url() -> String {
+    "https://download.maxmind.com/app/geoip_download".to_string()
+}
+
+/// settings of the attack campaigns correlation, see the `campaign` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawCampaignSettings {
+    /// in seconds
+    #[serde(default = "default_campaign_window")]
+    pub window: u64,
+    /// the number of distinct clients that starts a campaign
+    #[serde(default = "default_campaign_min_clients")]
+    pub min_clients: usize,
+    /// the number of aggregates kept, the new ones being ignored once it is reached
+    #[serde(default = "default_campaign_max_aggregates")]
+    pub max_aggregates: usize,
+}
+
+fn default_campaign_window() -> u64 {
+    300
+}
+
+fn default_campaign_min_clients() -> usize {
+    20
+}
+
+fn default_campaign_max_aggregates() -> usize {
+    10000
+}
+
+/// settings of the detection of the content harvesting, see the `harvesting` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawHarvestingSettings {
+    /// the number of ranges of a `Range` header above which it is abusive
+    #[serde(default = "default_harvesting_max_ranges")]
+    pub max_ranges: usize,
+    /// the pagination and identifier parameters whose enumeration is detected
+    #[serde(default = "default_harvesting_parameters")]
+    pub parameters: Vec<String>,
+    /// the enumeration of the numeric last segments of the paths is detected too
+    #[serde(default = "default_harvesting_path_ids")]
+    pub path_ids: bool,
+    #[serde(default)]
+    pub identity: RiskIdentity,
+    /// the length of the sequences that are enumerations
+    #[serde(default = "default_harvesting_min_sequence")]
+    pub min_sequence: usize,
+    /// the maximum delay between two requests of a sequence, in seconds
+    #[serde(default = "default_harvesting_window")]
+    pub window: u64,
+    /// the number of sequences kept, the new ones being ignored once it is reached
+    #[serde(default = "default_harvesting_max_tracked")]
+    pub max_tracked: usize,
+}
+
+fn default_harvesting_max_ranges() -> usize {
+    10
+}
+
+fn default_harvesting_parameters() -> Vec<String> {
+    ["page", "p", "offset", "start", "id"]
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+fn default_harvesting_path_ids() -> bool {
+    true
+}
+
+fn default_harvesting_min_sequence() -> usize {
+    10
+}
+
+fn default_harvesting_window() -> u64 {
+    60
+}
+
+fn default_harvesting_max_tracked() -> usize {
+    10000
+}
+
+/// settings of the risk score of the clients, see the `risk` module
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawRiskSettings {
+    #[serde(default)]
+    pub identity: RiskIdentity,
+    /// in seconds
+    #[serde(default = "default_risk_half_life")]
+    pub half_life: u64,
+    /// the increase of the score, by initiator of the decisions
+    #[serde(default = "default_risk_weights")]
+    pub weights: HashMap<Initiator, f64>,
+    /// the fraction of the bot score of each request that is added to the score
+    #[serde(default = "default_risk_bot_score_factor")]
+    pub bot_score_factor: f64,
+    /// the scores that get a `risk-score:<threshold>` tag
+    #[serde(default = "default_risk_thresholds")]
+    pub thresholds: Vec<u32>,
+}
+
+/// the identity of the clients whose risk is scored
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskIdentity {
+    #[default]
+    Session,
+    Ip,
+    Fingerprint,
+}
+
+fn default_risk_half_life() -> u64 {
+    600
+}
+
+fn default_risk_weights() -> HashMap<Initiator, f64> {
+    [
+        (Initiator::ContentFilter, 20.0),
+        (Initiator::Limit, 10.0),
+        (Initiator::FlowCheck, 10.0),
+    ]
+    .iter()
+    .copied()
+    .collect()
+}
+
+fn default_risk_bot_score_factor() -> f64 {
+    0.1
+}
+
+fn default_risk_thresholds() -> Vec<u32> {
+    vec![25, 50, 75]
+}
+
+/// settings of the native challenge, see the `challenge` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ChallengeSettings {
+    /// the key used to sign the seeds and cookies
+    pub secret: String,
+    /// validity of the cookies, in seconds
+    #[serde(default = "default_challenge_ttl")]
+    pub ttl: u64,
+    /// number of leading zeros of the proof of work hash, in hexadecimal
+    #[serde(default = "default_challenge_difficulty")]
+    pub difficulty: usize,
+}
+
+fn default_challenge_ttl() -> u64 {
+    86400
+}
+
+fn default_challenge_difficulty() -> usize {
+    3
+}
+
+/// settings of the JWT validation of a security policy, see the `jwt` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawJwtSettings {
+    pub issuers: Vec<RawJwtIssuer>,
+    /// the cookie holding the token, when it is not sent as a bearer token in the authorization header
+    #[serde(default)]
+    pub cookie: Option<String>,
+    /// tolerance of the expiry and not before times, in seconds
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway: u64,
+    /// the claims whose values are turned into `jwt:<claim>:<value>` tags
+    #[serde(default = "default_jwt_tag_claims")]
+    pub tag_claims: Vec<String>,
+    /// the claims whose hashed values are turned into `jwt:<claim>:<hash>` tags
+    #[serde(default = "default_jwt_hash_claims")]
+    pub hash_claims: Vec<String>,
+    /// the claim that identifies the session of the requests with a valid token
+    #[serde(default)]
+    pub session_claim: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawJwtIssuer {
+    /// the `iss` claim of the tokens
+    pub issuer: String,
+    /// when not empty, the `aud` claim of the tokens must hold one of these audiences
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// the URL of the JSON Web Key Set of the issuer
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// keys in the JWK format, such as the `oct` keys of the HMAC algorithms
+    #[serde(default)]
+    pub keys: Vec<serde_json::Value>,
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_jwt_tag_claims() -> Vec<String> {
+    vec!["scope".to_string()]
+}
+
+fn default_jwt_hash_claims() -> Vec<String> {
+    vec!["sub".to_string()]
+}
+
+/// the string that is signed by the clients, see the `requestsignature` module
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureCanonicalization {
+    /// the timestamp, method, path and query, body hash, and signed headers, one per line
+    #[default]
+    Request,
+    /// the timestamp and the body, separated by a dot
+    Body,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+/// HMAC signature verification of a security policy entry
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawRequestSignature {
+    /// the shared secrets, by key id
+    pub secrets: HashMap<String, String>,
+    #[serde(default = "default_signature_header")]
+    pub header: String,
+    /// the header holding the key id, the `default` secret being used when it is missing
+    #[serde(default = "default_signature_key_header")]
+    pub key_header: String,
+    /// the header holding the signing time, in seconds since the epoch
+    #[serde(default = "default_signature_timestamp_header")]
+    pub timestamp_header: String,
+    #[serde(default)]
+    pub canonicalization: SignatureCanonicalization,
+    /// the headers that are part of the signed string, with the `request` canonicalization
+    #[serde(default)]
+    pub signed_headers: Vec<String>,
+    #[serde(default)]
+    pub algorithm: SignatureAlgorithm,
+    /// the maximum difference, in seconds, between the signing time and the current time
+    #[serde(default = "default_signature_max_skew")]
+    pub max_skew: u64,
+    /// block the requests without a valid signature, only tag them otherwise
+    #[serde(default = "default_signature_enforce")]
+    pub enforce: bool,
+}
+
+fn default_signature_header() -> String {
+    "x-signature".to_string()
+}
+
+fn default_signature_key_header() -> String {
+    "x-signature-key-id".to_string()
+}
+
+fn default_signature_timestamp_header() -> String {
+    "x-signature-timestamp".to_string()
+}
+
+fn default_signature_max_skew() -> u64 {
+    300
+}
+
+fn default_signature_enforce() -> bool {
+    true
+}
+
+/// the ways a request can deviate from the OpenAPI specification of its host map
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenApiViolation {
+    UnknownPath,
+    UnknownMethod,
+    UndeclaredParameter,
+    TypeMismatch,
+    MissingRequired,
+}
+
+impl OpenApiViolation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OpenApiViolation::UnknownPath => "unknown_path",
+            OpenApiViolation::UnknownMethod => "unknown_method",
+            OpenApiViolation::UndeclaredParameter => "undeclared_parameter",
+            OpenApiViolation::TypeMismatch => "type_mismatch",
+            OpenApiViolation::MissingRequired => "missing_required",
         }
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OpenApiAction {
+    #[default]
+    Block,
+    /// only tag the requests
+    Tag,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CsrfAction {
+    #[default]
+    Block,
+    /// only tag the requests
+    Tag,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawCsrfProtection {
+    /// the HMAC key of the tokens
+    pub secret: String,
+    #[serde(default = "default_csrf_cookie")]
+    pub cookie: String,
+    /// the header holding the submitted token
+    #[serde(default = "default_csrf_header")]
+    pub header: String,
+    /// the argument holding the submitted token, for the forms, when the header is missing
+    #[serde(default)]
+    pub field: Option<String>,
+    /// the state changing methods, whose requests must submit the token
+    #[serde(default = "default_csrf_methods")]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub action: CsrfAction,
+}
+
+fn default_csrf_cookie() -> String {
+    "cf_csrf".to_string()
+}
+
+fn default_csrf_header() -> String {
+    "x-csrf-token".to_string()
+}
+
+fn default_csrf_methods() -> Vec<String> {
+    ["POST", "PUT", "PATCH", "DELETE"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSigningAction {
+    #[default]
+    Block,
+    /// only tag the requests
+    Tag,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawCookieSigning {
+    /// the HMAC key of the signatures
+    pub secret: String,
+    /// the names of the signed cookies
+    pub cookies: Vec<String>,
+    /// appended to the name of a cookie, for the name of its signature cookie
+    #[serde(default = "default_cookie_signing_suffix")]
+    pub suffix: String,
+    #[serde(default)]
+    pub action: CookieSigningAction,
+}
+
+fn default_cookie_signing_suffix() -> String {
+    "__sig".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SsrfAction {
+    /// only tag the requests
+    #[default]
+    Tag,
+    Block,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct RawSsrfDetection {
+    /// the names of the inspected arguments, all of them when empty
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// resolve the host names, and not only check the addresses
+    #[serde(default)]
+    pub resolve: bool,
+    #[serde(default)]
+    pub action: SsrfAction,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MethodAction {
+    /// only tag the requests
+    Tag,
+    #[default]
+    Block,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawMethodPolicy {
+    /// the allowed methods, the standard ones when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// the headers that override the method of the request
+    #[serde(default = "default_override_headers")]
+    pub override_headers: Vec<String>,
+    /// the arguments that override the method of the request
+    #[serde(default = "default_override_args")]
+    pub override_args: Vec<String>,
+    #[serde(default)]
+    pub action: MethodAction,
+}
+
+fn default_override_headers() -> Vec<String> {
+    ["x-http-method-override", "x-http-method", "x-method-override"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect()
+}
+
+fn default_override_args() -> Vec<String> {
+    vec!["_method".to_string()]
+}
+
+/// what happens to the login attempts once a failure threshold tripped
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginAction {
+    /// the clients that are not verified humans are challenged
+    #[default]
+    Challenge,
+    /// the attempts are blocked for `ban_duration` seconds
+    Ban,
+}
+
+/// the failure thresholds of a login endpoint, a zero threshold disabling its counter
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RawLoginProtection {
+    #[serde(default = "default_login_methods")]
+    pub methods: Vec<String>,
+    /// the argument holding the user name, such as `username`, from the body or the query
+    #[serde(default)]
+    pub username_field: Option<String>,
+    /// the response statuses of the failed attempts
+    #[serde(default = "default_login_failure_statuses")]
+    pub failure_statuses: Vec<u32>,
+    /// the failures are counted over this many seconds
+    #[serde(default = "default_login_window")]
+    pub window: u64,
+    #[serde(default = "default_login_ip_threshold")]
+    pub ip_threshold: u64,
+    #[serde(default = "default_login_ip_threshold")]
+    pub fingerprint_threshold: u64,
+    #[serde(default = "default_login_username_threshold")]
+    pub username_threshold: u64,
+    #[serde(default)]
+    pub action: LoginAction,
+    #[serde(default = "default_login_ban_duration")]
+    pub ban_duration: u64,
+}
+
+fn default_login_methods() -> Vec<String> {
+    vec!["POST".to_string()]
+}
+
+fn default_login_failure_statuses() -> Vec<u32> {
+    vec![401, 403]
+}
+
+fn default_login_window() -> u64 {
+    300
+}
+
+fn default_login_ip_threshold() -> u64 {
+    20
+}
+
+fn default_login_username_threshold() -> u64 {
+    5
+}
+
+fn default_login_ban_duration() -> u64 {
+    900
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RawOpenApiSettings {
+    /// the OpenAPI 3 specification, in the JSON format
+    pub spec: serde_json::Value,
+    /// the action for each kind of violation, `block` by default
+    #[serde(default)]
+    pub actions: HashMap<OpenApiViolation, OpenApiAction>,
+}
+
+/// decoy paths and arguments of a host map, see the `honeypot` module
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawHoneypot {
+    /// regular expressions, matched against the canonical path
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// names of the arguments, such as hidden form fields, that must be absent or empty
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// in seconds
+    #[serde(default = "default_honeypot_ban")]
+    pub ban_duration: u64,
+}
+
+fn default_honeypot_ban() -> u64 {
+    3600
+}
+
+/// settings of the captcha action, see the `captcha` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CaptchaSettings {
+    pub provider: CaptchaProvider,
+    pub site_key: String,
+    /// the provider secret, also used to sign the cookies
+    pub secret_key: String,
+    /// overrides the verification endpoint of the provider
+    #[serde(default)]
+    pub verify_url: Option<String>,
+    /// validity of the cookies, in seconds
+    #[serde(default = "default_challenge_ttl")]
+    pub ttl: u64,
+    /// timeout of the verification requests, in milliseconds
+    #[serde(default = "default_captcha_timeout")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    HCaptcha,
+    ReCaptcha,
+}
+
+fn default_captcha_timeout() -> u64 {
+    2000
+}
+
+/// a block page, see the `blockpage` module
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ResponseTemplate {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_template_format")]
+    pub format: TemplateFormat,
+    pub content: String,
+    #[serde(default)]
+    pub support_contact: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateFormat {
+    Html,
+    Json,
+}
+
+fn default_template_format() -> TemplateFormat {
+    TemplateFormat::Html
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AclProfile {
     pub id: String,
@@ -219,6 +1053,21 @@ pub struct AclProfile {
     pub deny_bot: HashSet<String>,
     pub passthrough: HashSet<String>,
     pub force_deny: HashSet<String>,
+    /// when set, denied requests, and bots that would have been challenged, are redirected there
+    #[serde(default)]
+    pub redirect: Option<AclRedirect>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AclRedirect {
+    /// location template, see `interface::render_location`
+    pub location: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u32,
+}
+
+fn default_redirect_status() -> u32 {
+    302
 }
 
 impl AclProfile {
@@ -232,8 +1081,19 @@ impl AclProfile {
             deny_bot: HashSet::new(),
             passthrough: HashSet::new(),
             force_deny: HashSet::new(),
+            redirect: None,
         }
     }
+
+    /// the profile has no rules, and lets every request pass
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty()
+            && self.allow_bot.is_empty()
+            && self.deny.is_empty()
+            && self.deny_bot.is_empty()
+            && self.passthrough.is_empty()
+            && self.force_deny.is_empty()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -279,6 +1139,92 @@ pub struct RawContentFilterProfile {
     pub content_type: Vec<ContentType>,
     pub max_body_size: Option<usize>,
     pub max_body_depth: Option<usize>,
+    #[serde(default)]
+    pub parse_budget: ParseBudget,
+    /// parse `a[]=1` and `a[b][c]=x` argument names as `a` and `a_b_c`
+    #[serde(default)]
+    pub nested_args: bool,
+    /// remove the offending entries and pass the request, instead of blocking it, when possible
+    #[serde(default)]
+    pub sanitize: bool,
+    /// regular expressions, or the `credit_card` and `email` predefined patterns, whose matches are masked in all the
+    /// values of the request
+    #[serde(default)]
+    pub mask_patterns: Vec<String>,
+    /// detection of the secrets in the arguments, see the `leaks` module
+    #[serde(default)]
+    pub leak_detection: Option<RawLeakDetection>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RawLeakDetection {
+    /// the names of the arguments that are expected to hold secrets
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// the length of the shortest high entropy strings
+    #[serde(default = "default_leak_min_length")]
+    pub min_length: usize,
+    /// the entropy of the high entropy strings, in bits per character
+    #[serde(default = "default_leak_min_entropy")]
+    pub min_entropy: f64,
+}
+
+fn default_leak_min_length() -> usize {
+    32
+}
+
+fn default_leak_min_entropy() -> f64 {
+    4.5
+}
+
+/// hard limits on the work performed when mapping a request, 0 meaning unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseBudget {
+    /// maximum number of arguments (query and body)
+    #[serde(default)]
+    pub max_args: usize,
+    #[serde(default)]
+    pub max_cookies: usize,
+    #[serde(default)]
+    pub max_headers: usize,
+    /// maximum amount of decoded bytes, per section
+    #[serde(default)]
+    pub max_decoded_bytes: usize,
+    /// maximum number of decoding passes, per value
+    #[serde(default)]
+    pub max_decode_passes: usize,
+    /// block requests that exceed the budget, instead of just tagging them
+    #[serde(default)]
+    pub block_on_overflow: bool,
+    /// maximum amount of bytes stored when mapping the whole request, see `ParseArena`
+    #[serde(default)]
+    pub max_request_bytes: usize,
+}
+
+impl ParseBudget {
+    /// the arena of a request, when its parsing products are capped
+    pub fn arena(&self) -> Option<Arc<ParseArena>> {
+        match self.max_request_bytes {
+            0 => None,
+            cap => Some(Arc::new(ParseArena::new(cap))),
+        }
+    }
+
+    pub fn field_budget(&self, max_entries: usize) -> FieldBudget {
+        FieldBudget {
+            max_entries,
+            max_decoded_bytes: self.max_decoded_bytes,
+            max_decode_passes: self.max_decode_passes,
+        }
+    }
+}
+
+/// parsing budget of a single request field, 0 meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FieldBudget {
+    pub max_entries: usize,
+    pub max_decoded_bytes: usize,
+    pub max_decode_passes: usize,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -365,8 +1311,8 @@ pub struct RawFlowEntry {
     pub include: Vec<String>,
     pub exclude: Vec<String>,
     pub name: String,
-    #[serde(default)]
-    pub key: Vec<HashMap<String, String>>,
+    /// defaults to the session identifier when absent, an empty list counting all the requests together
+    pub key: Option<Vec<HashMap<String, String>>>,
     pub active: bool,
     pub timeframe: u64,
     pub action: RawAction,
@@ -1,4 +1,4 @@
-use regex::Regex;
+use regex::{Regex, RegexSet};
 use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -9,6 +9,9 @@ pub enum RequestSelector {
     Uri,
     Country,
     Method,
+    Scheme,
+    Port,
+    HttpVersion,
     Asn,
     Args(String),
     Cookie(String),
@@ -16,12 +19,30 @@ pub enum RequestSelector {
     Company,
     Authority,
     Tags,
+    /// the session identifier, as resolved by the security policy session selectors
+    Session,
+    /// the client fingerprint
+    Fingerprint,
+    /// a claim from the bearer JWT in the authorization header
+    JwtClaim(String),
+    /// the subject of the mTLS client certificate
+    CertSubject,
+    /// the SHA-256 hash of the mTLS client certificate
+    CertFingerprint,
+    /// the JA3 fingerprint of the client hello
+    Ja3,
+    /// the JA4 fingerprint of the client hello
+    Ja4,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub enum XDataSource {
     CookieHeader,
     Uri,
+    /// a trailer of the chunked body, inspected with the headers
+    Trailer,
+    /// an extension of the body chunks, inspected with the headers
+    ChunkExtension,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -44,6 +65,7 @@ pub enum SelectorType {
     Cookies,
     Args,
     Attrs,
+    Jwt,
 }
 
 // all kind of selector related functions
@@ -55,10 +77,19 @@ pub fn decode_attribute(s: &str) -> Option<RequestSelector> {
         "uri" => Some(RequestSelector::Uri),
         "country" => Some(RequestSelector::Country),
         "method" => Some(RequestSelector::Method),
+        "scheme" => Some(RequestSelector::Scheme),
+        "port" => Some(RequestSelector::Port),
+        "http_version" => Some(RequestSelector::HttpVersion),
         "asn" => Some(RequestSelector::Asn),
         "company" => Some(RequestSelector::Company),
         "authority" => Some(RequestSelector::Authority),
         "tags" => Some(RequestSelector::Tags),
+        "session" => Some(RequestSelector::Session),
+        "fingerprint" => Some(RequestSelector::Fingerprint),
+        "cert_subject" => Some(RequestSelector::CertSubject),
+        "cert_fingerprint" => Some(RequestSelector::CertFingerprint),
+        "ja3" => Some(RequestSelector::Ja3),
+        "ja4" => Some(RequestSelector::Ja4),
         _ => None,
     }
 }
@@ -69,6 +100,7 @@ fn resolve_selector_type(k: &str) -> anyhow::Result<SelectorType> {
         "cookies" => Ok(SelectorType::Cookies),
         "args" => Ok(SelectorType::Args),
         "attrs" => Ok(SelectorType::Attrs),
+        "jwt" => Ok(SelectorType::Jwt),
         _ => Err(anyhow::anyhow!("Unknown selector type {}", k)),
     }
 }
@@ -84,6 +116,7 @@ pub fn resolve_selector(tp: SelectorType, v: &str) -> anyhow::Result<RequestSele
         SelectorType::Cookies => Ok(RequestSelector::Cookie(v.to_string())),
         SelectorType::Args => Ok(RequestSelector::Args(v.to_string())),
         SelectorType::Attrs => decode_attribute(v).ok_or_else(|| anyhow::anyhow!("Unknown attribute {}", v)),
+        SelectorType::Jwt => Ok(RequestSelector::JwtClaim(v.to_string())),
     }
 }
 
@@ -132,3 +165,54 @@ impl<A> Matching<A> {
         self.matcher.as_str().len()
     }
 }
+
+/// the patterns of a list of `Matching` entries, compiled together so that they are all tested with a single scan of
+/// the input
+#[derive(Debug, Clone)]
+pub struct MatchingSet {
+    set: RegexSet,
+    negated: Vec<bool>,
+}
+
+impl MatchingSet {
+    pub fn new<A>(entries: &[Matching<A>]) -> Result<Self, regex::Error> {
+        Ok(MatchingSet {
+            set: RegexSet::new(entries.iter().map(|e| e.matcher.as_str()))?,
+            negated: entries.iter().map(|e| e.negated).collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.negated.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.negated.is_empty()
+    }
+
+    /// the indices of the entries that match, in order
+    pub fn matches<'s>(&'s self, s: &str) -> impl Iterator<Item = usize> + 's {
+        let matched = self.set.matches(s);
+        (0..self.negated.len()).filter(move |i| matched.matched(*i) ^ self.negated[*i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_set() {
+        let entries: Vec<Matching<usize>> = ["^/api/", "!^/api/", "\\.php$", "^/$"]
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Matching::from_str(p, i).unwrap())
+            .collect();
+        let set = MatchingSet::new(&entries).unwrap();
+        for path in &["/api/index.php", "/index.php", "/", "/other"] {
+            let expected: Vec<usize> = entries.iter().filter(|e| e.matches(path)).map(|e| e.inner).collect();
+            assert_eq!(set.matches(path).collect::<Vec<_>>(), expected, "{}", path);
+        }
+        assert_eq!(set.matches("/api/index.php").collect::<Vec<_>>(), vec![0, 2]);
+    }
+}
@@ -1,19 +1,23 @@
-use hyperscan::Matching;
 use lazy_static::lazy_static;
 use libinjection::{sqli, xss};
-use serde_json::json;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 use crate::config::contentfilter::{
     rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules, ContentFilterSection, Section,
-    SectionIdx,
+    SectionIdx, SignatureMatches,
 };
 use crate::config::raw::ContentFilterRule;
 use crate::config::utils::XDataSource;
-use crate::interface::{Action, ActionType, Tags};
+use crate::hits::HITS;
+use crate::interface::{Action, ActionType, Mutation, Tags};
+use crate::reason::{Initiator, Reason};
 use crate::requestfields::RequestField;
-use crate::utils::RequestInfo;
+use crate::utils::decoders::{nested_key, urldecode_str, DecodingResult};
+use crate::utils::{mask_matches, masker, BodyDecodingResult, RequestInfo};
 use crate::Logs;
+use serde::Serialize;
 
 lazy_static! {
     pub static ref LIBINJECTION_SQLI_TAGS: HashSet<String> = [
@@ -36,6 +40,15 @@ lazy_static! {
     .collect();
 }
 
+#[cfg(feature = "parallel-scan")]
+lazy_static! {
+    /// the requests whose inspected values exceed this size, in bytes, have their sections scanned concurrently
+    static ref PARALLEL_SCAN_BYTES: usize = std::env::var("CURIEFENSE_PARALLEL_SCAN_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1 << 20);
+}
+
 #[derive(Debug, Clone)]
 pub struct ContentFilterMatched {
     pub section: SectionIdx,
@@ -62,40 +75,36 @@ pub enum ContentFilterBlock {
     Mismatch(ContentFilterMatched),
     Block(HashSet<String>),
     Monitor(HashSet<String>),
+    Sanitize(HashSet<String>, Vec<Mutation>),
 }
 
 impl ContentFilterBlock {
     pub fn to_action(&self) -> Action {
+        let reason = Reason::new(Initiator::ContentFilter);
         let reason = match self {
-            ContentFilterBlock::Block(ids) => json!({
-                "initiator": "content_filter",
-                "tags": ids,
-                "name": "block"
-            }),
-            ContentFilterBlock::Monitor(ids) => json!({
-                "initiator": "content_filter",
-                "tags": ids,
-                "name": "monitor"
-            }),
-            ContentFilterBlock::TooManyEntries(idx) => json!({
-                "section": idx,
-                "initiator": "content_filter",
-                "value": "Too many entries"
-            }),
-            ContentFilterBlock::EntryTooLarge(idx, nm) => json!({
-                "section": idx,
-                "name": nm,
-                "initiator": "content_filter",
-                "value": "Entry too large"
-            }),
-            ContentFilterBlock::Mismatch(wmatch) => json!({
-                "section": wmatch.section,
-                "name": wmatch.name,
-                "initiator": "content_filter",
-                "value": wmatch.value,
-                "msg": "Mismatch"
-            }),
+            ContentFilterBlock::Block(ids) => reason.with_name("block").with_tags(ids),
+            ContentFilterBlock::Monitor(ids) => reason.with_name("monitor").with_tags(ids),
+            ContentFilterBlock::Sanitize(ids, _) => reason.with_name("sanitize").with_tags(ids),
+            ContentFilterBlock::TooManyEntries(idx) => {
+                reason.with_entry(*idx, None, None).with_message("Too many entries")
+            }
+            ContentFilterBlock::EntryTooLarge(idx, nm) => {
+                reason.with_entry(*idx, Some(nm), None).with_message("Entry too large")
+            }
+            ContentFilterBlock::Mismatch(wmatch) => reason
+                .with_entry(wmatch.section, Some(&wmatch.name), Some(&wmatch.value))
+                .with_message("Mismatch"),
         };
+        if let ContentFilterBlock::Sanitize(_, mutations) = self {
+            return Action {
+                atype: ActionType::Sanitize,
+                block_mode: false,
+                status: 200,
+                reason,
+                mutations: mutations.clone(),
+                ..Action::default()
+            };
+        }
         let block_mode = !matches!(self, ContentFilterBlock::Monitor(_));
 
         Action {
@@ -107,6 +116,53 @@ impl ContentFilterBlock {
             reason,
             content: "Access denied".to_string(),
             extra_tags: None,
+            template: None,
+            mutations: Vec::new(),
+        }
+    }
+}
+
+/// what the content filter inspected, reported in the `inspection` entry of the decisions, so that the operators can
+/// check that the requests were fully inspected
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InspectionStats {
+    /// the content filter profile
+    pub profile: String,
+    /// the entries of the request, their decoded variants included
+    pub fields: usize,
+    /// the entries that were scanned by the signatures, the others being allowed by the section rules, or skipped
+    pub scanned: usize,
+    /// the size of the scanned values, in bytes
+    pub bytes: usize,
+    /// the decoding transformations that changed a value
+    pub decode_passes: usize,
+    /// `none`, `decoded`, `failed`, `truncated` when the truncated body could not be decoded, or `skipped` when it was
+    /// larger than `max_body_size`
+    pub body: &'static str,
+    /// the caller only passed the beginning of the body
+    pub body_truncated: bool,
+    /// entries or decoded values were dropped because of the parsing budget
+    pub overflow: bool,
+}
+
+impl InspectionStats {
+    /// the statistics of the mapped request, before it is scanned
+    pub fn new(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Self {
+        use SectionIdx::*;
+        let sections = [Path, Headers, Cookies, Args].map(|idx| get_section(idx, rinfo));
+        InspectionStats {
+            profile: profile.id.clone(),
+            fields: sections.iter().map(|s| s.len()).sum(),
+            decode_passes: sections.iter().map(|s| s.decode_passes()).sum(),
+            body: match rinfo.rinfo.qinfo.body_decoding {
+                BodyDecodingResult::NoBody => "none",
+                BodyDecodingResult::ProperlyDecoded => "decoded",
+                BodyDecodingResult::DecodingFailed(_) => "failed",
+                BodyDecodingResult::Truncated(_) => "truncated",
+            },
+            body_truncated: rinfo.rinfo.meta.body_truncated,
+            overflow: rinfo.parse_overflow() || rinfo.rinfo.qinfo.path_as_map.overflow,
+            ..InspectionStats::default()
         }
     }
 }
@@ -117,6 +173,14 @@ struct Omitted {
     exclusions: Section<HashMap<String, HashSet<String>>>,
 }
 
+/// entries to sanitize, with the length they must be truncated to, or `None` when they must be removed
+type ToSanitize = HashMap<(SectionIdx, String), Option<usize>>;
+
+/// the name of the request entry, without the suffix of the decoded values
+fn entry_name(name: &str) -> &str {
+    name.strip_suffix(":decoded").unwrap_or(name)
+}
+
 fn get_section(idx: SectionIdx, rinfo: &RequestInfo) -> &RequestField {
     use SectionIdx::*;
     match idx {
@@ -137,6 +201,8 @@ pub fn content_filter_check(
 ) -> Result<(), ContentFilterBlock> {
     use SectionIdx::*;
     let mut omit = Default::default();
+    let mut to_sanitize = ToSanitize::new();
+    logs.inspection = Some(InspectionStats::new(rinfo, profile));
 
     // directly exit if omitted profile
     if tags.has_intersection(&profile.ignore) {
@@ -154,6 +220,7 @@ pub fn content_filter_check(
             get_section(*idx, rinfo),
             profile.ignore_alphanum,
             &mut omit,
+            if profile.sanitize { Some(&mut to_sanitize) } else { None },
         )?;
     }
 
@@ -163,39 +230,64 @@ pub fn content_filter_check(
     let test_sqli = LIBINJECTION_SQLI_TAGS.intersection(&profile.ignore).next().is_none()
         && LIBINJECTION_SQLI_TAGS.intersection(&kept).next().is_some();
 
-    let mut hca_keys: HashMap<String, (SectionIdx, String)> = HashMap::new();
+    // offending values, with the tags they triggered
+    let mut offenders: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut specific_tags = Tags::default();
 
-    // list of non whitelisted entries
-    for idx in &[Path, Headers, Cookies, Args] {
-        let section_content = get_section(*idx, rinfo)
-            .iter()
-            .filter(|(name, _)| !omit.entries.get(*idx).contains(*name))
-            .map(|(name, value)| (value.to_string(), (*idx, name.to_string())));
-        hca_keys.extend(section_content);
-    }
+    // without active or report tags, no signature can be kept, and the entries are not scanned
+    if kept.is_empty() {
+        logs.debug("no active or report tags, signatures not checked");
+    } else {
+        // the values are borrowed from the request, only the latin1 versions being allocated
+        let mut hca_keys: HashMap<Cow<str>, (SectionIdx, &str)> = HashMap::new();
 
-    injection_check(tags, &hca_keys, &omit, test_xss, test_sqli);
+        // list of non whitelisted entries
+        let (mut scanned, mut bytes) = (0, 0);
+        for idx in &[Path, Headers, Cookies, Args] {
+            let section_content = get_section(*idx, rinfo)
+                .iter()
+                .filter(|(name, _)| !omit.entries.get(*idx).contains(*name))
+                .inspect(|(_, value)| {
+                    scanned += 1;
+                    bytes += value.len();
+                })
+                .map(|(name, value)| (Cow::Borrowed(value), (*idx, name)));
+            hca_keys.extend(section_content);
+            // values that were not valid UTF-8 are also checked in their latin1 form, so that no byte is lost
+            let raw_content = get_section(*idx, rinfo)
+                .iter_raw()
+                .filter(|(name, _)| !omit.entries.get(*idx).contains(*name))
+                .inspect(|(_, value)| bytes += value.len())
+                .map(|(name, value)| (Cow::Owned(latin1(value)), (*idx, name)));
+            hca_keys.extend(raw_content);
+        }
+        if let Some(stats) = logs.inspection.as_mut() {
+            stats.scanned = scanned;
+            stats.bytes = bytes;
+        }
 
-    let mut specific_tags = Tags::default();
+        injection_check(logs, tags, &mut offenders, &hca_keys, &omit, test_xss, test_sqli);
 
-    // finally, hyperscan check
-    match mhsdb {
-        Some(hsdb) => {
-            if let Err(rr) = hyperscan(
-                logs,
-                tags,
-                &mut specific_tags,
-                hca_keys,
-                hsdb,
-                &kept,
-                &profile.ignore,
-                &omit.exclusions,
-            ) {
-                logs.error(|| rr.to_string())
+        // finally, signatures check
+        match mhsdb {
+            Some(hsdb) => {
+                if let Err(rr) = match_signatures(
+                    logs,
+                    tags,
+                    &mut specific_tags,
+                    &mut offenders,
+                    hca_keys,
+                    hsdb,
+                    &kept,
+                    &profile.ignore,
+                    &omit.exclusions,
+                ) {
+                    logs.error(|| rr.to_string())
+                }
+            }
+            None => {
+                logs.warning(||format!("no hsdb found for profile {}, it probably means that no rules were matched by the active/report/ignore", profile.id));
             }
-        }
-        None => {
-            logs.warning(||format!("no hsdb found for profile {}, it probably means that no rules were matched by the active/report/ignore", profile.id));
         }
     }
 
@@ -204,7 +296,11 @@ pub fn content_filter_check(
     tags.extend(specific_tags);
 
     if !sactive.is_empty() {
-        return Err(ContentFilterBlock::Block(sactive));
+        return Err(block_or_sanitize(rinfo, profile, &offenders, to_sanitize, sactive));
+    }
+    if !to_sanitize.is_empty() {
+        let report = tags.intersect(&profile.report);
+        return Err(block_or_sanitize(rinfo, profile, &offenders, to_sanitize, report));
     }
     if !sreport.is_empty() {
         return Err(ContentFilterBlock::Monitor(sreport));
@@ -212,7 +308,7 @@ pub fn content_filter_check(
 
     let active = tags.intersect(&profile.active);
     if !active.is_empty() {
-        return Err(ContentFilterBlock::Block(active));
+        return Err(block_or_sanitize(rinfo, profile, &offenders, to_sanitize, active));
     }
 
     let report = tags.intersect(&profile.report);
@@ -223,7 +319,135 @@ pub fn content_filter_check(
     Ok(())
 }
 
+/// when the profile sanitizes requests, and all the active tags were raised by entries that can be removed, the
+/// request is sanitized instead of being blocked
+fn block_or_sanitize(
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    offenders: &HashMap<String, HashSet<String>>,
+    mut to_sanitize: ToSanitize,
+    active: HashSet<String>,
+) -> ContentFilterBlock {
+    if !profile.sanitize {
+        return ContentFilterBlock::Block(active);
+    }
+    let blocking: HashSet<&String> = active.iter().filter(|t| profile.active.contains(*t)).collect();
+    let offending: HashMap<&str, &HashSet<String>> = offenders
+        .iter()
+        .filter(|(_, otags)| otags.iter().any(|t| blocking.contains(t)))
+        .map(|(v, otags)| (v.as_str(), otags))
+        .collect();
+    // tags that were not raised by a request entry, such as those of the global filters
+    if !blocking
+        .iter()
+        .all(|t| offending.values().any(|otags| otags.contains(*t)))
+    {
+        return ContentFilterBlock::Block(active);
+    }
+    // all the entries that have an offending value are removed
+    for idx in &[
+        SectionIdx::Path,
+        SectionIdx::Headers,
+        SectionIdx::Cookies,
+        SectionIdx::Args,
+    ] {
+        let section = get_section(*idx, rinfo);
+        let values = section
+            .iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .chain(section.iter_raw().map(|(name, value)| (name, latin1(value))));
+        for (name, value) in values {
+            if offending.contains_key(value.as_str()) {
+                to_sanitize.insert((*idx, entry_name(name).to_string()), None);
+            }
+        }
+    }
+    match mutations(rinfo, profile, &to_sanitize) {
+        Some(m) => ContentFilterBlock::Sanitize(active, m),
+        None => ContentFilterBlock::Block(active),
+    }
+}
+
+/// converts the entries to sanitize into request mutations
+///
+/// returns `None` when one of them can't be removed by the proxy: path parts, or arguments that are not in the
+/// query string
+fn mutations(rinfo: &RequestInfo, profile: &ContentFilterProfile, to_sanitize: &ToSanitize) -> Option<Vec<Mutation>> {
+    let mut out = Vec::new();
+    let mut removed_args = HashSet::new();
+    let mut entries: Vec<(&(SectionIdx, String), &Option<usize>)> = to_sanitize.iter().collect();
+    entries.sort_by(|((_, n1), _), ((_, n2), _)| n1.cmp(n2));
+    for ((idx, name), truncate) in entries {
+        match idx {
+            SectionIdx::Path => return None,
+            SectionIdx::Headers => out.push(match truncate {
+                Some(length) => Mutation::TruncateHeader {
+                    name: name.clone(),
+                    length: *length,
+                },
+                None => Mutation::RemoveHeader { name: name.clone() },
+            }),
+            SectionIdx::Cookies => out.push(Mutation::RemoveCookie { name: name.clone() }),
+            SectionIdx::Args => {
+                removed_args.insert(name.as_str());
+            }
+        }
+    }
+    // a header can't be both truncated and removed
+    let removed_headers: HashSet<String> = out
+        .iter()
+        .filter_map(|m| match m {
+            Mutation::RemoveHeader { name } => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    out.retain(|m| !matches!(m, Mutation::TruncateHeader { name, .. } if removed_headers.contains(name)));
+
+    if !removed_args.is_empty() {
+        let mut found = HashSet::new();
+        let query = rinfo
+            .rinfo
+            .qinfo
+            .query
+            .split('&')
+            .filter(|kv| {
+                let rawkey = kv.split('=').next().unwrap_or_default();
+                let key = match urldecode_str(rawkey) {
+                    DecodingResult::NoChange => rawkey.to_string(),
+                    DecodingResult::Changed(k) => k,
+                };
+                let key = if profile.nested_args {
+                    nested_key(&key).unwrap_or(key)
+                } else {
+                    key
+                };
+                match removed_args.get(key.as_str()) {
+                    None => true,
+                    Some(k) => {
+                        found.insert(*k);
+                        false
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        if found.len() != removed_args.len() {
+            return None;
+        }
+        let mut removed: Vec<String> = removed_args.into_iter().map(|s| s.to_string()).collect();
+        removed.sort();
+        out.push(Mutation::SetQuery { query, removed });
+    }
+    Some(out)
+}
+
+/// decodes bytes as latin1, this can't fail and preserves all values
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
 /// checks a section (headers, args, cookies) against the policy
+#[allow(clippy::too_many_arguments)]
 fn section_check(
     logs: &mut Logs,
     tags: &Tags,
@@ -232,7 +456,9 @@ fn section_check(
     params: &RequestField,
     ignore_alphanum: bool,
     omit: &mut Omitted,
+    mut to_sanitize: Option<&mut ToSanitize>,
 ) -> Result<(), ContentFilterBlock> {
+    let sanitizing = idx != SectionIdx::Path && to_sanitize.is_some();
     if idx != SectionIdx::Path && params.len() > section.max_count {
         if section.max_count > 0 {
             return Err(ContentFilterBlock::TooManyEntries(idx));
@@ -245,7 +471,18 @@ fn section_check(
         // skip decoded parameters for length checks
         if !name.ends_with(":decoded") && value.len() > section.max_length {
             if section.max_length > 0 {
-                return Err(ContentFilterBlock::EntryTooLarge(idx, name.to_string()));
+                match to_sanitize.as_mut() {
+                    // headers are truncated, the other entries are removed
+                    Some(ts) if sanitizing => {
+                        let truncate = Some(section.max_length).filter(|_| idx == SectionIdx::Headers);
+                        ts.entry((idx, entry_name(name).to_string())).or_insert(truncate);
+                        if truncate.is_none() {
+                            omit.entries.at(idx).insert(name.to_string());
+                            continue;
+                        }
+                    }
+                    _ => return Err(ContentFilterBlock::EntryTooLarge(idx, name.to_string())),
+                }
             } else {
                 logs.warning(|| format!("In section {:?}, max_length = 0", idx));
             }
@@ -267,11 +504,19 @@ fn section_check(
             if matched {
                 omit.entries.at(idx).insert(name.to_string());
             } else if name_entry.restrict {
-                return Err(ContentFilterBlock::Mismatch(ContentFilterMatched::new(
-                    idx,
-                    name.to_string(),
-                    value.to_string(),
-                )));
+                match to_sanitize.as_mut() {
+                    Some(ts) if sanitizing => {
+                        ts.insert((idx, entry_name(name).to_string()), None);
+                        omit.entries.at(idx).insert(name.to_string());
+                    }
+                    _ => {
+                        return Err(ContentFilterBlock::Mismatch(ContentFilterMatched::new(
+                            idx,
+                            name.to_string(),
+                            value.to_string(),
+                        )))
+                    }
+                }
             } else if tags.has_intersection(&name_entry.exclusions) {
                 omit.entries.at(idx).insert(name.to_string());
             } else if !name_entry.exclusions.is_empty() {
@@ -304,14 +549,16 @@ fn section_check(
 /// TODO: This also populates the hca_keys map
 /// this is stupid and needs to be changed
 fn injection_check(
+    logs: &mut Logs,
     tags: &mut Tags,
-    hca_keys: &HashMap<String, (SectionIdx, String)>,
+    offenders: &mut HashMap<String, HashSet<String>>,
+    hca_keys: &HashMap<Cow<str>, (SectionIdx, &str)>,
     omit: &Omitted,
     test_xss: bool,
     test_sqli: bool,
 ) {
     for (value, (idx, name)) in hca_keys.iter() {
-        let omit_tags = omit.exclusions.get(*idx).get(name);
+        let omit_tags = omit.exclusions.get(*idx).get(*name);
         let rtest_xss = test_xss
             && !omit_tags
                 .map(|tgs| LIBINJECTION_XSS_TAGS.intersection(tgs).next().is_some())
@@ -320,81 +567,171 @@ fn injection_check(
             && !omit_tags
                 .map(|tgs| LIBINJECTION_SQLI_TAGS.intersection(tgs).next().is_some())
                 .unwrap_or(false);
+        let mut etags = Tags::default();
         if rtest_sqli {
-            if let Some((b, _)) = sqli(value) {
+            let start = Instant::now();
+            let result = sqli(value);
+            logs.signature_cost("libinjection-sqli", start.elapsed().as_micros() as u64);
+            if let Some((b, _)) = result {
                 if b {
-                    tags.insert_qualified("cf-rule-id", "libinjection-sqli");
-                    tags.insert_qualified("cf-rule-category", "libinjection");
-                    tags.insert_qualified("cf-rule-subcategory", "libinjection-sqli");
-                    tags.insert_qualified("cf-rule-risk", "libinjection");
+                    etags.insert_qualified("cf-rule-id", "libinjection-sqli");
+                    etags.insert_qualified("cf-rule-category", "libinjection");
+                    etags.insert_qualified("cf-rule-subcategory", "libinjection-sqli");
+                    etags.insert_qualified("cf-rule-risk", "libinjection");
                 }
             }
         }
         if rtest_xss {
-            if let Some(b) = xss(value) {
+            let start = Instant::now();
+            let result = xss(value);
+            logs.signature_cost("libinjection-xss", start.elapsed().as_micros() as u64);
+            if let Some(b) = result {
                 if b {
-                    tags.insert_qualified("cf-rule-id", "libinjection-xss");
-                    tags.insert_qualified("cf-rule-category", "libinjection");
-                    tags.insert_qualified("cf-rule-subcategory", "libinjection-xss");
-                    tags.insert_qualified("cf-rule-risk", "libinjection");
+                    etags.insert_qualified("cf-rule-id", "libinjection-xss");
+                    etags.insert_qualified("cf-rule-category", "libinjection");
+                    etags.insert_qualified("cf-rule-subcategory", "libinjection-xss");
+                    etags.insert_qualified("cf-rule-risk", "libinjection");
                 }
             }
         }
+        if !etags.is_empty() {
+            offenders
+                .entry(value.to_string())
+                .or_default()
+                .extend(etags.iter().map(|t| t.to_string()));
+            tags.extend(etags);
+        }
+    }
+}
+
+/// the matching signatures, as pairs of entry and signature indices
+#[cfg(not(feature = "parallel-scan"))]
+fn scan_entries(
+    sigs: &ContentFilterRules,
+    entries: &[(Cow<str>, (SectionIdx, &str))],
+) -> anyhow::Result<SignatureMatches> {
+    let values: Vec<&str> = entries.iter().map(|(k, _)| k.as_ref()).collect();
+    sigs.scanner()?.scan(&values)
+}
+
+/// the matching signatures, as pairs of entry and signature indices
+#[cfg(feature = "parallel-scan")]
+fn scan_entries(
+    sigs: &ContentFilterRules,
+    entries: &[(Cow<str>, (SectionIdx, &str))],
+) -> anyhow::Result<SignatureMatches> {
+    scan_sections(sigs, entries, *PARALLEL_SCAN_BYTES)
+}
+
+/// the sections of the requests larger than `threshold` are scanned concurrently, each with its own scanner, the
+/// smaller ones being scanned at once
+#[cfg(feature = "parallel-scan")]
+fn scan_sections(
+    sigs: &ContentFilterRules,
+    entries: &[(Cow<str>, (SectionIdx, &str))],
+    threshold: usize,
+) -> anyhow::Result<SignatureMatches> {
+    let size: usize = entries.iter().map(|(k, _)| k.len()).sum();
+    let mut sections: HashMap<SectionIdx, Vec<usize>> = HashMap::new();
+    for (i, (_, (idx, _))) in entries.iter().enumerate() {
+        sections.entry(*idx).or_default().push(i);
+    }
+    if size < threshold || sections.len() < 2 {
+        let values: Vec<&str> = entries.iter().map(|(k, _)| k.as_ref()).collect();
+        return sigs.scanner()?.scan(&values);
     }
+    let scan_section = |indices: &Vec<usize>| -> anyhow::Result<SignatureMatches> {
+        let values: Vec<&str> = indices.iter().map(|i| entries[*i].0.as_ref()).collect();
+        Ok(sigs
+            .scanner()?
+            .scan(&values)?
+            .into_iter()
+            .map(|(value, id)| (indices[value], id))
+            .collect())
+    };
+    let mut out = std::thread::scope(|scope| {
+        let threads: Vec<_> = sections
+            .values()
+            .map(|indices| scope.spawn(move || scan_section(indices)))
+            .collect();
+        threads
+            .into_iter()
+            .map(|t| {
+                t.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("a signature scan thread panicked")))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?
+    .into_iter()
+    .flatten()
+    .collect::<SignatureMatches>();
+    out.sort_unstable();
+    Ok(out)
 }
 
 #[allow(clippy::too_many_arguments)]
-fn hyperscan(
+fn match_signatures(
     logs: &mut Logs,
     tags: &mut Tags,
     specific_tags: &mut Tags,
-    hca_keys: HashMap<String, (SectionIdx, String)>,
+    offenders: &mut HashMap<String, HashSet<String>>,
+    hca_keys: HashMap<Cow<str>, (SectionIdx, &str)>,
     sigs: &ContentFilterRules,
     global_kept: &HashSet<String>,
     global_ignore: &HashSet<String>,
     exclusions: &Section<HashMap<String, HashSet<String>>>,
 ) -> anyhow::Result<()> {
-    let scratch = sigs.db.alloc_scratch()?;
-    // TODO: use `intersperse` when this stabilizes
-    let to_scan = hca_keys.keys().cloned().collect::<Vec<_>>().join("\n");
-    let mut found = false;
-    sigs.db.scan(&[to_scan], &scratch, |_, _, _, _| {
-        found = true;
-        Matching::Continue
-    })?;
-    logs.debug(|| format!("matching content filter signatures: {}", found));
-
-    if !found {
-        return Ok(());
+    let entries: Vec<(Cow<str>, (SectionIdx, &str))> = hca_keys.into_iter().collect();
+    let start = Instant::now();
+    let matches = scan_entries(sigs, &entries)?;
+    // the scan time is charged to the groups of the signatures that matched
+    let elapsed = start.elapsed().as_micros() as u64;
+    logs.debug(|| format!("matching content filter signatures: {}", !matches.is_empty()));
+    let groups: HashSet<&str> = matches
+        .iter()
+        .filter_map(|(_, id)| sigs.ids.get(*id))
+        .map(|sig| sig.category.as_str())
+        .collect();
+    for group in groups {
+        logs.signature_cost(group, elapsed);
     }
 
-    // something matched! but what?
-    for (k, (sid, name)) in hca_keys {
-        sigs.db.scan(&[k.as_bytes()], &scratch, |id, _, _, _| {
-            match sigs.ids.get(id as usize) {
-                None => logs.error(|| format!("Should not happen, invalid hyperscan index {}", id)),
-                Some(sig) => {
-                    logs.debug(|| format!("signature matched {:?}", sig));
-
-                    // new specific tags are singleton hashsets, but we use the Tags structure to make sure
-                    // they are properly converted
-                    let (new_specific_tags, new_tags) = rule_tags(sig);
-                    if (new_tags.has_intersection(global_kept) || new_specific_tags.has_intersection(global_kept))
-                        && exclusions
-                            .get(sid)
-                            .get(&name)
-                            .map(|ex| new_tags.has_intersection(ex) || new_specific_tags.has_intersection(ex))
-                            != Some(true)
-                        && !new_tags.has_intersection(global_ignore)
-                        && !new_specific_tags.has_intersection(global_ignore)
-                    {
-                        tags.extend(new_tags);
-                        specific_tags.extend(new_specific_tags);
-                    }
+    for (value, id) in matches {
+        let (k, (sid, name)) = &entries[value];
+        match sigs.ids.get(id) {
+            None => logs.error(|| format!("Should not happen, invalid signature index {}", id)),
+            Some(sig) => {
+                logs.debug(|| format!("signature matched {:?}", sig));
+                HITS.record_signature(&sig.id);
+
+                // new specific tags are singleton hashsets, but we use the Tags structure to make sure
+                // they are properly converted
+                let (new_specific_tags, new_tags) = rule_tags(sig);
+                let kept = (new_tags.has_intersection(global_kept) || new_specific_tags.has_intersection(global_kept))
+                    && exclusions
+                        .get(*sid)
+                        .get(*name)
+                        .map(|ex| new_tags.has_intersection(ex) || new_specific_tags.has_intersection(ex))
+                        != Some(true)
+                    && !new_tags.has_intersection(global_ignore)
+                    && !new_specific_tags.has_intersection(global_ignore);
+                // the value is not part of the trace, as it might be masked
+                logs.explain(
+                    "content_filter",
+                    &sig.id,
+                    kept,
+                    || serde_json::json!({ "section": sid, "name": name }),
+                );
+                if kept {
+                    offenders
+                        .entry(k.to_string())
+                        .or_default()
+                        .extend(new_tags.iter().chain(new_specific_tags.iter()).map(|t| t.to_string()));
+                    tags.extend(new_tags);
+                    specific_tags.extend(new_specific_tags);
                 }
             }
-            Matching::Continue
-        })?;
+        }
     }
     Ok(())
 }
@@ -442,19 +779,82 @@ pub fn masking(masking_seed: &[u8], req: RequestInfo, profile: &ContentFilterPro
         match x {
             // for now, do not mask the Uri
             XDataSource::Uri => (),
+            // masked along with the other entries of the headers section
+            XDataSource::Trailer | XDataSource::ChunkExtension => (),
             XDataSource::CookieHeader => {
                 ri.headers.mask(masking_seed, "cookie");
             }
         }
     }
+
+    let patterns = &profile.mask_patterns;
+    if !patterns.is_empty() {
+        ri.cookies.mask_matches(masking_seed, patterns);
+        ri.rinfo.qinfo.args.mask_matches(masking_seed, patterns);
+        ri.rinfo.qinfo.path_as_map.mask_matches(masking_seed, patterns);
+        ri.headers.mask_matches(masking_seed, patterns);
+        let qinfo = &mut ri.rinfo.qinfo;
+        for s in [
+            &mut qinfo.uri,
+            &mut qinfo.qpath,
+            &mut qinfo.canonical_path,
+            &mut qinfo.query,
+        ] {
+            if let Some(masked) = mask_matches(masking_seed, patterns, s) {
+                *s = masked;
+            }
+        }
+    }
     ri
 }
 
+/// is the entry masked by name
+fn is_masked_entry(profile: &ContentFilterProfile, section: SectionIdx, name: &str) -> bool {
+    let section = profile.sections.get(section);
+    match section.names.get(name) {
+        Some(e) => e.mask,
+        None => section.regex.iter().any(|(re, e)| e.mask && re.is_match(name)),
+    }
+}
+
+/// masks the values of the reason, and of the other matches, as they are in the request map
+pub fn mask_reason(masking_seed: &[u8], reason: &mut Reason, profile: &ContentFilterProfile) {
+    if let (Some(section), Some(entry), Some(value)) = (reason.section, &reason.entry, &reason.value) {
+        if is_masked_entry(profile, section, entry) {
+            reason.value = Some(masker(masking_seed, value));
+        }
+    }
+    for s in [&mut reason.value, &mut reason.message]
+        .iter_mut()
+        .filter_map(|s| s.as_mut())
+    {
+        if let Some(masked) = mask_matches(masking_seed, &profile.mask_patterns, s) {
+            *s = masked;
+        }
+    }
+    for m in reason.matches.iter_mut() {
+        mask_reason(masking_seed, m, profile);
+    }
+}
+
+/// masks the patterns in the inspection logs
+pub fn mask_logs(masking_seed: &[u8], logs: &mut Logs, profile: &ContentFilterProfile) {
+    if profile.mask_patterns.is_empty() {
+        return;
+    }
+    for log in logs.logs.iter_mut() {
+        if let Some(masked) = mask_matches(masking_seed, &profile.mask_patterns, &log.message) {
+            log.message = masked;
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::raw::ParseBudget;
     use crate::config::utils::DataSource;
-    use crate::utils::{map_request, RequestMeta};
+    use crate::utils::{map_request, RequestMeta, TlsFingerprint};
     use crate::{Logs, RawRequest};
 
     fn test_request_info() -> RequestInfo {
@@ -462,6 +862,18 @@ mod test {
             authority: Some("myhost".to_string()),
             method: "GET".to_string(),
             path: "/foo?arg1=avalue1&arg2=avalue2".to_string(),
+            scheme: None,
+            port: None,
+            http_version: None,
+            proxy: None,
+            body_truncated: false,
+            header_order: None,
+            client_cert: None,
+            tls: TlsFingerprint::default(),
+            config_path: None,
+            securitypolicy: None,
+            trailers: HashMap::default(),
+            chunk_extensions: Vec::new(),
             extra: HashMap::default(),
         };
         let mut logs = Logs::default();
@@ -473,9 +885,19 @@ mod test {
             ipstr: "1.2.3.4".into(),
             mbody: None,
             headers,
+            header_bytes: HashMap::new(),
             meta,
         };
-        map_request(&mut logs, &[], &[], 500, &raw_request)
+        map_request(
+            &mut logs,
+            &[],
+            &[],
+            500,
+            &[],
+            &ParseBudget::default(),
+            false,
+            &raw_request,
+        )
     }
 
     #[test]
@@ -497,6 +919,36 @@ mod test {
         }
     }
 
+    #[test]
+    fn masking_patterns() {
+        let rinfo = test_request_info();
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.mask_patterns = vec![regex::Regex::new("value1").unwrap()];
+        let masked = masking(b"test", rinfo, &profile);
+        assert_eq!(masked.rinfo.qinfo.args.get_str("arg1"), Some("aMASKED{0711e7c2}"));
+        assert_eq!(masked.rinfo.qinfo.args.get_str("arg2"), Some("avalue2"));
+        assert_eq!(masked.headers.get_str("h1"), Some("MASKED{0711e7c2}"));
+        assert_eq!(masked.rinfo.qinfo.uri, "/foo?arg1=aMASKED{0711e7c2}&arg2=avalue2");
+        assert_eq!(masked.rinfo.qinfo.query, "arg1=aMASKED{0711e7c2}&arg2=avalue2");
+
+        let mut reason =
+            Reason::new(Initiator::ContentFilter).with_entry(SectionIdx::Args, Some("arg2"), Some("avalue2"));
+        reason.matches.push(Reason::new(Initiator::ContentFilter).with_entry(
+            SectionIdx::Headers,
+            Some("h1"),
+            Some("value1"),
+        ));
+        profile.sections.at(SectionIdx::Args).names = std::iter::once(("arg2".to_string(), maskentry())).collect();
+        mask_reason(b"test", &mut reason, &profile);
+        assert_eq!(reason.value.as_deref(), Some("MASKED{7ce2d8de}"));
+        assert_eq!(reason.matches[0].value.as_deref(), Some("MASKED{0711e7c2}"));
+
+        let mut logs = Logs::default();
+        logs.debug("matched value1");
+        mask_logs(b"test", &mut logs, &profile);
+        assert_eq!(logs.logs[0].message, "matched MASKED{0711e7c2}");
+    }
+
     #[test]
     fn masking_all_args_re() {
         let rinfo = test_request_info();
@@ -580,4 +1032,137 @@ mod test {
             masked.rinfo.qinfo.args
         );
     }
+
+    #[test]
+    fn sanitize_entries() {
+        let rinfo = test_request_info();
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.ignore_alphanum = false;
+        profile.sections.at(SectionIdx::Headers).max_length = 5;
+        profile.sections.at(SectionIdx::Args).names = std::iter::once((
+            "arg2".to_string(),
+            ContentFilterEntryMatch {
+                restrict: true,
+                ..maskentry()
+            },
+        ))
+        .collect();
+        let mut logs = Logs::default();
+        let res = content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None);
+        assert!(matches!(
+            res,
+            Err(ContentFilterBlock::EntryTooLarge(SectionIdx::Headers, _))
+        ));
+
+        profile.sanitize = true;
+        let res = content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None);
+        let mutations = match res {
+            Err(ContentFilterBlock::Sanitize(_, m)) => m,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(
+            mutations,
+            vec![
+                Mutation::TruncateHeader {
+                    name: "h1".to_string(),
+                    length: 5
+                },
+                Mutation::TruncateHeader {
+                    name: "h2".to_string(),
+                    length: 5
+                },
+                Mutation::SetQuery {
+                    query: "arg1=avalue1".to_string(),
+                    removed: vec!["arg2".to_string()]
+                },
+            ]
+        );
+        let action = ContentFilterBlock::Sanitize(HashSet::new(), mutations).to_action();
+        assert_eq!(action.atype, ActionType::Sanitize);
+        assert!(!action.block_mode);
+    }
+
+    #[test]
+    fn empty_profile() {
+        let rinfo = test_request_info();
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        let scanned = |logs: &Logs| logs.to_stringvec().iter().any(|l| l.contains("no hsdb found"));
+
+        let mut logs = Logs::default();
+        let res = content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None);
+        assert!(res.is_ok());
+        assert!(!scanned(&logs));
+
+        profile.report.insert("cf-rule-risk:5".to_string());
+        let mut logs = Logs::default();
+        let res = content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None);
+        assert!(res.is_ok());
+        assert!(scanned(&logs));
+    }
+
+    #[test]
+    fn inspection_stats() {
+        use crate::config::contentfilter::Transformation;
+        use crate::test_utils::{ContentFilterBuilder, PolicyBuilder, RequestBuilder};
+
+        let profile = ContentFilterBuilder::new("stats")
+            .report(&["cf-rule-risk:5"])
+            .decoding(&[Transformation::UrlDecode])
+            .build();
+        let policy = PolicyBuilder::new("stats").content_filter(profile.clone()).build();
+        let rinfo = RequestBuilder::post("/a?q=%253Cscript%253E&n=1", "not json")
+            .header("content-type", "application/json")
+            .meta("body_truncated", "true")
+            .request_info_for(&policy);
+        let mut logs = Logs::default();
+        let res = content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None);
+        assert!(res.is_ok());
+        let stats = logs.inspection.unwrap();
+        assert_eq!(stats.profile, "stats");
+        assert_eq!(
+            stats.fields,
+            rinfo.headers.len() + rinfo.rinfo.qinfo.args.len() + rinfo.rinfo.qinfo.path_as_map.len()
+        );
+        assert_eq!(stats.decode_passes, 1);
+        assert!(rinfo.rinfo.qinfo.args.get_str("q:decoded").is_some());
+        // the alphanumeric values are not scanned
+        assert!(stats.scanned > 0 && stats.scanned < stats.fields);
+        assert!(stats.bytes >= "%3Cscript%3E".len() + "<script>".len());
+        assert_eq!(stats.body, "truncated");
+        assert!(stats.body_truncated);
+        assert!(!stats.overflow);
+
+        // nothing is scanned without active or report tags
+        let mut logs = Logs::default();
+        let profile = ContentFilterBuilder::new("stats").build();
+        content_filter_check(&mut logs, &mut Tags::default(), &rinfo, &profile, None).unwrap();
+        assert_eq!(logs.inspection.map(|s| (s.scanned, s.bytes)), Some((0, 0)));
+    }
+
+    #[cfg(feature = "parallel-scan")]
+    #[test]
+    fn parallel_scan() {
+        let rule = |id: &str, operand: &str| ContentFilterRule {
+            id: id.to_string(),
+            operand: operand.to_string(),
+            risk: 1,
+            category: "c".to_string(),
+            subcategory: "s".to_string(),
+            tags: HashSet::new(),
+        };
+        let sigs = crate::config::contentfilter::build_rules(
+            &mut Logs::default(),
+            vec![rule("1", "union.*select"), rule("2", "<script")],
+        )
+        .unwrap();
+        let entries: Vec<(Cow<str>, (SectionIdx, &str))> = vec![
+            (Cow::Borrowed("a union select"), (SectionIdx::Args, "a")),
+            (Cow::Borrowed("nothing"), (SectionIdx::Headers, "h")),
+            (Cow::Borrowed("<SCRIPT>"), (SectionIdx::Cookies, "c")),
+            (Cow::Borrowed("<script> union all select"), (SectionIdx::Args, "b")),
+        ];
+        let sequential = scan_sections(&sigs, &entries, usize::MAX).unwrap();
+        assert_eq!(sequential.to_vec(), vec![(0, 0), (2, 1), (3, 0), (3, 1)]);
+        assert_eq!(scan_sections(&sigs, &entries, 0).unwrap(), sequential);
+    }
 }
@@ -0,0 +1,190 @@
+use crate::config::config_snapshot;
+use crate::config::raw::{CookieSigningAction, RawCookieSigning};
+use crate::crypto::{from_hex, to_hex};
+use crate::interface::Action;
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::securitypolicy::match_securitypolicy;
+use crate::utils::RawRequest;
+use ring::hmac;
+use std::sync::Arc;
+
+/// the signed cookies of the request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieIntegrity {
+    /// the cookies with a valid signature
+    pub valid: Vec<String>,
+    /// the cookies without a signature, or whose signature does not match
+    pub tampered: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CookieSigning {
+    key: hmac::Key,
+    cookies: Vec<String>,
+    suffix: String,
+    action: CookieSigningAction,
+}
+
+/// the name and value of the cookies of a `cookie` header, split like `utils::cookie_map`
+fn parse_cookies(header: &str) -> impl Iterator<Item = (&str, &str)> {
+    header.split("; ").map(|c| c.split_once('=').unwrap_or((c, "")))
+}
+
+impl CookieSigning {
+    pub fn resolve(raw: &RawCookieSigning) -> Self {
+        CookieSigning {
+            key: hmac::Key::new(hmac::HMAC_SHA256, raw.secret.as_bytes()),
+            cookies: raw.cookies.clone(),
+            suffix: raw.suffix.clone(),
+            action: raw.action,
+        }
+    }
+
+    fn signature(&self, name: &str, value: &str) -> String {
+        to_hex(hmac::sign(&self.key, format!("{}={}", name, value).as_bytes()).as_ref())
+    }
+
+    /// checks the signatures of the cookies, `cookie_header` being the raw `cookie` header
+    pub fn verify(&self, cookie_header: Option<&str>) -> CookieIntegrity {
+        let mut out = CookieIntegrity::default();
+        let header = match cookie_header {
+            Some(h) => h,
+            None => return out,
+        };
+        let cookies: Vec<(&str, &str)> = parse_cookies(header).collect();
+        let get = |name: &str| -> Vec<&str> { cookies.iter().filter(|(k, _)| *k == name).map(|(_, v)| *v).collect() };
+        for name in &self.cookies {
+            // a repeated cookie could be read differently by the application, so it is tampered
+            let valid = match (
+                get(name).as_slice(),
+                get(&format!("{}{}", name, self.suffix)).as_slice(),
+            ) {
+                ([], _) => continue,
+                ([value], [signature]) => from_hex(signature)
+                    .is_some_and(|s| hmac::verify(&self.key, format!("{}={}", name, value).as_bytes(), &s).is_ok()),
+                _ => false,
+            };
+            if valid {
+                out.valid.push(name.clone());
+            } else {
+                out.tampered.push(name.clone());
+            }
+        }
+        out
+    }
+
+    /// the companion `Set-Cookie` value of an upstream `Set-Cookie`, when it sets one of the signed cookies
+    pub fn sign(&self, set_cookie: &str) -> Option<String> {
+        let (pair, attributes) = match set_cookie.split_once(';') {
+            Some((p, a)) => (p, Some(a)),
+            None => (set_cookie, None),
+        };
+        let (name, value) = pair.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if !self.cookies.iter().any(|c| c == name) {
+            return None;
+        }
+        let mut out = format!("{}{}={}", name, self.suffix, self.signature(name, value));
+        if let Some(attributes) = attributes {
+            out.push(';');
+            out.push_str(attributes);
+        }
+        Some(out)
+    }
+
+    /// the blocking action, for the requests with tampered cookies
+    pub fn action(&self, result: Option<&CookieIntegrity>) -> Option<Action> {
+        let tampered = &result?.tampered;
+        if self.action == CookieSigningAction::Tag || tampered.is_empty() {
+            return None;
+        }
+        Some(Action {
+            status: 403,
+            reason: Reason::new(Initiator::CookieSignature)
+                .with_message(format!("tampered cookies: {}", tampered.join(", "))),
+            ..Action::default()
+        })
+    }
+}
+
+/// the cookie signing settings of the security policy entry of the request, for the response path
+pub fn response_signer(configpath: &str, raw: &RawRequest) -> Option<Arc<CookieSigning>> {
+    let mut logs = Logs::default();
+    let snapshot = config_snapshot(configpath, &mut logs)?;
+    match_securitypolicy(
+        &raw.get_host(),
+        &raw.meta.canonical_path(),
+        &raw.meta,
+        &snapshot.config,
+        &mut logs,
+    )
+    .and_then(|(_, secpolicy)| secpolicy.cookie_signing.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    fn settings(action: CookieSigningAction) -> CookieSigning {
+        let mut raw: RawCookieSigning = serde_json::from_str(r#"{"secret": "k", "cookies": ["role"]}"#).unwrap();
+        raw.action = action;
+        CookieSigning::resolve(&raw)
+    }
+
+    #[test]
+    fn signed_cookies() {
+        let signing = settings(CookieSigningAction::Block);
+        assert_eq!(signing.sign("session=abc; Path=/"), None);
+        let companion = signing.sign("role=user; Path=/; HttpOnly").unwrap();
+        let (pair, attributes) = companion.split_once(';').unwrap();
+        assert_eq!(attributes, " Path=/; HttpOnly");
+        let signature = pair.strip_prefix("role__sig=").unwrap();
+        assert_eq!(signature.len(), 64);
+
+        let header = format!("session=abc; role=user; role__sig={}", signature);
+        let result = signing.verify(Some(&header));
+        assert_eq!(result.valid, vec!["role".to_string()]);
+        assert!(signing.action(Some(&result)).is_none());
+
+        let forged = format!("role=admin; role__sig={}", signature);
+        let result = signing.verify(Some(&forged));
+        assert_eq!(result.tampered, vec!["role".to_string()]);
+        let action = signing.action(Some(&result)).unwrap();
+        assert_eq!(action.reason.initiator, Initiator::CookieSignature);
+        assert_eq!(signing.verify(Some("role=admin")).tampered, vec!["role".to_string()]);
+        assert_eq!(signing.verify(Some("session=abc")), CookieIntegrity::default());
+        let repeated = format!("role=user; role__sig={}; role=admin", signature);
+        assert_eq!(signing.verify(Some(&repeated)).tampered, vec!["role".to_string()]);
+        let repeated = format!("role=user; role__sig={0}; role__sig={0}", signature);
+        assert_eq!(signing.verify(Some(&repeated)).tampered, vec!["role".to_string()]);
+        let truncated = format!("role=user; role__sig={}", &signature[..62]);
+        assert_eq!(signing.verify(Some(&truncated)).tampered, vec!["role".to_string()]);
+        let tag_only = settings(CookieSigningAction::Tag);
+        let result = tag_only.verify(Some("role=admin"));
+        assert_eq!(result.tampered, vec!["role".to_string()]);
+        assert!(tag_only.action(Some(&result)).is_none());
+    }
+
+    #[test]
+    fn capitalized_cookie_header() {
+        let signing = settings(CookieSigningAction::Block);
+        let companion = signing.sign("role=user").unwrap();
+        let rq = RequestBuilder::get("/");
+        let mut raw = rq.raw();
+        // the proxies do not all lowercase the header names
+        raw.headers
+            .insert("Cookie".to_string(), format!("role=user; {}", companion));
+        assert_eq!(
+            signing.verify(raw.cookie_header().as_deref()).valid,
+            vec!["role".to_string()]
+        );
+        raw.headers
+            .insert("Cookie".to_string(), format!("role=admin; {}", companion));
+        assert_eq!(
+            signing.verify(raw.cookie_header().as_deref()).tampered,
+            vec!["role".to_string()]
+        );
+    }
+}
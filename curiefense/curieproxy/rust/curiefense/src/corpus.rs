@@ -0,0 +1,119 @@
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::{Config, ConfigSnapshot};
+use crate::logs::Logs;
+use crate::securitypolicy::match_securitypolicy;
+use crate::utils::{map_request_with, InspectionRequest, RawRequest, RequestInfo};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct RecordedRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+impl RecordedRequest {
+    /// splits the pseudo headers, the client address defaulting to the `x-forwarded-for` header, like the Lua tests
+    pub fn to_inspection(&self) -> InspectionRequest {
+        let mut meta = HashMap::new();
+        let mut headers = HashMap::new();
+        for (k, v) in &self.headers {
+            match k.strip_prefix(':') {
+                Some(pseudo) => meta.insert(pseudo.to_lowercase(), v.clone()),
+                None => headers.insert(k.clone(), v.clone()),
+            };
+        }
+        let ip = self
+            .ip
+            .clone()
+            .or_else(|| headers.get("x-forwarded-for").cloned())
+            .unwrap_or_else(|| "1.2.3.4".to_string());
+        InspectionRequest {
+            ip,
+            meta,
+            headers,
+            body: self.body.clone(),
+        }
+    }
+}
+
+/// loads a corpus file
+pub fn load_requests<P: AsRef<Path>>(path: P) -> Result<Vec<InspectionRequest>, String> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).map_err(|rr| format!("{}: {}", path.display(), rr))?;
+    let recorded: Vec<RecordedRequest> =
+        serde_json::from_str(&content).map_err(|rr| format!("{}: {}", path.display(), rr))?;
+    Ok(recorded.iter().map(RecordedRequest::to_inspection).collect())
+}
+
+/// loads the configuration stored at `basepath`, without publishing it
+pub fn load_config(basepath: &str) -> Result<ConfigSnapshot, String> {
+    let mut logs = Logs::default();
+    match Config::empty().reload(&mut logs, basepath) {
+        Some((config, hsdb)) if config.default.is_some() || !config.securitypolicies.is_empty() => Ok(ConfigSnapshot {
+            config: Arc::new(config),
+            hsdb: Arc::new(hsdb),
+        }),
+        _ => Err(format!(
+            "could not load the configuration from {}: {:?}",
+            basepath,
+            logs.to_stringvec()
+        )),
+    }
+}
+
+/// matches the security policy and maps the request, like the inspection does
+pub fn map_with_policy<'a>(
+    logs: &mut Logs,
+    cfg: &'a Config,
+    raw: &RawRequest,
+) -> Option<(&'a SecurityPolicy, RequestInfo)> {
+    let (_, secpolicy) = match_securitypolicy(&raw.get_host(), &raw.meta.canonical_path(), &raw.meta, cfg, logs)?;
+    let reqinfo = map_request_with(
+        logs,
+        &secpolicy.content_filter_profile.decoding,
+        &secpolicy.content_filter_profile.content_type,
+        secpolicy.content_filter_profile.max_body_depth,
+        &secpolicy.session,
+        &secpolicy.content_filter_profile.parse_budget,
+        secpolicy.content_filter_profile.nested_args,
+        &secpolicy.normalization,
+        raw,
+    );
+    Some((secpolicy, reqinfo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_request() {
+        let recorded: RecordedRequest = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "headers": {
+                ":authority": "localhost",
+                ":method": "POST",
+                ":path": "/a?b=c",
+                "x-forwarded-for": "12.13.14.15"
+            },
+            "body": "d=e",
+            "response": { "action": "pass" }
+        }))
+        .unwrap();
+        let request = recorded.to_inspection();
+        assert_eq!(request.ip, "12.13.14.15");
+        assert_eq!(request.headers.len(), 1);
+        let raw = request.to_raw().unwrap();
+        assert_eq!(raw.meta.method, "POST");
+        assert_eq!(raw.get_host(), "localhost");
+        assert_eq!(raw.mbody, Some(&b"d=e"[..]));
+    }
+}
@@ -0,0 +1,219 @@
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref LOCAL: Option<Counters> = start();
+}
+
+/// the local store, when selected, or when there is no redis to share the counters
+fn start() -> Option<Counters> {
+    if cfg!(feature = "redis") && std::env::var("CURIEFENSE_LIMIT_STORE").ok().as_deref() != Some("local") {
+        return None;
+    }
+    let sweep = Duration::from_secs(env_or("CURIEFENSE_LIMIT_SWEEP_SECS", 10).max(1));
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-counters".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(sweep);
+            if let Some(counters) = LOCAL.as_ref() {
+                counters.sweep(now());
+            }
+        })
+    {
+        tracing::error!(target: "curiefense::limit", "could not start the limit counters sweeper: {}", rr);
+    }
+    Some(Counters::new(env_or("CURIEFENSE_LIMIT_SHARDS", 64)))
+}
+
+/// milliseconds since the process started (see `clock::monotonic_millis`)
+pub fn now() -> u64 {
+    crate::clock::monotonic_millis()
+}
+
+struct Counter {
+    count: AtomicU64,
+    /// when the counter expires, in milliseconds (see `now`)
+    expires: u64,
+    /// the distinct values of the pair limits
+    members: Option<Mutex<HashSet<String>>>,
+}
+
+impl Counter {
+    fn new(now: u64, timeframe: u64, member: Option<&str>) -> Self {
+        Counter {
+            count: AtomicU64::new(1),
+            expires: now.saturating_add(timeframe.saturating_mul(1000)),
+            members: member.map(|m| Mutex::new(std::iter::once(m.to_string()).collect())),
+        }
+    }
+
+    /// counts a hit, or a new distinct member, returning the count
+    fn hit(&self, member: Option<&str>) -> u64 {
+        match (member, &self.members) {
+            (Some(m), Some(members)) => {
+                let mut members = match members.lock() {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                if !members.contains(m) {
+                    members.insert(m.to_string());
+                }
+                let count = members.len() as u64;
+                self.count.store(count, Ordering::Relaxed);
+                count
+            }
+            _ => self.count.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+}
+
+type Shard = RwLock<HashMap<String, Counter>>;
+
+/// counters that expire, with the semantics of the redis `INCR` (or `SADD` and `SCARD`) and `EXPIRE` commands
+pub struct Counters {
+    hasher: RandomState,
+    shards: Vec<Shard>,
+}
+
+impl Counters {
+    pub fn new(shards: usize) -> Self {
+        Counters {
+            hasher: RandomState::new(),
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// counts a hit on the key, or a new distinct member for the pair limits, returning the count
+    ///
+    /// a new counter expires `timeframe` seconds after it was created
+    pub fn hit(&self, key: &str, member: Option<&str>, timeframe: u64, now: u64) -> u64 {
+        let shard = self.shard(key);
+        if let Ok(counters) = shard.read() {
+            if let Some(counter) = counters.get(key).filter(|c| c.expires > now) {
+                return counter.hit(member);
+            }
+        }
+        let mut counters = match shard.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match counters.get(key).filter(|c| c.expires > now) {
+            // created by another worker while waiting for the lock
+            Some(counter) => counter.hit(member),
+            None => {
+                counters.insert(key.to_string(), Counter::new(now, timeframe, member));
+                1
+            }
+        }
+    }
+
+    /// the count of the key, without counting a hit, zero when it expired
+    pub fn count(&self, key: &str, now: u64) -> u64 {
+        match self.shard(key).read() {
+            Ok(counters) => counters
+                .get(key)
+                .filter(|c| c.expires > now)
+                .map(|c| c.count.load(Ordering::Relaxed))
+                .unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// the key was hit and has not expired
+    pub fn is_set(&self, key: &str, now: u64) -> bool {
+        match self.shard(key).read() {
+            Ok(counters) => counters.get(key).map(|c| c.expires > now).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    /// sets the key for `duration` seconds, as the bans do
+    pub fn set(&self, key: &str, duration: u64, now: u64) {
+        let mut counters = match self.shard(key).write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        counters.insert(key.to_string(), Counter::new(now, duration, None));
+    }
+
+    /// removes the expired counters
+    pub fn sweep(&self, now: u64) {
+        for shard in &self.shards {
+            if let Ok(mut counters) = shard.write() {
+                counters.retain(|_, c| c.expires > now);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().map(|c| c.len()).unwrap_or(0)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn counting() {
+        let counters = Counters::new(4);
+        assert_eq!(counters.hit("a", None, 10, 0), 1);
+        assert_eq!(counters.hit("a", None, 10, 5_000), 2);
+        assert_eq!(counters.hit("b", None, 10, 5_000), 1);
+        assert_eq!(counters.count("a", 5_000), 2);
+        // expired after 10 seconds
+        assert_eq!(counters.hit("a", None, 10, 10_000), 1);
+
+        assert_eq!(counters.hit("p", Some("x"), 10, 0), 1);
+        assert_eq!(counters.hit("p", Some("x"), 10, 0), 1);
+        assert_eq!(counters.hit("p", Some("y"), 10, 0), 2);
+
+        counters.set("ban", 60, 0);
+        assert!(counters.is_set("ban", 59_999));
+        assert!(!counters.is_set("ban", 60_000));
+        assert!(!counters.is_set("other", 0));
+
+        assert_eq!(counters.len(), 4);
+        counters.sweep(15_000);
+        // the first counter of a was replaced at 10s, and ends at 20s
+        assert_eq!(counters.len(), 2);
+        counters.sweep(60_000);
+        assert!(counters.is_empty());
+    }
+
+    #[test]
+    fn concurrent_hits() {
+        let counters = Arc::new(Counters::new(8));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counters = counters.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        counters.hit(&format!("key{}", i % 10), None, 60, 0);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(counters.hit(&format!("key{}", i), None, 60, 0), 401);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use ring::hmac;
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(encoded: &str) -> Option<Vec<u8>> {
+    let chunks = encoded.as_bytes().chunks_exact(2);
+    if !chunks.remainder().is_empty() || !encoded.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    chunks
+        .map(|c| std::str::from_utf8(c).ok().and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+/// the HMAC-SHA256 of the message, in hexadecimal
+pub(crate) fn sign_hex(key: &[u8], msg: &[u8]) -> String {
+    to_hex(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), msg).as_ref())
+}
+
+/// checks an hexadecimal HMAC-SHA256 signature, in constant time
+pub(crate) fn verify_hex(key: &[u8], msg: &[u8], signature: &str) -> bool {
+    match from_hex(signature) {
+        Some(s) => hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, key), msg, &s).is_ok(),
+        None => false,
+    }
+}
+
+/// compares two secrets without leaking their lengths, or the position of the first difference
+#[cfg(feature = "http-server")]
+pub(crate) fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"curiefense");
+    hmac::verify(&key, a, hmac::sign(&key, b).as_ref()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_rfc4231() {
+        let signature = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(sign_hex(b"Jefe", b"what do ya want for nothing?"), signature);
+        assert!(verify_hex(b"Jefe", b"what do ya want for nothing?", signature));
+        assert!(!verify_hex(b"Jefe", b"what do ya want for nothing!", signature));
+        assert!(!verify_hex(b"Jefe", b"what do ya want for nothing?", &signature[..62]));
+        assert!(!verify_hex(b"Jefe", b"what do ya want for nothing?", "zz"));
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(from_hex(&to_hex(&[0, 1, 0xab, 0xff])), Some(vec![0, 1, 0xab, 0xff]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("é1"), None);
+    }
+
+    #[cfg(feature = "http-server")]
+    #[test]
+    fn secrets() {
+        assert!(same_secret(b"token", b"token"));
+        assert!(!same_secret(b"token", b"tokem"));
+        assert!(!same_secret(b"token", b"token2"));
+        assert!(!same_secret(b"", b"token"));
+    }
+}
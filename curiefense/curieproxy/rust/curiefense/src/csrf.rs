@@ -0,0 +1,193 @@
+use crate::clock;
+use crate::config::raw::{CsrfAction, RawCsrfProtection};
+use crate::crypto::{from_hex, to_hex};
+use crate::interface::Action;
+use crate::reason::{Initiator, Reason};
+use crate::utils::RequestInfo;
+use ring::hmac;
+
+/// the outcome of the check of a state changing request, turned into the `csrf:<status>` tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrfStatus {
+    Valid,
+    /// the cookie, or the submitted token, is missing
+    Missing,
+    /// the submitted token is not the one of the cookie
+    Mismatch,
+    /// the token was not issued for this session
+    Invalid,
+}
+
+impl CsrfStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CsrfStatus::Valid => "valid",
+            CsrfStatus::Missing => "missing",
+            CsrfStatus::Mismatch => "mismatch",
+            CsrfStatus::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfResult {
+    /// the outcome of the check, for the protected methods only
+    pub status: Option<CsrfStatus>,
+    /// the `Set-Cookie` value of a new token, when the request did not have a valid one
+    pub set_cookie: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CsrfProtection {
+    key: hmac::Key,
+    cookie: String,
+    header: String,
+    field: Option<String>,
+    methods: Vec<String>,
+    action: CsrfAction,
+}
+
+impl CsrfProtection {
+    pub fn resolve(raw: &RawCsrfProtection) -> Self {
+        CsrfProtection {
+            key: hmac::Key::new(hmac::HMAC_SHA256, raw.secret.as_bytes()),
+            cookie: raw.cookie.clone(),
+            header: raw.header.to_lowercase(),
+            field: raw.field.clone(),
+            methods: raw.methods.iter().map(|m| m.to_uppercase()).collect(),
+            action: raw.action,
+        }
+    }
+
+    fn signed(&self, session: &str, nonce: &str) -> hmac::Tag {
+        hmac::sign(&self.key, format!("{}\n{}", session, nonce).as_bytes())
+    }
+
+    /// a new token for the session
+    pub fn token(&self, session: &str) -> String {
+        let nonce = format!("{:016x}", clock::random::<u64>());
+        let signature = to_hex(self.signed(session, &nonce).as_ref());
+        format!("{}.{}", nonce, signature)
+    }
+
+    /// the token was issued for the session
+    pub fn verify(&self, token: &str, session: &str) -> bool {
+        let (nonce, signature) = match token.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        if signature.len() != 64 {
+            return false;
+        }
+        match from_hex(signature) {
+            Some(s) => hmac::verify(&self.key, format!("{}\n{}", session, nonce).as_bytes(), &s).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn check(&self, reqinfo: &RequestInfo) -> CsrfResult {
+        let cookie = reqinfo.cookies.get_str(&self.cookie).map(|c| c.trim());
+        let cookie_valid = cookie.map(|c| self.verify(c, &reqinfo.session)).unwrap_or(false);
+        let set_cookie = if cookie_valid {
+            None
+        } else {
+            Some(format!(
+                "{}={}; Path=/; Secure; SameSite=Strict",
+                self.cookie,
+                self.token(&reqinfo.session)
+            ))
+        };
+        if !self.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+            return CsrfResult {
+                status: None,
+                set_cookie,
+            };
+        }
+        let submitted = reqinfo
+            .headers
+            .get_str(&self.header)
+            .or_else(|| self.field.as_ref().and_then(|f| reqinfo.rinfo.qinfo.args.get_str(f)));
+        let status = match (cookie, submitted.map(|s| s.trim())) {
+            (Some(c), Some(s)) if c != s => CsrfStatus::Mismatch,
+            (Some(_), Some(_)) if !cookie_valid => CsrfStatus::Invalid,
+            (Some(_), Some(_)) => CsrfStatus::Valid,
+            _ => CsrfStatus::Missing,
+        };
+        CsrfResult {
+            status: Some(status),
+            set_cookie,
+        }
+    }
+
+    /// the blocking action, for the state changing requests without a valid token
+    pub fn action(&self, result: Option<&CsrfResult>) -> Option<Action> {
+        let status = result.and_then(|r| r.status)?;
+        if self.action == CsrfAction::Tag || status == CsrfStatus::Valid {
+            return None;
+        }
+        Some(Action {
+            status: 403,
+            reason: Reason::new(Initiator::Csrf).with_message(format!("csrf token {}", status.as_str())),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    fn protection(action: CsrfAction) -> CsrfProtection {
+        let mut raw: RawCsrfProtection =
+            serde_json::from_str(r#"{"secret": "s3cr3t", "field": "csrf_token"}"#).unwrap();
+        raw.action = action;
+        CsrfProtection::resolve(&raw)
+    }
+
+    fn status(protection: &CsrfProtection, rq: RequestBuilder) -> Option<CsrfStatus> {
+        protection.check(&rq.request_info()).status
+    }
+
+    #[test]
+    fn double_submit() {
+        let csrf = protection(CsrfAction::Block);
+        // the session defaults to the client address
+        let token = csrf.token("1.2.3.4");
+        assert!(csrf.verify(&token, "1.2.3.4"));
+        assert!(!csrf.verify(&token, "5.6.7.8"));
+
+        let get = csrf.check(&RequestBuilder::get("/form").request_info());
+        assert_eq!(get.status, None);
+        assert!(get.set_cookie.unwrap().starts_with("cf_csrf="));
+        let get = csrf.check(&RequestBuilder::get("/form").cookie("cf_csrf", &token).request_info());
+        assert_eq!(get.set_cookie, None);
+
+        let post = || RequestBuilder::post("/form", "a=1").cookie("cf_csrf", &token);
+        assert_eq!(
+            status(&csrf, post().header("x-csrf-token", &token)),
+            Some(CsrfStatus::Valid)
+        );
+        assert_eq!(status(&csrf, post()), Some(CsrfStatus::Missing));
+        assert_eq!(
+            status(&csrf, post().header("x-csrf-token", "other")),
+            Some(CsrfStatus::Mismatch)
+        );
+        let body = RequestBuilder::post("/form", format!("csrf_token={}", token))
+            .header("content-type", "application/x-www-form-urlencoded")
+            .cookie("cf_csrf", &token);
+        assert_eq!(status(&csrf, body), Some(CsrfStatus::Valid));
+
+        // a token of another session, submitted twice
+        let foreign = csrf.token("5.6.7.8");
+        let rq = RequestBuilder::post("/form", "")
+            .cookie("cf_csrf", &foreign)
+            .header("x-csrf-token", &foreign);
+        let result = csrf.check(&rq.request_info());
+        assert_eq!(result.status, Some(CsrfStatus::Invalid));
+        assert!(result.set_cookie.is_some());
+        let action = csrf.action(Some(&result)).unwrap();
+        assert_eq!((action.status, action.reason.initiator), (403, Initiator::Csrf));
+        assert!(protection(CsrfAction::Tag).action(Some(&result)).is_none());
+    }
+}
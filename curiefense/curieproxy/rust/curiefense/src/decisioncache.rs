@@ -0,0 +1,177 @@
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::Config;
+use crate::crypto::to_hex;
+use crate::interface::Decision;
+use crate::utils::{RawRequest, RequestInfo};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// the headers that are different for every request, and do not change its decision
+pub const VOLATILE_HEADERS: [&str; 10] = [
+    "x-request-id",
+    "traceparent",
+    "tracestate",
+    "x-b3-traceid",
+    "x-b3-spanid",
+    "x-b3-parentspanid",
+    "x-b3-sampled",
+    "x-amzn-trace-id",
+    "x-envoy-attempt-count",
+    "x-envoy-expected-rq-timeout-ms",
+];
+
+lazy_static! {
+    static ref TTL_SECS: u64 = std::env::var("CURIEFENSE_DECISION_CACHE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+}
+
+/// the cacheability hint of a decision
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CacheHint {
+    pub key: String,
+    /// in seconds
+    pub ttl: u64,
+}
+
+/// true when the inspection of the requests of the entry updates no state
+pub fn cacheable_policy(securitypolicy: &SecurityPolicy) -> bool {
+    securitypolicy.limits.is_empty()
+        && securitypolicy.ban_on_decision.is_empty()
+        && securitypolicy.honeypot.is_none()
+        && securitypolicy.risk.is_none()
+        && securitypolicy.campaigns.is_none()
+        && securitypolicy.harvesting.is_none()
+        && securitypolicy.login.is_none()
+        && securitypolicy.csrf.is_none()
+        && securitypolicy
+            .experiment
+            .as_ref()
+            .map(|e| cacheable_policy(&e.variant))
+            .unwrap_or(true)
+}
+
+/// the cache key of the request, `None` when its decision can't be cached
+pub fn cache_key(cfg: &Config, secpolname: &str, securitypolicy: &SecurityPolicy, raw: &RawRequest) -> Option<String> {
+    let method = raw.meta.method.to_uppercase();
+    if *TTL_SECS == 0
+        || (method != "GET" && method != "HEAD")
+        || raw.mbody.map(|b| !b.is_empty()).unwrap_or(false)
+        || !cfg.flows.is_empty()
+        || !cacheable_policy(securitypolicy)
+    {
+        return None;
+    }
+    let mut headers: Vec<(&String, &String)> = raw
+        .headers
+        .iter()
+        .filter(|(k, _)| !VOLATILE_HEADERS.contains(&k.as_str()))
+        .collect();
+    headers.sort();
+    let mut hasher = Sha256::new();
+    let meta = &raw.meta;
+    let parts: [&str; 12] = [
+        cfg.revision_hash.as_str(),
+        secpolname,
+        &securitypolicy.name,
+        &method,
+        &raw.get_host(),
+        &meta.path,
+        meta.scheme.as_deref().unwrap_or_default(),
+        &meta.port.map(|p| p.to_string()).unwrap_or_default(),
+        meta.http_version.as_deref().unwrap_or_default(),
+        &raw.ipstr,
+        &format!("{:?}", meta.tls),
+        &format!("{:?}", meta.client_cert),
+    ];
+    for part in parts.iter() {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+    for (name, value) in headers {
+        hasher.update(format!("{}\0{}\n", name, value).as_bytes());
+    }
+    Some(to_hex(&hasher.finalize()))
+}
+
+/// the hint of a decision, when its request has a key and was passed
+pub fn hint(decision: &Decision, rinfo: &RequestInfo) -> Option<CacheHint> {
+    match decision {
+        Decision::Pass => rinfo.cache_key.as_ref().map(|key| CacheHint {
+            key: key.clone(),
+            ttl: *TTL_SECS,
+        }),
+        Decision::Action(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::logs::Logs;
+    use crate::reason::Initiator;
+    use crate::test_utils::{inspect, AclBuilder, ConfigBuilder, PolicyBuilder, RequestBuilder};
+
+    #[test]
+    fn keys() {
+        let cfg = ConfigBuilder::new().build();
+        let policy = PolicyBuilder::new("default").build();
+        let key = |rq: RequestBuilder| cache_key(&cfg, "default", &policy, &rq.raw());
+        let base = key(RequestBuilder::get("/a?b=c").header("user-agent", "curl"));
+        assert!(base.is_some());
+        assert_eq!(
+            key(RequestBuilder::get("/a?b=c")
+                .header("user-agent", "curl")
+                .header("x-request-id", "1234")),
+            base
+        );
+        assert_ne!(key(RequestBuilder::get("/a?b=d").header("user-agent", "curl")), base);
+        assert_ne!(
+            key(RequestBuilder::get("/a?b=c").header("user-agent", "curl").ip("5.6.7.8")),
+            base
+        );
+        assert_ne!(key(RequestBuilder::get("/a?b=c").header("user-agent", "wget")), base);
+        assert_eq!(key(RequestBuilder::post("/a", "x=1")), None);
+
+        let mut banning = PolicyBuilder::new("banning").build();
+        banning.ban_on_decision.insert(Initiator::Acl, 60);
+        assert_eq!(
+            cache_key(&cfg, "default", &banning, &RequestBuilder::get("/").raw()),
+            None
+        );
+    }
+
+    #[test]
+    fn hints() {
+        let snapshot = ConfigBuilder::new()
+            .path(
+                "^/admin",
+                PolicyBuilder::new("admin")
+                    .acl(AclBuilder::new("admin").deny(&["all"]).build())
+                    .build(),
+            )
+            .snapshot();
+        let mut logs = Logs::default();
+        let (decision, _, rinfo) = inspect(
+            &snapshot,
+            None::<DummyGrasshopper>,
+            &RequestBuilder::get("/").raw(),
+            &mut logs,
+        );
+        let cached = hint(&decision, &rinfo).unwrap();
+        assert_eq!(Some(cached.key), rinfo.cache_key);
+        assert_eq!(cached.ttl, 5);
+
+        let (decision, _, rinfo) = inspect(
+            &snapshot,
+            None::<DummyGrasshopper>,
+            &RequestBuilder::get("/admin").raw(),
+            &mut logs,
+        );
+        assert!(rinfo.cache_key.is_some());
+        assert_eq!(hint(&decision, &rinfo), None);
+    }
+}
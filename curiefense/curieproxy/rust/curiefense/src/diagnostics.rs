@@ -0,0 +1,123 @@
+use crate::logs::{LogLevel, Logs};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::Registry;
+use tracing_subscriber::reload;
+
+/// the directives of the integrations that don't set them
+pub const DEFAULT_DIRECTIVES: &str = "warn";
+
+/// the number of events that are kept in memory
+const RECENT_EVENTS: usize = 1000;
+
+lazy_static! {
+    static ref RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(RECENT_EVENTS));
+    static ref FILTER: Mutex<Option<reload::Handle<Targets, Registry>>> = Mutex::new(None);
+}
+
+/// formats the message, and the other fields, of an event
+struct EventFormatter(String);
+
+impl Visit for EventFormatter {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = if field.name() == "message" {
+            write!(self.0, " {:?}", value)
+        } else {
+            write!(self.0, " {}={:?}", field.name(), value)
+        };
+    }
+}
+
+/// writes the events on stderr, and keeps the most recent ones
+struct RecentEvents;
+
+impl<S: Subscriber> Layer<S> for RecentEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let mut formatter = EventFormatter(format!("{:.3} {} {}:", now, metadata.level(), metadata.target()));
+        event.record(&mut formatter);
+        eprintln!("{}", formatter.0);
+        if let Ok(mut recent) = RECENT.lock() {
+            if recent.len() >= RECENT_EVENTS {
+                recent.pop_front();
+            }
+            recent.push_back(formatter.0);
+        }
+    }
+}
+
+fn parse_directives(directives: &str) -> Result<Targets, String> {
+    directives
+        .parse()
+        .map_err(|rr| format!("invalid log directives {}: {}", directives, rr))
+}
+
+/// installs the subscriber, unless it is already installed
+pub fn init(directives: &str) -> Result<(), String> {
+    let mut handle = FILTER.lock().map_err(|rr| rr.to_string())?;
+    if handle.is_some() {
+        return Ok(());
+    }
+    let (filter, new_handle) = reload::Layer::new(parse_directives(directives)?);
+    tracing::subscriber::set_global_default(Registry::default().with(filter).with(RecentEvents))
+        .map_err(|rr| rr.to_string())?;
+    *handle = Some(new_handle);
+    Ok(())
+}
+
+/// changes the levels, installing the subscriber if needed
+pub fn set_levels(directives: &str) -> Result<(), String> {
+    let targets = parse_directives(directives)?;
+    let installed = FILTER.lock().map_err(|rr| rr.to_string())?.clone();
+    match installed {
+        Some(handle) => handle.reload(targets).map_err(|rr| rr.to_string()),
+        None => init(directives),
+    }
+}
+
+/// the most recent events, oldest first
+pub fn recent_events() -> Vec<String> {
+    RECENT.lock().map(|r| r.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// emits the logs of an inspection, with the `curiefense::request` target
+pub fn emit_request_logs(logs: &Logs) {
+    for log in &logs.logs {
+        match log.level {
+            LogLevel::Debug => tracing::debug!(target: "curiefense::request", "{}", log),
+            LogLevel::Info => tracing::info!(target: "curiefense::request", "{}", log),
+            LogLevel::Warning => tracing::warn!(target: "curiefense::request", "{}", log),
+            LogLevel::Error => tracing::error!(target: "curiefense::request", "{}", log),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_levels() {
+        assert!(set_levels("nope=nope").is_err());
+        set_levels("warn,curiefense::diagnostics=debug").unwrap();
+        tracing::debug!(answer = 42, "recorded");
+        set_levels("warn").unwrap();
+        tracing::debug!("dropped");
+        let recent = recent_events();
+        assert!(recent
+            .iter()
+            .any(|e| e.ends_with("DEBUG curiefense::diagnostics::tests: recorded answer=42")));
+        assert!(!recent.iter().any(|e| e.contains("dropped")));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::backend;
+use crate::cache::Cache;
+use crate::interface::Tags;
+use crate::logs::Logs;
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// the cache is emptied when it still reaches this size once its expired entries are purged
+const MAX_CACHED: usize = 100_000;
+
+lazy_static! {
+    pub static ref DNSBL: Option<Dnsbl> = Dnsbl::from_env();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    /// the name used in the tags
+    pub name: String,
+    pub zone: String,
+}
+
+/// parses a list of `name=zone` pairs
+pub fn parse_zones(spec: &str) -> Result<Vec<Zone>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split_once('=') {
+            Some((name, zone)) if !name.trim().is_empty() && !zone.trim().is_empty() => Ok(Zone {
+                name: name.trim().to_string(),
+                zone: zone.trim().trim_end_matches('.').to_string(),
+            }),
+            _ => Err(format!("invalid DNSBL zone {}, expected name=zone", s)),
+        })
+        .collect()
+}
+
+/// the name to resolve: the reversed octets, or nibbles for IPv6, followed by the zone
+pub fn query_name(ip: IpAddr, zone: &str) -> String {
+    let mut labels: Vec<String> = match ip {
+        IpAddr::V4(a) => a.octets().iter().map(|o| o.to_string()).collect(),
+        IpAddr::V6(a) => a
+            .octets()
+            .iter()
+            .flat_map(|o| vec![format!("{:x}", o >> 4), format!("{:x}", o & 0xf)])
+            .collect(),
+    };
+    labels.reverse();
+    labels.push(zone.to_string());
+    labels.join(".")
+}
+
+/// listed addresses resolve to 127.0.0.0/8, the 127.255.255.0/24 answers being errors (such as a refused query)
+pub fn is_listed(answers: &[IpAddr]) -> bool {
+    answers.iter().any(|a| match a {
+        IpAddr::V4(a) => a.octets()[0] == 127 && a.octets()[..3] != [127, 255, 255],
+        IpAddr::V6(_) => false,
+    })
+}
+
+pub struct Dnsbl {
+    pub zones: Vec<Zone>,
+    timeout: Duration,
+    /// the answers, by zone index and address
+    cache: Cache<(usize, IpAddr), bool>,
+}
+
+impl Dnsbl {
+    pub fn new(zones: Vec<Zone>, timeout: Duration, ttl: Duration) -> Self {
+        Dnsbl {
+            zones,
+            timeout,
+            cache: Cache::ttl_only(ttl, MAX_CACHED),
+        }
+    }
+
+    fn from_env() -> Option<Self> {
+        let spec = std::env::var("CURIEFENSE_DNSBL_ZONES").ok().filter(|s| !s.is_empty())?;
+        match parse_zones(&spec) {
+            Ok(zones) if !zones.is_empty() => Some(Dnsbl::new(
+                zones,
+                Duration::from_millis(env_or("CURIEFENSE_DNSBL_TIMEOUT_MS", 200)),
+                Duration::from_secs(env_or("CURIEFENSE_DNSBL_CACHE_SECS", 300)),
+            )),
+            Ok(_) => None,
+            Err(rr) => {
+                tracing::error!(target: "curiefense::dnsbl", "{}", rr);
+                None
+            }
+        }
+    }
+
+    fn cached(&self, zone: usize, ip: IpAddr, now: Instant) -> Option<bool> {
+        self.cache.get(&(zone, ip), now)
+    }
+
+    fn store(&self, zone: usize, ip: IpAddr, listed: bool, now: Instant) {
+        self.cache.insert((zone, ip), listed, now);
+    }
+
+    async fn query(&self, logs: &mut Logs, zone: usize, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if let Some(listed) = self.cached(zone, ip, now) {
+            return listed;
+        }
+        let name = query_name(ip, &self.zones[zone].zone);
+        let qname = name.clone();
+        let lookup = async move {
+            async_std::net::ToSocketAddrs::to_socket_addrs(&(qname.as_str(), 0))
+                .await
+                .map(|addrs| addrs.map(|a| a.ip()).collect::<Vec<_>>())
+        };
+        let listed = match backend::call(self.timeout, lookup).await {
+            Ok(Ok(addrs)) => is_listed(&addrs),
+            // NXDOMAIN, the address is not listed
+            Ok(Err(_)) => false,
+            Err(rr) => {
+                logs.warning(|| format!("DNSBL lookup of {} failed: {}", name, rr));
+                false
+            }
+        };
+        self.store(zone, ip, listed, now);
+        listed
+    }
+
+    /// the names of the zones that list this address
+    pub async fn listed(&self, logs: &mut Logs, ip: IpAddr) -> Vec<&str> {
+        let queries = (0..self.zones.len()).map(|zone| {
+            let mut zlogs = Logs::new(logs.level);
+            async move { (self.query(&mut zlogs, zone, ip).await, zlogs) }
+        });
+        let mut out = Vec::new();
+        for ((listed, zlogs), zone) in futures::future::join_all(queries).await.into_iter().zip(&self.zones) {
+            logs.extend(zlogs);
+            if listed {
+                out.push(zone.name.as_str());
+            }
+        }
+        out
+    }
+}
+
+/// adds the tags of the configured zones that list the address
+pub async fn tag(logs: &mut Logs, tags: &mut Tags, ip: Option<IpAddr>) {
+    if let (Some(dnsbl), Some(ip)) = (DNSBL.as_ref(), ip) {
+        for name in dnsbl.listed(logs, ip).await {
+            tags.insert_qualified("dnsbl", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones_and_queries() {
+        assert_eq!(
+            parse_zones("spamhaus-xbl=xbl.spamhaus.org., spamcop = bl.spamcop.net"),
+            Ok(vec![
+                Zone {
+                    name: "spamhaus-xbl".to_string(),
+                    zone: "xbl.spamhaus.org".to_string()
+                },
+                Zone {
+                    name: "spamcop".to_string(),
+                    zone: "bl.spamcop.net".to_string()
+                }
+            ])
+        );
+        assert!(parse_zones("xbl.spamhaus.org").is_err());
+
+        assert_eq!(
+            query_name("1.2.3.4".parse().unwrap(), "xbl.spamhaus.org"),
+            "4.3.2.1.xbl.spamhaus.org"
+        );
+        assert_eq!(
+            query_name("2001:db8::1".parse().unwrap(), "zone"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.zone"
+        );
+
+        assert!(is_listed(&["127.0.0.4".parse().unwrap()]));
+        assert!(!is_listed(&["127.255.255.254".parse().unwrap()]));
+        assert!(!is_listed(&["10.0.0.1".parse().unwrap()]));
+        assert!(!is_listed(&[]));
+    }
+
+    #[test]
+    fn cache_expiry() {
+        let dnsbl = Dnsbl::new(
+            parse_zones("a=a.example").unwrap(),
+            Duration::from_millis(10),
+            Duration::from_secs(60),
+        );
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+        assert_eq!(dnsbl.cached(0, ip, now), None);
+        dnsbl.store(0, ip, true, now);
+        assert_eq!(dnsbl.cached(0, ip, now + Duration::from_secs(59)), Some(true));
+        assert_eq!(dnsbl.cached(0, ip, now + Duration::from_secs(60)), None);
+    }
+}
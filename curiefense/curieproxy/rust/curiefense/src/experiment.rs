@@ -0,0 +1,154 @@
+use crate::config::contentfilter::ContentFilterProfile;
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::limit::Limit;
+use crate::config::raw::{AclProfile, RawExperiment, RiskIdentity};
+use crate::interface::Tags;
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    pub name: String,
+    /// in hundredths of percent
+    share: u64,
+    identity: RiskIdentity,
+    pub variant: SecurityPolicy,
+}
+
+/// the bucket of a client, between 0 and 9999
+pub fn bucket(experiment: &str, identity: &str) -> u64 {
+    let digest = md5::compute(format!("{}\n{}", experiment, identity));
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest.0[..8]);
+    u64::from_be_bytes(bytes) % 10_000
+}
+
+impl Experiment {
+    pub fn new(name: &str, percent: f64, identity: RiskIdentity, variant: SecurityPolicy) -> Self {
+        Experiment {
+            name: name.to_string(),
+            share: (percent.clamp(0.0, 100.0) * 100.0).round() as u64,
+            identity,
+            variant,
+        }
+    }
+
+    /// the variant of the entry, its profiles being replaced with the ones of the experiment
+    pub fn resolve(
+        logs: &mut Logs,
+        base: &SecurityPolicy,
+        raw: &RawExperiment,
+        limits: &HashMap<String, Limit>,
+        acls: &HashMap<String, AclProfile>,
+        contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
+    ) -> Self {
+        let mut variant = base.clone();
+        if let Some(id) = &raw.acl_profile {
+            match acls.get(id) {
+                Some(p) => variant.acl_profile = p.clone(),
+                None => logs.warning(|| format!("Unknown ACL profile {} in experiment {}", id, raw.name)),
+            }
+        }
+        if let Some(id) = &raw.content_filter_profile {
+            match contentfilterprofiles.get(id) {
+                Some(p) => variant.content_filter_profile = p.clone(),
+                None => logs.warning(|| format!("Unknown Content Filter profile {} in experiment {}", id, raw.name)),
+            }
+        }
+        if let Some(ids) = &raw.limit_ids {
+            variant.limits = ids
+                .iter()
+                .filter_map(|id| {
+                    let limit = limits.get(id).cloned();
+                    if limit.is_none() {
+                        logs.warning(|| format!("Unknown limit {} in experiment {}", id, raw.name));
+                    }
+                    limit
+                })
+                .collect();
+        }
+        Experiment::new(&raw.name, raw.percent, raw.identity, variant)
+    }
+
+    /// true when the client of the request gets the variant, the clients without identity being in the control group
+    pub fn selects(&self, reqinfo: &RequestInfo) -> bool {
+        let identity = match self.identity {
+            RiskIdentity::Session => &reqinfo.session,
+            RiskIdentity::Ip => &reqinfo.rinfo.geoip.ipstr,
+            RiskIdentity::Fingerprint => &reqinfo.fingerprint,
+        };
+        !identity.is_empty() && bucket(&self.name, identity) < self.share
+    }
+
+    /// the security policy of the request, the entry or its variant, tagging the request with its group
+    pub fn select<'a>(
+        &'a self,
+        logs: &mut Logs,
+        base: &'a SecurityPolicy,
+        reqinfo: &RequestInfo,
+        tags: &mut Tags,
+    ) -> &'a SecurityPolicy {
+        tags.insert_qualified("experiment", &self.name);
+        if self.selects(reqinfo) {
+            logs.debug(|| format!("experiment {}: variant", self.name));
+            tags.insert_qualified("experiment-group", "variant");
+            &self.variant
+        } else {
+            tags.insert_qualified("experiment-group", "control");
+            base
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::interface::Decision;
+    use crate::test_utils::{inspect, AclBuilder, ConfigBuilder, PolicyBuilder, RequestBuilder};
+    use std::sync::Arc;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket("canary", "1.2.3.4"), bucket("canary", "1.2.3.4"));
+        let selected = (0..1000)
+            .filter(|i| bucket("canary", &format!("10.0.{}.{}", i / 256, i % 256)) < 2000)
+            .count();
+        assert!(
+            (150..250).contains(&selected),
+            "{} clients out of 1000 selected",
+            selected
+        );
+    }
+
+    #[test]
+    fn variants() {
+        let strict = PolicyBuilder::new("default")
+            .acl(AclBuilder::new("strict").deny(&["all"]).build())
+            .build();
+        let mut policy = PolicyBuilder::new("default").build();
+        policy.experiment = Some(Arc::new(Experiment::new("strict-acl", 50.0, RiskIdentity::Ip, strict)));
+        let snapshot = ConfigBuilder::new().default_policy(Some(policy)).snapshot();
+
+        let mut variant = 0;
+        for i in 0..100 {
+            let rq = RequestBuilder::get("/").ip(&format!("10.0.0.{}", i));
+            let (decision, tags, _) = inspect(&snapshot, None::<DummyGrasshopper>, &rq.raw(), &mut Logs::default());
+            assert!(tags.contains("experiment:strict-acl"));
+            if tags.contains("experiment-group:variant") {
+                variant += 1;
+                assert!(matches!(decision, Decision::Action(_)));
+                assert!(tags.contains("aclid:strict"));
+            } else {
+                assert!(tags.contains("experiment-group:control"));
+                assert!(matches!(decision, Decision::Pass));
+            }
+        }
+        assert!(
+            (30..70).contains(&variant),
+            "{} clients out of 100 in the variant",
+            variant
+        );
+    }
+}
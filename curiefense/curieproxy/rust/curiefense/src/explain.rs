@@ -0,0 +1,63 @@
+use crate::challenge::now;
+use crate::config::hostmap::SecurityPolicy;
+use crate::crypto::{sign_hex, verify_hex};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const EXPLAIN_HEADER: &str = "x-curiefense-explain";
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExplainStep {
+    pub elapsed_micros: u64,
+    /// `tagging`, `limit`, `acl` or `content_filter`
+    pub phase: &'static str,
+    /// the name of what was evaluated
+    pub name: String,
+    pub matched: bool,
+    pub details: serde_json::Value,
+}
+
+/// the value of the explain header, valid until `expiry`
+pub fn sign_header(secret: &str, expiry: u64) -> String {
+    let expiry = expiry.to_string();
+    let signature = sign_hex(secret.as_bytes(), ["explain", &expiry].join("|").as_bytes());
+    format!("{}.{}", expiry, signature)
+}
+
+fn check_header(secret: &str, value: &str, now: u64) -> bool {
+    match value.split_once('.') {
+        None => false,
+        Some((expiry, signature)) => match expiry.parse::<u64>() {
+            Ok(e) => e >= now && verify_hex(secret.as_bytes(), ["explain", expiry].join("|").as_bytes(), signature),
+            Err(_) => false,
+        },
+    }
+}
+
+/// should the inspection of this request be explained
+pub fn explain_enabled(policy: &SecurityPolicy, headers: &HashMap<String, String>) -> bool {
+    if policy.explain {
+        return true;
+    }
+    match (&policy.explain_secret, headers.get(EXPLAIN_HEADER)) {
+        (Some(secret), Some(value)) => check_header(secret.as_str(), value, now()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_header() {
+        let header = sign_header("secret", 1000);
+        assert!(header.starts_with("1000."));
+        assert!(check_header("secret", &header, 999));
+        assert!(check_header("secret", &header, 1000));
+        assert!(!check_header("secret", &header, 1001));
+        assert!(!check_header("other", &header, 999));
+        assert!(!check_header("secret", &header.replace("1000.", "2000."), 999));
+        assert!(!check_header("secret", "1000", 999));
+    }
+}
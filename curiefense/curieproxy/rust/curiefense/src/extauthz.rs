@@ -0,0 +1,583 @@
+use crate::diagnostics::emit_request_logs;
+use crate::grasshopper::DummyGrasshopper;
+use crate::inspect_generic_request_map;
+use crate::interface::{Action, Decision, RequestChanges};
+use crate::logs::{LogLevel, Logs};
+use crate::metadata::DynamicMetadata;
+use crate::metrics::record_inspection;
+use crate::requestmap::Geo;
+use crate::shipper::ship;
+use crate::utils::{RawRequest, RequestMeta};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+
+/// the subset of the Envoy protobuf messages that is used by the server
+///
+/// field numbers come from `envoy/service/auth/v3/external_auth.proto` and its dependencies. Oneof members are
+/// represented as optional fields, which is compatible on the wire.
+pub mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CheckRequest {
+        #[prost(message, optional, tag = "1")]
+        pub attributes: Option<AttributeContext>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AttributeContext {
+        #[prost(message, optional, tag = "1")]
+        pub source: Option<Peer>,
+        #[prost(message, optional, tag = "2")]
+        pub destination: Option<Peer>,
+        #[prost(message, optional, tag = "4")]
+        pub request: Option<Request>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Peer {
+        #[prost(message, optional, tag = "1")]
+        pub address: Option<Address>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Address {
+        #[prost(message, optional, tag = "1")]
+        pub socket_address: Option<SocketAddress>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SocketAddress {
+        #[prost(string, tag = "2")]
+        pub address: String,
+        #[prost(uint32, tag = "3")]
+        pub port_value: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Request {
+        #[prost(message, optional, tag = "2")]
+        pub http: Option<HttpRequest>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub method: String,
+        #[prost(map = "string, string", tag = "3")]
+        pub headers: HashMap<String, String>,
+        /// the request target, including the query string
+        #[prost(string, tag = "4")]
+        pub path: String,
+        #[prost(string, tag = "5")]
+        pub host: String,
+        #[prost(string, tag = "6")]
+        pub scheme: String,
+        #[prost(string, tag = "10")]
+        pub protocol: String,
+        #[prost(string, tag = "11")]
+        pub body: String,
+        /// set instead of `body` when the filter is configured with `pack_as_bytes`
+        #[prost(bytes = "vec", tag = "12")]
+        pub raw_body: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CheckResponse {
+        #[prost(message, optional, tag = "1")]
+        pub status: Option<RpcStatus>,
+        #[prost(oneof = "HttpResponse", tags = "2, 3")]
+        pub http_response: Option<HttpResponse>,
+        #[prost(message, optional, tag = "4")]
+        pub dynamic_metadata: Option<prost_types::Struct>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum HttpResponse {
+        #[prost(message, tag = "2")]
+        DeniedResponse(DeniedHttpResponse),
+        #[prost(message, tag = "3")]
+        OkResponse(OkHttpResponse),
+    }
+
+    /// `google.rpc.Status`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RpcStatus {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeniedHttpResponse {
+        #[prost(message, optional, tag = "1")]
+        pub status: Option<HttpStatus>,
+        #[prost(message, repeated, tag = "2")]
+        pub headers: Vec<HeaderValueOption>,
+        #[prost(string, tag = "3")]
+        pub body: String,
+    }
+
+    /// `envoy.type.v3.HttpStatus`, whose code is restricted to the values of the `StatusCode` enum
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpStatus {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OkHttpResponse {
+        /// headers added toward the upstream
+        #[prost(message, repeated, tag = "2")]
+        pub headers: Vec<HeaderValueOption>,
+        #[prost(string, repeated, tag = "5")]
+        pub headers_to_remove: Vec<String>,
+        #[prost(string, repeated, tag = "8")]
+        pub query_parameters_to_remove: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeaderValueOption {
+        #[prost(message, optional, tag = "1")]
+        pub header: Option<HeaderValue>,
+        #[prost(message, optional, tag = "2")]
+        pub append: Option<bool>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeaderValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+        /// set instead of `value` by the recent versions of Envoy
+        #[prost(bytes = "vec", tag = "3")]
+        pub raw_value: Vec<u8>,
+    }
+}
+
+use proto::*;
+
+pub const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+/// gRPC status codes
+const GRPC_OK: i32 = 0;
+const GRPC_PERMISSION_DENIED: i32 = 7;
+const GRPC_INVALID_ARGUMENT: i32 = 3;
+const GRPC_UNIMPLEMENTED: &str = "12";
+
+/// the statuses accepted by Envoy, other statuses being replaced with 200 (success) or 403 (errors)
+const ENVOY_STATUSES: &[u32] = &[
+    100, 200, 201, 202, 203, 204, 205, 206, 207, 208, 226, 300, 301, 302, 303, 304, 305, 307, 308, 400, 401, 402, 403,
+    404, 405, 406, 407, 408, 409, 410, 411, 412, 413, 414, 415, 416, 417, 421, 422, 423, 424, 426, 428, 429, 431, 500,
+    501, 502, 503, 504, 505, 506, 507, 508, 510, 511,
+];
+
+pub(crate) fn envoy_status(status: u32) -> i32 {
+    if ENVOY_STATUSES.contains(&status) {
+        status as i32
+    } else if status < 400 {
+        200
+    } else {
+        403
+    }
+}
+
+/// the parts of a `RawRequest`, that can't be built without borrowing the body
+pub struct CheckedRequest {
+    pub ip: String,
+    pub headers: HashMap<String, String>,
+    pub meta: RequestMeta,
+    pub body: Vec<u8>,
+}
+
+impl CheckedRequest {
+    pub fn new(request: CheckRequest) -> Result<Self, &'static str> {
+        let attributes = request.attributes.unwrap_or_default();
+        let http = attributes
+            .request
+            .and_then(|r| r.http)
+            .ok_or("missing http request attributes")?;
+        let socket_address = |peer: Option<Peer>| peer.and_then(|p| p.address).and_then(|a| a.socket_address);
+        let source = socket_address(attributes.source);
+        let destination = socket_address(attributes.destination);
+
+        let mut meta: HashMap<String, String> = HashMap::new();
+        meta.insert("method".to_string(), http.method);
+        meta.insert("path".to_string(), http.path);
+        let optional = [
+            ("authority", http.host),
+            ("scheme", http.scheme),
+            ("http_version", http.protocol),
+        ];
+        for (k, v) in optional.iter() {
+            if !v.is_empty() {
+                meta.insert(k.to_string(), v.clone());
+            }
+        }
+        if let Some(d) = destination.filter(|d| d.port_value != 0) {
+            meta.insert("port".to_string(), d.port_value.to_string());
+        }
+        // envoy flags the bodies that were cut at `max_request_bytes`
+        if http.headers.get("x-envoy-auth-partial-body").map(|s| s.as_str()) == Some("true") {
+            meta.insert("body_truncated".to_string(), "true".to_string());
+        }
+        let meta = RequestMeta::from_map(meta)?;
+        let headers = http.headers.into_iter().filter(|(k, _)| !k.starts_with(':')).collect();
+        let body = if http.raw_body.is_empty() {
+            http.body.into_bytes()
+        } else {
+            http.raw_body
+        };
+        Ok(CheckedRequest {
+            ip: meta.client_ip(source.map(|s| s.address).unwrap_or_default()),
+            headers,
+            meta,
+            body,
+        })
+    }
+
+    pub fn raw(&self) -> RawRequest<'_> {
+        RawRequest {
+            ipstr: self.ip.clone(),
+            headers: self.headers.clone(),
+            header_bytes: HashMap::new(),
+            meta: self.meta.clone(),
+            mbody: if self.body.is_empty() { None } else { Some(&self.body) },
+        }
+    }
+}
+
+pub(crate) fn header_option(key: &str, value: &str) -> HeaderValueOption {
+    HeaderValueOption {
+        header: Some(HeaderValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            raw_value: Vec::new(),
+        }),
+        append: Some(false),
+    }
+}
+
+/// the changes to apply to a request that is passed, `headers` being the request headers
+pub(crate) fn ok_response(action: Option<&Action>, headers: &HashMap<String, String>) -> OkHttpResponse {
+    let changes = RequestChanges::new(action, headers);
+    OkHttpResponse {
+        headers: changes.set_headers.iter().map(|(k, v)| header_option(k, v)).collect(),
+        headers_to_remove: changes.remove_headers,
+        query_parameters_to_remove: changes.removed_arguments,
+    }
+}
+
+fn proto_value(value: serde_json::Value) -> prost_types::Value {
+    use prost_types::value::Kind;
+    use serde_json::Value;
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(b) => Kind::BoolValue(b),
+        Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        Value::String(s) => Kind::StringValue(s),
+        Value::Array(a) => Kind::ListValue(prost_types::ListValue {
+            values: a.into_iter().map(proto_value).collect(),
+        }),
+        Value::Object(o) => Kind::StructValue(prost_types::Struct {
+            fields: o.into_iter().map(|(k, v)| (k, proto_value(v))).collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+pub(crate) fn proto_struct(value: serde_json::Value) -> Option<prost_types::Struct> {
+    match proto_value(value).kind {
+        Some(prost_types::value::Kind::StructValue(s)) => Some(s),
+        _ => None,
+    }
+}
+
+pub(crate) fn dynamic_metadata(metadata: &DynamicMetadata) -> Option<prost_types::Struct> {
+    serde_json::to_value(metadata).ok().and_then(proto_struct)
+}
+
+/// converts the decision, `headers` being the request headers
+pub fn check_response(
+    decision: &Decision,
+    metadata: &DynamicMetadata,
+    headers: &HashMap<String, String>,
+) -> CheckResponse {
+    let dynamic_metadata = dynamic_metadata(metadata);
+    let action = match decision {
+        Decision::Pass => None,
+        Decision::Action(a) => Some(a),
+    };
+    match action {
+        Some(a) if a.block_mode && a.atype.is_blocking() => CheckResponse {
+            status: Some(RpcStatus {
+                code: GRPC_PERMISSION_DENIED,
+                message: a.reason.initiator.to_string(),
+            }),
+            http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+                status: Some(HttpStatus {
+                    code: envoy_status(a.status),
+                }),
+                headers: a
+                    .headers
+                    .iter()
+                    .flatten()
+                    .map(|(k, v)| header_option(&k.to_lowercase(), v))
+                    .collect(),
+                body: a.content.clone(),
+            })),
+            dynamic_metadata,
+        },
+        _ => CheckResponse {
+            status: Some(RpcStatus {
+                code: GRPC_OK,
+                message: String::new(),
+            }),
+            http_response: Some(HttpResponse::OkResponse(ok_response(action, headers))),
+            dynamic_metadata,
+        },
+    }
+}
+
+/// the inspection, run on a blocking thread by the server
+pub fn check(configpath: &str, loglevel: LogLevel, request: CheckRequest) -> CheckResponse {
+    let checked = match CheckedRequest::new(request) {
+        Ok(c) => c,
+        Err(rr) => {
+            tracing::warn!("invalid check request: {}", rr);
+            return CheckResponse {
+                status: Some(RpcStatus {
+                    code: GRPC_INVALID_ARGUMENT,
+                    message: rr.to_string(),
+                }),
+                http_response: None,
+                dynamic_metadata: None,
+            };
+        }
+    };
+    let (decision, metadata) = inspect(configpath, loglevel, checked.raw());
+    check_response(&decision, &metadata, &checked.headers)
+}
+
+/// runs the inspection, emitting the logs (see the `diagnostics` module), and returns the decision with its dynamic
+/// metadata
+pub(crate) fn inspect(configpath: &str, loglevel: LogLevel, raw: RawRequest) -> (Decision, DynamicMetadata) {
+    let mut logs = Logs::new(loglevel);
+    let (decision, tags, rinfo) = inspect_generic_request_map(configpath, None::<DummyGrasshopper>, raw, &mut logs);
+    emit_request_logs(&logs);
+    record_inspection(&decision, &rinfo.request_id, &logs);
+    ship(&decision, &tags, &rinfo, &logs);
+    let metadata = DynamicMetadata::new(&decision, &tags)
+        .with_request_id(&rinfo.request_id)
+        .with_geo(&Geo::new(&rinfo.rinfo.geoip))
+        .with_set_cookie(rinfo.csrf.as_ref().and_then(|c| c.set_cookie.clone()));
+    (decision, metadata)
+}
+
+/// the `Authorization` gRPC service, to be added to a `tonic::transport::Server`
+#[derive(Debug, Clone)]
+pub struct AuthorizationServer {
+    configpath: Arc<String>,
+    loglevel: LogLevel,
+}
+
+impl AuthorizationServer {
+    pub fn new(configpath: String, loglevel: LogLevel) -> Self {
+        AuthorizationServer {
+            configpath: Arc::new(configpath),
+            loglevel,
+        }
+    }
+}
+
+struct CheckService(AuthorizationServer);
+
+impl tonic::server::UnaryService<CheckRequest> for CheckService {
+    type Response = CheckResponse;
+    type Future = BoxFuture<tonic::Response<CheckResponse>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<CheckRequest>) -> Self::Future {
+        let server = self.0.clone();
+        Box::pin(async move {
+            let response =
+                tokio::task::spawn_blocking(move || check(&server.configpath, server.loglevel, request.into_inner()))
+                    .await
+                    .map_err(|rr| tonic::Status::internal(rr.to_string()))?;
+            Ok(tonic::Response::new(response))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for AuthorizationServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != CHECK_PATH {
+            return Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", GRPC_UNIMPLEMENTED)
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            });
+        }
+        let service = CheckService(self.clone());
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.unary(service, request).await)
+        })
+    }
+}
+
+impl tonic::transport::NamedService for AuthorizationServer {
+    const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{ActionType, Mutation, Tags};
+    use crate::reason::{Initiator, Reason};
+
+    fn check_request() -> CheckRequest {
+        let headers: HashMap<String, String> = [
+            (":authority", "example.com"),
+            ("user-agent", "test"),
+            ("cookie", "a=1; b=2"),
+            ("x-long", "abcdef"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let peer = |address: &str, port_value: u32| Peer {
+            address: Some(Address {
+                socket_address: Some(SocketAddress {
+                    address: address.to_string(),
+                    port_value,
+                }),
+            }),
+        };
+        CheckRequest {
+            attributes: Some(AttributeContext {
+                source: Some(peer("1.2.3.4", 5555)),
+                destination: Some(peer("10.0.0.1", 8443)),
+                request: Some(Request {
+                    http: Some(HttpRequest {
+                        method: "POST".to_string(),
+                        headers,
+                        path: "/a?b=c".to_string(),
+                        host: "example.com".to_string(),
+                        scheme: "https".to_string(),
+                        protocol: "HTTP/1.1".to_string(),
+                        body: "x=y".to_string(),
+                        ..HttpRequest::default()
+                    }),
+                }),
+            }),
+        }
+    }
+
+    #[test]
+    fn request_attributes() {
+        let checked = CheckedRequest::new(check_request()).unwrap();
+        assert_eq!(checked.ip, "1.2.3.4");
+        assert_eq!(checked.meta.method, "POST");
+        assert_eq!(checked.meta.path, "/a?b=c");
+        assert_eq!(checked.meta.authority.as_deref(), Some("example.com"));
+        assert_eq!(checked.meta.port, Some(8443));
+        assert_eq!(checked.meta.http_version.as_deref(), Some("1.1"));
+        assert!(!checked.headers.contains_key(":authority"));
+        assert_eq!(checked.raw().mbody, Some(&b"x=y"[..]));
+        assert!(CheckedRequest::new(CheckRequest::default()).is_err());
+    }
+
+    #[test]
+    fn denied_response() {
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        let action = Action {
+            atype: ActionType::Block,
+            block_mode: true,
+            status: 247,
+            headers: Some(headers),
+            content: "challenge".to_string(),
+            reason: Reason::new(Initiator::Phase01),
+            ..Action::default()
+        };
+        let decision = Decision::Action(action);
+        let response = check_response(
+            &decision,
+            &DynamicMetadata::new(&decision, &Tags::default()),
+            &HashMap::new(),
+        );
+        assert_eq!(response.status.unwrap().code, GRPC_PERMISSION_DENIED);
+        match response.http_response {
+            Some(HttpResponse::DeniedResponse(denied)) => {
+                assert_eq!(denied.status.unwrap().code, 200);
+                assert_eq!(denied.headers, vec![header_option("content-type", "text/plain")]);
+                assert_eq!(denied.body, "challenge");
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        assert!(response.dynamic_metadata.unwrap().fields.contains_key("initiator"));
+        assert_eq!(envoy_status(451), 403);
+        assert_eq!(envoy_status(429), 429);
+    }
+
+    #[test]
+    fn sanitized_response() {
+        let checked = CheckedRequest::new(check_request()).unwrap();
+        let action = Action {
+            atype: ActionType::Sanitize,
+            mutations: vec![
+                Mutation::RemoveHeader {
+                    name: "user-agent".to_string(),
+                },
+                Mutation::TruncateHeader {
+                    name: "x-long".to_string(),
+                    length: 3,
+                },
+                Mutation::RemoveCookie { name: "a".to_string() },
+                Mutation::SetQuery {
+                    query: String::new(),
+                    removed: vec!["b".to_string()],
+                },
+            ],
+            ..Action::default()
+        };
+        let decision = Decision::Action(action);
+        let response = check_response(
+            &decision,
+            &DynamicMetadata::new(&decision, &Tags::default()),
+            &checked.headers,
+        );
+        assert_eq!(response.status.unwrap().code, GRPC_OK);
+        match response.http_response {
+            Some(HttpResponse::OkResponse(ok)) => {
+                assert_eq!(ok.headers_to_remove, vec!["user-agent".to_string()]);
+                assert_eq!(
+                    ok.headers,
+                    vec![header_option("x-long", "abc"), header_option("cookie", "b=2")]
+                );
+                assert_eq!(ok.query_parameters_to_remove, vec!["b".to_string()]);
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+}
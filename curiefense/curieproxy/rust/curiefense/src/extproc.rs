@@ -0,0 +1,726 @@
+use crate::cookiesigning::{response_signer, CookieSigning};
+use crate::extauthz::{dynamic_metadata, envoy_status, header_option, inspect, ok_response};
+use crate::interface::{Decision, Mutation};
+use crate::logs::LogLevel;
+use crate::metadata::DynamicMetadata;
+use crate::utils::{RawRequest, RequestMeta};
+use futures::stream::BoxStream;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+
+/// the subset of the Envoy protobuf messages that is used by the server
+///
+/// field numbers come from `envoy/service/ext_proc/v3/external_processor.proto` and its dependencies.
+pub mod proto {
+    pub use crate::extauthz::proto::{HeaderValue, HeaderValueOption, HttpStatus};
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProcessingRequest {
+        #[prost(oneof = "Phase", tags = "2, 3, 4, 5, 6, 7")]
+        pub request: Option<Phase>,
+        /// the attributes requested by the `request_attributes` setting, by filter name
+        #[prost(map = "string, message", tag = "9")]
+        pub attributes: HashMap<String, prost_types::Struct>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Phase {
+        #[prost(message, tag = "2")]
+        RequestHeaders(HttpHeaders),
+        #[prost(message, tag = "3")]
+        ResponseHeaders(HttpHeaders),
+        #[prost(message, tag = "4")]
+        RequestBody(HttpBody),
+        #[prost(message, tag = "5")]
+        ResponseBody(HttpBody),
+        #[prost(message, tag = "6")]
+        RequestTrailers(HttpTrailers),
+        #[prost(message, tag = "7")]
+        ResponseTrailers(HttpTrailers),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpHeaders {
+        #[prost(message, optional, tag = "1")]
+        pub headers: Option<HeaderMap>,
+        #[prost(bool, tag = "3")]
+        pub end_of_stream: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpBody {
+        #[prost(bytes = "vec", tag = "1")]
+        pub body: Vec<u8>,
+        #[prost(bool, tag = "2")]
+        pub end_of_stream: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpTrailers {
+        #[prost(message, optional, tag = "1")]
+        pub trailers: Option<HeaderMap>,
+    }
+
+    /// `envoy.config.core.v3.HeaderMap`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeaderMap {
+        #[prost(message, repeated, tag = "1")]
+        pub headers: Vec<HeaderValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProcessingResponse {
+        #[prost(oneof = "PhaseResponse", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub response: Option<PhaseResponse>,
+        #[prost(message, optional, tag = "8")]
+        pub dynamic_metadata: Option<prost_types::Struct>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum PhaseResponse {
+        #[prost(message, tag = "1")]
+        RequestHeaders(HeadersResponse),
+        #[prost(message, tag = "2")]
+        ResponseHeaders(HeadersResponse),
+        #[prost(message, tag = "3")]
+        RequestBody(BodyResponse),
+        #[prost(message, tag = "4")]
+        ResponseBody(BodyResponse),
+        #[prost(message, tag = "5")]
+        RequestTrailers(TrailersResponse),
+        #[prost(message, tag = "6")]
+        ResponseTrailers(TrailersResponse),
+        #[prost(message, tag = "7")]
+        ImmediateResponse(ImmediateResponse),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeadersResponse {
+        #[prost(message, optional, tag = "1")]
+        pub response: Option<CommonResponse>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BodyResponse {
+        #[prost(message, optional, tag = "1")]
+        pub response: Option<CommonResponse>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TrailersResponse {
+        #[prost(message, optional, tag = "1")]
+        pub header_mutation: Option<HeaderMutation>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommonResponse {
+        /// `CONTINUE` (0), as the body is never replaced
+        #[prost(int32, tag = "1")]
+        pub status: i32,
+        #[prost(message, optional, tag = "2")]
+        pub header_mutation: Option<HeaderMutation>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeaderMutation {
+        #[prost(message, repeated, tag = "1")]
+        pub set_headers: Vec<HeaderValueOption>,
+        #[prost(string, repeated, tag = "2")]
+        pub remove_headers: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ImmediateResponse {
+        #[prost(message, optional, tag = "1")]
+        pub status: Option<HttpStatus>,
+        #[prost(message, optional, tag = "2")]
+        pub headers: Option<HeaderMutation>,
+        #[prost(string, tag = "3")]
+        pub body: String,
+        #[prost(string, tag = "5")]
+        pub details: String,
+    }
+}
+
+use proto::*;
+
+pub const PROCESS_PATH: &str = "/envoy.service.ext_proc.v3.ExternalProcessor/Process";
+
+/// the attribute that holds the client address, when `source.address` is in the `request_attributes`
+const SOURCE_ADDRESS: &str = "source.address";
+
+/// the address, without its port, which is part of the `source.address` attribute
+fn strip_port(address: &str) -> &str {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        bracketed.split(']').next().unwrap_or(bracketed)
+    } else if address.matches(':').count() == 1 {
+        address.split(':').next().unwrap_or(address)
+    } else {
+        address
+    }
+}
+
+fn header_value(header: HeaderValue) -> (String, String) {
+    let value = if header.raw_value.is_empty() {
+        header.value
+    } else {
+        String::from_utf8_lossy(&header.raw_value).into_owned()
+    };
+    (header.key.to_lowercase(), value)
+}
+
+/// the phase of the request that carries the decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestPhase {
+    Headers,
+    Body,
+    Trailers,
+}
+
+/// the state of a `Process` stream, that holds the request until it is inspected
+#[derive(Debug, Default)]
+pub struct ProcessState {
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    ip: Option<String>,
+    body: Vec<u8>,
+    inspected: bool,
+    /// the id of the inspected request, for the login attempts outcome
+    request_id: Option<String>,
+    /// the cookie to set on the response, see `DynamicMetadata::set_cookie`
+    set_cookie: Option<String>,
+    /// the signing settings of the cookies set by the response, see the `cookiesigning` module
+    signer: Option<Arc<CookieSigning>>,
+}
+
+impl ProcessState {
+    fn add_headers(&mut self, headers: Option<HeaderMap>) {
+        for (key, value) in headers.into_iter().flat_map(|h| h.headers).map(header_value) {
+            match key.strip_prefix(':') {
+                Some(pseudo) => {
+                    self.meta.insert(pseudo.to_string(), value);
+                }
+                None => {
+                    // repeated headers are combined, as by the other proxies
+                    self.headers
+                        .entry(key)
+                        .and_modify(|v| {
+                            v.push_str(", ");
+                            v.push_str(&value)
+                        })
+                        .or_insert(value);
+                }
+            }
+        }
+    }
+
+    /// the trailers are inspected with the headers, see `RequestMeta::trailers`
+    fn add_trailers(&mut self, trailers: Option<HeaderMap>) {
+        for (key, value) in trailers.into_iter().flat_map(|h| h.headers).map(header_value) {
+            self.meta.insert(format!("trailer_{}", key), value);
+        }
+    }
+
+    fn add_attributes(&mut self, attributes: &HashMap<String, prost_types::Struct>) {
+        use prost_types::value::Kind;
+        let source = attributes
+            .values()
+            .filter_map(|s| s.fields.get(SOURCE_ADDRESS))
+            .find_map(|v| match &v.kind {
+                Some(Kind::StringValue(s)) => Some(s.clone()),
+                _ => None,
+            });
+        if let Some(s) = source {
+            self.ip = Some(strip_port(&s).to_string());
+        }
+    }
+
+    /// the client address, from the attributes, or from the header that Envoy sets when `use_remote_address` is on
+    fn client_ip(&self) -> String {
+        self.ip
+            .clone()
+            .or_else(|| self.headers.get("x-envoy-external-address").cloned())
+            .unwrap_or_default()
+    }
+
+    /// inspects the request, returning the decision, its dynamic metadata and the request path
+    fn inspect(&mut self, configpath: &str, loglevel: LogLevel) -> Result<(Decision, DynamicMetadata, String), String> {
+        self.inspected = true;
+        let meta = RequestMeta::from_map(self.meta.clone())?;
+        let path = meta.path.clone();
+        let raw = RawRequest {
+            ipstr: meta.client_ip(self.client_ip()),
+            headers: self.headers.clone(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: if self.body.is_empty() { None } else { Some(&self.body) },
+        };
+        self.signer = response_signer(configpath, &raw);
+        let (decision, metadata) = inspect(configpath, loglevel, raw);
+        Ok((decision, metadata, path))
+    }
+
+    /// reports the response status of the login attempts, and adds the CSRF token and the cookie signatures
+    fn response_headers(&mut self, headers: Option<HeaderMap>) -> HeadersResponse {
+        let headers: Vec<(String, String)> = headers.into_iter().flat_map(|h| h.headers).map(header_value).collect();
+        let status = headers
+            .iter()
+            .find(|(key, _)| key == ":status")
+            .and_then(|(_, value)| value.parse::<u32>().ok());
+        if let (Some(request_id), Some(status)) = (self.request_id.take(), status) {
+            crate::login::record_result(&request_id, status);
+        }
+        let mut cookies: Vec<String> = self.set_cookie.take().into_iter().collect();
+        if let Some(signer) = self.signer.take() {
+            cookies.extend(
+                headers
+                    .iter()
+                    .filter(|(key, _)| key == "set-cookie")
+                    .filter_map(|(_, value)| signer.sign(value)),
+            );
+        }
+        if cookies.is_empty() {
+            return HeadersResponse::default();
+        }
+        let set_headers = cookies
+            .iter()
+            .map(|cookie| HeaderValueOption {
+                append: Some(true),
+                ..header_option("set-cookie", cookie)
+            })
+            .collect();
+        HeadersResponse {
+            response: Some(CommonResponse {
+                status: 0,
+                header_mutation: Some(HeaderMutation {
+                    set_headers,
+                    remove_headers: Vec::new(),
+                }),
+            }),
+        }
+    }
+
+    /// processes a message of the stream, inspecting the request once it is complete
+    pub fn process(
+        &mut self,
+        configpath: &str,
+        loglevel: LogLevel,
+        request: ProcessingRequest,
+    ) -> Result<ProcessingResponse, String> {
+        self.add_attributes(&request.attributes);
+        let unchanged = |response| ProcessingResponse {
+            response: Some(response),
+            dynamic_metadata: None,
+        };
+        let (phase, complete) = match request.request {
+            Some(Phase::RequestHeaders(h)) => {
+                self.add_headers(h.headers);
+                (RequestPhase::Headers, h.end_of_stream)
+            }
+            Some(Phase::RequestBody(b)) => {
+                self.body.extend(b.body);
+                (RequestPhase::Body, b.end_of_stream)
+            }
+            Some(Phase::RequestTrailers(t)) => {
+                self.add_trailers(t.trailers);
+                (RequestPhase::Trailers, true)
+            }
+            Some(Phase::ResponseHeaders(h)) => {
+                let response = self.response_headers(h.headers);
+                return Ok(unchanged(PhaseResponse::ResponseHeaders(response)));
+            }
+            Some(Phase::ResponseBody(_)) => return Ok(unchanged(PhaseResponse::ResponseBody(BodyResponse::default()))),
+            Some(Phase::ResponseTrailers(_)) => {
+                return Ok(unchanged(PhaseResponse::ResponseTrailers(TrailersResponse::default())))
+            }
+            None => return Err("missing processing phase".to_string()),
+        };
+        // headers followed by a body, body chunks in streamed mode, or trailers after the decision
+        if !complete || self.inspected {
+            return Ok(unchanged(phase_response(phase, CommonResponse::default())));
+        }
+        let (decision, metadata, path) = self.inspect(configpath, loglevel)?;
+        self.request_id = metadata.request_id.clone();
+        self.set_cookie = metadata.set_cookie.clone();
+        Ok(decision_response(phase, &decision, &metadata, &self.headers, &path))
+    }
+}
+
+fn phase_response(phase: RequestPhase, common: CommonResponse) -> PhaseResponse {
+    match phase {
+        RequestPhase::Headers => PhaseResponse::RequestHeaders(HeadersResponse { response: Some(common) }),
+        RequestPhase::Body => PhaseResponse::RequestBody(BodyResponse { response: Some(common) }),
+        // the request headers were already sent upstream
+        RequestPhase::Trailers => PhaseResponse::RequestTrailers(TrailersResponse::default()),
+    }
+}
+
+/// the request path, with the new query
+fn rewrite_query(path: &str, query: &str) -> String {
+    let path = path.split('?').next().unwrap_or(path);
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query)
+    }
+}
+
+/// converts the decision, `headers` and `path` being those of the request
+pub fn decision_response(
+    phase: RequestPhase,
+    decision: &Decision,
+    metadata: &DynamicMetadata,
+    headers: &HashMap<String, String>,
+    path: &str,
+) -> ProcessingResponse {
+    let dynamic_metadata = dynamic_metadata(metadata);
+    let action = match decision {
+        Decision::Pass => None,
+        Decision::Action(a) => Some(a),
+    };
+    if let Some(a) = action.filter(|a| a.block_mode && a.atype.is_blocking()) {
+        return ProcessingResponse {
+            response: Some(PhaseResponse::ImmediateResponse(ImmediateResponse {
+                status: Some(HttpStatus {
+                    code: envoy_status(a.status),
+                }),
+                headers: Some(HeaderMutation {
+                    set_headers: a
+                        .headers
+                        .iter()
+                        .flatten()
+                        .map(|(k, v)| header_option(&k.to_lowercase(), v))
+                        .collect(),
+                    remove_headers: Vec::new(),
+                }),
+                body: a.content.clone(),
+                details: a.reason.initiator.to_string(),
+            })),
+            dynamic_metadata,
+        };
+    }
+    let ok = ok_response(action, headers);
+    let mut set_headers = ok.headers;
+    if !ok.query_parameters_to_remove.is_empty() {
+        let query = action
+            .into_iter()
+            .flat_map(|a| a.mutations.iter())
+            .filter_map(|m| match m {
+                Mutation::SetQuery { query, .. } => Some(query.as_str()),
+                _ => None,
+            })
+            .next_back()
+            .unwrap_or_default();
+        set_headers.push(header_option(":path", &rewrite_query(path, query)));
+    }
+    let common = CommonResponse {
+        status: 0,
+        header_mutation: Some(HeaderMutation {
+            set_headers,
+            remove_headers: ok.headers_to_remove,
+        }),
+    };
+    ProcessingResponse {
+        response: Some(phase_response(phase, common)),
+        dynamic_metadata,
+    }
+}
+
+/// the `ExternalProcessor` gRPC service, to be added to a `tonic::transport::Server`
+#[derive(Debug, Clone)]
+pub struct ExternalProcessorServer {
+    configpath: Arc<String>,
+    loglevel: LogLevel,
+}
+
+impl ExternalProcessorServer {
+    pub fn new(configpath: String, loglevel: LogLevel) -> Self {
+        ExternalProcessorServer {
+            configpath: Arc::new(configpath),
+            loglevel,
+        }
+    }
+}
+
+type ResponseStream = BoxStream<'static, Result<ProcessingResponse, tonic::Status>>;
+
+struct ProcessService(ExternalProcessorServer);
+
+impl tonic::server::StreamingService<ProcessingRequest> for ProcessService {
+    type Response = ProcessingResponse;
+    type ResponseStream = ResponseStream;
+    type Future = BoxFuture<tonic::Response<ResponseStream>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<tonic::Streaming<ProcessingRequest>>) -> Self::Future {
+        let server = self.0.clone();
+        let input = request.into_inner();
+        // the input is dropped after an error, ending the stream
+        let output = futures::stream::unfold((Some(input), ProcessState::default()), move |(input, state)| {
+            let server = server.clone();
+            async move {
+                let mut input = input?;
+                let message = match input.message().await {
+                    Ok(Some(m)) => m,
+                    Ok(None) => return None,
+                    Err(rr) => return Some((Err(rr), (None, state))),
+                };
+                let processed = tokio::task::spawn_blocking(move || {
+                    let mut state = state;
+                    let response = state.process(&server.configpath, server.loglevel, message);
+                    (state, response)
+                })
+                .await;
+                match processed {
+                    Ok((state, Ok(response))) => Some((Ok(response), (Some(input), state))),
+                    Ok((state, Err(rr))) => Some((Err(tonic::Status::invalid_argument(rr)), (None, state))),
+                    Err(rr) => Some((
+                        Err(tonic::Status::internal(rr.to_string())),
+                        (None, ProcessState::default()),
+                    )),
+                }
+            }
+        });
+        Box::pin(async move { Ok(tonic::Response::new(Box::pin(output) as ResponseStream)) })
+    }
+}
+
+impl<B> Service<http::Request<B>> for ExternalProcessorServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != PROCESS_PATH {
+            return Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            });
+        }
+        let service = ProcessService(self.clone());
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.streaming(service, request).await)
+        })
+    }
+}
+
+impl tonic::transport::NamedService for ExternalProcessorServer {
+    const NAME: &'static str = "envoy.service.ext_proc.v3.ExternalProcessor";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{Action, ActionType, Tags};
+    use crate::reason::{Initiator, Reason};
+
+    fn header(key: &str, value: &str) -> HeaderValue {
+        HeaderValue {
+            key: key.to_string(),
+            value: String::new(),
+            raw_value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn request_headers(end_of_stream: bool) -> ProcessingRequest {
+        let mut source = prost_types::Struct::default();
+        source.fields.insert(
+            SOURCE_ADDRESS.to_string(),
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::StringValue("1.2.3.4:5555".to_string())),
+            },
+        );
+        ProcessingRequest {
+            request: Some(Phase::RequestHeaders(HttpHeaders {
+                headers: Some(HeaderMap {
+                    headers: vec![
+                        header(":method", "POST"),
+                        header(":path", "/a?b=c&d=e"),
+                        header(":authority", "example.com"),
+                        header("Accept", "text/html"),
+                        header("accept", "*/*"),
+                    ],
+                }),
+                end_of_stream,
+            })),
+            attributes: std::iter::once(("envoy.filters.http.ext_proc".to_string(), source)).collect(),
+        }
+    }
+
+    #[test]
+    fn streamed_request() {
+        let mut state = ProcessState::default();
+        let response = state
+            .process("/nonexistent", LogLevel::Error, request_headers(false))
+            .unwrap();
+        assert!(matches!(response.response, Some(PhaseResponse::RequestHeaders(_))));
+        assert!(!state.inspected);
+        assert_eq!(state.meta.get("method").map(|s| s.as_str()), Some("POST"));
+        assert_eq!(state.headers.get("accept").map(|s| s.as_str()), Some("text/html, */*"));
+        assert_eq!(state.client_ip(), "1.2.3.4");
+
+        let chunk = |body: &[u8], end_of_stream: bool| ProcessingRequest {
+            request: Some(Phase::RequestBody(HttpBody {
+                body: body.to_vec(),
+                end_of_stream,
+            })),
+            attributes: HashMap::new(),
+        };
+        let response = state
+            .process("/nonexistent", LogLevel::Error, chunk(b"x=", false))
+            .unwrap();
+        assert!(matches!(response.response, Some(PhaseResponse::RequestBody(_))));
+        assert!(!state.inspected);
+        let response = state
+            .process("/nonexistent", LogLevel::Error, chunk(b"y", true))
+            .unwrap();
+        assert!(matches!(response.response, Some(PhaseResponse::RequestBody(_))));
+        assert!(response.dynamic_metadata.is_some());
+        assert!(state.inspected);
+        assert_eq!(state.body, b"x=y");
+
+        assert_eq!(strip_port("[::1]:80"), "::1");
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("1.2.3.4"), "1.2.3.4");
+    }
+
+    #[test]
+    fn request_trailers() {
+        let mut state = ProcessState::default();
+        state
+            .process("/nonexistent", LogLevel::Error, request_headers(false))
+            .unwrap();
+        let trailers = ProcessingRequest {
+            request: Some(Phase::RequestTrailers(HttpTrailers {
+                trailers: Some(HeaderMap {
+                    headers: vec![header("Authorization", "Basic YWRtaW46YWRtaW4=")],
+                }),
+            })),
+            attributes: HashMap::new(),
+        };
+        let response = state.process("/nonexistent", LogLevel::Error, trailers).unwrap();
+        assert!(matches!(response.response, Some(PhaseResponse::RequestTrailers(_))));
+        assert!(state.inspected);
+        assert_eq!(
+            state.meta.get("trailer_authorization").map(|s| s.as_str()),
+            Some("Basic YWRtaW46YWRtaW4=")
+        );
+        assert!(!state.headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn immediate_response() {
+        let action = Action {
+            atype: ActionType::Block,
+            block_mode: true,
+            status: 503,
+            content: "blocked".to_string(),
+            reason: Reason::new(Initiator::Acl),
+            ..Action::default()
+        };
+        let decision = Decision::Action(action);
+        let response = decision_response(
+            RequestPhase::Body,
+            &decision,
+            &DynamicMetadata::new(&decision, &Tags::default()),
+            &HashMap::new(),
+            "/",
+        );
+        match response.response {
+            Some(PhaseResponse::ImmediateResponse(r)) => {
+                assert_eq!(r.status.unwrap().code, 503);
+                assert_eq!(r.body, "blocked");
+                assert_eq!(r.details, "acl");
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn sanitized_request() {
+        let mut headers = HashMap::new();
+        headers.insert("user-agent".to_string(), "test".to_string());
+        let action = Action {
+            atype: ActionType::Sanitize,
+            mutations: vec![
+                Mutation::RemoveHeader {
+                    name: "user-agent".to_string(),
+                },
+                Mutation::SetQuery {
+                    query: "d=e".to_string(),
+                    removed: vec!["b".to_string()],
+                },
+            ],
+            ..Action::default()
+        };
+        let decision = Decision::Action(action);
+        let response = decision_response(
+            RequestPhase::Headers,
+            &decision,
+            &DynamicMetadata::new(&decision, &Tags::default()),
+            &headers,
+            "/a?b=c&d=e",
+        );
+        let mutation = match response.response {
+            Some(PhaseResponse::RequestHeaders(h)) => h.response.unwrap().header_mutation.unwrap(),
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!(mutation.remove_headers, vec!["user-agent".to_string()]);
+        assert_eq!(mutation.set_headers, vec![header_option(":path", "/a?d=e")]);
+        assert_eq!(rewrite_query("/a?b=c", ""), "/a");
+    }
+
+    #[test]
+    fn response_cookies() {
+        let raw: crate::config::raw::RawCookieSigning =
+            serde_json::from_str(r#"{"secret": "k", "cookies": ["role"]}"#).unwrap();
+        let signer = Arc::new(CookieSigning::resolve(&raw));
+        let mut state = ProcessState {
+            set_cookie: Some("cf_csrf=t; Path=/".to_string()),
+            signer: Some(signer.clone()),
+            ..ProcessState::default()
+        };
+        let headers = HeaderMap {
+            headers: vec![
+                header(":status", "200"),
+                header("set-cookie", "role=user; Path=/"),
+                header("set-cookie", "other=1"),
+            ],
+        };
+        let mutation = state
+            .response_headers(Some(headers))
+            .response
+            .unwrap()
+            .header_mutation
+            .unwrap();
+        let cookies: Vec<String> = mutation
+            .set_headers
+            .into_iter()
+            .filter(|h| h.append == Some(true))
+            .filter_map(|h| h.header.map(|h| h.value))
+            .collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "cf_csrf=t; Path=/".to_string(),
+                signer.sign("role=user; Path=/").unwrap()
+            ]
+        );
+        // only once
+        assert_eq!(state.response_headers(None), HeadersResponse::default());
+    }
+}
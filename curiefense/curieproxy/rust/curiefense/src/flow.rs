@@ -1,16 +1,25 @@
+#[cfg(feature = "redis")]
+use crate::reason::{Initiator, Reason};
+#[cfg(feature = "redis")]
 use crate::redis::{extract_bannable_action, get_ban_key, is_banned, BanStatus};
 use crate::Logs;
 use std::collections::HashMap;
 
 use crate::config::flow::{FlowElement, SequenceKey};
+#[cfg(feature = "redis")]
 use crate::config::utils::RequestSelector;
-use crate::interface::{stronger_decision, SimpleDecision, Tags};
-use crate::utils::{check_selector_cond, select_string, RequestInfo};
+#[cfg(feature = "redis")]
+use crate::interface::stronger_decision;
+use crate::interface::{SimpleDecision, Tags};
+use crate::utils::RequestInfo;
+#[cfg(feature = "redis")]
+use crate::utils::{check_selector_cond, select_string};
 
 fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
     SequenceKey(ri.rinfo.meta.method.to_string() + &ri.rinfo.host + &ri.rinfo.qinfo.qpath)
 }
 
+#[cfg(feature = "redis")]
 fn build_redis_key(
     reqinfo: &RequestInfo,
     tags: &Tags,
@@ -25,6 +34,7 @@ fn build_redis_key(
     Some(format!("{:X}", md5::compute(tohash)))
 }
 
+#[cfg(feature = "redis")]
 fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
     if elem.exclude.iter().any(|e| tags.contains(e)) {
         return false;
@@ -35,12 +45,14 @@ fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
     elem.select.iter().all(|e| check_selector_cond(reqinfo, tags, e))
 }
 
+#[cfg(feature = "redis")]
 enum FlowResult {
     NonLast,
     LastOk,
     LastBlock,
 }
 
+#[cfg(feature = "redis")]
 async fn check_flow<CNX: redis::aio::ConnectionLike>(
     cnx: &mut CNX,
     redis_key: &str,
@@ -82,6 +94,7 @@ async fn check_flow<CNX: redis::aio::ConnectionLike>(
     }
 }
 
+#[cfg(feature = "redis")]
 async fn ban_react<CNX: redis::aio::ConnectionLike>(
     logs: &mut Logs,
     cnx: &mut CNX,
@@ -112,19 +125,14 @@ async fn ban_react<CNX: redis::aio::ConnectionLike>(
 
         stronger_decision(
             bad,
-            SimpleDecision::Action(
-                action,
-                serde_json::json!({
-                    "initiator": "flow_check",
-                    "name": elem.name,
-                }),
-            ),
+            SimpleDecision::Action(action, Reason::new(Initiator::FlowCheck).with_name(&elem.name)),
         )
     } else {
         bad
     }
 }
 
+#[cfg(feature = "redis")]
 pub async fn flow_check(
     logs: &mut Logs,
     flows: &HashMap<SequenceKey, Vec<FlowElement>>,
@@ -164,3 +172,17 @@ pub async fn flow_check(
         }
     }
 }
+
+/// without redis, the steps of the flows cannot be followed across requests
+#[cfg(not(feature = "redis"))]
+pub async fn flow_check(
+    logs: &mut Logs,
+    flows: &HashMap<SequenceKey, Vec<FlowElement>>,
+    reqinfo: &RequestInfo,
+    _tags: &mut Tags,
+) -> anyhow::Result<SimpleDecision> {
+    if flows.contains_key(&session_sequence_key(reqinfo)) {
+        logs.debug("flow controls are not checked, redis is not available");
+    }
+    Ok(SimpleDecision::Pass)
+}
@@ -0,0 +1,121 @@
+use crate::body::parse_body;
+use crate::config::contentfilter::Transformation;
+use crate::config::raw::ContentType;
+use crate::config::utils::DataSource;
+use crate::config::{Config, CONFIG_FILES};
+use crate::logs::{LogLevel, Logs};
+use crate::requestfields::RequestField;
+use crate::utils::cookie_map;
+use crate::utils::decoders::{
+    base64dec_all, canonicalize_path, parse_urlencoded_params_bytes, urldecode_component, urldecode_str, UrlComponent,
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+const MAX_DEPTH: usize = 100;
+
+const ALL_DECODINGS: [Transformation; 4] = [
+    Transformation::Base64Decode,
+    Transformation::UrlDecode,
+    Transformation::HtmlEntitiesDecode,
+    Transformation::UnicodeDecode,
+];
+
+fn logs() -> Logs {
+    Logs::new(LogLevel::Error)
+}
+
+/// the URL decoders, and the query string parser
+pub fn urldecode(data: &[u8]) {
+    let _ = urldecode_str(&String::from_utf8_lossy(data));
+    let _ = canonicalize_path(&String::from_utf8_lossy(data));
+    for component in [UrlComponent::PathSegment, UrlComponent::Query, UrlComponent::Form] {
+        let _ = urldecode_component(data, component, true);
+        let _ = urldecode_component(data, component, false);
+    }
+    let mut args = RequestField::new(&[]);
+    parse_urlencoded_params_bytes(&mut args, data);
+}
+
+/// the cookie header parser, with all the decodings of the values
+pub fn cookies(data: &[u8]) {
+    let mut cookies = RequestField::new(&ALL_DECODINGS);
+    cookie_map(&mut cookies, &String::from_utf8_lossy(data));
+}
+
+/// the base64 decoder, and the heuristic that adds the decoded values of the arguments
+pub fn base64(data: &[u8]) {
+    let _ = base64dec_all(data);
+    let mut args = RequestField::new(&[Transformation::Base64Decode]);
+    args.add_bytes("fuzz".to_string(), DataSource::Root, data);
+}
+
+/// the JSON body parser
+pub fn json_body(data: &[u8]) {
+    let mut args = RequestField::new(&[]);
+    let _ = parse_body(
+        &mut logs(),
+        &mut args,
+        MAX_DEPTH,
+        Some("application/json"),
+        &[ContentType::Json],
+        data,
+    );
+}
+
+/// the multipart body parser, the first line of the input being the boundary
+pub fn multipart_body(data: &[u8]) {
+    let (boundary, body) = match data.iter().position(|&c| c == b'\n') {
+        Some(idx) => (&data[..idx], &data[idx + 1..]),
+        None => (data, &[][..]),
+    };
+    let content_type = format!("multipart/form-data; boundary={}", String::from_utf8_lossy(boundary));
+    let mut args = RequestField::new(&[]);
+    let _ = parse_body(
+        &mut logs(),
+        &mut args,
+        MAX_DEPTH,
+        Some(&content_type),
+        &[ContentType::MultipartForm],
+        body,
+    );
+}
+
+/// the configuration loader, the first byte of the input selecting the file (see `CONFIG_FILES`) whose content is
+/// the rest of the input
+pub fn config(data: &[u8]) {
+    let (selector, content) = match data.split_first() {
+        Some(s) => s,
+        None => return,
+    };
+    let fname = CONFIG_FILES[*selector as usize % CONFIG_FILES.len()];
+    let mut documents = HashMap::new();
+    documents.insert(fname.to_string(), content.to_vec());
+    let _ = Config::from_documents(&mut logs(), SystemTime::UNIX_EPOCH, Path::new("fuzz"), &documents);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_inputs() {
+        let inputs: [&[u8]; 6] = [
+            b"",
+            b"%",
+            b"a=%zz&%e9=\xff; b",
+            b"{\"a\": [1, {\"b\": \"\xc3\"}",
+            b"xx\n--xx\r\ncontent-disposition: form-data\r\n\r\n",
+            b"\x03[{\"id\": \"a\", \"allow\": 1}]",
+        ];
+        for input in inputs.iter() {
+            urldecode(input);
+            cookies(input);
+            base64(input);
+            json_body(input);
+            multipart_body(input);
+            config(input);
+        }
+    }
+}
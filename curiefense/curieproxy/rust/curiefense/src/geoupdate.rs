@@ -0,0 +1,249 @@
+use crate::config::raw::RawGeoipUpdates;
+use crate::crypto::to_hex;
+use crate::maxmind::database_dir;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+use zune_inflate::DeflateDecoder;
+
+/// the delay between the checks of the age of the files
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// the delay before a failed download is retried
+const RETRY_DELAY: Duration = Duration::from_secs(900);
+const TAR_BLOCK: usize = 512;
+
+lazy_static! {
+    /// the settings of the current configuration
+    static ref SETTINGS: RwLock<Option<RawGeoipUpdates>> = RwLock::new(None);
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// applies the settings of a newly loaded configuration, the updater being started the first time they are set
+pub fn configure(settings: Option<&RawGeoipUpdates>) {
+    if let Ok(mut current) = SETTINGS.write() {
+        *current = settings.cloned();
+    }
+    if settings.is_none() || STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-geoip".to_string())
+        .spawn(run)
+    {
+        tracing::error!(target: "curiefense::geoupdate", "could not start the MaxMind databases updater: {}", rr);
+    }
+}
+
+fn db_file(edition: &str) -> String {
+    format!("{}.mmdb", edition)
+}
+
+/// true when the file is missing, or was modified more than `interval` before `now`
+pub fn is_stale(path: &Path, interval: Duration, now: SystemTime) -> bool {
+    match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(modified) => now.duration_since(modified).map(|age| age >= interval).unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+fn run() {
+    // the last download attempt of each edition
+    let mut attempts: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let settings = SETTINGS.read().ok().and_then(|s| s.clone());
+        if let Some(settings) = settings {
+            let dir = database_dir();
+            let interval = Duration::from_secs(settings.interval);
+            for edition in &settings.editions {
+                let retrying = attempts
+                    .get(edition)
+                    .map(|t| t.elapsed() < RETRY_DELAY)
+                    .unwrap_or(false);
+                if retrying || !is_stale(&dir.join(db_file(edition)), interval, SystemTime::now()) {
+                    continue;
+                }
+                attempts.insert(edition.clone(), Instant::now());
+                match update(&settings, dir, edition) {
+                    Ok(()) => tracing::info!(target: "curiefense::geoupdate", edition, "database downloaded"),
+                    Err(rr) => {
+                        tracing::warn!(target: "curiefense::geoupdate", "could not download the {} database: {}", edition, rr)
+                    }
+                }
+            }
+        }
+        std::thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+fn download(settings: &RawGeoipUpdates, edition: &str, suffix: &str) -> Result<Vec<u8>, String> {
+    attohttpc::get(&settings.url)
+        .param("edition_id", edition)
+        .param("license_key", &settings.license_key)
+        .param("suffix", suffix)
+        .timeout(Duration::from_secs(300))
+        .send()
+        .and_then(|rsp| rsp.error_for_status())
+        .and_then(|rsp| rsp.bytes())
+        .map_err(|rr| rr.to_string())
+}
+
+fn update(settings: &RawGeoipUpdates, dir: &Path, edition: &str) -> Result<(), String> {
+    let archive = download(settings, edition, "tar.gz")?;
+    let checksum = download(settings, edition, "tar.gz.sha256")?;
+    let expected =
+        parse_checksum(&String::from_utf8_lossy(&checksum)).ok_or_else(|| "invalid checksum file".to_string())?;
+    verify(&archive, &expected)?;
+    let file = db_file(edition);
+    let db = extract(&archive, &file)?;
+    maxminddb::Reader::from_source(db.as_slice()).map_err(|rr| format!("invalid database: {}", rr))?;
+    install(dir, &file, &db)
+}
+
+/// the digest of a checksum file, formatted as the output of `sha256sum`
+pub fn parse_checksum(body: &str) -> Option<String> {
+    body.split_whitespace()
+        .next()
+        .filter(|d| d.len() == 64 && d.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|d| d.to_lowercase())
+}
+
+pub fn verify(archive: &[u8], expected: &str) -> Result<(), String> {
+    let actual = to_hex(&Sha256::digest(archive));
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("checksum mismatch, expected {}, got {}", expected, actual))
+    }
+}
+
+/// the contents of the file of a gzipped tar archive, whatever its directory
+pub fn extract(archive: &[u8], file: &str) -> Result<Vec<u8>, String> {
+    let tar = DeflateDecoder::new(archive)
+        .decode_gzip()
+        .map_err(|rr| format!("invalid archive: {}", rr))?;
+    let mut offset = 0;
+    while let Some(header) = tar.get(offset..offset + TAR_BLOCK) {
+        // the archive ends with empty blocks
+        if header[0] == 0 {
+            break;
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let raw = &header[range];
+            let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+            String::from_utf8_lossy(&raw[..end]).into_owned()
+        };
+        let size = usize::from_str_radix(field(124..136).trim(), 8)
+            .map_err(|rr| format!("invalid archive entry size: {}", rr))?;
+        let name = field(0..100);
+        let start = offset + TAR_BLOCK;
+        let regular = header[156] == b'0' || header[156] == 0;
+        if regular && (name == file || name.ends_with(&format!("/{}", file))) {
+            return tar
+                .get(start..start + size)
+                .map(|content| content.to_vec())
+                .ok_or_else(|| format!("truncated archive entry {}", name));
+        }
+        offset = start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    }
+    Err(format!("{} not found in the archive", file))
+}
+
+/// writes the file, and renames it over the current one, so that the readers of the previous one are not disturbed
+pub fn install(dir: &Path, file: &str, content: &[u8]) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|rr| format!("could not create {}: {}", dir.display(), rr))?;
+    let tmp = dir.join(format!(".{}.download", file));
+    std::fs::write(&tmp, content).map_err(|rr| format!("could not write {}: {}", tmp.display(), rr))?;
+    std::fs::rename(&tmp, dir.join(file)).map_err(|rr| format!("could not rename {}: {}", tmp.display(), rr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    /// a gzipped tar archive, with stored deflate blocks
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, content) in entries {
+            let mut header = [0u8; TAR_BLOCK];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+            header[156] = b'0';
+            tar.extend_from_slice(&header);
+            tar.extend_from_slice(content);
+            tar.resize(tar.len().div_ceil(TAR_BLOCK) * TAR_BLOCK, 0);
+        }
+        tar.resize(tar.len() + 2 * TAR_BLOCK, 0);
+
+        let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let chunks: Vec<&[u8]> = tar.chunks(0xffff).collect();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            gz.push(if idx + 1 == chunks.len() { 1 } else { 0 });
+            let len = chunk.len() as u16;
+            gz.extend_from_slice(&len.to_le_bytes());
+            gz.extend_from_slice(&(!len).to_le_bytes());
+            gz.extend_from_slice(chunk);
+        }
+        gz.extend_from_slice(&crc32(&tar).to_le_bytes());
+        gz.extend_from_slice(&(tar.len() as u32).to_le_bytes());
+        gz
+    }
+
+    #[test]
+    fn archives() {
+        let gz = archive(&[
+            ("GeoLite2-ASN_20240101/COPYRIGHT.txt", b"copyright"),
+            ("GeoLite2-ASN_20240101/GeoLite2-ASN.mmdb", b"the database"),
+        ]);
+        assert_eq!(extract(&gz, "GeoLite2-ASN.mmdb"), Ok(b"the database".to_vec()));
+        assert_eq!(extract(&gz, "COPYRIGHT.txt"), Ok(b"copyright".to_vec()));
+        assert!(extract(&gz, "GeoLite2-City.mmdb").is_err());
+        assert!(extract(&gz[..gz.len() - 20], "GeoLite2-ASN.mmdb").is_err());
+
+        let digest = to_hex(&Sha256::digest(&gz));
+        let checksum = parse_checksum(&format!("{}  GeoLite2-ASN_20240101.tar.gz\n", digest.to_uppercase()));
+        assert_eq!(checksum.as_deref(), Some(digest.as_str()));
+        assert_eq!(parse_checksum("not a digest"), None);
+        assert_eq!(verify(&gz, &digest), Ok(()));
+        assert!(verify(&gz[1..], &digest).is_err());
+    }
+
+    #[test]
+    fn installs() {
+        let dir = std::env::temp_dir().join(format!("curiefense-geoupdate-{}", std::process::id()));
+        let path = dir.join("GeoLite2-ASN.mmdb");
+        let day = Duration::from_secs(86400);
+        assert!(is_stale(&path, day, SystemTime::now()));
+
+        install(&dir, "GeoLite2-ASN.mmdb", b"the database").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"the database");
+        assert!(!is_stale(&path, day, SystemTime::now()));
+        assert!(is_stale(&path, day, SystemTime::now() + day));
+        assert!(!dir.join(".GeoLite2-ASN.mmdb.download").exists());
+
+        let raw: RawGeoipUpdates = serde_json::from_str(r#"{"license_key": "key"}"#).unwrap();
+        assert_eq!(raw.editions.len(), 3);
+        assert_eq!(raw.interval, 86400);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use crate::grasshopper::DummyGrasshopper;
+use crate::inspect_generic_request_map;
+use crate::logs::{LogLevel, Logs};
+use crate::metadata::DynamicMetadata;
+use crate::replay::Verdict;
+use crate::utils::InspectionRequest;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const UNRECORDED_TAG_PREFIXES: [&str; 2] = ["container:", "geo-"];
+
+#[derive(Debug, Deserialize)]
+pub struct Fixture {
+    pub name: String,
+    pub config: String,
+    pub request: InspectionRequest,
+    #[serde(default)]
+    pub expected: Option<Expected>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expected {
+    #[serde(flatten)]
+    pub verdict: Verdict,
+    pub tags: Vec<String>,
+}
+
+/// a fixture whose decision is not the recorded one
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub name: String,
+    pub expected: Option<Expected>,
+    pub actual: Expected,
+}
+
+/// the fixture files of the directory, sorted by name
+fn fixture_paths(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|rr| format!("{}: {}", dir.display(), rr))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|rr| format!("{}: {}", dir.display(), rr))?.path();
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn read_fixture(path: &Path) -> Result<(serde_json::Value, Fixture), String> {
+    let content = std::fs::read_to_string(path).map_err(|rr| format!("{}: {}", path.display(), rr))?;
+    let value: serde_json::Value =
+        serde_json::from_str(&content).map_err(|rr| format!("{}: {}", path.display(), rr))?;
+    let fixture = serde_json::from_value(value.clone()).map_err(|rr| format!("{}: {}", path.display(), rr))?;
+    Ok((value, fixture))
+}
+
+/// the current decision of the fixture, `dir` being the directory of the fixture
+pub fn inspect(dir: &Path, fixture: &Fixture) -> Result<Expected, String> {
+    let configpath = dir.join(&fixture.config);
+    let raw = fixture
+        .request
+        .to_raw()
+        .map_err(|rr| format!("{}: {}", fixture.name, rr))?;
+    let mut logs = Logs::new(LogLevel::Error);
+    let (decision, tags, _) =
+        inspect_generic_request_map(&configpath.to_string_lossy(), None::<DummyGrasshopper>, raw, &mut logs);
+    let metadata = DynamicMetadata::new(&decision, &tags);
+    let tags = metadata
+        .tags
+        .iter()
+        .filter(|t| !UNRECORDED_TAG_PREFIXES.iter().any(|p| t.starts_with(p)))
+        .cloned()
+        .collect();
+    Ok(Expected {
+        verdict: Verdict::new(&metadata),
+        tags,
+    })
+}
+
+/// the fixtures of the directory whose decision changed
+pub fn check(dir: &Path) -> Result<Vec<Mismatch>, String> {
+    let mut mismatches = Vec::new();
+    for path in fixture_paths(dir)? {
+        let (_, fixture) = read_fixture(&path)?;
+        let actual = inspect(dir, &fixture)?;
+        if fixture.expected.as_ref() != Some(&actual) {
+            mismatches.push(Mismatch {
+                path,
+                name: fixture.name,
+                expected: fixture.expected,
+                actual,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// records the current decisions of the fixtures of the directory, returning the number of updated fixtures
+pub fn update(dir: &Path) -> Result<usize, String> {
+    let mut updated = 0;
+    for path in fixture_paths(dir)? {
+        let (mut value, fixture) = read_fixture(&path)?;
+        let actual = inspect(dir, &fixture)?;
+        if fixture.expected.as_ref() == Some(&actual) {
+            continue;
+        }
+        value["expected"] = serde_json::to_value(&actual).map_err(|rr| rr.to_string())?;
+        let content = serde_json::to_string_pretty(&value).map_err(|rr| rr.to_string())?;
+        std::fs::write(&path, content + "\n").map_err(|rr| format!("{}: {}", path.display(), rr))?;
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+// the fixtures and their configurations are read from their directories
+#[cfg(all(test, feature = "file-config"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden");
+        if std::env::var("CURIEFENSE_GOLDEN_UPDATE").is_ok() {
+            update(&dir).unwrap();
+        }
+        let mismatches = check(&dir).unwrap();
+        assert!(
+            mismatches.is_empty(),
+            "{}",
+            serde_json::to_string_pretty(&mismatches).unwrap()
+        );
+    }
+}
@@ -1,16 +1,46 @@
+use crate::challenge::NativeChallenge;
+use crate::config::hostmap::ChallengePolicy;
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+#[cfg(feature = "redis")]
+use crate::redis::redis_async_conn;
 use crate::requestfields::RequestField;
+use crate::utils::RequestInfo;
 use crate::{Action, ActionType, Decision};
 use serde_json::json;
 use std::collections::HashMap;
 
+/// the `seed` arguments are the client user agent, and `None` is returned on internal failures
 pub trait Grasshopper {
+    /// the javascript library of the challenge page, that must define a `winsocks` function
     fn js_app(&self) -> Option<String>;
     fn js_bio(&self) -> Option<String>;
+    /// checks the `rbzid` cookie, returning whether the client is a verified human
     fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool>;
+    /// the seed embedded in the challenge page
     fn gen_new_seed(&self, seed: &str) -> Option<String>;
+    /// checks the proof sent in the `x-zebra-` header by the challenge page, returning the `rbzid` cookie
     fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String>;
 }
 
+impl<GH: Grasshopper> Grasshopper for &GH {
+    fn js_app(&self) -> Option<String> {
+        (*self).js_app()
+    }
+    fn js_bio(&self) -> Option<String> {
+        (*self).js_bio()
+    }
+    fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool> {
+        (*self).parse_rbzid(rbzid, seed)
+    }
+    fn gen_new_seed(&self, seed: &str) -> Option<String> {
+        (*self).gen_new_seed(seed)
+    }
+    fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String> {
+        (*self).verify_workproof(workproof, seed)
+    }
+}
+
 pub struct DummyGrasshopper {}
 
 // use this when grasshopper can't be used
@@ -32,16 +62,155 @@ impl Grasshopper for DummyGrasshopper {
     }
 }
 
+/// a deterministic implementation, for tests
+///
+/// the seed is `seed:<ua>`, the expected work proof `proof:seed:<ua>`, and the cookie `human:<ua>`
+pub struct MockGrasshopper {}
+
+impl Grasshopper for MockGrasshopper {
+    fn js_app(&self) -> Option<String> {
+        Some("function winsocks(){}".to_string())
+    }
+    fn js_bio(&self) -> Option<String> {
+        None
+    }
+    fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool> {
+        Some(rbzid.strip_prefix("human:") == Some(seed))
+    }
+    fn gen_new_seed(&self, seed: &str) -> Option<String> {
+        Some(format!("seed:{}", seed))
+    }
+    fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String> {
+        if workproof.strip_prefix("proof:seed:") == Some(seed) {
+            Some(format!("human:{}", seed))
+        } else {
+            None
+        }
+    }
+}
+
+/// the grasshopper component when it is available, the native challenge otherwise
+pub enum Challenger<GH> {
+    External(GH),
+    Native(NativeChallenge),
+}
+
+impl<GH: Grasshopper> Grasshopper for Challenger<GH> {
+    fn js_app(&self) -> Option<String> {
+        match self {
+            Challenger::External(gh) => gh.js_app(),
+            Challenger::Native(n) => n.js_app(),
+        }
+    }
+    fn js_bio(&self) -> Option<String> {
+        match self {
+            Challenger::External(gh) => gh.js_bio(),
+            Challenger::Native(n) => n.js_bio(),
+        }
+    }
+    fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool> {
+        match self {
+            Challenger::External(gh) => gh.parse_rbzid(rbzid, seed),
+            Challenger::Native(n) => n.parse_rbzid(rbzid, seed),
+        }
+    }
+    fn gen_new_seed(&self, seed: &str) -> Option<String> {
+        match self {
+            Challenger::External(gh) => gh.gen_new_seed(seed),
+            Challenger::Native(n) => n.gen_new_seed(seed),
+        }
+    }
+    fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String> {
+        match self {
+            Challenger::External(gh) => gh.verify_workproof(workproof, seed),
+            Challenger::Native(n) => n.verify_workproof(workproof, seed),
+        }
+    }
+}
+
+/// counts a challenge served to the session, returning the attempts in the window
+#[cfg(feature = "redis")]
+async fn count_attempts(key: &str, window: u64) -> anyhow::Result<u64> {
+    let mut redis = redis_async_conn().await?;
+    let attempts: u64 = redis::cmd("INCR").arg(key).query_async(&mut redis).await?;
+    if attempts == 1 {
+        let _: () = redis::cmd("EXPIRE")
+            .arg(key)
+            .arg(window)
+            .query_async(&mut redis)
+            .await?;
+    }
+    Ok(attempts)
+}
+
+/// without redis, the attempts are counted by the local counters
+#[cfg(not(feature = "redis"))]
+async fn count_attempts(key: &str, window: u64) -> anyhow::Result<u64> {
+    let local = crate::counters::LOCAL
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("the local counters are not available"))?;
+    Ok(local.hit(key, None, window, crate::counters::now()))
+}
+
+/// blocks the sessions that were served more than `max_attempts` challenges in the attempts window
+pub async fn limit_challenges(
+    logs: &mut Logs,
+    secpolname: &str,
+    policy: &ChallengePolicy,
+    rinfo: &RequestInfo,
+    decision: Decision,
+) -> Decision {
+    let max_attempts = match policy.max_attempts {
+        None => return decision,
+        Some(m) => m,
+    };
+    let is_challenge = match &decision {
+        Decision::Action(a) => a
+            .extra_tags
+            .as_ref()
+            .map(|t| t.contains("challenge_phase01"))
+            .unwrap_or(false),
+        Decision::Pass => false,
+    };
+    if !is_challenge {
+        return decision;
+    }
+    let key = format!(
+        "{:X}",
+        md5::compute(format!("challenge-attempts{}{}", secpolname, rinfo.session))
+    );
+    match count_attempts(&key, policy.attempts_window).await {
+        Err(rr) => {
+            logs.error(|| format!("Could not count the challenge attempts: {}", rr));
+            decision
+        }
+        Ok(attempts) if attempts > max_attempts => {
+            logs.debug(|| format!("session challenged {} times, blocking", attempts));
+            Decision::Action(Action {
+                status: 403,
+                reason: Reason::new(Initiator::Phase01)
+                    .with_message("too many challenge attempts")
+                    .with_details(json!({ "attempts": attempts, "max_attempts": max_attempts })),
+                extra_tags: Some(std::iter::once("challenge_exhausted".to_string()).collect()),
+                ..Action::default()
+            })
+        }
+        Ok(_) => decision,
+    }
+}
+
 pub fn gh_fail_decision(reason: &str) -> Decision {
     Decision::Action(Action {
         atype: ActionType::Block,
         block_mode: true,
         ban: false,
-        reason: json!({"initiator": "phase01", "reason": reason}),
+        reason: Reason::new(Initiator::Phase01).with_message(reason),
         headers: None,
         status: 500,
         content: "internal_error".to_string(),
         extra_tags: None,
+        template: None,
+        mutations: Vec::new(),
     })
 }
 
@@ -81,17 +250,16 @@ pub fn challenge_phase01<GH: Grasshopper>(gh: &GH, ua: &str, tags: Vec<String>)
         atype: ActionType::Block,
         block_mode: true,
         ban: false,
-        reason: if tags.is_empty() {
-            // this happens for rate limit / flow control / tag action
-            json!({"initiator": "phase01", "reason": "challenge"})
-        } else {
-            // this only happens for acl challenges
-            json!({"initiator": "phase01", "reason": "challenge", "tags": tags})
-        },
+        // tags are only set for acl challenges, not for rate limit / flow control / tag action
+        reason: Reason::new(Initiator::Phase01)
+            .with_message("challenge")
+            .with_tags(&tags),
         headers: Some(hdrs),
         status: 247,
         content,
         extra_tags: Some(["challenge_phase01"].iter().map(|s| s.to_string()).collect()),
+        template: None,
+        mutations: Vec::new(),
     })
 }
 
@@ -104,7 +272,13 @@ fn extract_zebra(headers: &RequestField) -> Option<String> {
     None
 }
 
-pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestField) -> Option<Decision> {
+/// `cookie_ttl` sets the lifetime of the cookie, that is otherwise a session cookie
+pub fn challenge_phase02<GH: Grasshopper>(
+    gh: &GH,
+    uri: &str,
+    headers: &RequestField,
+    cookie_ttl: Option<u64>,
+) -> Option<Decision> {
     if !uri.starts_with("/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/") {
         return None;
     }
@@ -115,6 +289,9 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestF
     let mut cookie = "rbzid=".to_string();
     cookie += &verified.replace('=', "-");
     cookie += "; Path=/; HttpOnly";
+    if let Some(ttl) = cookie_ttl {
+        cookie += &format!("; Max-Age={}", ttl);
+    }
 
     nheaders.insert("Set-Cookie".to_string(), cookie);
 
@@ -122,10 +299,65 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestF
         atype: ActionType::Block,
         block_mode: true,
         ban: false,
-        reason: json!({"initiator": "phase02", "reason": "challenge"}),
+        reason: Reason::new(Initiator::Phase02).with_message("challenge"),
         headers: Some(nheaders),
         status: 248,
         content: "{}".to_string(),
         extra_tags: Some(["challenge_phase02"].iter().map(|s| s.to_string()).collect()),
+        template: None,
+        mutations: Vec::new(),
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::utils::{DataSource, XDataSource};
+
+    fn headers(hdrs: &[(&str, &str)]) -> RequestField {
+        let ds = DataSource::X(XDataSource::Uri);
+        let content: Vec<(&str, &DataSource, &str)> = hdrs.iter().map(|(k, v)| (*k, &ds, *v)).collect();
+        RequestField::raw_create(&[], &content)
+    }
+
+    const PHASE02: &str = "/7060ac19f50208cbb6b45328ef94140a612ee92387e015594234077b4d1e64f1/1";
+
+    #[test]
+    fn mock_challenge() {
+        let gh = MockGrasshopper {};
+        let page = match challenge_phase01(&gh, "ua", Vec::new()) {
+            Decision::Action(a) => a,
+            Decision::Pass => panic!("the challenge should block"),
+        };
+        assert_eq!(page.status, 247);
+        assert!(page.content.contains("function winsocks(){}"));
+        assert!(page.content.contains("seed: \"seed:ua\""));
+
+        let hdrs = headers(&[("user-agent", "ua"), ("x-zebra-proof", "proof:seed:ua")]);
+        let verified = match challenge_phase02(&gh, PHASE02, &hdrs, Some(60)) {
+            Some(Decision::Action(a)) => a,
+            _ => panic!("the work proof should be accepted"),
+        };
+        assert_eq!(verified.status, 248);
+        assert_eq!(
+            verified.headers.unwrap().get("Set-Cookie").map(|s| s.as_str()),
+            Some("rbzid=human:ua; Path=/; HttpOnly; Max-Age=60")
+        );
+        assert_eq!(gh.parse_rbzid("human:ua", "ua"), Some(true));
+        assert_eq!(gh.parse_rbzid("human:ua", "other"), Some(false));
+    }
+
+    #[test]
+    fn mock_challenge_rejections() {
+        let gh = MockGrasshopper {};
+        let bad_proof = headers(&[("user-agent", "ua"), ("x-zebra-proof", "proof:seed:other")]);
+        assert!(challenge_phase02(&gh, PHASE02, &bad_proof, None).is_none());
+        let good_proof = headers(&[("user-agent", "ua"), ("x-zebra-proof", "proof:seed:ua")]);
+        assert!(challenge_phase02(&gh, "/other", &good_proof, None).is_none());
+        // backends that fail result in an internal error
+        assert!(matches!(
+            challenge_phase01(&DummyGrasshopper {}, "ua", Vec::new()),
+            Decision::Action(Action { status: 500, .. })
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::config::raw::{RawHarvestingSettings, RiskIdentity};
+use crate::counters;
+use crate::interface::Tags;
+use crate::utils::RequestInfo;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref SEQUENCES: Mutex<Tracker> = Mutex::new(Tracker::default());
+}
+
+/// identity, path and parameter
+type SequenceKey = (String, String, String);
+
+#[derive(Debug, Clone)]
+pub struct HarvestingDetection {
+    max_ranges: usize,
+    parameters: Vec<String>,
+    path_ids: bool,
+    identity: RiskIdentity,
+    min_sequence: usize,
+    /// in milliseconds
+    window: u64,
+    max_tracked: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeAbuse {
+    Count,
+    Overlap,
+}
+
+impl RangeAbuse {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RangeAbuse::Count => "count",
+            RangeAbuse::Overlap => "overlap",
+        }
+    }
+}
+
+/// the abuse of a `Range` header, such as `bytes=0-99,200-`, the suffix ranges (`-500`) never overlapping
+pub fn range_abuse(header: &str, max_ranges: usize) -> Option<RangeAbuse> {
+    let (unit, specs) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let ranges: Vec<&str> = specs.split(',').map(str::trim).filter(|r| !r.is_empty()).collect();
+    if ranges.len() > max_ranges {
+        return Some(RangeAbuse::Count);
+    }
+    let mut bounds: Vec<(u64, u64)> = ranges
+        .iter()
+        .filter_map(|r| {
+            let (start, end) = r.split_once('-')?;
+            let start = start.trim().parse::<u64>().ok()?;
+            match end.trim() {
+                "" => Some((start, u64::MAX)),
+                end => end.parse::<u64>().ok().map(|end| (start, end)),
+            }
+        })
+        .collect();
+    bounds.sort_unstable();
+    if bounds.windows(2).any(|w| w[1].0 <= w[0].1) {
+        Some(RangeAbuse::Overlap)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sequence {
+    last: i64,
+    step: i64,
+    length: usize,
+    /// in milliseconds
+    seen: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Tracker {
+    sequences: HashMap<SequenceKey, Sequence>,
+}
+
+impl Tracker {
+    /// records the value, returning the length of the sequence it extends, 0 when it could not be tracked
+    pub fn record(&mut self, settings: &HarvestingDetection, key: SequenceKey, value: i64, now: u64) -> usize {
+        if !self.sequences.contains_key(&key) && self.sequences.len() >= settings.max_tracked {
+            self.sequences
+                .retain(|_, s| now.saturating_sub(s.seen) < settings.window);
+            if self.sequences.len() >= settings.max_tracked {
+                return 0;
+            }
+        }
+        let fresh = Sequence {
+            last: value,
+            step: 0,
+            length: 1,
+            seen: now,
+        };
+        let sequence = self.sequences.entry(key).or_insert(Sequence { length: 0, ..fresh });
+        let step = value.wrapping_sub(sequence.last);
+        if sequence.length == 0 || now.saturating_sub(sequence.seen) >= settings.window {
+            *sequence = fresh;
+        } else if step == 0 {
+            // the same value, requested again, does not break the sequence
+            sequence.seen = now;
+        } else if sequence.length == 1 || step == sequence.step {
+            *sequence = Sequence {
+                step,
+                length: sequence.length + 1,
+                ..fresh
+            };
+        } else {
+            // another sequence starts from the previous value
+            *sequence = Sequence {
+                step,
+                length: 2,
+                ..fresh
+            };
+        }
+        sequence.length
+    }
+}
+
+fn tracker() -> std::sync::MutexGuard<'static, Tracker> {
+    match SEQUENCES.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl HarvestingDetection {
+    pub fn resolve(raw: &RawHarvestingSettings) -> Self {
+        HarvestingDetection {
+            max_ranges: raw.max_ranges.max(1),
+            parameters: raw.parameters.clone(),
+            path_ids: raw.path_ids,
+            identity: raw.identity,
+            min_sequence: raw.min_sequence.max(2),
+            window: raw.window.max(1) * 1000,
+            max_tracked: raw.max_tracked,
+        }
+    }
+
+    /// the tracked values of the request: their path, parameter and value
+    fn values(&self, reqinfo: &RequestInfo) -> Vec<(String, String, i64)> {
+        let qinfo = &reqinfo.rinfo.qinfo;
+        let mut values: Vec<(String, String, i64)> = self
+            .parameters
+            .iter()
+            .filter_map(|param| {
+                let value = qinfo.args.get_str(param)?.trim().parse().ok()?;
+                Some((qinfo.canonical_path.clone(), param.clone(), value))
+            })
+            .collect();
+        if self.path_ids {
+            if let Some((prefix, last)) = qinfo.canonical_path.rsplit_once('/') {
+                if let Ok(value) = last.parse() {
+                    values.push((format!("{}/*", prefix), "path".to_string(), value));
+                }
+            }
+        }
+        values
+    }
+
+    /// tags the range abuses and the enumerations
+    pub fn tag(&self, reqinfo: &RequestInfo, tags: &mut Tags) {
+        if let Some(abuse) = reqinfo
+            .headers
+            .get_str("range")
+            .and_then(|r| range_abuse(r, self.max_ranges))
+        {
+            tags.insert("range-abuse");
+            tags.insert_qualified("range-abuse", abuse.as_str());
+        }
+
+        let identity = match self.identity {
+            RiskIdentity::Session => &reqinfo.session,
+            RiskIdentity::Ip => &reqinfo.rinfo.geoip.ipstr,
+            RiskIdentity::Fingerprint => &reqinfo.fingerprint,
+        };
+        let values = self.values(reqinfo);
+        if identity.is_empty() || values.is_empty() {
+            return;
+        }
+        let now = counters::now();
+        let enumerated: Vec<String> = {
+            let mut tracker = tracker();
+            values
+                .into_iter()
+                .filter(|(path, param, value)| {
+                    let key = (identity.clone(), path.clone(), param.clone());
+                    tracker.record(self, key, *value, now) >= self.min_sequence
+                })
+                .map(|(_, param, _)| param)
+                .collect()
+        };
+        if !enumerated.is_empty() {
+            tags.insert("enumeration");
+        }
+        for param in enumerated {
+            tags.insert_qualified("enumeration", &param);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    fn settings() -> HarvestingDetection {
+        let raw: RawHarvestingSettings =
+            serde_json::from_str(r#"{"min_sequence": 4, "window": 60, "identity": "ip"}"#).unwrap();
+        HarvestingDetection::resolve(&raw)
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range_abuse("bytes=0-99", 10), None);
+        assert_eq!(range_abuse("bytes=0-99, 200-299, 500-", 10), None);
+        assert_eq!(range_abuse("bytes=-500, 0-99", 10), None);
+        let tiny: Vec<String> = (0..200).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        assert_eq!(
+            range_abuse(&format!("bytes={}", tiny.join(",")), 10),
+            Some(RangeAbuse::Count)
+        );
+        assert_eq!(range_abuse("bytes=0-99,50-150", 10), Some(RangeAbuse::Overlap));
+        assert_eq!(range_abuse("Bytes=0-,0-", 10), Some(RangeAbuse::Overlap));
+        assert_eq!(range_abuse("items=0-1,0-1", 10), None);
+    }
+
+    #[test]
+    fn sequences() {
+        let settings = settings();
+        let mut tracker = Tracker::default();
+        let key = |param: &str| ("1.2.3.4".to_string(), "/items".to_string(), param.to_string());
+        let lengths: Vec<usize> = [0, 20, 20, 40, 60]
+            .iter()
+            .enumerate()
+            .map(|(i, v)| tracker.record(&settings, key("offset"), *v, i as u64 * 1000))
+            .collect();
+        assert_eq!(lengths, vec![1, 2, 2, 3, 4]);
+        // another step starts another sequence
+        assert_eq!(tracker.record(&settings, key("offset"), 61, 6000), 2);
+        // a pause longer than the window too
+        assert_eq!(tracker.record(&settings, key("offset"), 62, 67_000), 1);
+        assert_eq!(tracker.record(&settings, key("page"), 3, 67_000), 1);
+    }
+
+    #[test]
+    fn tags() {
+        let settings = settings();
+        let mut tagged = Vec::new();
+        for page in 1..6 {
+            let tag = |path: String| {
+                let reqinfo = RequestBuilder::get(&path)
+                    .ip("192.0.2.77")
+                    .header("range", "bytes=0-10,5-20")
+                    .request_info();
+                let mut tags = Tags::default();
+                settings.tag(&reqinfo, &mut tags);
+                assert!(tags.contains("range-abuse:overlap"));
+                tags
+            };
+            let listing = tag(format!("/list?page={}", page));
+            let profile = tag(format!("/users/{}", 1000 - page));
+            tagged.push((
+                listing.contains("enumeration:page"),
+                profile.contains("enumeration:path"),
+            ));
+        }
+        assert_eq!(
+            tagged,
+            vec![
+                (false, false),
+                (false, false),
+                (false, false),
+                (true, true),
+                (true, true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::ipset::parse_network;
+use ipnet::{IpNet, Ipv4Subnets, Ipv6Subnets};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+/// the numeric value of an address, 128 bits wide to fit IPv6 addresses
+pub fn ip_to_num(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(a) => u32::from(a) as u128,
+        IpAddr::V6(a) => u128::from(a),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    /// 64 bits FNV-1a, much faster than the cryptographic hashes
+    Fnv1a,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: Option<&str>) -> Result<Self, String> {
+        match name {
+            None | Some("md5") => Ok(HashAlgorithm::Md5),
+            Some("sha256") => Ok(HashAlgorithm::Sha256),
+            Some("fnv1a") => Ok(HashAlgorithm::Fnv1a),
+            Some(other) => Err(format!(
+                "unknown hash algorithm {}, expected md5, sha256 or fnv1a",
+                other
+            )),
+        }
+    }
+
+    /// the first 64 bits of the digest
+    pub fn hash(self, data: &[u8]) -> u64 {
+        let first = |digest: &[u8]| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&digest[..8]);
+            u64::from_be_bytes(bytes)
+        };
+        match self {
+            HashAlgorithm::Md5 => first(&md5::compute(data).0),
+            HashAlgorithm::Sha256 => first(&Sha256::digest(data)),
+            HashAlgorithm::Fnv1a => data.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+                (h ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            }),
+        }
+    }
+}
+
+/// hashes the data into `0..modulo`
+pub fn modhash(data: &[u8], modulo: u64, algorithm: HashAlgorithm) -> Result<u64, String> {
+    if modulo == 0 {
+        return Err("the modulo must be positive".to_string());
+    }
+    Ok(algorithm.hash(data) % modulo)
+}
+
+/// the network (or address) `inner` is included in `outer`
+pub fn cidr_contains(outer: &str, inner: &str) -> Result<bool, String> {
+    Ok(parse_network(outer)?.contains(&parse_network(inner)?))
+}
+
+/// the networks have addresses in common
+pub fn cidr_overlaps(a: &str, b: &str) -> Result<bool, String> {
+    let (a, b) = (parse_network(a)?, parse_network(b)?);
+    Ok(a.contains(&b) || b.contains(&a))
+}
+
+/// the smallest list of networks covering the addresses from `start` to `end`, included
+pub fn range_to_cidrs(start: &str, end: &str) -> Result<Vec<IpNet>, String> {
+    let parse = |s: &str| s.trim().parse::<IpAddr>().map_err(|_| format!("invalid address {}", s));
+    match (parse(start)?, parse(end)?) {
+        (s, e) if ip_to_num(s) > ip_to_num(e) => Err(format!("{} is after {}", start, end)),
+        (IpAddr::V4(s), IpAddr::V4(e)) => Ok(Ipv4Subnets::new(s, e, 0).map(IpNet::V4).collect()),
+        (IpAddr::V6(s), IpAddr::V6(e)) => Ok(Ipv6Subnets::new(s, e, 0).map(IpNet::V6).collect()),
+        _ => Err(format!("{} and {} are not in the same address family", start, end)),
+    }
+}
+
+/// merges the overlapping and adjacent networks, IPv4 networks first
+pub fn aggregate_cidrs<S: AsRef<str>>(networks: &[S]) -> Result<Vec<IpNet>, String> {
+    let networks = networks
+        .iter()
+        .map(|n| parse_network(n.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(IpNet::aggregate(&networks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_numbers_and_hashes() {
+        assert_eq!(ip_to_num("1.2.3.4".parse().unwrap()), 0x0102_0304);
+        assert_eq!(
+            ip_to_num("2001:db8::1".parse().unwrap()),
+            0x2001_0db8_0000_0000_0000_0000_0000_0001
+        );
+
+        // d41d8cd98f00b204e9800998ecf8427e, e3b0c44298fc1c149afbf4c8996fb924...
+        assert_eq!(HashAlgorithm::Md5.hash(b""), 0xd41d_8cd9_8f00_b204);
+        assert_eq!(HashAlgorithm::Sha256.hash(b""), 0xe3b0_c442_98fc_1c14);
+        assert_eq!(HashAlgorithm::Fnv1a.hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let algorithm = HashAlgorithm::from_name(Some("sha256")).unwrap();
+        assert_eq!(modhash(b"", 1000, algorithm), Ok(0xe3b0_c442_98fc_1c14 % 1000));
+        assert!(modhash(b"x", 0, algorithm).is_err());
+        assert!(HashAlgorithm::from_name(Some("crc32")).is_err());
+    }
+
+    fn strings(nets: Result<Vec<IpNet>, String>) -> Vec<String> {
+        nets.unwrap().iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn cidrs() {
+        assert_eq!(cidr_contains("10.0.0.0/8", "10.1.0.0/16"), Ok(true));
+        assert_eq!(cidr_contains("10.1.0.0/16", "10.0.0.0/8"), Ok(false));
+        assert_eq!(cidr_contains("2001:db8::/32", "2001:db8::1"), Ok(true));
+        assert_eq!(cidr_contains("10.0.0.0/8", "::a00:1"), Ok(false));
+        assert!(cidr_contains("10.0.0.0/33", "10.0.0.1").is_err());
+
+        assert_eq!(cidr_overlaps("10.1.0.0/16", "10.0.0.0/8"), Ok(true));
+        assert_eq!(cidr_overlaps("10.1.0.0/16", "10.2.0.0/16"), Ok(false));
+
+        assert_eq!(
+            strings(range_to_cidrs("10.0.0.1", "10.0.0.10")),
+            vec![
+                "10.0.0.1/32",
+                "10.0.0.2/31",
+                "10.0.0.4/30",
+                "10.0.0.8/31",
+                "10.0.0.10/32"
+            ]
+        );
+        assert_eq!(strings(range_to_cidrs("::", "::ffff")), vec!["::/112"]);
+        assert!(range_to_cidrs("10.0.0.2", "10.0.0.1").is_err());
+        assert!(range_to_cidrs("10.0.0.1", "::1").is_err());
+
+        assert_eq!(
+            strings(aggregate_cidrs(&[
+                "10.0.1.0/24",
+                "2001:db8::/33",
+                "10.0.0.0/24",
+                "10.0.0.7",
+                "2001:db8:8000::/33"
+            ])),
+            vec!["10.0.0.0/23", "2001:db8::/32"]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use crate::accesslog::format_timestamp;
+use crate::acl::column_matches;
+use crate::config::raw::AclProfile;
+use crate::interface::Tags;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+lazy_static! {
+    pub static ref HITS: HitCounters = HitCounters::default();
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Hits {
+    /// the last configuration load
+    pub since: String,
+    /// number of requests that got each tag
+    pub tags: BTreeMap<String, u64>,
+    /// number of requests whose tags matched, by profile id, column and tag
+    pub acl: BTreeMap<String, BTreeMap<String, BTreeMap<String, u64>>>,
+    /// number of values that matched each signature, including the matches that were ignored
+    pub signatures: BTreeMap<String, u64>,
+}
+
+impl Default for Hits {
+    fn default() -> Self {
+        Hits {
+            since: format_timestamp(SystemTime::now()),
+            tags: BTreeMap::new(),
+            acl: BTreeMap::new(),
+            signatures: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HitCounters {
+    hits: Mutex<Hits>,
+}
+
+impl HitCounters {
+    /// clears the counters, listing the known tags and signatures
+    pub fn reset<T: Iterator<Item = String>, S: Iterator<Item = String>>(&self, tags: T, signatures: S) {
+        if let Ok(mut hits) = self.hits.lock() {
+            *hits = Hits {
+                tags: tags.map(|t| (t, 0)).collect(),
+                signatures: signatures.map(|s| (s, 0)).collect(),
+                ..Hits::default()
+            };
+        }
+    }
+
+    pub fn record_tags(&self, tags: &Tags) {
+        if let Ok(mut hits) = self.hits.lock() {
+            for tag in tags.iter() {
+                *hits.tags.entry(tag.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn record_acl(&self, tags: &Tags, acl: &AclProfile) {
+        let matches = column_matches(tags, acl);
+        if matches.iter().all(|(_, m)| m.is_empty()) {
+            return;
+        }
+        if let Ok(mut hits) = self.hits.lock() {
+            let profile = hits.acl.entry(acl.id.clone()).or_default();
+            for (column, matching) in matches {
+                for tag in matching {
+                    *profile
+                        .entry(column.to_string())
+                        .or_default()
+                        .entry(tag.clone())
+                        .or_default() += 1;
+                }
+            }
+        }
+    }
+
+    pub fn record_signature(&self, id: &str) {
+        if let Ok(mut hits) = self.hits.lock() {
+            *hits.signatures.entry(id.to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> Hits {
+        self.hits.lock().map(|h| h.clone()).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.snapshot()).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let counters = HitCounters::default();
+        counters.reset(
+            vec!["gf-tag".to_string()].into_iter(),
+            vec!["100".to_string(), "101".to_string()].into_iter(),
+        );
+        let mut tags = Tags::default();
+        tags.insert("all");
+        tags.insert("bad-bot");
+        counters.record_tags(&tags);
+        counters.record_tags(&tags);
+        counters.record_signature("100");
+        let acl = AclProfile {
+            id: "acl1".to_string(),
+            deny_bot: ["bad-bot".to_string()].iter().cloned().collect(),
+            ..AclProfile::default()
+        };
+        counters.record_acl(&tags, &acl);
+        counters.record_acl(&Tags::default(), &acl);
+
+        let hits = counters.snapshot();
+        assert_eq!(hits.tags["all"], 2);
+        assert_eq!(hits.tags["gf-tag"], 0);
+        assert_eq!(hits.signatures["100"], 1);
+        assert_eq!(hits.signatures["101"], 0);
+        assert_eq!(hits.acl["acl1"]["deny_bot"]["bad-bot"], 1);
+        assert_eq!(hits.acl["acl1"].len(), 1);
+
+        counters.reset(std::iter::empty(), std::iter::empty());
+        assert!(counters.snapshot().tags.is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::raw::RawHoneypot;
+use crate::interface::{Action, Tags};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::utils::RequestInfo;
+use regex::RegexSet;
+
+#[derive(Debug, Clone)]
+pub struct Honeypot {
+    paths: RegexSet,
+    args: Vec<String>,
+    pub ban_duration: u64,
+}
+
+impl Honeypot {
+    pub fn resolve(logs: &mut Logs, raw: &RawHoneypot) -> Self {
+        let paths: Vec<&String> = raw
+            .paths
+            .iter()
+            .filter(|p| match regex::Regex::new(p) {
+                Ok(_) => true,
+                Err(rr) => {
+                    logs.error(|| format!("Invalid honeypot path {}: {}", p, rr));
+                    false
+                }
+            })
+            .collect();
+        Honeypot {
+            paths: RegexSet::new(paths).unwrap_or_else(|_| RegexSet::empty()),
+            args: raw.args.clone(),
+            ban_duration: raw.ban_duration,
+        }
+    }
+
+    /// the decoy touched by the request, if any
+    pub fn trap(&self, reqinfo: &RequestInfo) -> Option<(&'static str, String)> {
+        let path = &reqinfo.rinfo.qinfo.canonical_path;
+        if self.paths.is_match(path) {
+            return Some(("path", path.clone()));
+        }
+        self.args
+            .iter()
+            .find(|a| {
+                reqinfo
+                    .rinfo
+                    .qinfo
+                    .args
+                    .get_str(a)
+                    .map(|v| !v.is_empty())
+                    .unwrap_or(false)
+            })
+            .map(|a| ("arg", a.clone()))
+    }
+
+    /// the blocking action, when the request touched a decoy, the session being banned once the decision is final
+    pub fn check(&self, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Action> {
+        let (kind, decoy) = self.trap(reqinfo)?;
+        tags.insert("honeypot");
+        tags.insert_qualified("honeypot", kind);
+        Some(Action {
+            status: 403,
+            reason: Reason::new(Initiator::Honeypot).with_message(format!("honeypot {} {}", kind, decoy)),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    #[test]
+    fn decoys() {
+        let raw: RawHoneypot =
+            serde_json::from_str(r#"{"paths": ["^/wp-admin", "^/\\.env$", "("], "args": ["website"]}"#).unwrap();
+        let mut logs = Logs::default();
+        let honeypot = Honeypot::resolve(&mut logs, &raw);
+        assert_eq!(logs.logs.len(), 1);
+        assert_eq!(honeypot.ban_duration, 3600);
+
+        let trap = |rq: RequestBuilder| honeypot.trap(&rq.request_info());
+        assert_eq!(
+            trap(RequestBuilder::get("/wp-admin/install.php")),
+            Some(("path", "/wp-admin/install.php".to_string()))
+        );
+        // the path is canonicalized before the match
+        assert_eq!(trap(RequestBuilder::get("/static/../.env")).map(|t| t.0), Some("path"));
+        assert_eq!(trap(RequestBuilder::get("/.envrc")), None);
+        assert_eq!(trap(RequestBuilder::get("/contact?website=")), None);
+        let form = RequestBuilder::post("/contact", "name=a&website=http%3A%2F%2Fspam")
+            .header("content-type", "application/x-www-form-urlencoded");
+        assert_eq!(trap(form), Some(("arg", "website".to_string())));
+
+        let mut tags = Tags::default();
+        let action = honeypot
+            .check(&RequestBuilder::get("/wp-admin").request_info(), &mut tags)
+            .unwrap();
+        assert_eq!(action.status, 403);
+        assert_eq!(action.reason.initiator, Initiator::Honeypot);
+        assert!(tags.contains("honeypot"));
+        assert!(tags.contains("honeypot:path"));
+    }
+}
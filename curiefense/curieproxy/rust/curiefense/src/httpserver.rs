@@ -0,0 +1,222 @@
+use crate::blocklist::{export, ListFormat};
+use crate::config::with_config;
+use crate::crypto::same_secret;
+use crate::grasshopper::DummyGrasshopper;
+use crate::hits::HITS;
+use crate::inspect_generic_request_map;
+use crate::logs::{LogLevel, Logs};
+use crate::metrics::{record_error, record_inspection, METRICS};
+use crate::modes::{mode_name, set_override, ModeOverride};
+use crate::shipper::ship;
+use crate::utils::InspectionRequest;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct InspectionService {
+    configpath: String,
+    loglevel: LogLevel,
+    admin_token: Option<String>,
+}
+
+fn respond(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    if let Ok(ct) = content_type.parse() {
+        response.headers_mut().insert(hyper::header::CONTENT_TYPE, ct);
+    }
+    response
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    respond(
+        status,
+        "application/json",
+        serde_json::json!({ "error": message }).to_string(),
+    )
+}
+
+impl InspectionService {
+    pub fn new(configpath: String, loglevel: LogLevel) -> Self {
+        let admin_token = std::env::var("CURIEFENSE_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        InspectionService {
+            configpath,
+            loglevel,
+            admin_token,
+        }
+    }
+
+    /// runs the inspection, returning the decision in the format of the Lua API
+    pub fn inspect(&self, request: InspectionRequest) -> Result<String, String> {
+        let mut raw = request.to_raw()?;
+        // the clients can't select another configuration than the one of the server
+        raw.meta.config_path = None;
+        let mut logs = Logs::new(self.loglevel);
+        let (decision, tags, rinfo) =
+            inspect_generic_request_map(&self.configpath, None::<DummyGrasshopper>, raw, &mut logs);
+        ship(&decision, &tags, &rinfo, &logs);
+        record_inspection(&decision, &rinfo.request_id, &logs);
+        Ok(decision.to_json(rinfo, tags, logs))
+    }
+
+    fn healthy(&self) -> bool {
+        with_config(&self.configpath, &mut Logs::new(self.loglevel), |_, _| ()).is_some()
+    }
+
+    /// checks the bearer token of the admin calls
+    fn authorized(&self, request: &Request<Body>) -> bool {
+        let token = match &self.admin_token {
+            Some(t) => t,
+            None => return false,
+        };
+        request
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|a| a.to_str().ok())
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .map(|a| same_secret(a.trim().as_bytes(), token.as_bytes()))
+            .unwrap_or(false)
+    }
+
+    /// overrides, or clears the override of, the mode of a host map
+    async fn override_mode(&self, request: Request<Body>) -> Response<Body> {
+        if !self.authorized(&request) {
+            return error(StatusCode::UNAUTHORIZED, "unauthorized".to_string());
+        }
+        let hostmap = request.uri().path().trim_start_matches("/modes/").to_string();
+        if hostmap.is_empty() {
+            return error(StatusCode::NOT_FOUND, "not found".to_string());
+        }
+        let modeoverride = if request.method() == Method::DELETE {
+            None
+        } else {
+            let body = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(b) => b,
+                Err(rr) => return error(StatusCode::BAD_REQUEST, rr.to_string()),
+            };
+            match serde_json::from_slice::<ModeOverride>(&body) {
+                Ok(o) => Some(o),
+                Err(rr) => return error(StatusCode::BAD_REQUEST, format!("Invalid mode: {}", rr)),
+            }
+        };
+        let mode = modeoverride.as_ref().map(|o| o.mode);
+        let ttl = modeoverride.and_then(|o| o.ttl);
+        match set_override(&hostmap, mode, ttl).await {
+            Ok(()) => respond(
+                StatusCode::OK,
+                "application/json",
+                serde_json::json!({ "hostmap": hostmap, "mode": mode.map(mode_name), "ttl": ttl }).to_string(),
+            ),
+            Err(rr) => error(StatusCode::SERVICE_UNAVAILABLE, rr.to_string()),
+        }
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        Ok(match (request.method(), request.uri().path()) {
+            (&Method::POST, "/inspect") => {
+                let body = match hyper::body::to_bytes(request.into_body()).await {
+                    Ok(b) => b,
+                    Err(rr) => return Ok(error(StatusCode::BAD_REQUEST, rr.to_string())),
+                };
+                let inspection: InspectionRequest = match serde_json::from_slice(&body) {
+                    Ok(i) => i,
+                    Err(rr) => return Ok(error(StatusCode::BAD_REQUEST, format!("Invalid request: {}", rr))),
+                };
+                let service = self.clone();
+                match tokio::task::spawn_blocking(move || service.inspect(inspection)).await {
+                    Ok(Ok(decision)) => respond(StatusCode::OK, "application/json", decision),
+                    Ok(Err(rr)) => {
+                        record_error();
+                        error(StatusCode::BAD_REQUEST, rr)
+                    }
+                    Err(rr) => {
+                        record_error();
+                        error(StatusCode::INTERNAL_SERVER_ERROR, rr.to_string())
+                    }
+                }
+            }
+            (&Method::GET, "/healthz") => {
+                if self.healthy() {
+                    respond(StatusCode::OK, "text/plain", "ok\n".to_string())
+                } else {
+                    respond(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "text/plain",
+                        "no configuration\n".to_string(),
+                    )
+                }
+            }
+            (&Method::GET, "/metrics") => {
+                let openmetrics = request
+                    .headers()
+                    .get(hyper::header::ACCEPT)
+                    .and_then(|a| a.to_str().ok())
+                    .map(|a| a.contains("application/openmetrics-text"))
+                    .unwrap_or(false);
+                if openmetrics {
+                    respond(
+                        StatusCode::OK,
+                        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                        METRICS.render_openmetrics(),
+                    )
+                } else {
+                    respond(StatusCode::OK, "text/plain; version=0.0.4", METRICS.render())
+                }
+            }
+            (&Method::GET, "/hits") => respond(StatusCode::OK, "application/json", HITS.to_json()),
+            (&Method::GET, "/blocklist") => blocklist(request.uri().query()),
+            (&Method::PUT, path) | (&Method::DELETE, path) if path.starts_with("/modes/") => {
+                self.override_mode(request).await
+            }
+            _ => error(StatusCode::NOT_FOUND, "not found".to_string()),
+        })
+    }
+}
+
+/// the exported blocklist, in the format of the `format` parameter, `plain` by default
+fn blocklist(query: Option<&str>) -> Response<Body> {
+    let format = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("format="))
+        .unwrap_or("plain");
+    let format: ListFormat = match format.parse() {
+        Ok(f) => f,
+        Err(rr) => return error(StatusCode::BAD_REQUEST, rr),
+    };
+    let content_type = match format {
+        ListFormat::Envoy => "application/json",
+        ListFormat::Plain | ListFormat::Ipset => "text/plain",
+    };
+    match export(format) {
+        Some(list) => respond(StatusCode::OK, content_type, list),
+        None => error(StatusCode::NOT_FOUND, "the blocklist is not exported".to_string()),
+    }
+}
+
+pub async fn serve(addr: SocketAddr, service: InspectionService) -> hyper::Result<()> {
+    let service = Arc::new(service);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| service.clone().handle(request))) }
+    });
+    Server::bind(&addr).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_format() {
+        let request: InspectionRequest = serde_json::from_str(
+            r#"{"ip": "1.2.3.4", "meta": {"method": "GET", "path": "/"}, "headers": {"host": "example.com"}}"#,
+        )
+        .unwrap();
+        assert_eq!(request.body, None);
+        assert_eq!(request.meta.get("method").map(|s| s.as_str()), Some("GET"));
+    }
+}
@@ -9,23 +9,26 @@ use std::collections::HashMap;
 */
 
 use crate::{
-    analyze::analyze,
+    analyze::{analyze, observe},
     body::body_too_large,
+    captcha::captcha_verified,
     challenge_verified,
     config::{
-        contentfilter::SectionIdx,
+        contentfilter::{ContentFilterRules, SectionIdx},
         flow::{FlowElement, SequenceKey},
         globalfilter::GlobalFilterSection,
         hostmap::SecurityPolicy,
         Config,
     },
     contentfilter::ContentFilterBlock,
+    dnsbl,
     grasshopper::Grasshopper,
     interface::{Action, Decision, Tags},
     logs::{LogLevel, Logs},
+    reason::stamp,
     securitypolicy::match_securitypolicy,
     tagging::tag_request,
-    utils::{map_request, RawRequest, RequestInfo, RequestMeta},
+    utils::{map_request_with, RawRequest, RequestInfo, RequestMeta},
 };
 
 pub struct IData<'t> {
@@ -46,7 +49,8 @@ pub fn inspect_init(
     let mut logs = Logs::new(loglevel);
     let mr = match_securitypolicy(
         meta.authority.as_deref().unwrap_or("localhost"),
-        &meta.path,
+        &meta.canonical_path(),
+        &meta,
         config,
         &mut logs,
     );
@@ -64,24 +68,37 @@ pub fn inspect_init(
 }
 
 /// called when the content filter policy is violated
-/// no tags are returned though!
+/// no tags are returned though, except for the observe mode ones!
 fn early_block(idata: IData, action: Action) -> (Decision, Tags, RequestInfo) {
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let rawrequest = RawRequest {
-        ipstr: extract_ip(idata.trusted_hops as usize, &idata.headers),
+        ipstr: idata
+            .meta
+            .client_ip(extract_ip(idata.trusted_hops as usize, &idata.headers)),
         headers: idata.headers,
+        header_bytes: HashMap::new(),
         meta: idata.meta,
         mbody: idata.body.as_deref(),
     };
-    let reqinfo = map_request(
+    let reqinfo = map_request_with(
         &mut logs,
         &secpolicy.content_filter_profile.decoding,
         &secpolicy.content_filter_profile.content_type,
         0,
+        &secpolicy.session,
+        &secpolicy.content_filter_profile.parse_budget,
+        secpolicy.content_filter_profile.nested_args,
+        &secpolicy.normalization,
         &rawrequest,
     );
-    (Decision::Action(action), Tags::default(), reqinfo)
+    let mut tags = Tags::default();
+    let decision = if secpolicy.observe {
+        observe(&mut logs, Decision::Action(action), &mut tags)
+    } else {
+        Decision::Action(action)
+    };
+    (stamp(&logs, decision, &reqinfo), tags, reqinfo)
 }
 
 /// incrementally add headers, can exit early if there are too many headers, or they are too large
@@ -145,6 +162,7 @@ pub fn add_body(idata: IData, new_body: Vec<u8>) -> Result<IData, (Decision, Tag
 }
 
 pub async fn finalize<'t, GH: Grasshopper>(
+    hsdb: &HashMap<String, ContentFilterRules>,
     idata: IData<'t>,
     mgh: Option<GH>,
     globalfilters: &[GlobalFilterSection],
@@ -153,30 +171,54 @@ pub async fn finalize<'t, GH: Grasshopper>(
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let rawrequest = RawRequest {
-        ipstr: extract_ip(idata.trusted_hops as usize, &idata.headers),
+        ipstr: idata
+            .meta
+            .client_ip(extract_ip(idata.trusted_hops as usize, &idata.headers)),
         headers: idata.headers,
+        header_bytes: HashMap::new(),
         meta: idata.meta,
         mbody: idata.body.as_deref(),
     };
-    let reqinfo = map_request(
+    let mut reqinfo = map_request_with(
         &mut logs,
         &secpolicy.content_filter_profile.decoding,
         &secpolicy.content_filter_profile.content_type,
         secpolicy.content_filter_profile.max_body_depth,
+        &secpolicy.session,
+        &secpolicy.content_filter_profile.parse_budget,
+        secpolicy.content_filter_profile.nested_args,
+        &secpolicy.normalization,
         &rawrequest,
     );
+    if let Some(jwt) = &secpolicy.jwt {
+        jwt.apply(&mut logs, &mut reqinfo);
+    }
+    if let Some(signature) = &secpolicy.request_signature {
+        reqinfo.signature = Some(signature.verify(&mut logs, &reqinfo, rawrequest.mbody));
+    }
+    if let Some(openapi) = &secpolicy.openapi {
+        reqinfo.openapi = Some(openapi.validate(&reqinfo, rawrequest.mbody));
+    }
+    if let Some(csrf) = &secpolicy.csrf {
+        reqinfo.csrf = Some(csrf.check(&reqinfo));
+    }
+    if let Some(signing) = &secpolicy.cookie_signing {
+        reqinfo.cookies_integrity = Some(signing.verify(rawrequest.headers.get("cookie").map(|c| c.as_str())));
+    }
 
     // without grasshopper, default to being human
     let is_human = if let Some(gh) = &mgh {
         challenge_verified(gh, &reqinfo, &mut logs)
     } else {
         false
-    };
+    } || captcha_verified(secpolicy, &reqinfo);
 
-    let (mut tags, globalfilter_dec) = tag_request(is_human, globalfilters, &reqinfo);
+    let (mut tags, globalfilter_dec) = tag_request(&mut logs, is_human, globalfilters, &reqinfo);
     tags.insert("all");
+    dnsbl::tag(&mut logs, &mut tags, reqinfo.rinfo.geoip.ip).await;
     analyze(
         &mut logs,
+        hsdb,
         mgh,
         tags,
         &secpolicy.name,
@@ -207,7 +249,12 @@ fn extract_ip(trusted_hops: usize, headers: &HashMap<String, String>) -> String
 
 #[cfg(test)]
 mod test {
-    use crate::config::{contentfilter::ContentFilterProfile, hostmap::HostMap, raw::AclProfile};
+    use crate::config::{
+        contentfilter::ContentFilterProfile,
+        hostmap::{ChallengePolicy, HostMap, RequestLineConditions},
+        raw::AclProfile,
+    };
+    use crate::utils::TlsFingerprint;
     use std::time::SystemTime;
 
     use super::*;
@@ -220,6 +267,8 @@ mod test {
                 id: "__default__".to_string(),
                 name: "default".to_string(),
                 entries: Vec::new(),
+                paths: None,
+                normalization: None,
                 default: Some(SecurityPolicy {
                     name: "default".to_string(),
                     acl_active: false,
@@ -227,12 +276,45 @@ mod test {
                     content_filter_active: true,
                     content_filter_profile: cf,
                     limits: Vec::new(),
+                    session: Vec::new(),
+                    request_line: RequestLineConditions::default(),
+                    template: None,
+                    templates: Default::default(),
+                    observe: false,
+                    run_all_phases: false,
+                    captcha: None,
+                    risk: None,
+                    campaigns: None,
+                    harvesting: None,
+                    jwt: None,
+                    request_signature: None,
+                    openapi: None,
+                    honeypot: None,
+                    mode: None,
+                    quota: None,
+                    normalization: None,
+                    experiment: None,
+                    login: None,
+                    csrf: None,
+                    cookie_signing: None,
+                    ssrf: None,
+                    allowed_methods: None,
+                    challenge: ChallengePolicy::default(),
+                    ban_on_decision: HashMap::new(),
+                    block_responses: HashMap::new(),
+                    json_errors: false,
+                    explain: false,
+                    explain_secret: None,
                 }),
             }),
             last_mod: SystemTime::now(),
             container_name: None,
             flows: HashMap::new(),
             content_filter_profiles: HashMap::new(),
+            native_challenge: None,
+            revision: Default::default(),
+            revision_hash: String::new(),
+            geoip_updates: None,
         }
     }
 
@@ -248,6 +330,18 @@ mod test {
                 authority: Some("authority".to_string()),
                 method: "GET".to_string(),
                 path: "/path/to/somewhere".to_string(),
+                scheme: None,
+                port: None,
+                http_version: None,
+                proxy: None,
+                body_truncated: false,
+                header_order: None,
+                client_cert: None,
+                tls: TlsFingerprint::default(),
+                config_path: None,
+                securitypolicy: None,
+                trailers: HashMap::default(),
+                chunk_extensions: Vec::new(),
                 extra: HashMap::default(),
             },
             1,
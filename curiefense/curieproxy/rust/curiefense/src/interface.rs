@@ -1,16 +1,25 @@
 /// this file contains all the data type that are used when interfacing with a proxy
+use crate::accesslog::AccessLog;
+use crate::captcha::Captcha;
 use crate::config::raw::{RawAction, RawActionType};
+use crate::decisioncache::hint;
 use crate::grasshopper::{challenge_phase01, Grasshopper};
 use crate::logs::Logs;
-use crate::requestfields::RequestField;
+use crate::metadata::DynamicMetadata;
+use crate::reason::Reason;
+use crate::requestmap::RequestMap;
+use crate::symbols::Tag;
+use crate::utils::decoders::urlencode_component;
 use crate::utils::RequestInfo;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum SimpleDecision {
     Pass,
-    Action(SimpleAction, serde_json::Value),
+    Action(SimpleAction, Reason),
 }
 
 pub fn stronger_decision(d1: SimpleDecision, d2: SimpleDecision) -> SimpleDecision {
@@ -28,6 +37,7 @@ pub fn stronger_decision(d1: SimpleDecision, d2: SimpleDecision) -> SimpleDecisi
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Decision {
     Pass,
     Action(Action),
@@ -35,21 +45,36 @@ pub enum Decision {
 
 impl Decision {
     pub fn to_json_raw(&self, request_map: serde_json::Value, logs: Logs) -> String {
+        serde_json::to_string(&self.to_value_raw(request_map, logs)).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    /// the document serialized by `to_json_raw`
+    pub fn to_value_raw(&self, request_map: serde_json::Value, logs: Logs) -> serde_json::Value {
         let (action_desc, response) = match self {
             Decision::Pass => ("pass", None),
             Decision::Action(a) => ("custom_response", Some(a)),
         };
-        let j = serde_json::json!({
+        serde_json::json!({
             "request_map": request_map,
             "action": action_desc,
             "response": response,
+            "metadata": DynamicMetadata::new(self, &Tags::default()),
+            "access_log": serde_json::Value::Null,
+            "cache": serde_json::Value::Null,
+            "explain": logs.explain,
+            "inspection": logs.inspection,
             "logs": logs.logs
-        });
-        serde_json::to_string(&j).unwrap_or_else(|_| "{}".to_string())
+        })
     }
 
     pub fn to_json(&self, rinfo: RequestInfo, tags: Tags, logs: Logs) -> String {
+        serde_json::to_string(&self.to_value(rinfo, tags, logs)).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    /// the document serialized by `to_json`
+    pub fn to_value(&self, rinfo: RequestInfo, tags: Tags, logs: Logs) -> serde_json::Value {
         let mut tgs = tags;
+        let cache = hint(self, &rinfo);
         let (action_desc, response) = match self {
             Decision::Pass => ("pass", None),
             Decision::Action(a) => ("custom_response", Some(a)),
@@ -61,14 +86,22 @@ impl Decision {
                 }
             }
         }
-        let request_map = rinfo.into_json(tgs);
-        let j = serde_json::json!({
+        let request_map = RequestMap::new(rinfo, tgs);
+        let metadata = DynamicMetadata::new(self, &request_map.tags)
+            .with_request_id(&request_map.attrs.request_id)
+            .with_geo(&request_map.geo);
+        let access_log = AccessLog::new(self, &request_map, &logs);
+        serde_json::json!({
             "request_map": request_map,
             "action": action_desc,
             "response": response,
+            "metadata": metadata,
+            "access_log": access_log,
+            "cache": cache,
+            "explain": logs.explain,
+            "inspection": logs.inspection,
             "logs": logs.logs
-        });
-        serde_json::to_string(&j).unwrap_or_else(|_| "{}".to_string())
+        })
     }
 
     /// is the action blocking (not passed to the underlying server)
@@ -89,10 +122,11 @@ impl Decision {
 }
 
 /// a newtype representing tags, to make sure they are tagified when inserted
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct Tags(HashSet<String>);
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct Tags(HashSet<Tag>);
 
-fn tagify(tag: &str) -> String {
+/// the value is borrowed when it already is a valid tag
+fn tagify(tag: &str) -> Cow<'_, str> {
     fn filter_char(c: char) -> char {
         if c.is_ascii_alphanumeric() || c == ':' {
             c
@@ -100,19 +134,25 @@ fn tagify(tag: &str) -> String {
             '-'
         }
     }
-    tag.to_lowercase().chars().map(filter_char).collect()
+    if tag
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == ':')
+    {
+        return Cow::Borrowed(tag);
+    }
+    Cow::Owned(tag.to_lowercase().chars().map(filter_char).collect())
 }
 
 impl Tags {
     pub fn insert(&mut self, value: &str) -> bool {
-        self.0.insert(tagify(value))
+        self.0.insert(Tag::new(tagify(value)))
     }
 
     pub fn insert_qualified(&mut self, id: &str, value: &str) -> bool {
         let mut to_insert = id.to_string();
         to_insert.push(':');
         to_insert += &tagify(value);
-        self.0.insert(to_insert)
+        self.0.insert(Tag::new(Cow::Owned(to_insert)))
     }
 
     pub fn extend(&mut self, other: Self) {
@@ -120,29 +160,48 @@ impl Tags {
     }
 
     pub fn from_slice(slice: &[String]) -> Self {
-        Tags(slice.iter().map(|s| tagify(s)).collect())
+        Tags(slice.iter().map(|s| Tag::new(tagify(s))).collect())
     }
 
     pub fn contains(&self, s: &str) -> bool {
-        self.0.contains(s)
+        self.0.contains(&Tag::new(Cow::Borrowed(s)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
-    pub fn as_hash_ref(&self) -> &HashSet<String> {
-        &self.0
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        self.0.iter().map(|t| t.as_str())
     }
 
     pub fn selector(&self) -> String {
-        let mut tvec: Vec<&str> = self.0.iter().map(|s| s.as_ref()).collect();
+        let mut tvec: Vec<&str> = self.iter().collect();
         tvec.sort_unstable();
         tvec.join("*")
     }
 
     pub fn intersect(&self, other: &HashSet<String>) -> HashSet<String> {
-        self.0.intersection(other).cloned().collect()
+        if self.len() <= other.len() {
+            self.iter()
+                .filter(|t| other.contains(*t))
+                .map(|t| t.to_string())
+                .collect()
+        } else {
+            other.iter().filter(|t| self.contains(t)).cloned().collect()
+        }
     }
 
     pub fn has_intersection(&self, other: &HashSet<String>) -> bool {
-        self.0.intersection(other).next().is_some()
+        if self.len() <= other.len() {
+            self.iter().any(|t| other.contains(t))
+        } else {
+            other.iter().any(|t| self.contains(t))
+        }
     }
 }
 
@@ -154,9 +213,36 @@ pub struct Action {
     pub block_mode: bool,
     pub status: u32,
     pub headers: Option<HashMap<String, String>>,
-    pub reason: serde_json::value::Value,
+    pub reason: Reason,
     pub content: String,
     pub extra_tags: Option<HashSet<String>>,
+    /// id of the response template used to render the content, see `blockpage`
+    #[serde(skip)]
+    pub template: Option<String>,
+    /// changes to apply to the request before passing it, for `sanitize` actions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mutations: Vec<Mutation>,
+}
+
+/// a change to the request, applied by the proxy before passing it to the upstream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    RemoveHeader {
+        name: String,
+    },
+    TruncateHeader {
+        name: String,
+        length: usize,
+    },
+    RemoveCookie {
+        name: String,
+    },
+    /// replaces the query string, the `removed` arguments having been dropped
+    SetQuery {
+        query: String,
+        removed: Vec<String>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -166,6 +252,7 @@ pub enum SimpleActionT {
     Response(String),
     Redirect(String),
     Challenge,
+    Captcha,
     Default,
     Ban(Box<SimpleAction>, u64), // duration, ttl
 }
@@ -176,6 +263,7 @@ impl SimpleActionT {
         match self {
             Ban(sub, _) => sub.atype.priority(),
             Default => 8,
+            Captcha => 7,
             Challenge => 6,
             Redirect(_) => 4,
             Response(_) => 3,
@@ -191,6 +279,7 @@ pub struct SimpleAction {
     pub atype: SimpleActionT,
     pub status: u32,
     pub reason: String,
+    pub template: Option<String>,
 }
 
 impl std::default::Default for SimpleActionT {
@@ -205,20 +294,191 @@ pub enum ActionType {
     Monitor,
     Block,
     AlterHeaders,
+    /// the client is sent elsewhere, the location being in the headers
+    Redirect,
+    /// the request is passed, once its offending parts have been removed
+    Sanitize,
 }
 
 impl ActionType {
     /// is the action blocking (not passed to the underlying server)
     pub fn is_blocking(&self) -> bool {
-        matches!(self, ActionType::Block)
+        matches!(self, ActionType::Block | ActionType::Redirect)
     }
 
     /// is the action final (no further processing)
+    ///
+    /// header alterations let the request continue, so that the other checks can still block it
     pub fn is_final(&self) -> bool {
-        !matches!(self, ActionType::Monitor)
+        !matches!(self, ActionType::Monitor | ActionType::AlterHeaders)
+    }
+}
+
+/// headers injected toward the upstream when a `request_header` action does not list any
+fn default_injected_headers() -> HashMap<String, String> {
+    [("X-Curiefense-Tags", "{tags}"), ("X-Client-Country", "{country}")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// replaces the placeholders of the injected header values with the request properties
+///
+/// the supported placeholders are `{tags}` (comma separated), `{country}`, `{asn}`, `{ip}`, `{session}`,
+/// `{fingerprint}` and `{human}`. Control characters are removed from the result.
+pub fn render_header_value(template: &str, tags: &Tags, rinfo: &RequestInfo) -> String {
+    let value = |placeholder: &str| -> String {
+        match placeholder {
+            "{tags}" => {
+                let mut tvec: Vec<&str> = tags.iter().collect();
+                tvec.sort_unstable();
+                tvec.join(",")
+            }
+            "{country}" => rinfo.rinfo.geoip.country_iso.clone().unwrap_or_default(),
+            "{asn}" => rinfo.rinfo.geoip.asn.map(|a| a.to_string()).unwrap_or_default(),
+            "{ip}" => rinfo.rinfo.geoip.ipstr.clone(),
+            "{session}" => rinfo.session.clone(),
+            "{fingerprint}" => rinfo.fingerprint.clone(),
+            "{human}" => tags.contains("human").to_string(),
+            _ => String::new(),
+        }
+    };
+    let rendered = [
+        "{tags}",
+        "{country}",
+        "{asn}",
+        "{ip}",
+        "{session}",
+        "{fingerprint}",
+        "{human}",
+    ]
+    .iter()
+    .fold(template.to_string(), |acc, placeholder| {
+        if acc.contains(placeholder) {
+            acc.replace(placeholder, &value(placeholder))
+        } else {
+            acc
+        }
+    });
+    rendered.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// status codes that can be used with redirections
+pub const REDIRECT_STATUSES: [u32; 5] = [301, 302, 303, 307, 308];
+
+impl Action {
+    /// a redirection to a location template, see `render_location`
+    pub fn redirect(location: &str, status: u32, rinfo: Option<&RequestInfo>) -> Self {
+        let mut headers = HashMap::new();
+        headers.insert("Location".into(), render_location(location, rinfo));
+        Action {
+            atype: ActionType::Redirect,
+            block_mode: true,
+            status,
+            headers: Some(headers),
+            content: "You are being redirected".into(),
+            ..Action::default()
+        }
     }
 }
 
+/// the changes to apply to a request that is passed, in the order the proxies apply them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestChanges {
+    /// the headers to set, with lowercase names
+    pub set_headers: Vec<(String, String)>,
+    pub remove_headers: Vec<String>,
+    /// the new query string, when arguments were removed
+    pub query: Option<String>,
+    pub removed_arguments: Vec<String>,
+}
+
+impl RequestChanges {
+    /// the changes of the `alter_headers` and `sanitize` actions, `headers` being the request headers
+    pub fn new(action: Option<&Action>, headers: &HashMap<String, String>) -> Self {
+        let mut out = RequestChanges::default();
+        let action = match action {
+            Some(a) if matches!(a.atype, ActionType::AlterHeaders | ActionType::Sanitize) => a,
+            _ => return out,
+        };
+        // the mutations are applied in order, so the cookie header is tracked
+        let mut cookies = headers.get("cookie").cloned();
+        for mutation in &action.mutations {
+            match mutation {
+                Mutation::RemoveHeader { name } => out.remove_headers.push(name.clone()),
+                Mutation::TruncateHeader { name, length } => {
+                    if let Some(value) = headers.get(name) {
+                        out.set_headers
+                            .push((name.clone(), truncate(value, *length).to_string()));
+                    }
+                }
+                Mutation::RemoveCookie { name } => {
+                    cookies = cookies.map(|c| remove_cookie(&c, name));
+                }
+                Mutation::SetQuery { query, removed } => {
+                    out.query = Some(query.clone());
+                    out.removed_arguments.extend(removed.iter().cloned());
+                }
+            }
+        }
+        if cookies != headers.get("cookie").cloned() {
+            match cookies {
+                None => out.remove_headers.push("cookie".to_string()),
+                Some(c) if c.is_empty() => out.remove_headers.push("cookie".to_string()),
+                Some(c) => out.set_headers.push(("cookie".to_string(), c)),
+            }
+        }
+        if let Some(injected) = &action.headers {
+            out.set_headers
+                .extend(injected.iter().map(|(k, v)| (k.to_lowercase(), v.clone())));
+        }
+        out
+    }
+}
+
+/// the cookie header, without the `name` cookie
+fn remove_cookie(cookies: &str, name: &str) -> String {
+    cookies
+        .split(';')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty() && c.split('=').next().map(|n| n.trim()) != Some(name))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn truncate(value: &str, length: usize) -> &str {
+    let mut end = length.min(value.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// replaces the placeholders of a redirection template with the url encoded request properties
+///
+/// the supported placeholders are `{uri}`, `{path}`, `{host}` and `{ip}`, they are left as is when the request
+/// information is not available
+pub fn render_location(template: &str, mrinfo: Option<&RequestInfo>) -> String {
+    let rinfo = match mrinfo {
+        None => return template.to_string(),
+        Some(r) => r,
+    };
+    [
+        ("{uri}", &rinfo.rinfo.qinfo.uri),
+        ("{path}", &rinfo.rinfo.qinfo.qpath),
+        ("{host}", &rinfo.rinfo.host),
+        ("{ip}", &rinfo.rinfo.geoip.ipstr),
+    ]
+    .iter()
+    .fold(template.to_string(), |acc, (placeholder, value)| {
+        if acc.contains(placeholder) {
+            acc.replace(placeholder, &urlencode_component(value))
+        } else {
+            acc
+        }
+    })
+}
+
 impl std::default::Default for Action {
     fn default() -> Self {
         Action {
@@ -227,9 +487,11 @@ impl std::default::Default for Action {
             ban: false,
             status: 503,
             headers: None,
-            reason: serde_json::value::Value::Null,
+            reason: Reason::default(),
             content: "request denied".to_string(),
             extra_tags: None,
+            template: None,
+            mutations: Vec::new(),
         }
     }
 }
@@ -240,6 +502,7 @@ impl SimpleAction {
             atype: SimpleActionT::default(),
             status: 503,
             reason,
+            template: None,
         }
     }
 
@@ -265,9 +528,14 @@ impl SimpleAction {
                     .and_then(|s| s.parse::<u64>().ok())
                     .unwrap_or(3600),
             ),
-            RawActionType::RequestHeader => {
-                SimpleActionT::RequestHeader(rawaction.params.headers.clone().unwrap_or_default())
-            }
+            RawActionType::RequestHeader => SimpleActionT::RequestHeader(
+                rawaction
+                    .params
+                    .headers
+                    .clone()
+                    .filter(|h| !h.is_empty())
+                    .unwrap_or_else(default_injected_headers),
+            ),
             RawActionType::Response => SimpleActionT::Response(
                 rawaction
                     .params
@@ -276,6 +544,7 @@ impl SimpleAction {
                     .unwrap_or_else(|| "default content".into()),
             ),
             RawActionType::Challenge => SimpleActionT::Challenge,
+            RawActionType::Captcha => SimpleActionT::Captcha,
             RawActionType::Redirect => SimpleActionT::Redirect(
                 rawaction
                     .params
@@ -284,11 +553,17 @@ impl SimpleAction {
                     .ok_or_else(|| anyhow::anyhow!("no location for redirect in rule {:?}", rawaction))?,
             ),
         };
+        let is_redirect = matches!(atype, SimpleActionT::Redirect(_));
         let status = if let Some(sstatus) = &rawaction.params.status {
             match sstatus.parse::<u32>() {
+                Ok(s) if is_redirect && !REDIRECT_STATUSES.contains(&s) => {
+                    return Err(anyhow::anyhow!("Invalid redirect status: {}", s))
+                }
                 Ok(s) => s,
                 Err(rr) => return Err(anyhow::anyhow!("Unparseable status: {} -> {}", sstatus, rr)),
             }
+        } else if is_redirect {
+            302
         } else {
             503
         };
@@ -296,11 +571,12 @@ impl SimpleAction {
             atype,
             status,
             reason: rawaction.params.reason.clone().unwrap_or_else(|| "no reason".into()),
+            template: rawaction.params.template.clone(),
         })
     }
 
-    /// returns None when it is a challenge, Some(action) otherwise
-    fn to_action(&self, is_human: bool) -> Option<Action> {
+    /// returns None when it is a challenge or a captcha, Some(action) otherwise
+    fn to_action(&self, is_human: bool, rinfo: Option<&RequestInfo>) -> Option<Action> {
         let mut action = Action::default();
         action.block_mode = action.atype.is_blocking();
         action.status = self.status;
@@ -308,31 +584,31 @@ impl SimpleAction {
             SimpleActionT::Default => {}
             SimpleActionT::Monitor => action.atype = ActionType::Monitor,
             SimpleActionT::Ban(sub, _) => {
-                action = sub.to_action(is_human).unwrap_or_default();
+                action = sub.to_action(is_human, rinfo).unwrap_or_default();
                 action.ban = true;
             }
             SimpleActionT::RequestHeader(hdrs) => {
                 action.headers = Some(hdrs.clone());
                 action.atype = ActionType::AlterHeaders;
+                action.block_mode = false;
             }
             SimpleActionT::Response(content) => {
                 action.atype = ActionType::Block;
                 action.content = content.clone();
             }
-            SimpleActionT::Challenge => {
+            SimpleActionT::Challenge | SimpleActionT::Captcha => {
                 if !is_human {
                     return None;
                 }
                 action.atype = ActionType::Monitor;
             }
             SimpleActionT::Redirect(to) => {
-                let mut headers = HashMap::new();
-                action.content = "You are being redirected".into();
-                headers.insert("Location".into(), to.clone());
-                action.atype = ActionType::Block;
-                action.headers = Some(headers);
+                action = Action::redirect(to, self.status, rinfo);
             }
         }
+        if action.template.is_none() {
+            action.template = self.template.clone();
+        }
         Some(action)
     }
 
@@ -340,12 +616,15 @@ impl SimpleAction {
         &self,
         is_human: bool,
         mgh: &Option<GH>,
-        headers: &RequestField,
-        reason: serde_json::Value,
+        captcha: Option<&Captcha>,
+        rinfo: &RequestInfo,
+        reason: Reason,
     ) -> Decision {
-        let mut action = match self.to_action(is_human) {
-            None => match (mgh, headers.get("user-agent")) {
-                (Some(gh), Some(ua)) => return challenge_phase01(gh, ua, Vec::new()),
+        let mut action = match self.to_action(is_human, Some(rinfo)) {
+            None => match (&self.atype, captcha, mgh, rinfo.headers.get("user-agent")) {
+                (SimpleActionT::Captcha, Some(c), _, _) => return c.page(rinfo, Vec::new()),
+                // without captcha configuration, fall back to the challenge
+                (_, _, Some(gh), Some(ua)) => return challenge_phase01(gh, ua, Vec::new()),
                 _ => Action::default(),
             },
             Some(a) => a,
@@ -354,8 +633,8 @@ impl SimpleAction {
         Decision::Action(action)
     }
 
-    pub fn to_decision_no_challenge(&self, reason: serde_json::Value) -> Decision {
-        let mut action = match self.to_action(true) {
+    pub fn to_decision_no_challenge(&self, reason: Reason) -> Decision {
+        let mut action = match self.to_action(true, None) {
             None => Action::default(),
             Some(a) => a,
         };
@@ -376,6 +655,7 @@ impl SimpleDecision {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_utils::RequestBuilder;
 
     #[test]
     fn tag_selector() {
@@ -388,4 +668,62 @@ mod test {
         let tags = Tags::from_slice(&["aaa".to_string(), "ccc".to_string(), "bbb".to_string()]);
         assert_eq!(tags.selector(), "aaa*bbb*ccc");
     }
+
+    fn raw_redirect(status: Option<&str>) -> RawAction {
+        RawAction {
+            type_: RawActionType::Redirect,
+            params: crate::config::raw::RawActionParams {
+                status: status.map(|s| s.to_string()),
+                location: Some("https://help.example.com/?from={uri}".to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn redirect_action() {
+        let action = SimpleAction::resolve(&raw_redirect(None)).unwrap();
+        assert_eq!(action.status, 302);
+        let action = SimpleAction::resolve(&raw_redirect(Some("307")))
+            .unwrap()
+            .to_action(false, None)
+            .unwrap();
+        assert_eq!(action.atype, ActionType::Redirect);
+        assert_eq!(action.status, 307);
+        assert!(action.atype.is_blocking());
+        assert_eq!(
+            action.headers.unwrap().get("Location").map(|s| s.as_str()),
+            Some("https://help.example.com/?from={uri}")
+        );
+        assert!(SimpleAction::resolve(&raw_redirect(Some("403"))).is_err());
+    }
+
+    #[test]
+    fn cookie_and_truncation() {
+        assert_eq!(remove_cookie("a=1; b=2", "a"), "b=2");
+        assert_eq!(remove_cookie("b=2", "b"), "");
+        assert_eq!(truncate("abcd", 3), "abc");
+        assert_eq!(truncate("é", 1), "");
+    }
+
+    #[test]
+    fn header_injection() {
+        let raw = RawAction {
+            type_: RawActionType::RequestHeader,
+            params: Default::default(),
+        };
+        let action = SimpleAction::resolve(&raw).unwrap().to_action(false, None).unwrap();
+        assert_eq!(action.atype, ActionType::AlterHeaders);
+        assert!(!action.block_mode);
+        assert!(!action.atype.is_final());
+        let headers = action.headers.unwrap();
+        assert_eq!(headers.get("X-Curiefense-Tags").map(|s| s.as_str()), Some("{tags}"));
+
+        let rinfo = RequestBuilder::get("/").request_info();
+        let tags = Tags::from_slice(&["zz".to_string(), "aa".to_string()]);
+        assert_eq!(
+            render_header_value("{tags};{ip};{country};\r\nx", &tags, &rinfo),
+            "aa,zz;1.2.3.4;;x"
+        );
+    }
 }
@@ -0,0 +1,335 @@
+use ipnet::IpNet;
+use serde::de::Deserializer;
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// addresses are stored left-aligned in a u128, so that both families share the bit indexing
+fn key_of(addr: IpAddr) -> (usize, u128, u8) {
+    match addr {
+        IpAddr::V4(a) => (0, (u32::from(a) as u128) << 96, 32),
+        IpAddr::V6(a) => (1, u128::from(a), 128),
+    }
+}
+
+fn mask(key: u128, len: u8) -> u128 {
+    match len {
+        0 => 0,
+        l => key & (u128::MAX << (128 - l as u32)),
+    }
+}
+
+fn bit(key: u128, idx: u8) -> usize {
+    ((key >> (127 - idx as u32)) & 1) as usize
+}
+
+/// the length of the common prefix of two keys, capped to `max`
+fn common(a: u128, b: u128, max: u8) -> u8 {
+    ((a ^ b).leading_zeros() as u8).min(max)
+}
+
+#[derive(Debug, Clone)]
+struct Node<V> {
+    key: u128,
+    len: u8,
+    value: Option<V>,
+    children: [Option<u32>; 2],
+}
+
+impl<V> Node<V> {
+    fn new(key: u128, len: u8, value: Option<V>) -> Self {
+        Node {
+            key: mask(key, len),
+            len,
+            value,
+            children: [None, None],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IpSet<V> {
+    /// the first two nodes are the roots of the IPv4 and IPv6 tries
+    nodes: Vec<Node<V>>,
+    len: usize,
+}
+
+impl<V> Default for IpSet<V> {
+    fn default() -> Self {
+        IpSet {
+            nodes: vec![Node::new(0, 0, None), Node::new(0, 0, None)],
+            len: 0,
+        }
+    }
+}
+
+/// parses a network (`10.0.0.0/8`) or a single address
+pub fn parse_network(s: &str) -> Result<IpNet, String> {
+    let s = s.trim();
+    s.parse::<IpNet>().map(|n| n.trunc()).or_else(|_| {
+        s.parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|_| format!("invalid network {}", s))
+    })
+}
+
+impl<V> IpSet<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the number of networks in the set
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, node: Node<V>) -> u32 {
+        self.nodes.push(node);
+        (self.nodes.len() - 1) as u32
+    }
+
+    /// inserts a network, returning the value it previously had
+    pub fn insert(&mut self, net: IpNet, value: V) -> Option<V> {
+        let (root, key, _) = key_of(net.network());
+        let len = net.prefix_len();
+        let key = mask(key, len);
+        let mut idx = root;
+        loop {
+            if self.nodes[idx].len == len {
+                let previous = self.nodes[idx].value.replace(value);
+                if previous.is_none() {
+                    self.len += 1;
+                }
+                return previous;
+            }
+            let b = bit(key, self.nodes[idx].len);
+            let child = match self.nodes[idx].children[b] {
+                None => {
+                    let leaf = self.push(Node::new(key, len, Some(value)));
+                    self.nodes[idx].children[b] = Some(leaf);
+                    self.len += 1;
+                    return None;
+                }
+                Some(c) => c,
+            };
+            let (ckey, clen) = (self.nodes[child as usize].key, self.nodes[child as usize].len);
+            let shared = common(ckey, key, clen.min(len));
+            if shared == clen {
+                idx = child as usize;
+                continue;
+            }
+            // the child diverges from the new network, a node is inserted at the divergence point
+            let mut middle = Node::new(key, shared, None);
+            middle.children[bit(ckey, shared)] = Some(child);
+            if shared == len {
+                middle.value = Some(value);
+            } else {
+                let leaf = self.push(Node::new(key, len, Some(value)));
+                middle.children[bit(key, shared)] = Some(leaf);
+            }
+            let middle = self.push(middle);
+            self.nodes[idx].children[b] = Some(middle);
+            self.len += 1;
+            return None;
+        }
+    }
+
+    /// the longest network containing this address, and its value
+    pub fn lookup(&self, addr: IpAddr) -> Option<(IpNet, &V)> {
+        let (root, key, bits) = key_of(addr);
+        let mut best = None;
+        let mut idx = root;
+        loop {
+            let node = &self.nodes[idx];
+            if common(node.key, key, node.len) < node.len {
+                break;
+            }
+            if let Some(v) = &node.value {
+                best = Some((node, v));
+            }
+            if node.len == bits {
+                break;
+            }
+            match node.children[bit(key, node.len)] {
+                Some(c) => idx = c as usize,
+                None => break,
+            }
+        }
+        best.map(|(node, v)| (Self::network(root, node), v))
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.lookup(addr).is_some()
+    }
+
+    fn network(root: usize, node: &Node<V>) -> IpNet {
+        // the prefix lengths are always valid for the family
+        if root == 0 {
+            IpNet::new(IpAddr::V4(Ipv4Addr::from((node.key >> 96) as u32)), node.len).unwrap()
+        } else {
+            IpNet::new(IpAddr::V6(Ipv6Addr::from(node.key)), node.len).unwrap()
+        }
+    }
+
+    /// the networks and their values, IPv4 first, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (IpNet, &V)> {
+        let mut stack = vec![(1, 1u32), (0, 0u32)];
+        std::iter::from_fn(move || {
+            while let Some((root, idx)) = stack.pop() {
+                let node = &self.nodes[idx as usize];
+                stack.extend(node.children.iter().rev().flatten().map(|c| (root, *c)));
+                if let Some(v) = &node.value {
+                    return Some((Self::network(root, node), v));
+                }
+            }
+            None
+        })
+    }
+
+    /// loads networks from a text file, one per line, in the format accepted by `extend_from_reader`
+    pub fn extend_from_file<P: AsRef<Path>, F: Fn(&str) -> Result<V, String>>(
+        &mut self,
+        path: P,
+        value: F,
+    ) -> Result<usize, String> {
+        let file = std::fs::File::open(path.as_ref())
+            .map_err(|rr| format!("could not open {}: {}", path.as_ref().display(), rr))?;
+        self.extend_from_reader(std::io::BufReader::new(file), value)
+    }
+
+    /// loads networks from lines made of a network or address, optionally followed by blanks and a value, that is
+    /// converted with `value` (an empty string being passed when it is missing)
+    ///
+    /// Empty lines and lines starting with `#` are ignored. Returns the number of loaded networks.
+    pub fn extend_from_reader<R: BufRead, F: Fn(&str) -> Result<V, String>>(
+        &mut self,
+        reader: R,
+        value: F,
+    ) -> Result<usize, String> {
+        let mut count = 0;
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line.map_err(|rr| format!("line {}: {}", lineno + 1, rr))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (net, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let net = parse_network(net).map_err(|rr| format!("line {}: {}", lineno + 1, rr))?;
+            let v = value(rest.trim()).map_err(|rr| format!("line {}: {}", lineno + 1, rr))?;
+            self.insert(net, v);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl<V: Serialize> Serialize for IpSet<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter().map(|(net, v)| (net.to_string(), v)))
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for IpSet<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries: HashMap<String, V> = HashMap::deserialize(deserializer)?;
+        let mut out = IpSet::new();
+        for (net, v) in entries {
+            out.insert(parse_network(&net).map_err(serde::de::Error::custom)?, v);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        parse_network(s).unwrap()
+    }
+
+    fn find<'a>(set: &'a IpSet<&'static str>, ip: &str) -> Option<(String, &'a str)> {
+        set.lookup(ip.parse().unwrap()).map(|(n, v)| (n.to_string(), *v))
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let mut set = IpSet::new();
+        set.insert(net("10.0.0.0/8"), "ten");
+        set.insert(net("10.1.2.0/24"), "ten-one-two");
+        set.insert(net("10.1.0.0/16"), "ten-one");
+        set.insert(net("10.128.0.0/9"), "ten-high");
+        set.insert(net("192.168.1.7"), "host");
+        set.insert(net("2001:db8::/32"), "doc");
+        set.insert(net("2001:db8:1::/48"), "doc-one");
+        assert_eq!(set.insert(net("10.1.0.0/16"), "TEN-ONE"), Some("ten-one"));
+        assert_eq!(set.len(), 7);
+
+        assert_eq!(find(&set, "10.1.2.3"), Some(("10.1.2.0/24".to_string(), "ten-one-two")));
+        assert_eq!(find(&set, "10.1.3.3"), Some(("10.1.0.0/16".to_string(), "TEN-ONE")));
+        assert_eq!(find(&set, "10.2.0.1"), Some(("10.0.0.0/8".to_string(), "ten")));
+        assert_eq!(find(&set, "10.200.0.1"), Some(("10.128.0.0/9".to_string(), "ten-high")));
+        assert_eq!(find(&set, "192.168.1.7"), Some(("192.168.1.7/32".to_string(), "host")));
+        assert_eq!(find(&set, "192.168.1.8"), None);
+        assert_eq!(find(&set, "11.0.0.1"), None);
+        assert_eq!(
+            find(&set, "2001:db8:1::5"),
+            Some(("2001:db8:1::/48".to_string(), "doc-one"))
+        );
+        assert_eq!(find(&set, "2001:db8:2::5"), Some(("2001:db8::/32".to_string(), "doc")));
+        // the families do not mix
+        assert_eq!(find(&set, "::a01:203"), None);
+
+        set.insert(net("0.0.0.0/0"), "any");
+        assert_eq!(find(&set, "11.0.0.1"), Some(("0.0.0.0/0".to_string(), "any")));
+
+        let listed: Vec<String> = set.iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(
+            listed,
+            vec![
+                "0.0.0.0/0",
+                "10.0.0.0/8",
+                "10.1.0.0/16",
+                "10.1.2.0/24",
+                "10.128.0.0/9",
+                "192.168.1.7/32",
+                "2001:db8::/32",
+                "2001:db8:1::/48"
+            ]
+        );
+    }
+
+    #[test]
+    fn load_and_serialize() {
+        let input = "# reputation\n10.0.0.0/8 bad\n\n10.1.2.3  worse\n2001:db8::/32\n";
+        let mut set: IpSet<String> = IpSet::new();
+        let count = set.extend_from_reader(input.as_bytes(), |v| Ok(v.to_string())).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            set.lookup("10.1.2.3".parse().unwrap()).map(|(_, v)| v.as_str()),
+            Some("worse")
+        );
+        assert_eq!(
+            set.lookup("2001:db8::1".parse().unwrap()).map(|(_, v)| v.as_str()),
+            Some("")
+        );
+
+        let err = set.extend_from_reader("1.2.3.0/24 ok\nnope\n".as_bytes(), |v| Ok(v.to_string()));
+        assert_eq!(err, Err("line 2: invalid network nope".to_string()));
+
+        let json = serde_json::to_value(&set).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"1.2.3.0/24": "ok", "10.0.0.0/8": "bad", "10.1.2.3/32": "worse", "2001:db8::/32": ""})
+        );
+        let back: IpSet<String> = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&back).unwrap(), json);
+    }
+}
@@ -0,0 +1,622 @@
+use crate::clock;
+use crate::config::raw::RawJwtSettings;
+use crate::crypto::to_hex;
+use crate::logs::Logs;
+use crate::utils::decoders::base64dec_all;
+use crate::utils::{env_or, RequestInfo};
+use lazy_static::lazy_static;
+use ring::{hmac, signature};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref JWKS: RwLock<HashMap<String, Vec<Jwk>>> = RwLock::new(HashMap::new());
+    static ref FETCHER: Option<Mutex<Sender<String>>> = start();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Curve {
+    P256,
+    P384,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyMaterial {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// the uncompressed point, `0x04 || x || y`
+    Ec {
+        curve: Curve,
+        point: Vec<u8>,
+    },
+    Oct(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+/// a public, or HMAC, key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    kid: Option<String>,
+    alg: Option<String>,
+    material: KeyMaterial,
+}
+
+fn b64_field(value: &Value, field: &str) -> Result<Vec<u8>, String> {
+    let encoded = value
+        .get(field)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("missing {} field", field))?;
+    base64dec_all(encoded.as_bytes()).map_err(|rr| format!("invalid {} field: {}", field, rr))
+}
+
+impl Jwk {
+    pub fn parse(value: &Value) -> Result<Self, String> {
+        let str_field = |field: &str| value.get(field).and_then(|v| v.as_str());
+        let material = match str_field("kty") {
+            Some("RSA") => KeyMaterial::Rsa {
+                n: b64_field(value, "n")?,
+                e: b64_field(value, "e")?,
+            },
+            Some("EC") => {
+                let curve = match str_field("crv") {
+                    Some("P-256") => Curve::P256,
+                    Some("P-384") => Curve::P384,
+                    crv => return Err(format!("unsupported curve {:?}", crv)),
+                };
+                let mut point = vec![4];
+                point.extend(b64_field(value, "x")?);
+                point.extend(b64_field(value, "y")?);
+                KeyMaterial::Ec { curve, point }
+            }
+            Some("oct") => KeyMaterial::Oct(b64_field(value, "k")?),
+            Some("OKP") if str_field("crv") == Some("Ed25519") => KeyMaterial::Ed25519(b64_field(value, "x")?),
+            Some("OKP") => return Err(format!("unsupported curve {:?}", str_field("crv"))),
+            kty => return Err(format!("unsupported key type {:?}", kty)),
+        };
+        Ok(Jwk {
+            kid: str_field("kid").map(|s| s.to_string()),
+            alg: str_field("alg").map(|s| s.to_string()),
+            material,
+        })
+    }
+
+    /// checks the signature, the key type must match the algorithm
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        if self.alg.as_deref().map(|a| a != alg).unwrap_or(false) {
+            return false;
+        }
+        let hmac_alg = match alg {
+            "HS256" => Some(hmac::HMAC_SHA256),
+            "HS384" => Some(hmac::HMAC_SHA384),
+            "HS512" => Some(hmac::HMAC_SHA512),
+            _ => None,
+        };
+        let rsa_alg: Option<&'static signature::RsaParameters> = match alg {
+            "RS256" => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+            "RS384" => Some(&signature::RSA_PKCS1_2048_8192_SHA384),
+            "RS512" => Some(&signature::RSA_PKCS1_2048_8192_SHA512),
+            "PS256" => Some(&signature::RSA_PSS_2048_8192_SHA256),
+            "PS384" => Some(&signature::RSA_PSS_2048_8192_SHA384),
+            "PS512" => Some(&signature::RSA_PSS_2048_8192_SHA512),
+            _ => None,
+        };
+        match &self.material {
+            KeyMaterial::Oct(k) => match hmac_alg {
+                Some(halg) => hmac::verify(&hmac::Key::new(halg, k), message, sig).is_ok(),
+                None => false,
+            },
+            KeyMaterial::Rsa { n, e } => match rsa_alg {
+                Some(ralg) => signature::RsaPublicKeyComponents { n, e }
+                    .verify(ralg, message, sig)
+                    .is_ok(),
+                None => false,
+            },
+            KeyMaterial::Ec { curve, point } => {
+                let ealg = match (alg, curve) {
+                    ("ES256", Curve::P256) => &signature::ECDSA_P256_SHA256_FIXED,
+                    ("ES384", Curve::P384) => &signature::ECDSA_P384_SHA384_FIXED,
+                    _ => return false,
+                };
+                signature::UnparsedPublicKey::new(ealg, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            KeyMaterial::Ed25519(k) => {
+                alg == "EdDSA"
+                    && signature::UnparsedPublicKey::new(&signature::ED25519, k)
+                        .verify(message, sig)
+                        .is_ok()
+            }
+        }
+    }
+}
+
+/// parses a JSON Web Key Set, skipping the unsupported keys
+pub fn parse_jwks(body: &str) -> Result<Vec<Jwk>, String> {
+    let value: Value = serde_json::from_str(body).map_err(|rr| rr.to_string())?;
+    let keys = value
+        .get("keys")
+        .and_then(|k| k.as_array())
+        .ok_or_else(|| "missing keys list".to_string())?;
+    Ok(keys.iter().filter_map(|k| Jwk::parse(k).ok()).collect())
+}
+
+fn start() -> Option<Mutex<Sender<String>>> {
+    let (sender, receiver) = channel::<String>();
+    let refresh = Duration::from_secs(env_or("CURIEFENSE_JWKS_REFRESH_SECS", 3600).max(60));
+    let min_refresh = Duration::from_secs(env_or("CURIEFENSE_JWKS_MIN_REFRESH_SECS", 60));
+    let spawned = std::thread::Builder::new()
+        .name("curiefense-jwks".to_string())
+        .spawn(move || {
+            // the last download attempt of each URL
+            let mut fetched: HashMap<String, Instant> = HashMap::new();
+            loop {
+                match receiver.recv_timeout(refresh) {
+                    Ok(url) => {
+                        if fetched.get(&url).map(|t| t.elapsed() >= min_refresh).unwrap_or(true) {
+                            fetch(&url);
+                            fetched.insert(url, Instant::now());
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        for (url, last) in fetched.iter_mut() {
+                            fetch(url);
+                            *last = Instant::now();
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    if let Err(rr) = spawned {
+        tracing::error!(target: "curiefense::jwt", "could not start the JWKS fetcher: {}", rr);
+        return None;
+    }
+    Some(Mutex::new(sender))
+}
+
+fn fetch(url: &str) {
+    let keys = attohttpc::get(url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .and_then(|rsp| rsp.error_for_status())
+        .and_then(|rsp| rsp.text())
+        .map_err(|rr| rr.to_string())
+        .and_then(|body| parse_jwks(&body));
+    match keys {
+        Ok(keys) => {
+            tracing::info!(target: "curiefense::jwt", url, keys = keys.len(), "JWKS refreshed");
+            if let Ok(mut jwks) = JWKS.write() {
+                jwks.insert(url.to_string(), keys);
+            }
+        }
+        Err(rr) => tracing::warn!(target: "curiefense::jwt", "could not download the JWKS {}: {}", url, rr),
+    }
+}
+
+/// asks the background thread to download the keys of the URL
+fn request_fetch(url: &str) {
+    if let Some(Ok(sender)) = FETCHER.as_ref().map(|s| s.lock()) {
+        let _ = sender.send(url.to_string());
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Issuer {
+    issuer: String,
+    audiences: Vec<String>,
+    jwks_url: Option<String>,
+    keys: Vec<Jwk>,
+}
+
+/// the JWT settings of a host map, shared between its security policies
+#[derive(Debug, Clone)]
+pub struct JwtPolicy {
+    issuers: Vec<Issuer>,
+    cookie: Option<String>,
+    leeway: u64,
+    tag_claims: Vec<String>,
+    hash_claims: Vec<String>,
+    session_claim: Option<String>,
+}
+
+/// the result of the validation of the token of a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JwtResult {
+    pub valid: bool,
+    /// the signature is valid, but the token expired
+    pub expired: bool,
+    /// the claims of a valid token, as tag names and values
+    pub tags: Vec<(String, String)>,
+}
+
+impl JwtResult {
+    fn invalid(expired: bool) -> Self {
+        JwtResult {
+            valid: false,
+            expired,
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// the values of a claim, the strings being split on whitespaces, as in the `scope` claim
+fn claim_values(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => s.split_whitespace().map(|v| v.to_string()).collect(),
+        Value::Array(items) => items.iter().flat_map(claim_values).collect(),
+        Value::Null | Value::Object(_) => Vec::new(),
+        v => vec![v.to_string()],
+    }
+}
+
+fn claim_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn decode_part(part: &str) -> Option<Value> {
+    base64dec_all(part.as_bytes())
+        .ok()
+        .and_then(|decoded| serde_json::from_slice(&decoded).ok())
+}
+
+impl JwtPolicy {
+    pub fn resolve(logs: &mut Logs, raw: &RawJwtSettings) -> Self {
+        let issuers = raw
+            .issuers
+            .iter()
+            .map(|rawissuer| {
+                let keys = rawissuer
+                    .keys
+                    .iter()
+                    .filter_map(|k| match Jwk::parse(k) {
+                        Ok(key) => Some(key),
+                        Err(rr) => {
+                            logs.warning(|| format!("Invalid key for the JWT issuer {}: {}", rawissuer.issuer, rr));
+                            None
+                        }
+                    })
+                    .collect();
+                if let Some(url) = &rawissuer.jwks_url {
+                    request_fetch(url);
+                }
+                Issuer {
+                    issuer: rawissuer.issuer.clone(),
+                    audiences: rawissuer.audiences.clone(),
+                    jwks_url: rawissuer.jwks_url.clone(),
+                    keys,
+                }
+            })
+            .collect();
+        JwtPolicy {
+            issuers,
+            cookie: raw.cookie.clone(),
+            leeway: raw.leeway,
+            tag_claims: raw.tag_claims.clone(),
+            hash_claims: raw.hash_claims.clone(),
+            session_claim: raw.session_claim.clone(),
+        }
+    }
+
+    fn token<'a>(&self, reqinfo: &'a RequestInfo) -> Option<&'a str> {
+        let bearer = reqinfo
+            .headers
+            .get_str("authorization")
+            .and_then(|auth| auth.strip_prefix("Bearer ").or_else(|| auth.strip_prefix("bearer ")));
+        bearer
+            .or_else(|| self.cookie.as_ref().and_then(|c| reqinfo.cookies.get_str(c)))
+            .map(|t| t.trim())
+    }
+
+    /// validates a token, returning the claims of the valid and expired tokens
+    fn validate(&self, logs: &mut Logs, token: &str) -> Result<serde_json::Map<String, Value>, bool> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let (header, claims, sig) = match parts.as_slice() {
+            [h, c, s] => (decode_part(h), decode_part(c), base64dec_all(s.as_bytes()).ok()),
+            _ => {
+                logs.debug("malformed JWT");
+                return Err(false);
+            }
+        };
+        let (header, claims, sig) = match (header, claims, sig) {
+            (Some(h), Some(Value::Object(c)), Some(s)) => (h, c, s),
+            _ => {
+                logs.debug("could not decode the JWT");
+                return Err(false);
+            }
+        };
+        let alg = header.get("alg").and_then(|a| a.as_str()).unwrap_or("none");
+        let kid = header.get("kid").and_then(|k| k.as_str());
+        let iss = claims.get("iss").and_then(|i| i.as_str());
+        let issuer = match self.issuers.iter().find(|i| Some(i.issuer.as_str()) == iss) {
+            Some(i) => i,
+            None => {
+                logs.debug(|| format!("unknown JWT issuer {:?}", iss));
+                return Err(false);
+            }
+        };
+
+        let message = &token.as_bytes()[..parts[0].len() + 1 + parts[1].len()];
+        let verify = |keys: &[Jwk]| {
+            keys.iter()
+                .filter(|k| kid.is_none() || k.kid.is_none() || k.kid.as_deref() == kid)
+                .any(|k| k.verify(alg, message, &sig))
+        };
+        let mut verified = verify(&issuer.keys);
+        if !verified {
+            if let Some(url) = &issuer.jwks_url {
+                let known_kid = match JWKS.read() {
+                    Ok(jwks) => {
+                        let keys = jwks.get(url).map(|k| k.as_slice()).unwrap_or(&[]);
+                        verified = verify(keys);
+                        kid.map(|kid| keys.iter().any(|k| k.kid.as_deref() == Some(kid)))
+                            .unwrap_or(true)
+                    }
+                    Err(_) => true,
+                };
+                // the keys might have been rotated
+                if !known_kid {
+                    request_fetch(url);
+                }
+            }
+        }
+        if !verified {
+            logs.debug(|| format!("invalid JWT signature, algorithm {}, key {:?}", alg, kid));
+            return Err(false);
+        }
+
+        if !issuer.audiences.is_empty() {
+            let audiences = claims.get("aud").map(claim_values).unwrap_or_default();
+            if !audiences.iter().any(|a| issuer.audiences.contains(a)) {
+                logs.debug(|| format!("unexpected JWT audience {:?}", audiences));
+                return Err(false);
+            }
+        }
+        let now = clock::unix_secs();
+        let time_claim = |name: &str| claims.get(name).and_then(|v| v.as_u64());
+        if let Some(nbf) = time_claim("nbf") {
+            if now + self.leeway < nbf {
+                logs.debug("the JWT is not valid yet");
+                return Err(false);
+            }
+        }
+        if let Some(exp) = time_claim("exp") {
+            if exp + self.leeway < now {
+                logs.debug("the JWT expired");
+                return Err(true);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// validates the token of the request, and sets the session identity from the claims of a valid token
+    pub fn apply(&self, logs: &mut Logs, reqinfo: &mut RequestInfo) {
+        let token = match self.token(reqinfo) {
+            Some(t) => t,
+            None => return,
+        };
+        let claims = match self.validate(logs, token) {
+            Ok(claims) => claims,
+            Err(expired) => {
+                reqinfo.jwt = Some(JwtResult::invalid(expired));
+                return;
+            }
+        };
+        let mut tags = Vec::new();
+        for claim in &self.tag_claims {
+            for value in claims.get(claim).map(claim_values).unwrap_or_default() {
+                tags.push((claim.clone(), value));
+            }
+        }
+        for claim in &self.hash_claims {
+            if let Some(value) = claims.get(claim).and_then(claim_string) {
+                let hash = to_hex(&Sha256::digest(value.as_bytes()));
+                tags.push((claim.clone(), hash[..16].to_string()));
+            }
+        }
+        if let Some(session) = self
+            .session_claim
+            .as_ref()
+            .and_then(|c| claims.get(c))
+            .and_then(claim_string)
+        {
+            logs.debug(|| format!("session identifier from the JWT: {}", session));
+            reqinfo.session = session;
+        }
+        reqinfo.jwt = Some(JwtResult {
+            valid: true,
+            expired: false,
+            tags,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawJwtIssuer;
+    use crate::interface::Tags;
+    use crate::tagging::tag_request;
+    use crate::test_utils::RequestBuilder;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// base64 encoding, with the URL safe alphabet and without padding
+    fn b64(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        out
+    }
+
+    fn sign(header: &Value, claims: &Value, signer: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let message = format!(
+            "{}.{}",
+            b64(header.to_string().as_bytes()),
+            b64(claims.to_string().as_bytes())
+        );
+        let sig = signer(message.as_bytes());
+        format!("{}.{}", message, b64(&sig))
+    }
+
+    fn policy(keys: Vec<Value>, session_claim: Option<&str>) -> JwtPolicy {
+        JwtPolicy::resolve(
+            &mut Logs::default(),
+            &RawJwtSettings {
+                issuers: vec![RawJwtIssuer {
+                    issuer: "https://issuer".to_string(),
+                    audiences: vec!["api".to_string()],
+                    jwks_url: None,
+                    keys,
+                }],
+                cookie: Some("token".to_string()),
+                leeway: 60,
+                tag_claims: vec!["scope".to_string()],
+                hash_claims: vec!["sub".to_string()],
+                session_claim: session_claim.map(|s| s.to_string()),
+            },
+        )
+    }
+
+    fn check(policy: &JwtPolicy, header: &str, value: &str) -> (Option<JwtResult>, String) {
+        let mut reqinfo = RequestBuilder::get("/").header(header, value).request_info();
+        policy.apply(&mut Logs::default(), &mut reqinfo);
+        (reqinfo.jwt, reqinfo.session)
+    }
+
+    fn claims(exp: u64) -> Value {
+        serde_json::json!({"iss": "https://issuer", "aud": ["api", "other"], "sub": "alice", "scope": "read admin", "exp": exp})
+    }
+
+    #[test]
+    fn hmac_tokens() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let hs256 = |msg: &[u8]| hmac::sign(&key, msg).as_ref().to_vec();
+        let policy = policy(vec![serde_json::json!({"kty": "oct", "k": b64(secret)})], Some("sub"));
+        let header = serde_json::json!({"alg": "HS256", "typ": "JWT"});
+
+        clock::deterministic(UNIX_EPOCH + Duration::from_secs(1_000_000), 0, || {
+            let token = sign(&header, &claims(1_000_030), hs256);
+            let (result, session) = check(&policy, "authorization", &format!("Bearer {}", token));
+            let result = result.unwrap();
+            assert!(result.valid);
+            assert_eq!(session, "alice");
+            let hash = to_hex(&Sha256::digest(b"alice"));
+            assert_eq!(
+                result.tags,
+                vec![
+                    ("scope".to_string(), "read".to_string()),
+                    ("scope".to_string(), "admin".to_string()),
+                    ("sub".to_string(), hash[..16].to_string())
+                ]
+            );
+            // from the cookie
+            let mut reqinfo = RequestBuilder::get("/").cookie("token", &token).request_info();
+            policy.apply(&mut Logs::default(), &mut reqinfo);
+            let result = reqinfo.jwt;
+            assert!(result.unwrap().valid);
+
+            // expired, beyond the leeway
+            let token = sign(&header, &claims(999_900), hs256);
+            let (result, session) = check(&policy, "authorization", &format!("Bearer {}", token));
+            assert_eq!(result, Some(JwtResult::invalid(true)));
+            assert_eq!(session, "1.2.3.4");
+
+            // the unsigned tokens are rejected
+            let token = sign(&serde_json::json!({"alg": "none"}), &claims(1_000_030), |_| Vec::new());
+            let (result, _) = check(&policy, "authorization", &format!("Bearer {}", token));
+            assert_eq!(result, Some(JwtResult::invalid(false)));
+
+            // tampered claims
+            let token = sign(&header, &claims(1_000_030), hs256);
+            let mut parts: Vec<&str> = token.split('.').collect();
+            let forged = b64(claims(2_000_000).to_string().as_bytes());
+            parts[1] = &forged;
+            let (result, _) = check(&policy, "authorization", &format!("Bearer {}", parts.join(".")));
+            assert_eq!(result, Some(JwtResult::invalid(false)));
+
+            // wrong audience
+            let mut wrong = claims(1_000_030);
+            wrong["aud"] = serde_json::json!("elsewhere");
+            let token = sign(&header, &wrong, hs256);
+            let (result, _) = check(&policy, "authorization", &format!("Bearer {}", token));
+            assert_eq!(result, Some(JwtResult::invalid(false)));
+
+            // no token
+            let (result, _) = check(&policy, "x-other", "value");
+            assert_eq!(result, None);
+        });
+    }
+
+    #[test]
+    fn asymmetric_tokens() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let ec = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let point = ec.public_key().as_ref();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let ed = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwks = serde_json::json!({"keys": [
+            {"kty": "EC", "crv": "P-256", "kid": "ec", "x": b64(&point[1..33]), "y": b64(&point[33..])},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "ed", "x": b64(ed.public_key().as_ref())},
+            {"kty": "unknown"}
+        ]});
+        let keys = parse_jwks(&jwks.to_string()).unwrap();
+        assert_eq!(keys.len(), 2);
+        let policy = policy(jwks["keys"].as_array().unwrap().clone(), None);
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 600;
+
+        let es256 = |msg: &[u8]| ec.sign(&rng, msg).unwrap().as_ref().to_vec();
+        let token = sign(&serde_json::json!({"alg": "ES256", "kid": "ec"}), &claims(exp), es256);
+        let (result, session) = check(&policy, "authorization", &format!("Bearer {}", token));
+        assert!(result.unwrap().valid);
+        assert_eq!(session, "1.2.3.4");
+
+        let eddsa = |msg: &[u8]| ed.sign(msg).as_ref().to_vec();
+        let token = sign(&serde_json::json!({"alg": "EdDSA", "kid": "ed"}), &claims(exp), eddsa);
+        let (result, _) = check(&policy, "authorization", &format!("Bearer {}", token));
+        assert!(result.unwrap().valid);
+
+        // the key type must match the algorithm
+        let token = sign(&serde_json::json!({"alg": "ES256", "kid": "ed"}), &claims(exp), eddsa);
+        let (result, _) = check(&policy, "authorization", &format!("Bearer {}", token));
+        assert!(!result.unwrap().valid);
+    }
+
+    #[test]
+    fn jwt_tags() {
+        let mut rinfo = RequestBuilder::get("/").request_info();
+        rinfo.jwt = Some(JwtResult {
+            valid: true,
+            expired: false,
+            tags: vec![("scope".to_string(), "admin".to_string())],
+        });
+        let (tags, _) = tag_request(&mut Logs::default(), true, &[], &rinfo);
+        assert!(tags.contains("jwt:valid"));
+        assert!(tags.contains("jwt:scope:admin"));
+        rinfo.jwt = Some(JwtResult::invalid(true));
+        let (tags, _): (Tags, _) = tag_request(&mut Logs::default(), true, &[], &rinfo);
+        assert!(tags.contains("jwt:invalid"));
+        assert!(tags.contains("jwt:expired"));
+    }
+}
@@ -1,35 +1,113 @@
+pub mod accesslog;
 pub mod acl;
+pub mod aggregation;
 pub mod analyze;
+pub mod audit;
+pub mod backend;
+pub mod blocklist;
+pub mod blockpage;
 pub mod body;
+pub mod botscore;
+pub mod cache;
+pub mod campaign;
+pub mod captcha;
+pub mod challenge;
+pub mod cli;
+pub mod clock;
 pub mod config;
 pub mod contentfilter;
+pub mod cookiesigning;
+#[cfg(feature = "bench-corpus")]
+pub mod corpus;
+pub mod counters;
+pub mod crypto;
+pub mod csrf;
+pub mod decisioncache;
+pub mod diagnostics;
+pub mod dnsbl;
+pub mod experiment;
+pub mod explain;
+#[cfg(feature = "ext-authz")]
+pub mod extauthz;
+#[cfg(feature = "ext-authz")]
+pub mod extproc;
 pub mod flow;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "file-config")]
+pub mod geoupdate;
+pub mod golden;
 pub mod grasshopper;
+pub mod harvesting;
+pub mod helpers;
+pub mod hits;
+pub mod honeypot;
+#[cfg(feature = "http-server")]
+pub mod httpserver;
 pub mod incremental;
 pub mod interface;
+pub mod ipset;
+pub mod jwt;
+pub mod leaks;
 pub mod limit;
+pub mod lint;
+pub mod loadtest;
+pub mod login;
 pub mod logs;
+pub mod matcher;
 pub mod maxmind;
+pub mod metadata;
+pub mod methods;
+pub mod metrics;
+pub mod modes;
+pub mod normalization;
+pub mod notify;
+pub mod openapi;
+pub mod otel;
+pub mod quota;
+pub mod reason;
 pub mod redis;
+pub mod replay;
 pub mod requestfields;
+pub mod requestmap;
+pub mod requestsignature;
+pub mod risk;
 pub mod securitypolicy;
+pub mod shipper;
+pub mod sigset;
 pub mod simple_executor;
+pub mod slow;
+pub mod ssrf;
+pub mod statsd;
+pub mod symbols;
 pub mod tagging;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod tor;
 pub mod utils;
 
+use blockpage::apply_template;
 use body::body_too_large;
-use config::{with_config, HSDB};
-use contentfilter::content_filter_check;
-use grasshopper::Grasshopper;
+use captcha::captcha_verified;
+use config::hostmap::SecurityPolicy;
+use config::raw::ParseBudget;
+use config::{config_snapshot, request_config_path, Config, ConfigSnapshot};
+use contentfilter::{content_filter_check, InspectionStats};
+use explain::explain_enabled;
+use grasshopper::{Challenger, Grasshopper};
 use interface::Tags;
-use interface::{Action, ActionType, Decision};
+use interface::{Action, ActionType, Decision, SimpleDecision};
 use logs::Logs;
+use matcher::MatcherInfo;
 use securitypolicy::match_securitypolicy;
+use serde::Serialize;
 use simple_executor::{Executor, Progress, Task};
+use std::collections::HashMap;
 use tagging::tag_request;
-use utils::{map_request, RawRequest, RequestInfo};
+use utils::{map_request, map_request_with, RawRequest, RequestInfo};
 
-fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mut Logs) -> bool {
+/// checks the challenge cookie (`rbzid`) of the request
+pub fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mut Logs) -> bool {
     if let Some(rbzid) = reqinfo.cookies.get("rbzid") {
         if let Some(ua) = reqinfo.headers.get("user-agent") {
             logs.debug(|| format!("Checking rbzid cookie {} with user-agent {}", rbzid, ua));
@@ -49,6 +127,150 @@ fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mu
     false
 }
 
+#[allow(clippy::large_enum_variant)]
+enum RequestMappingResult<A> {
+    NoSecurityPolicy,
+    BodyTooLarge(Decision, RequestInfo),
+    QuotaExceeded(String),
+    Res(A),
+}
+
+/// a request that was mapped and tagged, ready for the analysis
+struct MappedRequest<'a> {
+    secpolname: String,
+    securitypolicy: &'a SecurityPolicy,
+    tags: Tags,
+    globalfilter_dec: SimpleDecision,
+    reqinfo: RequestInfo,
+    is_human: bool,
+}
+
+/// the request map, when no security policy could be found
+fn map_request_default(logs: &mut Logs, raw: &RawRequest) -> RequestInfo {
+    map_request(logs, &[], &[], 0, &[], &ParseBudget::default(), false, raw)
+}
+
+/// matches the security policy, maps and tags the request
+///
+/// when the body is too large, the final decision is returned instead
+fn map_with_config<'a, GH: Grasshopper>(
+    logs: &mut Logs,
+    cfg: &'a Config,
+    mgh: &Option<GH>,
+    raw: &RawRequest,
+    tags: &mut Tags,
+) -> RequestMappingResult<MappedRequest<'a>> {
+    let (secpolname, secpolicy) =
+        match match_securitypolicy(&raw.get_host(), &raw.meta.canonical_path(), &raw.meta, cfg, logs) {
+            Some(x) => x,
+            None => return RequestMappingResult::NoSecurityPolicy,
+        };
+    // the requests of a host map that used its resources are passed without being mapped
+    if secpolicy.quota.as_ref().map(|q| q.exceeded()).unwrap_or(false) {
+        return RequestMappingResult::QuotaExceeded(secpolname);
+    }
+    let cache_key = decisioncache::cache_key(cfg, &secpolname, secpolicy, raw);
+    if explain_enabled(secpolicy, &raw.headers) {
+        logs.explain = Some(Vec::new());
+    }
+    let pmax_depth = secpolicy.content_filter_profile.max_body_depth;
+
+    // check if the body is too large
+    // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
+    let (body_too_large, max_depth) = if let Some(body) = raw.mbody {
+        if body.len() > secpolicy.content_filter_profile.max_body_size {
+            (
+                Some(body_too_large(
+                    secpolicy.content_filter_profile.max_body_size,
+                    body.len(),
+                )),
+                0,
+            )
+        } else {
+            (None, pmax_depth)
+        }
+    } else {
+        (None, pmax_depth)
+    };
+
+    // if the max depth is equal to 0, the body will not be parsed
+    let mut reqinfo = map_request_with(
+        logs,
+        &secpolicy.content_filter_profile.decoding,
+        &secpolicy.content_filter_profile.content_type,
+        max_depth,
+        &secpolicy.session,
+        &secpolicy.content_filter_profile.parse_budget,
+        secpolicy.content_filter_profile.nested_args,
+        &secpolicy.normalization,
+        raw,
+    );
+    reqinfo.cache_key = cache_key;
+    if let Some(jwt) = &secpolicy.jwt {
+        jwt.apply(logs, &mut reqinfo);
+    }
+    if let Some(signature) = &secpolicy.request_signature {
+        reqinfo.signature = Some(signature.verify(logs, &reqinfo, raw.mbody));
+    }
+    if let Some(openapi) = &secpolicy.openapi {
+        reqinfo.openapi = Some(openapi.validate(&reqinfo, raw.mbody));
+    }
+    if let Some(csrf) = &secpolicy.csrf {
+        reqinfo.csrf = Some(csrf.check(&reqinfo));
+    }
+    if let Some(signing) = &secpolicy.cookie_signing {
+        reqinfo.cookies_integrity = Some(signing.verify(raw.cookie_header().as_deref()));
+    }
+    logs.phase("mapping");
+
+    if let Some(action) = body_too_large {
+        logs.inspection = Some(InspectionStats {
+            body: "skipped",
+            ..InspectionStats::new(&reqinfo, &secpolicy.content_filter_profile)
+        });
+        let mut decision = apply_template(logs, Decision::Action(action), &reqinfo, secpolicy);
+        if secpolicy.observe {
+            decision = analyze::observe(logs, decision, tags);
+        }
+        let decision = reason::stamp(logs, decision, &reqinfo);
+        return RequestMappingResult::BodyTooLarge(decision, reqinfo);
+    }
+
+    // the variant of the experiment, if any, is selected once the client is identified
+    let secpolicy = match &secpolicy.experiment {
+        Some(experiment) => experiment.select(logs, secpolicy, &reqinfo, tags),
+        None => secpolicy,
+    };
+
+    // without grasshopper, default to being human
+    let is_human = if let Some(gh) = mgh {
+        challenge_verified(gh, &reqinfo, logs)
+    } else {
+        false
+    } || captcha_verified(secpolicy, &reqinfo);
+
+    let (ntags, globalfilter_dec) = tag_request(logs, is_human, &cfg.globalfilters, &reqinfo);
+    logs.phase("tagging");
+    RequestMappingResult::Res(MappedRequest {
+        secpolname,
+        securitypolicy: secpolicy,
+        tags: ntags,
+        globalfilter_dec,
+        reqinfo,
+        is_human,
+    })
+}
+
+/// the key of the decision of the request, when it can be cached, see the `decisioncache` module
+pub fn request_cache_key(configpath: &str, raw: &RawRequest, logs: &mut Logs) -> Option<String> {
+    let configpath = request_config_path(configpath, &raw.meta, logs);
+    let snapshot = config_snapshot(configpath, logs)?;
+    let cfg = &snapshot.config;
+    let (secpolname, secpolicy) =
+        match_securitypolicy(&raw.get_host(), &raw.meta.canonical_path(), &raw.meta, cfg, logs)?;
+    decisioncache::cache_key(cfg, &secpolname, secpolicy, raw)
+}
+
 /// # Safety
 ///
 /// Steps a valid executor
@@ -84,107 +306,158 @@ pub async fn inspect_generic_request_map_async<GH: Grasshopper>(
     mgh: Option<GH>,
     raw: RawRequest<'_>,
     logs: &mut Logs,
+) -> (Decision, Tags, RequestInfo) {
+    // the integration can select another configuration for this request
+    let configpath = request_config_path(configpath, &raw.meta, logs);
+
+    // all the phases use the same snapshot of the configuration, even if it is reloaded during the inspection
+    let snapshot = match config_snapshot(configpath, logs) {
+        Some(s) => s,
+        None => {
+            logs.debug("Something went wrong during security policy searching");
+            // insert the all tag here, to make sure it is always present, even in the presence of early errors
+            let mut tags = Tags::default();
+            tags.insert("all");
+            let traceparent = raw.headers.get("traceparent");
+            otel::export(traceparent.map(|t| t.as_str()), &Decision::Pass, logs, &[]);
+            return (Decision::Pass, tags, map_request_default(logs, &raw));
+        }
+    };
+
+    // without the grasshopper component, fall back to the native challenge when it is configured
+    let mgh = match mgh {
+        Some(gh) => Some(Challenger::External(gh)),
+        None => snapshot.config.native_challenge.clone().map(Challenger::Native),
+    };
+    inspect_with_config(&snapshot, mgh, &raw, logs).await
+}
+
+/// inspects a request with a snapshot of the configuration, and exports its trace
+async fn inspect_with_config<GH: Grasshopper>(
+    snapshot: &ConfigSnapshot,
+    mgh: Option<GH>,
+    raw: &RawRequest<'_>,
+    logs: &mut Logs,
+) -> (Decision, Tags, RequestInfo) {
+    let mut attributes = Vec::new();
+    let (decision, tags, rinfo) = inspect_traced(snapshot, mgh, raw, logs, &mut attributes).await;
+    let traceparent = raw.headers.get("traceparent");
+    otel::export(traceparent.map(|t| t.as_str()), &decision, logs, &attributes);
+    (decision, tags, rinfo)
+}
+
+/// runs the inspection, filling the attributes of its trace
+async fn inspect_traced<GH: Grasshopper>(
+    snapshot: &ConfigSnapshot,
+    mgh: Option<GH>,
+    raw: &RawRequest<'_>,
+    logs: &mut Logs,
+    attributes: &mut Vec<(&'static str, String)>,
 ) -> (Decision, Tags, RequestInfo) {
     let mut tags = Tags::default();
+    let cpu_start = quota::thread_cpu_micros();
+    let cfg = &snapshot.config;
 
     // insert the all tag here, to make sure it is always present, even in the presence of early errors
     tags.insert("all");
 
     logs.debug(|| format!("Inspection starts (grasshopper active: {})", mgh.is_some()));
 
-    #[allow(clippy::large_enum_variant)]
-    enum RequestMappingResult<A> {
-        NoSecurityPolicy,
-        BodyTooLarge(Action, RequestInfo),
-        Res(A),
-    }
-
-    // do all config queries in the lambda once
-    // there is a lot of copying taking place, to minimize the lock time
-    // this decision should be backed with benchmarks
-
-    let ((nm, securitypolicy), (ntags, globalfilter_dec), flows, reqinfo, is_human) =
-        match with_config(configpath, logs, |slogs, cfg| {
-            let mmapinfo =
-                match_securitypolicy(&raw.get_host(), &raw.meta.path, cfg, slogs).map(|(nm, um)| (nm, um.clone()));
-            match mmapinfo {
-                Some((nm, secpolicy)) => {
-                    // this part is where we use the configuration as much as possible, while we have a lock on it
-                    let pmax_depth = secpolicy.content_filter_profile.max_body_depth;
-
-                    // check if the body is too large
-                    // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
-                    let (body_too_large, max_depth) = if let Some(body) = raw.mbody {
-                        if body.len() > secpolicy.content_filter_profile.max_body_size {
-                            (
-                                Some(body_too_large(
-                                    secpolicy.content_filter_profile.max_body_size,
-                                    body.len(),
-                                )),
-                                0,
-                            )
-                        } else {
-                            (None, pmax_depth)
-                        }
-                    } else {
-                        (None, pmax_depth)
-                    };
-
-                    // if the max depth is equal to 0, the body will not be parsed
-                    let reqinfo = map_request(
-                        slogs,
-                        &secpolicy.content_filter_profile.decoding,
-                        &secpolicy.content_filter_profile.content_type,
-                        max_depth,
-                        &raw,
-                    );
-
-                    if let Some(action) = body_too_large {
-                        return RequestMappingResult::BodyTooLarge(action, reqinfo);
-                    }
-
-                    let nflows = cfg.flows.clone();
-
-                    // without grasshopper, default to being human
-                    let is_human = if let Some(gh) = &mgh {
-                        challenge_verified(gh, &reqinfo, slogs)
-                    } else {
-                        false
-                    };
-
-                    let ntags = tag_request(is_human, &cfg.globalfilters, &reqinfo);
-                    RequestMappingResult::Res(((nm, secpolicy), ntags, nflows, reqinfo, is_human))
-                }
-                None => RequestMappingResult::NoSecurityPolicy,
-            }
-        }) {
-            Some(RequestMappingResult::Res(x)) => x,
-            Some(RequestMappingResult::BodyTooLarge(action, rinfo)) => {
-                return (Decision::Action(action), tags, rinfo);
-            }
-            Some(RequestMappingResult::NoSecurityPolicy) => {
-                logs.debug("No security policy found");
-                return (Decision::Pass, tags, map_request(logs, &[], &[], 0, &raw));
-            }
-            None => {
-                logs.debug("Something went wrong during security policy searching");
-                return (Decision::Pass, tags, map_request(logs, &[], &[], 0, &raw));
-            }
-        };
+    let mapped = match map_with_config(logs, cfg, &mgh, raw, &mut tags) {
+        RequestMappingResult::Res(m) => m,
+        RequestMappingResult::BodyTooLarge(decision, rinfo) => {
+            return (decision, tags, rinfo);
+        }
+        RequestMappingResult::NoSecurityPolicy => {
+            logs.debug("No security policy found");
+            return (Decision::Pass, tags, map_request_default(logs, raw));
+        }
+        RequestMappingResult::QuotaExceeded(secpolname) => {
+            logs.info(|| format!("Quota of {} exceeded, the request is not inspected", secpolname));
+            tags.insert_qualified("securitypolicy", &secpolname);
+            tags.insert("quota:exceeded");
+            return (Decision::Pass, tags, map_request_default(logs, raw));
+        }
+    };
+    let securitypolicy = mapped.securitypolicy;
+    let nm = mapped.secpolname;
 
-    tags.extend(ntags);
-    analyze::analyze(
+    attributes.push(("curiefense.request_id", mapped.reqinfo.request_id.clone()));
+    attributes.push(("curiefense.securitypolicy", nm.clone()));
+    attributes.push(("curiefense.securitypolicy.entry", securitypolicy.name.clone()));
+    attributes.push(("curiefense.acl_profile", securitypolicy.acl_profile.id.clone()));
+    attributes.push((
+        "curiefense.content_filter_profile",
+        securitypolicy.content_filter_profile.id.clone(),
+    ));
+
+    tags.extend(mapped.tags);
+    dnsbl::tag(logs, &mut tags, mapped.reqinfo.rinfo.geoip.ip).await;
+    let result = analyze::analyze(
         logs,
+        &snapshot.hsdb,
         mgh,
         tags,
         &nm,
-        &securitypolicy,
-        reqinfo,
-        is_human,
-        globalfilter_dec,
-        &flows,
+        securitypolicy,
+        mapped.reqinfo,
+        mapped.is_human,
+        mapped.globalfilter_dec,
+        &cfg.flows,
     )
-    .await
+    .await;
+    if let Some(quota) = &securitypolicy.quota {
+        quota.record(
+            quota::thread_cpu_micros().saturating_sub(cpu_start),
+            quota::request_size(raw),
+        );
+    }
+    result
+}
+
+/// inspects a batch of requests, for log replays and policy regression tests
+///
+/// The configuration is looked up and locked once, and a snapshot of it is used for the whole batch (one for each
+/// configuration selected by the requests). The results are in the order of the requests, each with its own logs, the
+/// configuration loading logs going to `logs`.
+pub fn inspect_batch<GH: Grasshopper>(
+    configpath: &str,
+    mgh: Option<GH>,
+    raws: &[RawRequest],
+    logs: &mut Logs,
+) -> Vec<(Decision, Tags, RequestInfo, Logs)> {
+    inspect_batch_with(configpath, mgh, raws, logs, |_| ())
+}
+
+/// like `inspect_batch`, calling `before` with the index of each request before inspecting it, so that the replays
+/// can set the clock to the time of the request (see the `clock` module)
+pub fn inspect_batch_with<GH: Grasshopper, F: FnMut(usize)>(
+    configpath: &str,
+    mgh: Option<GH>,
+    raws: &[RawRequest],
+    logs: &mut Logs,
+    mut before: F,
+) -> Vec<(Decision, Tags, RequestInfo, Logs)> {
+    let level = logs.level;
+    let mut configs: HashMap<&str, ConfigSnapshot> = HashMap::new();
+    async_std::task::block_on(async {
+        let mut out = Vec::with_capacity(raws.len());
+        for (idx, raw) in raws.iter().enumerate() {
+            let path = request_config_path(configpath, &raw.meta, logs);
+            let snapshot = configs
+                .entry(path)
+                .or_insert_with(|| config_snapshot(path, logs).unwrap_or_else(ConfigSnapshot::empty));
+            let challenger = match &mgh {
+                Some(gh) => Some(Challenger::External(gh)),
+                None => snapshot.config.native_challenge.clone().map(Challenger::Native),
+            };
+            before(idx);
+            let mut rlogs = Logs::new(level);
+            let (decision, tags, rinfo) = inspect_with_config(snapshot, challenger, raw, &mut rlogs).await;
+            out.push((decision, tags, rinfo, rlogs));
+        }
+        out
+    })
 }
 
 // generic entry point when the request map has already been parsed
@@ -196,20 +469,36 @@ pub fn content_filter_check_generic_request_map(
 ) -> (Decision, RequestInfo, Tags) {
     let mut tags = Tags::default();
     logs.debug("Content Filter inspection starts");
-    let waf_profile = match with_config(configpath, logs, |_slogs, cfg| {
-        cfg.content_filter_profiles.get(content_filter_id).cloned()
-    }) {
-        Some(Some(prof)) => prof,
+    let configpath = request_config_path(configpath, &raw.meta, logs);
+    let snapshot = config_snapshot(configpath, logs);
+    let waf_profile = match snapshot
+        .as_ref()
+        .and_then(|s| s.config.content_filter_profiles.get(content_filter_id))
+    {
+        Some(prof) => prof,
         _ => {
             logs.error("Content Filter profile not found");
-            return (Decision::Pass, map_request(logs, &[], &[], 25, raw), tags);
+            return (
+                Decision::Pass,
+                map_request(logs, &[], &[], 25, &[], &ParseBudget::default(), false, raw),
+                tags,
+            );
         }
     };
 
     if let Some(body) = raw.mbody {
         if body.len() > waf_profile.max_body_size {
             logs.error("body too large, exiting early");
-            let reqinfo = map_request(logs, &waf_profile.decoding, &[], 0, raw);
+            let reqinfo = map_request(
+                logs,
+                &waf_profile.decoding,
+                &[],
+                0,
+                &[],
+                &waf_profile.parse_budget,
+                waf_profile.nested_args,
+                raw,
+            );
             return (
                 Decision::Action(body_too_large(waf_profile.max_body_size, body.len())),
                 reqinfo,
@@ -218,15 +507,24 @@ pub fn content_filter_check_generic_request_map(
         }
     }
 
-    let reqinfo = map_request(logs, &waf_profile.decoding, &[], waf_profile.max_body_depth, raw);
+    let reqinfo = map_request(
+        logs,
+        &waf_profile.decoding,
+        &[],
+        waf_profile.max_body_depth,
+        &[],
+        &waf_profile.parse_budget,
+        waf_profile.nested_args,
+        raw,
+    );
 
-    let waf_result = match HSDB.read() {
-        Ok(rd) => content_filter_check(logs, &mut tags, &reqinfo, &waf_profile, rd.get(content_filter_id)),
-        Err(rr) => {
-            logs.error(|| format!("Could not get lock on HSDB: {}", rr));
-            Ok(())
-        }
-    };
+    let waf_result = content_filter_check(
+        logs,
+        &mut tags,
+        &reqinfo,
+        waf_profile,
+        snapshot.as_ref().and_then(|s| s.hsdb.get(content_filter_id)),
+    );
     logs.debug("Content Filter checks done");
 
     (
@@ -238,3 +536,173 @@ pub fn content_filter_check_generic_request_map(
         tags,
     )
 }
+
+/// what was loaded by `preload`
+#[derive(Debug, Default, Serialize)]
+pub struct Preloaded {
+    pub content_filter_profiles: usize,
+    pub signatures: usize,
+    /// the GeoIP databases that were opened
+    pub geoip: Vec<&'static str>,
+    /// the problems that do not prevent the inspection, such as missing GeoIP databases
+    pub warnings: Vec<String>,
+}
+
+/// loads everything the first inspection would otherwise load, to be called when the worker starts
+///
+/// The configuration is loaded and published, the content filter databases are compiled and their scratch spaces
+/// allocated, the GeoIP databases are opened, and a dummy request is mapped. It fails only when the configuration can't
+/// be loaded, or has no security policy.
+pub fn preload(configpath: &str, logs: &mut Logs) -> Result<Preloaded, String> {
+    let snapshot = config_snapshot(configpath, logs)
+        .filter(|s| s.config.default.is_some() || !s.config.securitypolicies.is_empty())
+        .ok_or_else(|| format!("could not load a security policy from {}", configpath))?;
+    let mut out = Preloaded {
+        content_filter_profiles: snapshot.hsdb.len(),
+        ..Preloaded::default()
+    };
+    for (id, rules) in snapshot.hsdb.iter() {
+        out.signatures += rules.ids.len();
+        if let Err(rr) = rules.scanner().and_then(|scanner| scanner.scan(&["curiefense"])) {
+            out.warnings
+                .push(format!("could not scan with the content filter profile {}: {}", id, rr));
+        }
+    }
+    for (label, opened) in maxmind::preload() {
+        match opened {
+            Ok(()) => out.geoip.push(label),
+            Err(rr) => out.warnings.push(rr),
+        }
+    }
+    let raw = RawRequest {
+        ipstr: "127.0.0.1".to_string(),
+        headers: HashMap::new(),
+        header_bytes: HashMap::new(),
+        meta: utils::RequestMeta::from_map(
+            [("method", "GET"), ("path", "/")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )?,
+        mbody: None,
+    };
+    let reqinfo = map_request_default(logs, &raw);
+    tag_request(logs, false, &snapshot.config.globalfilters, &reqinfo);
+    Ok(out)
+}
+
+/// what the process inspects with, see `config_info`
+#[derive(Debug, Serialize)]
+pub struct ConfigInfo {
+    /// the digest of the loaded configuration, `None` when it could not be loaded
+    pub revision: Option<String>,
+    /// the content filter matcher, see the `matcher` module
+    pub matcher: &'static MatcherInfo,
+}
+
+pub fn config_info(configpath: &str, logs: &mut Logs) -> ConfigInfo {
+    ConfigInfo {
+        revision: config_snapshot(configpath, logs).map(|s| s.config.revision_hash.clone()),
+        matcher: matcher::info(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::utils::InspectionRequest;
+
+    #[test]
+    fn batch_order() {
+        let requests: Vec<InspectionRequest> = ["/a", "/b", "/c"]
+            .iter()
+            .map(|path| {
+                serde_json::from_value(serde_json::json!({
+                    "ip": "1.2.3.4",
+                    "meta": { "method": "GET", "path": path },
+                    "headers": { "host": "example.com" }
+                }))
+                .unwrap()
+            })
+            .collect();
+        let raws: Vec<RawRequest> = requests.iter().map(|r| r.to_raw().unwrap()).collect();
+        let mut logs = Logs::default();
+        let results = inspect_batch("/nonexistent", None::<DummyGrasshopper>, &raws, &mut logs);
+        let paths: Vec<&str> = results
+            .iter()
+            .map(|(_, _, rinfo, _)| rinfo.rinfo.qinfo.qpath.as_str())
+            .collect();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+        for (decision, tags, _, _) in &results {
+            assert!(matches!(decision, Decision::Pass));
+            assert!(tags.contains("all"));
+        }
+    }
+
+    #[test]
+    fn preload_missing_config() {
+        let mut logs = Logs::default();
+        assert_eq!(
+            preload("/nonexistent/preload", &mut logs).err(),
+            Some("could not load a security policy from /nonexistent/preload".to_string())
+        );
+    }
+
+    #[cfg(feature = "file-config")]
+    #[test]
+    fn config_override() {
+        let inspect = |configpath: &str, extra: &[(&str, &str)]| {
+            let meta = [("method", "GET"), ("path", "/")]
+                .iter()
+                .chain(extra.iter())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let raw = RawRequest {
+                ipstr: "1.2.3.4".to_string(),
+                headers: std::iter::once(("host".to_string(), "example.com".to_string())).collect(),
+                header_bytes: HashMap::new(),
+                meta: utils::RequestMeta::from_map(meta).unwrap(),
+                mbody: None,
+            };
+            let mut logs = Logs::default();
+            let (_, tags, _) = inspect_generic_request_map(configpath, None::<DummyGrasshopper>, raw, &mut logs);
+            (tags, logs)
+        };
+
+        let (tags, _) = inspect("/nonexistent", &[]);
+        assert!(!tags.contains("securitypolicy:default-entry"));
+        // outside of CURIEFENSE_CONFIG_ROOTS, the selected configuration is ignored
+        let (tags, logs) = inspect("/nonexistent", &[("config_path", "../../cf-config")]);
+        assert!(!tags.contains("securitypolicy:default-entry"));
+        assert!(logs
+            .logs
+            .iter()
+            .any(|l| l.message.contains("is not in CURIEFENSE_CONFIG_ROOTS")));
+        let (tags, logs) = inspect("../../cf-config", &[("securitypolicy", "unknown")]);
+        assert!(tags.contains("securitypolicy:default-entry"));
+        assert!(logs
+            .logs
+            .iter()
+            .any(|l| l.message.contains("Unknown security policy unknown")));
+    }
+
+    #[test]
+    fn installed_config() {
+        // without the signatures, as those the pure-Rust matcher can't compile are logged as errors
+        let documents: HashMap<String, Vec<u8>> = config::CONFIG_FILES
+            .iter()
+            .filter(|f| **f != "contentfilter-rules.json")
+            .filter_map(|f| {
+                std::fs::read(format!("../../cf-config/json/{}", f))
+                    .ok()
+                    .map(|content| (f.to_string(), content))
+            })
+            .collect();
+        let mut logs = Logs::default();
+        assert!(config::install_config("installed", &mut logs, &documents));
+        let snapshot = config_snapshot("installed", &mut logs).unwrap();
+        assert!(snapshot.config.default.is_some());
+        assert!(!snapshot.config.revision_hash.is_empty());
+    }
+}
@@ -1,13 +1,83 @@
+use crate::blocklist::record_ban;
+use crate::counters::{self, Counters, LOCAL};
 use crate::logs::Logs;
-use crate::redis::{extract_bannable_action, get_ban_key, is_banned};
-use redis::RedisResult;
+use crate::notify::{notify, Event};
+use crate::reason::{Initiator, Reason};
+use crate::redis::get_ban_key;
+#[cfg(feature = "redis")]
+use crate::redis::{extract_bannable_action, is_banned, redis_async_conn, BackendConnection};
 
 use crate::config::limit::Limit;
 use crate::config::limit::LimitThreshold;
-use crate::interface::{stronger_decision, SimpleActionT, SimpleDecision, Tags};
-use crate::redis::{redis_async_conn, BanStatus};
+use crate::interface::{stronger_decision, SimpleAction, SimpleActionT, SimpleDecision, Tags};
+use crate::redis::BanStatus;
 use crate::utils::{select_string, RequestInfo};
 
+/// where the limits are counted and the limit bans stored, see `counters`
+enum Store {
+    #[cfg(feature = "redis")]
+    Redis(BackendConnection),
+    Local(&'static Counters),
+}
+
+impl Store {
+    async fn is_banned(&mut self, ban_key: &str) -> bool {
+        match self {
+            #[cfg(feature = "redis")]
+            Store::Redis(cnx) => is_banned(cnx, ban_key).await,
+            Store::Local(local) => local.is_set(ban_key, counters::now()),
+        }
+    }
+
+    async fn count(&mut self, key: &str, timeframe: u64, pairvalue: Option<String>) -> anyhow::Result<i64> {
+        match self {
+            #[cfg(feature = "redis")]
+            Store::Redis(cnx) => Ok(redis_get_limit(cnx, key, timeframe, pairvalue).await?),
+            Store::Local(local) => Ok(local.hit(key, pairvalue.as_deref(), timeframe, counters::now()) as i64),
+        }
+    }
+
+    /// the current count, without counting a hit, for the limits on the responses
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    async fn current(&mut self, key: &str, paired: bool) -> anyhow::Result<i64> {
+        match self {
+            #[cfg(feature = "redis")]
+            Store::Redis(cnx) => {
+                let current: Option<i64> = redis::cmd(if paired { "SCARD" } else { "GET" })
+                    .arg(key)
+                    .query_async(cnx)
+                    .await?;
+                Ok(current.unwrap_or(0))
+            }
+            Store::Local(local) => Ok(local.count(key, counters::now()) as i64),
+        }
+    }
+
+    async fn bannable_action(
+        &mut self,
+        logs: &mut Logs,
+        action: &SimpleAction,
+        key: &str,
+        ban_key: &str,
+        ban_status: BanStatus,
+    ) -> SimpleAction {
+        match self {
+            #[cfg(feature = "redis")]
+            Store::Redis(cnx) => extract_bannable_action(cnx, logs, action, key, ban_key, ban_status).await,
+            Store::Local(local) => match &action.atype {
+                SimpleActionT::Ban(subaction, duration) => {
+                    logs.info(|| format!("Banned key {} for {}s", key, duration));
+                    if let BanStatus::NewBan = ban_status {
+                        local.set(ban_key, *duration, counters::now());
+                    }
+                    *subaction.clone()
+                }
+                _ => action.clone(),
+            },
+        }
+    }
+}
+
 fn build_key(security_policy_name: &str, reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> Option<String> {
     let mut key = security_policy_name.to_string() + &limit.id;
     for kpart in limit.key.iter().map(|r| select_string(reqinfo, r, tags)) {
@@ -17,10 +87,10 @@ fn build_key(security_policy_name: &str, reqinfo: &RequestInfo, tags: &Tags, lim
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn limit_react<CNX: redis::aio::ConnectionLike>(
+async fn limit_react(
     logs: &mut Logs,
     tags: &mut Tags,
-    cnx: &mut CNX,
+    store: &mut Store,
     limit: &Limit,
     threshold: &LimitThreshold,
     key: &str,
@@ -28,23 +98,32 @@ async fn limit_react<CNX: redis::aio::ConnectionLike>(
     ban_status: BanStatus,
 ) -> SimpleDecision {
     tags.insert(&limit.name);
-    let action = extract_bannable_action(cnx, logs, &threshold.action, key, ban_key, ban_status).await;
+    if let (SimpleActionT::Ban(_, duration), BanStatus::NewBan) = (&threshold.action.atype, &ban_status) {
+        notify(Event::Ban {
+            scope: "limit",
+            name: limit.name.clone(),
+            key: key.to_string(),
+            duration: *duration,
+        });
+    }
+    let action = store
+        .bannable_action(logs, &threshold.action, key, ban_key, ban_status)
+        .await;
     SimpleDecision::Action(
         action,
-        serde_json::json!({
-            "initiator": "limit",
-            "limitname": limit.name,
-            "key": key
-        }),
+        Reason::new(Initiator::Limit)
+            .with_name(&limit.name)
+            .with_details(serde_json::json!({ "key": key })),
     )
 }
 
+#[cfg(feature = "redis")]
 async fn redis_get_limit<CNX: redis::aio::ConnectionLike>(
     cnx: &mut CNX,
     key: &str,
     timeframe: u64,
     pairvalue: Option<String>,
-) -> RedisResult<i64> {
+) -> redis::RedisResult<i64> {
     let (mcurrent, mexpire): (Option<i64>, Option<i64>) = match &pairvalue {
         None => {
             redis::pipe()
@@ -79,6 +158,20 @@ async fn redis_get_limit<CNX: redis::aio::ConnectionLike>(
     Ok(current)
 }
 
+/// the limits that apply to the request, with their counter keys, without querying the counters
+pub fn limit_keys<'a>(
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &'a [Limit],
+    tags: &Tags,
+) -> Vec<(&'a Limit, String)> {
+    limits
+        .iter()
+        .filter(|limit| limit_match(tags, limit))
+        .filter_map(|limit| build_key(security_policy_name, reqinfo, tags, limit).map(|key| (limit, key)))
+        .collect()
+}
+
 fn limit_match(tags: &Tags, elem: &Limit) -> bool {
     if elem.exclude.iter().any(|e| tags.contains(e)) {
         return false;
@@ -89,6 +182,27 @@ fn limit_match(tags: &Tags, elem: &Limit) -> bool {
     true
 }
 
+/// the store of the counters, connecting once for all the limits
+#[cfg(feature = "redis")]
+async fn store(logs: &mut Logs) -> Option<Store> {
+    match LOCAL.as_ref() {
+        Some(local) => Some(Store::Local(local)),
+        None => match redis_async_conn().await {
+            Ok(c) => Some(Store::Redis(c)),
+            Err(rr) => {
+                logs.error(|| format!("Could not connect to the redis server {}", rr));
+                None
+            }
+        },
+    }
+}
+
+/// without redis, the limits are always counted locally (see `counters`)
+#[cfg(not(feature = "redis"))]
+async fn store(_logs: &mut Logs) -> Option<Store> {
+    LOCAL.as_ref().map(Store::Local)
+}
+
 pub async fn limit_check(
     logs: &mut Logs,
     security_policy_name: &str,
@@ -102,33 +216,47 @@ pub async fn limit_check(
         return SimpleDecision::Pass;
     }
 
-    // we connect once for all limit tests
-    let mut redis = match redis_async_conn().await {
-        Ok(c) => c,
-        Err(rr) => {
-            logs.error(|| format!("Could not connect to the redis server {}", rr));
-            return SimpleDecision::Pass;
-        }
-    };
+    match store(logs).await {
+        Some(mut store) => check_limits(logs, &mut store, security_policy_name, reqinfo, limits, tags).await,
+        None => SimpleDecision::Pass,
+    }
+}
 
+async fn check_limits(
+    logs: &mut Logs,
+    store: &mut Store,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &mut Tags,
+) -> SimpleDecision {
     let mut out = SimpleDecision::Pass;
 
     for limit in limits {
         if !limit_match(tags, limit) {
             logs.debug(|| format!("limit {} excluded", limit.name));
+            logs.explain("limit", &limit.name, false, || serde_json::json!({ "excluded": true }));
             continue;
         }
 
         let key = match build_key(security_policy_name, reqinfo, tags, limit) {
             // if we can't build the key, it usually means that a header is missing.
             // If that is the case, we continue to the next limit.
-            None => continue,
+            None => {
+                logs.explain(
+                    "limit",
+                    &limit.name,
+                    false,
+                    || serde_json::json!({ "missing_key": true }),
+                );
+                continue;
+            }
             Some(k) => k,
         };
         let ban_key = get_ban_key(&key);
         logs.debug(|| format!("limit={:?} key={}", limit, key));
 
-        if is_banned(&mut redis, &ban_key).await {
+        if store.is_banned(&ban_key).await {
             logs.debug("is banned!");
             tags.insert(&limit.name);
             let ban_threshold: &LimitThreshold = limit
@@ -141,7 +269,7 @@ pub async fn limit_check(
                 limit_react(
                     logs,
                     tags,
-                    &mut redis,
+                    store,
                     limit,
                     ban_threshold,
                     &key,
@@ -156,31 +284,50 @@ pub async fn limit_check(
         let pairvalue = match &limit.pairwith {
             None => None,
             Some(sel) => match select_string(reqinfo, sel, tags) {
-                None => continue,
+                None => {
+                    logs.explain(
+                        "limit",
+                        &limit.name,
+                        false,
+                        || serde_json::json!({ "missing_pair": true }),
+                    );
+                    continue;
+                }
                 Some(x) => Some(x),
             },
         };
 
-        match redis_get_limit(&mut redis, &key, limit.timeframe, pairvalue).await {
+        // the limits on the responses only count them when they are reported, see `report_response`
+        let count = if limit.statuses.is_empty() {
+            store.count(&key, limit.timeframe, pairvalue).await
+        } else {
+            store.current(&key, pairvalue.is_some()).await
+        };
+        match count {
             Err(rr) => logs.error(|| rr.to_string()),
             Ok(current_count) => {
+                // the triggered thresholds are only listed when the request is explained
+                let triggered = || {
+                    limit
+                        .thresholds
+                        .iter()
+                        .map(|t| t.limit)
+                        .filter(move |l| current_count > *l as i64)
+                };
+                logs.explain("limit", &limit.name, triggered().next().is_some(), || {
+                    let thresholds: Vec<u64> = triggered().collect();
+                    serde_json::json!({ "key": key, "count": current_count, "thresholds": thresholds })
+                });
                 for threshold in &limit.thresholds {
                     // Only one action with highest limit larger than current
                     // counter will be applied, all the rest will be skipped.
                     if current_count > threshold.limit as i64 {
+                        if let SimpleActionT::Ban(_, duration) = &threshold.action.atype {
+                            record_ban(reqinfo.rinfo.geoip.ip, *duration);
+                        }
                         out = stronger_decision(
                             out,
-                            limit_react(
-                                logs,
-                                tags,
-                                &mut redis,
-                                limit,
-                                threshold,
-                                &key,
-                                &ban_key,
-                                BanStatus::NewBan,
-                            )
-                            .await,
+                            limit_react(logs, tags, store, limit, threshold, &key, &ban_key, BanStatus::NewBan).await,
                         );
                     }
                 }
@@ -189,3 +336,106 @@ pub async fn limit_check(
     }
     out
 }
+
+/// counts the response of a request, for the limits on its status, returning the number of counters that were hit
+///
+/// The integrations call it once the upstream answered, with the tags of the inspection, so that the keys and the
+/// `include` and `exclude` conditions are evaluated as for the request.
+pub async fn report_response(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &Tags,
+    status: u32,
+) -> usize {
+    if !limits.iter().any(|l| l.statuses.contains(&status)) {
+        return 0;
+    }
+    match store(logs).await {
+        Some(mut store) => count_response(logs, &mut store, security_policy_name, reqinfo, limits, tags, status).await,
+        None => 0,
+    }
+}
+
+async fn count_response(
+    logs: &mut Logs,
+    store: &mut Store,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &Tags,
+    status: u32,
+) -> usize {
+    let mut hits = 0;
+    for limit in limits.iter().filter(|l| l.statuses.contains(&status)) {
+        if !limit_match(tags, limit) {
+            continue;
+        }
+        let key = match build_key(security_policy_name, reqinfo, tags, limit) {
+            Some(k) => k,
+            None => continue,
+        };
+        let pairvalue = match &limit.pairwith {
+            None => None,
+            Some(sel) => match select_string(reqinfo, sel, tags) {
+                None => continue,
+                Some(x) => Some(x),
+            },
+        };
+        match store.count(&key, limit.timeframe, pairvalue).await {
+            Ok(count) => {
+                logs.debug(|| format!("limit {}: {} responses with status {}", limit.name, count, status));
+                hits += 1;
+            }
+            Err(rr) => logs.error(|| rr.to_string()),
+        }
+    }
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawLimit;
+    use crate::test_utils::RequestBuilder;
+
+    #[test]
+    fn response_limits() {
+        let raw: RawLimit = serde_json::from_value(serde_json::json!({
+            "id": "failures",
+            "name": "too-many-failures",
+            "timeframe": "60",
+            "thresholds": [{"limit": "2", "action": {"type": "default"}}],
+            "pairwith": {},
+            "statuses": [401, 403]
+        }))
+        .unwrap();
+        let limits: Vec<Limit> = Limit::resolve(&mut Logs::default(), vec![raw]).into_values().collect();
+        let counters: &'static Counters = Box::leak(Box::new(Counters::new(1)));
+        let mut store = Store::Local(counters);
+        let reqinfo = RequestBuilder::get("/admin").request_info();
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        async_std::task::block_on(async {
+            // the requests themselves are not counted
+            for _ in 0..5 {
+                let decision = check_limits(&mut logs, &mut store, "secpol", &reqinfo, &limits, &mut tags).await;
+                assert!(matches!(decision, SimpleDecision::Pass));
+            }
+            assert_eq!(
+                count_response(&mut logs, &mut store, "secpol", &reqinfo, &limits, &tags, 200).await,
+                0
+            );
+            for _ in 0..3 {
+                assert_eq!(
+                    count_response(&mut logs, &mut store, "secpol", &reqinfo, &limits, &tags, 401).await,
+                    1
+                );
+            }
+            let decision = check_limits(&mut logs, &mut store, "secpol", &reqinfo, &limits, &mut tags).await;
+            assert!(matches!(decision, SimpleDecision::Action(_, _)));
+            assert!(tags.contains("too-many-failures"));
+        });
+    }
+}
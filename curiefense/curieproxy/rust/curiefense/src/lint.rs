@@ -0,0 +1,202 @@
+use crate::config::contentfilter::check_rule;
+use crate::config::raw::{ContentFilterGroup, ContentFilterRule};
+use crate::config::{Config, CONFIG_FILES};
+use crate::logs::{LogLevel, Logs};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    /// the configuration file, when the problem is specific to one
+    pub file: Option<String>,
+    /// the id of the entry, when the problem is specific to one
+    pub id: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LintReport {
+    pub path: String,
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl LintReport {
+    fn push(&mut self, severity: Severity, file: Option<&str>, id: Option<&str>, message: String) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        self.issues.push(Issue {
+            severity,
+            file: file.map(|f| f.to_string()),
+            id: id.map(|i| i.to_string()),
+            message,
+        });
+    }
+
+    /// the configuration has errors, or warnings when `strict` is set
+    pub fn failed(&self, strict: bool) -> bool {
+        self.errors > 0 || (strict && self.warnings > 0)
+    }
+}
+
+/// the entries of a file, when it is a JSON list, the resolution reporting the other files
+fn entries(documents: &HashMap<String, Vec<u8>>, fname: &str) -> Vec<serde_json::Value> {
+    documents
+        .get(fname)
+        .and_then(|content| serde_json::from_slice(content).ok())
+        .unwrap_or_default()
+}
+
+fn check_ids(report: &mut LintReport, fname: &str, entries: &[serde_json::Value]) {
+    let mut seen = HashSet::new();
+    for id in entries.iter().filter_map(|e| e.get("id").and_then(|i| i.as_str())) {
+        if !seen.insert(id) {
+            report.push(Severity::Error, Some(fname), Some(id), "duplicated id".to_string());
+        }
+    }
+}
+
+fn check_signatures(report: &mut LintReport, documents: &HashMap<String, Vec<u8>>) {
+    const RULES: &str = "contentfilter-rules.json";
+    const GROUPS: &str = "contentfilter-groups.json";
+    let mut ids = HashSet::new();
+    for value in entries(documents, RULES) {
+        // the entries that do not match the schema are reported by the resolution
+        if let Ok(rule) = serde_json::from_value::<ContentFilterRule>(value) {
+            if let Err(rr) = check_rule(&rule) {
+                report.push(
+                    Severity::Error,
+                    Some(RULES),
+                    Some(&rule.id),
+                    format!("the signature can not be compiled: {}", rr),
+                );
+            }
+            ids.insert(rule.id);
+        }
+    }
+    for value in entries(documents, GROUPS) {
+        if let Ok(group) = serde_json::from_value::<ContentFilterGroup>(value) {
+            for sig in group.signatures.iter().filter(|s| !ids.contains(*s)) {
+                report.push(
+                    Severity::Warning,
+                    Some(GROUPS),
+                    Some(sig),
+                    "unknown signature in a signature group".to_string(),
+                );
+            }
+        }
+    }
+}
+
+/// lints the configuration stored at `basepath`
+pub fn lint(basepath: &str) -> LintReport {
+    let mut report = LintReport {
+        path: basepath.to_string(),
+        ..LintReport::default()
+    };
+    let base = Path::new(basepath).join("json");
+    let mut documents = HashMap::new();
+    for fname in CONFIG_FILES.iter() {
+        match std::fs::read(base.join(fname)) {
+            Ok(content) => {
+                documents.insert(fname.to_string(), content);
+            }
+            Err(rr) => report.push(
+                Severity::Error,
+                Some(fname),
+                None,
+                format!("could not read the file: {}", rr),
+            ),
+        }
+    }
+    for fname in CONFIG_FILES.iter() {
+        check_ids(&mut report, fname, &entries(&documents, fname));
+    }
+    check_signatures(&mut report, &documents);
+
+    let mut logs = Logs::new(LogLevel::Warning);
+    let (config, _) = Config::from_documents(&mut logs, SystemTime::now(), &base, &documents);
+    for log in logs.logs {
+        let severity = match log.level {
+            LogLevel::Error => Severity::Error,
+            LogLevel::Warning => Severity::Warning,
+            _ => continue,
+        };
+        report.push(severity, None, None, log.message);
+    }
+    if config.default.is_none() && config.securitypolicies.is_empty() {
+        report.push(
+            Severity::Error,
+            Some("securitypolicy.json"),
+            None,
+            "no security policy is defined".to_string(),
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, fname: &str, value: serde_json::Value) {
+        std::fs::write(dir.join(fname), value.to_string()).unwrap();
+    }
+
+    #[test]
+    fn lint_problems() {
+        let root = std::env::temp_dir().join(format!("curiefense-lint-{}", std::process::id()));
+        let dir = root.join("json");
+        std::fs::create_dir_all(&dir).unwrap();
+        write(
+            &dir,
+            "acl-profiles.json",
+            serde_json::json!([
+                { "id": "a", "name": "a", "allow": [], "allow_bot": [], "deny": [], "deny_bot": [], "passthrough": [], "force_deny": [] },
+                { "id": "a", "name": "a", "allow": [], "allow_bot": [], "deny": [], "deny_bot": [], "passthrough": [], "force_deny": [] },
+                { "id": "b", "name": "b" }
+            ]),
+        );
+        write(
+            &dir,
+            "contentfilter-rules.json",
+            serde_json::json!([
+                { "id": "100", "operand": "(unclosed", "risk": 5, "category": "sqli", "subcategory": "test" }
+            ]),
+        );
+        write(
+            &dir,
+            "contentfilter-groups.json",
+            serde_json::json!([{ "tags": ["x"], "signatures": ["100", "101"] }]),
+        );
+        let report = lint(&root.to_string_lossy());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let has = |severity: Severity, id: Option<&str>, message: &str| {
+            report
+                .issues
+                .iter()
+                .any(|i| i.severity == severity && i.id.as_deref() == id && i.message.contains(message))
+        };
+        assert!(has(Severity::Error, None, "could not read the file"));
+        assert!(has(Severity::Error, Some("a"), "duplicated id"));
+        assert!(has(Severity::Error, None, "when resolving entry b from"));
+        assert!(has(Severity::Error, Some("100"), "can not be compiled"));
+        assert!(has(Severity::Warning, Some("101"), "unknown signature"));
+        assert!(has(Severity::Error, None, "no security policy is defined"));
+        assert!(report.failed(false));
+    }
+}
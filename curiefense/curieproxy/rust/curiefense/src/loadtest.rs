@@ -0,0 +1,257 @@
+use crate::accesslog::AccessLog;
+use crate::config::ConfigSnapshot;
+use crate::grasshopper::DummyGrasshopper;
+use crate::logs::{LogLevel, Logs};
+use crate::replay::to_request;
+use crate::utils::{InspectionRequest, RawRequest};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadOptions {
+    /// the target rate, in requests per second, the requests being sent as fast as possible when it is zero
+    pub rate: f64,
+    /// the number of requests to send, the corpus being replayed in a loop
+    pub requests: usize,
+    pub workers: usize,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            rate: 0.0,
+            requests: 1000,
+            workers: 1,
+        }
+    }
+}
+
+/// latency percentiles, in microseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Percentiles {
+    pub fn new(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Percentiles::default();
+        }
+        samples.sort_unstable();
+        let count = samples.len();
+        let rank = |q: f64| samples[((q * count as f64).ceil() as usize).clamp(1, count) - 1];
+        Percentiles {
+            count,
+            mean: samples.iter().sum::<u64>() as f64 / count as f64,
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+            max: samples[count - 1],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LoadReport {
+    pub requests: usize,
+    pub blocked: usize,
+    /// the corpus entries that could not be turned into requests
+    pub invalid: usize,
+    pub elapsed_secs: f64,
+    /// the rate that was achieved, in requests per second
+    pub rate: f64,
+    /// from the due time of the requests to the decision
+    pub latency: Percentiles,
+    /// the time spent inspecting the requests
+    pub inspection: Percentiles,
+    pub phases: BTreeMap<String, Percentiles>,
+}
+
+/// the samples of a worker, in microseconds
+#[derive(Default)]
+struct Samples {
+    blocked: usize,
+    latency: Vec<u64>,
+    inspection: Vec<u64>,
+    phases: BTreeMap<String, Vec<u64>>,
+}
+
+impl Samples {
+    fn record(&mut self, due: Instant, logs: &Logs, blocked: bool) {
+        let now = Instant::now();
+        if blocked {
+            self.blocked += 1;
+        }
+        self.latency.push(now.saturating_duration_since(due).as_micros() as u64);
+        self.inspection.push(now.duration_since(logs.start).as_micros() as u64);
+        let mut phase_start = 0;
+        for timing in &logs.phases {
+            self.phases
+                .entry(timing.phase.clone())
+                .or_default()
+                .push(timing.elapsed_micros.saturating_sub(phase_start));
+            phase_start = timing.elapsed_micros;
+        }
+    }
+
+    fn merge(&mut self, other: Samples) {
+        self.blocked += other.blocked;
+        self.latency.extend(other.latency);
+        self.inspection.extend(other.inspection);
+        for (phase, samples) in other.phases {
+            self.phases.entry(phase).or_default().extend(samples);
+        }
+    }
+}
+
+/// loads a corpus: a JSON list of requests in the format of the HTTP inspection service, or access log records, one
+/// JSON record per line (see `replay`)
+pub fn load_corpus(content: &str) -> Result<Vec<InspectionRequest>, String> {
+    if content.trim_start().starts_with('[') {
+        return serde_json::from_str(content).map_err(|rr| format!("invalid request list: {}", rr));
+    }
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            serde_json::from_str::<AccessLog>(line)
+                .map(|log| to_request(&log))
+                .map_err(|rr| format!("line {}: invalid access log record: {}", idx + 1, rr))
+        })
+        .collect()
+}
+
+fn worker(snapshot: &ConfigSnapshot, raws: &[RawRequest], options: &LoadOptions, start: Instant, id: usize) -> Samples {
+    let mut samples = Samples::default();
+    for idx in (id..options.requests).step_by(options.workers) {
+        let due = if options.rate > 0.0 {
+            let due = start + Duration::from_secs_f64(idx as f64 / options.rate);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+            due
+        } else {
+            Instant::now()
+        };
+        let mut logs = Logs::new(LogLevel::Error);
+        let (decision, _, _) = async_std::task::block_on(crate::inspect_with_config(
+            snapshot,
+            None::<DummyGrasshopper>,
+            &raws[idx % raws.len()],
+            &mut logs,
+        ));
+        samples.record(due, &logs, decision.is_blocking());
+    }
+    samples
+}
+
+/// sends the requests of the corpus to the engine, with the configuration snapshot
+pub fn run(snapshot: &ConfigSnapshot, corpus: &[InspectionRequest], options: &LoadOptions) -> LoadReport {
+    let mut invalid = 0;
+    let raws: Vec<RawRequest> = corpus
+        .iter()
+        .filter_map(|rq| {
+            let raw = rq.to_raw().ok();
+            if raw.is_none() {
+                invalid += 1;
+            }
+            raw
+        })
+        .collect();
+    let options = LoadOptions {
+        workers: options.workers.max(1),
+        requests: if raws.is_empty() { 0 } else { options.requests },
+        ..*options
+    };
+
+    let start = Instant::now();
+    let samples = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.workers)
+            .map(|id| {
+                let raws = &raws;
+                let options = &options;
+                scope.spawn(move || worker(snapshot, raws, options, start, id))
+            })
+            .collect();
+        let mut samples = Samples::default();
+        for handle in handles {
+            samples.merge(handle.join().unwrap_or_default());
+        }
+        samples
+    });
+    let elapsed_secs = start.elapsed().as_secs_f64();
+
+    LoadReport {
+        requests: samples.latency.len(),
+        blocked: samples.blocked,
+        invalid,
+        elapsed_secs,
+        rate: if elapsed_secs > 0.0 {
+            samples.latency.len() as f64 / elapsed_secs
+        } else {
+            0.0
+        },
+        latency: Percentiles::new(samples.latency),
+        inspection: Percentiles::new(samples.inspection),
+        phases: samples
+            .phases
+            .into_iter()
+            .map(|(phase, s)| (phase, Percentiles::new(s)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{AclBuilder, ConfigBuilder, PolicyBuilder};
+
+    #[test]
+    fn percentiles() {
+        let p = Percentiles::new((1..=100).rev().collect());
+        assert_eq!((p.count, p.p50, p.p90, p.p99, p.max), (100, 50, 90, 99, 100));
+        assert_eq!(p.mean, 50.5);
+        assert_eq!(Percentiles::new(vec![7]).p99, 7);
+        assert_eq!(Percentiles::new(Vec::new()), Percentiles::default());
+    }
+
+    #[test]
+    fn load_run() {
+        let snapshot = ConfigBuilder::new()
+            .path(
+                "^/admin",
+                PolicyBuilder::new("admin")
+                    .acl(AclBuilder::new("admin").deny(&["all"]).build())
+                    .build(),
+            )
+            .snapshot();
+        let corpus = load_corpus(
+            r#"[
+                {"ip": "1.2.3.4", "meta": {"method": "GET", "path": "/"}, "headers": {}},
+                {"ip": "1.2.3.4", "meta": {"method": "GET", "path": "/admin"}, "headers": {}},
+                {"ip": "1.2.3.4", "meta": {}, "headers": {}}
+            ]"#,
+        )
+        .unwrap();
+        let options = LoadOptions {
+            rate: 1000.0,
+            requests: 20,
+            workers: 2,
+        };
+        let report = run(&snapshot, &corpus, &options);
+        assert_eq!(report.invalid, 1);
+        assert_eq!(report.requests, 20);
+        assert_eq!(report.blocked, 10);
+        // the last request is due after 19ms
+        assert!(report.elapsed_secs >= 0.019);
+        assert_eq!(report.phases["mapping"].count, 20);
+        assert!(report.latency.max >= report.latency.p50);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::captcha::Captcha;
+use crate::config::raw::{LoginAction, RawLoginProtection};
+use crate::counters::{self, Counters};
+use crate::crypto::to_hex;
+use crate::grasshopper::Grasshopper;
+use crate::interface::{Action, Decision, SimpleAction, SimpleActionT, Tags};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::utils::{env_or, RequestInfo};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref FAILURES: Counters = Counters::new(16);
+    static ref BANS: Counters = Counters::new(16);
+    static ref PENDING: Mutex<HashMap<String, Pending>> = Mutex::new(HashMap::new());
+    static ref PENDING_SECS: u64 = env_or("CURIEFENSE_LOGIN_PENDING_SECS", 60);
+    static ref MAX_PENDING: usize = env_or("CURIEFENSE_LOGIN_MAX_PENDING", 100_000);
+}
+
+/// a login attempt waiting for its outcome
+struct Pending {
+    protection: Arc<LoginProtection>,
+    keys: Vec<String>,
+    /// in milliseconds, see `counters::now`
+    expires: u64,
+}
+
+/// the settings of a login endpoint
+#[derive(Debug, Clone)]
+pub struct LoginProtection {
+    methods: Vec<String>,
+    username_field: Option<String>,
+    failure_statuses: Vec<u32>,
+    window: u64,
+    ip_threshold: u64,
+    fingerprint_threshold: u64,
+    username_threshold: u64,
+    action: LoginAction,
+    ban_duration: u64,
+}
+
+/// the hash of the user name, so that the user names do not appear in the tags and the logs
+pub fn username_hash(username: &str) -> String {
+    let hash = to_hex(&Sha256::digest(username.trim().to_lowercase().as_bytes()));
+    hash[..16].to_string()
+}
+
+impl LoginProtection {
+    pub fn resolve(raw: &RawLoginProtection) -> Self {
+        LoginProtection {
+            methods: raw.methods.iter().map(|m| m.to_uppercase()).collect(),
+            username_field: raw.username_field.clone(),
+            failure_statuses: raw.failure_statuses.clone(),
+            window: raw.window,
+            ip_threshold: raw.ip_threshold,
+            fingerprint_threshold: raw.fingerprint_threshold,
+            username_threshold: raw.username_threshold,
+            action: raw.action,
+            ban_duration: raw.ban_duration,
+        }
+    }
+
+    /// the counter keys of the attempt, with their dimension and threshold
+    fn keys(&self, entry: &str, reqinfo: &RequestInfo) -> Vec<(&'static str, String, u64)> {
+        let mut keys = vec![
+            ("ip", reqinfo.rinfo.geoip.ipstr.clone(), self.ip_threshold),
+            ("fingerprint", reqinfo.fingerprint.clone(), self.fingerprint_threshold),
+        ];
+        if let Some(username) = self
+            .username_field
+            .as_ref()
+            .and_then(|f| reqinfo.rinfo.qinfo.args.get_str(f))
+            .filter(|u| !u.trim().is_empty())
+        {
+            keys.push(("username", username_hash(username), self.username_threshold));
+        }
+        keys.into_iter()
+            .filter(|(_, value, threshold)| *threshold > 0 && !value.is_empty())
+            .map(|(dimension, value, threshold)| (dimension, format!("{}\n{}\n{}", entry, dimension, value), threshold))
+            .collect()
+    }
+
+    /// checks a login attempt, returning the decision when the client is banned or a threshold tripped
+    ///
+    /// the attempt is remembered, so that its outcome can be recorded
+    #[allow(clippy::too_many_arguments)]
+    pub fn check<GH: Grasshopper>(
+        self: &Arc<Self>,
+        logs: &mut Logs,
+        entry: &str,
+        reqinfo: &RequestInfo,
+        tags: &mut Tags,
+        is_human: bool,
+        mgh: &Option<GH>,
+        captcha: Option<&Captcha>,
+    ) -> Option<Decision> {
+        if !self.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+            return None;
+        }
+        tags.insert("login:attempt");
+        let now = counters::now();
+        let keys = self.keys(entry, reqinfo);
+        if let Some((_, value, _)) = keys.iter().find(|(d, _, _)| *d == "username") {
+            tags.insert_qualified("login-user", value.rsplit('\n').next().unwrap_or(""));
+        }
+        let banned: Vec<&str> = keys
+            .iter()
+            .filter(|(_, key, _)| BANS.is_set(key, now))
+            .map(|(d, _, _)| *d)
+            .collect();
+        let tripped: Vec<&str> = keys
+            .iter()
+            .filter(|(_, key, threshold)| FAILURES.count(key, now) >= *threshold)
+            .map(|(d, _, _)| *d)
+            .collect();
+        self.remember(
+            &reqinfo.request_id,
+            keys.iter().map(|(_, k, _)| k.clone()).collect(),
+            now,
+        );
+
+        if !banned.is_empty() {
+            tags.insert("login:banned");
+            return Some(Decision::Action(Action {
+                status: 403,
+                ban: true,
+                reason: Reason::new(Initiator::Login)
+                    .with_message(format!("login attempts banned by {}", banned.join(", "))),
+                ..Action::default()
+            }));
+        }
+        if tripped.is_empty() {
+            return None;
+        }
+        for dimension in &tripped {
+            tags.insert_qualified("login-tripped", dimension);
+        }
+        logs.debug(|| format!("login failure thresholds tripped: {}", tripped.join(", ")));
+        let reason =
+            Reason::new(Initiator::Login).with_message(format!("too many login failures by {}", tripped.join(", ")));
+        match self.action {
+            LoginAction::Ban => {
+                for (_, key, _) in keys.iter().filter(|(d, _, _)| tripped.contains(d)) {
+                    BANS.set(key, self.ban_duration, now);
+                }
+                Some(Decision::Action(Action {
+                    status: 403,
+                    ban: true,
+                    reason,
+                    ..Action::default()
+                }))
+            }
+            LoginAction::Challenge if is_human => None,
+            LoginAction::Challenge => {
+                let challenge = SimpleAction {
+                    atype: SimpleActionT::Challenge,
+                    status: 403,
+                    reason: "login".to_string(),
+                    template: None,
+                };
+                Some(challenge.to_decision(is_human, mgh, captcha, reqinfo, reason))
+            }
+        }
+    }
+
+    fn remember(self: &Arc<Self>, request_id: &str, keys: Vec<String>, now: u64) {
+        let mut pending = match PENDING.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if pending.len() >= *MAX_PENDING {
+            pending.retain(|_, p| p.expires > now);
+            if pending.len() >= *MAX_PENDING {
+                tracing::warn!(target: "curiefense::login", "too many login attempts waiting for their outcome");
+                return;
+            }
+        }
+        pending.insert(
+            request_id.to_string(),
+            Pending {
+                protection: self.clone(),
+                keys,
+                expires: now + *PENDING_SECS * 1000,
+            },
+        );
+    }
+}
+
+/// records the outcome of a login attempt, from the status of its response
+///
+/// returns false when the request was not a login attempt, or was forgotten
+pub fn record_result(request_id: &str, status: u32) -> bool {
+    let now = counters::now();
+    let attempt = match PENDING.lock() {
+        Ok(mut pending) => pending.remove(request_id),
+        Err(poisoned) => poisoned.into_inner().remove(request_id),
+    };
+    let attempt = match attempt.filter(|a| a.expires > now) {
+        Some(a) => a,
+        None => return false,
+    };
+    if attempt.protection.failure_statuses.contains(&status) {
+        for key in &attempt.keys {
+            FAILURES.hit(key, None, attempt.protection.window, now);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawLoginProtection;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::test_utils::RequestBuilder;
+
+    fn protection(action: LoginAction) -> Arc<LoginProtection> {
+        let mut raw: RawLoginProtection =
+            serde_json::from_str(r#"{"username_field": "user", "username_threshold": 3}"#).unwrap();
+        raw.action = action;
+        Arc::new(LoginProtection::resolve(&raw))
+    }
+
+    fn attempt(
+        protection: &Arc<LoginProtection>,
+        entry: &str,
+        ip: &str,
+        user: &str,
+        id: &str,
+    ) -> (Option<Decision>, Tags) {
+        let reqinfo = RequestBuilder::post("/login", format!("user={}&password=x", user))
+            .ip(ip)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("x-request-id", id)
+            .request_info();
+        let mut tags = Tags::default();
+        let decision = protection.check(
+            &mut Logs::default(),
+            entry,
+            &reqinfo,
+            &mut tags,
+            false,
+            &None::<DummyGrasshopper>,
+            None,
+        );
+        (decision, tags)
+    }
+
+    #[test]
+    fn username_failures() {
+        let protection = protection(LoginAction::Ban);
+        for idx in 0..3 {
+            let id = format!("username-failures-{}", idx);
+            let (decision, tags) = attempt(&protection, "username-failures", &format!("10.0.0.{}", idx), "Bob", &id);
+            assert!(decision.is_none());
+            assert!(tags.contains("login:attempt"));
+            assert!(tags.contains(&format!("login-user:{}", username_hash("bob"))));
+            assert!(record_result(&id, 401));
+            // recorded once
+            assert!(!record_result(&id, 401));
+        }
+        // a success does not count
+        let (_, _) = attempt(
+            &protection,
+            "username-failures",
+            "10.0.1.1",
+            "alice",
+            "username-failures-ok",
+        );
+        assert!(record_result("username-failures-ok", 200));
+
+        let (decision, tags) = attempt(
+            &protection,
+            "username-failures",
+            "10.0.1.2",
+            "%20BOB",
+            "username-failures-4",
+        );
+        match decision {
+            Some(Decision::Action(action)) => {
+                assert_eq!(action.reason.initiator, Initiator::Login);
+                assert!(action.ban);
+            }
+            d => panic!("unexpected decision {:?}", d),
+        }
+        assert!(tags.contains("login-tripped:username"));
+        // banned afterwards, on this entry only
+        let (decision, tags) = attempt(
+            &protection,
+            "username-failures",
+            "10.0.1.3",
+            "bob",
+            "username-failures-5",
+        );
+        assert!(decision.is_some());
+        assert!(tags.contains("login:banned"));
+        let (decision, _) = attempt(&protection, "other-entry", "10.0.1.3", "bob", "username-failures-6");
+        assert!(decision.is_none());
+        assert!(!record_result("unknown", 401));
+    }
+
+    #[test]
+    fn challenge_escalation() {
+        let protection = protection(LoginAction::Challenge);
+        for idx in 0..3 {
+            let id = format!("challenge-escalation-{}", idx);
+            let (decision, _) = attempt(&protection, "challenge-escalation", "10.1.0.1", "carol", &id);
+            assert!(decision.is_none());
+            record_result(&id, 403);
+        }
+        let (decision, tags) = attempt(
+            &protection,
+            "challenge-escalation",
+            "10.1.0.2",
+            "carol",
+            "challenge-escalation-4",
+        );
+        // without grasshopper, the challenge falls back to a block
+        assert!(decision.is_some());
+        assert!(tags.contains("login-tripped:username"));
+        assert!(!tags.contains("login-tripped:ip"));
+    }
+}
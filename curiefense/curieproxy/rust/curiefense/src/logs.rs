@@ -1,4 +1,7 @@
-use serde::Serialize;
+use crate::contentfilter::InspectionStats;
+use crate::explain::ExplainStep;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::time::Instant;
 
 #[derive(Debug, Clone)]
@@ -6,6 +9,21 @@ pub struct Logs {
     pub level: LogLevel,
     pub start: Instant,
     pub logs: Vec<Log>,
+    /// end of the inspection phases, recorded regardless of the log level
+    pub phases: Vec<PhaseTiming>,
+    /// time spent matching each content filter signature group (the signature category, or the libinjection test), in
+    /// microseconds
+    pub signature_costs: HashMap<String, u64>,
+    /// the explain trace, when enabled for this request (see the `explain` module)
+    pub explain: Option<Vec<ExplainStep>>,
+    /// what the content filter inspected, once the request was mapped
+    pub inspection: Option<InspectionStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub phase: String,
+    pub elapsed_micros: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -37,6 +55,20 @@ impl LogLevel {
     }
 }
 
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warning" => Ok(LogLevel::Warning),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!("invalid log level {}", s)),
+        }
+    }
+}
+
 impl std::fmt::Display for Log {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{} {}µs {}", self.level.short(), self.elapsed_micros, self.message)
@@ -49,6 +81,10 @@ impl Default for Logs {
             start: Instant::now(),
             level: LogLevel::Debug,
             logs: Vec::new(),
+            phases: Vec::new(),
+            signature_costs: HashMap::new(),
+            explain: None,
+            inspection: None,
         }
     }
 }
@@ -80,6 +116,10 @@ impl Logs {
             start: Instant::now(),
             level: lvl,
             logs: Vec::new(),
+            phases: Vec::new(),
+            signature_costs: HashMap::new(),
+            explain: None,
+            inspection: None,
         }
     }
 
@@ -95,6 +135,41 @@ impl Logs {
         })
     }
 
+    /// records the end of an inspection phase
+    pub fn phase(&mut self, phase: &str) {
+        self.phases.push(PhaseTiming {
+            phase: phase.to_string(),
+            elapsed_micros: Instant::now().duration_since(self.start).as_micros() as u64,
+        });
+    }
+
+    /// charges the time spent matching a value to a signature group
+    pub fn signature_cost(&mut self, group: &str, micros: u64) {
+        *self.signature_costs.entry(group.to_string()).or_default() += micros;
+    }
+
+    /// records an explain step, when the explain trace is enabled
+    ///
+    /// the details are only computed in that case
+    pub fn explain<F: FnOnce() -> serde_json::Value>(
+        &mut self,
+        phase: &'static str,
+        name: &str,
+        matched: bool,
+        details: F,
+    ) {
+        let elapsed_micros = self.start.elapsed().as_micros() as u64;
+        if let Some(steps) = self.explain.as_mut() {
+            steps.push(ExplainStep {
+                elapsed_micros,
+                phase,
+                name: name.to_string(),
+                matched,
+                details: details(),
+            });
+        }
+    }
+
     pub fn debug<S: CheapString>(&mut self, message: S) {
         self.log(LogLevel::Debug, message);
     }
@@ -0,0 +1,193 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatcherBackend {
+    HyperscanAvx2,
+    HyperscanSse,
+    Regex,
+}
+
+impl MatcherBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            MatcherBackend::HyperscanAvx2 => "hyperscan-avx2",
+            MatcherBackend::HyperscanSse => "hyperscan-sse",
+            MatcherBackend::Regex => "regex",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [
+            MatcherBackend::HyperscanAvx2,
+            MatcherBackend::HyperscanSse,
+            MatcherBackend::Regex,
+        ]
+        .iter()
+        .copied()
+        .find(|b| b.name() == name)
+    }
+}
+
+/// what the host supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Probe {
+    /// hyperscan is built in, and the CPU has the instructions it requires
+    pub hyperscan: bool,
+    pub avx2: bool,
+}
+
+impl Probe {
+    pub fn host() -> Self {
+        Probe {
+            hyperscan: hyperscan_valid(),
+            avx2: avx2(),
+        }
+    }
+
+    /// the best matcher the host supports
+    pub fn best(&self) -> MatcherBackend {
+        match (self.hyperscan, self.avx2) {
+            (true, true) => MatcherBackend::HyperscanAvx2,
+            (true, false) => MatcherBackend::HyperscanSse,
+            (false, _) => MatcherBackend::Regex,
+        }
+    }
+
+    pub fn supports(&self, backend: MatcherBackend) -> bool {
+        match backend {
+            MatcherBackend::HyperscanAvx2 => self.hyperscan && self.avx2,
+            MatcherBackend::HyperscanSse => self.hyperscan,
+            MatcherBackend::Regex => true,
+        }
+    }
+}
+
+#[cfg(feature = "hyperscan")]
+fn hyperscan_valid() -> bool {
+    hyperscan::Platform::is_valid().is_ok()
+}
+
+#[cfg(not(feature = "hyperscan"))]
+fn hyperscan_valid() -> bool {
+    false
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn avx2() -> bool {
+    std::is_x86_feature_detected!("avx2")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn avx2() -> bool {
+    false
+}
+
+/// the selected matcher, and what it was selected from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MatcherInfo {
+    pub backend: MatcherBackend,
+    /// the value of `CURIEFENSE_MATCHER`
+    pub requested: String,
+    pub host: Probe,
+}
+
+/// the matcher for the requested one, with the warning to log when it could not be used
+pub fn select(host: Probe, requested: &str) -> (MatcherInfo, Option<String>) {
+    let (backend, warning) = match MatcherBackend::parse(requested) {
+        Some(b) if host.supports(b) => (b, None),
+        Some(b) => (
+            host.best(),
+            Some(format!(
+                "the {} matcher is not supported on this host, using {}",
+                b.name(),
+                host.best().name()
+            )),
+        ),
+        None if requested == "auto" => (host.best(), None),
+        None => (
+            host.best(),
+            Some(format!("unknown matcher {}, using {}", requested, host.best().name())),
+        ),
+    };
+    (
+        MatcherInfo {
+            backend,
+            requested: requested.to_string(),
+            host,
+        },
+        warning,
+    )
+}
+
+lazy_static! {
+    static ref SELECTED: MatcherInfo = {
+        let requested = std::env::var("CURIEFENSE_MATCHER")
+            .ok()
+            .filter(|m| !m.is_empty())
+            .unwrap_or_else(|| "auto".to_string());
+        let (info, warning) = select(Probe::host(), &requested);
+        if let Some(warning) = warning {
+            tracing::warn!(target: "curiefense::matcher", "{}", warning);
+        }
+        tracing::info!(target: "curiefense::matcher", backend = info.backend.name(), "content filter matcher selected");
+        info
+    };
+}
+
+pub fn info() -> &'static MatcherInfo {
+    &SELECTED
+}
+
+pub fn backend() -> MatcherBackend {
+    SELECTED.backend
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection() {
+        let avx2 = Probe {
+            hyperscan: true,
+            avx2: true,
+        };
+        let sse = Probe {
+            hyperscan: true,
+            avx2: false,
+        };
+        let none = Probe {
+            hyperscan: false,
+            avx2: true,
+        };
+        assert_eq!(select(avx2, "auto").0.backend, MatcherBackend::HyperscanAvx2);
+        assert_eq!(select(sse, "auto").0.backend, MatcherBackend::HyperscanSse);
+        assert_eq!(select(none, "auto").0.backend, MatcherBackend::Regex);
+        assert_eq!(
+            select(avx2, "regex"),
+            (
+                MatcherInfo {
+                    backend: MatcherBackend::Regex,
+                    requested: "regex".to_string(),
+                    host: avx2
+                },
+                None
+            )
+        );
+
+        // the unsupported or unknown matchers fall back to the best one
+        let (info, warning) = select(sse, "hyperscan-avx2");
+        assert_eq!(info.backend, MatcherBackend::HyperscanSse);
+        assert!(warning.unwrap().contains("not supported"));
+        let (info, warning) = select(none, "hyperscan");
+        assert_eq!(info.backend, MatcherBackend::Regex);
+        assert!(warning.is_some());
+
+        assert_eq!(
+            serde_json::to_value(info).unwrap(),
+            serde_json::json!({"backend": "regex", "requested": "hyperscan", "host": {"hyperscan": false, "avx2": true}})
+        );
+    }
+}
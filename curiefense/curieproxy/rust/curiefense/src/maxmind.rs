@@ -1,59 +1,242 @@
 use lazy_static::lazy_static;
 use maxminddb::{
-    geoip2::{Asn, City, Country},
+    geoip2::{AnonymousIp, Asn, City, Country},
     Reader,
 };
 use std::net::IpAddr;
-#[cfg(not(test))]
-use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// the databases are mapped in memory, or read when memory mapping is not available
+#[cfg(feature = "file-config")]
+type Source = memmap::Mmap;
+#[cfg(not(feature = "file-config"))]
+type Source = Vec<u8>;
 
 lazy_static! {
-    // as they are lazy, these loads will not be triggered in test mode
-    static ref ASN: Result<Reader<Vec<u8>>, maxminddb::MaxMindDBError> =
-        Reader::open_readfile("/cf-config/current/config/maxmind/GeoLite2-ASN.mmdb");
-    static ref COUNTRY: Result<Reader<Vec<u8>>, maxminddb::MaxMindDBError> =
-        Reader::open_readfile("/cf-config/current/config/maxmind/GeoLite2-Country.mmdb");
-    static ref CITY: Result<Reader<Vec<u8>>, maxminddb::MaxMindDBError> =
-        Reader::open_readfile("/cf-config/current/config/maxmind/GeoIP2-City.mmdb").or_else(|_|
-        Reader::open_readfile("/cf-config/current/config/maxmind/GeoLite2-City.mmdb"));
+    static ref DIR: PathBuf = std::env::var("CURIEFENSE_MAXMIND_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/cf-config/current/config/maxmind".to_string())
+        .into();
+    static ref RELOAD_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("CURIEFENSE_MAXMIND_RELOAD_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60)
+    );
+    // as they are lazy, these databases will not be opened in test mode
+    static ref ASN: Database = Database::new("ASN", &["GeoLite2-ASN.mmdb"]);
+    static ref COUNTRY: Database = Database::new("country", &["GeoLite2-Country.mmdb"]);
+    static ref CITY: Database = Database::new("city", &["GeoIP2-City.mmdb", "GeoLite2-City.mmdb"]);
+    static ref ANONYMOUS: Database = Database::new("anonymous IP", &["GeoIP2-Anonymous-IP.mmdb"]);
+}
+
+/// the directory of the database files
+pub fn database_dir() -> &'static Path {
+    &DIR
+}
+
+/// what identifies a version of a database file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
 }
 
-/// Retrieves the english name of the country associated with this IP
-#[cfg(not(test))]
-pub fn get_country(addr: IpAddr) -> Result<Country<'static>, String> {
-    match COUNTRY.deref() {
-        Err(rr) => Err(format!("could not read country db: {}", rr)),
-        Ok(db) => db.lookup(addr).map_err(|rr| format!("{}", rr)),
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        std::fs::metadata(path).ok().map(|m| Stamp {
+            modified: m.modified().ok(),
+            len: m.len(),
+        })
     }
 }
 
-#[cfg(not(test))]
-pub fn get_asn(addr: IpAddr) -> Result<Asn<'static>, String> {
-    match ASN.deref() {
-        Err(rr) => Err(format!("could not read ASN db: {}", rr)),
-        Ok(db) => db.lookup(addr).map_err(|rr| format!("{}", rr)),
+struct Loaded {
+    reader: Result<Arc<Reader<Source>>, String>,
+    /// the file that was opened, and its stamp at that time
+    source: Option<(PathBuf, Stamp)>,
+    checked: Instant,
+}
+
+pub struct Database {
+    label: &'static str,
+    /// the candidate file names, by order of preference
+    files: &'static [&'static str],
+    dir: PathBuf,
+    interval: Duration,
+    state: RwLock<Option<Loaded>>,
+}
+
+impl Database {
+    fn new(label: &'static str, files: &'static [&'static str]) -> Self {
+        Self::with_dir(label, files, DIR.clone(), *RELOAD_INTERVAL)
+    }
+
+    fn with_dir(label: &'static str, files: &'static [&'static str], dir: PathBuf, interval: Duration) -> Self {
+        Database {
+            label,
+            files,
+            dir,
+            interval,
+            state: RwLock::new(None),
+        }
+    }
+
+    /// the first candidate file that exists
+    fn locate(&self) -> Option<(PathBuf, Stamp)> {
+        self.files
+            .iter()
+            .map(|f| self.dir.join(f))
+            .find_map(|p| Stamp::of(&p).map(|s| (p, s)))
+    }
+
+    fn open(&self, now: Instant) -> Loaded {
+        let source = self.locate();
+        let reader = match &source {
+            None => Err(format!(
+                "could not read {} db: {} not found in {}",
+                self.label,
+                self.files.join(" or "),
+                self.dir.display()
+            )),
+            #[cfg(feature = "file-config")]
+            Some((path, _)) => Reader::open_mmap(path)
+                .map(Arc::new)
+                .map_err(|rr| format!("could not read {} db: {}", self.label, rr)),
+            #[cfg(not(feature = "file-config"))]
+            Some((path, _)) => Reader::open_readfile(path)
+                .map(Arc::new)
+                .map_err(|rr| format!("could not read {} db: {}", self.label, rr)),
+        };
+        match &reader {
+            Ok(_) => {
+                tracing::info!(target: "curiefense::maxmind", db = self.label, "database opened");
+                crate::cache::invalidate();
+            }
+            Err(rr) => tracing::warn!(target: "curiefense::maxmind", db = self.label, "{}", rr),
+        }
+        Loaded {
+            reader,
+            source,
+            checked: now,
+        }
+    }
+
+    /// the current reader, the file being re-opened when it changed since the last check
+    fn reader(&self, now: Instant) -> Result<Arc<Reader<Source>>, String> {
+        let fresh = |loaded: &Loaded| now.saturating_duration_since(loaded.checked) < self.interval;
+        if let Ok(state) = self.state.read() {
+            if let Some(loaded) = state.as_ref().filter(|l| fresh(l)) {
+                return loaded.reader.clone();
+            }
+        }
+        let mut state = self
+            .state
+            .write()
+            .map_err(|rr| format!("could not read {} db: {}", self.label, rr))?;
+        let reopen = match state.as_mut() {
+            // another lookup already checked the file
+            Some(loaded) if fresh(loaded) => false,
+            Some(loaded) if loaded.source.is_some() && loaded.source == self.locate() => {
+                loaded.checked = now;
+                false
+            }
+            _ => true,
+        };
+        if reopen {
+            *state = Some(self.open(now));
+        }
+        match state.as_ref() {
+            Some(loaded) => loaded.reader.clone(),
+            None => Err(format!("could not read {} db", self.label)),
+        }
     }
 }
 
-#[cfg(not(test))]
-pub fn get_city(addr: IpAddr) -> Result<City<'static>, String> {
-    match CITY.deref() {
-        Err(rr) => Err(format!("could not read city db: {}", rr)),
-        Ok(db) => db.lookup(addr).map_err(|rr| format!("{}", rr)),
+fn reader(db: &Database) -> Result<Arc<Reader<Source>>, String> {
+    if cfg!(test) {
+        return Err("TEST".into());
     }
+    db.reader(Instant::now())
 }
 
-#[cfg(test)]
-pub fn get_country(_addr: IpAddr) -> Result<Country<'static>, String> {
-    Err("TEST".into())
+/// opens the databases, returning their labels with the errors
+pub fn preload() -> Vec<(&'static str, Result<(), String>)> {
+    [&*COUNTRY, &*CITY, &*ASN, &*ANONYMOUS]
+        .iter()
+        .map(|db| (db.label, reader(db).map(|_| ())))
+        .collect()
 }
 
-#[cfg(test)]
-pub fn get_asn(_addr: IpAddr) -> Result<Asn<'static>, String> {
-    Err("TEST".into())
+/// Looks up the country associated with this IP
+pub fn with_country<R>(addr: IpAddr, f: impl FnOnce(Country) -> R) -> Result<R, String> {
+    let db = reader(&COUNTRY)?;
+    db.lookup(addr).map(f).map_err(|rr| format!("{}", rr))
+}
+
+pub fn with_asn<R>(addr: IpAddr, f: impl FnOnce(Asn) -> R) -> Result<R, String> {
+    let db = reader(&ASN)?;
+    db.lookup(addr).map(f).map_err(|rr| format!("{}", rr))
+}
+
+pub fn with_city<R>(addr: IpAddr, f: impl FnOnce(City) -> R) -> Result<R, String> {
+    let db = reader(&CITY)?;
+    db.lookup(addr).map(f).map_err(|rr| format!("{}", rr))
+}
+
+/// Retrieves the anonymous network flags (VPN, public proxies, Tor ...) associated with this IP
+pub fn get_anonymous(addr: IpAddr) -> Result<AnonymousIp, String> {
+    let db = reader(&ANONYMOUS)?;
+    db.lookup(addr).map_err(|rr| format!("{}", rr))
 }
 
 #[cfg(test)]
-pub fn get_city(_addr: IpAddr) -> Result<City<'static>, String> {
-    Err("TEST".into())
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopen_on_change() {
+        let dir = std::env::temp_dir().join(format!("curiefense-maxmind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::with_dir("test", &["a.mmdb", "b.mmdb"], dir.clone(), Duration::from_secs(60));
+        let start = Instant::now();
+
+        let missing = db.reader(start).err().unwrap();
+        assert!(missing.contains("a.mmdb or b.mmdb not found"), "{}", missing);
+
+        // not checked again before the interval
+        std::fs::write(dir.join("b.mmdb"), b"not a database").unwrap();
+        assert_eq!(db.reader(start + Duration::from_secs(30)).err(), Some(missing.clone()));
+
+        let later = start + Duration::from_secs(61);
+        let invalid = db.reader(later).err().unwrap();
+        assert_ne!(invalid, missing);
+        assert_eq!(
+            db.state
+                .read()
+                .unwrap()
+                .as_ref()
+                .and_then(|l| l.source.as_ref())
+                .map(|s| s.0.clone()),
+            Some(dir.join("b.mmdb"))
+        );
+
+        // the preferred file takes over once it exists
+        std::fs::write(dir.join("a.mmdb"), b"not a database either").unwrap();
+        let _ = db.reader(later + Duration::from_secs(61));
+        assert_eq!(
+            db.state
+                .read()
+                .unwrap()
+                .as_ref()
+                .and_then(|l| l.source.as_ref())
+                .map(|s| s.0.clone()),
+            Some(dir.join("a.mmdb"))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,192 @@
+use crate::interface::{Decision, Tags};
+use crate::reason::Initiator;
+use crate::requestmap::Geo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const METADATA_NAMESPACE: &str = "com.curiefense";
+pub const METADATA_SCHEMA_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DynamicMetadata {
+    pub schema_version: u32,
+    /// `pass`, or the action type
+    pub action: String,
+    pub blocking: bool,
+    /// status of the response sent to the client, for blocking actions
+    pub status: Option<u32>,
+    pub initiator: Option<Initiator>,
+    pub request_id: Option<String>,
+    pub rule_ids: Vec<String>,
+    /// the request tags, sorted
+    pub tags: Vec<String>,
+    pub scores: BTreeMap<String, i64>,
+    /// the client location and network, as the engine resolved them
+    #[serde(default)]
+    pub geo: Option<MetadataGeo>,
+    /// a new CSRF token cookie, to set on the response, see the `csrf` module
+    #[serde(default)]
+    pub set_cookie: Option<String>,
+}
+
+/// a flat summary of the geo enrichment, so that the access logs do not have to resolve the IP again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataGeo {
+    /// ISO code
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    /// the organization of the AS
+    pub company: Option<String>,
+    pub anonymous: Option<bool>,
+    pub vpn: Option<bool>,
+    pub hosting: Option<bool>,
+    pub public_proxy: Option<bool>,
+    pub tor: Option<bool>,
+}
+
+impl MetadataGeo {
+    pub fn new(geo: &Geo) -> Self {
+        MetadataGeo {
+            country: geo.country.iso.clone(),
+            city: Some(geo.city.name.clone()).filter(|n| n != "-"),
+            asn: geo.asn,
+            company: geo.company.clone(),
+            anonymous: geo.anonymous.anonymous,
+            vpn: geo.anonymous.vpn,
+            hosting: geo.anonymous.hosting,
+            public_proxy: geo.anonymous.public_proxy,
+            tor: geo.anonymous.tor,
+        }
+    }
+}
+
+impl DynamicMetadata {
+    pub fn new(decision: &Decision, tags: &Tags) -> Self {
+        let mut tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+        let action = match decision {
+            Decision::Pass => None,
+            Decision::Action(a) => Some(a),
+        };
+        if let Some(extra) = action.and_then(|a| a.extra_tags.as_ref()) {
+            tags.extend(extra.iter().cloned());
+        }
+        tags.sort();
+        tags.dedup();
+        let blocking = decision.is_blocking();
+        DynamicMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            action: match action {
+                None => "pass".to_string(),
+                Some(a) => serde_json::to_value(a.atype)
+                    .ok()
+                    .and_then(|v| v.as_str().map(|s| s.to_string()))
+                    .unwrap_or_default(),
+            },
+            blocking,
+            status: action.filter(|_| blocking).map(|a| a.status),
+            initiator: action.map(|a| a.reason.initiator),
+            request_id: action.and_then(|a| a.reason.request_id.clone()),
+            rule_ids: action.map(|a| a.reason.rule_ids.clone()).unwrap_or_default(),
+            tags,
+            scores: action.map(|a| a.reason.scores.clone()).unwrap_or_default(),
+            geo: None,
+            set_cookie: None,
+        }
+    }
+
+    /// sets the request id, that is only known from the decision for the actions
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    /// sets the geo enrichment of the client IP
+    pub fn with_geo(mut self, geo: &Geo) -> Self {
+        self.geo = Some(MetadataGeo::new(geo));
+        self
+    }
+
+    /// sets the cookie of the CSRF token issued to the client, if any
+    pub fn with_set_cookie(mut self, set_cookie: Option<String>) -> Self {
+        self.set_cookie = set_cookie;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Action;
+    use crate::reason::Reason;
+    use serde_json::json;
+
+    #[test]
+    fn serialized_fields() {
+        let mut tags = Tags::default();
+        tags.insert("b");
+        tags.insert("a");
+        assert_eq!(
+            serde_json::to_value(DynamicMetadata::new(&Decision::Pass, &tags)).unwrap(),
+            json!({
+                "schema_version": METADATA_SCHEMA_VERSION,
+                "action": "pass",
+                "blocking": false,
+                "status": null,
+                "initiator": null,
+                "request_id": null,
+                "rule_ids": [],
+                "tags": ["a", "b"],
+                "scores": {},
+                "geo": null,
+                "set_cookie": null
+            })
+        );
+
+        let action = Action {
+            status: 403,
+            reason: Reason::new(Initiator::ContentFilter).with_tags(&["cf-rule-id:42".to_string()]),
+            extra_tags: Some(std::iter::once("c".to_string()).collect()),
+            ..Action::default()
+        };
+        let metadata = DynamicMetadata::new(&Decision::Action(action), &tags);
+        assert_eq!(metadata.action, "block");
+        assert!(metadata.blocking);
+        assert_eq!(metadata.status, Some(403));
+        assert_eq!(metadata.initiator, Some(Initiator::ContentFilter));
+        assert_eq!(metadata.rule_ids, vec!["42".to_string()]);
+        assert_eq!(metadata.tags, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn geo() {
+        let geo: Geo = serde_json::from_value(json!({
+            "location": {},
+            "city": {"name": "-"},
+            "eu": false,
+            "country": {"name": "United States", "iso": "US"},
+            "continent": {"name": null, "code": null},
+            "asn": 15169,
+            "company": "Google LLC",
+            "region": null,
+            "subregion": null,
+            "anonymous": {"anonymous": true, "vpn": false, "hosting": true, "public_proxy": null, "tor": null}
+        }))
+        .unwrap();
+        let metadata = DynamicMetadata::new(&Decision::Pass, &Tags::default()).with_geo(&geo);
+        assert_eq!(
+            serde_json::to_value(metadata.geo).unwrap(),
+            json!({
+                "country": "US",
+                "city": null,
+                "asn": 15169,
+                "company": "Google LLC",
+                "anonymous": true,
+                "vpn": false,
+                "hosting": true,
+                "public_proxy": null,
+                "tor": null
+            })
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::raw::{MethodAction, RawMethodPolicy};
+use crate::interface::{Action, Tags};
+use crate::reason::{Initiator, Reason};
+use crate::utils::RequestInfo;
+use std::collections::HashMap;
+
+/// the methods of RFC 9110, and PATCH
+pub const STANDARD_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+#[derive(Debug, Clone)]
+pub struct MethodPolicy {
+    allowed: Vec<String>,
+    override_headers: Vec<String>,
+    override_args: Vec<String>,
+    action: MethodAction,
+}
+
+impl MethodPolicy {
+    pub fn resolve(raw: &RawMethodPolicy) -> Self {
+        let allowed = if raw.methods.is_empty() {
+            STANDARD_METHODS.iter().map(|m| m.to_string()).collect()
+        } else {
+            raw.methods.iter().map(|m| m.trim().to_uppercase()).collect()
+        };
+        MethodPolicy {
+            allowed,
+            override_headers: raw.override_headers.iter().map(|h| h.to_lowercase()).collect(),
+            override_args: raw.override_args.clone(),
+            action: raw.action,
+        }
+    }
+
+    /// the method overrides of the request
+    pub fn overrides(&self, reqinfo: &RequestInfo) -> Vec<String> {
+        let headers = self.override_headers.iter().filter_map(|h| reqinfo.headers.get_str(h));
+        let args = self
+            .override_args
+            .iter()
+            .filter_map(|a| reqinfo.rinfo.qinfo.args.get_str(a));
+        let mut out: Vec<String> = Vec::new();
+        for value in headers.chain(args) {
+            let method = value.trim().to_uppercase();
+            if !method.is_empty() && !out.contains(&method) {
+                out.push(method);
+            }
+        }
+        out
+    }
+
+    /// the first method of the request, or of its overrides, that is not allowed
+    pub fn denied(&self, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<String> {
+        let overrides = self.overrides(reqinfo);
+        for method in &overrides {
+            tags.insert_qualified("method-override", method);
+        }
+        let mut denied = None;
+        for method in std::iter::once(reqinfo.rinfo.meta.method.to_uppercase()).chain(overrides) {
+            if !STANDARD_METHODS.contains(&method.as_str()) {
+                tags.insert("nonstandard-method");
+            }
+            if denied.is_none() && !self.allowed.contains(&method) {
+                denied = Some(method);
+            }
+        }
+        denied
+    }
+
+    /// the blocking action, for the requests using a method that is not allowed
+    pub fn check(&self, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Action> {
+        let method = self.denied(reqinfo, tags)?;
+        tags.insert("method-not-allowed");
+        tags.insert_qualified("method-not-allowed", &method);
+        if self.action == MethodAction::Tag {
+            return None;
+        }
+        let mut headers = HashMap::new();
+        headers.insert("Allow".to_string(), self.allowed.join(", "));
+        Some(Action {
+            status: 405,
+            headers: Some(headers),
+            reason: Reason::new(Initiator::Method).with_message(format!("method {} is not allowed", method)),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    fn policy(raw: &str) -> MethodPolicy {
+        let raw: RawMethodPolicy = serde_json::from_str(raw).unwrap();
+        MethodPolicy::resolve(&raw)
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let methods = policy(r#"{"methods": ["get", "post"]}"#);
+        let denied = |rq: RequestBuilder| methods.denied(&rq.request_info(), &mut Tags::default());
+        assert_eq!(denied(RequestBuilder::get("/")), None);
+        assert_eq!(denied(RequestBuilder::post("/", "a=1")), None);
+        assert_eq!(denied(RequestBuilder::new("DELETE", "/")), Some("DELETE".to_string()));
+        // the overrides are checked too
+        assert_eq!(
+            denied(RequestBuilder::post("/", "").header("x-http-method-override", "delete")),
+            Some("DELETE".to_string())
+        );
+        let form = RequestBuilder::post("/", "_method=PUT").header("content-type", "application/x-www-form-urlencoded");
+        assert_eq!(denied(form), Some("PUT".to_string()));
+
+        let mut tags = Tags::default();
+        let rq = RequestBuilder::post("/", "")
+            .header("x-http-method-override", "DELETE")
+            .request_info();
+        let action = methods.check(&rq, &mut tags).unwrap();
+        assert_eq!(action.status, 405);
+        assert_eq!(action.reason.initiator, Initiator::Method);
+        assert_eq!(action.headers.unwrap().get("Allow").unwrap(), "GET, POST");
+        assert!(tags.contains("method-override:delete"));
+        assert!(tags.contains("method-not-allowed:delete"));
+
+        let tag_only = policy(r#"{"methods": ["GET"], "action": "tag"}"#);
+        let mut tags = Tags::default();
+        assert!(tag_only
+            .check(&RequestBuilder::new("PUT", "/").request_info(), &mut tags)
+            .is_none());
+        assert!(tags.contains("method-not-allowed"));
+    }
+
+    #[test]
+    fn nonstandard_methods() {
+        let methods = policy("{}");
+        let mut tags = Tags::default();
+        let rq = RequestBuilder::new("PROPFIND", "/").request_info();
+        assert!(methods.check(&rq, &mut tags).is_some());
+        assert!(tags.contains("nonstandard-method"));
+        assert!(tags.contains("method-not-allowed:propfind"));
+
+        let mut tags = Tags::default();
+        assert!(methods
+            .check(&RequestBuilder::new("patch", "/").request_info(), &mut tags)
+            .is_none());
+        assert!(!tags.contains("nonstandard-method"));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::interface::Decision;
+use crate::logs::{Logs, PhaseTiming};
+use crate::metadata::DynamicMetadata;
+use crate::notify;
+use crate::reason::{Initiator, Reason};
+use crate::slow;
+use crate::statsd;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// counts per bucket, not cumulative, the last one being `+Inf`
+    counts: [u64; 12],
+    /// the last request id and value of each bucket
+    exemplars: [Option<(String, f64)>; 12],
+    sum: f64,
+}
+
+/// the exemplar suffix of a sample, in the OpenMetrics format
+fn exemplar(request_id: &str, value: f64) -> String {
+    format!(" # {{request_id=\"{}\"}} {}", escape(request_id), value)
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64, request_id: Option<&str>) {
+        let idx = BUCKETS.iter().position(|b| seconds <= *b).unwrap_or(BUCKETS.len());
+        self.counts[idx] += 1;
+        self.sum += seconds;
+        if let Some(rid) = request_id {
+            self.exemplars[idx] = Some((rid.to_string(), seconds));
+        }
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, openmetrics: bool) {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = BUCKETS
+                .get(i)
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            let ex = match &self.exemplars[i] {
+                Some((rid, value)) if openmetrics => exemplar(rid, *value),
+                _ => String::new(),
+            };
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}{}", name, labels, le, cumulative, ex);
+        }
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            l => format!("{{{}}}", l),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// decisions, by action and initiator
+    decisions: BTreeMap<(String, String), u64>,
+    /// blocked requests, by initiator
+    blocks: BTreeMap<String, u64>,
+    /// the last blocked request, by initiator
+    block_exemplars: BTreeMap<String, String>,
+    /// challenges sent, by kind
+    challenges: BTreeMap<String, u64>,
+    /// triggered limits, by name
+    limits: BTreeMap<String, u64>,
+    /// content filter signatures that matched, by rule id
+    signatures: BTreeMap<String, u64>,
+    /// inspections over the latency budget, by dominant phase
+    slow: BTreeMap<String, u64>,
+    /// per phase latency
+    phases: BTreeMap<String, Histogram>,
+    inspection: Histogram,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    config_reloads: AtomicU64,
+    duration_micros: AtomicU64,
+    counters: Mutex<Counters>,
+}
+
+/// escapes a label value, that can come from the configuration
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// the metric family of counters drops the `_total` suffix in the OpenMetrics format
+fn counter_type(out: &mut String, name: &str, openmetrics: bool) {
+    let family = if openmetrics {
+        name.trim_end_matches("_total")
+    } else {
+        name
+    };
+    let _ = writeln!(out, "# TYPE {} counter", family);
+}
+
+fn render_counter(
+    out: &mut String,
+    name: &str,
+    label: &str,
+    values: &BTreeMap<String, u64>,
+    exemplars: Option<&BTreeMap<String, String>>,
+) {
+    for (k, count) in values {
+        let ex = match exemplars.and_then(|e| e.get(k)) {
+            Some(rid) => exemplar(rid, 1.0),
+            None => String::new(),
+        };
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}{}", name, label, escape(k), count, ex);
+    }
+}
+
+/// what triggered the action, or one of the other matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Trigger {
+    /// a challenge, by kind
+    Challenge,
+    /// a limit, by name
+    Limit,
+    /// a content filter signature, by rule id
+    Signature,
+}
+
+/// the values an inspection adds to the metrics, shared by the exporters
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Observation {
+    pub action: String,
+    pub initiator: &'static str,
+    /// a blocking action, in block mode
+    pub blocked: bool,
+    pub triggers: Vec<(Trigger, String)>,
+    /// duration of each phase, in microseconds
+    pub phases: Vec<(String, u64)>,
+    pub elapsed_micros: u64,
+}
+
+impl Observation {
+    pub fn new(decision: &Decision, phases: &[PhaseTiming], elapsed_micros: u64) -> Self {
+        let metadata = DynamicMetadata::new(decision, &Default::default());
+        let mut triggers = Vec::new();
+        let mut blocked = false;
+        if let Decision::Action(a) = decision {
+            blocked = a.block_mode && a.atype.is_blocking();
+            for reason in std::iter::once(&a.reason).chain(a.reason.matches.iter()) {
+                reason_triggers(&mut triggers, reason);
+            }
+        }
+        let mut previous = 0;
+        let phases = phases
+            .iter()
+            .map(|timing| {
+                let elapsed = timing.elapsed_micros.saturating_sub(previous);
+                previous = timing.elapsed_micros;
+                (timing.phase.clone(), elapsed)
+            })
+            .collect();
+        Observation {
+            action: metadata.action,
+            initiator: metadata.initiator.map(|i| i.as_str()).unwrap_or("none"),
+            blocked,
+            triggers,
+            phases,
+            elapsed_micros,
+        }
+    }
+}
+
+impl Metrics {
+    /// records an inspection, with its phase timings
+    pub fn record(&self, decision: &Decision, phases: &[PhaseTiming], elapsed_micros: u64, request_id: Option<&str>) {
+        self.record_observation(&Observation::new(decision, phases, elapsed_micros), request_id);
+    }
+
+    fn record_observation(&self, observation: &Observation, request_id: Option<&str>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.duration_micros
+            .fetch_add(observation.elapsed_micros, Ordering::Relaxed);
+        let initiator = observation.initiator.to_string();
+        let mut counters = match self.counters.lock() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        *counters
+            .decisions
+            .entry((observation.action.clone(), initiator.clone()))
+            .or_default() += 1;
+        if observation.blocked {
+            if let Some(rid) = request_id {
+                counters.block_exemplars.insert(initiator.clone(), rid.to_string());
+            }
+            *counters.blocks.entry(initiator).or_default() += 1;
+        }
+        for (trigger, label) in &observation.triggers {
+            let values = match trigger {
+                Trigger::Challenge => &mut counters.challenges,
+                Trigger::Limit => &mut counters.limits,
+                Trigger::Signature => &mut counters.signatures,
+            };
+            *values.entry(label.clone()).or_default() += 1;
+        }
+        for (phase, micros) in &observation.phases {
+            counters
+                .phases
+                .entry(phase.clone())
+                .or_default()
+                .observe(*micros as f64 / 1_000_000.0, None);
+        }
+        counters
+            .inspection
+            .observe(observation.elapsed_micros as f64 / 1_000_000.0, request_id);
+    }
+
+    /// an inspection that could not run, such as an invalid request
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_config_reload(&self) {
+        self.config_reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_slow(&self, phase: &str) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.slow.entry(phase.to_string()).or_default() += 1;
+        }
+    }
+
+    /// the metrics, in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.render_format(false)
+    }
+
+    /// the metrics, with exemplars, in the OpenMetrics text format
+    pub fn render_openmetrics(&self) -> String {
+        let mut out = self.render_format(true);
+        out += "# EOF\n";
+        out
+    }
+
+    fn render_format(&self, openmetrics: bool) -> String {
+        let mut out = String::new();
+        for (name, value) in [
+            (
+                "curiefense_requests_total",
+                self.requests.load(Ordering::Relaxed) as f64,
+            ),
+            ("curiefense_errors_total", self.errors.load(Ordering::Relaxed) as f64),
+            (
+                "curiefense_config_reloads_total",
+                self.config_reloads.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "curiefense_inspection_seconds_total",
+                self.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            ),
+        ] {
+            counter_type(&mut out, name, openmetrics);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        counter_type(&mut out, "curiefense_decisions_total", openmetrics);
+        let counters = match self.counters.lock() {
+            Ok(c) => c,
+            Err(_) => return out,
+        };
+        for ((action, initiator), count) in counters.decisions.iter() {
+            let _ = writeln!(
+                out,
+                "curiefense_decisions_total{{action=\"{}\",initiator=\"{}\"}} {}",
+                action, initiator, count
+            );
+        }
+        let block_exemplars = Some(&counters.block_exemplars).filter(|_| openmetrics);
+        for (name, label, values, exemplars) in [
+            (
+                "curiefense_blocks_total",
+                "initiator",
+                &counters.blocks,
+                block_exemplars,
+            ),
+            ("curiefense_challenges_total", "kind", &counters.challenges, None),
+            ("curiefense_limit_triggers_total", "limit", &counters.limits, None),
+            (
+                "curiefense_content_filter_hits_total",
+                "rule_id",
+                &counters.signatures,
+                None,
+            ),
+            ("curiefense_slow_inspections_total", "phase", &counters.slow, None),
+        ] {
+            counter_type(&mut out, name, openmetrics);
+            render_counter(&mut out, name, label, values, exemplars);
+        }
+        out += "# TYPE curiefense_phase_duration_seconds histogram\n";
+        for (phase, histogram) in counters.phases.iter() {
+            histogram.render(
+                &mut out,
+                "curiefense_phase_duration_seconds",
+                &format!("phase=\"{}\",", escape(phase)),
+                openmetrics,
+            );
+        }
+        out += "# TYPE curiefense_inspection_duration_seconds histogram\n";
+        counters
+            .inspection
+            .render(&mut out, "curiefense_inspection_duration_seconds", "", openmetrics);
+        out
+    }
+}
+
+fn reason_triggers(triggers: &mut Vec<(Trigger, String)>, reason: &Reason) {
+    match reason.initiator {
+        Initiator::Phase01 => triggers.push((Trigger::Challenge, "challenge".to_string())),
+        Initiator::Captcha => triggers.push((Trigger::Challenge, "captcha".to_string())),
+        Initiator::Limit => {
+            let name = reason.name.clone().unwrap_or_else(|| "unknown".to_string());
+            triggers.push((Trigger::Limit, name));
+        }
+        Initiator::ContentFilter => {
+            for rule_id in &reason.rule_ids {
+                triggers.push((Trigger::Signature, rule_id.clone()));
+            }
+        }
+        _ => (),
+    }
+}
+
+/// records an inspection in the process wide metrics, and sends it to statsd
+pub fn record_inspection(decision: &Decision, request_id: &str, logs: &Logs) {
+    let observation = Observation::new(decision, &logs.phases, logs.start.elapsed().as_micros() as u64);
+    METRICS.record_observation(&observation, Some(request_id));
+    statsd::inspection(&observation);
+    notify::inspection(decision);
+    if let Some(slow) = slow::check(request_id, &observation, logs) {
+        METRICS.record_slow(&slow.phase);
+        statsd::increment("slow_inspections", &[("phase", &slow.phase)]);
+    }
+}
+
+/// records an inspection that could not run, such as an invalid request
+pub fn record_error() {
+    METRICS.record_error();
+    statsd::increment("errors", &[]);
+}
+
+pub fn record_config_reload() {
+    METRICS.record_config_reload();
+    statsd::increment("config_reloads", &[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Action;
+
+    #[test]
+    fn metrics() {
+        let metrics = Metrics::default();
+        metrics.record(&Decision::Pass, &[], 500, None);
+        let block = Decision::Action(Action {
+            reason: Reason::new(Initiator::Acl),
+            ..Action::default()
+        });
+        metrics.record(&block, &[], 1500, Some("req-1"));
+        metrics.record(&block, &[], 1000, Some("req-2"));
+        let rendered = metrics.render();
+        assert!(rendered.contains("curiefense_requests_total 3\n"));
+        assert!(rendered.contains("curiefense_inspection_seconds_total 0.003\n"));
+        assert!(rendered.contains("curiefense_decisions_total{action=\"pass\",initiator=\"none\"} 1\n"));
+        assert!(rendered.contains("curiefense_decisions_total{action=\"block\",initiator=\"acl\"} 2\n"));
+        assert!(rendered.contains("curiefense_blocks_total{initiator=\"acl\"} 2\n"));
+        assert!(rendered.contains("curiefense_inspection_duration_seconds_bucket{le=\"0.001\"} 2\n"));
+        assert!(rendered.contains("curiefense_inspection_duration_seconds_count 3\n"));
+        assert!(!rendered.contains("req-2"));
+
+        let openmetrics = metrics.render_openmetrics();
+        assert!(openmetrics.contains("# TYPE curiefense_requests counter\ncuriefense_requests_total 3\n"));
+        assert!(openmetrics.contains("curiefense_blocks_total{initiator=\"acl\"} 2 # {request_id=\"req-2\"} 1\n"));
+        assert!(openmetrics.contains(
+            "curiefense_inspection_duration_seconds_bucket{le=\"0.001\"} 2 # {request_id=\"req-2\"} 0.001\n"
+        ));
+        assert!(openmetrics.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn triggers_and_phases() {
+        let metrics = Metrics::default();
+        let mut reason = Reason::new(Initiator::Limit);
+        reason.name = Some("rate".to_string());
+        let mut cf = Reason::new(Initiator::ContentFilter);
+        cf.rule_ids = vec!["100".to_string(), "101".to_string()];
+        reason.matches.push(cf);
+        let decision = Decision::Action(Action {
+            reason,
+            ..Action::default()
+        });
+        let phases = vec![
+            PhaseTiming {
+                phase: "mapping".to_string(),
+                elapsed_micros: 50,
+            },
+            PhaseTiming {
+                phase: "acl".to_string(),
+                elapsed_micros: 2050,
+            },
+        ];
+        metrics.record(&decision, &phases, 3000, None);
+        let rendered = metrics.render();
+        assert!(rendered.contains("curiefense_limit_triggers_total{limit=\"rate\"} 1\n"));
+        assert!(rendered.contains("curiefense_content_filter_hits_total{rule_id=\"101\"} 1\n"));
+        assert!(rendered.contains("curiefense_phase_duration_seconds_bucket{phase=\"mapping\",le=\"0.0001\"} 1\n"));
+        assert!(rendered.contains("curiefense_phase_duration_seconds_bucket{phase=\"acl\",le=\"0.001\"} 0\n"));
+        assert!(rendered.contains("curiefense_phase_duration_seconds_bucket{phase=\"acl\",le=\"0.0025\"} 1\n"));
+        assert!(rendered.contains("curiefense_phase_duration_seconds_sum{phase=\"acl\"} 0.002\n"));
+    }
+}
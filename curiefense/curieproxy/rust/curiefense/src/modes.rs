@@ -0,0 +1,217 @@
+use crate::blockpage::apply_block_response;
+use crate::cache::Cache;
+use crate::config::raw::{BlockResponse, HostMapMode, RawHostMap};
+use crate::interface::{Action, Decision, Tags};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+#[cfg(feature = "redis")]
+use crate::redis::redis_async_conn;
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// the cache is emptied when it still reaches this size once its expired entries are purged
+const MAX_CACHED: usize = 10_000;
+
+lazy_static! {
+    static ref REFRESH_MS: u64 = env_or("CURIEFENSE_MODE_REFRESH_MS", 0);
+    /// the override of each host map, if any
+    static ref OVERRIDES: Cache<String, Option<HostMapMode>> =
+        Cache::ttl_only(Duration::from_millis(*REFRESH_MS), MAX_CACHED);
+}
+
+fn override_key(hostmap: &str) -> String {
+    format!("curiefense-mode:{}", hostmap)
+}
+
+pub fn mode_name(mode: HostMapMode) -> &'static str {
+    match mode {
+        HostMapMode::Normal => "normal",
+        HostMapMode::Bypass => "bypass",
+        HostMapMode::Maintenance => "maintenance",
+        HostMapMode::Lockdown => "lockdown",
+    }
+}
+
+fn parse_mode(name: &str) -> Option<HostMapMode> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
+
+/// the body of the admin calls that override the mode of a host map
+#[derive(Debug, Deserialize)]
+pub struct ModeOverride {
+    pub mode: HostMapMode,
+    /// in seconds, the override lasting until it is cleared when absent
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// overrides the mode of a host map, for `ttl` seconds, or until it is cleared, the `None` mode clearing it
+pub async fn set_override(hostmap: &str, mode: Option<HostMapMode>, ttl: Option<u64>) -> anyhow::Result<()> {
+    store_override(&override_key(hostmap), mode, ttl).await?;
+    OVERRIDES.insert(hostmap.to_string(), mode, Instant::now());
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+async fn store_override(key: &str, mode: Option<HostMapMode>, ttl: Option<u64>) -> anyhow::Result<()> {
+    let mut cnx = redis_async_conn().await?;
+    let () = match mode {
+        None => redis::cmd("DEL").arg(key).query_async(&mut cnx).await?,
+        Some(mode) => {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(mode_name(mode));
+            if let Some(ttl) = ttl.filter(|t| *t > 0) {
+                cmd.arg("EX").arg(ttl);
+            }
+            cmd.query_async(&mut cnx).await?
+        }
+    };
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+async fn load_override(key: &str) -> anyhow::Result<Option<String>> {
+    let mut cnx = redis_async_conn().await?;
+    Ok(redis::cmd("GET").arg(key).query_async(&mut cnx).await?)
+}
+
+/// without redis, the overrides cannot be shared with the other processes
+#[cfg(not(feature = "redis"))]
+async fn store_override(_key: &str, _mode: Option<HostMapMode>, _ttl: Option<u64>) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "the mode overrides are stored in redis, that is not available"
+    ))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn load_override(_key: &str) -> anyhow::Result<Option<String>> {
+    Ok(None)
+}
+
+/// the runtime override of the mode of a host map, `None` when there is none or redis is unreachable
+async fn current_override(logs: &mut Logs, hostmap: &str) -> Option<HostMapMode> {
+    if *REFRESH_MS == 0 {
+        return None;
+    }
+    let now = Instant::now();
+    if let Some(cached) = OVERRIDES.get(hostmap, now) {
+        return cached;
+    }
+    let mode = match load_override(&override_key(hostmap)).await {
+        Ok(name) => name.as_deref().and_then(parse_mode),
+        Err(rr) => {
+            logs.error(|| format!("Could not read the mode override of {}: {}", hostmap, rr));
+            None
+        }
+    };
+    OVERRIDES.insert(hostmap.to_string(), mode, now);
+    mode
+}
+
+#[derive(Debug, Clone)]
+pub struct ModeSettings {
+    /// the id of the host map, that the overrides refer to
+    hostmap: String,
+    mode: HostMapMode,
+    maintenance: BlockResponse,
+    lockdown_allow: Vec<String>,
+}
+
+impl ModeSettings {
+    pub fn resolve(raw: &RawHostMap) -> Self {
+        ModeSettings {
+            hostmap: raw.id.clone(),
+            mode: raw.mode,
+            maintenance: raw.maintenance_response.clone().unwrap_or_default(),
+            lockdown_allow: raw.lockdown_allow.clone(),
+        }
+    }
+
+    /// the mode of the host map, its runtime override taking precedence
+    pub async fn current(&self, logs: &mut Logs) -> HostMapMode {
+        current_override(logs, &self.hostmap).await.unwrap_or(self.mode)
+    }
+
+    /// the decision of the mode, `None` for the requests that are inspected
+    pub fn check(&self, mode: HostMapMode, tags: &mut Tags) -> Option<Decision> {
+        let mut action = match mode {
+            HostMapMode::Normal => return None,
+            HostMapMode::Lockdown if self.lockdown_allow.iter().any(|t| tags.contains(t)) => return None,
+            HostMapMode::Bypass => {
+                tags.insert("bypass");
+                return Some(Decision::Pass);
+            }
+            HostMapMode::Maintenance => {
+                let mut action = Action {
+                    status: 503,
+                    content: "service under maintenance".to_string(),
+                    ..Action::default()
+                };
+                apply_block_response(&mut action, &self.maintenance);
+                action
+            }
+            HostMapMode::Lockdown => Action {
+                status: 403,
+                ..Action::default()
+            },
+        };
+        let name = mode_name(mode);
+        tags.insert_qualified("mode", name);
+        action.reason = Reason::new(Initiator::Mode)
+            .with_name(name)
+            .with_message(format!("host map {} is in {} mode", self.hostmap, name));
+        Some(Decision::Action(action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(raw: &str) -> ModeSettings {
+        let raw: RawHostMap = serde_json::from_str(raw).unwrap();
+        ModeSettings::resolve(&raw)
+    }
+
+    #[test]
+    fn modes() {
+        let modes = settings(
+            r#"{"match": ".*", "id": "__default__", "name": "default", "map": [], "mode": "lockdown",
+                "lockdown_allow": ["office"], "maintenance_response": {"content": "back soon"}}"#,
+        );
+        assert_eq!(modes.mode, HostMapMode::Lockdown);
+
+        let mut tags = Tags::default();
+        let action = match modes.check(HostMapMode::Lockdown, &mut tags) {
+            Some(Decision::Action(a)) => a,
+            d => panic!("lockdown should block, got {:?}", d),
+        };
+        assert_eq!(action.status, 403);
+        assert_eq!(action.reason.initiator, Initiator::Mode);
+        assert!(tags.contains("mode:lockdown"));
+        // the allow-listed tags pass
+        let mut tags = Tags::default();
+        tags.insert("office");
+        assert!(modes.check(HostMapMode::Lockdown, &mut tags).is_none());
+
+        let mut tags = Tags::default();
+        let action = match modes.check(HostMapMode::Maintenance, &mut tags) {
+            Some(Decision::Action(a)) => a,
+            d => panic!("maintenance should block, got {:?}", d),
+        };
+        assert_eq!(action.status, 503);
+        assert_eq!(action.content, "back soon");
+
+        let mut tags = Tags::default();
+        assert!(matches!(
+            modes.check(HostMapMode::Bypass, &mut tags),
+            Some(Decision::Pass)
+        ));
+        assert!(tags.contains("bypass"));
+        assert!(modes.check(HostMapMode::Normal, &mut tags).is_none());
+        assert_eq!(parse_mode("maintenance"), Some(HostMapMode::Maintenance));
+        assert_eq!(parse_mode("off"), None);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::config::raw::{NormalizationPreset, RawNormalization};
+use crate::utils::decoders::canonicalize_path;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// how the values of a repeated parameter are merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// all the values are kept
+    All,
+    First,
+    Last,
+    /// the values are joined with this separator
+    Join(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Normalization {
+    pub preset: NormalizationPreset,
+    pub duplicates: Duplicates,
+    pub semicolon_separator: bool,
+    /// the `;` parameters of the path segments are removed
+    pub path_parameters: bool,
+    pub nested_keys: bool,
+    /// the spaces and dots of the parameter names are replaced with underscores
+    pub php_names: bool,
+    /// the `%uXXXX` escapes are decoded
+    pub percent_u: bool,
+    /// the backslashes of the path are slashes
+    pub backslashes: bool,
+}
+
+impl Normalization {
+    pub fn preset(preset: NormalizationPreset) -> Self {
+        let generic = Normalization {
+            preset,
+            duplicates: Duplicates::All,
+            semicolon_separator: false,
+            path_parameters: false,
+            nested_keys: false,
+            php_names: false,
+            percent_u: false,
+            backslashes: false,
+        };
+        match preset {
+            NormalizationPreset::Generic => generic,
+            NormalizationPreset::Php => Normalization {
+                duplicates: Duplicates::Last,
+                nested_keys: true,
+                php_names: true,
+                ..generic
+            },
+            NormalizationPreset::Tomcat => Normalization {
+                duplicates: Duplicates::First,
+                path_parameters: true,
+                ..generic
+            },
+            NormalizationPreset::Iis => Normalization {
+                duplicates: Duplicates::Join(b','),
+                percent_u: true,
+                backslashes: true,
+                ..generic
+            },
+            NormalizationPreset::Node => Normalization {
+                duplicates: Duplicates::Join(b','),
+                nested_keys: true,
+                ..generic
+            },
+        }
+    }
+
+    /// the normalization of a host map, `None` when the requests are parsed the generic way
+    pub fn resolve(raw: &RawNormalization) -> Option<Self> {
+        let mut normalization = Normalization::preset(raw.preset);
+        if let Some(semicolon) = raw.semicolon_separator {
+            normalization.semicolon_separator = semicolon;
+        }
+        if normalization == Normalization::preset(NormalizationPreset::Generic) {
+            None
+        } else {
+            Some(normalization)
+        }
+    }
+
+    pub fn is_separator(&self, b: u8) -> bool {
+        b == b'&' || (self.semicolon_separator && b == b';')
+    }
+
+    /// true when the upstream resolves the paths differently from `canonicalize_path`
+    pub fn rewrites_path(&self) -> bool {
+        self.path_parameters || self.backslashes
+    }
+
+    /// the canonical form of the path, without the query string, as the upstream resolves it
+    pub fn canonical_path(&self, path: &str) -> String {
+        let mut path = Cow::Borrowed(path.split('?').next().unwrap_or_default());
+        if self.backslashes {
+            path = Cow::Owned(path.replace('\\', "/").replace("%5c", "/").replace("%5C", "/"));
+        }
+        if self.path_parameters {
+            path = Cow::Owned(
+                path.split('/')
+                    .map(|segment| segment.split(';').next().unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            );
+        }
+        canonicalize_path(&path)
+    }
+
+    /// the name of a parameter, as the upstream sees it, before it is nested
+    pub fn key(&self, key: String) -> String {
+        if !self.php_names {
+            return key;
+        }
+        let key = key.trim_start_matches(' ');
+        let (base, rest) = key.split_at(key.find('[').unwrap_or(key.len()));
+        let base = base.replace([' ', '.'], "_");
+        match rest.strip_prefix('[') {
+            // an unmatched bracket is replaced too
+            Some(nested) if !nested.contains(']') => format!("{}_{}", base, nested),
+            _ => format!("{}{}", base, rest),
+        }
+    }
+
+    /// decodes the `%uXXXX` escapes, when the upstream does, before the url decoding
+    pub fn decode_escapes<'a>(&self, input: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.percent_u || !input.windows(2).any(|w| w == b"%u" || w == b"%U") {
+            return Cow::Borrowed(input);
+        }
+        let mut out = Vec::with_capacity(input.len());
+        let mut idx = 0;
+        while idx < input.len() {
+            let escaped = input
+                .get(idx + 2..idx + 6)
+                .filter(|_| input[idx] == b'%' && (input[idx + 1] == b'u' || input[idx + 1] == b'U'))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32);
+            match escaped {
+                Some(c) => {
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    idx += 6;
+                }
+                None => {
+                    out.push(input[idx]);
+                    idx += 1;
+                }
+            }
+        }
+        Cow::Owned(out)
+    }
+}
+
+/// the parameters of a query string or form, the values of the repeated ones being merged like the upstream does
+#[derive(Debug)]
+pub struct Params {
+    duplicates: Duplicates,
+    index: HashMap<String, usize>,
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Params {
+    pub fn new(duplicates: Duplicates) -> Self {
+        Params {
+            duplicates,
+            index: HashMap::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, key: String, value: Vec<u8>) {
+        let existing = match self.duplicates {
+            Duplicates::All => None,
+            _ => self.index.get(&key).copied(),
+        };
+        match (existing, self.duplicates) {
+            (None, _) => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+            (Some(_), Duplicates::All) | (Some(_), Duplicates::First) => (),
+            (Some(i), Duplicates::Last) => self.entries[i].1 = value,
+            (Some(i), Duplicates::Join(separator)) => {
+                let current = &mut self.entries[i].1;
+                current.push(separator);
+                current.extend_from_slice(&value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requestfields::RequestField;
+    use crate::utils::decoders::parse_urlencoded_params;
+    use std::sync::Arc;
+
+    fn parse(preset: NormalizationPreset, query: &str) -> RequestField {
+        let mut args = RequestField::new(&[]);
+        args.normalization = Normalization::resolve(&RawNormalization {
+            preset,
+            semicolon_separator: None,
+        })
+        .map(Arc::new);
+        parse_urlencoded_params(&mut args, query);
+        args
+    }
+
+    #[test]
+    fn duplicates() {
+        let query = "a=1&a=2&b.c=3";
+        let generic = parse(NormalizationPreset::Generic, query);
+        assert_eq!(generic.get_str("a"), Some("1 2"));
+        assert_eq!(generic.get_str("b.c"), Some("3"));
+        let php = parse(NormalizationPreset::Php, query);
+        assert_eq!(php.get_str("a"), Some("2"));
+        assert_eq!(php.get_str("b_c"), Some("3"));
+        assert_eq!(parse(NormalizationPreset::Tomcat, query).get_str("a"), Some("1"));
+        assert_eq!(parse(NormalizationPreset::Node, query).get_str("a"), Some("1,2"));
+        // the pieces of a payload that is split over repeated parameters are inspected together
+        let iis = parse(NormalizationPreset::Iis, "q=%u003cscript%u003e/*&q=*/alert(1)");
+        assert_eq!(iis.get_str("q"), Some("<script>/*,*/alert(1)"));
+    }
+
+    #[test]
+    fn names_and_separators() {
+        let php = Normalization::preset(NormalizationPreset::Php);
+        assert_eq!(php.key(" a b.c[d.e]".to_string()), "a_b_c[d.e]");
+        assert_eq!(php.key("a[b".to_string()), "a_b");
+        assert_eq!(parse(NormalizationPreset::Php, "x[]=1&x[]=2").get_str("x"), Some("2"));
+
+        let cgi = Normalization::resolve(&RawNormalization {
+            preset: NormalizationPreset::Generic,
+            semicolon_separator: Some(true),
+        })
+        .unwrap();
+        let mut args = RequestField::new(&[]);
+        args.normalization = Some(Arc::new(cgi));
+        parse_urlencoded_params(&mut args, "a=1;b=2");
+        assert_eq!(args.get_str("b"), Some("2"));
+        assert_eq!(
+            parse(NormalizationPreset::Generic, "a=1;b=2").get_str("a"),
+            Some("1;b=2")
+        );
+        assert_eq!(Normalization::resolve(&RawNormalization::default()), None);
+    }
+
+    #[test]
+    fn paths() {
+        let tomcat = Normalization::preset(NormalizationPreset::Tomcat);
+        assert_eq!(tomcat.canonical_path("/public/..;/admin?x=1"), "/admin");
+        assert_eq!(tomcat.canonical_path("/a;jsessionid=1/b"), "/a/b");
+        assert_eq!(canonicalize_path("/public/..;/admin"), "/public/..;/admin");
+        let iis = Normalization::preset(NormalizationPreset::Iis);
+        assert_eq!(iis.canonical_path("/public\\..%5cadmin"), "/admin");
+        assert!(!Normalization::preset(NormalizationPreset::Php).rewrites_path());
+    }
+}
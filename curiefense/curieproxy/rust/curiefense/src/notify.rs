@@ -0,0 +1,434 @@
+use crate::accesslog::format_timestamp;
+use crate::clock;
+use crate::interface::Decision;
+use crate::shipper::{http_post, next_batch};
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const QUEUE_SIZE: usize = 1000;
+const BATCH_SIZE: usize = 100;
+const PAGERDUTY_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+lazy_static! {
+    static ref NOTIFIER: Option<Notifier> = Notifier::from_env();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// `scope` is `session` for the bans of the blocking decisions, and `limit` for the bans of the limits
+    Ban {
+        scope: &'static str,
+        name: String,
+        key: String,
+        duration: u64,
+    },
+    AttackBurst {
+        blocked: u64,
+        window: u64,
+    },
+    ConfigReloadFailed {
+        path: String,
+        errors: usize,
+        message: String,
+    },
+    /// see the `campaign` module
+    Campaign {
+        hash: String,
+        rule: String,
+        path: String,
+        country: String,
+        clients: usize,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Ban { .. } => "ban",
+            Event::AttackBurst { .. } => "attack_burst",
+            Event::ConfigReloadFailed { .. } => "config_reload_failed",
+            Event::Campaign { .. } => "campaign",
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Event::Ban {
+                scope,
+                name,
+                key,
+                duration,
+            } => format!("{} {} banned for {}s by {}", scope, key, duration, name),
+            Event::AttackBurst { blocked, window } => {
+                format!("attack burst: {} requests blocked within {}s", blocked, window)
+            }
+            Event::ConfigReloadFailed { path, errors, message } => {
+                format!(
+                    "configuration reload of {} failed with {} errors: {}",
+                    path, errors, message
+                )
+            }
+            Event::Campaign {
+                hash,
+                rule,
+                path,
+                country,
+                clients,
+            } => format!(
+                "campaign {}: {} clients triggered rule {} on {} from {:?}",
+                hash, clients, rule, path, country
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Webhook {
+    Slack(String),
+    PagerDuty(String),
+    Http(String),
+}
+
+impl Webhook {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (kind, target) = spec
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("invalid webhook {}", spec))?;
+        let url = |target: &str| {
+            if target.starts_with("http://") || target.starts_with("https://") {
+                Ok(target.to_string())
+            } else {
+                Err(format!("{} should be an URL", target))
+            }
+        };
+        match kind {
+            "slack" => url(target).map(Webhook::Slack),
+            "http" => url(target).map(Webhook::Http),
+            "pagerduty" if !target.is_empty() => Ok(Webhook::PagerDuty(target.to_string())),
+            _ => Err(format!("unknown webhook type {}", kind)),
+        }
+    }
+
+    /// the URL and the body of a batch
+    fn request(&self, batch: &[Notification], suppressed: u64) -> (&str, serde_json::Value) {
+        let mut lines: Vec<String> = batch.iter().map(|n| n.event.summary()).collect();
+        if suppressed > 0 {
+            lines.push(format!("{} events were suppressed by the rate limit", suppressed));
+        }
+        match self {
+            Webhook::Slack(url) => (
+                url,
+                serde_json::json!({ "text": format!("curiefense: {}", lines.join("\n")) }),
+            ),
+            Webhook::PagerDuty(routing_key) => {
+                let severity = if batch
+                    .iter()
+                    .any(|n| matches!(n.event, Event::ConfigReloadFailed { .. }))
+                {
+                    "error"
+                } else {
+                    "warning"
+                };
+                (
+                    PAGERDUTY_URL,
+                    serde_json::json!({
+                        "routing_key": routing_key,
+                        "event_action": "trigger",
+                        "payload": {
+                            "summary": lines.first().cloned().unwrap_or_default(),
+                            "source": "curiefense",
+                            "severity": severity,
+                            "custom_details": { "events": batch, "suppressed": suppressed },
+                        },
+                    }),
+                )
+            }
+            Webhook::Http(url) => (
+                url,
+                serde_json::json!({ "source": "curiefense", "events": batch, "suppressed": suppressed }),
+            ),
+        }
+    }
+
+    fn send(&self, batch: &[Notification], suppressed: u64) -> Result<(), String> {
+        let (url, body) = self.request(batch, suppressed);
+        http_post(url, "application/json", body.to_string())
+    }
+}
+
+/// the batches sent to a webhook within the last minute
+#[derive(Debug)]
+pub struct RateLimit {
+    per_minute: usize,
+    sent: VecDeque<Instant>,
+    /// the events that were not sent since the last batch
+    suppressed: u64,
+}
+
+impl RateLimit {
+    pub fn new(per_minute: usize) -> Self {
+        RateLimit {
+            per_minute,
+            sent: VecDeque::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// records a batch of `events`, returning whether it can be sent
+    pub fn allow(&mut self, events: usize, now: Instant) -> bool {
+        while let Some(first) = self.sent.front() {
+            if now.duration_since(*first) < Duration::from_secs(60) {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.per_minute {
+            self.suppressed += events as u64;
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+/// counts the blocked requests over a window, reporting a burst once per window
+#[derive(Debug)]
+pub struct Burst {
+    threshold: u64,
+    window: Duration,
+    start: Instant,
+    count: u64,
+    notified: bool,
+}
+
+impl Burst {
+    pub fn new(threshold: u64, window: Duration, now: Instant) -> Self {
+        Burst {
+            threshold,
+            window,
+            start: now,
+            count: 0,
+            notified: false,
+        }
+    }
+
+    /// counts a blocked request, returning the count when it reaches the threshold
+    pub fn blocked(&mut self, now: Instant) -> Option<u64> {
+        if now.duration_since(self.start) >= self.window {
+            self.start = now;
+            self.count = 0;
+            self.notified = false;
+        }
+        self.count += 1;
+        if self.threshold == 0 || self.count < self.threshold || self.notified {
+            return None;
+        }
+        self.notified = true;
+        Some(self.count)
+    }
+}
+
+struct Notifier {
+    queue: Mutex<SyncSender<Notification>>,
+    dropped: AtomicU64,
+    /// the notified event kinds, all of them when empty
+    events: HashSet<String>,
+    burst: Mutex<Burst>,
+}
+
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        let spec = std::env::var("CURIEFENSE_NOTIFY_WEBHOOKS")
+            .ok()
+            .filter(|s| !s.is_empty())?;
+        let mut webhooks = Vec::new();
+        for hook in spec.split(',').filter(|h| !h.trim().is_empty()) {
+            match Webhook::parse(hook) {
+                Ok(w) => webhooks.push(w),
+                Err(rr) => tracing::error!("webhook ignored: {}", rr),
+            }
+        }
+        if webhooks.is_empty() {
+            return None;
+        }
+        let flush = Duration::from_millis(env_or("CURIEFENSE_NOTIFY_FLUSH_MS", 10_000));
+        let per_minute = env_or("CURIEFENSE_NOTIFY_MAX_PER_MINUTE", 6_usize).max(1);
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        if let Err(rr) = std::thread::Builder::new()
+            .name("curiefense-notify".to_string())
+            .spawn(move || run(webhooks, receiver, flush, per_minute))
+        {
+            tracing::error!("could not start the notifier: {}", rr);
+            return None;
+        }
+        let events = std::env::var("CURIEFENSE_NOTIFY_EVENTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
+        let window = Duration::from_secs(env_or("CURIEFENSE_NOTIFY_BURST_SECS", 60_u64).max(1));
+        Some(Notifier {
+            queue: Mutex::new(sender),
+            dropped: AtomicU64::new(0),
+            events,
+            burst: Mutex::new(Burst::new(
+                env_or("CURIEFENSE_NOTIFY_BURST_THRESHOLD", 100),
+                window,
+                Instant::now(),
+            )),
+        })
+    }
+
+    fn push(&self, event: Event) {
+        if !self.events.is_empty() && !self.events.contains(event.kind()) {
+            return;
+        }
+        let notification = Notification {
+            timestamp: format_timestamp(clock::now()),
+            event,
+        };
+        let sent = match self.queue.lock() {
+            Ok(queue) => queue.try_send(notification),
+            Err(_) => return,
+        };
+        if let Err(TrySendError::Full(_)) = sent {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn run(webhooks: Vec<Webhook>, receiver: Receiver<Notification>, flush: Duration, per_minute: usize) {
+    let mut limits: Vec<RateLimit> = webhooks.iter().map(|_| RateLimit::new(per_minute)).collect();
+    while let Some(batch) = next_batch(&receiver, BATCH_SIZE, flush) {
+        if let Some(dropped) = NOTIFIER.as_ref().map(|n| n.dropped.swap(0, Ordering::Relaxed)) {
+            if dropped > 0 {
+                tracing::warn!("{} notifications were dropped, the queue is full", dropped);
+            }
+        }
+        for (webhook, limit) in webhooks.iter().zip(limits.iter_mut()) {
+            if !limit.allow(batch.len(), Instant::now()) {
+                continue;
+            }
+            match webhook.send(&batch, limit.suppressed) {
+                Ok(()) => limit.suppressed = 0,
+                Err(rr) => tracing::error!("could not send {} notifications: {}", batch.len(), rr),
+            }
+        }
+    }
+}
+
+/// queues an event, when webhooks are configured
+pub fn notify(event: Event) {
+    if let Some(notifier) = NOTIFIER.as_ref() {
+        notifier.push(event);
+    }
+}
+
+/// counts the blocking decisions, for the attack bursts
+pub fn inspection(decision: &Decision) {
+    let notifier = match NOTIFIER.as_ref() {
+        Some(n) => n,
+        None => return,
+    };
+    if !decision.is_blocking() {
+        return;
+    }
+    let burst = match notifier.burst.lock() {
+        Ok(mut burst) => burst
+            .blocked(Instant::now())
+            .map(|blocked| (blocked, burst.window.as_secs())),
+        Err(_) => None,
+    };
+    if let Some((blocked, window)) = burst {
+        notifier.push(Event::AttackBurst { blocked, window });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhooks() {
+        assert_eq!(
+            Webhook::parse(" slack:https://hooks.slack.com/services/T/B/x"),
+            Ok(Webhook::Slack("https://hooks.slack.com/services/T/B/x".to_string()))
+        );
+        assert_eq!(
+            Webhook::parse("pagerduty:R0UT1NGK3Y"),
+            Ok(Webhook::PagerDuty("R0UT1NGK3Y".to_string()))
+        );
+        assert!(Webhook::parse("http:hooks/path").is_err());
+        assert!(Webhook::parse("pagerduty:").is_err());
+        assert!(Webhook::parse("teams:https://x").is_err());
+
+        let batch = vec![Notification {
+            timestamp: "2026-10-16T00:00:00.000000Z".to_string(),
+            event: Event::Ban {
+                scope: "session",
+                name: "content_filter".to_string(),
+                key: "1.2.3.4".to_string(),
+                duration: 600,
+            },
+        }];
+        let (_, slack) = Webhook::Slack("https://slack".to_string()).request(&batch, 2);
+        assert_eq!(
+            slack["text"],
+            "curiefense: session 1.2.3.4 banned for 600s by content_filter\n2 events were suppressed by the rate limit"
+        );
+        let pagerduty_hook = Webhook::PagerDuty("key".to_string());
+        let (url, pagerduty) = pagerduty_hook.request(&batch, 0);
+        assert_eq!(url, PAGERDUTY_URL);
+        assert_eq!(pagerduty["payload"]["severity"], "warning");
+        let (_, generic) = Webhook::Http("https://hook".to_string()).request(&batch, 0);
+        assert_eq!(
+            generic["events"][0],
+            serde_json::json!({
+                "timestamp": "2026-10-16T00:00:00.000000Z",
+                "event": "ban",
+                "scope": "session",
+                "name": "content_filter",
+                "key": "1.2.3.4",
+                "duration": 600
+            })
+        );
+    }
+
+    #[test]
+    fn bursts_and_rate_limits() {
+        let start = Instant::now();
+        let mut burst = Burst::new(3, Duration::from_secs(60), start);
+        assert_eq!(burst.blocked(start), None);
+        assert_eq!(burst.blocked(start), None);
+        assert_eq!(burst.blocked(start), Some(3));
+        // once per window
+        assert_eq!(burst.blocked(start), None);
+        let later = start + Duration::from_secs(61);
+        assert_eq!(burst.blocked(later), None);
+        assert_eq!(burst.blocked(later), None);
+        assert_eq!(burst.blocked(later), Some(3));
+
+        let mut limit = RateLimit::new(2);
+        assert!(limit.allow(5, start));
+        assert!(limit.allow(5, start));
+        assert!(!limit.allow(5, start + Duration::from_secs(30)));
+        assert_eq!(limit.suppressed, 5);
+        assert!(limit.allow(1, start + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,605 @@
+use crate::config::raw::{OpenApiAction, OpenApiViolation, RawOpenApiSettings};
+use crate::interface::Action;
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::requestfields::RequestField;
+use crate::utils::decoders::parse_urlencoded_params;
+use crate::utils::RequestInfo;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// the violations of a request are not all listed, past this count
+const MAX_VIOLATIONS: usize = 16;
+/// the depth of the schemas, including the references, that are checked
+const MAX_SCHEMA_DEPTH: usize = 32;
+
+const METHODS: &[&str] = &["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: OpenApiViolation,
+    /// where the violation was found, such as `query:limit`, or `body/user/name`
+    pub location: String,
+}
+
+/// the result of the validation of a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenApiResult {
+    /// the `operationId` of the matched operation
+    pub operation: Option<String>,
+    pub violations: Vec<Violation>,
+}
+
+impl OpenApiResult {
+    fn push(&mut self, kind: OpenApiViolation, location: String) {
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(Violation { kind, location });
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    template: String,
+    segments: Vec<Segment>,
+    literals: usize,
+}
+
+impl Route {
+    fn new(template: &str) -> Self {
+        let segments: Vec<Segment> = template
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(s.to_string()),
+            })
+            .collect();
+        Route {
+            template: template.to_string(),
+            literals: segments.iter().filter(|s| matches!(s, Segment::Literal(_))).count(),
+            segments,
+        }
+    }
+
+    /// the path parameters, when the path matches
+    fn matches<'a>(&self, path: &[&'a str]) -> Option<Vec<(&str, &'a str)>> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (segment, part) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(l) if l == part => (),
+                Segment::Param(name) if !part.is_empty() => params.push((name.as_str(), *part)),
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+/// the OpenAPI specification of a host map
+#[derive(Debug, Clone)]
+pub struct OpenApi {
+    spec: Value,
+    /// the path of the servers
+    bases: Vec<String>,
+    /// sorted so that the literal segments are matched first
+    routes: Vec<Route>,
+    actions: HashMap<OpenApiViolation, OpenApiAction>,
+}
+
+/// the path of the server URL, such as `/v1` for `https://api.example.com/v1`
+fn server_base(url: &str) -> String {
+    let path = match url.find("://") {
+        Some(idx) => url[idx + 3..].find('/').map(|p| &url[idx + 3 + p..]).unwrap_or(""),
+        None => url,
+    };
+    path.trim_end_matches('/').to_string()
+}
+
+/// the string parameters are checked against the scalar types, the arrays being comma or space separated
+fn check_param(spec: &Value, schema: &Value, value: &str, depth: usize) -> bool {
+    let schema = match resolve(spec, schema, depth) {
+        Some(s) => s,
+        None => return true,
+    };
+    if let Some(values) = schema.get("enum").and_then(|e| e.as_array()) {
+        let found = values.iter().any(|v| match v {
+            Value::String(s) => s == value,
+            v => value.parse::<Value>().map(|p| p == *v).unwrap_or(false),
+        });
+        if !found {
+            return false;
+        }
+    }
+    match schema.get("type").and_then(|t| t.as_str()) {
+        Some("integer") => value.parse::<i64>().is_ok(),
+        Some("number") => value.parse::<f64>().is_ok(),
+        Some("boolean") => value == "true" || value == "false",
+        Some("array") => match schema.get("items") {
+            Some(items) => value.split([',', ' ']).all(|v| check_param(spec, items, v, depth + 1)),
+            None => true,
+        },
+        _ => true,
+    }
+}
+
+/// follows the local references
+fn resolve<'a>(spec: &'a Value, schema: &'a Value, depth: usize) -> Option<&'a Value> {
+    let mut schema = schema;
+    for _ in depth..MAX_SCHEMA_DEPTH {
+        match schema.get("$ref").and_then(|r| r.as_str()) {
+            Some(reference) => schema = spec.pointer(reference.strip_prefix('#')?)?,
+            None => return Some(schema),
+        }
+    }
+    None
+}
+
+fn json_type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().map(|f| f.fract() == 0.0).unwrap_or(false),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn check_json(spec: &Value, schema: &Value, value: &Value, location: &str, result: &mut OpenApiResult, depth: usize) {
+    let schema = match resolve(spec, schema, depth) {
+        Some(s) if depth < MAX_SCHEMA_DEPTH => s,
+        _ => return,
+    };
+    if value.is_null() && schema.get("nullable").and_then(|n| n.as_bool()) == Some(true) {
+        return;
+    }
+    if let Some(all) = schema.get("allOf").and_then(|a| a.as_array()) {
+        for sub in all {
+            check_json(spec, sub, value, location, result, depth + 1);
+        }
+    }
+    for key in &["anyOf", "oneOf"] {
+        if let Some(alternatives) = schema.get(key).and_then(|a| a.as_array()) {
+            let mut best: Option<OpenApiResult> = None;
+            for sub in alternatives {
+                let mut alternative = OpenApiResult::default();
+                check_json(spec, sub, value, location, &mut alternative, depth + 1);
+                if best
+                    .as_ref()
+                    .map(|b| alternative.violations.len() < b.violations.len())
+                    .unwrap_or(true)
+                {
+                    best = Some(alternative);
+                }
+            }
+            for violation in best.map(|b| b.violations).unwrap_or_default() {
+                result.push(violation.kind, violation.location);
+            }
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(|e| e.as_array()) {
+        if !values.contains(value) {
+            result.push(OpenApiViolation::TypeMismatch, location.to_string());
+            return;
+        }
+    }
+    if let Some(expected) = schema.get("type").and_then(|t| t.as_str()) {
+        if !json_type_matches(expected, value) {
+            result.push(OpenApiViolation::TypeMismatch, location.to_string());
+            return;
+        }
+    }
+    match value {
+        Value::Object(fields) => {
+            let properties = schema.get("properties").and_then(|p| p.as_object());
+            for required in schema.get("required").and_then(|r| r.as_array()).into_iter().flatten() {
+                if let Some(name) = required.as_str() {
+                    if !fields.contains_key(name) {
+                        result.push(OpenApiViolation::MissingRequired, format!("{}/{}", location, name));
+                    }
+                }
+            }
+            let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+            for (name, field) in fields {
+                match properties.and_then(|p| p.get(name)) {
+                    Some(sub) => check_json(spec, sub, field, &format!("{}/{}", location, name), result, depth + 1),
+                    None if closed => {
+                        result.push(OpenApiViolation::UndeclaredParameter, format!("{}/{}", location, name))
+                    }
+                    None => (),
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(sub) = schema.get("items") {
+                for (idx, item) in items.iter().enumerate() {
+                    check_json(spec, sub, item, &format!("{}/{}", location, idx), result, depth + 1);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// the media type of the request matches one of the declared ones, that can be wildcards
+fn media_type<'a>(content: &'a serde_json::Map<String, Value>, content_type: &str) -> Option<(&'a str, &'a Value)> {
+    let main = content_type.split('/').next().unwrap_or("");
+    content
+        .get_key_value(content_type)
+        .or_else(|| content.get_key_value(&format!("{}/*", main)))
+        .or_else(|| content.get_key_value("*/*"))
+        .map(|(k, v)| (k.as_str(), v))
+}
+
+impl OpenApi {
+    pub fn resolve(logs: &mut Logs, raw: &RawOpenApiSettings) -> Option<Self> {
+        let version = raw.spec.get("openapi").and_then(|v| v.as_str()).unwrap_or("");
+        if !version.starts_with("3.") {
+            logs.error(|| format!("Unsupported OpenAPI version {:?}, only OpenAPI 3 is supported", version));
+            return None;
+        }
+        let paths = match raw.spec.get("paths").and_then(|p| p.as_object()) {
+            Some(p) => p,
+            None => {
+                logs.error("The OpenAPI specification does not have paths");
+                return None;
+            }
+        };
+        let mut routes: Vec<Route> = paths.keys().map(|template| Route::new(template)).collect();
+        routes.sort_by_key(|r| usize::MAX - r.literals);
+        let mut bases: Vec<String> = raw
+            .spec
+            .get("servers")
+            .and_then(|s| s.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.get("url").and_then(|u| u.as_str()))
+            .map(server_base)
+            .collect();
+        if bases.is_empty() {
+            bases.push(String::new());
+        }
+        Some(OpenApi {
+            spec: raw.spec.clone(),
+            bases,
+            routes,
+            actions: raw.actions.clone(),
+        })
+    }
+
+    /// the declared parameters, of the path item and of the operation, the latter overriding the former
+    fn parameters<'a>(&'a self, item: &'a Value, operation: &'a Value) -> Vec<&'a Value> {
+        let mut params: Vec<&Value> = Vec::new();
+        for source in &[operation, item] {
+            for param in source
+                .get("parameters")
+                .and_then(|p| p.as_array())
+                .into_iter()
+                .flatten()
+            {
+                if let Some(param) = resolve(&self.spec, param, 0) {
+                    let key = |p: &Value| (p.get("name").cloned(), p.get("in").cloned());
+                    if !params.iter().any(|p| key(p) == key(param)) {
+                        params.push(param);
+                    }
+                }
+            }
+        }
+        params
+    }
+
+    /// checks the request, `body` being the raw body
+    pub fn validate(&self, reqinfo: &RequestInfo, body: Option<&[u8]>) -> OpenApiResult {
+        let mut result = OpenApiResult::default();
+        let qpath = &reqinfo.rinfo.qinfo.qpath;
+        let path: Option<Vec<&str>> = self.bases.iter().find_map(|base| {
+            let rest = qpath.strip_prefix(base.as_str())?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            Some(rest.split('/').filter(|s| !s.is_empty()).collect())
+        });
+        let matched = path.and_then(|path| {
+            self.routes
+                .iter()
+                .find_map(|route| route.matches(&path).map(|params| (route, params)))
+        });
+        let (route, path_params) = match matched {
+            Some(m) => m,
+            None => {
+                result.push(OpenApiViolation::UnknownPath, qpath.clone());
+                return result;
+            }
+        };
+        let item = match self.spec.get("paths").and_then(|p| p.get(&route.template)) {
+            Some(i) => i,
+            None => return result,
+        };
+        let method = reqinfo.rinfo.meta.method.to_lowercase();
+        let operation = match item.get(&method) {
+            Some(op) if METHODS.contains(&method.as_str()) => op,
+            _ => {
+                result.push(
+                    OpenApiViolation::UnknownMethod,
+                    format!("{} {}", method.to_uppercase(), route.template),
+                );
+                return result;
+            }
+        };
+        result.operation = operation
+            .get("operationId")
+            .and_then(|o| o.as_str())
+            .map(|s| s.to_string());
+
+        let mut query = RequestField::new(&[]);
+        parse_urlencoded_params(&mut query, &reqinfo.rinfo.qinfo.query);
+        let params = self.parameters(item, operation);
+        for param in &params {
+            let (name, location) = match (
+                param.get("name").and_then(|n| n.as_str()),
+                param.get("in").and_then(|i| i.as_str()),
+            ) {
+                (Some(n), Some(i)) => (n, i),
+                _ => continue,
+            };
+            let value = match location {
+                "query" => query.get_str(name),
+                "header" => reqinfo.headers.get_str(&name.to_lowercase()),
+                "cookie" => reqinfo.cookies.get_str(name),
+                "path" => path_params.iter().find(|(n, _)| *n == name).map(|(_, v)| *v),
+                _ => continue,
+            };
+            let place = format!("{}:{}", location, name);
+            match (value, param.get("schema")) {
+                (None, _) if param.get("required").and_then(|r| r.as_bool()) == Some(true) => {
+                    result.push(OpenApiViolation::MissingRequired, place)
+                }
+                (Some(value), Some(schema)) if !check_param(&self.spec, schema, value, 0) => {
+                    result.push(OpenApiViolation::TypeMismatch, place)
+                }
+                _ => (),
+            }
+        }
+        for name in query.fields.keys().filter(|k| !k.is_empty()) {
+            let declared = params.iter().any(|p| {
+                p.get("in").and_then(|i| i.as_str()) == Some("query")
+                    && p.get("name").and_then(|n| n.as_str()) == Some(name)
+            });
+            if !declared {
+                result.push(OpenApiViolation::UndeclaredParameter, format!("query:{}", name));
+            }
+        }
+
+        let body = body.filter(|b| !b.is_empty());
+        let request_body = operation.get("requestBody").and_then(|b| resolve(&self.spec, b, 0));
+        match (body, request_body) {
+            (None, Some(rb)) if rb.get("required").and_then(|r| r.as_bool()) == Some(true) => {
+                result.push(OpenApiViolation::MissingRequired, "body".to_string())
+            }
+            (Some(_), None) => result.push(OpenApiViolation::UndeclaredParameter, "body".to_string()),
+            (Some(body), Some(rb)) => self.check_body(reqinfo, body, rb, &mut result),
+            _ => (),
+        }
+        result
+    }
+
+    fn check_body(&self, reqinfo: &RequestInfo, body: &[u8], request_body: &Value, result: &mut OpenApiResult) {
+        let content = match request_body.get("content").and_then(|c| c.as_object()) {
+            Some(c) => c,
+            None => return,
+        };
+        let content_type = reqinfo
+            .headers
+            .get_str("content-type")
+            .and_then(|c| c.split(';').next())
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        let (media, declared) = match media_type(content, &content_type) {
+            Some(m) => m,
+            None => {
+                result.push(OpenApiViolation::TypeMismatch, "body:content-type".to_string());
+                return;
+            }
+        };
+        // the truncated bodies can not be checked
+        if !(media.contains("json") && content_type.contains("json")) || reqinfo.rinfo.meta.body_truncated {
+            return;
+        }
+        let schema = match declared.get("schema") {
+            Some(s) => s,
+            None => return,
+        };
+        match serde_json::from_slice::<Value>(body) {
+            Ok(value) => check_json(&self.spec, schema, &value, "body", result, 0),
+            Err(_) => result.push(OpenApiViolation::TypeMismatch, "body".to_string()),
+        }
+    }
+
+    /// the blocking action, for the first violation whose action is `block`
+    pub fn action(&self, result: Option<&OpenApiResult>) -> Option<Action> {
+        let violation = result?
+            .violations
+            .iter()
+            .find(|v| self.actions.get(&v.kind).copied().unwrap_or_default() == OpenApiAction::Block)?;
+        Some(Action {
+            status: 403,
+            reason: Reason::new(Initiator::OpenApi).with_message(format!(
+                "{} at {}",
+                violation.kind.as_str(),
+                violation.location
+            )),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    fn spec() -> OpenApi {
+        let spec = serde_json::json!({
+            "openapi": "3.0.3",
+            "servers": [{"url": "https://api.example.com/v1"}],
+            "paths": {
+                "/users": {
+                    "get": {
+                        "operationId": "listUsers",
+                        "parameters": [
+                            {"name": "limit", "in": "query", "schema": {"type": "integer"}},
+                            {"name": "order", "in": "query", "schema": {"type": "string", "enum": ["asc", "desc"]}}
+                        ]
+                    },
+                    "post": {
+                        "operationId": "createUser",
+                        "parameters": [{"$ref": "#/components/parameters/tenant"}],
+                        "requestBody": {
+                            "required": true,
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/User"}}}
+                        }
+                    }
+                },
+                "/users/me": {"get": {"operationId": "me"}},
+                "/users/{id}": {
+                    "parameters": [{"name": "id", "in": "path", "required": true, "schema": {"type": "integer"}}],
+                    "get": {"operationId": "getUser"}
+                }
+            },
+            "components": {
+                "parameters": {"tenant": {"name": "x-tenant", "in": "header", "required": true}},
+                "schemas": {
+                    "User": {
+                        "type": "object",
+                        "required": ["name"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "name": {"type": "string"},
+                            "age": {"type": "integer", "nullable": true},
+                            "roles": {"type": "array", "items": {"type": "string", "enum": ["user", "admin"]}}
+                        }
+                    }
+                }
+            }
+        });
+        let raw = RawOpenApiSettings {
+            spec,
+            actions: std::iter::once((OpenApiViolation::UndeclaredParameter, OpenApiAction::Tag)).collect(),
+        };
+        OpenApi::resolve(&mut Logs::default(), &raw).unwrap()
+    }
+
+    fn violations(api: &OpenApi, rq: RequestBuilder) -> Vec<(OpenApiViolation, String)> {
+        let body = rq.raw().mbody.map(|b| b.to_vec());
+        api.validate(&rq.request_info(), body.as_deref())
+            .violations
+            .into_iter()
+            .map(|v| (v.kind, v.location))
+            .collect()
+    }
+
+    fn user(body: &str) -> RequestBuilder {
+        RequestBuilder::post("/v1/users", body)
+            .header("content-type", "application/json; charset=utf-8")
+            .header("x-tenant", "acme")
+    }
+
+    #[test]
+    fn paths_and_parameters() {
+        use OpenApiViolation::*;
+        let api = spec();
+        let rq = RequestBuilder::get("/v1/users?limit=10&order=asc");
+        assert_eq!(
+            api.validate(&rq.request_info(), None).operation.as_deref(),
+            Some("listUsers")
+        );
+        assert_eq!(violations(&api, rq), vec![]);
+        // the literal segments are matched first
+        let rq = RequestBuilder::get("/v1/users/me");
+        assert_eq!(api.validate(&rq.request_info(), None).operation.as_deref(), Some("me"));
+        assert_eq!(violations(&api, RequestBuilder::get("/v1/users/12")), vec![]);
+
+        assert_eq!(
+            violations(&api, RequestBuilder::get("/v1/users/abc")),
+            vec![(TypeMismatch, "path:id".to_string())]
+        );
+        assert_eq!(
+            violations(&api, RequestBuilder::get("/users")),
+            vec![(UnknownPath, "/users".to_string())]
+        );
+        assert_eq!(
+            violations(&api, RequestBuilder::new("DELETE", "/v1/users/12")),
+            vec![(UnknownMethod, "DELETE /users/{id}".to_string())]
+        );
+        let mut found = violations(&api, RequestBuilder::get("/v1/users?limit=ten&order=random&debug=1"));
+        found.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            found,
+            vec![
+                (UndeclaredParameter, "query:debug".to_string()),
+                (TypeMismatch, "query:limit".to_string()),
+                (TypeMismatch, "query:order".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn bodies() {
+        use OpenApiViolation::*;
+        let api = spec();
+        assert_eq!(
+            violations(&api, user(r#"{"name": "alice", "age": null, "roles": ["admin"]}"#)),
+            vec![]
+        );
+        assert_eq!(
+            violations(&api, user(r#"{"age": "12", "roles": ["root"], "admin": true}"#)),
+            vec![
+                (MissingRequired, "body/name".to_string()),
+                (UndeclaredParameter, "body/admin".to_string()),
+                (TypeMismatch, "body/age".to_string()),
+                (TypeMismatch, "body/roles/0".to_string()),
+            ]
+        );
+        assert_eq!(violations(&api, user("{")), vec![(TypeMismatch, "body".to_string())]);
+        assert_eq!(
+            violations(&api, RequestBuilder::new("POST", "/v1/users")),
+            vec![
+                (MissingRequired, "header:x-tenant".to_string()),
+                (MissingRequired, "body".to_string())
+            ]
+        );
+        assert_eq!(
+            violations(
+                &api,
+                user("name=alice").header("content-type", "application/x-www-form-urlencoded")
+            ),
+            vec![(TypeMismatch, "body:content-type".to_string())]
+        );
+    }
+
+    #[test]
+    fn actions() {
+        let api = spec();
+        let rq = RequestBuilder::get("/v1/users?debug=1");
+        let result = api.validate(&rq.request_info(), None);
+        // only tagged
+        assert_eq!(api.action(Some(&result)), None);
+        let rq = RequestBuilder::get("/v1/users?limit=ten");
+        let action = api.action(Some(&api.validate(&rq.request_info(), None))).unwrap();
+        assert_eq!(action.reason.initiator, Initiator::OpenApi);
+        assert_eq!(action.reason.message.as_deref(), Some("type_mismatch at query:limit"));
+    }
+}
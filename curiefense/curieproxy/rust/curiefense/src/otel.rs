@@ -0,0 +1,262 @@
+use crate::crypto::to_hex;
+use crate::interface::Decision;
+use crate::logs::Logs;
+use crate::metadata::DynamicMetadata;
+use crate::shipper::{http_post, next_batch};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const QUEUE_SIZE: usize = 10000;
+/// inspections per export request
+const BATCH_SIZE: usize = 200;
+const FLUSH: Duration = Duration::from_secs(1);
+/// `SPAN_KIND_INTERNAL`
+const SPAN_KIND: u32 = 1;
+
+lazy_static! {
+    static ref EXPORTER: Option<Mutex<SyncSender<Vec<Value>>>> = start_exporter();
+}
+
+/// the parent of the inspection span, from a `traceparent` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+impl TraceParent {
+    /// parses a `version-traceid-parentid-flags` header, the ids being non zero
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version: [u8; 1] = parse_hex(parts.next()?)?;
+        let trace_id: [u8; 16] = parse_hex(parts.next()?)?;
+        let span_id: [u8; 8] = parse_hex(parts.next()?)?;
+        let flags: [u8; 1] = parse_hex(parts.next()?)?;
+        // future versions can append fields
+        if version[0] == 0xff || (version[0] == 0 && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(TraceParent {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+fn span(
+    trace_id: &str,
+    span_id: &[u8; 8],
+    parent: Option<&[u8; 8]>,
+    name: &str,
+    (start, end): (u128, u128),
+    attributes: Vec<Value>,
+) -> Value {
+    json!({
+        "traceId": trace_id,
+        "spanId": to_hex(span_id),
+        "parentSpanId": parent.map(|p| to_hex(p)).unwrap_or_default(),
+        "name": name,
+        "kind": SPAN_KIND,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes,
+    })
+}
+
+/// the spans of an inspection, that ended at `end`
+fn build_spans(
+    parent: Option<&TraceParent>,
+    decision: &Decision,
+    logs: &Logs,
+    attributes: &[(&str, String)],
+    end: SystemTime,
+) -> Vec<Value> {
+    let end_nanos = end.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let start_nanos = end_nanos.saturating_sub(logs.start.elapsed().as_nanos());
+    let trace_id = to_hex(&parent.map(|p| p.trace_id).unwrap_or_else(rand::random));
+    let root_id: [u8; 8] = rand::random();
+
+    let metadata = DynamicMetadata::new(decision, &Default::default());
+    let mut root_attributes: Vec<Value> = attributes.iter().map(|(k, v)| attribute(k, v)).collect();
+    root_attributes.push(attribute("curiefense.decision", &metadata.action));
+    if let Some(initiator) = metadata.initiator {
+        root_attributes.push(attribute("curiefense.initiator", initiator.as_str()));
+    }
+    let mut spans = vec![span(
+        &trace_id,
+        &root_id,
+        parent.map(|p| &p.span_id),
+        "curiefense.inspect",
+        (start_nanos, end_nanos),
+        root_attributes,
+    )];
+
+    let mut phase_start = start_nanos;
+    for timing in &logs.phases {
+        let phase_end = start_nanos + timing.elapsed_micros as u128 * 1000;
+        spans.push(span(
+            &trace_id,
+            &rand::random(),
+            Some(&root_id),
+            &format!("curiefense.{}", timing.phase),
+            (phase_start, phase_end),
+            Vec::new(),
+        ));
+        phase_start = phase_end;
+    }
+    spans
+}
+
+fn export_request(spans: Vec<Value>) -> Value {
+    let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "curiefense".to_string());
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute("service.name", &service)]},
+            "scopeSpans": [{"scope": {"name": "curiefense"}, "spans": spans}],
+        }]
+    })
+}
+
+fn start_exporter() -> Option<Mutex<SyncSender<Vec<Value>>>> {
+    let endpoint = match std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        Ok(url) if !url.is_empty() => url,
+        _ => std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .map(|url| format!("{}/v1/traces", url.trim_end_matches('/')))?,
+    };
+    let (sender, receiver) = sync_channel(QUEUE_SIZE);
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-otel".to_string())
+        .spawn(move || run(&endpoint, receiver))
+    {
+        tracing::error!("could not start the trace exporter: {}", rr);
+        return None;
+    }
+    Some(Mutex::new(sender))
+}
+
+fn run(endpoint: &str, receiver: Receiver<Vec<Value>>) {
+    while let Some(batch) = next_batch(&receiver, BATCH_SIZE, FLUSH) {
+        let body = export_request(batch.into_iter().flatten().collect());
+        if let Err(rr) = http_post(endpoint, "application/json", body.to_string()) {
+            tracing::warn!("could not export the traces: {}", rr);
+        }
+    }
+}
+
+/// exports the spans of an inspection, when an endpoint is configured
+///
+/// `attributes` are set on the inspection span, along with the decision
+pub fn export(traceparent: Option<&str>, decision: &Decision, logs: &Logs, attributes: &[(&str, String)]) {
+    let exporter = match EXPORTER.as_ref() {
+        None => return,
+        Some(e) => e,
+    };
+    let parent = traceparent.and_then(TraceParent::parse);
+    if parent.as_ref().map(|p| !p.sampled).unwrap_or(false) {
+        return;
+    }
+    let spans = build_spans(parent.as_ref(), decision, logs, attributes, SystemTime::now());
+    if let Ok(queue) = exporter.lock() {
+        // the spans are dropped when the queue is full
+        let _ = queue.try_send(spans);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::PhaseTiming;
+
+    #[test]
+    fn traceparent() {
+        let tp = TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(to_hex(&tp.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(to_hex(&tp.span_id), "00f067aa0ba902b7");
+        assert!(tp.sampled);
+        assert!(
+            !TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00")
+                .unwrap()
+                .sampled
+        );
+        assert!(TraceParent::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+        assert_eq!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"),
+            None
+        );
+        assert_eq!(
+            TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(TraceParent::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01"), None);
+        assert_eq!(TraceParent::parse("garbage"), None);
+    }
+
+    #[test]
+    fn spans() {
+        let parent = TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let logs = Logs {
+            phases: vec![
+                PhaseTiming {
+                    phase: "mapping".to_string(),
+                    elapsed_micros: 10,
+                },
+                PhaseTiming {
+                    phase: "acl".to_string(),
+                    elapsed_micros: 25,
+                },
+            ],
+            ..Logs::default()
+        };
+        let spans = build_spans(
+            Some(&parent),
+            &Decision::Pass,
+            &logs,
+            &[("curiefense.securitypolicy", "default".to_string())],
+            SystemTime::now(),
+        );
+        assert_eq!(spans.len(), 3);
+        let root = &spans[0];
+        assert_eq!(root["name"], "curiefense.inspect");
+        assert_eq!(root["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(root["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(
+            root["attributes"],
+            json!([
+                {"key": "curiefense.securitypolicy", "value": {"stringValue": "default"}},
+                {"key": "curiefense.decision", "value": {"stringValue": "pass"}},
+            ])
+        );
+        let acl = &spans[2];
+        assert_eq!(acl["name"], "curiefense.acl");
+        assert_eq!(acl["parentSpanId"], root["spanId"]);
+        let start: u128 = acl["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        let end: u128 = acl["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert_eq!(end - start, 15_000);
+        assert_eq!(spans[1]["endTimeUnixNano"], acl["startTimeUnixNano"]);
+    }
+}
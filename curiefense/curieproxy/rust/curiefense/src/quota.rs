@@ -0,0 +1,114 @@
+use crate::config::raw::RawQuota;
+use crate::counters;
+use crate::utils::RawRequest;
+use std::sync::Mutex;
+
+/// the CPU time used by the current thread, in microseconds
+pub fn thread_cpu_micros() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: the timespec is a valid pointer, and the clock is supported on all the targets
+    let res = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    if res != 0 {
+        return 0;
+    }
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+}
+
+/// the size of the data of a request, in bytes
+pub fn request_size(raw: &RawRequest) -> u64 {
+    let headers: usize = raw.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+    (headers + raw.meta.path.len() + raw.mbody.map(|b| b.len()).unwrap_or(0)) as u64
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// in milliseconds, see `counters::now`
+    window_start: u64,
+    cpu_micros: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct Quota {
+    /// in milliseconds
+    window: u64,
+    max_cpu_micros: Option<u64>,
+    max_bytes: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+impl Quota {
+    pub fn resolve(raw: &RawQuota) -> Self {
+        Quota {
+            window: raw.window.max(1) * 1000,
+            max_cpu_micros: raw.max_cpu_ms.map(|ms| ms * 1000),
+            max_bytes: raw.max_memory_kb.map(|kb| kb * 1024),
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    fn usage(&self, now: u64) -> std::sync::MutexGuard<'_, Usage> {
+        let mut usage = match self.usage.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if now.saturating_sub(usage.window_start) >= self.window {
+            *usage = Usage {
+                window_start: now,
+                ..Usage::default()
+            };
+        }
+        usage
+    }
+
+    /// true when a cap was reached during the current window
+    pub fn exceeded_at(&self, now: u64) -> bool {
+        let usage = self.usage(now);
+        self.max_cpu_micros.map(|m| usage.cpu_micros >= m).unwrap_or(false)
+            || self.max_bytes.map(|m| usage.bytes >= m).unwrap_or(false)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded_at(counters::now())
+    }
+
+    /// accounts the resources used by an inspection
+    pub fn record_at(&self, now: u64, cpu_micros: u64, bytes: u64) {
+        let mut usage = self.usage(now);
+        usage.cpu_micros += cpu_micros;
+        usage.bytes += bytes;
+    }
+
+    pub fn record(&self, cpu_micros: u64, bytes: u64) {
+        self.record_at(counters::now(), cpu_micros, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(raw: &str) -> Quota {
+        let raw: RawQuota = serde_json::from_str(raw).unwrap();
+        Quota::resolve(&raw)
+    }
+
+    #[test]
+    fn caps() {
+        let capped = quota(r#"{"window": 10, "max_cpu_ms": 5, "max_memory_kb": 1}"#);
+        assert!(!capped.exceeded_at(0));
+        capped.record_at(0, 4000, 100);
+        assert!(!capped.exceeded_at(1000));
+        capped.record_at(1000, 1000, 100);
+        assert!(capped.exceeded_at(2000));
+        // the usage starts over with the next window
+        assert!(!capped.exceeded_at(10_000));
+        capped.record_at(10_000, 0, 1024);
+        assert!(capped.exceeded_at(10_000));
+
+        let unlimited = quota("{}");
+        unlimited.record_at(0, u64::MAX / 2, u64::MAX / 2);
+        assert!(!unlimited.exceeded_at(0));
+        assert!(thread_cpu_micros() > 0);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::config::contentfilter::SectionIdx;
+use crate::interface::{Action, Decision};
+use crate::logs::{Logs, PhaseTiming};
+use crate::utils::RequestInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const REASON_SCHEMA_VERSION: u32 = 14;
+
+/// name of the response header that summarizes the reason of blocking actions
+pub const REASON_HEADER: &str = "X-Curiefense-Reason";
+
+/// the component that generated an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Initiator {
+    Acl,
+    ContentFilter,
+    Limit,
+    FlowCheck,
+    TagAction,
+    Phase01,
+    Phase02,
+    Captcha,
+    BodyMaxDepth,
+    BodyMaxSize,
+    BodyDecoding,
+    ParseBudget,
+    Observe,
+    Ban,
+    RequestSignature,
+    OpenApi,
+    Login,
+    BotScore,
+    Csrf,
+    CookieSignature,
+    Honeypot,
+    Ssrf,
+    Method,
+    Mode,
+    Unknown,
+}
+
+impl Initiator {
+    pub fn as_str(&self) -> &'static str {
+        use Initiator::*;
+        match self {
+            Acl => "acl",
+            ContentFilter => "content_filter",
+            Limit => "limit",
+            FlowCheck => "flow_check",
+            TagAction => "tag_action",
+            Phase01 => "phase01",
+            Phase02 => "phase02",
+            Captcha => "captcha",
+            BodyMaxDepth => "body_max_depth",
+            BodyMaxSize => "body_max_size",
+            BodyDecoding => "body_decoding",
+            ParseBudget => "parse_budget",
+            Observe => "observe",
+            Ban => "ban",
+            RequestSignature => "request_signature",
+            OpenApi => "openapi",
+            Login => "login",
+            BotScore => "bot_score",
+            Csrf => "csrf",
+            CookieSignature => "cookie_signature",
+            Honeypot => "honeypot",
+            Ssrf => "ssrf",
+            Method => "method",
+            Mode => "mode",
+            Unknown => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for Initiator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reason {
+    pub schema_version: u32,
+    pub initiator: Initiator,
+    /// the `x-request-id` header, set once the decision is taken
+    pub request_id: Option<String>,
+    /// name of the limit, flow control, or content filter outcome that triggered the action
+    pub name: Option<String>,
+    /// content filter rules that matched
+    pub rule_ids: Vec<String>,
+    pub tags: Vec<String>,
+    pub scores: BTreeMap<String, i64>,
+    /// time at which each inspection phase ended
+    pub timings: Vec<PhaseTiming>,
+    /// section of the request entry that triggered the action
+    pub section: Option<SectionIdx>,
+    /// name of the request entry that triggered the action
+    pub entry: Option<String>,
+    pub value: Option<String>,
+    /// human readable explanation
+    pub message: Option<String>,
+    /// initiator specific details
+    pub details: serde_json::Value,
+    /// the other matches, when all the inspection phases are run
+    pub matches: Vec<Reason>,
+}
+
+impl Default for Reason {
+    fn default() -> Self {
+        Reason::new(Initiator::Unknown)
+    }
+}
+
+impl Reason {
+    pub fn new(initiator: Initiator) -> Self {
+        Reason {
+            schema_version: REASON_SCHEMA_VERSION,
+            initiator,
+            request_id: None,
+            name: None,
+            rule_ids: Vec::new(),
+            tags: Vec::new(),
+            scores: BTreeMap::new(),
+            timings: Vec::new(),
+            section: None,
+            entry: None,
+            value: None,
+            message: None,
+            details: serde_json::Value::Null,
+            matches: Vec::new(),
+        }
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    /// sets the (sorted) tags, and the content filter rule ids they contain
+    pub fn with_tags<S: AsRef<str>, I: IntoIterator<Item = S>>(mut self, tags: I) -> Self {
+        let mut tags: Vec<String> = tags.into_iter().map(|t| t.as_ref().to_string()).collect();
+        tags.sort();
+        tags.dedup();
+        self.rule_ids = tags
+            .iter()
+            .filter_map(|t| t.strip_prefix("cf-rule-id:"))
+            .map(|t| t.to_string())
+            .collect();
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_entry(mut self, section: SectionIdx, entry: Option<&str>, value: Option<&str>) -> Self {
+        self.section = Some(section);
+        self.entry = entry.map(|s| s.to_string());
+        self.value = value.map(|s| s.to_string());
+        self
+    }
+
+    /// the summary sent in the `REASON_HEADER` response header, that does not disclose the rules
+    pub fn header_value(&self) -> String {
+        let mut out = format!("v={}; initiator={}", self.schema_version, self.initiator);
+        if let Some(rid) = &self.request_id {
+            // header values can't contain control characters
+            let rid: String = rid.chars().filter(|c| !c.is_control() && *c != ';').collect();
+            out += "; request_id=";
+            out += &rid;
+        }
+        out
+    }
+}
+
+/// sets the request id and the phase timings of the decision reason, and the reason header of blocking actions
+pub fn stamp(logs: &Logs, decision: Decision, rinfo: &RequestInfo) -> Decision {
+    let mut action: Action = match decision {
+        Decision::Pass => return Decision::Pass,
+        Decision::Action(a) => a,
+    };
+    action.reason.request_id = Some(rinfo.request_id.clone());
+    for m in action.reason.matches.iter_mut() {
+        m.request_id = Some(rinfo.request_id.clone());
+    }
+    action.reason.timings = logs.phases.clone();
+    if action.block_mode && action.atype.is_blocking() {
+        action
+            .headers
+            .get_or_insert_with(Default::default)
+            .insert(REASON_HEADER.to_string(), action.reason.header_value());
+    }
+    Decision::Action(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn serialized_fields() {
+        let tags: HashSet<String> = ["cf-rule-id:100", "sqli", "cf-rule-id:042"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let reason = Reason::new(Initiator::ContentFilter)
+            .with_name("block")
+            .with_tags(&tags);
+        assert_eq!(
+            serde_json::to_value(&reason).unwrap(),
+            json!({
+                "schema_version": REASON_SCHEMA_VERSION,
+                "initiator": "content_filter",
+                "request_id": null,
+                "name": "block",
+                "rule_ids": ["042", "100"],
+                "tags": ["cf-rule-id:042", "cf-rule-id:100", "sqli"],
+                "scores": {},
+                "timings": [],
+                "section": null,
+                "entry": null,
+                "value": null,
+                "message": null,
+                "details": null,
+                "matches": []
+            })
+        );
+    }
+
+    #[test]
+    fn header() {
+        let mut reason = Reason::new(Initiator::Acl);
+        assert_eq!(reason.header_value(), "v=14; initiator=acl");
+        reason.request_id = Some("abc;\r\nd".to_string());
+        assert_eq!(reason.header_value(), "v=14; initiator=acl; request_id=abcd");
+    }
+}
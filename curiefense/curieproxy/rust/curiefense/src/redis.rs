@@ -1,15 +1,30 @@
+use crate::logs::Logs;
+#[cfg(feature = "redis")]
 use crate::{
+    backend::{self, BackendError},
     interface::{SimpleAction, SimpleActionT},
-    logs::Logs,
 };
+#[cfg(feature = "redis")]
+use futures::lock::Mutex;
+#[cfg(feature = "redis")]
 use lazy_static::lazy_static;
-use redis::{ConnectionAddr, ConnectionInfo, RedisConnectionInfo};
+#[cfg(feature = "redis")]
+use redis::aio::{ConnectionLike, ConnectionManager};
+#[cfg(feature = "redis")]
+use redis::{
+    Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisConnectionInfo, RedisError, RedisFuture,
+    RedisResult, Value,
+};
 
+#[cfg(feature = "redis")]
 lazy_static! {
-    static ref RPOOL: anyhow::Result<redis::aio::ConnectionManager> = async_std::task::block_on(build_pool());
+    /// the connection, established by the first command, and retried by the next ones when it failed
+    static ref RPOOL: Mutex<Option<ConnectionManager>> = Mutex::new(None);
+    static ref RDB: i64 = std::env::var("REDIS_DB").ok().and_then(|db| db.parse().ok()).unwrap_or(0);
 }
 
 /// creates an async connection to a redis server
+#[cfg(feature = "redis")]
 pub async fn build_pool() -> anyhow::Result<redis::aio::ConnectionManager> {
     let server = std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis".to_string());
     let port = std::env::var("REDIS_PORT").unwrap_or_else(|_| "6379".to_string());
@@ -28,12 +43,66 @@ pub async fn build_pool() -> anyhow::Result<redis::aio::ConnectionManager> {
     Ok(o)
 }
 
-/// creates an async connection to a redis server
-pub async fn redis_async_conn() -> anyhow::Result<redis::aio::ConnectionManager> {
-    match &*RPOOL {
-        Ok(c) => Ok(c.clone()),
-        Err(rr) => Err(anyhow::anyhow!("{}", rr)),
+#[cfg(feature = "redis")]
+async fn shared_connection() -> RedisResult<ConnectionManager> {
+    let mut pool = RPOOL.lock().await;
+    if let Some(cnx) = pool.as_ref() {
+        return Ok(cnx.clone());
+    }
+    let cnx = build_pool()
+        .await
+        .map_err(|rr| RedisError::from((ErrorKind::IoError, "could not connect", rr.to_string())))?;
+    *pool = Some(cnx.clone());
+    Ok(cnx)
+}
+
+#[cfg(feature = "redis")]
+fn backend_error(rr: BackendError) -> RedisError {
+    RedisError::from((ErrorKind::IoError, "backend", rr.to_string()))
+}
+
+/// a connection whose commands are run on the backend threads (see `backend`), with the backend deadline
+#[cfg(feature = "redis")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackendConnection;
+
+#[cfg(feature = "redis")]
+impl ConnectionLike for BackendConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let cmd = cmd.clone();
+        Box::pin(async move {
+            let query = async move { shared_connection().await?.req_packed_command(&cmd).await };
+            backend::call(*backend::DEADLINE, query).await.map_err(backend_error)?
+        })
     }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let cmd = cmd.clone();
+        Box::pin(async move {
+            let query = async move {
+                shared_connection()
+                    .await?
+                    .req_packed_commands(&cmd, offset, count)
+                    .await
+            };
+            backend::call(*backend::DEADLINE, query).await.map_err(backend_error)?
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        *RDB
+    }
+}
+
+/// a connection to the redis server, the connection itself being established by the first command
+#[cfg(feature = "redis")]
+pub async fn redis_async_conn() -> anyhow::Result<BackendConnection> {
+    Ok(BackendConnection)
 }
 
 pub enum BanStatus {
@@ -41,6 +110,7 @@ pub enum BanStatus {
     AlreadyBanned,
 }
 
+#[cfg(feature = "redis")]
 pub async fn extract_bannable_action<CNX: redis::aio::ConnectionLike>(
     cnx: &mut CNX,
     logs: &mut Logs,
@@ -64,7 +134,7 @@ pub async fn extract_bannable_action<CNX: redis::aio::ConnectionLike>(
                     .query_async::<_, ()>(cnx)
                     .await
                 {
-                    println!("*** Redis error {}", rr);
+                    tracing::error!("Redis error {}", rr);
                 }
             }
         }
@@ -78,7 +148,56 @@ pub fn get_ban_key(key: &str) -> String {
     format!("{:X}", md5::compute(format!("limit-ban-hash{}", key)))
 }
 
+#[cfg(feature = "redis")]
 pub async fn is_banned<CNX: redis::aio::ConnectionLike>(cnx: &mut CNX, ban_key: &str) -> bool {
     let q: redis::RedisResult<Option<u32>> = redis::cmd("GET").arg(ban_key).query_async(cnx).await;
     q.unwrap_or(None).is_some()
 }
+
+/// the ban registered by blocking decisions, that is shared between the security policies
+pub fn get_decision_ban_key(session: &str) -> String {
+    format!("{:X}", md5::compute(format!("decision-ban-hash{}", session)))
+}
+
+#[cfg(feature = "redis")]
+pub async fn is_decision_banned(logs: &mut Logs, session: &str) -> bool {
+    match redis_async_conn().await {
+        Ok(mut cnx) => is_banned(&mut cnx, &get_decision_ban_key(session)).await,
+        Err(rr) => {
+            logs.error(|| format!("Could not connect to the redis server {}", rr));
+            false
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+pub async fn register_decision_ban(logs: &mut Logs, session: &str, duration: u64) {
+    let key = get_decision_ban_key(session);
+    let res: anyhow::Result<()> = async {
+        let mut cnx = redis_async_conn().await?;
+        redis::cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("EX")
+            .arg(duration)
+            .query_async::<_, ()>(&mut cnx)
+            .await?;
+        Ok(())
+    }
+    .await;
+    match res {
+        Ok(()) => logs.info(|| format!("Banned session {} for {}s", session, duration)),
+        Err(rr) => logs.error(|| format!("Could not register the ban: {}", rr)),
+    }
+}
+
+/// without redis, the blocking decisions do not ban the sessions
+#[cfg(not(feature = "redis"))]
+pub async fn is_decision_banned(_logs: &mut Logs, _session: &str) -> bool {
+    false
+}
+
+#[cfg(not(feature = "redis"))]
+pub async fn register_decision_ban(logs: &mut Logs, session: &str, _duration: u64) {
+    logs.debug(|| format!("Session {} not banned, redis is not available", session));
+}
@@ -0,0 +1,291 @@
+use crate::accesslog::{parse_timestamp, AccessLog};
+use crate::clock;
+use crate::grasshopper::DummyGrasshopper;
+use crate::inspect_batch_with;
+use crate::logs::{LogLevel, Logs};
+use crate::metadata::DynamicMetadata;
+use crate::reason::Initiator;
+use crate::utils::InspectionRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::BufRead;
+use std::time::UNIX_EPOCH;
+
+/// the number of requests that are inspected with the same configuration snapshot
+const BATCH_SIZE: usize = 1000;
+
+/// the part of the decision that is compared
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Verdict {
+    pub action: String,
+    pub blocking: bool,
+    pub initiator: Option<Initiator>,
+    pub rule_ids: Vec<String>,
+}
+
+impl Verdict {
+    pub fn new(metadata: &DynamicMetadata) -> Self {
+        let mut rule_ids = metadata.rule_ids.clone();
+        rule_ids.sort();
+        Verdict {
+            action: metadata.action.clone(),
+            blocking: metadata.blocking,
+            initiator: metadata.initiator,
+            rule_ids,
+        }
+    }
+
+    /// a short description, such as `block/content_filter`
+    pub fn summary(&self) -> String {
+        match &self.initiator {
+            None => self.action.clone(),
+            Some(i) => format!("{}/{}", self.action, i.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayDiff {
+    /// the line of the record in the access log
+    pub line: usize,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub original: Verdict,
+    pub candidate: Verdict,
+    pub added_tags: Vec<String>,
+    pub removed_tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub unchanged: usize,
+    /// the number of changed decisions, by `original -> candidate` summary
+    pub transitions: BTreeMap<String, usize>,
+    pub changed: Vec<ReplayDiff>,
+    /// the records that could not be replayed, with their line
+    pub errors: Vec<(usize, String)>,
+}
+
+/// the request of an access log record
+pub fn to_request(log: &AccessLog) -> InspectionRequest {
+    let attrs = &log.attributes;
+    let mut meta: HashMap<String, String> = HashMap::new();
+    meta.insert("method".to_string(), attrs.method.clone());
+    meta.insert("path".to_string(), attrs.uri.clone());
+    meta.insert("authority".to_string(), attrs.authority.clone());
+    let optional = [
+        ("scheme", &attrs.scheme),
+        ("port", &attrs.port),
+        ("http_version", &attrs.http_version),
+    ];
+    for (k, v) in optional.iter() {
+        if let Some(v) = v {
+            meta.insert(k.to_string(), v.clone());
+        }
+    }
+    // the decoded values are added again by the inspection
+    let original = |k: &String| !k.ends_with(":decoded");
+    let mut headers: HashMap<String, String> = log
+        .headers
+        .iter()
+        .filter(|(k, _)| original(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let mut cookies: Vec<String> = log
+        .cookies
+        .iter()
+        .filter(|(k, _)| original(k))
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    if !cookies.is_empty() {
+        cookies.sort();
+        headers.insert("cookie".to_string(), cookies.join("; "));
+    }
+    InspectionRequest {
+        ip: attrs.ip.clone(),
+        meta,
+        headers,
+        body: None,
+    }
+}
+
+fn tag_diff(original: &[String], candidate: &[String]) -> (Vec<String>, Vec<String>) {
+    let original: BTreeSet<&String> = original.iter().collect();
+    let candidate: BTreeSet<&String> = candidate.iter().collect();
+    (
+        candidate.difference(&original).map(|t| t.to_string()).collect(),
+        original.difference(&candidate).map(|t| t.to_string()).collect(),
+    )
+}
+
+impl ReplayReport {
+    fn record(&mut self, line: usize, log: &AccessLog, metadata: &DynamicMetadata) {
+        self.replayed += 1;
+        let original = Verdict::new(&log.metadata);
+        let candidate = Verdict::new(metadata);
+        if original == candidate {
+            self.unchanged += 1;
+            return;
+        }
+        *self
+            .transitions
+            .entry(format!("{} -> {}", original.summary(), candidate.summary()))
+            .or_default() += 1;
+        let (added_tags, removed_tags) = tag_diff(&log.metadata.tags, &metadata.tags);
+        self.changed.push(ReplayDiff {
+            line,
+            request_id: log.request_id.clone(),
+            method: log.attributes.method.clone(),
+            uri: log.attributes.uri.clone(),
+            original,
+            candidate,
+            added_tags,
+            removed_tags,
+        });
+    }
+
+    fn replay_batch(&mut self, configpath: &str, batch: &[(usize, AccessLog)]) {
+        let requests: Vec<InspectionRequest> = batch.iter().map(|(_, log)| to_request(log)).collect();
+        let mut raws = Vec::with_capacity(requests.len());
+        let mut replayed = Vec::with_capacity(requests.len());
+        for ((line, log), request) in batch.iter().zip(requests.iter()) {
+            match request.to_raw() {
+                Ok(raw) => {
+                    raws.push(raw);
+                    replayed.push((*line, log));
+                }
+                Err(rr) => self.errors.push((*line, rr.to_string())),
+            }
+        }
+        let mut logs = Logs::new(LogLevel::Error);
+        let times: Vec<_> = replayed
+            .iter()
+            .map(|(_, log)| parse_timestamp(&log.timestamp))
+            .collect();
+        let results = inspect_batch_with(configpath, None::<DummyGrasshopper>, &raws, &mut logs, |idx| {
+            // the records without a valid timestamp are replayed at the time of the previous one
+            if let Some(time) = times[idx] {
+                clock::set_time(time);
+            }
+        });
+        for ((line, log), (decision, tags, _, _)) in replayed.into_iter().zip(results) {
+            let metadata = DynamicMetadata::new(&decision, &tags);
+            self.record(line, log, &metadata);
+        }
+    }
+}
+
+/// replays the access log records read from `reader` with the configuration stored at `configpath`, the random values
+/// coming from a generator seeded with `seed`
+pub fn replay<R: BufRead>(configpath: &str, reader: R, seed: u64) -> ReplayReport {
+    clock::deterministic(UNIX_EPOCH, seed, || replay_records(configpath, reader))
+}
+
+fn replay_records<R: BufRead>(configpath: &str, reader: R) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    let mut batch: Vec<(usize, AccessLog)> = Vec::with_capacity(BATCH_SIZE);
+    for (idx, line) in reader.lines().enumerate() {
+        let lineno = idx + 1;
+        let line = match line {
+            Ok(l) => l,
+            Err(rr) => {
+                report.errors.push((lineno, rr.to_string()));
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<AccessLog>(&line) {
+            Ok(log) => batch.push((lineno, log)),
+            Err(rr) => report
+                .errors
+                .push((lineno, format!("invalid access log record: {}", rr))),
+        }
+        if batch.len() >= BATCH_SIZE {
+            report.replay_batch(configpath, &batch);
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        report.replay_batch(configpath, &batch);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::ParseBudget;
+    use crate::interface::{Action, Decision, Tags};
+    use crate::reason::Reason;
+    use crate::requestmap::RequestMap;
+    use crate::utils::{map_request, RawRequest, RequestMeta};
+
+    fn access_log(decision: &Decision) -> AccessLog {
+        let meta = RequestMeta::from_map(
+            [("method", "POST"), ("path", "/foo?a=1"), ("authority", "myhost")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap();
+        let headers = [("user-agent", "test"), ("cookie", "c=d; e=f")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let raw = RawRequest {
+            ipstr: "5.6.7.8".to_string(),
+            headers,
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: None,
+        };
+        let mut logs = Logs::default();
+        let rinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        let mut tags = Tags::default();
+        tags.insert("t1");
+        AccessLog::new(decision, &RequestMap::new(rinfo, tags), &logs)
+    }
+
+    #[test]
+    fn rebuilt_request() {
+        let log = access_log(&Decision::Pass);
+        let request = to_request(&log);
+        assert_eq!(request.ip, "5.6.7.8");
+        assert_eq!(request.meta["method"], "POST");
+        assert_eq!(request.meta["path"], "/foo?a=1");
+        assert_eq!(request.meta["authority"], "myhost");
+        assert_eq!(request.headers["user-agent"], "test");
+        assert_eq!(request.headers["cookie"], "c=d; e=f");
+
+        // the serialized record can be read back
+        let line = serde_json::to_string(&log).unwrap();
+        let read: AccessLog = serde_json::from_str(&line).unwrap();
+        assert_eq!(to_request(&read).headers, request.headers);
+    }
+
+    #[test]
+    fn changed_decisions() {
+        let pass = access_log(&Decision::Pass);
+        let block = Decision::Action(Action {
+            reason: Reason::new(Initiator::Acl),
+            ..Action::default()
+        });
+        let mut tags = Tags::default();
+        tags.insert("t1");
+        tags.insert("t2");
+
+        let mut report = ReplayReport::default();
+        report.record(1, &pass, &DynamicMetadata::new(&Decision::Pass, &tags));
+        report.record(2, &pass, &DynamicMetadata::new(&block, &tags));
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.transitions["pass -> block/acl"], 1);
+        assert_eq!(report.changed[0].line, 2);
+        assert_eq!(report.changed[0].added_tags, vec!["t2".to_string()]);
+        assert!(report.changed[0].removed_tags.is_empty());
+    }
+}
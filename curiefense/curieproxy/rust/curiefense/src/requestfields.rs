@@ -1,9 +1,81 @@
 use crate::config::contentfilter::Transformation;
+use crate::config::raw::FieldBudget;
 use crate::config::utils::{DataSource, XDataSource};
-use crate::utils::decoders::DecodingResult;
-use crate::utils::masker;
+use crate::normalization::Normalization;
+use crate::utils::decoders::{nested_key, DecodingResult};
+use crate::utils::{mask_matches, masker};
+use regex::Regex;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::{hash_map, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// the memory ceiling of the products of the parsing of a single request, shared by all its fields
+///
+/// The keys, values, decoded variants and raw bytes that are stored in the fields are charged to the arena, and the
+/// products that do not fit are dropped, setting the `overflow` flag of the field. The values are still allocated
+/// individually, as the mapped request outlives the raw request it was parsed from.
+#[derive(Debug, Default)]
+pub struct ParseArena {
+    cap: usize,
+    used: AtomicUsize,
+}
+
+impl ParseArena {
+    pub fn new(cap: usize) -> Self {
+        ParseArena {
+            cap,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// charges these bytes to the arena, failing if they do not fit
+    pub fn reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.cap)
+            })
+            .is_ok()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+}
+
+impl PartialEq for ParseArena {
+    fn eq(&self, other: &Self) -> bool {
+        self.cap == other.cap && self.used() == other.used()
+    }
+}
+
+impl Eq for ParseArena {}
+
+/// the value decoded by the transformation, if it changed it
+fn decode(tr: &Transformation, value: &str) -> Option<String> {
+    match tr {
+        Transformation::Base64Decode => crate::utils::decoders::base64dec_all_str(value).ok(),
+        Transformation::UrlDecode => match crate::utils::decoders::urldecode_str(value) {
+            DecodingResult::Changed(ns) => Some(ns),
+            DecodingResult::NoChange => None,
+        },
+        // this code is not robust enough, as it fails on the first entity error, and will not decode anything
+        // ie. "foo &gt&gt;" will not be decoded, but it should return "foo &gt>"
+        Transformation::HtmlEntitiesDecode => match crate::utils::decoders::htmlentities(value) {
+            DecodingResult::Changed(ns) => Some(ns),
+            DecodingResult::NoChange => None,
+        },
+        Transformation::UnicodeDecode => match crate::utils::decoders::parse_unicode(value) {
+            DecodingResult::Changed(ns) => Some(ns),
+            DecodingResult::NoChange => None,
+        },
+    }
+}
 
 /// a newtype for user supplied data that can collide
 /// more or less like a HashMap, but concatenates entries with a separator on insert
@@ -11,67 +83,122 @@ use std::collections::{hash_map, HashMap};
 pub struct RequestField {
     pub decoding: Vec<Transformation>,
     pub fields: HashMap<String, (String, HashSet<DataSource>)>,
+    /// original bytes of the values that were not valid UTF-8, the `fields` entry holding their lossy version
+    pub raw: HashMap<String, Vec<u8>>,
+    pub budget: FieldBudget,
+    /// when set, PHP style argument names are converted, see `nested_key`
+    pub nested_keys: bool,
+    /// when set, the parameters are parsed as the upstream parses them, see the `normalization` module
+    pub normalization: Option<Arc<Normalization>>,
+    /// amount of entries, not counting the decoded ones
+    entries: usize,
+    decoded_bytes: usize,
+    /// amount of decoding transformations that changed a value
+    decode_passes: usize,
+    /// set when entries or decoded values were dropped because of the budget
+    pub overflow: bool,
+    /// the arena of the request, when its parsing products are capped
+    pub arena: Option<Arc<ParseArena>>,
 }
 
 impl RequestField {
+    /// charges these bytes to the arena, if any, setting the overflow flag when they do not fit
+    fn reserve(&mut self, bytes: usize) -> bool {
+        match &self.arena {
+            Some(arena) if !arena.reserve(bytes) => {
+                self.overflow = true;
+                false
+            }
+            _ => true,
+        }
+    }
+
     fn base_add(&mut self, key: String, ds: DataSource, value: String) {
-        self.fields
-            .entry(key)
-            .and_modify(|(v, pds)| {
+        match self.fields.entry(key) {
+            hash_map::Entry::Occupied(mut entry) => {
+                let (v, pds) = entry.get_mut();
                 v.push(' ');
                 v.push_str(&value);
-                pds.insert(ds.clone());
-            })
-            .or_insert({
+                pds.insert(ds);
+            }
+            hash_map::Entry::Vacant(entry) => {
                 let mut hs = HashSet::new();
                 hs.insert(ds);
-                (value, hs)
-            });
+                entry.insert((value, hs));
+            }
+        }
     }
 
     pub fn add(&mut self, key: String, ds: DataSource, value: String) {
-        let mut v = value.clone();
+        if !self.fields.contains_key(&key) {
+            if self.budget.max_entries > 0 && self.entries >= self.budget.max_entries {
+                self.overflow = true;
+                return;
+            }
+            self.entries += 1;
+        }
+        if !self.reserve(key.len() + value.len()) {
+            return;
+        }
         // try to insert each value as its decoded base64 version, if it makes sense
-        if !&v.is_empty() {
-            let mut changed = false;
-            for tr in self.decoding.iter() {
-                match tr {
-                    Transformation::Base64Decode => {
-                        if let Ok(n) = crate::utils::decoders::base64dec_all_str(&v) {
-                            v = n;
-                            changed = true;
-                        }
-                    }
-                    Transformation::UrlDecode => {
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::urldecode_str(&v) {
-                            v = ns;
-                            changed = true;
-                        }
-                    }
-                    Transformation::HtmlEntitiesDecode => {
-                        // this code is not robust enough, as it fails on the first entity error, and will not decode anything
-                        // ie. "foo &gt&gt;" will not be decoded, but it should return "foo &gt>"
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::htmlentities(&v) {
-                            v = ns;
-                            changed = true;
-                        }
-                    }
-                    Transformation::UnicodeDecode => {
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::parse_unicode(&v) {
-                            v = ns;
-                            changed = true;
-                        }
-                    }
+        // the value is only copied when one of the transformations changed it
+        if !value.is_empty() {
+            let mut v = Cow::Borrowed(value.as_str());
+            let passes = match self.budget.max_decode_passes {
+                0 => self.decoding.len(),
+                n => n.min(self.decoding.len()),
+            };
+            for tr in &self.decoding[..passes] {
+                if let Some(n) = decode(tr, &v) {
+                    v = Cow::Owned(n);
+                    self.decode_passes += 1;
                 }
             }
-            if changed {
-                self.base_add(key.clone() + ":decoded", DataSource::DecodedFrom(key.clone()), v);
+            // the budget only overflows when a skipped transformation would have decoded the value
+            if self.decoding[passes..].iter().any(|tr| decode(tr, &v).is_some()) {
+                self.overflow = true;
+            }
+            if let Cow::Owned(v) = v {
+                self.decoded_bytes += v.len();
+                if self.budget.max_decoded_bytes > 0 && self.decoded_bytes > self.budget.max_decoded_bytes {
+                    self.overflow = true;
+                } else if self.reserve(key.len() + ":decoded".len() + v.len()) {
+                    self.base_add(format!("{}:decoded", key), DataSource::DecodedFrom(key.clone()), v);
+                }
             }
         }
         self.base_add(key, ds, value);
     }
 
+    /// adds a value that might not be valid UTF-8
+    ///
+    /// the lossy version of the value is inserted, and the original bytes are kept so that they can be inspected
+    pub fn add_bytes(&mut self, key: String, ds: DataSource, value: &[u8]) {
+        match std::str::from_utf8(value) {
+            Ok(s) => self.add(key, ds, s.to_string()),
+            Err(_) => {
+                if !self.reserve(value.len()) {
+                    return;
+                }
+                self.raw
+                    .entry(key.clone())
+                    .and_modify(|v| {
+                        v.push(b' ');
+                        v.extend_from_slice(value);
+                    })
+                    .or_insert_with(|| value.to_vec());
+                self.add(key, ds, String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+
+    /// iterates over the original bytes of the values that were not valid UTF-8
+    pub fn iter_raw(&self) -> impl Iterator<Item = (&str, &[u8])> + '_ {
+        self.raw.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
     pub fn mask(&mut self, masking_seed: &[u8], key: &str) -> HashSet<XDataSource> {
+        self.raw.remove(key);
         let remask = self
             .fields
             .get_mut(key)
@@ -96,6 +223,16 @@ impl RequestField {
             .collect()
     }
 
+    /// masks the parts of all the values that match the patterns
+    pub fn mask_matches(&mut self, masking_seed: &[u8], patterns: &[Regex]) {
+        for (k, (v, _)) in self.fields.iter_mut() {
+            if let Some(masked) = mask_matches(masking_seed, patterns, v) {
+                *v = masked;
+                self.raw.remove(k);
+            }
+        }
+    }
+
     pub fn get(&self, k: &str) -> Option<&String> {
         self.fields.get(k).map(|(v, _)| v)
     }
@@ -108,6 +245,10 @@ impl RequestField {
         self.fields.len()
     }
 
+    pub fn decode_passes(&self) -> usize {
+        self.decode_passes
+    }
+
     pub fn is_empty(&self) -> bool {
         self.fields.is_empty()
     }
@@ -116,6 +257,10 @@ impl RequestField {
         self.fields.iter().map(|(k, (v, _))| (k.as_str(), v.as_str()))
     }
 
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
     pub fn to_json(&self) -> serde_json::Value {
         serde_json::Value::Object(
             self.iter()
@@ -125,12 +270,44 @@ impl RequestField {
     }
 
     pub fn new(decoding: &[Transformation]) -> Self {
+        Self::with_budget(decoding, FieldBudget::default())
+    }
+
+    pub fn with_budget(decoding: &[Transformation], budget: FieldBudget) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
             fields: HashMap::default(),
+            raw: HashMap::default(),
+            budget,
+            nested_keys: false,
+            normalization: None,
+            entries: 0,
+            decoded_bytes: 0,
+            decode_passes: 0,
+            overflow: false,
+            arena: None,
+        }
+    }
+
+    /// the name of a query or form parameter, as the upstream sees it
+    pub fn param_key(&self, key: String) -> String {
+        let key = match &self.normalization {
+            Some(n) => n.key(key),
+            None => key,
+        };
+        if self.nested_keys || self.normalization.as_ref().map(|n| n.nested_keys).unwrap_or(false) {
+            nested_key(&key).unwrap_or(key)
+        } else {
+            key
         }
     }
 
+    /// charges the parsing products to this arena
+    pub fn with_arena(mut self, arena: Option<Arc<ParseArena>>) -> Self {
+        self.arena = arena;
+        self
+    }
+
     pub fn singleton(decoding: &[Transformation], k: String, ds: DataSource, v: String) -> Self {
         let mut out = RequestField::new(decoding);
         out.add(k, ds, v);
@@ -165,6 +342,15 @@ impl RequestField {
                     (k.to_string(), (v.to_string(), hs))
                 })
                 .collect(),
+            raw: HashMap::default(),
+            budget: FieldBudget::default(),
+            nested_keys: false,
+            normalization: None,
+            entries: content.len(),
+            decoded_bytes: 0,
+            decode_passes: 0,
+            overflow: false,
+            arena: None,
         }
     }
 }
@@ -0,0 +1,273 @@
+use crate::helpers::ip_to_num;
+use crate::interface::Tags;
+use crate::utils::{GeoIp, RequestInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const SCHEMA_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RequestMap {
+    pub schema_version: u32,
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub args: HashMap<String, String>,
+    pub path: HashMap<String, String>,
+    pub attrs: Attrs,
+    pub tags: Tags,
+    pub geo: Geo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Attrs {
+    pub uri: String,
+    pub path: String,
+    pub canonical_path: String,
+    pub query: String,
+    pub ip: String,
+    /// the IP address, as a decimal number
+    pub ipnum: Option<String>,
+    pub authority: String,
+    pub method: String,
+    pub scheme: Option<String>,
+    pub port: Option<String>,
+    pub http_version: Option<String>,
+    pub body_truncated: String,
+    pub session: String,
+    pub fingerprint: String,
+    /// see `utils::request_id`
+    #[serde(default)]
+    pub request_id: String,
+    /// the heuristic bot score, from 0 to 100, see the `botscore` module
+    #[serde(default)]
+    pub bot_score: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cert_subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cert_san: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cert_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cert_verified: Option<String>,
+    /// the TLS fingerprints, see `utils::TlsFingerprint`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ja3: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ja4: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub proxy_src_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub proxy_src_port: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub proxy_dst_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub proxy_dst_port: Option<String>,
+    /// metadata entries that are not interpreted
+    #[serde(flatten)]
+    pub extra: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Geo {
+    pub location: GeoLocation,
+    pub city: GeoCity,
+    pub eu: Option<bool>,
+    pub country: GeoCountry,
+    pub continent: GeoContinent,
+    pub asn: Option<u32>,
+    pub company: Option<String>,
+    pub region: Option<String>,
+    pub subregion: Option<String>,
+    pub anonymous: GeoAnonymous,
+}
+
+/// serialized as an empty object when the location is unknown
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct GeoLocation {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub lon: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GeoCity {
+    /// `-` when unknown
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GeoCountry {
+    pub name: Option<String>,
+    pub iso: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GeoContinent {
+    pub name: Option<String>,
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GeoAnonymous {
+    pub anonymous: Option<bool>,
+    pub vpn: Option<bool>,
+    pub hosting: Option<bool>,
+    pub public_proxy: Option<bool>,
+    pub tor: Option<bool>,
+}
+
+impl Geo {
+    pub fn new(geoip: &GeoIp) -> Self {
+        Geo {
+            location: geoip
+                .location
+                .map(|(lat, lon)| GeoLocation {
+                    lat: Some(lat),
+                    lon: Some(lon),
+                })
+                .unwrap_or_default(),
+            city: GeoCity {
+                name: geoip.city_name.clone().unwrap_or_else(|| "-".to_string()),
+            },
+            eu: geoip.in_eu,
+            country: GeoCountry {
+                name: geoip.country_name.clone(),
+                iso: geoip.country_iso.clone(),
+            },
+            continent: GeoContinent {
+                name: geoip.continent_name.clone(),
+                code: geoip.continent_code.clone(),
+            },
+            asn: geoip.asn,
+            company: geoip.company.clone(),
+            region: geoip.region.clone(),
+            subregion: geoip.subregion.clone(),
+            anonymous: GeoAnonymous {
+                anonymous: geoip.anonymous.is_anonymous,
+                vpn: geoip.anonymous.is_anonymous_vpn,
+                hosting: geoip.anonymous.is_hosting_provider,
+                public_proxy: geoip.anonymous.is_public_proxy,
+                tor: geoip.anonymous.is_tor_exit_node,
+            },
+        }
+    }
+}
+
+impl RequestMap {
+    pub fn new(rinfo: RequestInfo, tags: Tags) -> Self {
+        let geo = Geo::new(&rinfo.rinfo.geoip);
+        let ipnum: Option<String> = rinfo.rinfo.geoip.ip.map(|i| ip_to_num(i).to_string());
+        let meta = rinfo.rinfo.meta;
+        let qinfo = rinfo.rinfo.qinfo;
+        let cert = rinfo.client_cert;
+        let proxy = meta.proxy;
+        let attrs = Attrs {
+            uri: qinfo.uri,
+            path: qinfo.qpath,
+            canonical_path: qinfo.canonical_path,
+            query: qinfo.query,
+            ip: rinfo.rinfo.geoip.ipstr,
+            ipnum,
+            authority: rinfo.rinfo.host,
+            method: meta.method,
+            scheme: meta.scheme,
+            port: meta.port.map(|p| p.to_string()),
+            http_version: meta.http_version,
+            body_truncated: meta.body_truncated.to_string(),
+            session: rinfo.session,
+            fingerprint: rinfo.fingerprint,
+            request_id: rinfo.request_id,
+            bot_score: rinfo.bot.score.to_string(),
+            cert_subject: cert.as_ref().and_then(|c| c.subject.clone()),
+            cert_san: cert.as_ref().map(|c| c.san.join(",")),
+            cert_fingerprint: cert.as_ref().and_then(|c| c.fingerprint.clone()),
+            cert_verified: cert.as_ref().map(|c| c.verified.to_string()),
+            ja3: rinfo.tls.ja3,
+            ja4: rinfo.tls.ja4,
+            proxy_src_ip: proxy.as_ref().map(|p| p.src_ip.clone()),
+            proxy_src_port: proxy.as_ref().and_then(|p| p.src_port).map(|p| p.to_string()),
+            proxy_dst_ip: proxy.as_ref().and_then(|p| p.dst_ip.clone()),
+            proxy_dst_port: proxy.as_ref().and_then(|p| p.dst_port).map(|p| p.to_string()),
+            extra: meta.extra,
+        };
+        RequestMap {
+            schema_version: SCHEMA_VERSION,
+            headers: rinfo.headers.to_map(),
+            cookies: rinfo.cookies.to_map(),
+            args: qinfo.args.to_map(),
+            path: qinfo.path_as_map.to_map(),
+            attrs,
+            tags,
+            geo,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+    use serde_json::json;
+
+    fn request_map() -> RequestMap {
+        let rinfo = RequestBuilder::get("/foo?a=1")
+            .host("myhost")
+            .meta("x-custom", "cv")
+            .header("user-agent", "dummy")
+            .cookie("c", "v")
+            .request_info();
+        let mut tags = Tags::default();
+        tags.insert("t1");
+        RequestMap::new(rinfo, tags)
+    }
+
+    #[test]
+    fn schema_fields() {
+        let v = serde_json::to_value(request_map()).unwrap();
+        assert_eq!(v["schema_version"], json!(SCHEMA_VERSION));
+        assert_eq!(v["headers"], json!({"user-agent": "dummy"}));
+        assert_eq!(v["cookies"], json!({"c": "v"}));
+        assert_eq!(v["args"], json!({"a": "1"}));
+        assert_eq!(v["tags"], json!(["t1"]));
+        let attrs = v["attrs"].as_object().unwrap();
+        for (k, expected) in [
+            ("uri", json!("/foo?a=1")),
+            ("path", json!("/foo")),
+            ("canonical_path", json!("/foo")),
+            ("query", json!("a=1")),
+            ("ip", json!("1.2.3.4")),
+            ("ipnum", json!("16909060")),
+            ("authority", json!("myhost")),
+            ("method", json!("GET")),
+            ("scheme", json!(null)),
+            ("port", json!(null)),
+            ("http_version", json!(null)),
+            ("body_truncated", json!("false")),
+            ("session", json!("1.2.3.4")),
+            ("x-custom", json!("cv")),
+        ] {
+            assert_eq!(attrs.get(k), Some(&expected), "attribute {}", k);
+        }
+        assert!(attrs.contains_key("fingerprint"));
+        assert_eq!(attrs["request_id"].as_str().map(|s| s.len()), Some(36));
+        assert_eq!(attrs["bot_score"], json!("0"));
+        assert!(!attrs.contains_key("proxy_src_ip"));
+        assert!(!attrs.contains_key("cert_subject"));
+        assert!(!attrs.contains_key("ja3"));
+        assert_eq!(v["geo"]["location"], json!({}));
+        assert_eq!(v["geo"]["city"], json!({"name": "-"}));
+        assert_eq!(
+            v["geo"]["anonymous"],
+            json!({"anonymous": null, "vpn": null, "hosting": null, "public_proxy": null, "tor": null})
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let rmap = request_map();
+        let serialized = serde_json::to_string(&rmap).unwrap();
+        let deserialized: RequestMap = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, rmap);
+    }
+}
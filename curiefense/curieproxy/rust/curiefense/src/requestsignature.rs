@@ -0,0 +1,278 @@
+use crate::clock;
+use crate::config::raw::{RawRequestSignature, SignatureAlgorithm, SignatureCanonicalization};
+use crate::crypto::{from_hex, to_hex};
+use crate::interface::Action;
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::utils::decoders::base64dec_all;
+use crate::utils::RequestInfo;
+use ring::hmac;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// the outcome of the verification, turned into the `signature:<status>` tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Valid,
+    Missing,
+    UnknownKey,
+    Expired,
+    Invalid,
+}
+
+impl SignatureStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureStatus::Valid => "valid",
+            SignatureStatus::Missing => "missing",
+            SignatureStatus::UnknownKey => "unknown-key",
+            SignatureStatus::Expired => "expired",
+            SignatureStatus::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureResult {
+    pub status: SignatureStatus,
+    /// the key id of the valid signatures
+    pub key_id: Option<String>,
+}
+
+impl SignatureResult {
+    fn failed(status: SignatureStatus) -> Self {
+        SignatureResult { status, key_id: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestSignature {
+    keys: HashMap<String, hmac::Key>,
+    algorithm: SignatureAlgorithm,
+    header: String,
+    key_header: String,
+    timestamp_header: String,
+    canonicalization: SignatureCanonicalization,
+    signed_headers: Vec<String>,
+    max_skew: u64,
+    enforce: bool,
+}
+
+/// decodes the signature, in hexadecimal or base64
+fn decode_signature(encoded: &str, algorithm: SignatureAlgorithm) -> Option<Vec<u8>> {
+    let (prefix, len) = match algorithm {
+        SignatureAlgorithm::Sha256 => ("sha256=", 32),
+        SignatureAlgorithm::Sha512 => ("sha512=", 64),
+    };
+    let encoded = encoded.trim();
+    let encoded = encoded.strip_prefix(prefix).unwrap_or(encoded);
+    if encoded.len() == len * 2 {
+        if let Some(signature) = from_hex(encoded) {
+            return Some(signature);
+        }
+    }
+    base64dec_all(encoded.as_bytes()).ok()
+}
+
+impl RequestSignature {
+    pub fn resolve(raw: &RawRequestSignature) -> Self {
+        let halg = match raw.algorithm {
+            SignatureAlgorithm::Sha256 => hmac::HMAC_SHA256,
+            SignatureAlgorithm::Sha512 => hmac::HMAC_SHA512,
+        };
+        RequestSignature {
+            keys: raw
+                .secrets
+                .iter()
+                .map(|(kid, secret)| (kid.clone(), hmac::Key::new(halg, secret.as_bytes())))
+                .collect(),
+            algorithm: raw.algorithm,
+            header: raw.header.to_lowercase(),
+            key_header: raw.key_header.to_lowercase(),
+            timestamp_header: raw.timestamp_header.to_lowercase(),
+            canonicalization: raw.canonicalization,
+            signed_headers: raw.signed_headers.iter().map(|h| h.to_lowercase()).collect(),
+            max_skew: raw.max_skew,
+            enforce: raw.enforce,
+        }
+    }
+
+    fn canonical(&self, reqinfo: &RequestInfo, timestamp: &str, body: &[u8]) -> Vec<u8> {
+        match self.canonicalization {
+            SignatureCanonicalization::Body => {
+                let mut out = format!("{}.", timestamp).into_bytes();
+                out.extend(body);
+                out
+            }
+            SignatureCanonicalization::Request => {
+                let mut out = format!(
+                    "{}\n{}\n{}\n{}",
+                    timestamp,
+                    reqinfo.rinfo.meta.method.to_uppercase(),
+                    reqinfo.rinfo.meta.path,
+                    to_hex(&Sha256::digest(body))
+                );
+                for name in &self.signed_headers {
+                    out += &format!("\n{}:{}", name, reqinfo.headers.get_str(name).unwrap_or("").trim());
+                }
+                out.into_bytes()
+            }
+        }
+    }
+
+    /// verifies the signature of the request, `body` being the raw body
+    pub fn verify(&self, logs: &mut Logs, reqinfo: &RequestInfo, body: Option<&[u8]>) -> SignatureResult {
+        let (encoded, timestamp) = match (
+            reqinfo.headers.get_str(&self.header),
+            reqinfo.headers.get_str(&self.timestamp_header),
+        ) {
+            (Some(s), Some(t)) => (s, t.trim()),
+            _ => return SignatureResult::failed(SignatureStatus::Missing),
+        };
+        let key_id = reqinfo.headers.get_str(&self.key_header).unwrap_or("default").trim();
+        let key = match self.keys.get(key_id) {
+            Some(k) => k,
+            None => {
+                logs.debug(|| format!("unknown signature key {}", key_id));
+                return SignatureResult::failed(SignatureStatus::UnknownKey);
+            }
+        };
+        let signature = match decode_signature(encoded, self.algorithm) {
+            Some(s) => s,
+            None => return SignatureResult::failed(SignatureStatus::Invalid),
+        };
+        // the signature covers the whole body, that the truncated bodies do not have
+        if reqinfo.rinfo.meta.body_truncated {
+            logs.debug("the signature can not be verified on a truncated body");
+            return SignatureResult::failed(SignatureStatus::Invalid);
+        }
+        let message = self.canonical(reqinfo, timestamp, body.unwrap_or_default());
+        if hmac::verify(key, &message, &signature).is_err() {
+            return SignatureResult::failed(SignatureStatus::Invalid);
+        }
+        // checked once the timestamp is known to be authentic
+        let now = clock::unix_secs();
+        match timestamp.parse::<u64>() {
+            Ok(ts) if now.max(ts) - now.min(ts) <= self.max_skew => SignatureResult {
+                status: SignatureStatus::Valid,
+                key_id: Some(key_id.to_string()),
+            },
+            _ => {
+                logs.debug(|| format!("signature timestamp {} out of the skew window", timestamp));
+                SignatureResult::failed(SignatureStatus::Expired)
+            }
+        }
+    }
+
+    /// the blocking action, when the signatures are enforced and the request does not have a valid one
+    pub fn action(&self, result: Option<&SignatureResult>) -> Option<Action> {
+        let status = result.map(|r| r.status).unwrap_or(SignatureStatus::Missing);
+        if !self.enforce || status == SignatureStatus::Valid {
+            return None;
+        }
+        Some(Action {
+            status: 401,
+            reason: Reason::new(Initiator::RequestSignature)
+                .with_message(format!("request signature {}", status.as_str())),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn settings(canonicalization: SignatureCanonicalization) -> RequestSignature {
+        RequestSignature::resolve(&RawRequestSignature {
+            secrets: std::iter::once(("svc".to_string(), "secret".to_string())).collect(),
+            header: "X-Signature".to_string(),
+            key_header: "x-signature-key-id".to_string(),
+            timestamp_header: "x-signature-timestamp".to_string(),
+            canonicalization,
+            signed_headers: vec!["content-type".to_string()],
+            algorithm: SignatureAlgorithm::Sha256,
+            max_skew: 300,
+            enforce: true,
+        })
+    }
+
+    fn sign(message: &str) -> String {
+        to_hex(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"secret"), message.as_bytes()).as_ref())
+    }
+
+    fn verify(settings: &RequestSignature, rq: RequestBuilder) -> SignatureStatus {
+        let body = rq.raw().mbody.map(|b| b.to_vec());
+        settings
+            .verify(&mut Logs::default(), &rq.request_info(), body.as_deref())
+            .status
+    }
+
+    #[test]
+    fn request_signatures() {
+        let settings = settings(SignatureCanonicalization::Request);
+        let body_hash = to_hex(&Sha256::digest(b"{\"a\":1}"));
+        let signature = sign(&format!(
+            "1000000\nPOST\n/api?x=1\n{}\ncontent-type:application/json",
+            body_hash
+        ));
+        let request = |signature: &str, key: &str| {
+            RequestBuilder::post("/api?x=1", "{\"a\":1}")
+                .header("content-type", "application/json")
+                .header("x-signature", signature)
+                .header("x-signature-key-id", key)
+                .header("x-signature-timestamp", "1000000")
+        };
+        clock::deterministic(UNIX_EPOCH + Duration::from_secs(1_000_100), 0, || {
+            assert_eq!(verify(&settings, request(&signature, "svc")), SignatureStatus::Valid);
+            assert_eq!(
+                verify(&settings, request(&format!("sha256={}", signature), "svc")),
+                SignatureStatus::Valid
+            );
+            assert_eq!(
+                verify(&settings, request(&signature, "other")),
+                SignatureStatus::UnknownKey
+            );
+            assert_eq!(
+                verify(&settings, request(&sign("forged"), "svc")),
+                SignatureStatus::Invalid
+            );
+            let tampered = request(&signature, "svc").header("content-type", "text/plain");
+            assert_eq!(verify(&settings, tampered), SignatureStatus::Invalid);
+            assert_eq!(
+                verify(&settings, RequestBuilder::post("/api?x=1", "{\"a\":1}")),
+                SignatureStatus::Missing
+            );
+            clock::advance(Duration::from_secs(300));
+            assert_eq!(verify(&settings, request(&signature, "svc")), SignatureStatus::Expired);
+        });
+
+        let action = settings.action(None).unwrap();
+        assert_eq!(
+            (action.status, action.reason.initiator),
+            (401, Initiator::RequestSignature)
+        );
+        let valid = SignatureResult {
+            status: SignatureStatus::Valid,
+            key_id: Some("svc".to_string()),
+        };
+        assert!(settings.action(Some(&valid)).is_none());
+    }
+
+    #[test]
+    fn body_signatures() {
+        let settings = settings(SignatureCanonicalization::Body);
+        let signature = sign("1000000.payload");
+        clock::deterministic(UNIX_EPOCH + Duration::from_secs(1_000_000), 0, || {
+            // without a key id, the `default` secret is used, and the settings only have the `svc` one
+            let rq = RequestBuilder::post("/hook", "payload")
+                .header("x-signature", &signature)
+                .header("x-signature-timestamp", "1000000");
+            assert_eq!(verify(&settings, rq.clone()), SignatureStatus::UnknownKey);
+            let rq = rq.header("x-signature-key-id", "svc");
+            assert_eq!(verify(&settings, rq), SignatureStatus::Valid);
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use crate::clock;
+use crate::config::raw::{RawRiskSettings, RiskIdentity};
+use crate::interface::{Decision, Tags};
+use crate::logs::Logs;
+use crate::reason::Initiator;
+#[cfg(feature = "redis")]
+use crate::redis::redis_async_conn;
+use crate::utils::RequestInfo;
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+
+/// decays the score, then adds the increment, atomically
+#[cfg(feature = "redis")]
+const UPDATE_SCRIPT: &str = r"
+local now = tonumber(ARGV[1])
+local score = tonumber(redis.call('HGET', KEYS[1], 's') or '0')
+local last = tonumber(redis.call('HGET', KEYS[1], 't') or ARGV[1])
+score = score * math.pow(0.5, math.max(now - last, 0) / tonumber(ARGV[2])) + tonumber(ARGV[3])
+redis.call('HSET', KEYS[1], 's', tostring(score), 't', ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return tostring(score)
+";
+
+#[derive(Debug, Clone)]
+pub struct RiskScoring {
+    identity: RiskIdentity,
+    /// in milliseconds
+    half_life: u64,
+    weights: HashMap<Initiator, f64>,
+    bot_score_factor: f64,
+    thresholds: Vec<u32>,
+}
+
+fn now_millis() -> u64 {
+    clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// the score stored at `last`, at `now`
+pub fn decayed(score: f64, last: u64, now: u64, half_life: u64) -> f64 {
+    let elapsed = now.saturating_sub(last) as f64;
+    score * 0.5f64.powf(elapsed / half_life.max(1) as f64)
+}
+
+impl RiskScoring {
+    pub fn resolve(raw: &RawRiskSettings) -> Self {
+        let mut thresholds = raw.thresholds.clone();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        RiskScoring {
+            identity: raw.identity,
+            half_life: raw.half_life.max(1) * 1000,
+            weights: raw.weights.clone(),
+            bot_score_factor: raw.bot_score_factor,
+            thresholds,
+        }
+    }
+
+    fn key(&self, reqinfo: &RequestInfo) -> Option<String> {
+        let identity = match self.identity {
+            RiskIdentity::Session => &reqinfo.session,
+            RiskIdentity::Ip => &reqinfo.rinfo.geoip.ipstr,
+            RiskIdentity::Fingerprint => &reqinfo.fingerprint,
+        };
+        if identity.is_empty() {
+            return None;
+        }
+        Some(format!("{:X}", md5::compute(format!("risk-score{}", identity))))
+    }
+
+    /// the increase of the score, for the final decision of a request
+    pub fn increment(&self, reqinfo: &RequestInfo, decision: &Decision) -> f64 {
+        let decision_weight = match decision {
+            Decision::Action(action) => self.weights.get(&action.reason.initiator).copied().unwrap_or(0.0),
+            Decision::Pass => 0.0,
+        };
+        decision_weight + self.bot_score_factor * f64::from(reqinfo.bot.score)
+    }
+
+    /// tags the thresholds reached by the score
+    pub fn tag(&self, score: f64, tags: &mut Tags) {
+        for threshold in self.thresholds.iter().filter(|t| score >= f64::from(**t)) {
+            tags.insert_qualified("risk-score", &threshold.to_string());
+        }
+    }
+
+    /// the current score of the client of the request, 0 when it is unknown or redis is unreachable
+    pub async fn score(&self, logs: &mut Logs, reqinfo: &RequestInfo) -> f64 {
+        let key = match self.key(reqinfo) {
+            Some(k) => k,
+            None => return 0.0,
+        };
+        match load_score(&key).await {
+            Ok((Some(score), Some(last))) => decayed(score, last, now_millis(), self.half_life),
+            Ok(_) => 0.0,
+            Err(rr) => {
+                logs.error(|| format!("Could not read the risk score: {}", rr));
+                0.0
+            }
+        }
+    }
+
+    /// adds the increment of the decision to the score of the client of the request
+    pub async fn record(&self, logs: &mut Logs, reqinfo: &RequestInfo, decision: &Decision) {
+        let increment = self.increment(reqinfo, decision);
+        let key = match self.key(reqinfo) {
+            Some(k) if increment > 0.0 => k,
+            _ => return,
+        };
+        // below a thousandth of its value, the score is forgotten
+        let ttl = self.half_life / 1000 * 10;
+        match raise_score(&key, self.half_life, increment, ttl).await {
+            Ok(score) => logs.debug(|| format!("risk score raised by {} to {}", increment, score)),
+            Err(rr) => logs.error(|| format!("Could not update the risk score: {}", rr)),
+        }
+    }
+}
+
+/// the stored score, and when it was last updated
+#[cfg(feature = "redis")]
+async fn load_score(key: &str) -> anyhow::Result<(Option<f64>, Option<u64>)> {
+    let mut cnx = redis_async_conn().await?;
+    Ok(redis::cmd("HMGET")
+        .arg(key)
+        .arg("s")
+        .arg("t")
+        .query_async(&mut cnx)
+        .await?)
+}
+
+#[cfg(feature = "redis")]
+async fn raise_score(key: &str, half_life: u64, increment: f64, ttl: u64) -> anyhow::Result<String> {
+    let mut cnx = redis_async_conn().await?;
+    Ok(redis::cmd("EVAL")
+        .arg(UPDATE_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .arg(now_millis())
+        .arg(half_life)
+        .arg(increment)
+        .arg(ttl)
+        .query_async(&mut cnx)
+        .await?)
+}
+
+/// without redis, the scores are not kept between requests
+#[cfg(not(feature = "redis"))]
+async fn load_score(_key: &str) -> anyhow::Result<(Option<f64>, Option<u64>)> {
+    Ok((None, None))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn raise_score(_key: &str, _half_life: u64, increment: f64, _ttl: u64) -> anyhow::Result<String> {
+    Ok(increment.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Action;
+    use crate::reason::Reason;
+    use crate::test_utils::RequestBuilder;
+
+    fn scoring() -> RiskScoring {
+        let raw: RawRiskSettings = serde_json::from_str(r#"{"thresholds": [50, 25, 50], "half_life": 60}"#).unwrap();
+        RiskScoring::resolve(&raw)
+    }
+
+    #[test]
+    fn decay() {
+        assert_eq!(decayed(40.0, 1000, 1000, 60_000), 40.0);
+        assert_eq!(decayed(40.0, 1000, 61_000, 60_000), 20.0);
+        assert_eq!(decayed(40.0, 1000, 121_000, 60_000), 10.0);
+        // a clock going backward does not raise the score
+        assert_eq!(decayed(40.0, 5000, 1000, 60_000), 40.0);
+    }
+
+    #[test]
+    fn increments_and_tags() {
+        let risk = scoring();
+        let reqinfo = RequestBuilder::get("/")
+            .header("user-agent", "curl/8.4.0")
+            .request_info();
+        let blocked = |initiator| {
+            Decision::Action(Action {
+                reason: Reason::new(initiator),
+                ..Action::default()
+            })
+        };
+        // the bot score is 80, and counts for a tenth
+        assert_eq!(risk.increment(&reqinfo, &Decision::Pass), 8.0);
+        assert_eq!(risk.increment(&reqinfo, &blocked(Initiator::ContentFilter)), 28.0);
+        assert_eq!(risk.increment(&reqinfo, &blocked(Initiator::Limit)), 18.0);
+        assert_eq!(risk.increment(&reqinfo, &blocked(Initiator::Observe)), 8.0);
+        assert!(risk.key(&reqinfo).is_some());
+
+        let mut tags = Tags::default();
+        risk.tag(30.0, &mut tags);
+        assert!(tags.contains("risk-score:25"));
+        assert!(!tags.contains("risk-score:50"));
+        risk.tag(50.0, &mut tags);
+        assert!(tags.contains("risk-score:50"));
+    }
+}
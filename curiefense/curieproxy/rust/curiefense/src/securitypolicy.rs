@@ -1,35 +1,71 @@
 use crate::config::hostmap::{HostMap, SecurityPolicy};
 use crate::config::Config;
 use crate::logs::Logs;
+use crate::utils::RequestMeta;
 
 /// finds the securitypolicy matching a given request, based on the configuration
 /// there are cases where default values do not exist (even though the UI should prevent that)
 ///
-/// note that the url is matched using the url-decoded path!
+/// note that the path should be canonicalized (see `RequestMeta::canonical_path`), so that the selection can't
+/// be bypassed with encoded or dot segments
+///
+/// entries can also be restricted to some methods, schemes, ports or protocol versions, in which case the
+/// next matching entry is selected when the request does not satisfy them
+///
+/// the integration can also select the host map by name, with the `securitypolicy` entry of the metadata
 ///
 /// returns the matching security policy, along with the id of the selected host map
 pub fn match_securitypolicy<'a>(
     host: &str,
     path: &str,
+    meta: &RequestMeta,
     cfg: &'a Config,
     logs: &mut Logs,
 ) -> Option<(String, &'a SecurityPolicy)> {
+    // the hostmap can be selected by name, by the integration
+    let named = meta.securitypolicy.as_ref().and_then(|name| {
+        let found = cfg
+            .securitypolicies
+            .iter()
+            .map(|m| &m.inner)
+            .chain(cfg.default.as_ref())
+            .find(|h| &h.name == name);
+        if found.is_none() {
+            logs.warning(|| format!("Unknown security policy {}, matching the host instead", name));
+        }
+        found
+    });
     // find the first matching hostmap, or use the default, if it exists
-    let hostmap: &HostMap = cfg
-        .securitypolicies
-        .iter()
-        .find(|e| e.matches(host))
-        .map(|m| &m.inner)
-        .or(cfg.default.as_ref())?;
+    let hostmap: &HostMap = match named {
+        Some(h) => h,
+        None => cfg
+            .securitypolicies
+            .iter()
+            .find(|e| e.matches(host))
+            .map(|m| &m.inner)
+            .or(cfg.default.as_ref())?,
+    };
     logs.debug(|| format!("Selected hostmap {}", hostmap.name));
+    // the entry is selected with the path the upstream resolves
+    let normalized = hostmap
+        .normalization
+        .as_ref()
+        .filter(|n| n.rewrites_path())
+        .map(|n| n.canonical_path(&meta.path));
+    let path = normalized.as_deref().unwrap_or(path);
     // find the first matching securitypolicy, or use the default, if it exists
-    let securitypolicy: &SecurityPolicy = match hostmap
-        .entries
-        .iter()
-        .find(|e| e.matches(path))
-        .map(|m| &m.inner)
-        .or(hostmap.default.as_ref())
-    {
+    // the path patterns are tested in a single pass when they could be compiled together
+    let matched = match &hostmap.paths {
+        Some(paths) if paths.len() == hostmap.entries.len() => paths
+            .matches(path)
+            .map(|i| &hostmap.entries[i])
+            .find(|e| e.inner.request_line.matches(meta)),
+        _ => hostmap
+            .entries
+            .iter()
+            .find(|e| e.matches(path) && e.inner.request_line.matches(meta)),
+    };
+    let securitypolicy: &SecurityPolicy = match matched.map(|m| &m.inner).or(hostmap.default.as_ref()) {
         None => {
             logs.debug("This hostname has no default entry!");
             return None;
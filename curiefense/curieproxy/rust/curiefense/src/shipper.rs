@@ -0,0 +1,343 @@
+use crate::accesslog::AccessLog;
+use crate::aggregation::{AggregationKey, Aggregator};
+use crate::interface::{Decision, Tags};
+use crate::logs::Logs;
+use crate::requestmap::RequestMap;
+use crate::utils::{env_or, RequestInfo};
+use lazy_static::lazy_static;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_QUEUE_SIZE: usize = 10000;
+const DEFAULT_BATCH_SIZE: usize = 500;
+const DEFAULT_FLUSH_MS: u64 = 1000;
+/// attempts to send a batch, before dropping it
+const SEND_ATTEMPTS: u32 = 3;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref SHIPPER: Option<Shipper> = Shipper::from_env();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    File(String),
+    Syslog(String),
+    Elasticsearch { url: String, index: String },
+    Kafka { url: String, topic: String },
+}
+
+/// splits an URL such as `http://host:9200/index` into the base URL and its last path segment
+fn split_url(spec: &str) -> Result<(String, String), String> {
+    match spec.trim_end_matches('/').rsplit_once('/') {
+        Some((url, name)) if !name.is_empty() && url.contains("://") && !url.ends_with('/') => {
+            Ok((url.to_string(), name.to_string()))
+        }
+        _ => Err(format!(
+            "{} should be an URL ending with a name, such as http://host:port/name",
+            spec
+        )),
+    }
+}
+
+impl Sink {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (kind, target) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("invalid log sink {}", spec))?;
+        match kind {
+            "file" => Ok(Sink::File(target.to_string())),
+            "syslog" => Ok(Sink::Syslog(target.to_string())),
+            "elasticsearch" => split_url(target).map(|(url, index)| Sink::Elasticsearch { url, index }),
+            "kafka" => split_url(target).map(|(url, topic)| Sink::Kafka { url, topic }),
+            _ => Err(format!("unknown log sink type {}", kind)),
+        }
+    }
+
+    fn send(&self, batch: &[String]) -> Result<(), String> {
+        match self {
+            Sink::File(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|rr| rr.to_string())?;
+                let mut lines = batch.join("\n");
+                lines.push('\n');
+                file.write_all(lines.as_bytes()).map_err(|rr| rr.to_string())
+            }
+            Sink::Syslog(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|rr| rr.to_string())?;
+                for record in batch {
+                    socket
+                        .send_to(syslog_message(record).as_bytes(), addr)
+                        .map_err(|rr| rr.to_string())?;
+                }
+                Ok(())
+            }
+            Sink::Elasticsearch { url, index } => http_post(
+                &format!("{}/_bulk", url),
+                "application/x-ndjson",
+                elasticsearch_body(index, batch),
+            ),
+            Sink::Kafka { url, topic } => http_post(
+                &format!("{}/topics/{}", url, topic),
+                "application/vnd.kafka.json.v2+json",
+                kafka_body(batch),
+            ),
+        }
+    }
+}
+
+/// facility local0, severity informational, no timestamp and hostname (set by the collector)
+fn syslog_message(record: &str) -> String {
+    format!("<134>1 - - curiefense - - - {}", record)
+}
+
+fn elasticsearch_body(index: &str, batch: &[String]) -> String {
+    let action = serde_json::json!({"index": {"_index": index}}).to_string();
+    let mut body = String::new();
+    for record in batch {
+        body.push_str(&action);
+        body.push('\n');
+        body.push_str(record);
+        body.push('\n');
+    }
+    body
+}
+
+/// the records are already serialized, and are inserted as they are
+fn kafka_body(batch: &[String]) -> String {
+    let records: Vec<String> = batch.iter().map(|r| format!("{{\"value\":{}}}", r)).collect();
+    format!("{{\"records\":[{}]}}", records.join(","))
+}
+
+pub(crate) fn http_post(url: &str, content_type: &str, body: String) -> Result<(), String> {
+    let rsp = attohttpc::post(url)
+        .timeout(HTTP_TIMEOUT)
+        .header("content-type", content_type)
+        .text(body)
+        .send()
+        .map_err(|rr| rr.to_string())?;
+    if rsp.is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} returned {}", url, rsp.status()))
+    }
+}
+
+struct Shipper {
+    queue: Mutex<SyncSender<String>>,
+    dropped: AtomicU64,
+    aggregator: Option<Mutex<Aggregator>>,
+    /// fraction of the passed requests that are shipped
+    pass_sampling: f64,
+}
+
+impl Shipper {
+    fn from_env() -> Option<Self> {
+        let spec = std::env::var("CURIEFENSE_LOG_SINK").ok().filter(|s| !s.is_empty())?;
+        let sink = match Sink::parse(&spec) {
+            Ok(s) => s,
+            Err(rr) => {
+                tracing::error!("access logs are not shipped: {}", rr);
+                return None;
+            }
+        };
+        let batch_size = env_or("CURIEFENSE_LOG_BATCH_SIZE", DEFAULT_BATCH_SIZE).max(1);
+        let flush = Duration::from_millis(env_or("CURIEFENSE_LOG_FLUSH_MS", DEFAULT_FLUSH_MS));
+        let (sender, receiver) = sync_channel(env_or("CURIEFENSE_LOG_QUEUE_SIZE", DEFAULT_QUEUE_SIZE));
+        if let Err(rr) = std::thread::Builder::new()
+            .name("curiefense-shipper".to_string())
+            .spawn(move || run(sink, receiver, batch_size, flush))
+        {
+            tracing::error!("could not start the access log shipper: {}", rr);
+            return None;
+        }
+        let window = Duration::from_millis(env_or("CURIEFENSE_LOG_AGGREGATION_MS", 0));
+        let aggregator = if window.is_zero() {
+            None
+        } else {
+            if let Err(rr) = std::thread::Builder::new()
+                .name("curiefense-aggregation".to_string())
+                .spawn(move || flush_aggregates(window))
+            {
+                tracing::error!("could not start the aggregation thread: {}", rr);
+                return None;
+            }
+            Some(Mutex::new(Aggregator::new(window)))
+        };
+        tracing::info!("shipping the access logs to {}", spec);
+        Some(Shipper {
+            queue: Mutex::new(sender),
+            dropped: AtomicU64::new(0),
+            aggregator,
+            pass_sampling: env_or("CURIEFENSE_LOG_PASS_SAMPLING", 1.0_f64).clamp(0.0, 1.0),
+        })
+    }
+
+    fn push_record(&self, record: &AccessLog) {
+        match serde_json::to_string(record) {
+            Ok(r) => self.push(r),
+            Err(rr) => tracing::error!("could not serialize the access log: {}", rr),
+        }
+    }
+
+    fn push(&self, record: String) {
+        let sent = match self.queue.lock() {
+            Ok(queue) => queue.try_send(record),
+            Err(_) => return,
+        };
+        match sent {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+/// reads up to `batch_size` records, waiting at most `flush` once the first one is received
+///
+/// returns `None` when the queue is closed and empty
+pub(crate) fn next_batch<T>(receiver: &Receiver<T>, batch_size: usize, flush: Duration) -> Option<Vec<T>> {
+    let first = receiver.recv().ok()?;
+    let deadline = Instant::now() + flush;
+    let mut batch = vec![first];
+    while batch.len() < batch_size {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(record) => batch.push(record),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Some(batch)
+}
+
+/// ships the summaries of the aggregation windows that ended
+fn flush_aggregates(window: Duration) {
+    loop {
+        std::thread::sleep(window / 2);
+        if let Some(shipper) = SHIPPER.as_ref() {
+            let summaries = match shipper.aggregator.as_ref().map(|a| a.lock()) {
+                Some(Ok(mut aggregator)) => aggregator.expired(Instant::now()),
+                _ => return,
+            };
+            for record in summaries {
+                shipper.push_record(&record);
+            }
+        }
+    }
+}
+
+fn run(sink: Sink, receiver: Receiver<String>, batch_size: usize, flush: Duration) {
+    while let Some(batch) = next_batch(&receiver, batch_size, flush) {
+        if let Some(dropped) = SHIPPER.as_ref().map(|s| s.dropped.swap(0, Ordering::Relaxed)) {
+            if dropped > 0 {
+                tracing::warn!("{} access log records were dropped, the queue is full", dropped);
+            }
+        }
+        let mut attempt = 0;
+        while let Err(rr) = sink.send(&batch) {
+            attempt += 1;
+            if attempt >= SEND_ATTEMPTS {
+                tracing::error!("dropped {} access log records: {}", batch.len(), rr);
+                break;
+            }
+            tracing::warn!("could not ship the access logs, retrying: {}", rr);
+            std::thread::sleep(Duration::from_millis(200 << attempt));
+        }
+    }
+}
+
+/// queues the access log of an inspection, when a sink is configured
+pub fn ship(decision: &Decision, tags: &Tags, rinfo: &RequestInfo, logs: &Logs) {
+    if let Some(shipper) = SHIPPER.as_ref() {
+        if matches!(decision, Decision::Pass)
+            && shipper.pass_sampling < 1.0
+            && crate::clock::random::<f64>() >= shipper.pass_sampling
+        {
+            return;
+        }
+        let mut tags = tags.clone();
+        if let Decision::Action(a) = decision {
+            for t in a.extra_tags.iter().flatten() {
+                tags.insert(t);
+            }
+        }
+        let access_log = AccessLog::new(decision, &RequestMap::new(rinfo.clone(), tags), logs);
+        let records = match (&shipper.aggregator, AggregationKey::new(&access_log)) {
+            (Some(aggregator), Some(key)) => match aggregator.lock() {
+                Ok(mut a) => a.add(key, access_log, Instant::now()),
+                Err(_) => return,
+            },
+            _ => vec![access_log],
+        };
+        for record in records {
+            shipper.push_record(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sinks() {
+        assert_eq!(
+            Sink::parse("file:/tmp/cf.json"),
+            Ok(Sink::File("/tmp/cf.json".to_string()))
+        );
+        assert_eq!(
+            Sink::parse("elasticsearch:http://es:9200/curiefense/"),
+            Ok(Sink::Elasticsearch {
+                url: "http://es:9200".to_string(),
+                index: "curiefense".to_string()
+            })
+        );
+        assert_eq!(
+            Sink::parse("kafka:https://rest:8082/logs"),
+            Ok(Sink::Kafka {
+                url: "https://rest:8082".to_string(),
+                topic: "logs".to_string()
+            })
+        );
+        assert!(Sink::parse("kafka:http://rest:8082").is_err());
+        assert!(Sink::parse("kafka:rest/logs").is_err());
+        assert!(Sink::parse("s3:bucket").is_err());
+    }
+
+    #[test]
+    fn bodies() {
+        let batch = vec!["{\"a\":1}".to_string(), "{\"b\":2}".to_string()];
+        assert_eq!(
+            elasticsearch_body("cf", &batch),
+            "{\"index\":{\"_index\":\"cf\"}}\n{\"a\":1}\n{\"index\":{\"_index\":\"cf\"}}\n{\"b\":2}\n"
+        );
+        let kafka: serde_json::Value = serde_json::from_str(&kafka_body(&batch)).unwrap();
+        assert_eq!(
+            kafka,
+            serde_json::json!({"records": [{"value": {"a": 1}}, {"value": {"b": 2}}]})
+        );
+    }
+
+    #[test]
+    fn batching() {
+        let (sender, receiver) = sync_channel(10);
+        for i in 0..5 {
+            sender.send(i.to_string()).unwrap();
+        }
+        drop(sender);
+        let flush = Duration::from_millis(10);
+        assert_eq!(next_batch(&receiver, 3, flush).unwrap(), vec!["0", "1", "2"]);
+        assert_eq!(next_batch(&receiver, 3, flush).unwrap(), vec!["3", "4"]);
+        assert!(next_batch(&receiver, 3, flush).is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigSetOptions {
+    /// ASCII letters match regardless of their case
+    pub case_insensitive: bool,
+    /// the matches must not be preceded or followed by a word character (ASCII alphanumeric or `_`)
+    pub whole_word: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigMatch<'a> {
+    pub id: &'a str,
+    /// byte offsets of the match, the end being excluded
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SigSet {
+    ids: Vec<String>,
+    automaton: AhoCorasick,
+    whole_word: bool,
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+impl SigSet {
+    /// compiles the patterns, given with their ids
+    pub fn new<P: AsRef<[u8]>>(patterns: Vec<(String, P)>, options: SigSetOptions) -> Result<Self, String> {
+        if let Some((id, _)) = patterns.iter().find(|(_, p)| p.as_ref().is_empty()) {
+            return Err(format!("empty pattern {}", id));
+        }
+        let (ids, patterns): (Vec<String>, Vec<P>) = patterns.into_iter().unzip();
+        let automaton = AhoCorasick::builder()
+            .ascii_case_insensitive(options.case_insensitive)
+            .match_kind(MatchKind::Standard)
+            .build(patterns)
+            .map_err(|rr| rr.to_string())?;
+        Ok(SigSet {
+            ids,
+            automaton,
+            whole_word: options.whole_word,
+        })
+    }
+
+    /// the number of patterns
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// all the matches, ordered by end offset
+    pub fn matches<'a>(&'a self, input: &[u8]) -> Vec<SigMatch<'a>> {
+        self.automaton
+            .find_overlapping_iter(input)
+            .filter(|m| {
+                !self.whole_word
+                    || (m.start() == 0 || !is_word(input[m.start() - 1]))
+                        && input.get(m.end()).map(|b| !is_word(*b)).unwrap_or(true)
+            })
+            .map(|m| SigMatch {
+                id: &self.ids[m.pattern().as_usize()],
+                start: m.start(),
+                end: m.end(),
+            })
+            .collect()
+    }
+
+    pub fn is_match(&self, input: &[u8]) -> bool {
+        if self.whole_word {
+            !self.matches(input).is_empty()
+        } else {
+            self.automaton.is_match(input)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(patterns: &[(&str, &str)], options: SigSetOptions) -> SigSet {
+        SigSet::new(
+            patterns.iter().map(|(i, p)| (i.to_string(), p.to_string())).collect(),
+            options,
+        )
+        .unwrap()
+    }
+
+    fn found(set: &SigSet, input: &str) -> Vec<(String, usize, usize)> {
+        set.matches(input.as_bytes())
+            .into_iter()
+            .map(|m| (m.id.to_string(), m.start, m.end))
+            .collect()
+    }
+
+    #[test]
+    fn all_matches() {
+        let patterns = [("union", "union"), ("select", "select"), ("ion", "ion")];
+        let plain = set(&patterns, SigSetOptions::default());
+        assert_eq!(
+            found(&plain, "union select UNION"),
+            vec![
+                ("union".to_string(), 0, 5),
+                ("ion".to_string(), 2, 5),
+                ("select".to_string(), 6, 12)
+            ]
+        );
+        assert!(!plain.is_match(b"nothing here"));
+
+        let nocase = set(
+            &patterns,
+            SigSetOptions {
+                case_insensitive: true,
+                whole_word: false,
+            },
+        );
+        assert_eq!(found(&nocase, "UNION").len(), 2);
+
+        let words = set(
+            &patterns,
+            SigSetOptions {
+                case_insensitive: true,
+                whole_word: true,
+            },
+        );
+        assert_eq!(
+            found(&words, "reunion; Select union_all UNION"),
+            vec![("select".to_string(), 9, 15), ("union".to_string(), 26, 31)]
+        );
+        assert!(!words.is_match(b"selection"));
+
+        assert_eq!(
+            SigSet::new(vec![("a".to_string(), "")], SigSetOptions::default()).err(),
+            Some("empty pattern a".to_string())
+        );
+    }
+}
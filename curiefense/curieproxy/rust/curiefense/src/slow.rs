@@ -0,0 +1,118 @@
+use crate::logs::Logs;
+use crate::metrics::Observation;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+lazy_static! {
+    static ref BUDGET_MICROS: Option<u64> = std::env::var("CURIEFENSE_LATENCY_BUDGET_US")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|b| *b > 0);
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SlowInspection {
+    pub request_id: String,
+    pub elapsed_micros: u64,
+    pub budget_micros: u64,
+    /// the phase that took the most time
+    pub phase: String,
+    pub phase_micros: u64,
+    /// the most expensive content filter signature group
+    pub signature_group: Option<String>,
+    pub signature_group_micros: Option<u64>,
+}
+
+impl SlowInspection {
+    /// the slow inspection event, when the inspection exceeded its budget
+    pub(crate) fn new(request_id: &str, observation: &Observation, logs: &Logs, budget_micros: u64) -> Option<Self> {
+        if observation.elapsed_micros <= budget_micros {
+            return None;
+        }
+        let phases_micros: u64 = observation.phases.iter().map(|(_, micros)| micros).sum();
+        let other = (
+            "other".to_string(),
+            observation.elapsed_micros.saturating_sub(phases_micros),
+        );
+        // the first phase wins ties
+        let (phase, phase_micros) = observation
+            .phases
+            .iter()
+            .chain(std::iter::once(&other))
+            .fold(None, |best: Option<&(String, u64)>, cur| match best {
+                Some(b) if b.1 >= cur.1 => Some(b),
+                _ => Some(cur),
+            })
+            .cloned()
+            .unwrap_or(other);
+        let group = logs
+            .signature_costs
+            .iter()
+            .max_by(|(ga, a), (gb, b)| a.cmp(b).then_with(|| gb.cmp(ga)));
+        Some(SlowInspection {
+            request_id: request_id.to_string(),
+            elapsed_micros: observation.elapsed_micros,
+            budget_micros,
+            phase,
+            phase_micros,
+            signature_group: group.map(|(g, _)| g.clone()),
+            signature_group_micros: group.map(|(_, micros)| *micros),
+        })
+    }
+}
+
+/// logs the slow inspection event, when a budget is set and exceeded
+pub(crate) fn check(request_id: &str, observation: &Observation, logs: &Logs) -> Option<SlowInspection> {
+    let slow = SlowInspection::new(request_id, observation, logs, (*BUDGET_MICROS)?)?;
+    tracing::warn!(
+        target: "curiefense::slow",
+        request_id = %slow.request_id,
+        elapsed_micros = slow.elapsed_micros,
+        budget_micros = slow.budget_micros,
+        phase = %slow.phase,
+        phase_micros = slow.phase_micros,
+        signature_group = ?slow.signature_group,
+        signature_group_micros = ?slow.signature_group_micros,
+        "slow inspection"
+    );
+    Some(slow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dominant_phase() {
+        let mut observation = Observation {
+            action: "pass".to_string(),
+            initiator: "none",
+            blocked: false,
+            triggers: Vec::new(),
+            phases: vec![
+                ("mapping".to_string(), 100),
+                ("content_filter".to_string(), 700),
+                ("acl".to_string(), 50),
+            ],
+            elapsed_micros: 1000,
+        };
+        let mut logs = Logs::default();
+        logs.signature_cost("sqli", 300);
+        logs.signature_cost("xss", 50);
+        logs.signature_cost("sqli", 200);
+
+        assert_eq!(SlowInspection::new("r1", &observation, &logs, 1000), None);
+        let slow = SlowInspection::new("r1", &observation, &logs, 500).unwrap();
+        assert_eq!(slow.phase, "content_filter");
+        assert_eq!(slow.phase_micros, 700);
+        assert_eq!(slow.signature_group.as_deref(), Some("sqli"));
+        assert_eq!(slow.signature_group_micros, Some(500));
+
+        // most of the time was spent after the last recorded phase
+        observation.elapsed_micros = 5000;
+        let slow = SlowInspection::new("r1", &observation, &Logs::default(), 500).unwrap();
+        assert_eq!(slow.phase, "other");
+        assert_eq!(slow.phase_micros, 4150);
+        assert_eq!(slow.signature_group, None);
+    }
+}
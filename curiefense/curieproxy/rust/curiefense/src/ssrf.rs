@@ -0,0 +1,340 @@
+use crate::backend;
+use crate::cache::Cache;
+use crate::config::raw::{RawSsrfDetection, SsrfAction};
+use crate::interface::{Action, Tags};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
+use crate::utils::{env_or, RequestInfo};
+use lazy_static::lazy_static;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// the number of host names resolved for a single request
+const MAX_RESOLVED: usize = 4;
+/// the cache is emptied when it still reaches this size once its expired entries are purged
+const MAX_CACHED: usize = 100_000;
+
+lazy_static! {
+    static ref DNS_TIMEOUT: Duration = Duration::from_millis(env_or("CURIEFENSE_SSRF_DNS_TIMEOUT_MS", 200));
+    /// the range of the first internal address of each host name, if any
+    static ref RESOLVED: Cache<String, Option<&'static str>> =
+        Cache::ttl_only(Duration::from_secs(env_or("CURIEFENSE_SSRF_DNS_CACHE_SECS", 300)), MAX_CACHED);
+}
+
+/// the hosts of a URL valued argument, lowercased
+///
+/// the browsers handle the backslashes as slashes, and most HTTP libraries as part of the user info: both hosts are
+/// returned when they differ
+pub fn url_hosts(value: &str) -> Vec<String> {
+    let value = value.trim();
+    let rest = match value.find("://") {
+        Some(idx)
+            if idx > 0
+                && value[..idx]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.') =>
+        {
+            &value[idx + 3..]
+        }
+        _ => match value.strip_prefix("//") {
+            Some(r) => r,
+            None => return Vec::new(),
+        },
+    };
+    let mut out: Vec<String> = Vec::new();
+    for backslash_delimits in &[true, false] {
+        let authority = rest
+            .split(|c| matches!(c, '/' | '?' | '#') || (*backslash_delimits && c == '\\'))
+            .next()
+            .unwrap_or("");
+        let hostport = authority.rsplit('@').next().unwrap_or("");
+        let host = match hostport.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next().unwrap_or(""),
+            None => hostport.split(':').next().unwrap_or(""),
+        };
+        let host = host.trim_end_matches('.').to_lowercase();
+        if !host.is_empty() && !out.contains(&host) {
+            out.push(host);
+        }
+    }
+    out
+}
+
+/// parses an IPv4 address like `inet_aton`: one to four decimal, octal (`0` prefix) or hexadecimal (`0x` prefix)
+/// parts, the last one filling the remaining bytes
+pub fn parse_ipv4(host: &str) -> Option<Ipv4Addr> {
+    let parts: Vec<&str> = host.split('.').collect();
+    if parts.is_empty() || parts.len() > 4 {
+        return None;
+    }
+    let numbers: Vec<u64> = parts
+        .iter()
+        .map(|p| {
+            if let Some(hex) = p.strip_prefix("0x").or_else(|| p.strip_prefix("0X")) {
+                if hex.is_empty() {
+                    Some(0)
+                } else {
+                    u64::from_str_radix(hex, 16).ok()
+                }
+            } else if p.len() > 1 && p.starts_with('0') {
+                u64::from_str_radix(&p[1..], 8).ok()
+            } else if !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()) {
+                p.parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect::<Option<Vec<u64>>>()?;
+    let (last, leading) = numbers.split_last()?;
+    if leading.iter().any(|n| *n > 255) || *last >= 1 << (8 * (5 - numbers.len())) {
+        return None;
+    }
+    let address = leading
+        .iter()
+        .enumerate()
+        .fold(*last, |acc, (idx, n)| acc | (n << (8 * (3 - idx))));
+    Some(Ipv4Addr::from(address as u32))
+}
+
+/// the internal range of an address
+pub fn internal_range(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(a) => {
+            let o = a.octets();
+            match o {
+                [169, 254, 169, 254] | [100, 100, 100, 200] => Some("metadata"),
+                [127, ..] => Some("loopback"),
+                [0, ..] => Some("unspecified"),
+                [169, 254, ..] => Some("link-local"),
+                [10, ..] | [192, 168, ..] => Some("private"),
+                [172, b, ..] if (16..32).contains(&b) => Some("private"),
+                // shared address space, used by the carrier grade NATs and some cloud networks
+                [100, b, ..] if (64..128).contains(&b) => Some("private"),
+                _ => None,
+            }
+        }
+        IpAddr::V6(a) => {
+            if let Some(v4) = to_ipv4_mapped(&a) {
+                return internal_range(IpAddr::V4(v4));
+            }
+            let s = a.segments();
+            if a == Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254) {
+                Some("metadata")
+            } else if a.is_loopback() {
+                Some("loopback")
+            } else if a.is_unspecified() {
+                Some("unspecified")
+            } else if s[0] & 0xffc0 == 0xfe80 {
+                Some("link-local")
+            } else if s[0] & 0xfe00 == 0xfc00 {
+                Some("private")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn to_ipv4_mapped(a: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match a.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::from(((hi as u32) << 16) | lo as u32)),
+        _ => None,
+    }
+}
+
+/// the internal range of a host, without resolving it
+pub fn host_range(host: &str) -> Option<&'static str> {
+    if host == "localhost" || host.ends_with(".localhost") {
+        return Some("loopback");
+    }
+    if host == "metadata.google.internal" {
+        return Some("metadata");
+    }
+    if let Ok(a) = host.parse::<Ipv6Addr>() {
+        return internal_range(IpAddr::V6(a));
+    }
+    parse_ipv4(host).and_then(|a| internal_range(IpAddr::V4(a)))
+}
+
+/// a host name, that is not an address
+fn is_name(host: &str) -> bool {
+    host.parse::<Ipv6Addr>().is_err() && parse_ipv4(host).is_none()
+}
+
+async fn resolved_range(logs: &mut Logs, host: &str) -> Option<&'static str> {
+    let now = Instant::now();
+    if let Some(range) = RESOLVED.get(host, now) {
+        return range;
+    }
+    let qhost = host.to_string();
+    let lookup = async move {
+        async_std::net::ToSocketAddrs::to_socket_addrs(&(qhost.as_str(), 0))
+            .await
+            .map(|addrs| addrs.map(|a| a.ip()).collect::<Vec<_>>())
+    };
+    let range = match backend::call(*DNS_TIMEOUT, lookup).await {
+        Ok(Ok(addrs)) => addrs.into_iter().find_map(internal_range),
+        Ok(Err(_)) => None,
+        Err(rr) => {
+            logs.warning(|| format!("SSRF lookup of {} failed: {}", host, rr));
+            None
+        }
+    };
+    RESOLVED.insert(host.to_string(), range, now);
+    range
+}
+
+/// an argument targeting an internal address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InternalTarget {
+    pub arg: String,
+    pub host: String,
+    pub range: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct SsrfDetection {
+    /// the inspected arguments, all of them when empty
+    args: Vec<String>,
+    resolve: bool,
+    action: SsrfAction,
+}
+
+impl SsrfDetection {
+    pub fn resolve(raw: &RawSsrfDetection) -> Self {
+        SsrfDetection {
+            args: raw.args.clone(),
+            resolve: raw.resolve,
+            action: raw.action,
+        }
+    }
+
+    /// the URL valued arguments of the request, with their host
+    fn hosts<'a>(&'a self, reqinfo: &'a RequestInfo) -> impl Iterator<Item = (&'a str, String)> + 'a {
+        reqinfo
+            .rinfo
+            .qinfo
+            .args
+            .iter()
+            .filter(move |(name, _)| self.args.is_empty() || self.args.iter().any(|a| a == name))
+            .flat_map(|(name, value)| url_hosts(value).into_iter().map(move |host| (name, host)))
+    }
+
+    /// the arguments targeting an internal address
+    pub async fn targets(&self, logs: &mut Logs, reqinfo: &RequestInfo) -> Vec<InternalTarget> {
+        let mut out = Vec::new();
+        let mut resolved = 0;
+        for (arg, host) in self.hosts(reqinfo) {
+            let range = match host_range(&host) {
+                Some(r) => Some(r),
+                None if self.resolve && is_name(&host) && resolved < MAX_RESOLVED => {
+                    resolved += 1;
+                    resolved_range(logs, &host).await
+                }
+                None => None,
+            };
+            if let Some(range) = range {
+                out.push(InternalTarget {
+                    arg: arg.to_string(),
+                    host,
+                    range,
+                });
+            }
+        }
+        out
+    }
+
+    /// tags the internal targets of the request, returning the blocking action when there are some
+    pub async fn check(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Action> {
+        let targets = self.targets(logs, reqinfo).await;
+        let first = targets.first()?;
+        tags.insert_qualified("ssrf", "internal-target");
+        for target in &targets {
+            tags.insert_qualified("ssrf-range", target.range);
+        }
+        if self.action == SsrfAction::Tag {
+            return None;
+        }
+        Some(Action {
+            status: 403,
+            reason: Reason::new(Initiator::Ssrf).with_message(format!(
+                "argument {} targets the {} address {}",
+                first.arg, first.range, first.host
+            )),
+            ..Action::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::RequestBuilder;
+
+    #[test]
+    fn hosts() {
+        let hosts = |v: &str| url_hosts(v);
+        assert_eq!(hosts("https://user:pw@Example.COM.:8443/a?b"), vec!["example.com"]);
+        assert_eq!(hosts("//10.0.0.1/x"), vec!["10.0.0.1"]);
+        assert_eq!(hosts("http://[::1]:80/"), vec!["::1"]);
+        assert_eq!(hosts("http://evil.com\\@127.0.0.1/"), vec!["evil.com", "127.0.0.1"]);
+        assert!(hosts("not a url").is_empty());
+        assert!(hosts("a b://host").is_empty());
+    }
+
+    #[test]
+    fn address_math() {
+        let range = |h: &str| host_range(h);
+        assert_eq!(range("127.0.0.1"), Some("loopback"));
+        assert_eq!(range("2130706433"), Some("loopback"));
+        assert_eq!(range("0x7f.1"), Some("loopback"));
+        assert_eq!(range("0177.0.0.1"), Some("loopback"));
+        assert_eq!(range("169.254.169.254"), Some("metadata"));
+        assert_eq!(range("0xa9fea9fe"), Some("metadata"));
+        assert_eq!(range("::ffff:169.254.169.254"), Some("metadata"));
+        assert_eq!(range("172.31.0.1"), Some("private"));
+        assert_eq!(range("172.32.0.1"), None);
+        assert_eq!(range("fe80::1"), Some("link-local"));
+        assert_eq!(range("fd12::1"), Some("private"));
+        assert_eq!(range("localhost"), Some("loopback"));
+        assert_eq!(range("8.8.8.8"), None);
+        assert_eq!(range("1.256.1"), None);
+        assert_eq!(parse_ipv4("10.1"), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(parse_ipv4("256.1.1.1"), None);
+        assert_eq!(parse_ipv4("example.com"), None);
+    }
+
+    #[test]
+    fn internal_targets() {
+        let raw: RawSsrfDetection = serde_json::from_str(r#"{"action": "block"}"#).unwrap();
+        let detection = SsrfDetection::resolve(&raw);
+        let rq = RequestBuilder::get("/fetch?next=/home&url=http%3A%2F%2F2130706433%2Fadmin").request_info();
+        let mut tags = Tags::default();
+        let action = async_std::task::block_on(detection.check(&mut Logs::default(), &rq, &mut tags)).unwrap();
+        assert_eq!(action.reason.initiator, Initiator::Ssrf);
+        assert!(tags.contains("ssrf:internal-target"));
+        assert!(tags.contains("ssrf-range:loopback"));
+
+        let rq = RequestBuilder::get("/fetch?url=https%3A%2F%2F8.8.8.8%2F").request_info();
+        let mut tags = Tags::default();
+        assert!(async_std::task::block_on(detection.check(&mut Logs::default(), &rq, &mut tags)).is_none());
+        assert!(!tags.contains("ssrf:internal-target"));
+
+        // tag only, on the configured arguments
+        let raw: RawSsrfDetection = serde_json::from_str(r#"{"args": ["url"]}"#).unwrap();
+        let detection = SsrfDetection::resolve(&raw);
+        let rq = RequestBuilder::get("/fetch?url=http%3A%2F%2F10.0.0.1&other=http%3A%2F%2F[::1]").request_info();
+        let targets = async_std::task::block_on(detection.targets(&mut Logs::default(), &rq));
+        assert_eq!(
+            targets,
+            vec![InternalTarget {
+                arg: "url".to_string(),
+                host: "10.0.0.1".to_string(),
+                range: "private"
+            }]
+        );
+        let mut tags = Tags::default();
+        assert!(async_std::task::block_on(detection.check(&mut Logs::default(), &rq, &mut tags)).is_none());
+        assert!(tags.contains("ssrf:internal-target"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::metrics::{Observation, Trigger};
+use lazy_static::lazy_static;
+use std::net::UdpSocket;
+
+/// keeps the datagrams under the usual MTU
+const MAX_PACKET_SIZE: usize = 1432;
+
+lazy_static! {
+    static ref CLIENT: Option<Client> = Client::from_env();
+}
+
+struct Client {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+impl Client {
+    fn from_env() -> Option<Self> {
+        let addr = std::env::var("CURIEFENSE_STATSD_ADDR").ok().filter(|a| !a.is_empty())?;
+        let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|s| s.connect(&addr).map(|_| s)) {
+            Ok(s) => s,
+            Err(rr) => {
+                tracing::error!("could not connect to the statsd agent at {}: {}", addr, rr);
+                return None;
+            }
+        };
+        if let Err(rr) = socket.set_nonblocking(true) {
+            tracing::warn!("could not make the statsd socket non blocking: {}", rr);
+        }
+        let prefix = std::env::var("CURIEFENSE_STATSD_PREFIX").unwrap_or_else(|_| "curiefense".to_string());
+        let tags = std::env::var("CURIEFENSE_STATSD_TAGS")
+            .map(|t| parse_tags(&t))
+            .unwrap_or_default();
+        Some(Client { socket, prefix, tags })
+    }
+
+    fn send(&self, lines: &[String]) {
+        for packet in packets(lines) {
+            // the metrics are lost when the agent does not keep up
+            let _ = self.socket.send(packet.as_bytes());
+        }
+    }
+}
+
+fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(sanitize)
+        .collect()
+}
+
+/// the separators of the format can not appear in the names and tags
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if matches!(c, '|' | ',' | '#' | '\n') { '_' } else { c })
+        .collect()
+}
+
+/// a metric line, such as `curiefense.blocks:1|c|#initiator:acl`
+fn line(prefix: &str, name: &str, value: &str, kind: &str, constant_tags: &[String], tags: &[(&str, &str)]) -> String {
+    let mut out = if prefix.is_empty() {
+        format!("{}:{}|{}", name, value, kind)
+    } else {
+        format!("{}.{}:{}|{}", prefix, name, value, kind)
+    };
+    let all_tags: Vec<String> = constant_tags
+        .iter()
+        .cloned()
+        .chain(tags.iter().map(|(k, v)| format!("{}:{}", k, sanitize(v))))
+        .collect();
+    if !all_tags.is_empty() {
+        out += "|#";
+        out += &all_tags.join(",");
+    }
+    out
+}
+
+/// joins the lines in newline separated datagrams, of at most `MAX_PACKET_SIZE` bytes unless a line is larger
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for l in lines {
+        if !current.is_empty() && current.len() + 1 + l.len() > MAX_PACKET_SIZE {
+            out.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current += l;
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+fn inspection_lines(prefix: &str, constant_tags: &[String], observation: &Observation) -> Vec<String> {
+    let counter = |name: &str, tags: &[(&str, &str)]| line(prefix, name, "1", "c", constant_tags, tags);
+    let timing = |name: &str, micros: u64, tags: &[(&str, &str)]| {
+        line(
+            prefix,
+            name,
+            &(micros as f64 / 1000.0).to_string(),
+            "ms",
+            constant_tags,
+            tags,
+        )
+    };
+    let mut lines = vec![
+        counter("requests", &[]),
+        counter(
+            "decisions",
+            &[("action", &observation.action), ("initiator", observation.initiator)],
+        ),
+    ];
+    if observation.blocked {
+        lines.push(counter("blocks", &[("initiator", observation.initiator)]));
+    }
+    for (trigger, label) in &observation.triggers {
+        lines.push(match trigger {
+            Trigger::Challenge => counter("challenges", &[("kind", label)]),
+            Trigger::Limit => counter("limit_triggers", &[("limit", label)]),
+            Trigger::Signature => counter("content_filter_hits", &[("rule_id", label)]),
+        });
+    }
+    for (phase, micros) in &observation.phases {
+        lines.push(timing("phase_duration", *micros, &[("phase", phase)]));
+    }
+    lines.push(timing("inspection_duration", observation.elapsed_micros, &[]));
+    lines
+}
+
+/// sends the metrics of an inspection, when an agent is configured
+pub(crate) fn inspection(observation: &Observation) {
+    if let Some(client) = CLIENT.as_ref() {
+        client.send(&inspection_lines(&client.prefix, &client.tags, observation));
+    }
+}
+
+/// increments a counter, when an agent is configured
+pub fn increment(name: &str, tags: &[(&str, &str)]) {
+    if let Some(client) = CLIENT.as_ref() {
+        client.send(&[line(&client.prefix, name, "1", "c", &client.tags, tags)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        let tags = parse_tags("env:prod, service:edge,,");
+        assert_eq!(tags, vec!["env:prod", "service:edge"]);
+        let observation = Observation {
+            action: "block".to_string(),
+            initiator: "limit",
+            blocked: true,
+            triggers: vec![(Trigger::Limit, "too|many,req".to_string())],
+            phases: vec![("mapping".to_string(), 1500)],
+            elapsed_micros: 2000,
+        };
+        assert_eq!(
+            inspection_lines("cf", &tags, &observation),
+            vec![
+                "cf.requests:1|c|#env:prod,service:edge",
+                "cf.decisions:1|c|#env:prod,service:edge,action:block,initiator:limit",
+                "cf.blocks:1|c|#env:prod,service:edge,initiator:limit",
+                "cf.limit_triggers:1|c|#env:prod,service:edge,limit:too_many_req",
+                "cf.phase_duration:1.5|ms|#env:prod,service:edge,phase:mapping",
+                "cf.inspection_duration:2|ms|#env:prod,service:edge",
+            ]
+        );
+        assert_eq!(line("", "errors", "1", "c", &[], &[]), "errors:1|c");
+    }
+
+    #[test]
+    fn split_packets() {
+        let lines: Vec<String> = (0..100).map(|i| format!("curiefense.requests:{}|c", i)).collect();
+        let packets = packets(&lines);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_SIZE));
+        assert_eq!(packets.join("\n"), lines.join("\n"));
+        assert!(super::packets(&[]).is_empty());
+    }
+}
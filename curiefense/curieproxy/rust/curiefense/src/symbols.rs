@@ -0,0 +1,144 @@
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref HASHER: RandomState = RandomState::new();
+    /// the interned names, with their hash
+    static ref SYMBOLS: ArcSwap<HashMap<&'static str, u64>> = ArcSwap::from_pointee(HashMap::new());
+    /// serializes the writers of `SYMBOLS`
+    static ref INTERNING: Mutex<()> = Mutex::new(());
+}
+
+fn hash_name(name: &str) -> u64 {
+    HASHER.hash_one(name)
+}
+
+/// interns these names, so that the tags created afterwards with one of them share it
+pub fn intern<'a, I: IntoIterator<Item = &'a str>>(names: I) {
+    let _interning = match INTERNING.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let current = SYMBOLS.load();
+    let new: Vec<&str> = names.into_iter().filter(|n| !current.contains_key(n)).collect();
+    if new.is_empty() {
+        return;
+    }
+    let mut symbols = HashMap::clone(&current);
+    for name in new {
+        if !symbols.contains_key(name) {
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            symbols.insert(name, hash_name(name));
+        }
+    }
+    SYMBOLS.store(Arc::new(symbols));
+}
+
+#[derive(Debug, Clone)]
+enum Name {
+    Interned(&'static str),
+    Owned(Box<str>),
+}
+
+/// a tag name
+#[derive(Debug, Clone)]
+pub struct Tag {
+    hash: u64,
+    name: Name,
+}
+
+impl Tag {
+    /// the tag with this name, which is not tagified
+    pub fn new(name: Cow<str>) -> Self {
+        match SYMBOLS.load().get_key_value(name.as_ref()) {
+            Some((interned, hash)) => Tag {
+                hash: *hash,
+                name: Name::Interned(interned),
+            },
+            None => Tag {
+                hash: hash_name(&name),
+                name: Name::Owned(name.into_owned().into_boxed_str()),
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.name {
+            Name::Interned(name) => name,
+            Name::Owned(name) => name,
+        }
+    }
+
+    pub fn is_interned(&self) -> bool {
+        matches!(self.name, Name::Interned(_))
+    }
+}
+
+impl PartialEq for Tag {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+            && match (&self.name, &other.name) {
+                (Name::Interned(a), Name::Interned(b)) => std::ptr::eq(*a, *b),
+                _ => self.as_str() == other.as_str(),
+            }
+    }
+}
+
+impl Eq for Tag {}
+
+impl Hash for Tag {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash)
+    }
+}
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Tag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|s| Tag::new(Cow::Owned(s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn interning() {
+        let before = Tag::new(Cow::Borrowed("symbols-test:a"));
+        assert!(!before.is_interned());
+        intern(vec!["symbols-test:a", "symbols-test:b", "symbols-test:a"]);
+
+        let after = Tag::new(Cow::Borrowed("symbols-test:a"));
+        assert!(after.is_interned());
+        assert_eq!(after.as_str(), "symbols-test:a");
+        // tags created before the name was interned are still equal
+        assert_eq!(before, after);
+        assert_ne!(after, Tag::new(Cow::Borrowed("symbols-test:b")));
+
+        let set: HashSet<Tag> = vec![before].into_iter().collect();
+        assert!(set.contains(&after));
+        assert_eq!(
+            serde_json::from_str::<Tag>(&serde_json::to_string(&after).unwrap()).unwrap(),
+            after
+        );
+    }
+}
@@ -3,8 +3,10 @@ use crate::config::globalfilter::{
 };
 use crate::config::raw::Relation;
 use crate::interface::{SimpleActionT, SimpleDecision, Tags};
+use crate::logs::Logs;
+use crate::reason::{Initiator, Reason};
 use crate::requestfields::RequestField;
-use crate::utils::RequestInfo;
+use crate::utils::{is_sensitive_trailer, RequestInfo};
 use std::net::IpAddr;
 
 fn check_relation<A, F>(rinfo: &RequestInfo, rel: Relation, elems: &[A], checker: F) -> bool
@@ -64,6 +66,26 @@ fn check_entry(rinfo: &RequestInfo, sub: &GlobalFilterEntry) -> bool {
             .map(|ccty| check_single(cty, ccty.to_lowercase().as_ref()))
             .unwrap_or(false),
         GlobalFilterEntryE::Method(mtd) => check_single(mtd, &rinfo.rinfo.meta.method),
+        GlobalFilterEntryE::Scheme(sch) => rinfo
+            .rinfo
+            .meta
+            .scheme
+            .as_ref()
+            .map(|s| check_single(sch, s))
+            .unwrap_or(false),
+        GlobalFilterEntryE::Port(prt) => rinfo
+            .rinfo
+            .meta
+            .port
+            .map(|p| check_single(prt, &p.to_string()))
+            .unwrap_or(false),
+        GlobalFilterEntryE::HttpVersion(ver) => rinfo
+            .rinfo
+            .meta
+            .http_version
+            .as_ref()
+            .map(|v| check_single(ver, v))
+            .unwrap_or(false),
         GlobalFilterEntryE::Header(hdr) => check_pair(hdr, &rinfo.headers),
         GlobalFilterEntryE::Args(arg) => check_pair(arg, &rinfo.rinfo.qinfo.args),
         GlobalFilterEntryE::Cookies(arg) => check_pair(arg, &rinfo.cookies),
@@ -76,6 +98,8 @@ fn check_entry(rinfo: &RequestInfo, sub: &GlobalFilterEntry) -> bool {
             .map(|ccmp| check_single(cmp, ccmp.as_str()))
             .unwrap_or(false),
         GlobalFilterEntryE::Authority(at) => check_single(at, &rinfo.rinfo.host),
+        GlobalFilterEntryE::Ja3(ja3) => rinfo.tls.ja3.as_ref().map(|v| check_single(ja3, v)).unwrap_or(false),
+        GlobalFilterEntryE::Ja4(ja4) => rinfo.tls.ja4.as_ref().map(|v| check_single(ja4, v)).unwrap_or(false),
     };
     c ^ sub.negated
 }
@@ -85,6 +109,7 @@ fn check_subsection(rinfo: &RequestInfo, sub: &GlobalFilterSSection) -> bool {
 }
 
 pub fn tag_request(
+    logs: &mut Logs,
     is_human: bool,
     globalfilters: &[GlobalFilterSection],
     rinfo: &RequestInfo,
@@ -96,6 +121,79 @@ pub fn tag_request(
         tags.insert("bot");
     }
     tags.insert_qualified("ip", &rinfo.rinfo.geoip.ipstr);
+    tags.insert_qualified("fingerprint", &rinfo.fingerprint);
+    if rinfo.parse_overflow() {
+        tags.insert("parse:overflow");
+    }
+    if let Some(cert) = &rinfo.client_cert {
+        tags.insert(if cert.verified {
+            "mtls:verified"
+        } else {
+            "mtls:unverified"
+        });
+        if let Some(fp) = &cert.fingerprint {
+            tags.insert_qualified("cert-fingerprint", fp);
+        }
+        if let Some(subject) = &cert.subject {
+            tags.insert_qualified("cert-subject", subject);
+        }
+        for san in &cert.san {
+            tags.insert_qualified("cert-san", san);
+        }
+    }
+    if let Some(jwt) = &rinfo.jwt {
+        tags.insert(if jwt.valid { "jwt:valid" } else { "jwt:invalid" });
+        if jwt.expired {
+            tags.insert("jwt:expired");
+        }
+        for (claim, value) in &jwt.tags {
+            tags.insert_qualified(&format!("jwt:{}", claim), value);
+        }
+    }
+    if let Some(signature) = &rinfo.signature {
+        tags.insert_qualified("signature", signature.status.as_str());
+        if let Some(kid) = &signature.key_id {
+            tags.insert_qualified("signature-key", kid);
+        }
+    }
+    if let Some(openapi) = &rinfo.openapi {
+        if openapi.violations.is_empty() {
+            tags.insert("openapi:valid");
+        }
+        for violation in &openapi.violations {
+            tags.insert_qualified("openapi", violation.kind.as_str());
+        }
+        if let Some(operation) = &openapi.operation {
+            tags.insert_qualified("openapi-operation", operation);
+        }
+    }
+    if let Some(status) = rinfo.csrf.as_ref().and_then(|c| c.status) {
+        tags.insert_qualified("csrf", status.as_str());
+    }
+    if let Some(integrity) = &rinfo.cookies_integrity {
+        for name in &integrity.tampered {
+            tags.insert_qualified("cookie-tampered", name);
+        }
+        if !integrity.valid.is_empty() && integrity.tampered.is_empty() {
+            tags.insert("cookie-signature:valid");
+        }
+    }
+    let meta = &rinfo.rinfo.meta;
+    let mut smuggled: Vec<&String> = meta.trailers.keys().filter(|k| is_sensitive_trailer(k)).collect();
+    if !smuggled.is_empty() {
+        smuggled.sort();
+        tags.insert("trailer-smuggling");
+        for name in smuggled {
+            tags.insert_qualified("trailer-smuggling", name);
+        }
+    }
+    if !meta.chunk_extensions.is_empty() {
+        tags.insert("chunk-extensions");
+    }
+    tags.insert_qualified("bot-score", rinfo.bot.level());
+    for signal in &rinfo.bot.signals {
+        tags.insert_qualified("bot-signal", signal);
+    }
     tags.insert_qualified(
         "geo-continent-name",
         rinfo.rinfo.geoip.continent_name.as_deref().unwrap_or("nil"),
@@ -111,6 +209,17 @@ pub fn tag_request(
     );
     tags.insert_qualified("geo-region", rinfo.rinfo.geoip.region.as_deref().unwrap_or("nil"));
     tags.insert_qualified("geo-subregion", rinfo.rinfo.geoip.subregion.as_deref().unwrap_or("nil"));
+    let anonymous = &rinfo.rinfo.geoip.anonymous;
+    for (flag, tag) in [
+        (anonymous.is_anonymous_vpn, "anon:vpn"),
+        (anonymous.is_tor_exit_node, "anon:tor"),
+        (anonymous.is_hosting_provider, "anon:hosting"),
+        (anonymous.is_public_proxy, "anon:proxy"),
+    ] {
+        if flag == Some(true) {
+            tags.insert(tag);
+        }
+    }
     match rinfo.rinfo.geoip.asn {
         None => {
             tags.insert_qualified("geo-asn", "nil");
@@ -121,17 +230,26 @@ pub fn tag_request(
         }
     }
     for psection in globalfilters {
-        if check_relation(rinfo, psection.relation, &psection.sections, check_subsection) {
+        let matched = check_relation(rinfo, psection.relation, &psection.sections, check_subsection);
+        logs.explain(
+            "tagging",
+            &psection.name,
+            matched,
+            || serde_json::json!({ "id": psection.id, "tags": psection.tags }),
+        );
+        if matched {
             tags.extend(psection.tags.clone());
             if let Some(a) = &psection.action {
-                if a.atype == SimpleActionT::Monitor || (a.atype == SimpleActionT::Challenge && is_human) {
+                if a.atype == SimpleActionT::Monitor
+                    || (matches!(a.atype, SimpleActionT::Challenge | SimpleActionT::Captcha) && is_human)
+                {
                     continue;
                 }
                 return (
                     tags,
                     SimpleDecision::Action(
                         a.clone(),
-                        serde_json::json!({"initiator": "tag action", "tags": psection.tags}),
+                        Reason::new(Initiator::TagAction).with_tags(psection.tags.iter()),
                     ),
                 );
             }
@@ -144,7 +262,9 @@ pub fn tag_request(
 mod tests {
     use super::*;
     use crate::config::globalfilter::optimize_ipranges;
+    use crate::config::raw::ParseBudget;
     use crate::logs::Logs;
+    use crate::test_utils::RequestBuilder;
     use crate::utils::map_request;
     use crate::utils::RawRequest;
     use crate::utils::RequestMeta;
@@ -184,9 +304,13 @@ mod tests {
             &[],
             &[],
             500,
+            &[],
+            &ParseBudget::default(),
+            false,
             &RawRequest {
                 ipstr: "52.78.12.56".to_string(),
                 headers,
+                header_bytes: HashMap::new(),
                 meta,
                 mbody: None,
             },
@@ -258,6 +382,18 @@ mod tests {
         assert!(r);
     }
 
+    #[test]
+    fn check_ja3() {
+        let mut ri = mk_rinfo();
+        let entry = GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::Ja3(single_re("e7d705a3286e19ea42f587b344ee6865")),
+        };
+        assert!(!check_entry(&ri, &entry));
+        ri.tls.ja3 = Some("e7d705a3286e19ea42f587b344ee6865".to_string());
+        assert!(check_entry(&ri, &entry));
+    }
+
     fn mk_globalfilterentries(lst: &[&str]) -> Vec<GlobalFilterEntry> {
         lst.iter()
             .map(|e| match e.strip_prefix('!') {
@@ -355,4 +491,40 @@ mod tests {
         ];
         check_iprange(Relation::Or, &entries, &samples);
     }
+
+    #[test]
+    fn anonymous_tags() {
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.geoip.anonymous.is_anonymous_vpn = Some(true);
+        rinfo.rinfo.geoip.anonymous.is_hosting_provider = Some(false);
+        rinfo.rinfo.geoip.anonymous.is_tor_exit_node = Some(true);
+        let (tags, _) = tag_request(&mut Logs::default(), false, &[], &rinfo);
+        assert!(tags.contains("anon:vpn"));
+        assert!(tags.contains("anon:tor"));
+        assert!(!tags.contains("anon:hosting"));
+        assert!(!tags.contains("anon:proxy"));
+
+        let exits = crate::tor::parse("# exits\n185.220.101.1\n2001:db8::/64\n").unwrap();
+        assert!(exits.contains("185.220.101.1".parse().unwrap()));
+        assert!(exits.contains("2001:db8::5".parse().unwrap()));
+        assert!(!exits.contains("185.220.101.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn trailer_tags() {
+        let rinfo = RequestBuilder::post("/upload", "a=1")
+            .meta("trailer_Authorization", "Basic YWRtaW46YWRtaW4=")
+            .meta("trailer_x-checksum", "1234")
+            .meta("chunk_extensions", ";name=value")
+            .request_info();
+        let (tags, _) = tag_request(&mut Logs::default(), false, &[], &rinfo);
+        assert!(tags.contains("trailer-smuggling"));
+        assert!(tags.contains("trailer-smuggling:authorization"));
+        assert!(!tags.contains("trailer-smuggling:x-checksum"));
+        assert!(tags.contains("chunk-extensions"));
+
+        let (tags, _) = tag_request(&mut Logs::default(), false, &[], &mk_rinfo());
+        assert!(!tags.contains("trailer-smuggling"));
+        assert!(!tags.contains("chunk-extensions"));
+    }
 }
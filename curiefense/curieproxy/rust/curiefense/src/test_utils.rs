@@ -0,0 +1,549 @@
+use crate::config::contentfilter::{resolve_rules, ContentFilterProfile, Transformation};
+use crate::config::globalfilter::GlobalFilterSection;
+use crate::config::hostmap::{ChallengePolicy, HostMap, RequestLineConditions, SecurityPolicy};
+use crate::config::limit::Limit;
+use crate::config::raw::{AclProfile, ContentFilterGroup, ContentFilterRule, ContentType, ParseBudget};
+use crate::config::utils::{Matching, MatchingSet};
+use crate::config::{Config, ConfigSnapshot};
+use crate::grasshopper::Grasshopper;
+use crate::interface::{Decision, Tags};
+use crate::logs::Logs;
+use crate::utils::{map_request, map_request_with, RawRequest, RequestInfo, RequestMeta};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub use crate::grasshopper::MockGrasshopper;
+
+fn tag_set(tags: &[&str]) -> HashSet<String> {
+    tags.iter().map(|t| t.to_string()).collect()
+}
+
+/// a request, `GET /` from `1.2.3.4` by default
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    ip: String,
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Option<Vec<u8>>,
+}
+
+impl Default for RequestBuilder {
+    fn default() -> Self {
+        RequestBuilder::new("GET", "/")
+    }
+}
+
+impl RequestBuilder {
+    pub fn new(method: &str, path: &str) -> Self {
+        let mut meta = HashMap::new();
+        meta.insert("method".to_string(), method.to_string());
+        meta.insert("path".to_string(), path.to_string());
+        RequestBuilder {
+            ip: "1.2.3.4".to_string(),
+            meta,
+            headers: HashMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(path: &str) -> Self {
+        RequestBuilder::new("GET", path)
+    }
+
+    pub fn post(path: &str, body: impl Into<Vec<u8>>) -> Self {
+        RequestBuilder::new("POST", path).body(body)
+    }
+
+    pub fn ip(mut self, ip: &str) -> Self {
+        self.ip = ip.to_string();
+        self
+    }
+
+    pub fn host(self, host: &str) -> Self {
+        self.meta("authority", host)
+    }
+
+    /// sets a meta data entry, with the keys of the Lua API (`method`, `path`, `authority`, `config_path`...)
+    pub fn meta(mut self, key: &str, value: &str) -> Self {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// sets a header, whose name is lowercased
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_lowercase(), value.to_string());
+        self
+    }
+
+    /// adds a cookie to the `cookie` header
+    pub fn cookie(mut self, name: &str, value: &str) -> Self {
+        let cookie = format!("{}={}", name, value);
+        match self.headers.get_mut("cookie") {
+            Some(c) => *c = format!("{}; {}", c, cookie),
+            None => {
+                self.headers.insert("cookie".to_string(), cookie);
+            }
+        }
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// the raw request, panics when the meta data is invalid
+    pub fn raw(&self) -> RawRequest<'_> {
+        let meta = RequestMeta::from_map(self.meta.clone()).expect("invalid request meta data");
+        RawRequest {
+            ipstr: meta.client_ip(self.ip.clone()),
+            headers: self.headers.clone(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: self.body.as_deref(),
+        }
+    }
+
+    /// the request, mapped without a content filter profile
+    pub fn request_info(&self) -> RequestInfo {
+        map_request(
+            &mut Logs::default(),
+            &[],
+            &[],
+            500,
+            &[],
+            &ParseBudget::default(),
+            false,
+            &self.raw(),
+        )
+    }
+
+    /// the request, mapped with the content filter profile and the session selectors of the security policy
+    pub fn request_info_for(&self, policy: &SecurityPolicy) -> RequestInfo {
+        let profile = &policy.content_filter_profile;
+        map_request_with(
+            &mut Logs::default(),
+            &profile.decoding,
+            &profile.content_type,
+            profile.max_body_depth,
+            &policy.session,
+            &profile.parse_budget,
+            profile.nested_args,
+            &policy.normalization,
+            &self.raw(),
+        )
+    }
+}
+
+/// an ACL profile, without any rule by default
+#[derive(Debug, Clone)]
+pub struct AclBuilder(AclProfile);
+
+impl AclBuilder {
+    pub fn new(id: &str) -> Self {
+        AclBuilder(AclProfile {
+            id: id.to_string(),
+            name: id.to_string(),
+            ..AclProfile::default()
+        })
+    }
+
+    pub fn allow(mut self, tags: &[&str]) -> Self {
+        self.0.allow = tag_set(tags);
+        self
+    }
+
+    pub fn allow_bot(mut self, tags: &[&str]) -> Self {
+        self.0.allow_bot = tag_set(tags);
+        self
+    }
+
+    pub fn deny(mut self, tags: &[&str]) -> Self {
+        self.0.deny = tag_set(tags);
+        self
+    }
+
+    pub fn deny_bot(mut self, tags: &[&str]) -> Self {
+        self.0.deny_bot = tag_set(tags);
+        self
+    }
+
+    pub fn passthrough(mut self, tags: &[&str]) -> Self {
+        self.0.passthrough = tag_set(tags);
+        self
+    }
+
+    pub fn force_deny(mut self, tags: &[&str]) -> Self {
+        self.0.force_deny = tag_set(tags);
+        self
+    }
+
+    pub fn build(self) -> AclProfile {
+        self.0
+    }
+}
+
+/// a content filter profile, the default one by default, that does not enable any signature
+#[derive(Debug, Clone)]
+pub struct ContentFilterBuilder(ContentFilterProfile);
+
+impl ContentFilterBuilder {
+    pub fn new(id: &str) -> Self {
+        ContentFilterBuilder(ContentFilterProfile {
+            id: id.to_string(),
+            name: id.to_string(),
+            ..ContentFilterProfile::default_from_seed("seed")
+        })
+    }
+
+    /// the tags of the signatures that block the requests
+    pub fn active(mut self, tags: &[&str]) -> Self {
+        self.0.active = tag_set(tags);
+        self
+    }
+
+    /// the tags of the signatures that are only reported
+    pub fn report(mut self, tags: &[&str]) -> Self {
+        self.0.report = tag_set(tags);
+        self
+    }
+
+    pub fn ignore(mut self, tags: &[&str]) -> Self {
+        self.0.ignore = tag_set(tags);
+        self
+    }
+
+    pub fn ignore_alphanum(mut self, ignore: bool) -> Self {
+        self.0.ignore_alphanum = ignore;
+        self
+    }
+
+    /// the maximum number of entries of each section (headers, cookies, arguments and path parts)
+    pub fn max_count(mut self, count: usize) -> Self {
+        let sections = &mut self.0.sections;
+        for section in [
+            &mut sections.headers,
+            &mut sections.cookies,
+            &mut sections.args,
+            &mut sections.path,
+        ] {
+            section.max_count = count;
+        }
+        self
+    }
+
+    /// the maximum length of the entries of each section
+    pub fn max_length(mut self, length: usize) -> Self {
+        let sections = &mut self.0.sections;
+        for section in [
+            &mut sections.headers,
+            &mut sections.cookies,
+            &mut sections.args,
+            &mut sections.path,
+        ] {
+            section.max_length = length;
+        }
+        self
+    }
+
+    pub fn decoding(mut self, decoding: &[Transformation]) -> Self {
+        self.0.decoding = decoding.to_vec();
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &[ContentType]) -> Self {
+        self.0.content_type = content_type.to_vec();
+        self
+    }
+
+    pub fn build(self) -> ContentFilterProfile {
+        self.0
+    }
+}
+
+/// a content filter signature, in the `test` category
+///
+/// the profiles select it with its `cf-rule-id:<id>`, `cf-rule-risk:<risk>` or `cf-rule-category:test` tags
+pub fn signature(id: &str, operand: &str, risk: u8) -> ContentFilterRule {
+    ContentFilterRule {
+        id: id.to_string(),
+        operand: operand.to_string(),
+        risk,
+        category: "test".to_string(),
+        subcategory: "test".to_string(),
+        tags: HashSet::new(),
+    }
+}
+
+/// a security policy entry, with active empty profiles by default
+#[derive(Debug, Clone)]
+pub struct PolicyBuilder(SecurityPolicy);
+
+impl PolicyBuilder {
+    pub fn new(name: &str) -> Self {
+        PolicyBuilder(SecurityPolicy {
+            name: name.to_string(),
+            acl_active: true,
+            acl_profile: AclProfile::default(),
+            content_filter_active: true,
+            content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
+            limits: Vec::new(),
+            session: Vec::new(),
+            request_line: RequestLineConditions::default(),
+            template: None,
+            templates: Default::default(),
+            observe: false,
+            run_all_phases: false,
+            captcha: None,
+            risk: None,
+            campaigns: None,
+            harvesting: None,
+            jwt: None,
+            request_signature: None,
+            openapi: None,
+            honeypot: None,
+            mode: None,
+            quota: None,
+            normalization: None,
+            experiment: None,
+            login: None,
+            csrf: None,
+            cookie_signing: None,
+            ssrf: None,
+            allowed_methods: None,
+            challenge: ChallengePolicy::default(),
+            ban_on_decision: HashMap::new(),
+            block_responses: HashMap::new(),
+            json_errors: false,
+            explain: false,
+            explain_secret: None,
+        })
+    }
+
+    pub fn acl(mut self, profile: AclProfile) -> Self {
+        self.0.acl_profile = profile;
+        self
+    }
+
+    pub fn acl_active(mut self, active: bool) -> Self {
+        self.0.acl_active = active;
+        self
+    }
+
+    pub fn content_filter(mut self, profile: ContentFilterProfile) -> Self {
+        self.0.content_filter_profile = profile;
+        self
+    }
+
+    pub fn content_filter_active(mut self, active: bool) -> Self {
+        self.0.content_filter_active = active;
+        self
+    }
+
+    pub fn limits(mut self, limits: Vec<Limit>) -> Self {
+        self.0.limits = limits;
+        self
+    }
+
+    pub fn observe(mut self, observe: bool) -> Self {
+        self.0.observe = observe;
+        self
+    }
+
+    pub fn run_all_phases(mut self, run: bool) -> Self {
+        self.0.run_all_phases = run;
+        self
+    }
+
+    pub fn build(self) -> SecurityPolicy {
+        self.0
+    }
+}
+
+/// a configuration with a single host map, whose default entry is the `default` policy by default
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    default: Option<SecurityPolicy>,
+    entries: Vec<(String, SecurityPolicy)>,
+    globalfilters: Vec<GlobalFilterSection>,
+    signatures: Vec<ContentFilterRule>,
+    groups: Vec<ContentFilterGroup>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder {
+            default: Some(PolicyBuilder::new("default").build()),
+            entries: Vec::new(),
+            globalfilters: Vec::new(),
+            signatures: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// the policy of the paths that match no entry, `None` to let them pass without inspection
+    pub fn default_policy(mut self, policy: Option<SecurityPolicy>) -> Self {
+        self.default = policy;
+        self
+    }
+
+    /// adds an entry, for the paths that match the regular expression, the longest expressions being tried first
+    pub fn path(mut self, pattern: &str, policy: SecurityPolicy) -> Self {
+        self.entries.push((pattern.to_string(), policy));
+        self
+    }
+
+    pub fn global_filter(mut self, section: GlobalFilterSection) -> Self {
+        self.globalfilters.push(section);
+        self
+    }
+
+    pub fn signature(mut self, signature: ContentFilterRule) -> Self {
+        self.signatures.push(signature);
+        self
+    }
+
+    /// tags the signatures of a group with extra tags
+    pub fn signature_group(mut self, tags: &[&str], signatures: &[&str]) -> Self {
+        self.groups.push(ContentFilterGroup {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            signatures: signatures.iter().map(|s| s.to_string()).collect(),
+        });
+        self
+    }
+
+    fn policies(&self) -> impl Iterator<Item = &SecurityPolicy> {
+        self.default.iter().chain(self.entries.iter().map(|(_, p)| p))
+    }
+
+    pub fn build(&self) -> Config {
+        let mut entries: Vec<Matching<SecurityPolicy>> = self
+            .entries
+            .iter()
+            .map(|(pattern, policy)| Matching::from_str(pattern, policy.clone()).expect("invalid path pattern"))
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.matcher_len()));
+        let content_filter_profiles = self
+            .policies()
+            .map(|p| (p.content_filter_profile.id.clone(), p.content_filter_profile.clone()))
+            .collect();
+        Config {
+            default: Some(HostMap {
+                id: "__default__".to_string(),
+                name: "default".to_string(),
+                paths: MatchingSet::new(&entries).ok(),
+                normalization: None,
+                entries,
+                default: self.default.clone(),
+            }),
+            globalfilters: self.globalfilters.clone(),
+            content_filter_profiles,
+            ..Config::empty()
+        }
+    }
+
+    /// the configuration, with its content filter signatures
+    pub fn snapshot(&self) -> ConfigSnapshot {
+        let config = self.build();
+        let hsdb = resolve_rules(
+            &mut Logs::default(),
+            &config.content_filter_profiles,
+            self.signatures.clone(),
+            self.groups.clone(),
+        );
+        ConfigSnapshot {
+            config: Arc::new(config),
+            hsdb: Arc::new(hsdb),
+        }
+    }
+}
+
+/// inspects the request with the configuration, like `inspect_generic_request_map` does with a stored one
+pub fn inspect<GH: Grasshopper>(
+    snapshot: &ConfigSnapshot,
+    mgh: Option<GH>,
+    raw: &RawRequest,
+    logs: &mut Logs,
+) -> (Decision, Tags, RequestInfo) {
+    async_std::task::block_on(crate::inspect_with_config(snapshot, mgh, raw, logs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::reason::Initiator;
+
+    fn initiator(decision: &Decision) -> Option<Initiator> {
+        match decision {
+            Decision::Pass => None,
+            Decision::Action(a) => Some(a.reason.initiator),
+        }
+    }
+
+    #[test]
+    fn scenario() {
+        let snapshot = ConfigBuilder::new()
+            .path(
+                "^/admin",
+                PolicyBuilder::new("admin")
+                    .acl(AclBuilder::new("admin").deny(&["all"]).allow(&["ip:10-0-0-1"]).build())
+                    .build(),
+            )
+            .path(
+                "^/api",
+                PolicyBuilder::new("api")
+                    .content_filter(
+                        ContentFilterBuilder::new("api")
+                            .active(&["cf-rule-category:test"])
+                            .build(),
+                    )
+                    .build(),
+            )
+            .signature(signature("100", "xp_cmdshell", 5))
+            .snapshot();
+        let inspect = |rq: RequestBuilder| {
+            let raw = rq.raw();
+            let (decision, tags, _) = inspect(&snapshot, None::<DummyGrasshopper>, &raw, &mut Logs::default());
+            (initiator(&decision), tags)
+        };
+
+        let (init, tags) = inspect(RequestBuilder::get("/"));
+        assert_eq!(init, None);
+        assert!(tags.contains("securitypolicy-entry:default"));
+        assert_eq!(inspect(RequestBuilder::get("/admin/users")).0, Some(Initiator::Acl));
+        assert_eq!(inspect(RequestBuilder::get("/admin/users").ip("10.0.0.1")).0, None);
+        assert_eq!(
+            inspect(RequestBuilder::get("/api?q=xp_cmdshell")).0,
+            Some(Initiator::ContentFilter)
+        );
+        assert_eq!(
+            inspect(
+                RequestBuilder::post("/api", "q=xp_cmdshell")
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+            )
+            .0,
+            Some(Initiator::ContentFilter)
+        );
+        assert_eq!(inspect(RequestBuilder::get("/?q=xp_cmdshell")).0, None);
+    }
+
+    #[test]
+    fn request_builder() {
+        let rq = RequestBuilder::get("/a?b=c")
+            .host("example.com")
+            .cookie("d", "e")
+            .cookie("f", "g")
+            .request_info();
+        assert_eq!(rq.rinfo.host, "example.com");
+        assert_eq!(rq.rinfo.qinfo.qpath, "/a");
+        assert_eq!(rq.rinfo.qinfo.args.get_str("b"), Some("c"));
+        assert_eq!(rq.cookies.get_str("f"), Some("g"));
+    }
+}
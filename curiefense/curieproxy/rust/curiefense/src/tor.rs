@@ -0,0 +1,65 @@
+use crate::ipset::IpSet;
+use crate::utils::env_or;
+use lazy_static::lazy_static;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+lazy_static! {
+    static ref EXITS: Option<RwLock<IpSet<()>>> = start();
+}
+
+fn start() -> Option<RwLock<IpSet<()>>> {
+    let url = std::env::var("CURIEFENSE_TOR_EXIT_LIST_URL")
+        .ok()
+        .filter(|s| !s.is_empty())?;
+    let refresh = Duration::from_secs(env_or("CURIEFENSE_TOR_EXIT_REFRESH_SECS", 3600).max(60));
+    if let Err(rr) = std::thread::Builder::new()
+        .name("curiefense-tor".to_string())
+        .spawn(move || run(&url, refresh))
+    {
+        tracing::error!(target: "curiefense::tor", "could not start the Tor exit list fetcher: {}", rr);
+        return None;
+    }
+    Some(RwLock::new(IpSet::new()))
+}
+
+fn fetch(url: &str) -> Result<IpSet<()>, String> {
+    let body = attohttpc::get(url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .and_then(|rsp| rsp.error_for_status())
+        .and_then(|rsp| rsp.text())
+        .map_err(|rr| rr.to_string())?;
+    parse(&body)
+}
+
+/// parses the list, ignoring the empty lines and comments
+pub fn parse(body: &str) -> Result<IpSet<()>, String> {
+    let mut exits = IpSet::new();
+    exits.extend_from_reader(body.as_bytes(), |_| Ok(()))?;
+    Ok(exits)
+}
+
+fn run(url: &str, refresh: Duration) {
+    loop {
+        match fetch(url) {
+            Ok(exits) => {
+                tracing::info!(target: "curiefense::tor", exits = exits.len(), "Tor exit list refreshed");
+                if let Some(Ok(mut current)) = EXITS.as_ref().map(|e| e.write()) {
+                    *current = exits;
+                }
+            }
+            Err(rr) => tracing::warn!(target: "curiefense::tor", "could not download the Tor exit list: {}", rr),
+        }
+        std::thread::sleep(refresh);
+    }
+}
+
+/// the address is a known Tor exit node
+pub fn is_exit(ip: IpAddr) -> bool {
+    match EXITS.as_ref().map(|e| e.read()) {
+        Some(Ok(exits)) => exits.contains(ip),
+        _ => false,
+    }
+}
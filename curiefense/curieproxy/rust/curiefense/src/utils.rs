@@ -1,21 +1,42 @@
 use itertools::Itertools;
 use maxminddb::geoip2::model;
-use serde_json::json;
+use regex::Regex;
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub mod decoders;
 
+use crate::accesslog::AccessLog;
 use crate::body::parse_body;
+use crate::botscore::{bot_score, BotScore};
+use crate::cache;
 use crate::config::contentfilter::Transformation;
-use crate::config::raw::ContentType;
+use crate::config::raw::{ContentType, FieldBudget, ParseBudget};
 use crate::config::utils::{DataSource, RequestSelector, RequestSelectorCondition, XDataSource};
+use crate::cookiesigning::CookieIntegrity;
+use crate::csrf::CsrfResult;
 use crate::interface::{Decision, Tags};
+use crate::jwt::JwtResult;
 use crate::logs::Logs;
-use crate::maxmind::{get_asn, get_city, get_country};
-use crate::requestfields::RequestField;
-use crate::utils::decoders::{parse_urlencoded_params, urldecode_str, DecodingResult};
+use crate::maxmind::{get_anonymous, with_asn, with_city, with_country};
+use crate::normalization::Normalization;
+use crate::openapi::OpenApiResult;
+use crate::requestfields::{ParseArena, RequestField};
+use crate::requestmap::RequestMap;
+use crate::requestsignature::SignatureResult;
+use crate::tor;
+use crate::utils::decoders::{
+    base64dec_all_str, canonicalize_path, parse_urlencoded_params, urldecode_str, DecodingResult,
+};
+
+/// the value of an environment variable, or the default when it is unset or invalid
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
 
 pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
     // tries to split the cookie around "="
@@ -35,24 +56,57 @@ pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
 /// * extract cookies
 ///
 /// Returns (headers, cookies)
-pub fn map_headers(dec: &[Transformation], rawheaders: &HashMap<String, String>) -> (RequestField, RequestField) {
-    let mut cookies = RequestField::new(dec);
-    let mut headers = RequestField::new(dec);
+pub fn map_headers(
+    dec: &[Transformation],
+    rawheaders: &HashMap<String, String>,
+    rawbytes: &HashMap<String, Vec<u8>>,
+    budget: &ParseBudget,
+    arena: &Option<Arc<ParseArena>>,
+) -> (RequestField, RequestField) {
+    let mut cookies = RequestField::with_budget(dec, budget.field_budget(budget.max_cookies)).with_arena(arena.clone());
+    let mut headers = RequestField::with_budget(dec, budget.field_budget(budget.max_headers)).with_arena(arena.clone());
     for (k, v) in rawheaders {
         let lk = k.to_lowercase();
         if lk == "cookie" {
             cookie_map(&mut cookies, v);
         } else {
-            headers.add(lk, DataSource::Root, v.clone());
+            match rawbytes.get(k) {
+                Some(bytes) => headers.add_bytes(lk, DataSource::Root, bytes),
+                None => headers.add(lk, DataSource::Root, v.clone()),
+            }
         }
     }
 
     (headers, cookies)
 }
 
+/// adds the trailers and the chunk extensions to the headers, as `trailer:<name>` and `chunk-extension:<name>`, so
+/// that the header section of the content filter inspects them, without shadowing the actual headers
+fn map_trailers(headers: &mut RequestField, meta: &RequestMeta) {
+    for (k, v) in &meta.trailers {
+        headers.add(format!("trailer:{}", k), DataSource::X(XDataSource::Trailer), v.clone());
+    }
+    for (k, v) in &meta.chunk_extensions {
+        headers.add(
+            format!("chunk-extension:{}", k),
+            DataSource::X(XDataSource::ChunkExtension),
+            v.clone(),
+        );
+    }
+}
+
 /// parses query parameters, such as
-fn parse_query_params(dec: &[Transformation], query: &str) -> RequestField {
-    let mut rf = RequestField::new(dec);
+fn parse_query_params(
+    dec: &[Transformation],
+    budget: FieldBudget,
+    arena: &Option<Arc<ParseArena>>,
+    nested_args: bool,
+    normalization: &Option<Arc<Normalization>>,
+    query: &str,
+) -> RequestField {
+    let mut rf = RequestField::with_budget(dec, budget).with_arena(arena.clone());
+    rf.nested_keys = nested_args;
+    rf.normalization = normalization.clone();
     parse_urlencoded_params(&mut rf, query);
     rf
 }
@@ -62,13 +116,20 @@ pub enum BodyDecodingResult {
     NoBody,
     ProperlyDecoded,
     DecodingFailed(String),
+    /// the body was declared as truncated by the caller, and could not be decoded
+    Truncated(String),
 }
 
 /// parses the request uri, storing the path and query parts (if possible)
 /// returns the hashmap of arguments
+#[allow(clippy::too_many_arguments)]
 fn map_args(
     logs: &mut Logs,
     dec: &[Transformation],
+    budget: &ParseBudget,
+    arena: &Option<Arc<ParseArena>>,
+    nested_args: bool,
+    normalization: &Option<Arc<Normalization>>,
     path: &str,
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
@@ -80,20 +141,31 @@ fn map_args(
         DecodingResult::NoChange => path.to_string(),
         DecodingResult::Changed(nuri) => nuri,
     };
+    let canonical_path = match normalization {
+        Some(n) => n.canonical_path(path),
+        None => canonicalize_path(path.split('?').next().unwrap_or_default()),
+    };
+    let args_budget = budget.field_budget(budget.max_args);
     let (qpath, query, mut args) = match path.splitn(2, '?').collect_tuple() {
-        Some((qpath, query)) => (qpath.to_string(), query.to_string(), parse_query_params(dec, query)),
-        None => (path.to_string(), String::new(), RequestField::new(dec)),
+        Some((qpath, query)) => (
+            qpath.to_string(),
+            query.to_string(),
+            parse_query_params(dec, args_budget, arena, nested_args, normalization, query),
+        ),
+        None => (
+            path.to_string(),
+            String::new(),
+            RequestField::with_budget(dec, args_budget).with_arena(arena.clone()),
+        ),
     };
+    args.nested_keys = nested_args;
+    args.normalization = normalization.clone();
 
     let body_decoding = if let Some(body) = mbody {
         if let Err(rr) = parse_body(logs, &mut args, max_depth, mcontent_type, accepted_types, body) {
             logs.debug(|| format!("Body parsing failed: {}", rr));
             // if the body could not be parsed, store it in an argument, as if it was text
-            args.add(
-                "RAW_BODY".to_string(),
-                DataSource::Root,
-                String::from_utf8_lossy(body).to_string(),
-            );
+            args.add_bytes("RAW_BODY".to_string(), DataSource::Root, body);
             BodyDecodingResult::DecodingFailed(rr)
         } else {
             BodyDecodingResult::ProperlyDecoded
@@ -115,6 +187,7 @@ fn map_args(
 
     QueryInfo {
         qpath,
+        canonical_path,
         query,
         uri,
         args,
@@ -128,6 +201,8 @@ fn map_args(
 pub struct QueryInfo {
     /// the "path" portion of the raw query path
     pub qpath: String,
+    /// the canonical form of the "path" portion, used for security policy selection
+    pub canonical_path: String,
     /// the "query" portion of the raw query path
     pub query: String,
     /// URL decoded path, if decoding worked
@@ -152,55 +227,17 @@ pub struct GeoIp {
     pub company: Option<String>,
     pub region: Option<String>,
     pub subregion: Option<String>,
+    pub anonymous: AnonymousFlags,
 }
 
-impl GeoIp {
-    fn to_json(&self) -> HashMap<&'static str, serde_json::Value> {
-        let mut out = HashMap::new();
-        for k in &["location", "country", "continent", "city"] {
-            out.insert(*k, json!({}));
-        }
-
-        if let Some(loc) = self.location {
-            out.insert(
-                "location",
-                json!({
-                    "lat": loc.0,
-                    "lon": loc.1
-                }),
-            );
-        }
-        out.insert(
-            "city",
-            json!({ "name": match &self.city_name {
-                None => "-",
-                Some(n) => n
-            } }),
-        );
-
-        out.insert("eu", json!(self.in_eu));
-        out.insert(
-            "country",
-            json!({
-                "name": self.country_name,
-                "iso": self.country_iso
-            }),
-        );
-        out.insert(
-            "continent",
-            json!({
-                "name": self.continent_name,
-                "code": self.continent_code
-            }),
-        );
-
-        out.insert("asn", json!(self.asn));
-        out.insert("company", json!(self.company));
-        out.insert("region", json!(self.region));
-        out.insert("subregion", json!(self.subregion));
-
-        out
-    }
+/// anonymous network flags, from the anonymous IP database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnonymousFlags {
+    pub is_anonymous: Option<bool>,
+    pub is_anonymous_vpn: Option<bool>,
+    pub is_hosting_provider: Option<bool>,
+    pub is_public_proxy: Option<bool>,
+    pub is_tor_exit_node: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -208,6 +245,30 @@ pub struct RequestMeta {
     pub authority: Option<String>,
     pub method: String,
     pub path: String,
+    /// the request scheme, lowercased
+    pub scheme: Option<String>,
+    /// the destination port
+    pub port: Option<u16>,
+    /// the HTTP protocol version, normalized with `normalize_http_version`
+    pub http_version: Option<String>,
+    /// connection information received through the PROXY protocol, if any
+    pub proxy: Option<ProxyProtocolInfo>,
+    /// set when the body that is passed along is only the beginning of the actual request body
+    pub body_truncated: bool,
+    /// header names, in the order they were received, when provided by the caller
+    pub header_order: Option<Vec<String>>,
+    /// client certificate details provided by the listener, if any
+    pub client_cert: Option<ClientCertificate>,
+    /// TLS fingerprints of the client hello provided by the listener, if any
+    pub tls: TlsFingerprint,
+    /// the configuration to use instead of the one of the integration
+    pub config_path: Option<String>,
+    /// the name of the security policy (hostmap) to use, instead of the one matching the authority
+    pub securitypolicy: Option<String>,
+    /// the trailers of the request, read from the `trailer_<name>` entries, their names being lowercased
+    pub trailers: HashMap<String, String>,
+    /// the extensions of the body chunks (`;name=value`), read from the `chunk_extensions` entry
+    pub chunk_extensions: Vec<(String, String)>,
     /// this field only exists for gradual Lua interop
     /// TODO: remove when complete
     pub extra: HashMap<String, String>,
@@ -219,13 +280,422 @@ impl RequestMeta {
         let authority = mattrs.remove("authority");
         let method = mattrs.remove("method").ok_or("missing method field")?;
         let path = mattrs.remove("path").ok_or("missing path field")?;
+        let proxy = ProxyProtocolInfo::from_map(&mut mattrs);
+        let body_truncated = meta_flag(mattrs.remove("body_truncated"));
+        let header_order = mattrs
+            .remove("header_order")
+            .map(|o| o.split(',').map(|h| h.trim().to_lowercase()).collect());
+        let client_cert = ClientCertificate::from_map(&mut mattrs);
+        let tls = TlsFingerprint::from_map(&mut mattrs);
+        let scheme = mattrs.remove("scheme").map(|s| s.to_lowercase());
+        let port = mattrs
+            .remove("port")
+            .and_then(|p| p.parse().ok())
+            .or_else(|| authority.as_deref().and_then(authority_port))
+            .or_else(|| proxy.as_ref().and_then(|p| p.dst_port))
+            .or(match scheme.as_deref() {
+                Some("http") => Some(80),
+                Some("https") => Some(443),
+                _ => None,
+            });
+        let http_version = mattrs.remove("http_version").map(|v| normalize_http_version(&v));
+        let config_path = mattrs.remove("config_path");
+        let securitypolicy = mattrs.remove("securitypolicy");
+        let trailers = trailers_from_map(&mut mattrs);
+        let chunk_extensions = mattrs
+            .remove("chunk_extensions")
+            .map(|e| parse_chunk_extensions(&e))
+            .unwrap_or_default();
         Ok(RequestMeta {
             authority,
             method,
             path,
+            scheme,
+            port,
+            http_version,
+            proxy,
+            body_truncated,
+            header_order,
+            client_cert,
+            tls,
+            config_path,
+            securitypolicy,
+            trailers,
+            chunk_extensions,
             extra: mattrs,
         })
     }
+
+    /// canonical form of the path, without the query string
+    pub fn canonical_path(&self) -> String {
+        canonicalize_path(self.path.split('?').next().unwrap_or_default())
+    }
+
+    /// returns the client address that should be used for this request
+    ///
+    /// the PROXY protocol source address is preferred over the header derived one when the listener is trusted
+    pub fn client_ip(&self, header_ip: String) -> String {
+        match &self.proxy {
+            Some(p) if p.trusted => p.src_ip.clone(),
+            _ => header_ip,
+        }
+    }
+}
+
+/// port part of an authority, such as `example.com:8080` or `[::1]:8080`
+fn authority_port(authority: &str) -> Option<u16> {
+    let (host, port) = authority.rsplitn(2, ':').collect_tuple().map(|(p, h)| (h, p))?;
+    // a bare IPv6 address, without brackets
+    if host.contains(':') && !host.ends_with(']') {
+        return None;
+    }
+    port.parse().ok()
+}
+
+/// normalizes the HTTP protocol version, so that `HTTP/1.1` becomes `1.1`, and `HTTP/2.0` becomes `2`
+pub fn normalize_http_version(v: &str) -> String {
+    let v = v.trim();
+    let version = if v.len() > 5 && v.get(..5).is_some_and(|p| p.eq_ignore_ascii_case("http/")) {
+        &v[5..]
+    } else {
+        v
+    };
+    version
+        .strip_suffix(".0")
+        .filter(|m| *m != "1")
+        .unwrap_or(version)
+        .to_string()
+}
+
+const TRAILER_PREFIX: &str = "trailer_";
+
+/// removes the `trailer_<name>` entries of the metadata map
+fn trailers_from_map(attrs: &mut HashMap<String, String>) -> HashMap<String, String> {
+    let names: Vec<String> = attrs
+        .keys()
+        .filter(|k| k.len() > TRAILER_PREFIX.len() && k.starts_with(TRAILER_PREFIX))
+        .cloned()
+        .collect();
+    names
+        .into_iter()
+        .filter_map(|k| {
+            let v = attrs.remove(&k)?;
+            Some((k[TRAILER_PREFIX.len()..].to_lowercase(), v))
+        })
+        .collect()
+}
+
+/// parses the extensions of the body chunks, such as `;a=1;b="x y";c`, as they are concatenated by the caller
+///
+/// the quoted values are unquoted, and the extensions without a value get an empty one
+pub fn parse_chunk_extensions(s: &str) -> Vec<(String, String)> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| match e.split_once('=') {
+            Some((k, v)) => {
+                let v = v.trim();
+                let v = match v.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                    Some(quoted) => quoted.replace("\\\"", "\""),
+                    None => v.to_string(),
+                };
+                (k.trim().to_lowercase(), v)
+            }
+            None => (e.to_lowercase(), String::new()),
+        })
+        .collect()
+}
+
+/// the fields that must not be sent in trailers (RFC 9110, section 6.5.1): framing, routing, authentication, request
+/// controls and content metadata, that the upstreams merging the trailers into the headers would honour, but that
+/// were never inspected as headers
+const SENSITIVE_TRAILERS: [&str; 22] = [
+    "authorization",
+    "cache-control",
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "cookie",
+    "expect",
+    "forwarded",
+    "host",
+    "max-forwards",
+    "pragma",
+    "proxy-authorization",
+    "range",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-http-method-override",
+    "x-real-ip",
+];
+
+/// true for the lowercased names of the fields that must not be sent in trailers, including the conditionals (`if-*`)
+pub fn is_sensitive_trailer(name: &str) -> bool {
+    SENSITIVE_TRAILERS.contains(&name) || name.starts_with("if-")
+}
+
+/// boolean flags in the metadata map
+fn meta_flag(v: Option<String>) -> bool {
+    matches!(v.as_deref(), Some("true") | Some("1") | Some("yes"))
+}
+
+/// TLS fingerprints of the client hello, that identify the TLS library of the client whatever its address and its
+/// user agent
+///
+/// They are read from the `metadata` table (`tls_ja3` and `tls_ja4`), or else from the `x-ja3-fingerprint` and
+/// `x-ja4-fingerprint` headers, that Envoy can set with `request_headers_to_add` (`%TLS_JA3_FINGERPRINT%`,
+/// `%TLS_JA4_FINGERPRINT%`). The headers can only be trusted when Envoy overwrites them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsFingerprint {
+    /// the hex encoded MD5 hash of the JA3 string, lowercased
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+}
+
+fn valid_ja3(v: &str) -> Option<String> {
+    let v = v.trim();
+    if v.len() == 32 && v.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(v.to_lowercase())
+    } else {
+        None
+    }
+}
+
+fn valid_ja4(v: &str) -> Option<String> {
+    let v = v.trim();
+    if !v.is_empty() && v.len() <= 64 && v.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        Some(v.to_string())
+    } else {
+        None
+    }
+}
+
+impl TlsFingerprint {
+    /// extracts (and removes) the fingerprints from the metadata map
+    fn from_map(attrs: &mut HashMap<String, String>) -> Self {
+        TlsFingerprint {
+            ja3: attrs.remove("tls_ja3").and_then(|v| valid_ja3(&v)),
+            ja4: attrs.remove("tls_ja4").and_then(|v| valid_ja4(&v)),
+        }
+    }
+
+    /// the listener provided fingerprints are preferred, the headers being used as a fallback
+    fn resolve(meta: &TlsFingerprint, headers: &HashMap<String, String>) -> Self {
+        TlsFingerprint {
+            ja3: meta
+                .ja3
+                .clone()
+                .or_else(|| headers.get("x-ja3-fingerprint").and_then(|v| valid_ja3(v))),
+            ja4: meta
+                .ja4
+                .clone()
+                .or_else(|| headers.get("x-ja4-fingerprint").and_then(|v| valid_ja4(v))),
+        }
+    }
+}
+
+/// Client certificate details, when the connection was established with mutual TLS
+///
+/// They are read from the `metadata` table (`tls_client_subject`, `tls_client_san` as a comma separated list,
+/// `tls_client_fingerprint` and `tls_client_verified`), or else from the `x-forwarded-client-cert` header set by
+/// Envoy. The header can only be trusted when Envoy is configured to sanitize it (`forward_client_cert_details`).
+/// The certificate is only considered verified when the listener sets `tls_client_verified` to `true`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub subject: Option<String>,
+    pub san: Vec<String>,
+    /// hex encoded SHA-256 hash of the certificate
+    pub fingerprint: Option<String>,
+    pub verified: bool,
+}
+
+impl ClientCertificate {
+    /// extracts (and removes) the client certificate fields from the metadata map
+    fn from_map(attrs: &mut HashMap<String, String>) -> Option<Self> {
+        let verified = attrs.remove("tls_client_verified");
+        let subject = attrs.remove("tls_client_subject");
+        let san = attrs.remove("tls_client_san");
+        let fingerprint = attrs.remove("tls_client_fingerprint");
+        if verified.is_none() && subject.is_none() && san.is_none() && fingerprint.is_none() {
+            return None;
+        }
+        Some(ClientCertificate {
+            subject,
+            san: san
+                .map(|s| {
+                    s.split(',')
+                        .map(|e| e.trim().to_string())
+                        .filter(|e| !e.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            fingerprint: fingerprint.map(|f| f.to_lowercase()),
+            verified: meta_flag(verified),
+        })
+    }
+
+    fn has_details(&self) -> bool {
+        self.subject.is_some() || !self.san.is_empty() || self.fingerprint.is_some()
+    }
+
+    /// parses the `x-forwarded-client-cert` header, only keeping the element added by the closest proxy
+    ///
+    /// example: `By=spiffe://lyft.com/frontend;Hash=468ed33b;Subject="CN=Test Client,OU=Lyft";URI=spiffe://lyft.com/test`
+    pub fn from_xfcc(header: &str) -> Option<Self> {
+        let element = split_quoted(header, ',').pop()?;
+        let mut cert = ClientCertificate::default();
+        for pair in split_quoted(&element, ';') {
+            let (k, v) = match pair.splitn(2, '=').collect_tuple() {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let v = unquote(v.trim());
+            match k.trim().to_lowercase().as_str() {
+                "hash" => cert.fingerprint = Some(v.to_lowercase()),
+                "subject" => cert.subject = Some(v),
+                "uri" | "dns" => cert.san.push(v),
+                _ => (),
+            }
+        }
+        if cert.has_details() {
+            Some(cert)
+        } else {
+            None
+        }
+    }
+
+    /// the listener provided details are preferred, the header being used as a fallback
+    fn resolve(meta: Option<&ClientCertificate>, xfcc: Option<&String>) -> Option<Self> {
+        match meta {
+            Some(c) if c.has_details() => Some(c.clone()),
+            _ => match xfcc.and_then(|h| ClientCertificate::from_xfcc(h)) {
+                Some(mut c) => {
+                    c.verified = meta.map(|m| m.verified).unwrap_or(false);
+                    Some(c)
+                }
+                None => meta.cloned(),
+            },
+        }
+    }
+}
+
+/// splits a string around a separator, ignoring separators that are between double quotes
+fn split_quoted(s: &str, sep: char) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            out.push(std::mem::take(&mut cur));
+            continue;
+        }
+        cur.push(c);
+    }
+    out.push(cur);
+    out
+}
+
+/// removes the surrounding double quotes, and the escaping backslashes, of a value
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|i| i.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut escaped = false;
+            for c in inner.chars() {
+                if c == '\\' && !escaped {
+                    escaped = true;
+                } else {
+                    escaped = false;
+                    out.push(c);
+                }
+            }
+            out
+        }
+        None => s.to_string(),
+    }
+}
+
+/// Connection endpoints, as received by the listener through the PROXY protocol (v1 or v2)
+///
+/// The listener exposes them in the `metadata` table, either as a raw v1 header line (`proxy_protocol_header`),
+/// or as decoded fields (`proxy_protocol_src_ip`, `proxy_protocol_src_port`, `proxy_protocol_dst_ip`,
+/// `proxy_protocol_dst_port`, `proxy_protocol_version`). The source address is only used in place of the
+/// header derived one when `proxy_protocol_trusted` is set to `true`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyProtocolInfo {
+    pub version: Option<u8>,
+    pub src_ip: String,
+    pub src_port: Option<u16>,
+    pub dst_ip: Option<String>,
+    pub dst_port: Option<u16>,
+    pub trusted: bool,
+}
+
+impl ProxyProtocolInfo {
+    /// extracts (and removes) the PROXY protocol fields from the metadata map
+    ///
+    /// invalid addresses are ignored
+    fn from_map(attrs: &mut HashMap<String, String>) -> Option<Self> {
+        let trusted = meta_flag(attrs.remove("proxy_protocol_trusted"));
+        let header = attrs.remove("proxy_protocol_header");
+        let version = attrs.remove("proxy_protocol_version");
+        let src_ip = attrs.remove("proxy_protocol_src_ip");
+        let src_port = attrs.remove("proxy_protocol_src_port");
+        let dst_ip = attrs.remove("proxy_protocol_dst_ip");
+        let dst_port = attrs.remove("proxy_protocol_dst_port");
+
+        let mut info = match header {
+            Some(h) => Self::parse_v1(&h)?,
+            None => ProxyProtocolInfo {
+                version: version.and_then(|v| v.parse().ok()),
+                src_ip: src_ip.filter(|i| i.parse::<IpAddr>().is_ok())?,
+                src_port: src_port.and_then(|p| p.parse().ok()),
+                dst_ip: dst_ip.filter(|i| i.parse::<IpAddr>().is_ok()),
+                dst_port: dst_port.and_then(|p| p.parse().ok()),
+                trusted: false,
+            },
+        };
+        info.trusted = trusted;
+        Some(info)
+    }
+
+    /// parses a v1 header line, such as `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443`
+    fn parse_v1(line: &str) -> Option<Self> {
+        let mut parts = line.trim_end().split(' ');
+        if parts.next() != Some("PROXY") {
+            return None;
+        }
+        match parts.next()? {
+            "TCP4" | "TCP6" => (),
+            // UNKNOWN connections carry no usable address
+            _ => return None,
+        }
+        let src_ip = parts.next()?;
+        let dst_ip = parts.next()?;
+        if src_ip.parse::<IpAddr>().is_err() || dst_ip.parse::<IpAddr>().is_err() {
+            return None;
+        }
+        Some(ProxyProtocolInfo {
+            version: Some(1),
+            src_ip: src_ip.to_string(),
+            dst_ip: Some(dst_ip.to_string()),
+            src_port: parts.next().and_then(|p| p.parse().ok()),
+            dst_port: parts.next().and_then(|p| p.parse().ok()),
+            trusted: false,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -241,37 +711,40 @@ pub struct RequestInfo {
     pub cookies: RequestField,
     pub headers: RequestField,
     pub rinfo: RInfo,
+    /// the session identifier, defaults to the client IP
+    pub session: String,
+    /// client fingerprint, see `fingerprint`
+    pub fingerprint: String,
+    /// client certificate details, when the connection used mutual TLS
+    pub client_cert: Option<ClientCertificate>,
+    /// TLS fingerprints of the client, see `TlsFingerprint`
+    pub tls: TlsFingerprint,
+    /// the `x-request-id` header, or a generated identifier, see `request_id`
+    pub request_id: String,
+    /// the validation result of the JWT of the request, when the host map validates them
+    pub jwt: Option<JwtResult>,
+    /// the verification result of the HMAC signature of the request, when the security policy requires them
+    pub signature: Option<SignatureResult>,
+    /// the validation result of the request against the API specification of the host map
+    pub openapi: Option<OpenApiResult>,
+    /// the CSRF token check, when the security policy requires the tokens
+    pub csrf: Option<CsrfResult>,
+    /// the signatures of the cookies, when the security policy signs them
+    pub cookies_integrity: Option<CookieIntegrity>,
+    /// the heuristic bot score, see the `botscore` module
+    pub bot: BotScore,
+    /// the key of the decision, when it can be cached, see the `decisioncache` module
+    pub cache_key: Option<String>,
 }
 
 impl RequestInfo {
+    /// true if some part of the request was not parsed because of the parsing budget
+    pub fn parse_overflow(&self) -> bool {
+        self.headers.overflow || self.cookies.overflow || self.rinfo.qinfo.args.overflow
+    }
+
     pub fn into_json(self, tags: Tags) -> serde_json::Value {
-        let ipnum: Option<String> = self.rinfo.geoip.ip.as_ref().map(|i| match i {
-            IpAddr::V4(a) => u32::from_be_bytes(a.octets()).to_string(),
-            IpAddr::V6(a) => u128::from_be_bytes(a.octets()).to_string(),
-        });
-        let geo = self.rinfo.geoip.to_json();
-        let mut attrs: HashMap<String, Option<String>> = [
-            ("uri", Some(self.rinfo.qinfo.uri)),
-            ("path", Some(self.rinfo.qinfo.qpath)),
-            ("query", Some(self.rinfo.qinfo.query)),
-            ("ip", Some(self.rinfo.geoip.ipstr)),
-            ("ipnum", ipnum),
-            ("authority", Some(self.rinfo.host)),
-            ("method", Some(self.rinfo.meta.method)),
-        ]
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.clone()))
-        .collect();
-        attrs.extend(self.rinfo.meta.extra.into_iter().map(|(k, v)| (k, Some(v))));
-        serde_json::json!({
-            "headers": self.headers.to_json(),
-            "cookies": self.cookies.to_json(),
-            "args": self.rinfo.qinfo.args.to_json(),
-            "path": self.rinfo.qinfo.path_as_map.to_json(),
-            "attrs": attrs,
-            "tags": tags,
-            "geo": geo
-        })
+        serde_json::to_value(RequestMap::new(self, tags)).unwrap_or(serde_json::Value::Null)
     }
 }
 
@@ -293,42 +766,85 @@ impl InspectionResult {
         };
         (resp, self.err)
     }
+
+    /// the access log record, when the request could be mapped
+    pub fn access_log(&self) -> Option<AccessLog> {
+        let rinfo = self.rinfo.clone()?;
+        let mut tags = self.tags.clone().unwrap_or_default();
+        if let Decision::Action(a) = &self.decision {
+            for t in a.extra_tags.iter().flatten() {
+                tags.insert(t);
+            }
+        }
+        Some(AccessLog::new(
+            &self.decision,
+            &RequestMap::new(rinfo, tags),
+            &self.logs,
+        ))
+    }
 }
 
 pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
-    let pip = ipstr.parse();
-    let mut geoip = GeoIp {
-        ipstr,
-        ip: None,
-        location: None,
-        in_eu: None,
-        city_name: None,
-        country_iso: None,
-        country_name: None,
-        continent_name: None,
-        continent_code: None,
-        asn: None,
-        company: None,
-        region: None,
-        subregion: None,
-    };
-
-    let ip = match pip {
+    let ip = match ipstr.parse() {
         Ok(x) => x,
         Err(rr) => {
             logs.error(|| format!("When parsing ip {}", rr));
-            return geoip;
+            return GeoIp::unknown(ipstr);
         }
     };
+    let mut geoip = cache::GEOIP.get_or_insert_with(ip, Instant::now(), || lookup_geoip(logs, ip));
+    geoip.ipstr = ipstr;
+    // the Tor exit list is refreshed independently of the databases
+    if tor::is_exit(ip) {
+        geoip.anonymous.is_anonymous = Some(true);
+        geoip.anonymous.is_tor_exit_node = Some(true);
+    }
+    geoip
+}
+
+impl GeoIp {
+    fn unknown(ipstr: String) -> Self {
+        GeoIp {
+            ipstr,
+            ip: None,
+            location: None,
+            in_eu: None,
+            city_name: None,
+            country_iso: None,
+            country_name: None,
+            continent_name: None,
+            continent_code: None,
+            asn: None,
+            company: None,
+            region: None,
+            subregion: None,
+            anonymous: AnonymousFlags::default(),
+        }
+    }
+}
+
+/// the information of the GeoIP databases, `ipstr` being left empty
+fn lookup_geoip(logs: &mut Logs, ip: IpAddr) -> GeoIp {
+    let mut geoip = GeoIp::unknown(String::new());
 
     let get_name = |mmap: &Option<std::collections::BTreeMap<&str, &str>>| {
         mmap.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase())
     };
 
-    if let Ok(asninfo) = get_asn(ip) {
+    if let Ok(anon) = get_anonymous(ip) {
+        geoip.anonymous = AnonymousFlags {
+            is_anonymous: anon.is_anonymous,
+            is_anonymous_vpn: anon.is_anonymous_vpn,
+            is_hosting_provider: anon.is_hosting_provider,
+            is_public_proxy: anon.is_public_proxy,
+            is_tor_exit_node: anon.is_tor_exit_node,
+        };
+    }
+
+    let _ = with_asn(ip, |asninfo| {
         geoip.asn = asninfo.autonomous_system_number;
         geoip.company = asninfo.autonomous_system_organization.map(|s| s.to_string());
-    }
+    });
 
     let extract_continent = |g: &mut GeoIp, mcnt: Option<model::Continent>| {
         if let Some(continent) = mcnt {
@@ -346,13 +862,13 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
     };
 
     // first put country data in the geoip
-    if let Ok(cnty) = get_country(ip) {
+    let _ = with_country(ip, |cnty| {
         extract_continent(&mut geoip, cnty.continent);
         extract_country(&mut geoip, cnty.country);
-    }
+    });
 
     // potentially overwrite some with the city data
-    if let Ok(cty) = get_city(ip) {
+    let _ = with_city(ip, |cty| {
         extract_continent(&mut geoip, cty.continent);
         extract_country(&mut geoip, cty.country);
         geoip.location = cty
@@ -371,7 +887,7 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
             }
         }
         geoip.city_name = cty.city.as_ref().and_then(|c| get_name(&c.names));
-    }
+    });
 
     geoip.ip = Some(ip);
     geoip
@@ -380,10 +896,57 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
 pub struct RawRequest<'a> {
     pub ipstr: String,
     pub headers: HashMap<String, String>,
+    /// original bytes of the header values that are not valid UTF-8, `headers` holding their lossy version
+    pub header_bytes: HashMap<String, Vec<u8>>,
     pub meta: RequestMeta,
     pub mbody: Option<&'a [u8]>,
 }
 
+/// a serialized request, with the same keys as the arguments of the Lua API
+#[derive(Debug, Deserialize)]
+pub struct InspectionRequest {
+    pub ip: String,
+    pub meta: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+impl InspectionRequest {
+    pub fn to_raw(&self) -> Result<RawRequest<'_>, &'static str> {
+        let meta = RequestMeta::from_map(self.meta.clone())?;
+        Ok(RawRequest {
+            ipstr: meta.client_ip(self.ip.clone()),
+            headers: self.headers.clone(),
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: self.body.as_ref().map(|b| b.as_bytes()),
+        })
+    }
+}
+
+/// converts header values that are not guaranteed to be valid UTF-8
+///
+/// returns the lossy headers, along with the original bytes of the values that were not valid
+pub fn decode_header_bytes<'t, I: IntoIterator<Item = (String, &'t [u8])>>(
+    raw: I,
+) -> (HashMap<String, String>, HashMap<String, Vec<u8>>) {
+    let mut headers = HashMap::new();
+    let mut header_bytes = HashMap::new();
+    for (k, v) in raw {
+        match std::str::from_utf8(v) {
+            Ok(s) => {
+                headers.insert(k, s.to_string());
+            }
+            Err(_) => {
+                headers.insert(k.clone(), String::from_utf8_lossy(v).into_owned());
+                header_bytes.insert(k, v.to_vec());
+            }
+        }
+    }
+    (headers, header_bytes)
+}
+
 impl<'a> RawRequest<'a> {
     pub fn get_host(&'a self) -> String {
         match self.meta.authority.as_ref().or_else(|| self.headers.get("host")) {
@@ -391,32 +954,92 @@ impl<'a> RawRequest<'a> {
             None => "unknown".to_string(),
         }
     }
+
+    /// the `cookie` headers, whatever the case of their names, joined like repeated cookie headers
+    pub fn cookie_header(&self) -> Option<String> {
+        let mut cookies: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_unstable();
+        Some(cookies.join("; "))
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn map_request(
     logs: &mut Logs,
     dec: &[Transformation],
     accepted_types: &[ContentType],
     max_depth: usize, // if set to 0, the body will not be parsed
+    session: &[RequestSelector],
+    budget: &ParseBudget,
+    nested_args: bool, // parse PHP style array and nested argument names
+    raw: &RawRequest,
+) -> RequestInfo {
+    map_request_with(
+        logs,
+        dec,
+        accepted_types,
+        max_depth,
+        session,
+        budget,
+        nested_args,
+        &None,
+        raw,
+    )
+}
+
+/// maps the request, its parameters being parsed as the upstream of its host map parses them
+#[allow(clippy::too_many_arguments)]
+pub fn map_request_with(
+    logs: &mut Logs,
+    dec: &[Transformation],
+    accepted_types: &[ContentType],
+    max_depth: usize, // if set to 0, the body will not be parsed
+    session: &[RequestSelector],
+    budget: &ParseBudget,
+    nested_args: bool, // parse PHP style array and nested argument names
+    normalization: &Option<Arc<Normalization>>,
     raw: &RawRequest,
 ) -> RequestInfo {
     let host = raw.get_host();
 
     logs.debug("map_request starts");
-    let (headers, cookies) = map_headers(dec, &raw.headers);
+    let arena = budget.arena();
+    let (mut headers, cookies) = map_headers(dec, &raw.headers, &raw.header_bytes, budget, &arena);
+    map_trailers(&mut headers, &raw.meta);
     logs.debug("headers mapped");
     let geoip = find_geoip(logs, raw.ipstr.clone());
     logs.debug("geoip computed");
-    let qinfo = map_args(
+    let mut qinfo = map_args(
         logs,
         dec,
+        budget,
+        &arena,
+        nested_args,
+        normalization,
         &raw.meta.path,
         headers.get_str("content-type"),
         accepted_types,
         raw.mbody,
         max_depth,
     );
+    if raw.meta.body_truncated {
+        logs.debug("the body was truncated by the caller");
+        if let BodyDecodingResult::DecodingFailed(rr) = qinfo.body_decoding {
+            qinfo.body_decoding = BodyDecodingResult::Truncated(rr);
+        }
+    }
     logs.debug("args mapped");
+    if headers.overflow || cookies.overflow || qinfo.args.overflow {
+        logs.warning("the request exceeds the parsing budget");
+    }
 
     let rinfo = RInfo {
         meta: raw.meta.clone(),
@@ -425,11 +1048,105 @@ pub fn map_request(
         host,
     };
 
-    RequestInfo {
+    let fingerprint = fingerprint(&raw.meta, &raw.headers);
+    let bot = bot_score(&raw.meta, &headers);
+    let client_cert = ClientCertificate::resolve(
+        raw.meta.client_cert.as_ref(),
+        raw.headers.get("x-forwarded-client-cert"),
+    );
+    let tls = TlsFingerprint::resolve(&raw.meta.tls, &raw.headers);
+    let request_id = request_id(&headers);
+    logs.debug(|| format!("request id: {}", request_id));
+    let mut reqinfo = RequestInfo {
         cookies,
         headers,
         rinfo,
+        session: raw.ipstr.clone(),
+        fingerprint,
+        client_cert,
+        tls,
+        request_id,
+        jwt: None,
+        signature: None,
+        openapi: None,
+        csrf: None,
+        cookies_integrity: None,
+        bot,
+        cache_key: None,
+    };
+    let empty_tags = Tags::default();
+    if let Some(s) = session.iter().find_map(|s| select_string(&reqinfo, s, &empty_tags)) {
+        reqinfo.session = s;
+    }
+    logs.debug(|| format!("session identifier: {}", reqinfo.session));
+    reqinfo
+}
+
+/// longer `x-request-id` headers are replaced by a generated identifier
+pub const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// the identifier of the request, that ties the decision, the block page, the logs and the metrics together
+///
+/// the `x-request-id` header is used when present, otherwise a random UUID is generated, that the integrations
+/// forward upstream
+pub fn request_id(headers: &RequestField) -> String {
+    match headers.get_str("x-request-id").map(|s| s.trim()) {
+        Some(rid) if !rid.is_empty() && rid.len() <= MAX_REQUEST_ID_LENGTH => rid.to_string(),
+        _ => {
+            let mut bytes: [u8; 16] = crate::clock::random();
+            // version 4, variant 1
+            bytes[6] = (bytes[6] & 0x0f) | 0x40;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+            let hex = crate::crypto::to_hex(&bytes);
+            format!(
+                "{}-{}-{}-{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..]
+            )
+        }
+    }
+}
+
+/// computes a client fingerprint, from the header names (in order when available), the accept-language header, and
+/// the structure of the user agent (digits are ignored, so that version bumps do not change the fingerprint)
+///
+/// this is meant to identify clients that rotate their IP addresses, but keep the same HTTP stack
+pub fn fingerprint(meta: &RequestMeta, headers: &HashMap<String, String>) -> String {
+    let mut hasher = Sha256::new();
+    match &meta.header_order {
+        Some(order) => {
+            for h in order {
+                hasher.update(h.as_bytes());
+                hasher.update(b",");
+            }
+        }
+        None => {
+            for h in headers.keys().map(|k| k.to_lowercase()).sorted() {
+                hasher.update(h.as_bytes());
+                hasher.update(b",");
+            }
+        }
     }
+    hasher.update(b"|");
+    let get_header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    };
+    hasher.update(get_header("accept-language").as_bytes());
+    hasher.update(b"|");
+    let ua_structure: String = get_header("user-agent")
+        .chars()
+        .filter(|c| !c.is_ascii_digit())
+        .collect();
+    hasher.update(ua_structure.as_bytes());
+    let bytes = hasher.finalize();
+    format!("{:x}", bytes)[0..16].to_string()
 }
 
 enum Selected<'a> {
@@ -460,11 +1177,55 @@ fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: &Tags) ->
             }
         }
         RequestSelector::Method => Some(&reqinfo.rinfo.meta.method).map(Selected::Str),
+        RequestSelector::Scheme => reqinfo.rinfo.meta.scheme.as_ref().map(Selected::Str),
+        RequestSelector::Port => reqinfo.rinfo.meta.port.map(|p| Selected::U32(p as u32)),
+        RequestSelector::HttpVersion => reqinfo.rinfo.meta.http_version.as_ref().map(Selected::Str),
         RequestSelector::Country => reqinfo.rinfo.geoip.country_iso.as_ref().map(Selected::Str),
         RequestSelector::Authority => Some(Selected::Str(&reqinfo.rinfo.host)),
         RequestSelector::Company => reqinfo.rinfo.geoip.company.as_ref().map(Selected::Str),
         RequestSelector::Asn => reqinfo.rinfo.geoip.asn.map(Selected::U32),
         RequestSelector::Tags => Some(Selected::OStr(tags.selector())),
+        RequestSelector::Session => Some(Selected::Str(&reqinfo.session)),
+        RequestSelector::Fingerprint => Some(Selected::Str(&reqinfo.fingerprint)),
+        RequestSelector::JwtClaim(c) => jwt_claim(reqinfo, c).map(Selected::OStr),
+        RequestSelector::CertSubject => reqinfo
+            .client_cert
+            .as_ref()
+            .and_then(|c| c.subject.as_ref())
+            .map(Selected::Str),
+        RequestSelector::CertFingerprint => reqinfo
+            .client_cert
+            .as_ref()
+            .and_then(|c| c.fingerprint.as_ref())
+            .map(Selected::Str),
+        RequestSelector::Ja3 => reqinfo.tls.ja3.as_ref().map(Selected::Str),
+        RequestSelector::Ja4 => reqinfo.tls.ja4.as_ref().map(Selected::Str),
+    }
+}
+
+/// extracts a claim from the bearer JWT found in the authorization header
+///
+/// the signature is not verified, this is only meant to be used as an identifier
+fn jwt_claim(reqinfo: &RequestInfo, claim: &str) -> Option<String> {
+    let auth = reqinfo.headers.get_str("authorization")?;
+    let token = auth.strip_prefix("Bearer ").or_else(|| auth.strip_prefix("bearer "))?;
+    let payload = token.split('.').nth(1)?;
+    let now = Instant::now();
+    let claims = match cache::JWT.get(payload, now) {
+        Some(claims) => claims,
+        None => {
+            let claims = base64dec_all_str(payload)
+                .ok()
+                .and_then(|decoded| serde_json::from_str(&decoded).ok())
+                .map(Arc::new);
+            cache::JWT.insert(payload.to_string(), claims.clone(), now);
+            claims
+        }
+    }?;
+    match claims.get(claim)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Null => None,
+        v => Some(v.to_string()),
     }
 }
 
@@ -497,6 +1258,21 @@ pub fn masker(seed: &[u8], value: &str) -> String {
     format!("MASKED{{{}}}", &hash_str[0..8])
 }
 
+/// masks the parts of the value that match the patterns, returning `None` when nothing matched
+pub fn mask_matches(seed: &[u8], patterns: &[Regex], value: &str) -> Option<String> {
+    let mut out: Option<String> = None;
+    for re in patterns {
+        let current = out.as_deref().unwrap_or(value);
+        if re.is_match(current) {
+            out = Some(
+                re.replace_all(current, |c: &regex::Captures| masker(seed, &c[0]))
+                    .into_owned(),
+            );
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -507,6 +1283,10 @@ mod tests {
         let qinfo = map_args(
             &mut logs,
             &[Transformation::Base64Decode],
+            &ParseBudget::default(),
+            &None,
+            false,
+            &None,
             "/a/b/%20c?xa%20=12&bbbb=12%28&cccc&b64=YXJndW1lbnQ%3D",
             None,
             &[],
@@ -537,7 +1317,19 @@ mod tests {
     #[test]
     fn test_map_args_simple() {
         let mut logs = Logs::default();
-        let qinfo = map_args(&mut logs, &[], "/a/b", None, &[], None, 500);
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            &ParseBudget::default(),
+            &None,
+            false,
+            &None,
+            "/a/b",
+            None,
+            &[],
+            None,
+            500,
+        );
 
         assert_eq!(qinfo.qpath, "/a/b");
         assert_eq!(qinfo.uri, "/a/b");
@@ -545,4 +1337,432 @@ mod tests {
 
         assert_eq!(qinfo.args, RequestField::new(&[]));
     }
+
+    #[test]
+    fn test_map_args_nested() {
+        let mut logs = Logs::default();
+        let path = "/?a%5B%5D=1&a[]=2&u[name][first]=x&b[=c";
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            &ParseBudget::default(),
+            &None,
+            true,
+            &None,
+            path,
+            None,
+            &[],
+            None,
+            500,
+        );
+        assert_eq!(qinfo.args.get_str("a"), Some("1 2"));
+        assert_eq!(qinfo.args.get_str("u_name_first"), Some("x"));
+        assert_eq!(qinfo.args.get_str("b["), Some("c"));
+
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            &ParseBudget::default(),
+            &None,
+            false,
+            &None,
+            path,
+            None,
+            &[],
+            None,
+            500,
+        );
+        assert_eq!(qinfo.args.get_str("a[]"), Some("1 2"));
+    }
+
+    fn mk_meta(extra: &[(&str, &str)]) -> RequestMeta {
+        let attrs = [("method", "GET"), ("path", "/")]
+            .iter()
+            .chain(extra.iter())
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        RequestMeta::from_map(attrs).unwrap()
+    }
+
+    #[test]
+    fn trailers_and_chunk_extensions() {
+        let meta = mk_meta(&[
+            ("trailer_X-Checksum", "abcd"),
+            ("trailer_", "ignored"),
+            ("chunk_extensions", r#";a=1; B="x \"y\"" ;c;"#),
+        ]);
+        assert_eq!(meta.trailers.get("x-checksum").map(String::as_str), Some("abcd"));
+        assert_eq!(meta.trailers.len(), 1);
+        assert_eq!(meta.extra.get("trailer_").map(String::as_str), Some("ignored"));
+        assert_eq!(
+            meta.chunk_extensions,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "x \"y\"".to_string()),
+                ("c".to_string(), String::new())
+            ]
+        );
+        assert!(is_sensitive_trailer("transfer-encoding"));
+        assert!(is_sensitive_trailer("if-none-match"));
+        assert!(!is_sensitive_trailer("x-checksum"));
+
+        // they are inspected with the headers, without shadowing them
+        let mut headers = RequestField::new(&[]);
+        headers.add("x-checksum".to_string(), DataSource::Root, "real".to_string());
+        map_trailers(&mut headers, &meta);
+        assert_eq!(headers.get_str("x-checksum"), Some("real"));
+        assert_eq!(headers.get_str("trailer:x-checksum"), Some("abcd"));
+        assert_eq!(headers.get_str("chunk-extension:b"), Some("x \"y\""));
+    }
+
+    #[test]
+    fn proxy_protocol_v1_header() {
+        let meta = mk_meta(&[
+            (
+                "proxy_protocol_header",
+                "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            ),
+            ("proxy_protocol_trusted", "true"),
+        ]);
+        assert_eq!(
+            meta.proxy,
+            Some(ProxyProtocolInfo {
+                version: Some(1),
+                src_ip: "192.168.0.1".to_string(),
+                src_port: Some(56324),
+                dst_ip: Some("192.168.0.11".to_string()),
+                dst_port: Some(443),
+                trusted: true
+            })
+        );
+        assert!(meta.extra.is_empty());
+        assert_eq!(meta.client_ip("1.2.3.4".to_string()), "192.168.0.1");
+    }
+
+    #[test]
+    fn proxy_protocol_v2_fields_untrusted() {
+        let meta = mk_meta(&[
+            ("proxy_protocol_version", "2"),
+            ("proxy_protocol_src_ip", "2001:db8::1"),
+            ("proxy_protocol_src_port", "1234"),
+        ]);
+        let proxy = meta.proxy.clone().unwrap();
+        assert_eq!(proxy.version, Some(2));
+        assert_eq!(proxy.src_ip, "2001:db8::1");
+        assert_eq!(proxy.src_port, Some(1234));
+        assert_eq!(proxy.dst_ip, None);
+        assert_eq!(meta.client_ip("1.2.3.4".to_string()), "1.2.3.4");
+    }
+
+    #[test]
+    fn proxy_protocol_invalid() {
+        let meta = mk_meta(&[
+            ("proxy_protocol_header", "PROXY UNKNOWN\r\n"),
+            ("proxy_protocol_trusted", "true"),
+        ]);
+        assert_eq!(meta.proxy, None);
+        let meta = mk_meta(&[("proxy_protocol_src_ip", "garbage"), ("proxy_protocol_trusted", "true")]);
+        assert_eq!(meta.proxy, None);
+        assert_eq!(meta.client_ip("1.2.3.4".to_string()), "1.2.3.4");
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut logs = Logs::default();
+        let body = br#"{"a": "b", "c": "#;
+        let mut meta = mk_meta(&[("body_truncated", "true")]);
+        let headers: HashMap<String, String> = [("content-type".to_string(), "application/json".to_string())]
+            .iter()
+            .cloned()
+            .collect();
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: headers.clone(),
+            header_bytes: HashMap::new(),
+            meta: meta.clone(),
+            mbody: Some(body),
+        };
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        assert!(matches!(
+            reqinfo.rinfo.qinfo.body_decoding,
+            BodyDecodingResult::Truncated(_)
+        ));
+        assert_eq!(
+            reqinfo.rinfo.qinfo.args.get_str("RAW_BODY"),
+            Some(r#"{"a": "b", "c": "#)
+        );
+
+        meta.body_truncated = false;
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers,
+            header_bytes: HashMap::new(),
+            meta,
+            mbody: Some(body),
+        };
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        assert!(matches!(
+            reqinfo.rinfo.qinfo.body_decoding,
+            BodyDecodingResult::DecodingFailed(_)
+        ));
+    }
+
+    fn session_of(headers: &[(&str, &str)], session: &[RequestSelector]) -> String {
+        let mut logs = Logs::default();
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            header_bytes: HashMap::new(),
+            meta: mk_meta(&[]),
+            mbody: None,
+        };
+        map_request(&mut logs, &[], &[], 500, session, &ParseBudget::default(), false, &raw).session
+    }
+
+    #[test]
+    fn session_selectors() {
+        let selectors = [
+            RequestSelector::Cookie("sessionid".to_string()),
+            RequestSelector::JwtClaim("sub".to_string()),
+        ];
+        // {"sub":"user1","n":3}
+        let jwt = "Bearer eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJ1c2VyMSIsIm4iOjN9.sig";
+        assert_eq!(session_of(&[], &selectors), "1.2.3.4");
+        assert_eq!(session_of(&[("authorization", jwt)], &selectors), "user1");
+        assert_eq!(
+            session_of(&[("authorization", jwt), ("cookie", "sessionid=abcd")], &selectors),
+            "abcd"
+        );
+        assert_eq!(
+            session_of(&[("authorization", jwt)], &[RequestSelector::JwtClaim("n".to_string())]),
+            "3"
+        );
+        assert_eq!(
+            session_of(&[("authorization", "Bearer garbage")], &selectors),
+            "1.2.3.4"
+        );
+    }
+
+    #[test]
+    fn request_ids() {
+        let header =
+            |v: &str| RequestField::singleton(&[], "x-request-id".to_string(), DataSource::Root, v.to_string());
+        assert_eq!(request_id(&header(" abc-123 ")), "abc-123");
+        let generated = request_id(&RequestField::new(&[]));
+        assert_eq!(generated.len(), 36);
+        assert_eq!(generated.as_bytes()[14], b'4');
+        assert_ne!(generated, request_id(&RequestField::new(&[])));
+        assert_eq!(request_id(&header("")).len(), 36);
+        assert_eq!(request_id(&header(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1))).len(), 36);
+    }
+
+    #[test]
+    fn fingerprint_header_order() {
+        let headers: HashMap<String, String> = [("accept", "*/*"), ("user-agent", "curl/7.68.0"), ("host", "a")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let fp = |meta: &[(&str, &str)], hdrs: &HashMap<String, String>| fingerprint(&mk_meta(meta), hdrs);
+
+        let ordered = fp(&[("header_order", "host,user-agent,accept")], &headers);
+        let reordered = fp(&[("header_order", "user-agent,host,accept")], &headers);
+        assert_eq!(ordered.len(), 16);
+        assert_ne!(ordered, reordered);
+        // without an explicit order, the names are sorted
+        assert_eq!(
+            fp(&[], &headers),
+            fp(&[("header_order", "accept,host,user-agent")], &headers)
+        );
+
+        // version changes in the user agent are ignored
+        let mut updated = headers.clone();
+        updated.insert("user-agent".to_string(), "curl/7.74.0".to_string());
+        assert_eq!(fp(&[], &headers), fp(&[], &updated));
+        updated.insert("user-agent".to_string(), "wget/7.74.0".to_string());
+        assert_ne!(fp(&[], &headers), fp(&[], &updated));
+    }
+
+    #[test]
+    fn non_utf8_values() {
+        let mut logs = Logs::default();
+        let (headers, header_bytes) = decode_header_bytes(vec![
+            ("h1".to_string(), &b"caf\xe9"[..]),
+            ("h2".to_string(), &b"ok"[..]),
+        ]);
+        assert_eq!(headers.get("h1").map(|s| s.as_str()), Some("caf\u{fffd}"));
+        assert!(!header_bytes.contains_key("h2"));
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers,
+            header_bytes,
+            meta: mk_meta(&[("path", "/?a=%bf%27&b=c")]),
+            mbody: Some(b"\xff\xfe"),
+        };
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        assert_eq!(reqinfo.headers.raw.get("h1"), Some(&b"caf\xe9".to_vec()));
+        assert_eq!(reqinfo.rinfo.qinfo.args.raw.get("a"), Some(&b"\xbf'".to_vec()));
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("b"), Some("c"));
+        assert!(!reqinfo.rinfo.qinfo.args.raw.contains_key("b"));
+        assert_eq!(
+            reqinfo.rinfo.qinfo.args.raw.get("RAW_BODY"),
+            Some(&b"\xff\xfe".to_vec())
+        );
+    }
+
+    #[test]
+    fn request_line_meta() {
+        let meta = mk_meta(&[
+            ("scheme", "HTTPS"),
+            ("http_version", "HTTP/2.0"),
+            ("authority", "a.com"),
+        ]);
+        assert_eq!(meta.scheme.as_deref(), Some("https"));
+        assert_eq!(meta.port, Some(443));
+        assert_eq!(meta.http_version.as_deref(), Some("2"));
+        let meta = mk_meta(&[("authority", "[::1]:8080"), ("http_version", "HTTP/1.0")]);
+        assert_eq!(meta.port, Some(8080));
+        assert_eq!(meta.http_version.as_deref(), Some("1.0"));
+        assert_eq!(mk_meta(&[("authority", "::1")]).port, None);
+        assert_eq!(mk_meta(&[("port", "8443"), ("scheme", "http")]).port, Some(8443));
+        // the non ASCII versions are kept as they are
+        assert_eq!(normalize_http_version("HTTPé/1.1"), "HTTPé/1.1");
+        assert_eq!(normalize_http_version("éé/1.1"), "éé/1.1");
+        let meta = mk_meta(&[("http_version", "HTTP\u{e9}/1.1")]);
+        assert_eq!(meta.http_version.as_deref(), Some("HTTP\u{e9}/1.1"));
+    }
+
+    #[test]
+    fn client_cert_xfcc() {
+        let cert = ClientCertificate::from_xfcc(
+            "By=spiffe://a/b;Hash=AA;URI=spiffe://a/c,By=spiffe://a/d;Hash=468ED33B;Subject=\"CN=Test Client,OU=Lyft\";URI=spiffe://lyft.com/test;DNS=test.lyft.com",
+        );
+        assert_eq!(
+            cert,
+            Some(ClientCertificate {
+                subject: Some("CN=Test Client,OU=Lyft".to_string()),
+                san: vec!["spiffe://lyft.com/test".to_string(), "test.lyft.com".to_string()],
+                fingerprint: Some("468ed33b".to_string()),
+                verified: false,
+            })
+        );
+        assert_eq!(ClientCertificate::from_xfcc("By=spiffe://a/b"), None);
+    }
+
+    #[test]
+    fn client_cert_metadata() {
+        let meta = mk_meta(&[
+            ("tls_client_subject", "CN=partner"),
+            ("tls_client_san", "a.example.com, b.example.com"),
+            ("tls_client_verified", "true"),
+        ]);
+        let cert = meta.client_cert.clone().unwrap();
+        assert_eq!(cert.san, vec!["a.example.com".to_string(), "b.example.com".to_string()]);
+        assert!(cert.verified);
+        assert!(meta.extra.is_empty());
+
+        // the header is only used when the listener did not provide the details
+        let xfcc = "Hash=abcd;Subject=\"CN=other\"".to_string();
+        assert_eq!(ClientCertificate::resolve(Some(&cert), Some(&xfcc)), Some(cert));
+        let flag_only = mk_meta(&[("tls_client_verified", "true")]).client_cert;
+        let resolved = ClientCertificate::resolve(flag_only.as_ref(), Some(&xfcc)).unwrap();
+        assert_eq!(resolved.subject.as_deref(), Some("CN=other"));
+        assert!(resolved.verified);
+        assert_eq!(ClientCertificate::resolve(None, None), None);
+    }
+
+    #[test]
+    fn tls_fingerprints() {
+        let meta = mk_meta(&[
+            ("tls_ja3", "E7D705A3286E19EA42F587B344EE6865"),
+            ("tls_ja4", "t13d1516h2_8daaf6152771_b186095e22b6"),
+        ]);
+        assert_eq!(meta.tls.ja3.as_deref(), Some("e7d705a3286e19ea42f587b344ee6865"));
+        assert!(meta.extra.is_empty());
+        assert_eq!(mk_meta(&[("tls_ja3", "not a hash")]).tls, TlsFingerprint::default());
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "x-ja3-fingerprint".to_string(),
+            "00000000000000000000000000000000".to_string(),
+        );
+        headers.insert("x-ja4-fingerprint".to_string(), "t13d_header".to_string());
+        // the headers are only used when the listener did not provide the fingerprints
+        let resolved = TlsFingerprint::resolve(&meta.tls, &headers);
+        assert_eq!(resolved, meta.tls);
+        let resolved = TlsFingerprint::resolve(&TlsFingerprint::default(), &headers);
+        assert_eq!(resolved.ja3.as_deref(), Some("00000000000000000000000000000000"));
+        assert_eq!(resolved.ja4.as_deref(), Some("t13d_header"));
+    }
+
+    #[test]
+    fn parse_budget() {
+        let mut logs = Logs::default();
+        let mut headers = HashMap::new();
+        headers.insert("h1".to_string(), "v1".to_string());
+        headers.insert("h2".to_string(), "v2".to_string());
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers,
+            header_bytes: HashMap::new(),
+            meta: mk_meta(&[("path", "/?a=1&b=2&c=3&a=4")]),
+            mbody: None,
+        };
+        let budget = ParseBudget {
+            max_args: 2,
+            max_headers: 2,
+            ..ParseBudget::default()
+        };
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &budget, false, &raw);
+        assert!(reqinfo.parse_overflow());
+        assert!(!reqinfo.headers.overflow);
+        assert_eq!(reqinfo.rinfo.qinfo.args.len(), 2);
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("a"), Some("1 4"));
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("c"), None);
+
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &ParseBudget::default(), false, &raw);
+        assert!(!reqinfo.parse_overflow());
+
+        // the headers take 8 bytes of the arena, and the first argument 2
+        let budget = ParseBudget {
+            max_request_bytes: 11,
+            ..ParseBudget::default()
+        };
+        let reqinfo = map_request(&mut logs, &[], &[], 500, &[], &budget, false, &raw);
+        assert!(reqinfo.parse_overflow());
+        assert!(!reqinfo.headers.overflow);
+        assert_eq!(reqinfo.headers.len(), 2);
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("a"), Some("1"));
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("b"), None);
+        let arena = reqinfo.rinfo.qinfo.args.arena.as_ref().unwrap();
+        assert_eq!(arena.used(), 10);
+        assert_eq!(arena.cap(), 11);
+    }
+
+    #[test]
+    fn decode_passes_budget() {
+        let mut logs = Logs::default();
+        let dec = [
+            Transformation::Base64Decode,
+            Transformation::UrlDecode,
+            Transformation::HtmlEntitiesDecode,
+            Transformation::UnicodeDecode,
+        ];
+        let budget = ParseBudget {
+            max_decode_passes: 1,
+            ..ParseBudget::default()
+        };
+        let raw = |path: &str| RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: HashMap::new(),
+            header_bytes: HashMap::new(),
+            meta: mk_meta(&[("path", path)]),
+            mbody: None,
+        };
+        // none of the skipped transformations would change a plain value
+        let reqinfo = map_request(&mut logs, &dec, &[], 500, &[], &budget, false, &raw("/?a=plain"));
+        assert!(!reqinfo.parse_overflow());
+        // the html entities of the value are left undecoded
+        let reqinfo = map_request(&mut logs, &dec, &[], 500, &[], &budget, false, &raw("/?a=%26lt%3B"));
+        assert!(reqinfo.parse_overflow());
+    }
 }
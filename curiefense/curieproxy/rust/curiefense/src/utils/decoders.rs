@@ -1,4 +1,5 @@
 use crate::config::utils::{DataSource, XDataSource};
+use crate::normalization::Params;
 use crate::requestfields::RequestField;
 
 use itertools::Itertools;
@@ -12,6 +13,7 @@ use nom::combinator::map_res;
 use nom::combinator::opt;
 use nom::combinator::success;
 use nom::IResult;
+use std::borrow::Cow;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum DecodingResult<T> {
@@ -102,7 +104,8 @@ fn urldecode_bytes(input: &[u8]) -> DecodingResult<Vec<u8>> {
     }
 }
 
-fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
+/// decodes a base64 encoded input, either in the standard or the URL safe alphabet
+pub fn base64dec_all(input: &[u8]) -> Result<Vec<u8>, &'static str> {
     const BAD_PADDING_MESSAGE: &str = "bad padding";
     if input.len() % 4 == 1 {
         return Err(BAD_PADDING_MESSAGE);
@@ -111,14 +114,14 @@ fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
     let mut v: u32 = 0;
     let mut res: Vec<u8> = Vec::default();
     let mut pad = 0;
-    for c in input.chars() {
+    for &c in input {
         let n = match c {
-            '0'..='9' => 52 + (c as u8) - b'0',
-            'A'..='Z' => (c as u8) - b'A',
-            'a'..='z' => 26 + (c as u8) - b'a',
-            '+' | '-' => 62,
-            '/' | '_' => 63,
-            '=' => {
+            b'0'..=b'9' => 52 + c - b'0',
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => 26 + c - b'a',
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => {
                 if (pad >= 2) || (i >= 3) {
                     return Err("bad padding");
                 }
@@ -156,7 +159,7 @@ fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
 
 /// decodes an url encoded string into a string, which can contain REPLACEMENT CHARACTER on decoding failure
 pub fn base64dec_all_str(input: &str) -> Result<String, &str> {
-    match base64dec_all(input) {
+    match base64dec_all(input.as_bytes()) {
         Ok(d) => match String::from_utf8(d) {
             Err(_) => Err("invalid utf8"),
             Ok(x) => Ok(x),
@@ -175,6 +178,7 @@ pub fn urldecode_str(input: &str) -> DecodingResult<String> {
 }
 
 /// same as urldecode_str, but defaults to the input string when no change happeneds
+#[cfg(test)]
 fn urldecode_str_def(input: &str) -> String {
     match urldecode_str(input) {
         DecodingResult::Changed(s) => s,
@@ -184,31 +188,178 @@ fn urldecode_str_def(input: &str) -> String {
 
 /// parses query parameters, that look like a=b&c=d
 pub fn parse_urlencoded_params(args: &mut RequestField, query: &str) {
-    for kv in query.split('&') {
-        let (k, v) = match kv.splitn(2, '=').collect_tuple() {
-            Some((k, v)) => (urldecode_str_def(k), urldecode_str_def(v)),
-            None => (urldecode_str_def(kv), String::new()),
-        };
-        args.add(k, DataSource::X(XDataSource::Uri), v);
-    }
+    parse_urlencoded_params_bytes(args, query.as_bytes())
 }
 
 fn urldecode_bytes_str(input: &[u8]) -> String {
     match urldecode_bytes(input) {
         DecodingResult::NoChange => String::from_utf8_lossy(input).into_owned(),
-        DecodingResult::Changed(r) => String::from_utf8_lossy(&r).into_owned(),
+        // the decoded buffer is reused when it is valid UTF-8
+        DecodingResult::Changed(r) => {
+            String::from_utf8(r).unwrap_or_else(|rr| String::from_utf8_lossy(rr.as_bytes()).into_owned())
+        }
+    }
+}
+
+/// the input is borrowed when it does not need to be decoded
+fn urldecode_bytes_cow(input: &[u8]) -> Cow<'_, [u8]> {
+    match urldecode_bytes(input) {
+        DecodingResult::NoChange => Cow::Borrowed(input),
+        DecodingResult::Changed(r) => Cow::Owned(r),
     }
 }
 
 /// parses query parameters, that look like a=b&c=d
+///
+/// decoded values that are not valid UTF-8 are kept as raw bytes in the request field
 pub fn parse_urlencoded_params_bytes(args: &mut RequestField, query: &[u8]) {
-    for kv in query.split(|x| *x == b'&') {
+    let normalization = match args.normalization.clone() {
+        Some(n) => n,
+        None => {
+            for kv in query.split(|x| *x == b'&') {
+                let (k, v) = match kv.splitn(2, |x| *x == b'=').collect_tuple() {
+                    Some((k, v)) => (urldecode_bytes_str(k), urldecode_bytes_cow(v)),
+                    None => (urldecode_bytes_str(kv), Cow::Borrowed(&[][..])),
+                };
+                let k = args.param_key(k);
+                args.add_bytes(k, DataSource::X(XDataSource::Uri), &v);
+            }
+            return;
+        }
+    };
+    // the repeated parameters are merged before they are stored, as the upstream merges them
+    let mut params = Params::new(normalization.duplicates);
+    for kv in query.split(|x| normalization.is_separator(*x)) {
         let (k, v) = match kv.splitn(2, |x| *x == b'=').collect_tuple() {
-            Some((k, v)) => (urldecode_bytes_str(k), urldecode_bytes_str(v)),
-            None => (urldecode_bytes_str(kv), String::new()),
+            Some((k, v)) => (k, v),
+            None => (kv, &[][..]),
         };
-        args.add(k, DataSource::X(XDataSource::Uri), v);
+        let k = urldecode_bytes_str(&normalization.decode_escapes(k));
+        let v = urldecode_bytes_cow(&normalization.decode_escapes(v)).into_owned();
+        params.push(args.param_key(k), v);
+    }
+    for (k, v) in params.entries {
+        args.add_bytes(k, DataSource::X(XDataSource::Uri), &v);
+    }
+}
+
+/// percent encodes everything but the unreserved characters, so that the result can be used in any URL component
+pub fn urlencode_component(input: &str) -> String {
+    urlencode(input.as_bytes(), UrlComponent::Component)
+}
+
+/// the part of an URL a value is encoded for, or decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlComponent {
+    /// only the RFC 3986 unreserved characters are kept, so that the result can be used anywhere
+    Component,
+    /// a path segment, the `/`, `?` and `#` delimiters being encoded
+    PathSegment,
+    /// a query string name or value, the `&`, `=`, `+` and `#` characters being encoded
+    Query,
+    /// `application/x-www-form-urlencoded`, where spaces are encoded as `+`
+    Form,
+}
+
+impl UrlComponent {
+    pub fn from_name(name: Option<&str>) -> Result<Self, String> {
+        match name {
+            None | Some("component") => Ok(UrlComponent::Component),
+            Some("path") => Ok(UrlComponent::PathSegment),
+            Some("query") => Ok(UrlComponent::Query),
+            Some("form") => Ok(UrlComponent::Form),
+            Some(other) => Err(format!(
+                "unknown URL component {}, expected component, path, query or form",
+                other
+            )),
+        }
     }
+
+    fn keeps(self, b: u8) -> bool {
+        b.is_ascii_alphanumeric()
+            || match self {
+                UrlComponent::Component => b"-._~".contains(&b),
+                UrlComponent::PathSegment => b"-._~!$&'()*+,;=:@".contains(&b),
+                UrlComponent::Query => b"-._~!$'()*,;:@/?".contains(&b),
+                UrlComponent::Form => b"*-._".contains(&b),
+            }
+    }
+}
+
+/// percent encodes the characters that are not allowed as is in the URL component
+pub fn urlencode(input: &[u8], component: UrlComponent) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input {
+        if component.keeps(*b) {
+            out.push(*b as char);
+        } else if *b == b' ' && component == UrlComponent::Form {
+            out.push('+');
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlDecoded {
+    pub value: Vec<u8>,
+    /// the decoded value still contains percent escapes, which is how double encoding attacks look
+    pub double_encoded: bool,
+}
+
+fn percent_escape_at(input: &[u8], idx: usize) -> Option<u8> {
+    match input.get(idx..idx + 3) {
+        Some([b'%', h, l]) => Some(from_hex_digit(*h)? * 16 + from_hex_digit(*l)?),
+        _ => None,
+    }
+}
+
+/// decodes an URL component, `+` being a space in forms only
+///
+/// In strict mode, the `%` characters that do not start a valid escape are errors, otherwise they are kept as is.
+pub fn urldecode_component(input: &[u8], component: UrlComponent, strict: bool) -> Result<UrlDecoded, String> {
+    let mut value = Vec::with_capacity(input.len());
+    let mut idx = 0;
+    while idx < input.len() {
+        match input[idx] {
+            b'%' => match percent_escape_at(input, idx) {
+                Some(b) => {
+                    value.push(b);
+                    idx += 3;
+                    continue;
+                }
+                None if strict => return Err(format!("invalid percent escape at offset {}", idx)),
+                None => value.push(b'%'),
+            },
+            b'+' if component == UrlComponent::Form => value.push(b' '),
+            b => value.push(b),
+        }
+        idx += 1;
+    }
+    let double_encoded = (0..value.len()).any(|i| percent_escape_at(&value, i).is_some());
+    Ok(UrlDecoded { value, double_encoded })
+}
+
+/// converts PHP / Rails style argument names into the flattened names used for JSON bodies
+///
+/// `a[b][c]` becomes `a_b_c`, `a[0]` becomes `a_0`, and the empty brackets of `a[]` are dropped, so that all the
+/// values are stored under `a`. Returns `None` when the name does not use this syntax.
+pub fn nested_key(k: &str) -> Option<String> {
+    let (base, rest) = k.split_at(k.find('[')?);
+    if base.is_empty() || !rest.ends_with(']') {
+        return None;
+    }
+    let segments = rest[1..rest.len() - 1].split("][").collect::<Vec<_>>();
+    if segments.iter().any(|s| s.contains('[') || s.contains(']')) {
+        return None;
+    }
+    let mut out = base.to_string();
+    for s in segments.into_iter().filter(|s| !s.is_empty()) {
+        out.push('_');
+        out.push_str(s);
+    }
+    Some(out)
 }
 
 fn from_hex(input: &str) -> Result<char, &'static str> {
@@ -343,6 +494,55 @@ pub fn htmlentities(input: &str) -> DecodingResult<String> {
     }
 }
 
+/// canonical form of a request path, used for security policy selection
+///
+/// * the fragment is removed,
+/// * the path is percent-decoded once (`+` is not considered as a space),
+/// * duplicate slashes are collapsed,
+/// * `.` and `..` segments are resolved, without escaping the root.
+///
+/// The trailing slash, if any, is preserved.
+pub fn canonicalize_path(path: &str) -> String {
+    let path = path.split('#').next().unwrap_or_default();
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let b = bytes[idx];
+        if b == b'%' && idx + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (from_hex_digit(bytes[idx + 1]), from_hex_digit(bytes[idx + 2])) {
+                decoded.push(h * 16 + l);
+                idx += 3;
+                continue;
+            }
+        }
+        decoded.push(b);
+        idx += 1;
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let trailing = decoded.len() > 1 && (decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/.."));
+    let mut out = String::with_capacity(decoded.len() + 1);
+    for s in segments {
+        out.push('/');
+        out.push_str(s);
+    }
+    if out.is_empty() || trailing {
+        out.push('/');
+    }
+    out
+}
+
 #[cfg(test)]
 mod test_lib {
     use super::*;
@@ -395,6 +595,47 @@ mod test_lib {
         assert!(urldecode_str_def("%F0%9F%BE%20%21%") == "� !%");
     }
 
+    #[test]
+    fn test_url_components() {
+        let input = "a b+c/d?e=f&g#h%i~é";
+        let cases = [
+            (UrlComponent::Component, "a%20b%2Bc%2Fd%3Fe%3Df%26g%23h%25i~%C3%A9"),
+            (UrlComponent::PathSegment, "a%20b+c%2Fd%3Fe=f&g%23h%25i~%C3%A9"),
+            (UrlComponent::Query, "a%20b%2Bc/d?e%3Df%26g%23h%25i~%C3%A9"),
+            (UrlComponent::Form, "a+b%2Bc%2Fd%3Fe%3Df%26g%23h%25i%7E%C3%A9"),
+        ];
+        for (component, encoded) in cases.iter() {
+            assert_eq!(&urlencode(input.as_bytes(), *component), encoded);
+            let decoded = urldecode_component(encoded.as_bytes(), *component, true).unwrap();
+            assert_eq!(decoded.value, input.as_bytes());
+            assert!(!decoded.double_encoded);
+        }
+
+        assert_eq!(
+            urldecode_component(b"a+b", UrlComponent::Query, true).unwrap().value,
+            b"a+b"
+        );
+        assert_eq!(
+            urldecode_component(b"100%+%zz", UrlComponent::Form, false)
+                .unwrap()
+                .value,
+            b"100% %zz"
+        );
+        assert_eq!(
+            urldecode_component(b"100%", UrlComponent::PathSegment, true),
+            Err("invalid percent escape at offset 3".to_string())
+        );
+        assert!(
+            urldecode_component(b"%252e%252e%252f", UrlComponent::PathSegment, true)
+                .unwrap()
+                .double_encoded
+        );
+        assert_eq!(
+            UrlComponent::from_name(Some("fragment")),
+            Err("unknown URL component fragment, expected component, path, query or form".to_string())
+        );
+    }
+
     #[test]
     fn test_ok_base64dec_all_str() {
         for (input, output) in [
@@ -496,4 +737,48 @@ mod test_lib {
             }
         }
     }
+
+    #[test]
+    fn test_urlencode_component() {
+        assert_eq!(urlencode_component("/a b?c=d&e~"), "%2Fa%20b%3Fc%3Dd%26e~");
+        assert_eq!(urlencode_component("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn test_nested_key() {
+        for (input, output) in [
+            ("a", None),
+            ("a[]", Some("a")),
+            ("a[b][c]", Some("a_b_c")),
+            ("a[0][]", Some("a_0")),
+            ("[a]", None),
+            ("a[b", None),
+            ("a[b]c]", None),
+        ] {
+            assert_eq!(nested_key(input).as_deref(), output, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_path() {
+        for (input, output) in [
+            ("", "/"),
+            ("/", "/"),
+            ("/a/b", "/a/b"),
+            ("/a/b/", "/a/b/"),
+            ("//a///b", "/a/b"),
+            ("/a/./b/../c", "/a/c"),
+            ("/admin/%2e%2e/admin", "/admin"),
+            ("/admin/%2E%2E%2fadmin", "/admin"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/a/b/..", "/a/"),
+            ("/a+b%20c", "/a+b c"),
+            ("/a/b#fragment", "/a/b"),
+            ("/a%2", "/a%2"),
+        ]
+        .iter()
+        {
+            assert_eq!(canonicalize_path(input), *output, "input: {}", input);
+        }
+    }
 }